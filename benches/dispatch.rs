@@ -0,0 +1,85 @@
+//! Benchmarks of the interpreter loop.  Run them with `make bench`, which
+//! runs them with the plain `match` loop and then with the
+//! `threaded-dispatch` feature, to compare the two.
+//!
+//! These use the unstable `test` crate, so they need a nightly compiler
+//! (`cargo +nightly bench`).
+
+#![feature(test)]
+
+extern crate rusty_scheme;
+extern crate test;
+
+use rusty_scheme::{Input, State};
+use test::Bencher;
+
+/// A state in which `source` has been evaluated, with its last value, a
+/// procedure of no arguments, on top of the stack.
+fn setup(source: &str) -> State {
+    let mut interp = State::new();
+    let mut input = Input::new(source.as_bytes(), "bench");
+    assert!(interp.read(&mut input).unwrap());
+    interp.execute().unwrap();
+    while interp.read(&mut input).unwrap() {
+        // Replace the value of the previous datum.
+        interp.store(0, 1);
+        interp.drop().unwrap();
+        interp.execute().unwrap()
+    }
+    interp
+}
+
+/// Calls the procedure on top of the stack, leaving it there.
+fn run(b: &mut Bencher, interp: &mut State) {
+    b.iter(|| {
+        interp.load(0);
+        interp.call(0).unwrap();
+        interp.drop().unwrap()
+    })
+}
+
+/// Lists of 4096 elements, made by doubling, and a thunk that reverses one.
+/// Mostly calls, conditionals and register moves.
+const LISTS: &'static str = "
+(define (reverse-onto list tail)
+  (if (null? list)
+      tail
+      (reverse-onto (cdr list) (cons (car list) tail))))
+(define (double list) (reverse-onto list list))
+(define (make-list doublings)
+  (let loop ((list '(x)) (doublings doublings))
+    (if (null? doublings)
+        list
+        (loop (double list) (cdr doublings)))))
+(define big (make-list '(1 2 3 4 5 6 7 8 9 10 11 12)))
+(lambda () (reverse-onto big '()))";
+
+#[bench]
+fn reverse_list(b: &mut Bencher) {
+    let mut interp = setup(LISTS);
+    run(b, &mut interp)
+}
+
+/// A thunk that makes and calls closures over the elements of a list of
+/// 4096 elements.  Mostly closure creation, captured variables and boxes.
+const CLOSURES: &'static str = "
+(define (double list)
+  (let loop ((list list) (tail list))
+    (if (null? list) tail (loop (cdr list) (cons (car list) tail)))))
+(define big
+  (let loop ((list '(x)) (doublings '(1 2 3 4 5 6 7 8 9 10 11 12)))
+    (if (null? doublings) list (loop (double list) (cdr doublings)))))
+(define (count-with-closures list)
+  (define last #f)
+  (define (visit element) (lambda () (set! last element) last))
+  (let loop ((list list))
+    (if (null? list)
+        last
+        (begin ((visit (car list))) (loop (cdr list))))))
+(lambda () (count-with-closures big))";
+
+#[bench]
+fn closures(b: &mut Bencher) {
+    let mut interp = setup(CLOSURES);
+    run(b, &mut interp)
+}
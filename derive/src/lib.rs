@@ -0,0 +1,162 @@
+//! `#[derive(SchemeRecord)]`, which converts a struct with named fields to
+//! and from a Scheme record (see `rusty_scheme::SchemeRecord`).
+//!
+//! The record type is named after the struct, and its fields after the
+//! struct's fields, in kebab case: `struct HttpRequest { max_age: u32 }`
+//! becomes the type `http-request` with the field `max-age`.  Every field
+//! must implement both `ToScheme` and `FromScheme`.
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate rusty_scheme_derive;
+//! extern crate rusty_scheme;
+//!
+//! #[derive(SchemeRecord, Debug, PartialEq)]
+//! struct Point {
+//!     x: i32,
+//!     y: i32,
+//! }
+//!
+//! fn main() {
+//!     let mut interp = rusty_scheme::Interpreter::new();
+//!     interp.define_record::<Point>().unwrap();
+//!     let point = interp.value(Point { x: 1, y: 2 }).unwrap();
+//!     interp.define("p", &point).unwrap();
+//!     let moved = interp.eval_str("(make-point (point-y p) (point-x p))").unwrap();
+//!     assert_eq!(interp.get(&moved), Ok(Point { x: 2, y: 1 }));
+//! }
+//! ```
+//!
+//! This parses the struct by hand, so that the crate has no dependencies.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, TokenStream, TokenTree};
+
+#[proc_macro_derive(SchemeRecord)]
+pub fn derive_scheme_record(input: TokenStream) -> TokenStream {
+    let code = match parse(input) {
+        Ok((name, fields)) => implementation(&name, &fields),
+        Err(message) => format!("compile_error!({:?});", message),
+    };
+    code.parse().expect("generated code does not parse")
+}
+
+/// The name and field names of the struct `input`.
+fn parse(input: TokenStream) -> Result<(String, Vec<String>), String> {
+    let mut tokens = input.into_iter();
+    loop {
+        match tokens.next() {
+            Some(TokenTree::Ident(ref ident)) if ident.to_string() == "struct" => break,
+            Some(TokenTree::Ident(ref ident)) if ident.to_string() == "enum" ||
+                                                 ident.to_string() == "union" => {
+                return Err("SchemeRecord can only be derived for structs".to_owned())
+            }
+            Some(_) => {}
+            None => return Err("expected a struct".to_owned()),
+        }
+    }
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ident)) => ident.to_string(),
+        _ => return Err("expected the name of the struct".to_owned()),
+    };
+    match tokens.next() {
+        Some(TokenTree::Group(ref group)) if group.delimiter() == Delimiter::Brace => {
+            Ok((name, try!(field_names(group.stream()))))
+        }
+        Some(TokenTree::Punct(ref punct)) if punct.as_char() == '<' => {
+            Err("SchemeRecord cannot be derived for generic structs".to_owned())
+        }
+        _ => Err("SchemeRecord can only be derived for structs with named fields".to_owned()),
+    }
+}
+
+/// The names of the fields declared by `body`, the contents of the braces
+/// of a struct.
+fn field_names(body: TokenStream) -> Result<Vec<String>, String> {
+    let mut names = vec![];
+    // Whether the next identifier starts a field (after attributes and
+    // visibility), and how deeply nested in `<>` the current type is.  The
+    // `>` of a `->` does not close anything.
+    let (mut at_start, mut angle_depth, mut arrow) = (true, 0, false);
+    let mut previous_ident = None;
+    for token in body {
+        let dash = match token {
+            TokenTree::Punct(ref punct) => punct.as_char() == '-',
+            _ => false,
+        };
+        match token {
+            TokenTree::Punct(ref punct) => {
+                match punct.as_char() {
+                    ':' if at_start && angle_depth == 0 => {
+                        match previous_ident.take() {
+                            Some(name) => names.push(name),
+                            None => return Err("expected a field name".to_owned()),
+                        }
+                        at_start = false
+                    }
+                    '<' => angle_depth += 1,
+                    '>' if angle_depth > 0 && !arrow => angle_depth -= 1,
+                    ',' if angle_depth == 0 => at_start = true,
+                    _ => {}
+                }
+            }
+            TokenTree::Ident(ref ident) if at_start && ident.to_string() != "pub" => {
+                previous_ident = Some(ident.to_string())
+            }
+            _ => {}
+        }
+        arrow = dash
+    }
+    Ok(names)
+}
+
+/// `name`, in kebab case: a dash before each capital letter that follows a
+/// lowercase letter or digit, and instead of each underscore.
+fn kebab_case(name: &str) -> String {
+    let mut result = String::new();
+    let mut previous: Option<char> = None;
+    for c in name.trim_start_matches("r#").chars() {
+        if c == '_' {
+            result.push('-')
+        } else {
+            if c.is_uppercase() && previous.map_or(false, |p| p.is_lowercase() || p.is_numeric()) {
+                result.push('-')
+            }
+            result.extend(c.to_lowercase())
+        }
+        previous = Some(c)
+    }
+    result
+}
+
+/// The `SchemeRecord` implementation for the struct `name`.
+fn implementation(name: &str, fields: &[String]) -> String {
+    let field_names: Vec<String> =
+        fields.iter().map(|field| format!("{:?}", kebab_case(field))).collect();
+    let references: Vec<String> =
+        fields.iter()
+              .map(|field| format!("&self.{} as &dyn ::rusty_scheme::ToScheme", field))
+              .collect();
+    let initializers: Vec<String> =
+        fields.iter().map(|field| format!("{}: fields.next()?", field)).collect();
+    format!("impl ::rusty_scheme::SchemeRecord for {name} {{
+                 const NAME: &'static str = {scheme_name:?};
+                 const FIELDS: &'static [&'static str] = &[{field_names}];
+
+                 fn fields(&self) -> ::std::vec::Vec<&dyn ::rusty_scheme::ToScheme> {{
+                     vec![{references}]
+                 }}
+
+                 #[allow(unused_variables)]
+                 fn from_fields(fields: &mut ::rusty_scheme::Fields)
+                                -> ::std::result::Result<Self, ::std::string::String> {{
+                     ::std::result::Result::Ok({name} {{ {initializers} }})
+                 }}
+             }}",
+            name = name,
+            scheme_name = kebab_case(name),
+            field_names = field_names.join(", "),
+            references = references.join(", "),
+            initializers = initializers.join(", "))
+}
@@ -0,0 +1,75 @@
+/* The C API of rusty_scheme, built with the `capi` feature.  See
+ * src/capi.rs for how values, contexts, errors and NULL pointers work. */
+
+#ifndef RUSTY_SCHEME_H
+#define RUSTY_SCHEME_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct rusty_scheme rusty_scheme;
+typedef struct rusty_scheme_context rusty_scheme_context;
+typedef struct rusty_scheme_value rusty_scheme_value;
+
+/* The types of values, as rusty_scheme_type returns them. */
+enum {
+    RUSTY_SCHEME_OTHER = 0,
+    RUSTY_SCHEME_BOOLEAN = 1,
+    RUSTY_SCHEME_INTEGER = 2,
+    RUSTY_SCHEME_REAL = 3,
+    RUSTY_SCHEME_STRING = 4,
+    RUSTY_SCHEME_SYMBOL = 5,
+    RUSTY_SCHEME_NULL = 6,
+    RUSTY_SCHEME_PAIR = 7,
+    RUSTY_SCHEME_VECTOR = 8,
+    RUSTY_SCHEME_PROCEDURE = 9
+};
+
+/* A procedure written in C.  Returns NULL, after rusty_scheme_set_error,
+ * to fail. */
+typedef rusty_scheme_value *(*rusty_scheme_callback)(rusty_scheme_context *cx,
+                                                     const rusty_scheme_value *const *args,
+                                                     size_t nargs,
+                                                     void *data);
+
+rusty_scheme *rusty_scheme_new(void);
+void rusty_scheme_free(rusty_scheme *scheme);
+rusty_scheme_context *rusty_scheme_context(rusty_scheme *scheme);
+const char *rusty_scheme_error(const rusty_scheme *scheme);
+
+rusty_scheme_value *rusty_scheme_eval(rusty_scheme *scheme, const char *source, size_t len);
+rusty_scheme_value *rusty_scheme_call(rusty_scheme *scheme,
+                                      const char *name,
+                                      const rusty_scheme_value *const *args,
+                                      size_t nargs);
+int rusty_scheme_define_fn(rusty_scheme *scheme,
+                           const char *name,
+                           size_t min_args,
+                           ptrdiff_t max_args,
+                           rusty_scheme_callback callback,
+                           void *data);
+void rusty_scheme_set_error(rusty_scheme_context *cx, const char *message);
+
+void rusty_scheme_value_free(rusty_scheme_value *value);
+int rusty_scheme_type(const rusty_scheme_value *value);
+int rusty_scheme_is_true(const rusty_scheme_value *value);
+int rusty_scheme_to_int64(rusty_scheme_context *cx, const rusty_scheme_value *value, int64_t *out);
+int rusty_scheme_to_double(rusty_scheme_context *cx, const rusty_scheme_value *value, double *out);
+char *rusty_scheme_to_string(rusty_scheme_context *cx, const rusty_scheme_value *value);
+char *rusty_scheme_write_string(rusty_scheme_context *cx, const rusty_scheme_value *value);
+void rusty_scheme_string_free(char *string);
+
+rusty_scheme_value *rusty_scheme_from_int64(rusty_scheme_context *cx, int64_t number);
+rusty_scheme_value *rusty_scheme_from_double(rusty_scheme_context *cx, double number);
+rusty_scheme_value *rusty_scheme_from_bool(rusty_scheme_context *cx, int boolean);
+rusty_scheme_value *rusty_scheme_from_string(rusty_scheme_context *cx, const char *string, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
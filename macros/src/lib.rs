@@ -0,0 +1,148 @@
+//! `include_scheme!` and `scheme!`, which compile Scheme code when the Rust
+//! code that uses them is built, so that the program does not read, expand
+//! and compile it every time it starts.
+//!
+//! `include_scheme!("lib/shapes.scm")` compiles a file, relative to the
+//! directory of the crate being built, and `scheme!("...")` the code in a
+//! string literal.  Scheme code is not made of Rust tokens (a comment or a
+//! character such as `#\(` would not lex), so it must be quoted.  Either
+//! expands to a `&'static [u8]`: the contents of a compiled file (see
+//! `compile-file`), which `Interpreter::load_compiled` runs.
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate rusty_scheme_macros;
+//! extern crate rusty_scheme;
+//!
+//! fn main() {
+//!     let mut interp = rusty_scheme::Interpreter::new();
+//!     interp.load_compiled("squares", scheme!("(define (square x) (* x x))")).unwrap();
+//!     let value = interp.eval_str("(square 12)").unwrap();
+//!     assert_eq!(interp.write_string(&value), "144");
+//! }
+//! ```
+//!
+//! As with `compile-file`, the code is run while it is compiled, here by
+//! the compiler of the crate, so its side effects happen then.  Libraries
+//! that it imports are searched for in the directory of the file, or of the
+//! crate.  The compiled code can only be run by the version of RustyScheme
+//! that this crate was built with.
+
+extern crate proc_macro;
+extern crate rusty_scheme;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use proc_macro::{Delimiter, Literal, TokenStream, TokenTree};
+use rusty_scheme::State;
+
+#[proc_macro]
+pub fn include_scheme(input: TokenStream) -> TokenStream {
+    expand(string_argument(input).and_then(|file| include(&file)))
+}
+
+#[proc_macro]
+pub fn scheme(input: TokenStream) -> TokenStream {
+    expand(string_argument(input).and_then(|source| {
+        let compiled = try!(compile(&crate_directory(), "scheme!", &source));
+        Ok(bytes(&compiled))
+    }))
+}
+
+/// The expansion of a macro that returned `code`.
+fn expand(code: Result<String, String>) -> TokenStream {
+    let code = match code {
+        Ok(code) => code,
+        Err(message) => format!("compile_error!({:?})", message),
+    };
+    code.parse().expect("generated code does not parse")
+}
+
+/// The contents of the string literal that is the only argument of a
+/// macro.
+fn string_argument(input: TokenStream) -> Result<String, String> {
+    let mut tokens = input.into_iter();
+    let literal = match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(literal)), None) => literal.to_string(),
+        (Some(TokenTree::Group(ref group)), None) if group.delimiter() == Delimiter::None => {
+            return string_argument(group.stream())
+        }
+        _ => return Err("expected a string literal".to_owned()),
+    };
+    unquote(&literal).ok_or_else(|| "expected a string literal".to_owned())
+}
+
+/// The string that `literal`, a string literal, possibly raw, stands for.
+fn unquote(literal: &str) -> Option<String> {
+    if literal.starts_with('r') {
+        let hashes = literal.len() - 1 - literal[1..].trim_start_matches('#').len();
+        let quoted = &literal[1 + hashes..literal.len() - hashes];
+        return Some(quoted[1..quoted.len() - 1].to_owned())
+    }
+    if literal.len() < 2 || !literal.starts_with('"') || !literal.ends_with('"') {
+        return None
+    }
+    let mut result = String::new();
+    let mut chars = literal[1..literal.len() - 1].chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => result.push(c),
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                result.push(u8::from_str_radix(&digits, 16).ok()? as char)
+            }
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+                let code = u32::from_str_radix(&digits.replace('_', ""), 16).ok()?;
+                result.push(::std::char::from_u32(code)?)
+            }
+            Some('\n') => {
+                while chars.peek().map_or(false, |c| c.is_whitespace()) {
+                    chars.next();
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(result)
+}
+
+/// The directory of the crate being built.
+fn crate_directory() -> PathBuf {
+    env::var_os("CARGO_MANIFEST_DIR").map_or_else(PathBuf::new, PathBuf::from)
+}
+
+/// The expansion of `include_scheme!(file)`.  It includes the source file
+/// too, unused, so that the crate is built again when the file changes.
+fn include(file: &str) -> Result<String, String> {
+    let path = crate_directory().join(file);
+    let source = try!(fs::read_to_string(&path)
+                          .map_err(|e| format!("{}: {}", path.display(), e)));
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let compiled = try!(compile(directory, &path.to_string_lossy(), &source));
+    Ok(format!("{{ const _: &'static [u8] = include_bytes!({:?}); {} }}",
+               path.to_string_lossy(),
+               bytes(&compiled)))
+}
+
+/// Compiles `source`, which comes from `name`, in a new interpreter that
+/// loads libraries from `directory`.
+fn compile(directory: &Path, name: &str, source: &str) -> Result<Vec<u8>, String> {
+    let mut state = State::new();
+    state.add_library_directory(directory);
+    state.compile_str(name, source)
+}
+
+/// A `&'static [u8]` expression for `bytes`.
+fn bytes(bytes: &[u8]) -> String {
+    format!("{{ let bytes: &'static [u8] = {}; bytes }}", Literal::byte_string(bytes))
+}
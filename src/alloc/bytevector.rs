@@ -0,0 +1,121 @@
+//! Bytevectors.
+//!
+//! A bytevector is a `RustData` object laid out as a `value::Bytevector`
+//! (header, `BYTEVECTOR_TYPE`, and length in bytes), followed by the bytes,
+//! starting at the 8-byte aligned offset `value::BYTEVECTOR_PAYLOAD`.  The
+//! bytes are padded to a whole number of words, and are not scanned by the
+//! GC.
+//!
+//! Pinned bytevectors are allocated in the large object space, whatever
+//! their size, so they never move.  A pointer to their contents stays valid
+//! for as long as the bytevector is reachable, and can be handed to C or
+//! Rust I/O routines without copying.
+
+use value::{self, Value};
+use super::Heap;
+
+/// Checks that `value` is a bytevector.
+fn as_bytevector(value: &Value) -> Result<*mut value::Bytevector, String> {
+    if value.raw_tag() != value::RUST_DATA_TAG {
+        return Err("Value is not a bytevector".to_owned())
+    }
+    unsafe {
+        let ptr = value.as_ptr();
+        if (*ptr.offset(1)).get() != value::BYTEVECTOR_TYPE {
+            return Err("Value is not a bytevector".to_owned())
+        }
+        Ok(ptr as *mut value::Bytevector)
+    }
+}
+
+impl Heap {
+    /// Allocates a bytevector holding `len` copies of `fill`, and pushes it on
+    /// the stack.
+    pub fn alloc_bytevector(&mut self, len: usize, fill: u8) -> Result<(), String> {
+        self.alloc_bytevector_in(len, fill, false)
+    }
+
+    /// Allocates a zero-filled bytevector of `len` bytes that will never be
+    /// moved by the GC, and pushes it on the stack.
+    pub fn alloc_pinned_bytevector(&mut self, len: usize) -> Result<(), String> {
+        self.alloc_bytevector_in(len, 0, true)
+    }
+
+    fn alloc_bytevector_in(&mut self, len: usize, fill: u8, pinned: bool) -> Result<(), String> {
+        let words = (value::BYTEVECTOR_PAYLOAD + len + size_of!(Value) - 1) / size_of!(Value);
+        let (value_ptr, final_len) = if pinned {
+            try!(self.alloc_raw_pinned(words, value::HeaderTag::RustData))
+        } else {
+            try!(self.alloc_raw(words, value::HeaderTag::RustData))
+        };
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let space = self.space_mut();
+        space.extend_from_slice(&[Value::new(value::BYTEVECTOR_TYPE), Value::new(len)]);
+        space.resize(final_len, Value::new(0));
+        let bytevector = Value::new(ptr);
+        if fill != 0 {
+            for byte in unsafe { try!(self.bytevector_as_mut_slice(&bytevector)) } {
+                *byte = fill
+            }
+        }
+        Ok(self.stack.push(bytevector))
+    }
+
+    /// Allocates a bytevector holding a copy of `bytes`, and pushes it on the
+    /// stack.
+    pub fn alloc_bytevector_from(&mut self, bytes: &[u8]) -> Result<(), String> {
+        try!(self.alloc_bytevector(bytes.len(), 0));
+        let bytevector = self.stack[self.stack.len() - 1].clone();
+        unsafe { try!(self.bytevector_as_mut_slice(&bytevector)).copy_from_slice(bytes) }
+        Ok(())
+    }
+
+    /// The length of the bytevector `bytevector`, in bytes.
+    pub fn bytevector_len(&self, bytevector: &Value) -> Result<usize, String> {
+        let ptr = try!(as_bytevector(bytevector));
+        Ok(unsafe { (*ptr).len })
+    }
+
+    /// A pointer to the contents of `bytevector`.
+    ///
+    /// The pointer is only stable if the bytevector was allocated with
+    /// `alloc_pinned_bytevector`, and only for as long as the bytevector is
+    /// reachable.
+    pub fn bytevector_as_mut_ptr(&self, bytevector: &Value) -> Result<*mut u8, String> {
+        let ptr = try!(as_bytevector(bytevector));
+        Ok(unsafe { (*ptr).as_ptr() })
+    }
+
+    /// The contents of `bytevector`.  The slice is invalidated by any
+    /// allocation.
+    pub unsafe fn bytevector_as_slice(&self, bytevector: &Value) -> Result<&[u8], String> {
+        let ptr = try!(as_bytevector(bytevector));
+        Ok((*ptr).as_slice())
+    }
+
+    /// The contents of `bytevector`, mutably.  The slice is invalidated by
+    /// any allocation.
+    pub unsafe fn bytevector_as_mut_slice(&self,
+                                          bytevector: &Value)
+                                          -> Result<&mut [u8], String> {
+        let ptr = try!(as_bytevector(bytevector));
+        Ok((*ptr).as_mut_slice())
+    }
+
+    /// Copies `bytes` into `bytevector`, starting at byte `start`.
+    pub fn bytevector_copy_from(&mut self,
+                                bytevector: &Value,
+                                start: usize,
+                                bytes: &[u8])
+                                -> Result<(), String> {
+        if bytevector.immutablep() {
+            return Err("Cannot modify a constant bytevector".to_owned())
+        }
+        let contents = unsafe { try!(self.bytevector_as_mut_slice(bytevector)) };
+        if start > contents.len() || bytes.len() > contents.len() - start {
+            return Err("Bytevector index out of range".to_owned())
+        }
+        contents[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
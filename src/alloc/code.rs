@@ -0,0 +1,29 @@
+//! Bytecode objects.
+//!
+//! A BCO is laid out as a `bytecode::BCO` — header, the length of the code
+//! in bytes, and the constants vector — followed by the code itself.  Only
+//! the constants vector is scanned by the GC.
+
+use bytecode;
+use value::{self, Value};
+use super::Heap;
+
+impl Heap {
+    /// Allocates a BCO holding `code`, whose constants vector is popped
+    /// from the stack, and pushes it on the stack.
+    pub fn alloc_bytecode(&mut self, code: &[u8]) -> Result<(), String> {
+        let words = (size_of!(bytecode::BCO) + code.len() + size_of!(Value) - 1) /
+                    size_of!(Value);
+        let (value_ptr, final_len) = try!(self.alloc_raw(words, value::HeaderTag::Bytecode));
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let constants = self.stack.pop().unwrap();
+        let space = self.space_mut();
+        space.extend_from_slice(&[Value::new(code.len()), constants]);
+        space.resize(final_len, Value::new(0));
+        unsafe {
+            let start = (value_ptr as usize + size_of!(bytecode::BCO)) as *mut u8;
+            ::std::ptr::copy_nonoverlapping(code.as_ptr(), start, code.len())
+        }
+        Ok(self.stack.push(Value::new(ptr)))
+    }
+}
@@ -0,0 +1,121 @@
+//! Heap sizing policy.
+
+use std::fmt;
+use value::Value;
+
+/// Called when an allocation would exceed the maximum heap size, with the
+/// size (in bytes) that the heap would need to have.  Returns a new maximum
+/// heap size (in bytes) to continue, or `None` to fail the allocation with an
+/// out-of-memory error.
+pub type OutOfMemoryHandler = Box<dyn FnMut(usize) -> Option<usize>>;
+
+/// The configuration of a `Heap`.
+pub struct HeapConfig {
+    /// The initial size of the heap, in bytes.  The heap never shrinks below
+    /// this size.
+    pub min_bytes: usize,
+
+    /// The maximum size of the heap, in bytes.  `None` means no limit.
+    pub max_bytes: Option<usize>,
+
+    /// After a collection, tospace is grown to hold this many times the
+    /// amount of memory that survived.  Must be at least 1.
+    pub growth_factor: f64,
+
+    /// Called when the maximum heap size would be exceeded.
+    pub out_of_memory: Option<OutOfMemoryHandler>,
+
+    /// Objects at least this large (in bytes) are allocated in the large
+    /// object space, and never copied.
+    pub large_object_bytes: usize,
+
+    /// If set, collect incrementally, spending at most about this many
+    /// microseconds per allocation.  `None` means stop-the-world collection.
+    /// Ignored by generational heaps.
+    pub max_pause_us: Option<u64>,
+
+    /// Check the whole heap (see `Heap::verify`) before and after every
+    /// collection, panicking if it is corrupt.  Very slow.  On by default in
+    /// debug builds, and with the `gc-verify` feature.
+    pub verify: bool,
+
+    /// Collect before every allocation, so that every object moves whenever
+    /// anything is allocated, and code that keeps a value across an
+    /// allocation without rooting it fails at once.  Very slow.  On by
+    /// default with the `gc-stress` feature.
+    pub stress: bool,
+
+    /// Overwrite fromspace and the nursery with garbage once a collection
+    /// has copied them, so that a dangling pointer into them finds garbage
+    /// rather than a stale copy of its object.  On by default with the
+    /// `gc-stress` feature.
+    pub poison: bool,
+}
+
+impl Default for HeapConfig {
+    fn default() -> Self {
+        HeapConfig {
+            min_bytes: (1 << 16) * size_of!(Value),
+            max_bytes: None,
+            growth_factor: 1.5,
+            out_of_memory: None,
+            large_object_bytes: 1 << 16,
+            max_pause_us: None,
+            verify: cfg!(any(debug_assertions, feature = "gc-verify")),
+            stress: cfg!(feature = "gc-stress"),
+            poison: cfg!(feature = "gc-stress"),
+        }
+    }
+}
+
+impl fmt::Debug for HeapConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HeapConfig")
+         .field("min_bytes", &self.min_bytes)
+         .field("max_bytes", &self.max_bytes)
+         .field("growth_factor", &self.growth_factor)
+         .field("out_of_memory", &self.out_of_memory.is_some())
+         .field("large_object_bytes", &self.large_object_bytes)
+         .field("max_pause_us", &self.max_pause_us)
+         .field("verify", &self.verify)
+         .field("stress", &self.stress)
+         .field("poison", &self.poison)
+         .finish()
+    }
+}
+
+impl HeapConfig {
+    /// The minimum heap size, in words.
+    pub fn min_words(&self) -> usize {
+        self.min_bytes / size_of!(Value)
+    }
+
+    /// The maximum heap size, in words.
+    pub fn max_words(&self) -> usize {
+        self.max_bytes.map_or(::std::usize::MAX, |bytes| bytes / size_of!(Value))
+    }
+
+    /// The capacity tospace should have after a collection in which `live`
+    /// words survived.
+    pub fn target_words(&self, live: usize) -> usize {
+        debug_assert!(self.growth_factor >= 1.0);
+        let grown = (live as f64 * self.growth_factor) as usize;
+        ::std::cmp::max(self.min_words(), ::std::cmp::min(grown, self.max_words()))
+    }
+
+    /// A copy of this configuration for another heap, such as that of a
+    /// thread, without the out-of-memory handler, which cannot be shared.
+    pub fn without_handler(&self) -> Self {
+        HeapConfig {
+            min_bytes: self.min_bytes,
+            max_bytes: self.max_bytes,
+            growth_factor: self.growth_factor,
+            out_of_memory: None,
+            large_object_bytes: self.large_object_bytes,
+            max_pause_us: self.max_pause_us,
+            verify: self.verify,
+            stress: self.stress,
+            poison: self.poison,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Heap snapshots, for debugging leaks.
+//!
+//! `Heap::dump` writes a text dump of the heap, with one line per root and
+//! one line per object:
+//!
+//! ```text
+//! root stack:0 0x7f3a5c001018
+//! root global:foo 0x7f3a5c001000
+//! object 0x7f3a5c001000 pair 3 0x7f3a5c001018
+//! object 0x7f3a5c001018 vector 4
+//! ```
+//!
+//! An object line gives the object's address, its kind, its size in words,
+//! and the addresses of the heap objects it refers to.  Immediates, symbols,
+//! and primitives are not heap objects, and do not appear as edges.
+//!
+//! A dump can be read back with `Snapshot::parse`, and explored with
+//! `Snapshot::inspect`, which shows an object, what refers to it, and a
+//! path from a root that keeps it alive.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, BufRead, Write};
+use value::{self, Value, HEADER_TAG, HEADER_SIZE};
+use bytecode;
+use super::{debug, Heap, PAIR, VECTOR, BYTECODE, RECORD, CLOSURE};
+
+/// An object in a `Snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The kind of object, such as `pair` or `vector`.
+    pub kind: String,
+
+    /// The size of the object, in words.
+    pub words: usize,
+
+    /// The addresses of the objects this object refers to.
+    pub edges: Vec<usize>,
+}
+
+/// A snapshot of the object graph of a heap.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The roots, as (description, address) pairs.
+    pub roots: Vec<(String, usize)>,
+
+    /// The objects, indexed by address.
+    pub objects: BTreeMap<usize, ObjectInfo>,
+}
+
+/// The name of the kind of object with header `header`.
+fn kind_name(header: usize) -> &'static str {
+    match header & HEADER_TAG {
+        PAIR => "pair",
+        VECTOR => "vector",
+        BYTECODE => "bytecode",
+        RECORD => "record",
+        CLOSURE => "closure",
+        x if x == value::HeaderTag::RustData as usize => "rustdata",
+        x if x == value::HeaderTag::Finalized as usize => "finalized",
+        _ => "forwarded",
+    }
+}
+
+/// The address of the heap object `value` refers to, if any.
+fn heap_address(value: &Value) -> Option<usize> {
+    if value.immediatep() || value.tag() == value::Tags::Symbol ||
+       value.tag() == value::Tags::RustFunc {
+        None
+    } else {
+        Some(value.get() & !0b111)
+    }
+}
+
+fn parse_address(word: &str) -> Result<usize, String> {
+    let digits = if word.starts_with("0x") { &word[2..] } else { word };
+    usize::from_str_radix(digits, 16).map_err(|e| format!("Bad address {}: {}", word, e))
+}
+
+impl Snapshot {
+    /// Writes the snapshot in the dump format.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for &(ref name, address) in &self.roots {
+            try!(writeln!(writer, "root {} 0x{:x}", name, address));
+        }
+        for (address, object) in &self.objects {
+            try!(write!(writer, "object 0x{:x} {} {}", address, object.kind, object.words));
+            for edge in &object.edges {
+                try!(write!(writer, " 0x{:x}", edge));
+            }
+            try!(writeln!(writer));
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot written by `write` (or `Heap::dump`).
+    pub fn parse<R: BufRead>(reader: R) -> Result<Snapshot, String> {
+        let mut snapshot = Snapshot::default();
+        for line in reader.lines() {
+            let line = try!(line.map_err(|e| e.to_string()));
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first() {
+                None => {}
+                Some(&"root") if words.len() >= 3 => {
+                    let address = try!(parse_address(words[words.len() - 1]));
+                    let name = words[1..words.len() - 1].join(" ");
+                    snapshot.roots.push((name, address))
+                }
+                Some(&"object") if words.len() >= 4 => {
+                    let address = try!(parse_address(words[1]));
+                    let object_words = try!(words[3]
+                                                .parse()
+                                                .map_err(|_| format!("Bad size in: {}", line)));
+                    let mut edges = vec![];
+                    for word in &words[4..] {
+                        edges.push(try!(parse_address(word)))
+                    }
+                    snapshot.objects.insert(address,
+                                            ObjectInfo {
+                                                kind: words[2].to_owned(),
+                                                words: object_words,
+                                                edges: edges,
+                                            });
+                }
+                _ => return Err(format!("Bad line in heap dump: {}", line)),
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// The addresses of the objects that refer to the object at `address`.
+    pub fn retainers(&self, address: usize) -> Vec<usize> {
+        self.objects
+            .iter()
+            .filter(|&(_, object)| object.edges.contains(&address))
+            .map(|(&retainer, _)| retainer)
+            .collect()
+    }
+
+    /// The shortest path from a root to the object at `address`: the name of
+    /// the root, and the addresses of the objects along the way (ending with
+    /// `address`).
+    pub fn path_to(&self, address: usize) -> Option<(String, Vec<usize>)> {
+        let mut parents: HashMap<usize, Option<usize>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut root_names = HashMap::new();
+        for &(ref name, root) in &self.roots {
+            if !parents.contains_key(&root) {
+                parents.insert(root, None);
+                root_names.insert(root, name.clone());
+                queue.push_back(root)
+            }
+        }
+        while let Some(current) = queue.pop_front() {
+            if current == address {
+                let mut path = vec![current];
+                let mut node = current;
+                while let Some(parent) = parents[&node] {
+                    path.push(parent);
+                    node = parent
+                }
+                path.reverse();
+                return Some((root_names[&node].clone(), path))
+            }
+            if let Some(object) = self.objects.get(&current) {
+                for &edge in &object.edges {
+                    if !parents.contains_key(&edge) {
+                        parents.insert(edge, Some(current));
+                        queue.push_back(edge)
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Writes a description of the object at `address`: its kind, size, and
+    /// edges, the objects that refer to it, and how it is reachable.
+    pub fn inspect<W: Write>(&self, address: usize, writer: &mut W) -> io::Result<()> {
+        let object = match self.objects.get(&address) {
+            Some(object) => object,
+            None => return writeln!(writer, "0x{:x}: no such object", address),
+        };
+        try!(writeln!(writer, "0x{:x}: {} ({} words)", address, object.kind, object.words));
+        for edge in &object.edges {
+            let kind = self.objects.get(edge).map_or("?", |object| &object.kind[..]);
+            try!(writeln!(writer, "  -> 0x{:x} {}", edge, kind));
+        }
+        for retainer in self.retainers(address) {
+            try!(writeln!(writer, "  <- 0x{:x} {}", retainer, self.objects[&retainer].kind));
+        }
+        match self.path_to(address) {
+            Some((root, path)) => {
+                try!(write!(writer, "  reachable from {}", root));
+                for step in path {
+                    try!(write!(writer, " -> 0x{:x}", step));
+                }
+                writeln!(writer)
+            }
+            None => writeln!(writer, "  unreachable"),
+        }
+    }
+}
+
+impl Heap {
+    /// Takes a snapshot of the object graph.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (i, value) in self.stack.iter().enumerate() {
+            if let Some(address) = heap_address(value) {
+                snapshot.roots.push((format!("stack:{}", i), address))
+            }
+        }
+        let fibers = self.control.fibers.iter().chain(self.control.suspended.iter());
+        for (i, fiber) in fibers.enumerate() {
+            for (j, value) in fiber.stack.iter().enumerate() {
+                if let Some(address) = heap_address(value) {
+                    snapshot.roots.push((format!("fiber:{}:{}", i, j), address))
+                }
+            }
+        }
+        for (i, value) in self.roots.borrow().iter().enumerate() {
+            if let Some(address) = heap_address(value) {
+                snapshot.roots.push((format!("root:{}", i), address))
+            }
+        }
+        for (name, symbol) in &self.symbol_table.contents {
+            let value = unsafe { (*symbol.contents.get()).clone() };
+            if let Some(address) = heap_address(&value) {
+                snapshot.roots.push((format!("global:{}", name), address))
+            }
+        }
+        let mut spaces = vec![&self.tospace[..]];
+        if let Some(ref nursery) = self.nursery {
+            spaces.push(&nursery[..])
+        }
+        spaces.extend(self.large_objects.spaces());
+        for space in spaces {
+            debug::for_each_object(space, |index, header| {
+                let object = &space[index..];
+                let words = header & HEADER_SIZE;
+                let mut edges = vec![];
+                match header & HEADER_TAG {
+                    PAIR | VECTOR | RECORD | CLOSURE => {
+                        edges.extend(object[1..words].iter().filter_map(heap_address))
+                    }
+                    BYTECODE => {
+                        let bco = object.as_ptr() as *const bytecode::BCO;
+                        let constants = unsafe {
+                            (*bytecode::get_constants_vector(&*bco).get()).clone()
+                        };
+                        edges.extend(heap_address(&constants))
+                    }
+                    _ => /* not scanned */ {}
+                }
+                snapshot.objects.insert(object.as_ptr() as usize,
+                                        ObjectInfo {
+                                            kind: kind_name(header).to_owned(),
+                                            words: words,
+                                            edges: edges,
+                                        });
+            })
+        }
+        snapshot
+    }
+
+    /// Writes a dump of the heap (see `Snapshot`).
+    pub fn dump<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.snapshot().write(writer)
+    }
+}
@@ -0,0 +1,127 @@
+//! Growable vectors.
+//!
+//! A growable vector is a record whose descriptor is `DYNVECTOR_DESCRIPTOR`
+//! (a `value::DynVector`), holding its length and its storage: a vector
+//! whose first elements are its elements.  When the storage is full,
+//! pushing an element replaces it with a vector twice as long, so that
+//! pushing takes amortized constant time.
+
+use value::{self, Kind, Value};
+use super::Heap;
+
+/// The capacity of the storage that the first push to an empty growable
+/// vector with no room allocates.
+const MIN_CAPACITY: usize = 4;
+
+/// The growable vector `value`.
+fn as_dynvector(value: &Value) -> Result<*mut value::DynVector, String> {
+    match value.kind() {
+        Kind::DynVector(dynvector) => Ok(dynvector),
+        _ => Err("Value is not a growable vector".to_owned()),
+    }
+}
+
+impl Heap {
+    /// Allocates an empty growable vector with room for `capacity`
+    /// elements, and pushes it on the stack.
+    pub fn alloc_dynvector(&mut self, capacity: usize) -> Result<(), String> {
+        let base = self.stack.len();
+        self.stack.push(Value::new(value::DYNVECTOR_DESCRIPTOR));
+        self.stack.push(Value::new_fixnum(0));
+        let result = self.alloc_storage(capacity).and_then(|()| {
+            self.alloc_vector_like(value::HeaderTag::Record, base, base + 3)
+        });
+        let object = self.stack.pop();
+        self.stack.truncate(base);
+        try!(result);
+        Ok(self.stack.push(object.unwrap()))
+    }
+
+    /// Pushes a vector of `capacity` unspecified values.
+    fn alloc_storage(&mut self, capacity: usize) -> Result<(), String> {
+        let base = self.stack.len();
+        for _ in 0..capacity {
+            self.stack.push(Value::new(value::UNSPECIFIED))
+        }
+        let result = self.alloc_vector(base, base + capacity);
+        let storage = self.stack.pop();
+        self.stack.truncate(base);
+        try!(result);
+        Ok(self.stack.push(storage.unwrap()))
+    }
+
+    /// Appends `stack[element]` to the growable vector `stack[dynvector]`.
+    pub fn dynvector_push(&mut self, dynvector: usize, element: usize) -> Result<(), String> {
+        let (len, capacity) = {
+            let object = try!(as_dynvector(&self.stack[dynvector]));
+            unsafe { ((*object).len(), (*object).capacity()) }
+        };
+        if len == capacity {
+            let base = self.stack.len();
+            let elements = self.dynvector_elements(&self.stack[dynvector].clone());
+            let capacity = ::std::cmp::max(2 * capacity, MIN_CAPACITY);
+            self.stack.extend_from_slice(&elements);
+            for _ in len..capacity {
+                self.stack.push(Value::new(value::UNSPECIFIED))
+            }
+            let result = self.alloc_vector(base, base + capacity);
+            let storage = self.stack.pop();
+            self.stack.truncate(base);
+            try!(result);
+            let storage = storage.unwrap();
+            let object = self.stack[dynvector].clone();
+            unsafe { (*(object.as_ptr() as *mut value::DynVector)).storage.set(storage.clone()) };
+            self.write_barrier(&object, &storage)
+        }
+        let object = self.stack[dynvector].clone();
+        let new = self.stack[element].clone();
+        let storage = unsafe {
+            let dynvector = object.as_ptr() as *mut value::DynVector;
+            (*dynvector).element(len).set(new.clone());
+            (*dynvector).len.set(Value::new_fixnum(len + 1));
+            (*dynvector).storage.clone()
+        };
+        self.write_barrier(&storage, &new);
+        Ok(())
+    }
+
+    /// Removes the last element of the growable vector `value`, which must
+    /// not be empty, and returns it.  The storage keeps its capacity.
+    pub fn dynvector_pop(&mut self, value: &Value) -> Result<Value, String> {
+        let dynvector = try!(as_dynvector(value));
+        unsafe {
+            let len = (*dynvector).len();
+            if len == 0 {
+                return Err("Growable vector is empty".to_owned())
+            }
+            let element = (*dynvector).element(len - 1).clone();
+            (*dynvector).element(len - 1).set(Value::new(value::UNSPECIFIED));
+            (*dynvector).len.set(Value::new_fixnum(len - 1));
+            Ok(element)
+        }
+    }
+
+    /// The elements of the growable vector `value`.
+    pub fn dynvector_elements(&self, value: &Value) -> Vec<Value> {
+        match value.kind() {
+            Kind::DynVector(dynvector) => unsafe {
+                (0..(*dynvector).len()).map(|i| (*dynvector).element(i).clone()).collect()
+            },
+            _ => bug!("dynvector_elements: not a growable vector"),
+        }
+    }
+
+    /// Replaces the growable vector on top of the stack by a vector of its
+    /// elements.
+    pub fn dynvector_to_vector(&mut self) -> Result<(), String> {
+        let top = self.stack.len() - 1;
+        let elements = self.dynvector_elements(&self.stack[top]);
+        self.stack.extend_from_slice(&elements);
+        let result = self.alloc_vector(top + 1, top + 1 + elements.len());
+        let vector = self.stack.pop();
+        self.stack.truncate(top + 1);
+        try!(result);
+        self.stack[top] = vector.unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Ephemerons and weak hash tables.
+//!
+//! An ephemeron is a key/value pair in which the key is held weakly, and
+//! the value is held only as long as the key is alive.  Weak hash tables are
+//! tables of ephemerons, keyed by object identity (`eq?`).
+//!
+//! Like symbols, the tables themselves are not allocated on the GC heap.
+//! They are stored on the Rust heap in a `WeakTables` object, and referred to
+//! from Scheme by a small `RustData` handle holding an index into it.
+//!
+//! During a collection, once the ordinary Cheney scan is finished:
+//!
+//! 1. The value of every entry whose key is live is relocated, and the new
+//!    part of tospace is scanned.  This may make more keys live, so this is
+//!    repeated until no more values are found.
+//! 2. Entries with dead keys are removed, tables with dead handles are
+//!    freed, and all remaining entries are rehashed, since their keys may
+//!    have moved.
+//!
+//! WARNING: keep this in sync with the GC!  This code does manual relocation
+//! of heap pointers!
+
+use std::collections::HashMap;
+use value::{self, Value};
+use super::{Heap, Condemned, relocate, scavange_all};
+
+/// A weak hash table.
+#[derive(Debug)]
+struct WeakTable {
+    /// The Scheme object referring to this table.  Not a root.
+    handle: Value,
+
+    /// The entries, indexed by the raw bits of their keys.
+    entries: HashMap<usize, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: Value,
+    value: Value,
+
+    /// Has the value been relocated during the current collection?
+    traced: bool,
+}
+
+/// All of the weak tables of a heap.
+#[derive(Debug, Default)]
+pub struct WeakTables {
+    tables: Vec<Option<WeakTable>>,
+    free: Vec<usize>,
+}
+
+/// Checks if `value` survived (or will survive) the current collection.
+pub unsafe fn is_live(value: &Value, condemned: Condemned) -> bool {
+    if value.immediatep() {
+        true
+    } else if value.tag() == value::Tags::Symbol {
+        (*(value.as_ptr() as *const ::symbol::Symbol)).alive.get()
+    } else {
+        condemned.survives(value.as_ptr())
+    }
+}
+
+/// The new location of a live value.
+pub unsafe fn forwarded(value: &Value, condemned: Condemned) -> Value {
+    if !value.immediatep() && value.tag() != value::Tags::Symbol &&
+       condemned.contains(value.as_ptr() as usize) {
+        (*value.as_ptr().offset(1)).clone()
+    } else {
+        value.clone()
+    }
+}
+
+impl WeakTables {
+    /// Traces the values of all entries with live keys, scanning tospace
+    /// (starting at word `scanned`) until a fixpoint is reached.
+    pub unsafe fn trace(&mut self,
+                        tospace: &mut Vec<Value>,
+                        mut scanned: usize,
+                        condemned: Condemned) {
+        loop {
+            let mut progress = false;
+            for table in self.tables.iter_mut().filter_map(|x| x.as_mut()) {
+                if !is_live(&table.handle, condemned) {
+                    continue
+                }
+                for entry in table.entries.values_mut() {
+                    if !entry.traced && is_live(&entry.key, condemned) {
+                        relocate(&mut entry.value, tospace, condemned);
+                        entry.traced = true;
+                        progress = true
+                    }
+                }
+            }
+            if !progress {
+                return
+            }
+            scavange_all(tospace, scanned, condemned);
+            scanned = tospace.len()
+        }
+    }
+
+    /// Removes dead entries and tables, and rehashes the survivors.  The
+    /// condemned spaces must not have been cleared yet.
+    pub unsafe fn sweep(&mut self, condemned: Condemned) {
+        for (index, slot) in self.tables.iter_mut().enumerate() {
+            let dead = match *slot {
+                Some(ref table) => !is_live(&table.handle, condemned),
+                None => continue,
+            };
+            if dead {
+                *slot = None;
+                self.free.push(index);
+                continue
+            }
+            let table = slot.as_mut().unwrap();
+            table.handle = forwarded(&table.handle, condemned);
+            let entries = ::std::mem::replace(&mut table.entries, HashMap::new());
+            for (_, entry) in entries {
+                if is_live(&entry.key, condemned) {
+                    let key = forwarded(&entry.key, condemned);
+                    table.entries.insert(key.get(), Entry {
+                        key: key,
+                        value: entry.value,
+                        traced: false,
+                    });
+                }
+            }
+        }
+    }
+
+    /// The index and handle of every table, with the keys and values of its
+    /// entries (for `Heap::verify`).
+    pub fn contents(&self) -> Vec<(usize, Value, Vec<Value>)> {
+        let tables = self.tables.iter().enumerate();
+        tables.filter_map(|(index, table)| table.as_ref().map(|table| (index, table)))
+              .map(|(index, table)| {
+                  let mut values = vec![];
+                  for entry in table.entries.values() {
+                      values.push(entry.key.clone());
+                      values.push(entry.value.clone())
+                  }
+                  (index, table.handle.clone(), values)
+              })
+              .collect()
+    }
+
+    fn insert(&mut self, table: WeakTable) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.tables[index] = Some(table);
+                index
+            }
+            None => {
+                self.tables.push(Some(table));
+                self.tables.len() - 1
+            }
+        }
+    }
+}
+
+impl Heap {
+    /// Allocates a new, empty weak hash table, and pushes it onto the stack.
+    pub fn alloc_weak_table(&mut self) -> Result<(), String> {
+        let (value_ptr, _) = try!(self.alloc_raw(3, value::HeaderTag::RustData));
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let index = self.weak_tables.insert(WeakTable {
+            handle: Value::new(ptr),
+            entries: HashMap::new(),
+        });
+        let space = self.space_mut();
+        space.extend_from_slice(&[Value::new(value::WEAK_TABLE_TYPE), Value::new(index)]);
+        Ok(self.stack.push(Value::new(ptr)))
+    }
+
+    fn weak_table_mut(&mut self, table: &Value) -> Result<&mut WeakTable, String> {
+        if table.raw_tag() != value::RUST_DATA_TAG {
+            return Err("Value is not a weak table".to_owned())
+        }
+        let index = unsafe {
+            let ptr = table.as_ptr();
+            if (*ptr.offset(1)).get() != value::WEAK_TABLE_TYPE {
+                return Err("Value is not a weak table".to_owned())
+            }
+            (*ptr.offset(2)).get()
+        };
+        Ok(self.weak_tables.tables[index].as_mut().expect("dangling weak table handle"))
+    }
+
+    /// Looks up `key` in a weak table.
+    pub fn weak_table_get(&mut self, table: &Value, key: &Value) -> Result<Option<Value>, String> {
+        let table = try!(self.weak_table_mut(table));
+        Ok(table.entries.get(&key.get()).map(|entry| entry.value.clone()))
+    }
+
+    /// Associates `key` with `value` in a weak table.
+    pub fn weak_table_set(&mut self, table: &Value, key: Value, value: Value) -> Result<(), String> {
+        let table = try!(self.weak_table_mut(table));
+        table.entries.insert(key.get(), Entry {
+            key: key,
+            value: value,
+            traced: false,
+        });
+        Ok(())
+    }
+
+    /// Removes `key` from a weak table.  Returns `true` if it was present.
+    pub fn weak_table_remove(&mut self, table: &Value, key: &Value) -> Result<bool, String> {
+        let table = try!(self.weak_table_mut(table));
+        Ok(table.entries.remove(&key.get()).is_some())
+    }
+
+    /// The number of entries in a weak table.  Entries with dead keys are
+    /// only removed by a collection.
+    pub fn weak_table_len(&mut self, table: &Value) -> Result<usize, String> {
+        self.weak_table_mut(table).map(|table| table.entries.len())
+    }
+}
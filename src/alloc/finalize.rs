@@ -0,0 +1,80 @@
+//! Finalizers.
+//!
+//! The heap keeps a list of objects with finalizers.  After the main GC,
+//! each entry that points into a condemned space is checked: if the object
+//! was copied, the entry is updated to point to the new copy; otherwise, the
+//! object is unreachable and its finalizer is run.
+//!
+//! Entries are not roots, so an object with a finalizer is collected as soon
+//! as nothing else refers to it.  Finalizers run after the collection has
+//! finished, so they never see the heap in an inconsistent state.
+
+use std::fmt;
+use value::{Value, HEADER_TAG};
+use super::Condemned;
+
+/// A finalizer: cleanup code run once after its object dies.
+pub type Finalizer = Box<dyn FnOnce()>;
+
+/// The objects with finalizers.
+#[derive(Default)]
+pub struct Finalizers {
+    entries: Vec<(Value, Finalizer)>,
+}
+
+impl fmt::Debug for Finalizers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Finalizers {{ {} entries }}", self.entries.len())
+    }
+}
+
+impl Finalizers {
+    /// Registers `finalizer` to be run when `object` dies.
+    pub fn register(&mut self, object: Value, finalizer: Finalizer) {
+        self.entries.push((object, finalizer))
+    }
+
+    /// The number of registered finalizers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Updates the entries after a collection, and returns the finalizers of
+    /// the objects that died.  The condemned spaces must not have been
+    /// cleared yet, since the forwarding pointers live there.
+    pub unsafe fn sweep(&mut self, condemned: Condemned) -> Vec<Finalizer> {
+        let mut dead = vec![];
+        let mut live = Vec::with_capacity(self.entries.len());
+        for (object, finalizer) in self.entries.drain(..) {
+            let pointer = object.as_ptr();
+            if !condemned.survives(pointer) {
+                dead.push(finalizer)
+            } else if !condemned.contains(pointer as usize) {
+                live.push((object, finalizer))
+            } else if (*pointer).get() == HEADER_TAG {
+                // Forwarding pointer: the object survived.
+                live.push(((*pointer.offset(1)).clone(), finalizer))
+            } else {
+                dead.push(finalizer)
+            }
+        }
+        self.entries = live;
+        dead
+    }
+
+    /// Takes every finalizer, dead or alive.  Used when the heap itself is
+    /// destroyed.
+    pub fn drain(&mut self) -> Vec<Finalizer> {
+        self.entries.drain(..).map(|(_, finalizer)| finalizer).collect()
+    }
+}
+
+/// Runs finalizers returned by `Finalizers::sweep`.
+pub fn run(finalizers: Vec<Finalizer>) {
+    if !finalizers.is_empty() {
+        debug!("Running {} finalizers", finalizers.len());
+    }
+    for finalizer in finalizers {
+        finalizer()
+    }
+}
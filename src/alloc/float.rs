@@ -0,0 +1,32 @@
+//! Boxed floats.
+//!
+//! A float is a `RustData` object laid out as a `value::Float`: header,
+//! `FLOAT_TYPE`, and the `f64` itself.  Like other leaf objects, floats are
+//! copied by `relocate` but never scanned.
+//!
+//! With the `nan-boxing` feature, floats are immediates, and nothing is
+//! allocated.
+
+use value;
+use super::Heap;
+
+impl Heap {
+    /// Allocates a boxed float holding `float`, and pushes it on the stack.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn alloc_float(&mut self, float: f64) -> Result<(), String> {
+        let words = size_of!(value::Float) / size_of!(value::Value);
+        let (value_ptr, final_len) = try!(self.alloc_raw(words, value::HeaderTag::RustData));
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let space = self.space_mut();
+        space.extend_from_slice(&[value::Value::new(value::FLOAT_TYPE), value::Value::new(0)]);
+        space.resize(final_len, value::Value::new(0));
+        unsafe { (*(value_ptr as *mut value::Float)).value = float }
+        Ok(self.stack.push(value::Value::new(ptr)))
+    }
+
+    /// Pushes the flonum `float` on the stack.
+    #[cfg(feature = "nan-boxing")]
+    pub fn alloc_float(&mut self, float: f64) -> Result<(), String> {
+        Ok(self.stack.push(value::Value::new_float(float)))
+    }
+}
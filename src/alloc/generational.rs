@@ -0,0 +1,115 @@
+//! Minor collections of the nursery.
+//!
+//! A generational heap allocates new objects in a small nursery.  When the
+//! nursery fills up, a minor collection copies every live nursery object
+//! onto the end of tospace (the old generation), using the same Cheney scan
+//! as a full collection, but only condemning the nursery.
+//!
+//! The roots of a minor collection are:
+//!
+//! 1. The stack, and the stacks of the suspended fibers.
+//! 2. The contents of every symbol (the global variables).
+//! 3. The remembered set: old objects that have been mutated since the last
+//!    minor collection, and so may point into the nursery.  These are
+//!    recorded by `Heap::write_barrier`.
+//!
+//! If the old generation does not have room for the entire nursery, a full
+//! collection is performed instead.
+
+use std::mem;
+use platform::Instant;
+use value::{self, Value};
+use super::{Heap, Condemned, relocate, scavange_heap, scavange_object, scavange_stack, collect};
+
+/// Performs a minor collection, promoting every live nursery object into
+/// the old generation.  Performs a full collection if the heap is not
+/// generational or the old generation is too full.
+pub fn collect_nursery(heap: &mut Heap) {
+    let nursery_len = match heap.nursery {
+        Some(ref nursery) => nursery.len(),
+        None => return collect(heap),
+    };
+    if heap.tospace.capacity() - heap.tospace.len() < nursery_len {
+        debug!("Old generation full, performing a full collection");
+        return collect(heap);
+    }
+    heap.hooks.run(super::GcPhase::Before, &heap.stats);
+    debug!("Initiated minor collection");
+    let start_time = Instant::now();
+    heap.clear_scratch_registers();
+    heap.verify_if_enabled("before a minor collection");
+    let mut nursery = heap.nursery.take().unwrap();
+    let condemned = Condemned::new(&nursery, &[]);
+    let start = heap.tospace.len();
+    unsafe {
+        // Symbols are never copied.  Marking them all alive keeps `relocate`
+        // from following symbol chains; their contents are roots instead.
+        for symbol in heap.symbol_table.contents.values() {
+            symbol.alive.set(true)
+        }
+        for symbol in heap.symbol_table.contents.values() {
+            relocate(symbol.contents.get(), &mut heap.tospace, condemned)
+        }
+        scavange_stack(&mut heap.stack,
+                       &mut heap.control,
+                       &heap.roots,
+                       &mut heap.tospace,
+                       condemned);
+        debug!("Stack scavanged");
+        let remembered_set = mem::replace(&mut heap.remembered_set, vec![]);
+        for object in &remembered_set {
+            scavange_object(object, &mut heap.tospace, condemned)
+        }
+        debug!("Remembered set scavanged");
+        scavange_heap(&mut heap.tospace, start, condemned);
+        super::trace_tables(&mut heap.weak_tables,
+                            &mut heap.hash_tables,
+                            &mut heap.tospace,
+                            condemned);
+        heap.weak_tables.sweep(condemned);
+        heap.hash_tables.sweep(condemned);
+        debug!("Promoted {} words", heap.tospace.len() - start);
+        for symbol in heap.symbol_table.contents.values() {
+            symbol.alive.set(false)
+        }
+        let dead = heap.finalizers.sweep(condemned);
+        if heap.config.poison {
+            super::poison(&mut nursery)
+        }
+        nursery.clear();
+        heap.nursery = Some(nursery);
+        heap.verify_if_enabled("after a minor collection");
+        let live_bytes = heap.words_in_use() * size_of!(Value);
+        heap.stats.record_collection(true, live_bytes, start_time.elapsed());
+        heap.hooks.run(super::GcPhase::After, &heap.stats);
+        super::finalize::run(dead)
+    }
+}
+
+impl Heap {
+    /// The write barrier.  Must be called after storing `new` into a field of
+    /// `object`, so that minor collections can find old-to-young pointers,
+    /// and incremental collections can find mutated objects.
+    pub fn write_barrier(&mut self, object: &Value, new: &Value) {
+        if object.immediatep() || object.tag() == value::Tags::Symbol {
+            return
+        }
+        if let Some(ref mut cycle) = self.cycle {
+            cycle.record_mutation(object)
+        }
+        let in_nursery = match self.nursery {
+            Some(ref nursery) => {
+                let condemned = Condemned::new(nursery, &[]);
+                move |value: &Value| condemned.contains(value.get() & !0b111)
+            }
+            None => return,
+        };
+        if new.immediatep() {
+            return
+        }
+        // Large objects are never in the nursery, so they are old.
+        if in_nursery(new) && !in_nursery(object) {
+            self.remembered_set.push(object.clone())
+        }
+    }
+}
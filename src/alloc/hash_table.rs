@@ -0,0 +1,536 @@
+//! Hash tables.
+//!
+//! Like weak tables, hash tables live on the Rust heap, in a `HashTables`
+//! object, and are referred to from Scheme by a `RustData` handle (a
+//! `value::HashTable`) holding an index into it.  The entries are strong:
+//! during a collection, once the ordinary Cheney scan is finished, the keys
+//! and values of every table whose handle is live are relocated, and the
+//! new part of tospace is scanned.  Tables whose handles are dead are freed.
+//!
+//! Each table is an open-addressing table with linear probing.  A table
+//! compares keys with `eq?`, `eqv?`, `equal?`, or a pair of procedures
+//! supplied by the user.  The built-in hashes are computed from the bits of
+//! the key, which for most heap objects means its address.  Each entry
+//! records whether its hash depends on an address; if a collection moves
+//! such a key, the table is marked stale, and is rehashed on its next use.
+//! User-supplied hash procedures must not depend on addresses.
+//!
+//! Entries are numbered in the order they were added.  In deterministic
+//! mode, they are listed in that order, rather than in the order of their
+//! slots, which depends on addresses.
+//!
+//! Until compiled procedures can be called from Rust, user-supplied hash and
+//! equality procedures must be primitives (or closures over primitives).
+//!
+//! WARNING: keep this in sync with the GC!  This code does manual relocation
+//! of heap pointers!
+
+use value::{self, Kind, Value};
+use builtins;
+use equiv::{self, eq_hash, eqv_hash, equal_hash};
+use super::{Heap, Condemned, relocate, scavange_all};
+use super::ephemeron::{is_live, forwarded};
+
+/// The initial number of slots of a table.  Always a power of 2.
+const INITIAL_SLOTS: usize = 8;
+
+/// How the keys of a hash table are compared.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashKind {
+    /// By identity (`eq?`).
+    Eq,
+
+    /// By `eqv?`: like `eq?`, but flonums are compared by value.
+    Eqv,
+
+    /// By `equal?`: pairs, vectors, strings, and bytevectors are compared
+    /// by contents.
+    Equal,
+
+    /// By user-supplied hash and equality procedures.
+    Custom,
+}
+
+#[derive(Clone, Debug)]
+enum Slot {
+    Empty,
+    Deleted,
+    Full(Entry),
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    key: Value,
+    value: Value,
+    hash: u64,
+
+    /// Does `hash` depend on the address of a heap object?
+    moves: bool,
+
+    /// The number of entries added to the table before this one.
+    serial: u64,
+}
+
+/// A hash table.
+#[derive(Debug)]
+struct HashTable {
+    /// The Scheme object referring to this table.  Not a root.
+    handle: Value,
+
+    kind: HashKind,
+
+    /// The hash and equality procedures of a `Custom` table.
+    hash: Value,
+    equality: Value,
+
+    /// The slots.  Their number is a power of 2.
+    slots: Vec<Slot>,
+
+    /// The number of full slots.
+    len: usize,
+
+    /// The number of deleted slots.
+    deleted: usize,
+
+    /// The number of entries ever added.
+    added: u64,
+
+    /// Has a key whose hash depends on its address moved?
+    stale: bool,
+
+    /// Have the entries been relocated during the current collection?
+    traced: bool,
+}
+
+/// All of the hash tables of a heap.
+#[derive(Debug, Default)]
+pub struct HashTables {
+    tables: Vec<Option<HashTable>>,
+    free: Vec<usize>,
+}
+
+impl HashKind {
+    /// The hash of `key`, and whether it depends on an address.  Must not
+    /// be called on `Custom`.
+    fn hash(self, key: &Value) -> (u64, bool) {
+        match self {
+            HashKind::Eq => eq_hash(key),
+            HashKind::Eqv => eqv_hash(key),
+            HashKind::Equal => equal_hash(key),
+            HashKind::Custom => bug!("custom hash tables have no built-in hash"),
+        }
+    }
+
+    /// Compares two keys.  Must not be called on `Custom`.
+    fn equal(self, first: &Value, second: &Value) -> bool {
+        match self {
+            HashKind::Eq => equiv::eq(first, second),
+            HashKind::Eqv => equiv::eqv(first, second),
+            HashKind::Equal => equiv::equal(first, second),
+            HashKind::Custom => bug!("custom hash tables have no built-in equality"),
+        }
+    }
+}
+
+impl HashTable {
+    fn mask(&self) -> usize {
+        self.slots.len() - 1
+    }
+
+    /// Rebuilds the table with `slots` slots, recomputing the hashes of
+    /// keys that have moved if `rehash` is set.
+    fn rebuild(&mut self, slots: usize, rehash: bool) {
+        let old = ::std::mem::replace(&mut self.slots, (0..slots).map(|_| Slot::Empty).collect());
+        self.deleted = 0;
+        self.stale = false;
+        for slot in old {
+            if let Slot::Full(mut entry) = slot {
+                if rehash && entry.moves {
+                    let (hash, moves) = self.kind.hash(&entry.key);
+                    entry.hash = hash;
+                    entry.moves = moves
+                }
+                let index = self.free_slot(entry.hash);
+                self.slots[index] = Slot::Full(entry)
+            }
+        }
+    }
+
+    /// The first empty or deleted slot for a key with hash `hash`.
+    fn free_slot(&self, hash: u64) -> usize {
+        let mut index = hash as usize & self.mask();
+        loop {
+            match self.slots[index] {
+                Slot::Full(_) => index = (index + 1) & self.mask(),
+                _ => return index,
+            }
+        }
+    }
+
+    /// Makes room for one more entry.
+    fn reserve(&mut self) {
+        if (self.len + self.deleted + 1) * 4 > self.slots.len() * 3 {
+            let slots = if (self.len + 1) * 2 > self.slots.len() {
+                self.slots.len() * 2
+            } else {
+                self.slots.len()
+            };
+            self.rebuild(slots, false)
+        }
+    }
+}
+
+impl HashTables {
+    /// Relocates the keys and values of every table with a live handle that
+    /// has not been traced yet, and scans tospace (starting at word
+    /// `scanned`).  Returns `false` if there was no such table.
+    pub unsafe fn trace(&mut self,
+                        tospace: &mut Vec<Value>,
+                        scanned: usize,
+                        condemned: Condemned)
+                        -> bool {
+        let mut progress = false;
+        for table in self.tables.iter_mut().filter_map(|x| x.as_mut()) {
+            if table.traced || !is_live(&table.handle, condemned) {
+                continue
+            }
+            relocate(&mut table.hash, tospace, condemned);
+            relocate(&mut table.equality, tospace, condemned);
+            for slot in &mut table.slots {
+                if let Slot::Full(ref mut entry) = *slot {
+                    let old = entry.key.get();
+                    relocate(&mut entry.key, tospace, condemned);
+                    relocate(&mut entry.value, tospace, condemned);
+                    if entry.moves && entry.key.get() != old {
+                        table.stale = true
+                    }
+                }
+            }
+            table.traced = true;
+            progress = true
+        }
+        if progress {
+            scavange_all(tospace, scanned, condemned)
+        }
+        progress
+    }
+
+    /// Frees the tables with dead handles.  The condemned spaces must not
+    /// have been cleared yet.
+    pub unsafe fn sweep(&mut self, condemned: Condemned) {
+        for (index, slot) in self.tables.iter_mut().enumerate() {
+            let dead = match *slot {
+                Some(ref table) => !is_live(&table.handle, condemned),
+                None => continue,
+            };
+            if dead {
+                *slot = None;
+                self.free.push(index);
+                continue
+            }
+            let table = slot.as_mut().unwrap();
+            table.handle = forwarded(&table.handle, condemned);
+            table.traced = false
+        }
+    }
+
+    /// The index and handle of every table, with the values it holds (for
+    /// `Heap::verify`).
+    pub fn contents(&self) -> Vec<(usize, Value, Vec<Value>)> {
+        let tables = self.tables.iter().enumerate();
+        tables.filter_map(|(index, table)| table.as_ref().map(|table| (index, table)))
+              .map(|(index, table)| {
+                  let mut values = vec![table.hash.clone(), table.equality.clone()];
+                  for slot in &table.slots {
+                      if let Slot::Full(ref entry) = *slot {
+                          values.push(entry.key.clone());
+                          values.push(entry.value.clone())
+                      }
+                  }
+                  (index, table.handle.clone(), values)
+              })
+              .collect()
+    }
+
+    fn insert(&mut self, table: HashTable) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.tables[index] = Some(table);
+                index
+            }
+            None => {
+                self.tables.push(Some(table));
+                self.tables.len() - 1
+            }
+        }
+    }
+
+    fn get_mut(&mut self, index: usize) -> &mut HashTable {
+        self.tables[index].as_mut().expect("dangling hash table handle")
+    }
+}
+
+/// The index of the hash table `table` refers to.
+fn table_index(table: &Value) -> Result<usize, String> {
+    match table.kind() {
+        Kind::HashTable(table) => Ok(unsafe { (*table).index }),
+        _ => Err("Value is not a hash table".to_owned()),
+    }
+}
+
+impl Heap {
+    /// Allocates a new, empty hash table of kind `kind`, which must not be
+    /// `Custom`, and pushes it onto the stack.
+    pub fn alloc_hash_table(&mut self, kind: HashKind) -> Result<(), String> {
+        assert!(kind != HashKind::Custom, "use alloc_custom_hash_table");
+        let unspecified = Value::new(value::UNSPECIFIED);
+        self.alloc_hash_table_in(kind, unspecified.clone(), unspecified)
+    }
+
+    /// Allocates a new, empty hash table using the hash procedure at stack
+    /// index `hash` and the equality procedure at stack index `equality`,
+    /// and pushes it onto the stack.
+    pub fn alloc_custom_hash_table(&mut self, hash: usize, equality: usize) -> Result<(), String> {
+        let (hash, equality) = (self.stack[hash].clone(), self.stack[equality].clone());
+        self.alloc_hash_table_in(HashKind::Custom, hash, equality)
+    }
+
+    fn alloc_hash_table_in(&mut self,
+                           kind: HashKind,
+                           hash: Value,
+                           equality: Value)
+                           -> Result<(), String> {
+        // Allocating the handle may collect, so the procedures must be
+        // rooted until the table holds them.
+        self.stack.push(hash);
+        self.stack.push(equality);
+        let (value_ptr, final_len) = match self.alloc_raw(3, value::HeaderTag::RustData) {
+            Ok(result) => result,
+            Err(e) => {
+                self.stack.pop();
+                self.stack.pop();
+                return Err(e)
+            }
+        };
+        let equality = self.stack.pop().unwrap();
+        let hash = self.stack.pop().unwrap();
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let index = self.hash_tables.insert(HashTable {
+            handle: Value::new(ptr),
+            kind: kind,
+            hash: hash,
+            equality: equality,
+            slots: (0..INITIAL_SLOTS).map(|_| Slot::Empty).collect(),
+            len: 0,
+            deleted: 0,
+            added: 0,
+            stale: false,
+            traced: false,
+        });
+        let space = self.space_mut();
+        space.extend_from_slice(&[Value::new(value::HASH_TABLE_TYPE), Value::new(index)]);
+        space.resize(final_len, Value::new(0));
+        Ok(self.stack.push(Value::new(ptr)))
+    }
+
+    /// The kind of the hash table `table`.
+    pub fn hash_table_kind(&mut self, table: &Value) -> Result<HashKind, String> {
+        let index = try!(table_index(table));
+        Ok(self.hash_tables.get_mut(index).kind)
+    }
+
+    /// The number of entries in the hash table `table`.
+    pub fn hash_table_len(&mut self, table: &Value) -> Result<usize, String> {
+        let index = try!(table_index(table));
+        Ok(self.hash_tables.get_mut(index).len)
+    }
+
+    /// The entries of the hash table `table`, in the order they were added
+    /// in deterministic mode.  The copies returned are invalidated by any
+    /// allocation.
+    pub fn hash_table_entries(&mut self, table: &Value) -> Result<Vec<(Value, Value)>, String> {
+        let index = try!(table_index(table));
+        let mut entries: Vec<_> = self.hash_tables
+                                      .get_mut(index)
+                                      .slots
+                                      .iter()
+                                      .filter_map(|slot| match *slot {
+                                          Slot::Full(ref entry) => Some(entry),
+                                          _ => None,
+                                      })
+                                      .collect();
+        if self.control.is_deterministic() {
+            entries.sort_by_key(|entry| entry.serial)
+        }
+        Ok(entries.into_iter().map(|entry| (entry.key.clone(), entry.value.clone())).collect())
+    }
+
+    /// Allocates a copy of the hash table at stack index `table`, with the
+    /// same kind and procedures, and pushes it onto the stack.
+    pub fn hash_table_copy(&mut self, table: usize) -> Result<(), String> {
+        let index = try!(table_index(&self.stack[table]));
+        let (kind, hash, equality) = {
+            let table = self.hash_tables.get_mut(index);
+            (table.kind, table.hash.clone(), table.equality.clone())
+        };
+        try!(self.alloc_hash_table_in(kind, hash, equality));
+        // The entries are copied after allocating, which may relocate them.
+        let copy = try!(table_index(&self.stack[self.stack.len() - 1]));
+        let (slots, len, deleted, added, stale) = {
+            let table = self.hash_tables.get_mut(index);
+            (table.slots.clone(), table.len, table.deleted, table.added, table.stale)
+        };
+        let copy = self.hash_tables.get_mut(copy);
+        copy.slots = slots;
+        copy.len = len;
+        copy.deleted = deleted;
+        copy.added = added;
+        copy.stale = stale;
+        Ok(())
+    }
+
+    /// Removes every entry from the hash table `table`.
+    pub fn hash_table_clear(&mut self, table: &Value) -> Result<(), String> {
+        let table = self.hash_tables.get_mut(try!(table_index(table)));
+        table.slots = (0..INITIAL_SLOTS).map(|_| Slot::Empty).collect();
+        table.len = 0;
+        table.deleted = 0;
+        table.stale = false;
+        Ok(())
+    }
+
+    /// Calls the procedure `procedure` with the arguments at the given stack
+    /// indices, and returns its result.
+    fn call_procedure(&mut self, procedure: Value, args: &[usize]) -> Result<Value, String> {
+        self.stack.push(procedure);
+        for &arg in args {
+            let arg = self.stack[arg].clone();
+            self.stack.push(arg)
+        }
+        let base = self.stack.len() - args.len() - 1;
+        match builtins::call(self, args.len()) {
+            Ok(()) => Ok(self.stack.pop().unwrap()),
+            Err(e) => {
+                self.stack.truncate(base);
+                Err(e)
+            }
+        }
+    }
+
+    /// Finds the key at stack index `key` in the hash table at stack index
+    /// `table`.  Returns the index of the table, the hash of the key and
+    /// whether it depends on an address, and the slot holding the key (or
+    /// `None`).
+    fn hash_table_find(&mut self,
+                       table: usize,
+                       key: usize)
+                       -> Result<(usize, (u64, bool), Option<usize>), String> {
+        let index = try!(table_index(&self.stack[table]));
+        let kind = {
+            let table = self.hash_tables.get_mut(index);
+            if table.stale {
+                let slots = table.slots.len();
+                table.rebuild(slots, true)
+            }
+            table.kind
+        };
+        let hash = if kind == HashKind::Custom {
+            let procedure = self.hash_tables.get_mut(index).hash.clone();
+            let hash = try!(self.call_procedure(procedure, &[key]));
+            let hash = try!(hash.as_fixnum()
+                                .map_err(|_| "hash procedure returned a non-fixnum".to_owned()));
+            (hash as u64, false)
+        } else {
+            kind.hash(&self.stack[key])
+        };
+        let mut slot = hash.0 as usize & self.hash_tables.get_mut(index).mask();
+        loop {
+            let (candidate, slots) = {
+                let table = self.hash_tables.get_mut(index);
+                let candidate = match table.slots[slot] {
+                    Slot::Empty => return Ok((index, hash, None)),
+                    Slot::Full(ref entry) if entry.hash == hash.0 => entry.key.clone(),
+                    _ => Value::new(value::UNSPECIFIED),
+                };
+                (candidate, table.slots.len())
+            };
+            if candidate.get() != value::UNSPECIFIED {
+                let found = if kind == HashKind::Custom {
+                    let procedure = self.hash_tables.get_mut(index).equality.clone();
+                    self.stack.push(candidate);
+                    let candidate = self.stack.len() - 1;
+                    let result = self.call_procedure(procedure, &[key, candidate]);
+                    self.stack.pop();
+                    if self.hash_tables.get_mut(index).slots.len() != slots {
+                        return Err("hash table modified by its equality procedure".to_owned())
+                    }
+                    try!(result).get() != value::FALSE
+                } else {
+                    kind.equal(&self.stack[key], &candidate)
+                };
+                if found {
+                    return Ok((index, hash, Some(slot)))
+                }
+            }
+            slot = (slot + 1) & (slots - 1)
+        }
+    }
+
+    /// Looks up the key at stack index `key` in the hash table at stack
+    /// index `table`.
+    pub fn hash_table_get(&mut self, table: usize, key: usize) -> Result<Option<Value>, String> {
+        let (index, _, slot) = try!(self.hash_table_find(table, key));
+        Ok(slot.map(|slot| match self.hash_tables.get_mut(index).slots[slot] {
+            Slot::Full(ref entry) => entry.value.clone(),
+            _ => bug!("hash_table_find returned an empty slot"),
+        }))
+    }
+
+    /// Associates the key at stack index `key` with the value at stack index
+    /// `value` in the hash table at stack index `table`.
+    pub fn hash_table_set(&mut self, table: usize, key: usize, value: usize) -> Result<(), String> {
+        let (index, (hash, moves), slot) = try!(self.hash_table_find(table, key));
+        let (key, value) = (self.stack[key].clone(), self.stack[value].clone());
+        let table = self.hash_tables.get_mut(index);
+        match slot {
+            Some(slot) => {
+                if let Slot::Full(ref mut entry) = table.slots[slot] {
+                    entry.value = value
+                }
+            }
+            None => {
+                table.reserve();
+                let slot = table.free_slot(hash);
+                if let Slot::Deleted = table.slots[slot] {
+                    table.deleted -= 1
+                }
+                table.slots[slot] = Slot::Full(Entry {
+                    key: key,
+                    value: value,
+                    hash: hash,
+                    moves: moves,
+                    serial: table.added,
+                });
+                table.added += 1;
+                table.len += 1
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the key at stack index `key` from the hash table at stack
+    /// index `table`.  Returns `true` if it was present.
+    pub fn hash_table_remove(&mut self, table: usize, key: usize) -> Result<bool, String> {
+        let (index, _, slot) = try!(self.hash_table_find(table, key));
+        let table = self.hash_tables.get_mut(index);
+        Ok(match slot {
+            Some(slot) => {
+                table.slots[slot] = Slot::Deleted;
+                table.len -= 1;
+                table.deleted += 1;
+                true
+            }
+            None => false,
+        })
+    }
+}
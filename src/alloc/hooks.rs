@@ -0,0 +1,55 @@
+//! Callbacks run around collections.
+
+use std::fmt;
+use super::HeapStats;
+
+/// When a GC hook is being run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcPhase {
+    /// Before a collection starts.
+    Before,
+
+    /// After a collection has finished (but before finalizers are run).
+    After,
+}
+
+/// A GC hook.  Hooks are passed the heap statistics as of the phase; they
+/// cannot access the heap itself, which is inconsistent during a collection.
+pub type GcHook = Box<dyn FnMut(GcPhase, &HeapStats)>;
+
+/// The hooks registered on a heap.
+#[derive(Default)]
+pub struct GcHooks {
+    hooks: Vec<(usize, GcHook)>,
+    next_id: usize,
+}
+
+impl fmt::Debug for GcHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GcHooks {{ {} hooks }}", self.hooks.len())
+    }
+}
+
+impl GcHooks {
+    /// Adds a hook, returning an ID that can be used to remove it.
+    pub fn add(&mut self, hook: GcHook) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.hooks.push((id, hook));
+        id
+    }
+
+    /// Removes a hook.  Returns `false` if there was no such hook.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.hooks.len();
+        self.hooks.retain(|&(hook_id, _)| hook_id != id);
+        self.hooks.len() != len
+    }
+
+    /// Runs every hook, in the order they were added.
+    pub fn run(&mut self, phase: GcPhase, stats: &HeapStats) {
+        for &mut (_, ref mut hook) in &mut self.hooks {
+            hook(phase, stats)
+        }
+    }
+}
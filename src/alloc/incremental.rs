@@ -0,0 +1,246 @@
+//! Incremental collection.
+//!
+//! When `HeapConfig::max_pause_us` is set, most of the copying work of a full
+//! collection is spread over allocations, using replication (as described by
+//! Nettles and O'Toole):
+//!
+//! 1. A cycle starts when tospace is half full.  The collector replicates
+//!    the objects reachable from the roots into a new space.  Unlike
+//!    `relocate`, replication does not overwrite the original object with a
+//!    forwarding pointer: the forwarding addresses are kept in a side table,
+//!    so the mutator keeps using the originals undisturbed.
+//! 2. Each allocation scans replicas for at most `max_pause_us`
+//!    microseconds, replicating the objects they refer to.  Mutations of
+//!    objects are recorded by the write barrier.
+//! 3. Once every replica has been scanned, the collector "flips": mutated
+//!    objects are copied into their replicas again, the originals are
+//!    overwritten with forwarding pointers, and an ordinary (stop-the-world)
+//!    collection finishes the job, starting from the roots as they are now.
+//!    Only objects allocated or made reachable during the cycle are copied
+//!    at this point.
+//!
+//! Objects that die during a cycle survive until the next one.
+//!
+//! Incremental collection is not supported by generational heaps, which
+//! already have short pauses.
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::slice;
+use std::time::Duration;
+use platform::Instant;
+use value::{self, Value, HEADER_TAG, HEADER_SIZE};
+use bytecode;
+use super::{Heap, align_word_size, evacuate, relocate, scavange_object, PAIR,
+            VECTOR, BYTECODE, RECORD, CLOSURE};
+
+/// The number of objects scanned between checks of the clock.
+const OBJECTS_PER_CHECK: usize = 64;
+
+/// The state of an incremental collection cycle.
+#[derive(Debug)]
+pub struct Cycle {
+    /// The replicas.  Becomes tospace at the flip.
+    replica: Vec<Value>,
+
+    /// The number of words of `replica` that have been scanned.
+    scanned: usize,
+
+    /// The space being replicated, as an address range.  Since tospace is
+    /// never reallocated, this covers objects allocated during the cycle.
+    space: (usize, usize),
+
+    /// Maps the addresses of replicated objects to their replicas.
+    forward: HashMap<usize, Value>,
+
+    /// Objects mutated during the cycle.
+    mutated: Vec<Value>,
+
+    /// Pointers to objects outside of the space being replicated (symbols
+    /// and large objects) found by scanning replicas.  These are relocated
+    /// at the flip, to mark their targets as alive.
+    deferred: HashSet<usize>,
+}
+
+impl Cycle {
+    /// Records that `object` has been mutated.
+    pub fn record_mutation(&mut self, object: &Value) {
+        self.mutated.push(object.clone())
+    }
+
+    fn in_space(&self, ptr: usize) -> bool {
+        ptr >= self.space.0 && ptr < self.space.1
+    }
+
+    /// Replicates the object `*current` points to, if it has not been
+    /// replicated yet, and makes `*current` point to the replica.
+    unsafe fn replicate(&mut self, current: *mut Value) {
+        let size = match (*current).size() {
+            None => return,
+            Some(size) => size,
+        };
+        let pointer = (*current).as_ptr();
+        if size == 0 && (*current).tag() == value::Tags::Symbol ||
+           !self.in_space(pointer as usize) {
+            self.deferred.insert((*current).get());
+            return
+        }
+        if let Some(replica) = self.forward.get(&(pointer as usize)) {
+            *current = replica.clone();
+            return
+        }
+        let amount_to_copy = align_word_size(size);
+        let len = self.replica.len();
+        debug_assert!(len + amount_to_copy <= self.replica.capacity(),
+                      "internal error: replicate: replica space exhausted");
+        let end = self.replica.as_ptr().offset(len as isize);
+        self.replica.extend_from_slice(slice::from_raw_parts(pointer, amount_to_copy));
+        let new = Value::new(end as usize | ((*current).get() & 0b111));
+        self.forward.insert(pointer as usize, new.clone());
+        *current = new
+    }
+
+    /// Replicates the object a root refers to, without changing the root.
+    unsafe fn replicate_root(&mut self, root: &Value) {
+        let mut root = root.clone();
+        self.replicate(&mut root)
+    }
+
+    /// Scans one object in the replicas.
+    pub unsafe fn scan_object(&mut self) {
+        let object = self.replica.as_mut_ptr().offset(self.scanned as isize);
+        let header = (*object).get();
+        let size = header & HEADER_SIZE;
+        debug_assert!(size > 0);
+        match header & HEADER_TAG {
+            PAIR | VECTOR | RECORD | CLOSURE => {
+                for i in 1..size {
+                    self.replicate(object.offset(i as isize))
+                }
+            }
+            BYTECODE => {
+                let bco = object as *mut bytecode::BCO;
+                self.replicate(bytecode::get_constants_vector(&*bco).get())
+            }
+            _ => /* Not scanned */ {}
+        }
+        self.scanned += align_word_size(size)
+    }
+
+    /// Checks if every replica has been scanned.
+    pub fn is_scanned(&self) -> bool {
+        self.scanned == self.replica.len()
+    }
+}
+
+/// Starts a collection cycle, if none is in progress.
+pub fn start(heap: &mut Heap) {
+    if heap.cycle.is_some() {
+        return
+    }
+    debug!("Starting an incremental collection cycle");
+    let start_time = Instant::now();
+    heap.clear_scratch_registers();
+    heap.verify_if_enabled("before an incremental collection cycle");
+    // Everything in tospace might be replicated, and the replicas must never
+    // be reallocated during the cycle.
+    let capacity = heap.tospace.capacity();
+    let mut replica = mem::replace(&mut heap.fromspace, vec![]);
+    replica.clear();
+    replica.reserve(::std::cmp::max(heap.config.target_words(capacity), capacity));
+    let space = heap.tospace.as_ptr() as usize;
+    let mut cycle = Cycle {
+        replica: replica,
+        scanned: 0,
+        space: (space, space + capacity * size_of!(Value)),
+        forward: HashMap::new(),
+        mutated: vec![],
+        deferred: HashSet::new(),
+    };
+    unsafe {
+        let fibers = heap.control
+                         .fibers
+                         .iter()
+                         .chain(heap.control.suspended.iter())
+                         .flat_map(|fiber| fiber.stack.iter());
+        for root in heap.stack.iter().chain(fibers).chain(heap.roots.borrow().iter()) {
+            cycle.replicate_root(root)
+        }
+    }
+    heap.cycle = Some(cycle);
+    heap.stats.record_slice(start_time.elapsed())
+}
+
+/// Scans replicas for at most `max_pause_us` microseconds, and flips if the
+/// cycle is complete.
+pub fn step(heap: &mut Heap) {
+    let start_time = Instant::now();
+    let budget = Duration::from_micros(heap.config.max_pause_us.unwrap_or(0));
+    let done = match heap.cycle {
+        Some(ref mut cycle) => unsafe {
+            'scan: while !cycle.is_scanned() {
+                for _ in 0..OBJECTS_PER_CHECK {
+                    if cycle.is_scanned() {
+                        break 'scan
+                    }
+                    cycle.scan_object()
+                }
+                if start_time.elapsed() >= budget {
+                    break
+                }
+            }
+            cycle.is_scanned()
+        },
+        None => return,
+    };
+    heap.stats.record_slice(start_time.elapsed());
+    if done {
+        flip(heap, 0)
+    }
+}
+
+/// Finishes the current cycle, leaving at least `room` words free in
+/// tospace.
+pub fn flip(heap: &mut Heap, room: usize) {
+    let mut cycle = heap.cycle.take().expect("flip without a collection cycle");
+    debug!("Flipping after an incremental collection cycle");
+    heap.hooks.run(super::GcPhase::Before, &heap.stats);
+    let start_time = Instant::now();
+    heap.clear_scratch_registers();
+    heap.verify_if_enabled("before a flip");
+    unsafe {
+        // Bring the replicas of mutated objects up to date.  This must be
+        // done before the forwarding pointers clobber the originals.
+        let mut rescan = vec![];
+        for object in cycle.mutated.drain(..) {
+            let pointer = object.as_ptr();
+            if let Some(replica) = cycle.forward.get(&(pointer as usize)) {
+                let size = (*pointer).get() & HEADER_SIZE;
+                let target = replica.as_ptr();
+                for i in 1..size as isize {
+                    *target.offset(i) = (*pointer.offset(i)).clone()
+                }
+                rescan.push(replica.clone())
+            }
+        }
+        for (&pointer, replica) in &cycle.forward {
+            let pointer = pointer as *mut Value;
+            *pointer = Value::new(HEADER_TAG);
+            *pointer.offset(1) = replica.clone();
+        }
+        mem::swap(&mut heap.tospace, &mut heap.fromspace);
+        heap.tospace = cycle.replica;
+        let deferred = cycle.deferred;
+        evacuate(heap, cycle.scanned, start_time, |tospace, condemned| {
+            for object in &rescan {
+                scavange_object(object, tospace, condemned)
+            }
+            for &value in &deferred {
+                relocate(&mut Value::new(value), tospace, condemned)
+            }
+        });
+    }
+    if heap.tospace.capacity() - heap.tospace.len() < room {
+        super::collect_with_room(heap, room)
+    }
+}
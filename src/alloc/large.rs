@@ -0,0 +1,143 @@
+//! The large object space.
+//!
+//! Objects at least `HeapConfig::large_object_bytes` in size are not
+//! allocated in tospace.  Instead, each one gets its own allocation, and is
+//! never moved: copying a multi-megabyte vector on every collection would be
+//! wasteful.  Objects that must never move, such as pinned bytevectors, are
+//! allocated here too, whatever their size.
+//!
+//! Large objects are managed by mark-and-sweep, during full collections
+//! only (minor collections treat them as part of the old generation):
+//!
+//! 1. When `relocate` finds a pointer to an unmarked large object, the
+//!    object is marked and added to the gray list, instead of being copied.
+//! 2. Gray objects are scanned (like tospace is scanned by the Cheney
+//!    algorithm) until both tospace and the gray list are exhausted.
+//! 3. After the collection, unmarked large objects are freed.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use value::Value;
+use super::{Condemned, scavange_object};
+
+#[derive(Debug)]
+struct LargeObject {
+    /// The object.  Never reallocated.
+    words: Vec<Value>,
+
+    /// Has this object been found to be live during the current collection?
+    marked: Cell<bool>,
+}
+
+/// The large object space.
+#[derive(Debug, Default)]
+pub struct LargeObjectSpace {
+    /// The objects, indexed by address.
+    objects: BTreeMap<usize, LargeObject>,
+
+    /// Objects that have been marked, but not yet scanned.
+    gray: RefCell<Vec<*mut Value>>,
+
+    /// The address of the most recently allocated object.
+    last: usize,
+
+    /// The total size of all objects, in words.
+    words: usize,
+}
+
+impl LargeObjectSpace {
+    /// Allocates room for an object of `words` words, returning the (empty)
+    /// space to fill it into.
+    pub fn alloc(&mut self, words: usize) -> &mut Vec<Value> {
+        let object = LargeObject {
+            words: Vec::with_capacity(words),
+            marked: Cell::new(false),
+        };
+        let address = object.words.as_ptr() as usize;
+        debug_assert!(address & 0b111 == 0, "misaligned large object");
+        self.objects.insert(address, object);
+        self.last = address;
+        self.words += words;
+        self.last_mut()
+    }
+
+    /// The most recently allocated object.
+    pub fn last_mut(&mut self) -> &mut Vec<Value> {
+        &mut self.objects.get_mut(&self.last).expect("no large object allocated").words
+    }
+
+    /// The total size of all large objects, in words.
+    pub fn words(&self) -> usize {
+        self.words
+    }
+
+    fn find(&self, ptr: usize) -> Option<&LargeObject> {
+        self.objects
+            .range(..ptr + 1)
+            .next_back()
+            .and_then(|(&start, object)| if ptr < start + object.words.capacity() * size_of!(Value) {
+                Some(object)
+            } else {
+                None
+            })
+    }
+
+    /// Checks if `ptr` points into a large object.
+    pub fn contains(&self, ptr: usize) -> bool {
+        self.find(ptr).is_some()
+    }
+
+    /// Checks if `ptr` points into a large object that has been marked.
+    pub fn is_marked(&self, ptr: usize) -> bool {
+        self.find(ptr).map_or(false, |object| object.marked.get())
+    }
+
+    /// Marks the large object `ptr` points into, if any.  Returns `false` if
+    /// `ptr` does not point into a large object.
+    pub fn mark(&self, ptr: usize) -> bool {
+        match self.find(ptr) {
+            Some(object) => {
+                if !object.marked.get() {
+                    object.marked.set(true);
+                    self.gray.borrow_mut().push(object.words.as_ptr() as *mut Value)
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Scans every gray object.  Returns `false` if there were none.
+    pub unsafe fn scan_gray(&self, tospace: &mut Vec<Value>, condemned: Condemned) -> bool {
+        let mut found = false;
+        loop {
+            let object = match self.gray.borrow_mut().pop() {
+                Some(object) => object,
+                None => return found,
+            };
+            found = true;
+            scavange_object(&Value::new(object as usize), tospace, condemned)
+        }
+    }
+
+    /// Frees every unmarked object, and unmarks the rest.
+    pub fn sweep(&mut self) {
+        let dead: Vec<usize> = self.objects
+                                   .iter()
+                                   .filter(|&(_, object)| !object.marked.get())
+                                   .map(|(&address, _)| address)
+                                   .collect();
+        for address in dead {
+            let object = self.objects.remove(&address).unwrap();
+            self.words -= object.words.capacity();
+        }
+        for object in self.objects.values() {
+            object.marked.set(false)
+        }
+    }
+
+    /// The large objects, for consistency checks.
+    pub fn spaces(&self) -> Vec<&[Value]> {
+        self.objects.values().map(|object| &object.words[..]).collect()
+    }
+}
@@ -0,0 +1,90 @@
+//! Source locations.
+//!
+//! The reader records where each list it reads starts, so that errors can
+//! be reported in terms of the source.  A location is stored as a vector
+//! `#(file line column)`, where `file` is a symbol, in a weak table keyed by
+//! the first pair of the list.  Since the table holds its keys weakly, a
+//! location lives exactly as long as its list, and does not change how the
+//! list prints or compares.
+
+use std::fmt;
+use std::rc::Rc;
+use value::{Kind, Value};
+use super::Heap;
+
+/// A position in a source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The name of the file.
+    pub file: Rc<String>,
+
+    /// The line, counting from 1.
+    pub line: usize,
+
+    /// The column, in characters, counting from 1.
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+impl Heap {
+    /// The weak table of locations, which is created on first use.
+    fn location_table(&mut self) -> Result<Value, String> {
+        if let Some(ref table) = self.locations {
+            return Ok(table.get())
+        }
+        try!(self.alloc_weak_table());
+        let table = try!(self.root_top());
+        let value = table.get();
+        self.locations = Some(table);
+        Ok(value)
+    }
+
+    /// Records that the pair at `stack[index]` was read at `location`.
+    pub fn set_location(&mut self, index: usize, location: &Location) -> Result<(), String> {
+        try!(self.location_table());
+        let base = self.stack.len();
+        self.intern(&location.file);
+        self.stack.push(Value::new_fixnum(location.line));
+        self.stack.push(Value::new_fixnum(location.column));
+        if let Err(e) = self.alloc_vector(base, base + 3) {
+            self.stack.truncate(base);
+            return Err(e)
+        }
+        let vector = self.stack.pop().unwrap();
+        self.stack.truncate(base);
+        // The allocation may have moved the table and the key.
+        let (table, key) = (try!(self.location_table()), self.stack[index].clone());
+        self.weak_table_set(&table, key, vector)
+    }
+
+    /// The location `value` was read at, if known.
+    pub fn location(&mut self, value: &Value) -> Option<Location> {
+        let table = match self.locations {
+            Some(ref table) => table.get(),
+            None => return None,
+        };
+        let vector = match self.weak_table_get(&table, value) {
+            Ok(Some(vector)) => vector,
+            _ => return None,
+        };
+        match vector.kind() {
+            Kind::Vector(vector) => unsafe {
+                let file = match (*vector).element(0).kind() {
+                    Kind::Symbol(symbol) => (*symbol).name(),
+                    _ => bug!("bad file name in source location"),
+                };
+                Some(Location {
+                    file: file,
+                    line: (*vector).element(1).as_fixnum().unwrap(),
+                    column: (*vector).element(2).as_fixnum().unwrap(),
+                })
+            },
+            _ => bug!("bad source location"),
+        }
+    }
+}
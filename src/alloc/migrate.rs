@@ -0,0 +1,19 @@
+//! Copying values from one heap to another.
+//!
+//! The copy is made through a `message::Message`, so it has the same
+//! structure as the original: objects reachable along several paths are
+//! copied once, and cycles are kept.  Symbols are interned in the other
+//! heap.  Objects that cannot be copied, like records and hash tables, make
+//! the whole copy fail, leaving the other heap as it was.
+
+use message::Message;
+use value::Value;
+use super::Heap;
+
+impl Heap {
+    /// Copies `value`, which belongs to this heap, into `other`, and pushes
+    /// the copy on the stack of `other`.
+    pub fn migrate(&self, value: &Value, other: &mut Heap) -> Result<(), String> {
+        try!(Message::new(value)).push_to(other)
+    }
+}
@@ -8,7 +8,8 @@
 //!
 //! Finalizers for custom objects are supported by:
 //!
-//! 1. Create an intrusive linked list of objects with finalizers.
+//! 1. Keep a list of objects with finalizers (see `finalize`), registered
+//!    with `Heap::register_finalizer`.
 //! 2. During GC, do not relocate the finalizer list pointers.
 //! 3. After the main GC, traverse the list of object with finalizers.
 //!    Relocate the pointers that point to forwarding pointers.  Execute
@@ -29,16 +30,56 @@
 //! TODO finish this.
 
 extern crate libc;
-use std::fs::File;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::mem;
 use std::ptr;
+use std::rc::Rc;
 use std::slice;
+use platform::Instant;
 use super::value;
-use value::{Value, SIZEOF_PAIR, HEADER_TAG, SYMBOL_TAG, Kind};
+use value::{Value, SIZEOF_PAIR, HEADER_TAG, HEADER_SIZE, Kind};
 use symbol;
 use bytecode;
+use builtins;
+use compiler;
+use interp;
 
+mod bytevector;
+mod code;
+mod config;
 mod debug;
+mod dump;
+mod dynvector;
+mod ephemeron;
+mod finalize;
+mod float;
+mod generational;
+mod hash_table;
+mod hooks;
+mod incremental;
+mod large;
+mod location;
+mod migrate;
+mod port;
+mod random;
+mod roots;
+mod rust_data;
+mod stack_map;
+mod stats;
+mod string;
+mod verify;
+
+pub use self::generational::collect_nursery;
+pub use self::finalize::Finalizer;
+pub use self::roots::{Root, RootTable};
+pub use self::config::{HeapConfig, OutOfMemoryHandler};
+pub use self::stats::{HeapStats, as_micros};
+pub use self::hooks::GcPhase;
+pub use self::dump::{ObjectInfo, Snapshot};
+pub use self::hash_table::HashKind;
+pub use self::location::Location;
 
 //mod iter;
 /// An allocator for `RustyScheme` objects
@@ -62,19 +103,26 @@ pub trait Allocator {
     fn alloc_hash_table(&mut self, size: usize) -> value::HashTable;
 
     /// Allocates a port
-    fn alloc_port(&mut self, File) -> value::IOPort;
+    fn alloc_port(&mut self, port: ::port::Port) -> value::Port;
 
-    /// Allocates a rustdata, which contains an arbitrary Rust object
-    fn alloc_rustdata<T>(&mut self, object: &T) -> value::RustData;
+    /// Allocates a rustdata, which owns an arbitrary Rust object
+    fn alloc_rustdata(&mut self, object: Box<dyn Any>) -> value::RustData;
 
-    // /// Allocates a boxed float on the top of the stack.
-    // fn alloc_float(&mut self, float: f64) -> value::Float;
+    /// Allocates a boxed float on the top of the stack.
+    fn alloc_float(&mut self, float: f64) -> value::Float;
 }
 
 const PAIR: usize = value::HeaderTag::Pair as usize;
 const RUSTDATA: usize = value::HeaderTag::RustData as usize;
 const VECTOR: usize = value::HeaderTag::Vector as usize;
 const BYTECODE: usize = value::HeaderTag::Bytecode as usize;
+const RECORD: usize = value::HeaderTag::Record as usize;
+const CLOSURE: usize = value::HeaderTag::Closure as usize;
+
+/// The word written over the spaces emptied by a collection when
+/// `HeapConfig::poison` is set.  As a value, it has the reserved tag
+/// `value::FUNCTION_TAG`, so that `Heap::verify` rejects it.
+const POISON: usize = 0xdead_bee2;
 
 /// An instance of the garbage-collected Scheme heap.
 #[derive(Debug)]
@@ -87,17 +135,134 @@ pub struct Heap {
 
     /// The fromspace.
     fromspace: Vec<Value>,
-    /// The environment of the current closure.
-    pub environment: *mut value::Vector,
-
-    /// The constants vector of the current closure.
-    pub constants: *const value::Vector,
-
     /// The execution stack.
     pub stack: self::Stack,
 
     /// The approximate amount of memory used last
-    last_mem_use: usize
+    last_mem_use: usize,
+
+    /// The nursery, if this heap is generational.  New objects are allocated
+    /// here, and survivors are promoted into tospace by a minor collection.
+    nursery: Option<Vec<Value>>,
+
+    /// Objects in the old generation that have been mutated since the last
+    /// minor collection, and so may point into the nursery.
+    remembered_set: Vec<Value>,
+
+    /// The space that the most recent call to `alloc_raw` allocated in.
+    target: Space,
+
+    /// Objects with finalizers.
+    finalizers: finalize::Finalizers,
+
+    /// The weak hash tables.
+    weak_tables: ephemeron::WeakTables,
+
+    /// The hash tables.
+    hash_tables: hash_table::HashTables,
+
+    /// The persistent roots.
+    roots: Rc<RefCell<RootTable>>,
+
+    /// The sizing policy.
+    config: HeapConfig,
+
+    /// Statistics, for tuning.
+    stats: HeapStats,
+
+    /// Callbacks run before and after each collection.
+    hooks: hooks::GcHooks,
+
+    /// The large object space.
+    large_objects: large::LargeObjectSpace,
+
+    /// The incremental collection cycle in progress, if any.
+    cycle: Option<incremental::Cycle>,
+
+    /// The weak table of source locations, once one has been recorded.
+    locations: Option<Root>,
+
+    /// The symbols naming global variables that have been assigned, by
+    /// address.  The symbol table is weak, so these are rooted to keep
+    /// their values.
+    globals: HashMap<usize, Root>,
+
+    /// The interpreter state that is not on the stack.
+    pub control: interp::Control,
+
+    /// The macros defined at top level.
+    pub macros: compiler::Macros,
+
+    /// The Rust closures registered as procedures.
+    pub natives: builtins::Natives,
+}
+
+/// A space that objects can be allocated in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Space {
+    /// The old generation (tospace).  The only space of a non-generational
+    /// heap.
+    Old,
+
+    /// The nursery of a generational heap.
+    Nursery,
+
+    /// The large object space.
+    Large,
+}
+
+/// The address ranges being evacuated by a collection.
+///
+/// Pointers into these ranges are copied into tospace by `relocate`.  All
+/// other heap pointers are left alone: during a minor collection, these are
+/// pointers into the old generation.  If a large object space is being
+/// collected (by a full collection), pointers into it are marked.
+#[derive(Copy, Clone, Debug)]
+pub struct Condemned<'a> {
+    ranges: [(usize, usize); 2],
+    large: Option<&'a large::LargeObjectSpace>,
+}
+
+impl Condemned<'static> {
+    /// Condemns `first` and `second`.  Either may be empty.
+    fn new(first: &[Value], second: &[Value]) -> Self {
+        let range = |space: &[Value]| {
+            let start = space.as_ptr() as usize;
+            (start, start + space.len() * size_of!(Value))
+        };
+        Condemned {
+            ranges: [range(first), range(second)],
+            large: None,
+        }
+    }
+}
+
+impl<'a> Condemned<'a> {
+    /// Also collects the large object space `large`.
+    fn with_large<'b>(self, large: &'b large::LargeObjectSpace) -> Condemned<'b> {
+        Condemned {
+            ranges: self.ranges,
+            large: Some(large),
+        }
+    }
+
+    /// Checks if `ptr` points into a condemned space.
+    fn contains(&self, ptr: usize) -> bool {
+        self.ranges.iter().any(|&(start, end)| ptr >= start && ptr < end)
+    }
+
+    /// Checks if the object at `ptr` survived the collection so far: it was
+    /// copied, marked, or is not being collected at all.
+    unsafe fn survives(&self, ptr: *mut Value) -> bool {
+        if self.contains(ptr as usize) {
+            (*ptr).get() == HEADER_TAG
+        } else {
+            match self.large {
+                Some(large) if large.contains(ptr as usize) => large.is_marked(ptr as usize),
+                _ => true,
+            }
+        }
+    }
 }
 
 #[repr(packed)]
@@ -118,26 +283,6 @@ pub struct FinalizedObject {
     payload: Drop,
 }
 
-use std::cell;
-
-/// A GC root.
-///
-/// A `Root` is a handle to an object on the garbage-collected Scheme heap.
-/// The Scheme garbage collector knows about `Root`s, and ensures that they
-/// stay valid even when a garbage collection occurs.  Therefore, client code
-/// must use `Root`s (or the stack) to store all references to Scheme data.
-///
-/// There are 2 types of `Root`: local roots and persistent roots.  A local
-/// root can only be created from within a callback from the VM and is stack
-/// allocated.  A global root can be created by any code that has a valid
-/// reference to the VM, and may live for as long as the VM is alive.  Global
-/// roots are more expensive in terms of VM resources (they are stored in a
-/// heap-allocated data structure), but are not limited by a scope.
-#[derive(Debug)]
-pub struct Root<'a> {
-    contents: &'a cell::UnsafeCell<Value>,
-}
-
 /// Rounds the size of a heap object up to the nearest multiple of 8 bytes,
 /// expressed in words.
 fn align_word_size(size: usize) -> usize {
@@ -153,20 +298,23 @@ fn align_word_size(size: usize) -> usize {
     x
 }
 
+/// Overwrites the words of `space`, which is about to be emptied, with
+/// `POISON`, so that a dangling pointer into it does not find a stale copy.
+fn poison(space: &mut [Value]) {
+    for word in space {
+        *word = Value::new(POISON)
+    }
+}
+
 /// Relocates a `Value` in the heap.
 ///
-/// This function relocates a `Value` in the Scheme heap.  It takes two
-/// arguments: `current`, the `Value` being relocated, and `end`, the current
-/// end of tospace.
+/// This function relocates a `Value` in the Scheme heap.  It takes three
+/// arguments: `current`, the `Value` being relocated, `tospace`, the space
+/// being copied into, and `condemned`, the spaces being evacuated.  Values
+/// that do not point into `condemned` are left as-is.
 ///
 /// This function takes raw pointers because of aliasing concerns.
-unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mut Vec<Value>) {
-    debug_assert!(tospace.capacity() >= fromspace.len());
-    if false {
-        debug!("Tospace capacity: {}, Fromspace length: {}",
-               tospace.capacity(),
-               fromspace.len());
-    }
+unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, condemned: Condemned) {
     let size_of_value: usize = size_of!(Value);
     (*current).size().map(|size| {
         if size == 0 && (*current).tag() == value::Tags::Symbol {
@@ -185,13 +333,22 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
                            current,
                            (*current).get());
                     debug!("Chain length: {}", chain_length);
-                    return relocate(current, tospace, fromspace)
+                    return relocate(current, tospace, condemned)
                 }
             }
         }
         // pointer to head of object being copied
         let pointer: *mut Value = (*current).as_ptr();
 
+        // Objects outside of the condemned spaces stay where they are.
+        // Large objects are marked instead.
+        if !condemned.contains(pointer as usize) {
+            if let Some(large) = condemned.large {
+                large.mark(pointer as usize);
+            }
+            return
+        }
+
         //debug!("HEADER_TAG is {:b}\n", HEADER_TAG);
 
         let header = (*pointer).get();
@@ -219,11 +376,9 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
             debug_assert!(end as usize & 0b111 == 0,
                           "internal error: relocate: misaligned end pointer");
 
-            // Check that the pointer really is to fromspace
-            debug_assert!((pointer as usize) <
-                          fromspace.as_ptr() as usize + fromspace.len() * size_of!(usize),
-                          "internal error: relocate: attempt to relocate pointer not to fromspace");
-            debug_assert!(pointer as usize >= fromspace.as_ptr() as usize);
+            // Check that there is room in tospace
+            debug_assert!(amount_to_copy + len <= tospace.capacity(),
+                          "internal error: relocate: tospace exhausted");
 
             if cfg!(feature = "memcpy-gc") {
                 let words_to_copy = amount_to_copy * size_of_value;
@@ -248,16 +403,15 @@ unsafe fn relocate(current: *mut Value, tospace: &mut Vec<Value>, fromspace: &mu
     });
 }
 
-/// Process the heap.
-unsafe fn scavange_heap(tospace: &mut Vec<Value>, fromspace: &mut Vec<Value>) {
-    let mut offset: isize = 0;
+/// Process the heap, starting at word `start` of tospace.
+unsafe fn scavange_heap(tospace: &mut Vec<Value>, start: usize, condemned: Condemned) {
+    let mut offset: isize = start as isize;
     use std::isize;
     assert!(tospace.len() <= isize::MAX as usize);
-    assert!(fromspace.len() <= isize::MAX as usize);
     let current = tospace.as_mut_ptr();
     while offset < tospace.len() as isize {
         let header = (*current.offset(offset)).get();
-        let size = header & !HEADER_TAG;
+        let size = header & HEADER_SIZE;
         let tag = header & HEADER_TAG;
         assert!(size > 0);
         offset += 1;
@@ -267,78 +421,177 @@ unsafe fn scavange_heap(tospace: &mut Vec<Value>, fromspace: &mut Vec<Value>) {
             PAIR => /* Pair */ {
                 debug_assert!(size == 3)
             }
-            RUSTDATA => /* Rustdata – not scanned by the GC */ {
+            RUSTDATA => /* Leaf objects (strings, bytevectors, floats, Rust data) – not scanned */ {
                 offset += size as isize - 1;
                 continue;
             }
-            VECTOR => /* Vector-like object */ { }
+            VECTOR | RECORD | CLOSURE => /* Vector-like object */ { }
             BYTECODE => /* Bytecode object */ {
-                let ptr: *mut bytecode::BCO = current.offset(-1) as *mut _;
+                let ptr: *mut bytecode::BCO = current.offset(offset - 1) as *mut _;
                 relocate(bytecode::get_constants_vector(&*ptr).get(), tospace,
-                         fromspace);
+                         condemned);
                 offset += size as isize - 1;
                 continue;
             }
             _ => bug!("Strange header type {:x}", tag),
         }
 
-        if !(*current).leafp() {
-            if !(*current).raw_tag() != SYMBOL_TAG {
-                for _ in 1..size {
-                    relocate(current.offset(offset), tospace, fromspace);
-                    offset += 1
-                }
-            } else {
-                relocate(current.offset(offset), tospace, fromspace);
-                offset += size as isize - 1
+        // Pairs and vectors: every field is a `Value`.
+        for _ in 1..size {
+            relocate(current.offset(offset), tospace, condemned);
+            offset += 1
+        }
+        offset = align_word_size(offset as usize) as isize
+    }
+}
+
+/// Process the heap starting at word `start`, and any gray large objects,
+/// until there is nothing left to scan.
+unsafe fn scavange_all(tospace: &mut Vec<Value>, start: usize, condemned: Condemned) {
+    let mut scanned = start;
+    loop {
+        scavange_heap(tospace, scanned, condemned);
+        scanned = tospace.len();
+        let grayed = condemned.large.map_or(false, |large| large.scan_gray(tospace, condemned));
+        if !grayed && scanned == tospace.len() {
+            return
+        }
+    }
+}
+
+/// Relocates every field of a (mutable) object outside of tospace: an
+/// old-generation object during a minor collection, or a large object.
+unsafe fn scavange_object(object: &Value, tospace: &mut Vec<Value>, condemned: Condemned) {
+    let pointer = object.as_ptr();
+    let header = (*pointer).get();
+    match header & HEADER_TAG {
+        PAIR | VECTOR | RECORD | CLOSURE => {
+            for i in 1..(header & HEADER_SIZE) {
+                relocate(pointer.offset(i as isize), tospace, condemned)
             }
-            offset = align_word_size(offset as usize) as isize
         }
+        BYTECODE => {
+            let bco = pointer as *mut bytecode::BCO;
+            relocate(bytecode::get_constants_vector(&*bco).get(), tospace, condemned)
+        }
+        _ => {}
     }
 }
 
-/// Handles all of the data on the stack.
+/// Handles all of the data on the stack and the stacks of the suspended
+/// fibers, and the persistent roots.  The scratch registers of the running
+/// frames must have been cleared (see `stack_map`), since the code of the
+/// frames may have moved by now.
 unsafe fn scavange_stack(stack: &mut Vec<Value>,
+                         control: &mut interp::Control,
+                         roots: &RefCell<RootTable>,
                          tospace: &mut Vec<Value>,
-                         fromspace: &mut Vec<Value>) {
-    for i in stack.iter_mut() {
-        relocate(i, tospace, fromspace);
+                         condemned: Condemned) {
+    let fibers = control.fibers.iter_mut().chain(control.suspended.iter_mut());
+    for i in stack.iter_mut().chain(fibers.flat_map(|fiber| fiber.stack.iter_mut())) {
+        relocate(i, tospace, condemned);
+    }
+    roots.borrow_mut().scavange(tospace, condemned)
+}
+
+/// Relocates the entries of the weak tables and hash tables, once the
+/// ordinary scan is finished.  Each kind of table can make entries of the
+/// other live, so this is repeated until neither finds anything new.
+unsafe fn trace_tables(weak_tables: &mut ephemeron::WeakTables,
+                       hash_tables: &mut hash_table::HashTables,
+                       tospace: &mut Vec<Value>,
+                       condemned: Condemned) {
+    loop {
+        let scanned = tospace.len();
+        weak_tables.trace(tospace, scanned, condemned);
+        let scanned = tospace.len();
+        if !hash_tables.trace(tospace, scanned, condemned) {
+            return
+        }
     }
 }
 
 /// Performs a full garbage collection
 pub fn collect(heap: &mut Heap) {
+    collect_with_room(heap, 0)
+}
+
+/// Performs a full garbage collection, leaving at least `room` words free in
+/// tospace.  Finishes the incremental collection cycle in progress, if any.
+fn collect_with_room(heap: &mut Heap, room: usize) {
+    if heap.cycle.is_some() {
+        return incremental::flip(heap, room)
+    }
+    heap.hooks.run(GcPhase::Before, &heap.stats);
     debug!("Initiated garbage collection");
+    let start_time = Instant::now();
+    heap.clear_scratch_registers();
+    heap.verify_if_enabled("before a full collection");
     unsafe {
-        if cfg!(debug_assertions) {
-            for i in &heap.stack.innards {
-                debug::assert_valid_heap_pointer(&heap.tospace, i)
-            }
-            debug::consistency_check(&heap.tospace);
-        }
-        debug!("Completed first consistency check");
         mem::swap(&mut heap.tospace, &mut heap.fromspace);
-        heap.tospace.reserve(heap.fromspace.len() + heap.fromspace.len() / 2);
-        debug!("Fromspace size is {}",
-               heap.fromspace.len() + heap.fromspace.len() / 2);
+        // Everything might survive, and tospace must never be reallocated
+        // during a collection.
+        let nursery_len = heap.nursery.as_ref().map_or(0, |n| n.len());
+        let survivors = heap.fromspace.len() + nursery_len;
+        let target = ::std::cmp::max(heap.config.target_words(survivors), survivors);
+        heap.tospace.reserve(target + room);
+        debug!("Fromspace size is {}", heap.fromspace.len());
         heap.tospace.resize(0, Value::new(0));
         debug!("Tospace resized to {}", heap.tospace.capacity());
+        evacuate(heap, 0, start_time, |_, _| {})
+    }
+}
+
+/// Finishes a full collection.  Fromspace (and the nursery) must hold the
+/// objects being collected, and tospace the objects copied so far, of which
+/// the first `scanned` words have been scanned.  `extra_roots` is called to
+/// relocate any roots other than the stack and the persistent roots.
+unsafe fn evacuate<F>(heap: &mut Heap, scanned: usize, start_time: Instant, extra_roots: F)
+    where F: FnOnce(&mut Vec<Value>, Condemned)
+{
+    {
         debug!("Stack size is {}", heap.stack.len());
-        scavange_stack(&mut heap.stack, &mut heap.tospace, &mut heap.fromspace);
+        let condemned = Condemned::new(&heap.fromspace,
+                                       heap.nursery.as_ref().map_or(&[], |n| &n[..]))
+                            .with_large(&heap.large_objects);
+        scavange_stack(&mut heap.stack,
+                       &mut heap.control,
+                       &heap.roots,
+                       &mut heap.tospace,
+                       condemned);
+        extra_roots(&mut heap.tospace, condemned);
         debug!("Stack scavanged");
-        scavange_heap(&mut heap.tospace, &mut heap.fromspace);
+        scavange_all(&mut heap.tospace, scanned, condemned);
         debug!("Heap scavanged");
+        trace_tables(&mut heap.weak_tables,
+                     &mut heap.hash_tables,
+                     &mut heap.tospace,
+                     condemned);
+        heap.weak_tables.sweep(condemned);
+        heap.hash_tables.sweep(condemned);
+        debug!("Ephemerons processed");
+        let dead = heap.finalizers.sweep(condemned);
+        heap.large_objects.sweep();
+        if let Some(ref mut nursery) = heap.nursery {
+            if heap.config.poison {
+                poison(nursery)
+            }
+            nursery.clear()
+        }
+        heap.remembered_set.clear();
         heap.symbol_table.fixup();
         debug!("Fixed up symbol table");
-        if cfg!(debug_assertions) {
-            for i in &heap.stack.innards {
-                debug::assert_valid_heap_pointer(&heap.tospace, i)
-            }
-            debug::consistency_check(&heap.tospace);
+        heap.verify_if_enabled("after a full collection");
+        if heap.config.poison {
+            poison(&mut heap.fromspace)
         }
-        debug!("Completed second consistency check");
         heap.fromspace.resize(0, Value::new(0));
-        heap.last_mem_use = heap.fromspace.capacity() + 8*heap.symbol_table.contents.len()
+        heap.last_mem_use = heap.fromspace.capacity() + 8*heap.symbol_table.contents.len() +
+                            heap.large_objects.words();
+        let live_bytes = heap.words_in_use() * size_of!(Value);
+        heap.stats.record_collection(false, live_bytes, start_time.elapsed());
+        heap.hooks.run(GcPhase::After, &heap.stats);
+        finalize::run(dead)
     }
 }
 
@@ -369,132 +622,451 @@ impl Heap {
     /// Allocates a Scheme pair, which must be rooted by the caller.
     ///
     /// The arguments are stack indexes.
-    pub fn alloc_pair(&mut self, car: usize, cdr: usize) {
+    pub fn alloc_pair(&mut self, car: usize, cdr: usize) -> Result<(), String> {
         if cfg!(debug_assertions) {
             for i in &[car, cdr] {
-                debug::assert_valid_heap_pointer(&self.tospace, &self.stack[*i])
+                self.assert_valid_heap_pointer(&self.stack[*i])
             }
         }
         // unsafe { consistency_check(&self.tospace) }
         let x = SIZEOF_PAIR;
-        self.alloc_raw(x, value::HeaderTag::Pair);
+        try!(self.alloc_raw(x, value::HeaderTag::Pair));
+        let (car, cdr) = (self.stack[car].clone(), self.stack[cdr].clone());
+        let space = self.space_mut();
         let len = if size_of!(usize) < 8 {
-            self.tospace.extend_from_slice(&[self.stack[car].clone(),
-                                             self.stack[cdr].clone(),
-                                             Value::new(1)]);
-            self.tospace.len() - 4
+            space.extend_from_slice(&[car, cdr, Value::new(1)]);
+            space.len() - 4
         } else {
-            self.tospace.extend_from_slice(&[self.stack[car].clone(), self.stack[cdr].clone()]);
-            self.tospace.len() - 3
+            space.extend_from_slice(&[car, cdr]);
+            space.len() - 3
         };
         let new_value = Value::new(unsafe {
-            self.tospace.as_ptr().offset(len as isize) as usize | value::PAIR_TAG
+            space.as_ptr().offset(len as isize) as usize | value::PAIR_TAG
         });
         if cfg!(debug_assertions) {
-            debug::assert_valid_heap_pointer(&self.tospace, &new_value);
+            self.assert_valid_heap_pointer(&new_value);
         }
-        self.stack.push(new_value);
+        Ok(self.stack.push(new_value))
         // unsafe { consistency_check(&self.tospace) }
         // debug!("Allocated a pair")
     }
 
     pub fn check_must_collect(&mut self) {
         let should_collect = 8*self.symbol_table.contents.len() +
-            self.tospace.capacity() >
+            self.tospace.capacity() + self.large_objects.words() >
             ((2*self.last_mem_use) + if cfg!(debug_assertions) {
                 1
             } else{
                 1 << 16
             });
         if should_collect {
-            collect(self)
+            if self.is_incremental() {
+                incremental::start(self)
+            } else {
+                collect(self)
+            }
         }
     }
 
+    /// Checks if this heap collects incrementally.
+    fn is_incremental(&self) -> bool {
+        self.config.max_pause_us.is_some() && self.nursery.is_none()
+    }
+
     /// FIXME use enum for tag
+    ///
+    /// The object is allocated in the large object space if it is at least
+    /// `HeapConfig::large_object_bytes` in size, in the nursery if this heap
+    /// is generational and the object fits, and in tospace otherwise.
+    /// Callers must fill in the rest of the object through `space_mut`.
+    ///
+    /// Fails if the allocation would exceed the maximum heap size, and the
+    /// out-of-memory handler (if any) does not raise it.
     pub fn alloc_raw(&mut self, space: usize,
-                     tag: value::HeaderTag) -> (*mut libc::c_void, usize) {
+                     tag: value::HeaderTag) -> Result<(*mut libc::c_void, usize), String> {
+        let large = align_word_size(space) * size_of!(Value) >= self.config.large_object_bytes;
+        self.alloc_raw_in(space, tag, large)
+    }
+
+    /// Like `alloc_raw`, but the object is never moved by the GC, whatever
+    /// its size.
+    pub fn alloc_raw_pinned(&mut self, space: usize,
+                            tag: value::HeaderTag) -> Result<(*mut libc::c_void, usize), String> {
+        self.alloc_raw_in(space, tag, true)
+    }
+
+    fn alloc_raw_in(&mut self, space: usize, tag: value::HeaderTag,
+                    large: bool) -> Result<(*mut libc::c_void, usize), String> {
         debug_assert!(space > 1);
         let real_space = align_word_size(space);
-        let tospace_space = self.tospace.capacity() - self.tospace.len();
-        if tospace_space < real_space  {
-            collect(self);
-        } else {
-            self.check_must_collect()
+        if self.config.stress {
+            collect(self)
         }
-        debug_assert!(((self.tospace.len()*size_of!(usize)) & 7) == 0);
+        if self.cycle.is_some() {
+            incremental::step(self)
+        } else if self.is_incremental() && 2 * self.tospace.len() >= self.tospace.capacity() {
+            incremental::start(self)
+        }
+        try!(self.check_limit(real_space));
+        self.stats.bytes_allocated += real_space * size_of!(Value);
+        let nursery_room = self.nursery.as_ref().map(|n| (n.capacity(), n.capacity() - n.len()));
+        self.target = match nursery_room {
+            _ if large => {
+                self.check_must_collect();
+                self.large_objects.alloc(real_space);
+                Space::Large
+            }
+            Some((capacity, room)) if real_space <= capacity => {
+                if room < real_space {
+                    collect_nursery(self)
+                }
+                Space::Nursery
+            }
+            _ => {
+                let tospace_space = self.tospace.capacity() - self.tospace.len();
+                if tospace_space < real_space  {
+                    collect_with_room(self, real_space);
+                } else {
+                    self.check_must_collect()
+                }
+                Space::Old
+            }
+        };
+        let alloc_space = self.space_mut();
+        debug_assert!(((alloc_space.len()*size_of!(usize)) & 7) == 0);
+        let start = alloc_space.len();
         let alloced_ptr = unsafe {
-            self.tospace.as_ptr().offset(self.tospace.len() as isize)
+            alloc_space.as_ptr().offset(start as isize)
         };
-        self.tospace.push(Value::new(space | tag as usize));
+        alloc_space.push(Value::new(space | tag as usize));
         debug_assert!(alloced_ptr as usize & 7 == 0);
-        (alloced_ptr as *mut libc::c_void,
-         self.tospace.len() + real_space)
+        // Objects allocated outside of the nursery may be initialized with
+        // pointers into it.
+        if self.nursery.is_some() && self.target != Space::Nursery {
+            self.remembered_set.push(Value::new(alloced_ptr as usize))
+        }
+        Ok((alloced_ptr as *mut libc::c_void,
+            start + real_space))
+    }
+
+    /// The number of words in use by the heap.
+    fn words_in_use(&self) -> usize {
+        self.tospace.len() + self.nursery.as_ref().map_or(0, |n| n.len()) +
+        self.large_objects.words()
+    }
+
+    /// Checks that `words` more words can be allocated without exceeding the
+    /// maximum heap size, collecting garbage and calling the out-of-memory
+    /// handler if needed.
+    fn check_limit(&mut self, words: usize) -> Result<(), String> {
+        if self.words_in_use() + words <= self.config.max_words() {
+            return Ok(())
+        }
+        collect(self);
+        loop {
+            let needed = self.words_in_use() + words;
+            if needed <= self.config.max_words() {
+                return Ok(())
+            }
+            let needed_bytes = needed * size_of!(Value);
+            let new_max = match self.config.out_of_memory {
+                Some(ref mut handler) => handler(needed_bytes),
+                None => None,
+            };
+            match new_max {
+                Some(bytes) if bytes > self.config.max_bytes.unwrap_or(0) => {
+                    self.config.max_bytes = Some(bytes)
+                }
+                _ => {
+                    return Err(format!("Out of memory: heap would grow to {} bytes, \
+                                        but the limit is {} bytes",
+                                       needed_bytes,
+                                       self.config.max_words() * size_of!(Value)))
+                }
+            }
+        }
+    }
+
+    /// The space that the last object was allocated in by `alloc_raw`.
+    fn space_mut(&mut self) -> &mut Vec<Value> {
+        match self.target {
+            Space::Old => &mut self.tospace,
+            Space::Nursery => self.nursery.as_mut().expect("nursery target without a nursery"),
+            Space::Large => self.large_objects.last_mut(),
+        }
     }
 
-    /// Allocates a vector.  The `elements` array must be rooted for the GC.
-    pub fn alloc_vector(&mut self, start: usize, end: usize) {
+    /// Checks that `value` is an immediate or points into the heap (in
+    /// debug mode).
+    fn assert_valid_heap_pointer(&self, value: &Value) {
+        match self.nursery {
+            Some(ref nursery) if Condemned::new(nursery, &[]).contains(value.get() & !0b111) => {}
+            _ => {
+                debug::assert_valid_heap_pointer_with(&self.tospace,
+                                                      &|value| self.is_large_object(value),
+                                                      value)
+            }
+        }
+    }
+
+    /// Checks if `value` points to a large object.
+    fn is_large_object(&self, value: &Value) -> bool {
+        !value.immediatep() && self.large_objects.contains(value.get() & !0b111)
+    }
+
+    /// Checks if the nursery is empty (or does not exist).
+    fn nursery_is_empty(&self) -> bool {
+        self.nursery.as_ref().map_or(true, |n| n.is_empty())
+    }
+
+    /// Allocates a vector holding `stack[start..end]`, and pushes it on the
+    /// stack.  The word after the header is reserved (see `value::Vector`).
+    pub fn alloc_vector(&mut self, start: usize, end: usize) -> Result<(), String> {
         assert!(end >= start);
-        let (value_ptr, final_len) = self.alloc_raw(end - start + 2,
-                                                    value::HeaderTag::Vector);
-        self.tospace.push(Value::new(0));
-        let ptr = value_ptr as usize | value::VECTOR_TAG;
+        let size = end - start + 2;
+        let (value_ptr, final_len) = try!(self.alloc_raw(size, value::HeaderTag::Vector));
+        // The elements are read only now, since allocating may have moved
+        // them.
+        let elements = self.stack[start..end].to_vec();
         {
-            let stack = &self.stack[start..end];
-            self.tospace.extend_from_slice(stack);
-        }
-        unsafe { self.tospace.set_len(final_len) };
-        self.stack.push(Value::new(ptr));
-    }
-
-    /// Allocates a closure. `src` and `src2` are as found in the opcode.
-    pub fn alloc_closure(&mut self, src: u8, src2: u8, upvalues: usize) {
-        let argcount = (src as u16) << 7 | src2 as u16;
-        let vararg = src & ::std::i8::MIN as u8 == 0;
-        let stack_len = self.stack.len();
-        let (value_ptr, final_len) = self.alloc_raw(upvalues + 2,
-                                                    value::HeaderTag::Vector);
-        let ptr = {
-            let elements = &self.stack[stack_len - upvalues..stack_len];
-            let ptr = value_ptr as usize | value::VECTOR_TAG;
-            self.tospace.push(Value::new((argcount as usize) << 2 |
-                                         (-(vararg as isize) as usize &
-                                          ::std::isize::MIN as usize)));
-            self.tospace.extend_from_slice(elements);
-            unsafe { self.tospace.set_len(final_len) };
-            ptr
-        };
-        self.stack.push(Value::new(ptr));
+            let space = self.space_mut();
+            space.push(Value::new(0));
+            space.extend_from_slice(&elements);
+            // Padding to an even number of words, on 32-bit platforms.
+            space.resize(final_len, Value::new(0));
+        }
+        // The space must not have been reallocated since the header was
+        // written, or `value_ptr` would dangle.
+        debug_assert_eq!(unsafe { (*(value_ptr as *const Value)).get() }, size);
+        Ok(self.stack.push(Value::new(value_ptr as usize | value::VECTOR_TAG)))
+    }
+
+    /// Allocates a record whose descriptor is `stack[start]` and whose
+    /// fields are `stack[start + 1..end]`, and pushes it on the stack.
+    pub fn alloc_record(&mut self, start: usize, end: usize) -> Result<(), String> {
+        self.alloc_vector_like(value::HeaderTag::Record, start, end)
+    }
+
+    /// Allocates a closure whose code (a BCO or a primitive) is
+    /// `stack[start]` and whose captured values are `stack[start + 1..end]`,
+    /// and pushes it on the stack.
+    pub fn alloc_closure(&mut self, start: usize, end: usize) -> Result<(), String> {
+        debug_assert!(match self.stack[start].kind() {
+            Kind::Bytecode(_) | Kind::Primitive(_) => true,
+            _ => false,
+        });
+        self.alloc_vector_like(value::HeaderTag::Closure, start, end)
+    }
+
+    /// Allocates a promise, and pushes it on the stack.  If `done` is set,
+    /// `stack[value]` is its value; otherwise, it is a thunk returning a
+    /// promise.
+    pub fn alloc_promise(&mut self, done: bool, value: usize) -> Result<(), String> {
+        let base = self.stack.len();
+        let value = self.stack[value].clone();
+        self.stack.push(Value::new(if done { value::TRUE } else { value::FALSE }));
+        self.stack.push(value);
+        let result = self.alloc_pair(base, base + 1).and_then(|()| {
+            self.stack[base] = Value::new(value::PROMISE_DESCRIPTOR);
+            self.stack[base + 1] = self.stack.pop().unwrap();
+            self.alloc_vector_like(value::HeaderTag::Record, base, base + 2)
+        });
+        let promise = self.stack.pop();
+        self.stack.truncate(base);
+        try!(result);
+        Ok(self.stack.push(promise.unwrap()))
+    }
+
+    /// Allocates a condition whose type, message and irritants are
+    /// `stack[start..start + 3]`, and pushes it on the stack.
+    pub fn alloc_condition(&mut self, start: usize) -> Result<(), String> {
+        let base = self.stack.len();
+        self.stack.push(Value::new(value::CONDITION_DESCRIPTOR));
+        for i in start..start + 3 {
+            let field = self.stack[i].clone();
+            self.stack.push(field)
+        }
+        let result = self.alloc_vector_like(value::HeaderTag::Record, base, base + 4);
+        let condition = self.stack.pop();
+        self.stack.truncate(base);
+        try!(result);
+        Ok(self.stack.push(condition.unwrap()))
+    }
+
+    /// Allocates an object holding the multiple values `stack[start..end]`,
+    /// and pushes it on the stack.
+    pub fn alloc_values(&mut self, start: usize, end: usize) -> Result<(), String> {
+        let base = self.stack.len();
+        self.stack.push(Value::new(value::VALUES_DESCRIPTOR));
+        for i in start..end {
+            let value = self.stack[i].clone();
+            self.stack.push(value)
+        }
+        let result = self.alloc_vector_like(value::HeaderTag::Record, base, base + 1 + end - start);
+        let values = self.stack.pop();
+        self.stack.truncate(base);
+        try!(result);
+        Ok(self.stack.push(values.unwrap()))
+    }
+
+    /// Allocates an object with header tag `tag`, holding `stack[start..end]`.
+    fn alloc_vector_like(&mut self,
+                         tag: value::HeaderTag,
+                         start: usize,
+                         end: usize)
+                         -> Result<(), String> {
+        assert!(end > start);
+        let (value_ptr, final_len) = try!(self.alloc_raw(end - start + 1, tag));
+        let elements = self.stack[start..end].to_vec();
+        let space = self.space_mut();
+        space.extend_from_slice(&elements);
+        space.resize(final_len, Value::new(0));
+        Ok(self.stack.push(Value::new(value_ptr as usize | value::VECTOR_TAG)))
     }
 
     /// Create an instance of the garage collector
     pub fn new(size: usize) -> Self {
+        Heap::with_config(HeapConfig { min_bytes: size * size_of!(Value), ..Default::default() })
+    }
+
+    /// The configuration of the heap.
+    pub fn config(&self) -> &HeapConfig {
+        &self.config
+    }
+
+    /// Create an instance of the garbage collector with the given sizing
+    /// policy.
+    pub fn with_config(config: HeapConfig) -> Self {
+        let size = config.min_words();
         Heap {
             fromspace: Vec::with_capacity(size),
             tospace: Vec::with_capacity(size),
             symbol_table: symbol::SymbolTable::default(),
-            environment: ptr::null_mut(),
-            constants: ptr::null(),
             stack: Stack { innards: Vec::with_capacity(1 << 16) },
-            last_mem_use: 1<<16
+            last_mem_use: 1<<16,
+            nursery: None,
+            remembered_set: vec![],
+            target: Space::Old,
+            finalizers: Default::default(),
+            weak_tables: Default::default(),
+            hash_tables: Default::default(),
+            roots: Default::default(),
+            config: config,
+            stats: Default::default(),
+            hooks: Default::default(),
+            large_objects: Default::default(),
+            cycle: None,
+            locations: None,
+            globals: HashMap::new(),
+            control: interp::Control::new(),
+            macros: Default::default(),
+            natives: Default::default(),
         }
     }
 
-    /// Interns a symbol.
-    pub fn intern(&mut self, string: &str) {
-        use symbol::Symbol;
-        use std::rc::Rc;
-        {
-            let rc = Rc::new(string.to_owned());
-            let val = self.symbol_table.contents
-                                       .entry(rc.clone())
-                                       .or_insert_with(|| Box::new(Symbol::new(rc)));
-            self.stack.push(Value::new(&mut(**val) as *mut _ as usize |
-                                       value::SYMBOL_TAG))
+    /// Create a generational heap, with a nursery of `nursery_size` words
+    /// and an old generation of (initially) `old_size` words.
+    pub fn new_generational(nursery_size: usize, old_size: usize) -> Self {
+        let mut heap = Heap::new(old_size);
+        heap.nursery = Some(Vec::with_capacity(nursery_size));
+        heap
+    }
+
+    /// Statistics about this heap.
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Registers a callback to be run before and after every collection.
+    /// Returns an ID for use with `remove_gc_hook`.
+    pub fn on_gc<F: FnMut(GcPhase, &HeapStats) + 'static>(&mut self, hook: F) -> usize {
+        self.hooks.add(Box::new(hook))
+    }
+
+    /// Removes a callback registered with `on_gc`.  Returns `false` if there
+    /// was no such callback.
+    pub fn remove_gc_hook(&mut self, id: usize) -> bool {
+        self.hooks.remove(id)
+    }
+
+    /// Roots `value`, which must be an immediate or point into this heap.
+    pub fn root<T: ?Sized>(&self, value: Value) -> Root<T> {
+        self.assert_valid_heap_pointer(&value);
+        Root::new(&self.roots, value)
+    }
+
+    /// Pops the top of the stack into a new root.
+    pub fn root_top<T: ?Sized>(&mut self) -> Result<Root<T>, String> {
+        match self.stack.pop() {
+            Some(value) => Ok(self.root(value)),
+            None => Err("Attempt to pop from empty stack".to_owned()),
+        }
+    }
+
+    /// Registers a finalizer, which will be run (once) after the first
+    /// collection that finds `object` unreachable, or when the heap is
+    /// destroyed.  Fails if `object` is not a heap object.
+    pub fn register_finalizer(&mut self, object: Value, finalizer: Finalizer) -> Result<(), String> {
+        if object.immediatep() || object.tag() == value::Tags::Symbol {
+            return Err("Attempt to register a finalizer on a non-heap object".to_owned())
         }
+        Ok(self.finalizers.register(object, finalizer))
+    }
+
+    /// Interns a symbol, and pushes it on the stack.
+    pub fn intern(&mut self, string: &str) {
+        let control = &self.control;
+        let symbol = self.symbol_table.intern(string, |symbol| {
+            match builtins::lookup(string) {
+                Some(primitive) if control.allows(string) => {
+                    unsafe { *symbol.contents.get() = primitive.to_value() }
+                    symbol.bound.set(true)
+                }
+                _ => {}
+            }
+        });
+        self.stack.push(symbol);
         self.check_must_collect()
     }
 
+    /// The symbols naming the global variables that have been assigned.
+    pub fn assigned_globals(&self) -> Vec<Value> {
+        self.globals.values().map(Root::get).collect()
+    }
+
+    /// Withholds the primitives of `group`: the global variables named after
+    /// them are left unbound, and unbound if they are still bound to them.
+    pub fn deny(&mut self, group: builtins::Group) {
+        self.control.deny(group);
+        for name in builtins::names().into_iter().filter(|&name| group.contains(name)) {
+            let primitive = builtins::lookup(name).unwrap().to_value();
+            if let Some(symbol) = self.symbol_table.lookup(name) {
+                if symbol.bound.get() && unsafe { *symbol.contents.get() == primitive } {
+                    unsafe { *symbol.contents.get() = Value::new(value::FALSE) }
+                    symbol.bound.set(false)
+                }
+            }
+        }
+    }
+
+    /// Sets the global variable named by the symbol `symbol` to `value`,
+    /// binding it if need be.
+    pub fn set_global(&mut self, symbol: &Value, value: Value) -> Result<(), String> {
+        let ptr = match symbol.kind() {
+            Kind::Symbol(ptr) => ptr,
+            _ => return Err("Attempt to set the value of a non-symbol".to_owned()),
+        };
+        unsafe {
+            *(*ptr).contents.get() = value;
+            (*ptr).bound.set(true)
+        }
+        if !self.globals.contains_key(&symbol.get()) {
+            let root = self.root(symbol.clone());
+            self.globals.insert(symbol.get(), root);
+        }
+        Ok(())
+    }
+
 
     pub fn store_global(&mut self) -> Result<(), String> {
         match self.stack.pop().unwrap().kind() {
@@ -519,6 +1091,12 @@ impl Heap {
     }
 }
 
+impl Drop for Heap {
+    fn drop(&mut self) {
+        finalize::run(self.finalizers.drain())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -534,11 +1112,11 @@ mod tests {
                PAIR_HEADER,
                SIZEOF_PAIR);
         heap.stack.push(zero);
-        heap.alloc_pair(0, 0);
+        heap.alloc_pair(0, 0).unwrap();
         heap.stack[0] = heap.stack.pop().unwrap();
         // debug!("{:?}", heap);
         for i in 1..((1 << 11)) {
-            heap.alloc_pair(0, 0);
+            heap.alloc_pair(0, 0).unwrap();
             assert_eq!(heap.stack.len(), 2);
             assert_eq!(heap.stack[1].tag(), Tags::Pair);
             heap.stack[0] = heap.stack.pop().unwrap();
@@ -565,4 +1143,345 @@ mod tests {
     super::collect(&mut heap);
     assert!(heap.tospace.len() == 0)
 }
+
+    #[test]
+    fn generational_heap_promotes_survivors() {
+        let mut heap = Heap::new_generational(1 << 6, 1 << 10);
+        heap.stack.push(Value::new(0));
+        heap.alloc_pair(0, 0).unwrap();
+        heap.stack[0] = heap.stack.pop().unwrap();
+        for _ in 0..(1 << 8) {
+            // Garbage, which should never be promoted
+            heap.alloc_pair(0, 0).unwrap();
+            heap.stack.pop();
+        }
+        assert_eq!(heap.stack[0].tag(), Tags::Pair);
+        assert!(heap.tospace.len() <= 3);
+        collect_nursery(&mut heap);
+        assert!(heap.nursery_is_empty());
+        assert_eq!(heap.stack[0].tag(), Tags::Pair);
+        assert_eq!(heap.stack[0].size(), Some(3));
+        assert_eq!(heap.tospace.len(), 3)
+    }
+
+    #[test]
+    fn migrate_keeps_cycles() {
+        let (mut heap, mut other) = (Heap::new(1 << 4), Heap::new(1 << 4));
+        heap.stack.push(Value::new_fixnum(1));
+        heap.alloc_pair(0, 0).unwrap();
+        heap.stack[1].set_cdr(heap.stack[1].clone()).unwrap();
+        heap.migrate(&heap.stack[1], &mut other).unwrap();
+        assert_eq!(other.stack.len(), 1);
+        super::collect(&mut other);
+        let copy = other.stack[0].clone();
+        assert!(copy.get() != heap.stack[1].get());
+        assert_eq!(copy.car().unwrap().get(), Value::new_fixnum(1).get());
+        assert_eq!(copy.cdr().unwrap().get(), copy.get());
+        // Symbols are interned in the other heap.
+        heap.intern("migrated");
+        heap.migrate(&heap.stack[2], &mut other).unwrap();
+        other.intern("migrated");
+        assert_eq!(other.stack[1].get(), other.stack[2].get())
+    }
+
+    #[test]
+    fn finalizers_run_on_dead_objects_only() {
+        use std::rc::Rc;
+        let mut heap = Heap::new(1 << 4);
+        let (live, dead) = (Rc::new(Cell::new(false)), Rc::new(Cell::new(false)));
+        heap.stack.push(Value::new(0));
+        heap.alloc_pair(0, 0).unwrap();
+        heap.alloc_pair(0, 0).unwrap();
+        let (flag, object) = (live.clone(), heap.stack[1].clone());
+        heap.register_finalizer(object, Box::new(move || flag.set(true))).unwrap();
+        let (flag, object) = (dead.clone(), heap.stack[2].clone());
+        heap.register_finalizer(object, Box::new(move || flag.set(true))).unwrap();
+        heap.stack.pop();
+        super::collect(&mut heap);
+        assert!(!live.get());
+        assert!(dead.get());
+        assert_eq!(heap.finalizers.len(), 1);
+        drop(heap);
+        assert!(live.get())
+    }
+
+    #[test]
+    fn weak_table_values_die_with_keys() {
+        let mut heap = Heap::new(1 << 4);
+        heap.alloc_weak_table().unwrap();
+        heap.stack.push(Value::new(0));
+        heap.alloc_pair(1, 1).unwrap(); // live key
+        heap.alloc_pair(1, 1).unwrap(); // dead key
+        heap.alloc_pair(1, 1).unwrap(); // value of the live key
+        heap.alloc_pair(1, 1).unwrap(); // value of the dead key
+        let table = heap.stack[0].clone();
+        for &(key, value) in &[(2, 4), (3, 5)] {
+            let (key, value) = (heap.stack[key].clone(), heap.stack[value].clone());
+            heap.weak_table_set(&table, key, value).unwrap();
+        }
+        heap.stack.truncate(3);
+        super::collect(&mut heap);
+        let table = heap.stack[0].clone();
+        let key = heap.stack[2].clone();
+        assert_eq!(heap.weak_table_len(&table), Ok(1));
+        let value = heap.weak_table_get(&table, &key).unwrap().unwrap();
+        assert_eq!(value.tag(), Tags::Pair);
+        // The table handle, the live key, and its value
+        assert_eq!(heap.tospace.len(), 3 + 3 + 3)
+    }
+
+    #[test]
+    fn roots_survive_collection() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::new(0));
+        heap.alloc_pair(0, 0).unwrap();
+        let root: Root = heap.root_top().unwrap();
+        let dropped: Root = heap.root(root.get());
+        drop(dropped);
+        heap.stack.clear();
+        super::collect(&mut heap);
+        assert_eq!(heap.roots.borrow().len(), 1);
+        assert_eq!(root.get().tag(), Tags::Pair);
+        assert_eq!(heap.tospace.len(), 3);
+        drop(root);
+        super::collect(&mut heap);
+        assert_eq!(heap.tospace.len(), 0)
+    }
+
+    #[test]
+    fn memory_limit_is_enforced() {
+        let calls = ::std::rc::Rc::new(Cell::new(0));
+        let handler_calls = calls.clone();
+        let mut heap = Heap::with_config(HeapConfig {
+            min_bytes: 16 * size_of!(Value),
+            max_bytes: Some(16 * size_of!(Value)),
+            growth_factor: 2.0,
+            out_of_memory: Some(Box::new(move |_| {
+                handler_calls.set(handler_calls.get() + 1);
+                if handler_calls.get() == 1 { Some(32 * size_of!(Value)) } else { None }
+            })),
+            ..Default::default()
+        });
+        heap.stack.push(Value::new(0));
+        let mut result = Ok(());
+        let mut count = 0;
+        while result.is_ok() {
+            result = heap.alloc_pair(0, 0);
+            count += 1;
+        }
+        assert_eq!(calls.get(), 2);
+        // 32 words hold 10 pairs
+        assert_eq!(count, 11);
+        assert!(heap.words_in_use() <= 32)
+    }
+
+    #[test]
+    fn stats_track_collections() {
+        let mut heap = Heap::new(1 << 4);
+        heap.stack.push(Value::new(0));
+        heap.alloc_pair(0, 0).unwrap();
+        assert_eq!(heap.stats().bytes_allocated, 3 * size_of!(Value));
+        super::collect(&mut heap);
+        let stats = heap.stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.minor_collections, 0);
+        assert_eq!(stats.bytes_allocated, 0);
+        assert_eq!(stats.live_bytes, 3 * size_of!(Value));
+        assert!(stats.max_pause >= stats.last_pause)
+    }
+
+    #[test]
+    fn gc_hooks_see_each_collection() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        let mut heap = Heap::new(1 << 4);
+        let log = Rc::new(RefCell::new(vec![]));
+        let hook_log = log.clone();
+        let id = heap.on_gc(move |phase, stats| hook_log.borrow_mut().push((phase, stats.collections)));
+        super::collect(&mut heap);
+        assert_eq!(*log.borrow(), [(GcPhase::Before, 0), (GcPhase::After, 1)]);
+        assert!(heap.remove_gc_hook(id));
+        super::collect(&mut heap);
+        assert_eq!(log.borrow().len(), 2);
+    }
+
+    #[test]
+    fn large_objects_are_not_copied() {
+        let mut heap = Heap::with_config(HeapConfig {
+            min_bytes: 16 * size_of!(Value),
+            large_object_bytes: 64 * size_of!(Value),
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            heap.stack.push(Value::new(0));
+        }
+        heap.alloc_vector(0, 100).unwrap();
+        let vector = heap.stack.pop().unwrap();
+        heap.stack.clear();
+        heap.stack.push(vector.clone());
+        heap.alloc_pair(0, 0).unwrap();
+        heap.stack.swap_remove(0);
+        super::collect(&mut heap);
+        assert_eq!(heap.large_objects.words(), 102);
+        assert_eq!(heap.tospace.len(), 3);
+        // The vector did not move
+        assert_eq!(heap.stack[0].car().unwrap(), vector);
+        heap.stack.clear();
+        super::collect(&mut heap);
+        assert_eq!(heap.large_objects.words(), 0)
+    }
+
+    #[test]
+    fn pinned_bytevectors_do_not_move() {
+        let mut heap = Heap::new_generational(1 << 4, 1 << 4);
+        heap.alloc_pinned_bytevector(5).unwrap();
+        let bytevector = heap.stack[0].clone();
+        heap.bytevector_copy_from(&bytevector, 1, b"abcd").unwrap();
+        assert!(heap.bytevector_copy_from(&bytevector, 2, b"abcd").is_err());
+        let ptr = heap.bytevector_as_mut_ptr(&bytevector).unwrap();
+        super::collect_nursery(&mut heap);
+        super::collect(&mut heap);
+        assert_eq!(heap.stack[0], bytevector);
+        assert_eq!(heap.bytevector_len(&heap.stack[0]).unwrap(), 5);
+        let contents = unsafe { ::std::slice::from_raw_parts(ptr, 5) };
+        assert_eq!(contents, b"\0abcd");
+    }
+
+    #[test]
+    fn strings_survive_collection() {
+        let mut heap = Heap::new(1 << 4);
+        heap.alloc_string("héllo, world").unwrap();
+        heap.alloc_string("").unwrap();
+        heap.alloc_pair(0, 1).unwrap();
+        super::collect(&mut heap);
+        unsafe {
+            assert_eq!(heap.string_as_str(&heap.stack[0]).unwrap(), "héllo, world");
+            assert_eq!(heap.string_as_str(&heap.stack[1]).unwrap(), "");
+            assert_eq!(heap.string_as_str(&heap.stack[2].car().unwrap()).unwrap(),
+                       "héllo, world");
+        }
+        assert_eq!(heap.string_len(&heap.stack[0]).unwrap(), 13);
+        assert!(heap.string_len(&heap.stack[2]).is_err());
+        heap.alloc_bytevector(3, 0).unwrap();
+        assert!(heap.string_len(&heap.stack[3]).is_err());
+    }
+
+    #[test]
+    fn verify_finds_corruption() {
+        let mut heap = Heap::new_generational(1 << 8, 1 << 10);
+        heap.alloc_string("verified").unwrap();
+        heap.alloc_hash_table(HashKind::Eq).unwrap();
+        heap.alloc_pair(0, 1).unwrap();
+        assert_eq!(heap.verify(), Ok(()));
+        super::collect_nursery(&mut heap);
+        super::collect(&mut heap);
+        assert_eq!(heap.verify(), Ok(()));
+        // A pointer into the middle of an object.
+        let pair = heap.stack[2].clone();
+        let interior = Value::new(((pair.get() & !0b111) + size_of!(Value)) | value::PAIR_TAG);
+        pair.set_cdr(interior).unwrap();
+        assert!(heap.verify().unwrap_err().contains("does not point to an object"));
+        pair.set_cdr(heap.stack[1].clone()).unwrap();
+        // An old object pointing into the nursery, behind the back of the
+        // write barrier.
+        heap.stack.push(Value::new(value::NIL));
+        heap.alloc_pair(3, 3).unwrap();
+        let young = heap.stack.pop().unwrap();
+        pair.set_car(young.clone()).unwrap();
+        assert!(heap.verify().unwrap_err().contains("remembered set"));
+        heap.write_barrier(&pair, &young);
+        assert_eq!(heap.verify(), Ok(()));
+    }
+
+    #[test]
+    fn stress_mode_moves_unrooted_values() {
+        let mut heap = Heap::with_config(HeapConfig {
+            stress: true,
+            poison: true,
+            ..Default::default()
+        });
+        heap.stack.push(Value::new(value::NIL));
+        for _ in 0..10 {
+            heap.alloc_pair(0, 0).unwrap();
+            heap.stack[0] = heap.stack.pop().unwrap();
+        }
+        assert_eq!(heap.stats().collections, 10);
+        // A copy of a value that is not rooted is left pointing at garbage.
+        let unrooted = heap.stack[0].clone();
+        heap.alloc_pair(0, 0).unwrap();
+        assert!(heap.stack[0] != unrooted);
+        assert_eq!(unsafe { (*unrooted.as_ptr()).get() }, super::POISON);
+        let mut length = 0;
+        let mut current = heap.stack[1].clone();
+        while current.tag() == value::Tags::Pair {
+            current = current.cdr().unwrap();
+            length += 1
+        }
+        assert_eq!(length, 11);
+    }
+
+    #[test]
+    fn incremental_collection_sees_mutations() {
+        let mut heap = Heap::with_config(HeapConfig {
+            min_bytes: 256 * size_of!(Value),
+            max_pause_us: Some(0),
+            ..Default::default()
+        });
+        heap.stack.push(Value::new(value::NIL));
+        for _ in 0..10 {
+            heap.alloc_pair(0, 0).unwrap();
+            heap.stack[0] = heap.stack.pop().unwrap();
+        }
+        // An object that is unreachable when the cycle starts.
+        heap.stack.push(Value::new_fixnum(7));
+        heap.alloc_pair(1, 1).unwrap();
+        let unreachable = heap.stack.pop().unwrap();
+        heap.stack.pop();
+        super::incremental::start(&mut heap);
+        while !heap.cycle.as_ref().unwrap().is_scanned() {
+            unsafe { heap.cycle.as_mut().unwrap().scan_object() }
+        }
+        let list = heap.stack[0].clone();
+        list.set_car(unreachable.clone()).unwrap();
+        heap.write_barrier(&list, &unreachable);
+        super::collect(&mut heap);
+        assert!(heap.cycle.is_none());
+        assert_eq!(heap.stats().collections, 1);
+        assert!(heap.stack[0] != list);
+        let car = heap.stack[0].car().unwrap();
+        assert!(car != unreachable);
+        assert_eq!(car.car().unwrap(), Value::new_fixnum(7));
+        let mut length = 0;
+        let mut current = heap.stack[0].clone();
+        while current.tag() == value::Tags::Pair {
+            current = current.cdr().unwrap();
+            length += 1
+        }
+        assert_eq!(length, 10);
+        // Everything was copied into the replicas or at the flip.
+        assert_eq!(heap.tospace.len(), 11 * SIZEOF_PAIR);
+    }
+
+    #[test]
+    fn dumps_can_be_parsed_and_inspected() {
+        let mut heap = Heap::new(1 << 8);
+        heap.stack.push(Value::new(value::NIL));
+        heap.alloc_pair(0, 0).unwrap();
+        heap.alloc_pair(1, 0).unwrap();
+        let (inner, outer) = (heap.stack[1].get() & !0b111, heap.stack[2].get() & !0b111);
+        let mut dump = vec![];
+        heap.dump(&mut dump).unwrap();
+        let snapshot = Snapshot::parse(&dump[..]).unwrap();
+        assert_eq!(snapshot, heap.snapshot());
+        assert_eq!(snapshot.objects.len(), 2);
+        assert_eq!(snapshot.objects[&outer].kind, "pair");
+        assert_eq!(snapshot.objects[&outer].edges, vec![inner]);
+        assert_eq!(snapshot.retainers(inner), vec![outer]);
+        assert_eq!(snapshot.path_to(inner), Some(("stack:1".to_owned(), vec![inner])));
+        let mut inspected = vec![];
+        snapshot.inspect(outer, &mut inspected).unwrap();
+        let inspected = String::from_utf8(inspected).unwrap();
+        assert!(inspected.starts_with(&format!("0x{:x}: pair (3 words)", outer)));
+        assert!(inspected.contains("reachable from stack:2"));
+    }
 }
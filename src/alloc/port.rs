@@ -0,0 +1,54 @@
+//! Ports.
+//!
+//! A port is a record whose descriptor is `PORT_DESCRIPTOR` (a
+//! `value::Port`), holding the address of its state, a `port::Port` on the
+//! Rust heap.  A finalizer frees the state, which closes the port, once the
+//! port is unreachable.
+
+use port::{self, Buffering, Sink, Source};
+use value::{self, Value};
+use super::Heap;
+
+impl Heap {
+    /// Allocates a port whose state is `port`, and pushes it on the stack.
+    pub fn alloc_port(&mut self, port: port::Port) -> Result<(), String> {
+        let state = Box::into_raw(Box::new(port)) as usize;
+        let base = self.stack.len();
+        self.stack.push(Value::new(value::PORT_DESCRIPTOR));
+        self.stack.push(Value::new(state));
+        let result = self.alloc_vector_like(value::HeaderTag::Record, base, base + 2);
+        let object = self.stack.pop().unwrap();
+        self.stack.truncate(base);
+        let free = move || unsafe { drop(Box::from_raw(state as *mut port::Port)) };
+        match result {
+            Ok(()) => {
+                self.stack.push(object.clone());
+                self.register_finalizer(object, Box::new(free))
+            }
+            Err(e) => {
+                free();
+                Err(e)
+            }
+        }
+    }
+    /// Allocates an input port named `name` reading from `reader`, and
+    /// pushes it on the stack.  Textual ports decode UTF-8.
+    pub fn alloc_port_from_reader(&mut self,
+                                  name: &str,
+                                  reader: Box<dyn Source>,
+                                  textual: bool)
+                                  -> Result<(), String> {
+        self.alloc_port(port::Port::input(name, reader, textual))
+    }
+
+    /// Allocates an output port named `name` writing to `writer`, and
+    /// pushes it on the stack.  Textual ports encode UTF-8.
+    pub fn alloc_port_from_writer(&mut self,
+                                  name: &str,
+                                  writer: Box<dyn Sink>,
+                                  textual: bool,
+                                  buffering: Buffering)
+                                  -> Result<(), String> {
+        self.alloc_port(port::Port::output(name, writer, textual, buffering))
+    }
+}
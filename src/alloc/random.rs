@@ -0,0 +1,24 @@
+//! Random sources.
+//!
+//! A random source is a `RustData` object laid out as a
+//! `value::RandomSource`: header, `RANDOM_SOURCE_TYPE`, and the state of the
+//! generator.  Like floats, random sources are copied by `relocate` but never
+//! scanned, so they need no finalizer.
+
+use value;
+use super::Heap;
+
+impl Heap {
+    /// Allocates a random source in state `state`, and pushes it on the
+    /// stack.
+    pub fn alloc_random_source(&mut self, state: u64) -> Result<(), String> {
+        let words = size_of!(value::RandomSource) / size_of!(value::Value);
+        let (value_ptr, final_len) = try!(self.alloc_raw(words, value::HeaderTag::RustData));
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let space = self.space_mut();
+        space.push(value::Value::new(value::RANDOM_SOURCE_TYPE));
+        space.resize(final_len, value::Value::new(0));
+        unsafe { (*(value_ptr as *mut value::RandomSource)).state = state }
+        Ok(self.stack.push(value::Value::new(ptr)))
+    }
+}
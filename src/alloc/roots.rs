@@ -0,0 +1,149 @@
+//! Persistent GC roots.
+//!
+//! A `Root` is a handle to an object on the garbage-collected Scheme heap.
+//! The Scheme garbage collector knows about `Root`s, and ensures that they
+//! stay valid even when a garbage collection occurs.  Therefore, client code
+//! must use `Root`s (or the stack) to store all references to Scheme data.
+//!
+//! Roots are stored in a `RootTable`, which is shared between the heap and
+//! every `Root`, so that dropping a `Root` can unregister it without access
+//! to the heap.  Free slots in the table form a linked list.
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::rc::Rc;
+use value::Value;
+use super::{Condemned, relocate};
+
+#[derive(Debug)]
+enum Slot {
+    /// A rooted Scheme object.
+    SchemeObject(Value),
+
+    /// A free slot, linking to the next free slot.
+    Free(Option<usize>),
+}
+
+/// The set of persistent roots of a heap.
+#[derive(Debug, Default)]
+pub struct RootTable {
+    slots: Vec<Slot>,
+    first_free: Option<usize>,
+}
+
+impl RootTable {
+    fn insert(&mut self, value: Value) -> usize {
+        match self.first_free {
+            Some(index) => {
+                self.first_free = match self.slots[index] {
+                    Slot::Free(next) => next,
+                    Slot::SchemeObject(_) => bug!("RootTable: free list points to a live slot"),
+                };
+                self.slots[index] = Slot::SchemeObject(value);
+                index
+            }
+            None => {
+                self.slots.push(Slot::SchemeObject(value));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.slots[index] = Slot::Free(self.first_free);
+        self.first_free = Some(index)
+    }
+
+    fn get(&self, index: usize) -> &Value {
+        match self.slots[index] {
+            Slot::SchemeObject(ref value) => value,
+            Slot::Free(_) => bug!("RootTable: dangling root"),
+        }
+    }
+
+    /// The number of live roots.
+    pub fn len(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| match **slot {
+                Slot::SchemeObject(_) => true,
+                Slot::Free(_) => false,
+            })
+            .count()
+    }
+
+    /// Relocates every root.
+    pub unsafe fn scavange(&mut self, tospace: &mut Vec<Value>, condemned: Condemned) {
+        for slot in &mut self.slots {
+            if let Slot::SchemeObject(ref mut value) = *slot {
+                relocate(value, tospace, condemned)
+            }
+        }
+    }
+
+    /// Iterates over the live roots.
+    pub fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Value> + 'a> {
+        Box::new(self.slots.iter().filter_map(|slot| match *slot {
+            Slot::SchemeObject(ref value) => Some(value),
+            Slot::Free(_) => None,
+        }))
+    }
+}
+
+/// A GC root.
+///
+/// The type parameter records what kind of object is rooted (for example,
+/// `value::Closure`), for the benefit of APIs that take roots.  It is not
+/// checked by the collector.
+///
+/// A global root can be created by any code that has a valid reference to
+/// the heap, and may live for as long as it likes – even longer than the
+/// heap, in which case it is simply inert.  Roots are more expensive than
+/// stack slots, but are not limited by a scope.
+#[derive(Debug)]
+pub struct Root<T: ?Sized = Value> {
+    index: usize,
+    table: Rc<RefCell<RootTable>>,
+    phantom: PhantomData<*const T>,
+}
+
+impl<T: ?Sized> Root<T> {
+    /// Registers `value` in `table`.
+    pub fn new(table: &Rc<RefCell<RootTable>>, value: Value) -> Self {
+        let index = table.borrow_mut().insert(value);
+        Root {
+            index: index,
+            table: table.clone(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Gets the rooted value.  The result is not itself rooted.
+    pub fn get(&self) -> Value {
+        self.table.borrow().get(self.index).clone()
+    }
+
+    /// Replaces the rooted value.
+    pub fn set(&self, value: Value) {
+        self.table.borrow_mut().slots[self.index] = Slot::SchemeObject(value)
+    }
+
+    /// Reinterprets the kind of object rooted.
+    pub fn cast<U: ?Sized>(self) -> Root<U> {
+        let root = Root::new(&self.table, self.get());
+        drop(self);
+        root
+    }
+}
+
+impl<T: ?Sized> Clone for Root<T> {
+    fn clone(&self) -> Self {
+        Root::new(&self.table, self.get())
+    }
+}
+
+impl<T: ?Sized> Drop for Root<T> {
+    fn drop(&mut self) {
+        self.table.borrow_mut().remove(self.index)
+    }
+}
@@ -1 +1,36 @@
-pub fn alloc_rustdata_tag() {
+//! Rust objects owned by the heap.
+//!
+//! Such an object is a `RustData` object laid out as a `value::RustData`:
+//! header, `RUST_OBJECT_TYPE`, and the address of a `Box<dyn Any>` on the
+//! Rust heap.  Like random sources, these are copied by `relocate` but never
+//! scanned.  A finalizer drops the box, running the object's destructor, once
+//! the `RustData` is unreachable.
+
+use std::any::Any;
+use value;
+use super::Heap;
+
+impl Heap {
+    /// Allocates a `RustData` owning `object`, and pushes it on the stack.
+    pub fn alloc_rustdata(&mut self, object: Box<dyn Any>) -> Result<(), String> {
+        let object = Box::into_raw(Box::new(object)) as usize;
+        let free = move || unsafe { drop(Box::from_raw(object as *mut Box<dyn Any>)) };
+        let words = size_of!(value::RustData) / size_of!(value::Value);
+        let (value_ptr, final_len) = match self.alloc_raw(words, value::HeaderTag::RustData) {
+            Ok(allocated) => allocated,
+            Err(e) => {
+                free();
+                return Err(e)
+            }
+        };
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        {
+            let space = self.space_mut();
+            space.push(value::Value::new(value::RUST_OBJECT_TYPE));
+            space.push(value::Value::new(object));
+            space.resize(final_len, value::Value::new(0));
+        }
+        self.stack.push(value::Value::new(ptr));
+        self.register_finalizer(value::Value::new(ptr), Box::new(free))
+    }
+}
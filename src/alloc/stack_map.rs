@@ -0,0 +1,72 @@
+//! Stack maps.
+//!
+//! The compiler records how many registers of its frame each instruction
+//! has in use (see `bytecode::BCO::live_registers`).  The registers above
+//! them are scratch space: the code writes them before it reads them again.
+//! Before a collection scans the stack, the scratch registers of the frames
+//! that are running an instruction are cleared, so that the garbage left in
+//! them is not kept alive, and so that they need not hold references at
+//! all: the collector never sees what an instruction leaves there.
+//!
+//! A frame making a call has no scratch registers on the stack, since the
+//! call drops the registers above the callee.  Frames whose code has no
+//! stack map, and the stacks of the suspended fibers, are scanned in full.
+
+use bytecode::{BCO, Opcode};
+use value::{self, Kind, Value};
+use super::Heap;
+
+impl Heap {
+    /// Clears the scratch registers of the frames on the stack.
+    pub fn clear_scratch_registers(&mut self) {
+        let frames = self.control.running_frames();
+        for (i, &(fp, pc)) in frames.iter().enumerate() {
+            if fp >= self.stack.len() {
+                continue
+            }
+            let end = frames.get(i + 1).map_or(self.stack.len(), |&(next, _)| next);
+            let (start, registers_end) = match scratch_registers(&self.stack[fp], pc) {
+                Some((start, end)) => (fp + 1 + start, fp + 1 + end),
+                None => continue,
+            };
+            let end = ::std::cmp::min(::std::cmp::min(end, registers_end), self.stack.len());
+            for slot in self.stack.iter_mut().take(end).skip(start) {
+                *slot = Value::new(value::UNSPECIFIED)
+            }
+        }
+    }
+}
+
+/// The code of `procedure`, if it is a closure over compiled code.
+fn code(procedure: &Value) -> Option<&BCO> {
+    match procedure.kind() {
+        Kind::Closure(closure) => {
+            match unsafe { (*closure).code.kind() } {
+                Kind::Bytecode(bco) => Some(unsafe { &*bco }),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The first scratch register of a frame of `procedure` that is running
+/// the instruction before `pc`, and the number of its registers, if it has
+/// scratch registers on the stack.
+fn scratch_registers(procedure: &Value, pc: usize) -> Option<(usize, usize)> {
+    let bco = match code(procedure) {
+        Some(bco) if pc > 0 && pc <= bco.len() => bco,
+        _ => return None,
+    };
+    match bco.instruction(pc - 1).opcode {
+        Opcode::Call | Opcode::TailCall | Opcode::CallJumpIfFalse | Opcode::LoadConstantCall |
+        Opcode::MoveCall => None,
+        _ => {
+            let registers = bco.instruction(0).dst as usize;
+            match bco.live_registers(pc - 1) {
+                Some(live) if live < registers => Some((live, registers)),
+                _ => None,
+            }
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Heap statistics.
+
+use std::time::Duration;
+
+/// Statistics about a heap, for tuning and monitoring.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes allocated since the last collection (full or minor).
+    pub bytes_allocated: usize,
+
+    /// The total number of collections, including minor collections.
+    pub collections: usize,
+
+    /// The number of minor collections.
+    pub minor_collections: usize,
+
+    /// Bytes in use immediately after the last collection.
+    pub live_bytes: usize,
+
+    /// The duration of the last collection (or of the final step of an
+    /// incremental collection).
+    pub last_pause: Duration,
+
+    /// The longest collection so far.
+    pub max_pause: Duration,
+
+    /// The total time spent collecting garbage.
+    pub total_pause: Duration,
+}
+
+impl HeapStats {
+    /// Records a finished collection.
+    pub fn record_collection(&mut self, minor: bool, live_bytes: usize, pause: Duration) {
+        self.bytes_allocated = 0;
+        self.collections += 1;
+        if minor {
+            self.minor_collections += 1
+        }
+        self.live_bytes = live_bytes;
+        self.last_pause = pause;
+        self.total_pause += pause;
+        if pause > self.max_pause {
+            self.max_pause = pause
+        }
+    }
+
+    /// Records an incremental collection step that did not finish a
+    /// collection.
+    pub fn record_slice(&mut self, pause: Duration) {
+        self.total_pause += pause;
+        if pause > self.max_pause {
+            self.max_pause = pause
+        }
+    }
+}
+
+/// Converts a `Duration` to whole microseconds.
+pub fn as_micros(duration: Duration) -> usize {
+    duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1_000
+}
@@ -0,0 +1,56 @@
+//! Strings.
+//!
+//! A string is a `RustData` object laid out as a `value::SchemeStr` (header,
+//! `STRING_TYPE`, and length in bytes), followed by the UTF-8 contents,
+//! padded to a whole number of words.  Strings are never scanned by the GC:
+//! `relocate` copies them like any other object, and `scavange_heap` skips
+//! over them.
+
+use value::{self, Value};
+use super::Heap;
+
+/// Checks that `value` is a string.
+fn as_string(value: &Value) -> Result<*mut value::SchemeStr, String> {
+    if value.raw_tag() != value::RUST_DATA_TAG {
+        return Err("Value is not a string".to_owned())
+    }
+    unsafe {
+        let ptr = value.as_ptr();
+        if (*ptr.offset(1)).get() != value::STRING_TYPE {
+            return Err("Value is not a string".to_owned())
+        }
+        Ok(ptr as *mut value::SchemeStr)
+    }
+}
+
+impl Heap {
+    /// Allocates a string holding a copy of `contents`, and pushes it on the
+    /// stack.
+    pub fn alloc_string(&mut self, contents: &str) -> Result<(), String> {
+        let words = (value::STRING_PAYLOAD + contents.len() + size_of!(Value) - 1) /
+                    size_of!(Value);
+        let (value_ptr, final_len) = try!(self.alloc_raw(words, value::HeaderTag::RustData));
+        let ptr = value_ptr as usize | value::RUST_DATA_TAG;
+        let space = self.space_mut();
+        space.extend_from_slice(&[Value::new(value::STRING_TYPE), Value::new(contents.len())]);
+        space.resize(final_len, Value::new(0));
+        unsafe {
+            let start = (value_ptr as usize + value::STRING_PAYLOAD) as *mut u8;
+            ::std::ptr::copy_nonoverlapping(contents.as_ptr(), start, contents.len())
+        }
+        Ok(self.stack.push(Value::new(ptr)))
+    }
+
+    /// The length of the string `string`, in bytes.
+    pub fn string_len(&self, string: &Value) -> Result<usize, String> {
+        let ptr = try!(as_string(string));
+        Ok(unsafe { (*ptr).len })
+    }
+
+    /// The contents of `string`.  The `&str` is invalidated by any
+    /// allocation.
+    pub unsafe fn string_as_str(&self, string: &Value) -> Result<&str, String> {
+        let ptr = try!(as_string(string));
+        Ok((*ptr).as_str())
+    }
+}
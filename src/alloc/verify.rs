@@ -0,0 +1,294 @@
+//! Verification of the whole heap.
+//!
+//! `Heap::verify` walks tospace, the nursery and the large object space,
+//! and checks that every object is well-formed, and that every pointer held
+//! by an object, the stacks, the roots, the symbols or the tables points to
+//! the start of an object of the right kind.  It also checks the invariant
+//! that minor collections rely on: every old object pointing into the
+//! nursery is in the remembered set.
+//!
+//! This is slow, so it is run around collections only if
+//! `HeapConfig::verify` is set, as it is by default in debug builds and
+//! with the `gc-verify` feature.
+
+use std::collections::{HashMap, HashSet};
+use std::slice;
+use std::str;
+use bytecode::BCO;
+use value::{self, Value, Tags, HEADER_TAG, HEADER_SIZE, IMMUTABLE};
+use super::{Heap, align_word_size, PAIR, VECTOR, BYTECODE, RUSTDATA, RECORD, CLOSURE};
+
+/// What the heap holds, as found by `Heap::verify`.
+struct Objects {
+    /// The headers of the objects, indexed by address.
+    headers: HashMap<usize, usize>,
+
+    /// The addresses of the symbols.
+    symbols: HashSet<usize>,
+
+    /// The handles of the hash tables, indexed by table.
+    hash_tables: HashMap<usize, Value>,
+
+    /// The handles of the weak tables, indexed by table.
+    weak_tables: HashMap<usize, Value>,
+}
+
+impl Objects {
+    /// The header of the object `value` points to, if it is a valid pointer
+    /// to an object on the heap.
+    fn header(&self, value: &Value) -> Option<usize> {
+        if value.immediatep() {
+            return None
+        }
+        match value.tag() {
+            Tags::Pair | Tags::Vector | Tags::RustData => {
+                self.headers.get(&(value.get() & !0b111)).cloned()
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks that `value` is an immediate, a primitive, a live symbol, or
+    /// a pointer to an object of the kind its tag says.
+    fn check_value(&self, value: &Value) -> Result<(), String> {
+        if value.immediatep() {
+            return Ok(())
+        }
+        let expected: &[usize] = match value.tag() {
+            Tags::Num | Tags::Num2 | Tags::RustFunc => return Ok(()),
+            Tags::Symbol if self.symbols.contains(&(value.get() & !0b111)) => return Ok(()),
+            Tags::Symbol => return Err(format!("0x{:x} is a dead symbol", value.get())),
+            Tags::Function => return Err(format!("0x{:x} has a reserved tag", value.get())),
+            Tags::Pair => &[PAIR],
+            Tags::Vector => &[VECTOR, RECORD, CLOSURE],
+            Tags::RustData => &[RUSTDATA, BYTECODE],
+        };
+        match self.header(value) {
+            Some(header) if expected.contains(&(header & HEADER_TAG)) => Ok(()),
+            Some(header) => {
+                Err(format!("0x{:x} points to an object with header 0x{:x}",
+                            value.get(),
+                            header))
+            }
+            None => Err(format!("0x{:x} does not point to an object", value.get())),
+        }
+    }
+
+    /// Checks the object at `address`, whose header is `header`.
+    unsafe fn check_object(&self, address: usize, header: usize) -> Result<(), String> {
+        let size = header & HEADER_SIZE;
+        let words = slice::from_raw_parts(address as *const Value, size);
+        for field in fields(words) {
+            try!(self.check_value(field))
+        }
+        match header & HEADER_TAG {
+            PAIR if header & !IMMUTABLE != value::PAIR_HEADER => {
+                Err(format!("bad pair header 0x{:x}", header))
+            }
+            RECORD => {
+                match self.header(&words[1]) {
+                    Some(header) if header & HEADER_TAG != RECORD => {
+                        Err("the descriptor is not a record".to_owned())
+                    }
+                    _ => Ok(()),
+                }
+            }
+            CLOSURE => {
+                let code = &words[1];
+                let primitive = code.tag() == Tags::RustFunc && !code.charp();
+                match self.header(code) {
+                    Some(header) if header & HEADER_TAG == BYTECODE => Ok(()),
+                    _ if primitive => Ok(()),
+                    _ => Err("the code is neither a BCO nor a primitive".to_owned()),
+                }
+            }
+            BYTECODE => {
+                let bco = &*(address as *const BCO);
+                if size < 3 || size_of!(BCO) + 4 * bco.len() > size * size_of!(Value) {
+                    return Err(format!("{} instructions do not fit in {} words", bco.len(), size))
+                }
+                match self.header(&bco.constants()) {
+                    Some(header) if header & HEADER_TAG == VECTOR => Ok(()),
+                    _ => Err("the constants are not a vector".to_owned()),
+                }
+            }
+            RUSTDATA => self.check_rust_data(address, words),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `handle` is the handle of table `index`, of type `ty`.
+    unsafe fn check_handle(&self, handle: &Value, ty: usize, index: usize) -> Result<(), String> {
+        try!(self.check_value(handle));
+        let words = handle.as_ptr();
+        match self.header(handle) {
+            Some(header) if header & HEADER_TAG == RUSTDATA && header & HEADER_SIZE >= 3 &&
+                            (*words.offset(1)).get() == ty &&
+                            (*words.offset(2)).get() == index => Ok(()),
+            _ => Err(format!("the handle of table {} is 0x{:x}", index, handle.get())),
+        }
+    }
+
+    /// Checks the `RustData` at `address`, made of `words`.
+    unsafe fn check_rust_data(&self, address: usize, words: &[Value]) -> Result<(), String> {
+        let bytes = words.len() * size_of!(Value);
+        let (needed, tables) = match words[1].get() {
+            value::STRING_TYPE | value::BYTEVECTOR_TYPE if words.len() < 3 => {
+                return Err("no room for the length".to_owned())
+            }
+            value::STRING_TYPE => {
+                let len = words[2].get();
+                if value::STRING_PAYLOAD + len <= bytes {
+                    let start = (address + value::STRING_PAYLOAD) as *const u8;
+                    if str::from_utf8(slice::from_raw_parts(start, len)).is_err() {
+                        return Err("the string is not UTF-8".to_owned())
+                    }
+                }
+                (value::STRING_PAYLOAD + len, None)
+            }
+            value::BYTEVECTOR_TYPE => (value::BYTEVECTOR_PAYLOAD + words[2].get(), None),
+            value::FLOAT_TYPE => (size_of!(value::Float), None),
+            value::RANDOM_SOURCE_TYPE => (size_of!(value::RandomSource), None),
+            value::RUST_OBJECT_TYPE => (size_of!(value::RustData), None),
+            value::HASH_TABLE_TYPE => (size_of!(value::HashTable), Some(&self.hash_tables)),
+            value::WEAK_TABLE_TYPE => (3 * size_of!(Value), Some(&self.weak_tables)),
+            ty => return Err(format!("unknown type {}", ty)),
+        };
+        if needed > bytes {
+            return Err(format!("{} bytes do not fit in {} words", needed, words.len()))
+        }
+        match tables {
+            Some(tables) => {
+                match tables.get(&words[2].get()) {
+                    Some(handle) if handle.get() & !0b111 == address => Ok(()),
+                    Some(_) => Err(format!("table {} has another handle", words[2].get())),
+                    None => Err(format!("table {} does not exist", words[2].get())),
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The fields of the object made of `words` that the collector relocates.
+fn fields(words: &[Value]) -> &[Value] {
+    match words[0].get() & HEADER_TAG {
+        PAIR | VECTOR | RECORD | CLOSURE => &words[1..],
+        BYTECODE if words.len() >= 3 => &words[2..3],
+        _ => &[],
+    }
+}
+
+/// Adds the address and header of every object in `space` to `headers`.
+fn find_objects(name: &str,
+                space: &[Value],
+                headers: &mut HashMap<usize, usize>)
+                -> Result<(), String> {
+    let mut index = 0;
+    while index < space.len() {
+        let header = space[index].get();
+        let size = header & HEADER_SIZE;
+        let address = &space[index] as *const Value as usize;
+        match header & HEADER_TAG {
+            PAIR | VECTOR | RECORD | CLOSURE | BYTECODE | RUSTDATA => {}
+            _ => return Err(format!("{}: strange header 0x{:x} at 0x{:x}", name, header, address)),
+        }
+        if size < 2 || index + size > space.len() {
+            return Err(format!("{}: bad size in header 0x{:x} at 0x{:x}", name, header, address))
+        }
+        headers.insert(address, header);
+        index += align_word_size(size)
+    }
+    Ok(())
+}
+
+impl Heap {
+    /// Checks the whole heap for corruption, returning a description of
+    /// the first problem found.  Very slow.
+    pub fn verify(&self) -> Result<(), String> {
+        let nursery = self.nursery.as_ref().map_or(&[][..], |n| &n[..]);
+        let large = self.large_objects.spaces();
+        let mut spaces = vec![("tospace", &self.tospace[..]), ("nursery", nursery)];
+        spaces.extend(large.iter().map(|&space| ("large object space", space)));
+        let mut objects = Objects {
+            headers: HashMap::new(),
+            symbols: self.symbol_table
+                         .contents
+                         .values()
+                         .map(|symbol| symbol.to_value().get() & !0b111)
+                         .collect(),
+            hash_tables: HashMap::new(),
+            weak_tables: HashMap::new(),
+        };
+        for &(name, space) in &spaces {
+            try!(find_objects(name, space, &mut objects.headers))
+        }
+        let mut held = vec![];
+        for (index, handle, values) in self.hash_tables.contents() {
+            objects.hash_tables.insert(index, handle);
+            held.extend(values)
+        }
+        for (index, handle, values) in self.weak_tables.contents() {
+            objects.weak_tables.insert(index, handle);
+            held.extend(values)
+        }
+        let nursery_range = (nursery.as_ptr() as usize,
+                             nursery.as_ptr() as usize + nursery.len() * size_of!(Value));
+        let in_nursery = |address: usize| address >= nursery_range.0 && address < nursery_range.1;
+        let remembered: HashSet<usize> =
+            self.remembered_set.iter().map(|object| object.get() & !0b111).collect();
+        for &address in &remembered {
+            match objects.headers.get(&address) {
+                Some(_) if !in_nursery(address) => {}
+                _ => return Err(format!("remembered set: 0x{:x} is not an old object", address)),
+            }
+        }
+        for (&address, &header) in &objects.headers {
+            unsafe {
+                try!(objects.check_object(address, header)
+                            .map_err(|e| format!("object at 0x{:x}: {}", address, e)));
+                let words = slice::from_raw_parts(address as *const Value, header & HEADER_SIZE);
+                let young = |field: &Value| !field.immediatep() && in_nursery(field.get() & !0b111);
+                if !in_nursery(address) && !remembered.contains(&address) &&
+                   fields(words).iter().any(young) {
+                    return Err(format!("object at 0x{:x} points into the nursery, but is not \
+                                        in the remembered set",
+                                       address))
+                }
+            }
+        }
+        for (&index, handle) in &objects.hash_tables {
+            try!(unsafe { objects.check_handle(handle, value::HASH_TABLE_TYPE, index) })
+        }
+        for (&index, handle) in &objects.weak_tables {
+            try!(unsafe { objects.check_handle(handle, value::WEAK_TABLE_TYPE, index) })
+        }
+        let fibers = self.control.fibers.iter().chain(self.control.suspended.iter());
+        let stacks = self.stack.iter().chain(fibers.flat_map(|fiber| fiber.stack.iter()));
+        for (index, value) in stacks.enumerate() {
+            try!(objects.check_value(value).map_err(|e| format!("stack slot {}: {}", index, e)))
+        }
+        for value in self.roots.borrow().iter() {
+            try!(objects.check_value(value).map_err(|e| format!("root: {}", e)))
+        }
+        for symbol in self.symbol_table.contents.values() {
+            let contents = unsafe { &*symbol.contents.get() };
+            try!(objects.check_value(contents)
+                        .map_err(|e| format!("symbol {}: {}", symbol.name(), e)))
+        }
+        for value in &held {
+            try!(objects.check_value(value).map_err(|e| format!("table entry: {}", e)))
+        }
+        Ok(())
+    }
+
+    /// Verifies the heap if `HeapConfig::verify` is set, panicking if it is
+    /// corrupt.  `when` says at which point of a collection this is.
+    pub(crate) fn verify_if_enabled(&self, when: &str) {
+        if self.config.verify {
+            if let Err(message) = self.verify() {
+                bug!("heap corrupt {}: {}", when, message)
+            }
+        }
+    }
+}
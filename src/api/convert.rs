@@ -0,0 +1,371 @@
+//! Conversions between Rust and Scheme values, for arguments and results of
+//! native procedures (see `Context::get` and `Context::value`).
+//!
+//! | Rust                | Scheme                                          |
+//! |---------------------|-------------------------------------------------|
+//! | integers            | fixnums                                         |
+//! | `f64`               | flonums; fixnums are also accepted              |
+//! | `bool`              | `#t` and `#f`                                   |
+//! | `char`              | characters                                      |
+//! | `String`, `&str`    | strings                                         |
+//! | `Option<T>`         | `#f` for `None`, and the value for `Some`       |
+//! | `Vec<T>`            | lists; vectors are also accepted                |
+//! | `HashMap<String,T>` | `equal?` hash tables with string keys           |
+//! | tuples              | lists of as many elements                       |
+//! | `Root`              | any value                                       |
+//!
+//! Since `#f` is `None`, an `Option<bool>` is never `Some(false)`.
+
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use alloc::{HashKind, Heap, Root};
+use value::{self, Kind, Value};
+
+/// A Rust value that can be converted to a Scheme value.
+pub trait ToScheme {
+    /// Pushes `self`, as a Scheme value, on the stack.
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String>;
+}
+
+/// A Rust value that can be converted from a Scheme value.
+pub trait FromScheme: Sized {
+    /// `value`, as a Rust value.  `value` must be rooted.
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String>;
+}
+
+/// Replaces the `len` values on top of the stack by a list of them.
+pub fn list(heap: &mut Heap, len: usize) -> Result<(), String> {
+    let start = heap.stack.len() - len;
+    heap.stack.push(Value::new(value::NIL));
+    for i in (start..start + len).rev() {
+        let top = heap.stack.len() - 1;
+        try!(heap.alloc_pair(i, top));
+        let pair = heap.stack.pop().unwrap();
+        heap.stack.truncate(top);
+        heap.stack.push(pair)
+    }
+    let list = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    Ok(heap.stack.push(list))
+}
+
+/// Converts the value at stack index `index`, where it stays rooted even if
+/// the conversion allocates.
+pub fn from_stack<T: FromScheme>(heap: &mut Heap, index: usize) -> Result<T, String> {
+    let value = heap.stack[index].clone();
+    T::from_scheme(heap, &value)
+}
+
+/// Converts each of `values`, which are pushed while they are converted.
+fn from_each<T: FromScheme>(heap: &mut Heap, values: &[Value]) -> Result<Vec<T>, String> {
+    let base = heap.stack.len();
+    heap.stack.extend_from_slice(values);
+    let result = (base..base + values.len()).map(|i| from_stack(heap, i)).collect();
+    heap.stack.truncate(base);
+    result
+}
+
+/// Pushes each of `values`, or nothing if one fails.
+pub fn push_all(heap: &mut Heap, values: &[&dyn ToScheme]) -> Result<(), String> {
+    let start = heap.stack.len();
+    for value in values {
+        if let Err(e) = value.to_scheme(heap) {
+            heap.stack.truncate(start);
+            return Err(e)
+        }
+    }
+    Ok(())
+}
+
+/// The elements of `value`, a proper list or a vector.
+pub fn elements(value: &Value) -> Result<Vec<Value>, String> {
+    if let Kind::Vector(vector) = value.kind() {
+        let len = unsafe { (*vector).len() };
+        return Ok((0..len).map(|i| unsafe { (*vector).element(i) }.clone()).collect())
+    }
+    let mut elements = vec![];
+    let mut rest = value.clone();
+    while let Kind::Pair(pair) = rest.kind() {
+        elements.push(unsafe { (*pair).car.clone() });
+        rest = unsafe { (*pair).cdr.clone() };
+    }
+    if rest.get() == value::NIL {
+        Ok(elements)
+    } else {
+        Err("expected a list".to_owned())
+    }
+}
+
+macro_rules! integer {
+    ($($t: ty),*) => {$(
+        impl ToScheme for $t {
+            fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+                let fixnum = if *self as i128 <= isize::max_value() as i128 {
+                    (*self as isize).checked_mul(4).and_then(Value::from_fixnum_word)
+                } else {
+                    None
+                };
+                let fixnum = try!(fixnum.ok_or_else(|| format!("{} is too large", self)));
+                Ok(heap.stack.push(fixnum))
+            }
+        }
+
+        impl FromScheme for $t {
+            fn from_scheme(_: &mut Heap, value: &Value) -> Result<Self, String> {
+                if !value.fixnump() {
+                    return Err("expected an exact integer".to_owned())
+                }
+                let n = value.get() as isize >> 2;
+                let (min, max) = (<$t>::min_value() as i128, <$t>::max_value() as i128);
+                if n as i128 >= min && n as i128 <= max {
+                    Ok(n as $t)
+                } else {
+                    Err(format!("{} is out of range", n))
+                }
+            }
+        }
+    )*}
+}
+
+integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToScheme for f64 {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        heap.alloc_float(*self)
+    }
+}
+
+impl FromScheme for f64 {
+    fn from_scheme(_: &mut Heap, value: &Value) -> Result<Self, String> {
+        match value.kind() {
+            Kind::Float(f) => Ok(f),
+            _ if value.fixnump() => Ok((value.get() as isize >> 2) as f64),
+            _ => Err("expected a number".to_owned()),
+        }
+    }
+}
+
+impl ToScheme for bool {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        Ok(heap.stack.push(Value::new(if *self { value::TRUE } else { value::FALSE })))
+    }
+}
+
+impl FromScheme for bool {
+    fn from_scheme(_: &mut Heap, value: &Value) -> Result<Self, String> {
+        match value.get() {
+            value::TRUE => Ok(true),
+            value::FALSE => Ok(false),
+            _ => Err("expected a boolean".to_owned()),
+        }
+    }
+}
+
+impl ToScheme for char {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        Ok(heap.stack.push(Value::new_char(*self)))
+    }
+}
+
+impl FromScheme for char {
+    fn from_scheme(_: &mut Heap, value: &Value) -> Result<Self, String> {
+        value.as_char().map_err(|_| "expected a character".to_owned())
+    }
+}
+
+impl<'a> ToScheme for &'a str {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        heap.alloc_string(self)
+    }
+}
+
+impl ToScheme for String {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        heap.alloc_string(self)
+    }
+}
+
+impl FromScheme for String {
+    fn from_scheme(_: &mut Heap, value: &Value) -> Result<Self, String> {
+        match value.kind() {
+            Kind::String(s) => Ok(unsafe { (*s).as_str() }.to_owned()),
+            _ => Err("expected a string".to_owned()),
+        }
+    }
+}
+
+impl<T: ToScheme> ToScheme for Option<T> {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        match *self {
+            Some(ref value) => value.to_scheme(heap),
+            None => Ok(heap.stack.push(Value::new(value::FALSE))),
+        }
+    }
+}
+
+impl<T: FromScheme> FromScheme for Option<T> {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        if value.get() == value::FALSE {
+            Ok(None)
+        } else {
+            T::from_scheme(heap, value).map(Some)
+        }
+    }
+}
+
+impl<T: ToScheme> ToScheme for Vec<T> {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        let elements: Vec<&dyn ToScheme> = self.iter().map(|e| e as &dyn ToScheme).collect();
+        try!(push_all(heap, &elements));
+        list(heap, self.len())
+    }
+}
+
+impl<T: FromScheme> FromScheme for Vec<T> {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        let elements = try!(elements(value));
+        from_each(heap, &elements)
+    }
+}
+
+impl<T: ToScheme, S: BuildHasher> ToScheme for HashMap<String, T, S> {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        try!(heap.alloc_hash_table(HashKind::Equal));
+        let table = heap.stack.len() - 1;
+        for (key, value) in self {
+            try!(push_all(heap, &[key as &dyn ToScheme, value]));
+            let result = heap.hash_table_set(table, table + 1, table + 2);
+            heap.stack.truncate(table + 1);
+            try!(result)
+        }
+        Ok(())
+    }
+}
+
+impl<T: FromScheme, S: BuildHasher + Default> FromScheme for HashMap<String, T, S> {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        match value.kind() {
+            Kind::HashTable(_) => {}
+            _ => return Err("expected a hash table".to_owned()),
+        }
+        let entries = try!(heap.hash_table_entries(value));
+        let base = heap.stack.len();
+        for &(ref key, ref value) in &entries {
+            heap.stack.push(key.clone());
+            heap.stack.push(value.clone())
+        }
+        let result = (0..entries.len())
+                         .map(|i| {
+                             let key = base + 2 * i;
+                             Ok((try!(from_stack(heap, key)), try!(from_stack(heap, key + 1))))
+                         })
+                         .collect();
+        heap.stack.truncate(base);
+        result
+    }
+}
+
+macro_rules! tuple {
+    ($len: expr; $($t: ident $index: tt),*) => {
+        impl<$($t: ToScheme),*> ToScheme for ($($t,)*) {
+            fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+                try!(push_all(heap, &[$(&self.$index as &dyn ToScheme),*]));
+                list(heap, $len)
+            }
+        }
+
+        impl<$($t: FromScheme),*> FromScheme for ($($t,)*) {
+            fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+                let elements = try!(elements(value));
+                if elements.len() != $len {
+                    return Err(format!("expected a list of {} elements", $len))
+                }
+                let base = heap.stack.len();
+                heap.stack.extend_from_slice(&elements);
+                let result = (|| Ok(($(try!(from_stack::<$t>(heap, base + $index)),)*)))();
+                heap.stack.truncate(base);
+                result
+            }
+        }
+    }
+}
+
+tuple!(1; A 0);
+tuple!(2; A 0, B 1);
+tuple!(3; A 0, B 1, C 2);
+tuple!(4; A 0, B 1, C 2, D 3);
+
+impl ToScheme for Root {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        Ok(heap.stack.push(self.get()))
+    }
+}
+
+impl FromScheme for Root {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        Ok(heap.root(value.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use alloc::{Heap, HeapConfig, Root};
+    use value::Value;
+    use super::{FromScheme, ToScheme};
+
+    /// A pair of a value with itself, which is allocated when converting,
+    /// so that in stress mode each conversion collects.
+    struct Doubled(Root);
+
+    impl FromScheme for Doubled {
+        fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+            heap.stack.push(value.clone());
+            let top = heap.stack.len() - 1;
+            let result = heap.alloc_pair(top, top);
+            let pair = heap.stack.pop().unwrap();
+            heap.stack.truncate(top);
+            try!(result);
+            Ok(Doubled(heap.root(pair)))
+        }
+    }
+
+    fn string(heap: &mut Heap, value: &Value) -> String {
+        String::from_scheme(heap, value).unwrap()
+    }
+
+    #[test]
+    fn elements_stay_rooted_while_converted() {
+        let mut heap = Heap::with_config(HeapConfig {
+            stress: true,
+            poison: true,
+            ..Default::default()
+        });
+        let names = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        let elements: Vec<_> = names.iter().map(|name| (name.clone(), name.clone())).collect();
+        elements.to_scheme(&mut heap).unwrap();
+        let list: Root = heap.root_top().unwrap();
+        let converted: Vec<(Doubled, Root)> = FromScheme::from_scheme(&mut heap, &list.get())
+                                                   .unwrap();
+        for (name, &(ref doubled, ref root)) in names.iter().zip(&converted) {
+            let car = doubled.0.get().car().unwrap();
+            assert_eq!(&string(&mut heap, &car), name);
+            assert_eq!(&string(&mut heap, &root.get()), name);
+        }
+
+        let table: HashMap<String, (String, String)> = names.iter()
+                                                            .cloned()
+                                                            .zip(elements.iter().cloned())
+                                                            .collect();
+        table.to_scheme(&mut heap).unwrap();
+        let table: Root = heap.root_top().unwrap();
+        let converted: HashMap<String, (Doubled, Root)> =
+            FromScheme::from_scheme(&mut heap, &table.get()).unwrap();
+        assert_eq!(converted.len(), names.len());
+        for (name, &(ref doubled, ref root)) in &converted {
+            let car = doubled.0.get().car().unwrap();
+            assert_eq!(&string(&mut heap, &car), name);
+            assert_eq!(&string(&mut heap, &root.get()), name);
+        }
+    }
+}
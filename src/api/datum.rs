@@ -0,0 +1,514 @@
+//! Serde support, with the `serde` feature.
+//!
+//! A `Datum` is a copy of a Scheme value as a Rust tree, which serde can
+//! serialize, deserialize, and deserialize other types from.  It converts to
+//! and from Scheme values with `Interpreter::value` and `Interpreter::get`:
+//!
+//! | Scheme                          | `Datum`                 | serde         |
+//! |---------------------------------|-------------------------|---------------|
+//! | `#t` and `#f`                   | `Boolean`               | bool          |
+//! | fixnums                         | `Integer`               | i64           |
+//! | flonums                         | `Float`                 | f64           |
+//! | characters                      | `Char`                  | char          |
+//! | strings                         | `String`                | string        |
+//! | symbols                         | `Symbol`                | string        |
+//! | bytevectors                     | `Bytes`                 | bytes         |
+//! | proper lists                    | `List`                  | sequence      |
+//! | vectors                         | `Vector`                | sequence      |
+//! | hash tables                     | `Map`                   | map           |
+//! | records                         | `Record`                | map of fields |
+//!
+//! Serde's unit and `None` become `#f`, as in `FromScheme`; strings become
+//! Scheme strings, sequences lists and maps `equal?` hash tables.  Records
+//! are converted back through the record type's global variable, as
+//! `SchemeRecord` types are.  Other values, such as procedures, improper
+//! lists and values nested more than `MAX_DEPTH` deep, cannot be converted.
+//!
+//! Deserializing from a `Datum` accepts enums with unit variants only, named
+//! by strings or symbols.
+//!
+//! ```rust,ignore
+//! let value = interp.eval_str("(vector 1 \"two\" 'three)")?;
+//! let datum: Datum = interp.get(&value)?;
+//! assert_eq!(serde_json::to_string(&datum)?, r#"[1,"two","three"]"#);
+//! let (one, two, three): (u8, String, String) = interp.deserialize(&value)?;
+//! ```
+
+use std::fmt;
+use serde::de::{self, Deserialize, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+use alloc::{HashKind, Heap};
+use value::{self, Kind, Value};
+use super::{FromScheme, SchemeError, ToScheme};
+use super::convert::{self, list, push_all};
+use super::record;
+
+/// How deeply values may be nested.  This also stops circular structures.
+pub const MAX_DEPTH: usize = 512;
+
+/// A Scheme value, copied out of the heap.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Datum {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Char(char),
+    String(String),
+    Symbol(String),
+    Bytes(Vec<u8>),
+    List(Vec<Datum>),
+    Vector(Vec<Datum>),
+    Map(Vec<(Datum, Datum)>),
+
+    /// A record: the name of its type, and its fields with their names.
+    Record(String, Vec<(String, Datum)>),
+}
+
+/// The name of `symbol`.
+fn symbol_name(symbol: &Value) -> String {
+    match symbol.kind() {
+        Kind::Symbol(symbol) => unsafe { (*symbol).name() }.to_string(),
+        _ => bug!("record type or field name that is not a symbol"),
+    }
+}
+
+/// The names of the fields of records of type `rtd`, inherited ones first.
+fn field_names(rtd: &Value) -> Vec<String> {
+    let mut names = vec![];
+    let mut rtd = rtd.clone();
+    while let Kind::Record(record) = rtd.kind() {
+        let rtd_record = record as *mut value::RecordDescriptor;
+        let mut own = vec![];
+        let mut fields = unsafe { (*rtd_record).fields.clone() };
+        while let Ok(field) = fields.car() {
+            own.push(symbol_name(&field.car().unwrap()));
+            fields = fields.cdr().unwrap()
+        }
+        own.extend(names);
+        names = own;
+        rtd = unsafe { (*rtd_record).parent.clone() }
+    }
+    names
+}
+
+/// `value` as a `Datum`, `depth` levels down.
+fn datum(heap: &mut Heap, value: &Value, depth: usize) -> Result<Datum, String> {
+    if depth == MAX_DEPTH {
+        return Err("value nested too deeply, or circular".to_owned())
+    }
+    let elements = |heap: &mut Heap, elements: Vec<Value>| -> Result<Vec<Datum>, String> {
+        elements.iter().map(|element| datum(heap, element, depth + 1)).collect()
+    };
+    Ok(match value.kind() {
+        Kind::Constant(value::TRUE) => Datum::Boolean(true),
+        Kind::Constant(value::FALSE) => Datum::Boolean(false),
+        Kind::Constant(value::NIL) => Datum::List(vec![]),
+        Kind::Fixnum(_) => Datum::Integer((value.get() as isize >> 2) as i64),
+        Kind::Float(f) => Datum::Float(f),
+        Kind::Char(c) => Datum::Char(c),
+        Kind::String(s) => Datum::String(unsafe { (*s).as_str() }.to_owned()),
+        Kind::Symbol(_) => Datum::Symbol(symbol_name(value)),
+        Kind::Bytevector(b) => Datum::Bytes(unsafe { (*b).as_slice() }.to_vec()),
+        Kind::Pair(_) => {
+            let list = try!(convert::elements(value)
+                                .map_err(|_| "cannot convert an improper list".to_owned()));
+            Datum::List(try!(elements(heap, list)))
+        }
+        Kind::Vector(_) => {
+            let vector = try!(convert::elements(value));
+            Datum::Vector(try!(elements(heap, vector)))
+        }
+        Kind::HashTable(_) => {
+            let mut entries = vec![];
+            for (key, value) in try!(heap.hash_table_entries(value)) {
+                entries.push((try!(datum(heap, &key, depth + 1)),
+                              try!(datum(heap, &value, depth + 1))))
+            }
+            Datum::Map(entries)
+        }
+        Kind::Record(record) => {
+            let rtd = unsafe { (*record).descriptor.clone() };
+            let name = match rtd.kind() {
+                Kind::Record(rtd) => {
+                    symbol_name(unsafe { &(*(rtd as *mut value::RecordDescriptor)).name })
+                }
+                _ => return Err("cannot convert a record type descriptor".to_owned()),
+            };
+            let mut fields = vec![];
+            for (index, field) in field_names(&rtd).into_iter().enumerate() {
+                let value = unsafe { (*record).field(index) }.clone();
+                fields.push((field, try!(datum(heap, &value, depth + 1))))
+            }
+            Datum::Record(name, fields)
+        }
+        _ => {
+            let printed = ::print::to_string(value, ::print::Mode::Write);
+            return Err(format!("cannot convert {}", printed))
+        }
+    })
+}
+
+impl FromScheme for Datum {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        datum(heap, value, 0)
+    }
+}
+
+impl ToScheme for Datum {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        match *self {
+            Datum::Boolean(b) => b.to_scheme(heap),
+            Datum::Integer(i) => i.to_scheme(heap),
+            Datum::Float(f) => f.to_scheme(heap),
+            Datum::Char(c) => c.to_scheme(heap),
+            Datum::String(ref s) => s.to_scheme(heap),
+            Datum::Symbol(ref s) => Ok(heap.intern(s)),
+            Datum::Bytes(ref bytes) => heap.alloc_bytevector_from(bytes),
+            Datum::List(ref elements) => {
+                let elements: Vec<&dyn ToScheme> = elements.iter().map(|e| e as _).collect();
+                try!(push_all(heap, &elements));
+                list(heap, elements.len())
+            }
+            Datum::Vector(ref elements) => {
+                let elements: Vec<&dyn ToScheme> = elements.iter().map(|e| e as _).collect();
+                let start = heap.stack.len();
+                try!(push_all(heap, &elements));
+                let result = heap.alloc_vector(start, start + elements.len());
+                let vector = heap.stack.pop().unwrap();
+                heap.stack.truncate(start);
+                try!(result);
+                Ok(heap.stack.push(vector))
+            }
+            Datum::Map(ref entries) => {
+                try!(heap.alloc_hash_table(HashKind::Equal));
+                let table = heap.stack.len() - 1;
+                for &(ref key, ref value) in entries {
+                    try!(push_all(heap, &[key as &dyn ToScheme, value]));
+                    let result = heap.hash_table_set(table, table + 1, table + 2);
+                    heap.stack.truncate(table + 1);
+                    try!(result)
+                }
+                Ok(())
+            }
+            Datum::Record(ref name, ref fields) => {
+                let fields: Vec<&dyn ToScheme> =
+                    fields.iter().map(|&(_, ref field)| field as _).collect();
+                record::push_record(heap, name, &fields)
+            }
+        }
+    }
+}
+
+impl Serialize for Datum {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Datum::Boolean(b) => serializer.serialize_bool(b),
+            Datum::Integer(i) => serializer.serialize_i64(i),
+            Datum::Float(f) => serializer.serialize_f64(f),
+            Datum::Char(c) => serializer.serialize_char(c),
+            Datum::String(ref s) | Datum::Symbol(ref s) => serializer.serialize_str(s),
+            Datum::Bytes(ref bytes) => serializer.serialize_bytes(bytes),
+            Datum::List(ref elements) | Datum::Vector(ref elements) => {
+                let mut seq = try!(serializer.serialize_seq(Some(elements.len())));
+                for element in elements {
+                    try!(seq.serialize_element(element))
+                }
+                seq.end()
+            }
+            Datum::Map(ref entries) => {
+                let mut map = try!(serializer.serialize_map(Some(entries.len())));
+                for &(ref key, ref value) in entries {
+                    try!(map.serialize_entry(key, value))
+                }
+                map.end()
+            }
+            Datum::Record(_, ref fields) => {
+                let mut map = try!(serializer.serialize_map(Some(fields.len())));
+                for &(ref name, ref value) in fields {
+                    try!(map.serialize_entry(name, value))
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+/// Builds a `Datum` from what serde reads.
+struct DatumVisitor;
+
+impl<'de> Visitor<'de> for DatumVisitor {
+    type Value = Datum;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value that Scheme can represent")
+    }
+
+    fn visit_bool<E: de::Error>(self, b: bool) -> Result<Datum, E> {
+        Ok(Datum::Boolean(b))
+    }
+
+    fn visit_i64<E: de::Error>(self, i: i64) -> Result<Datum, E> {
+        Ok(Datum::Integer(i))
+    }
+
+    fn visit_u64<E: de::Error>(self, u: u64) -> Result<Datum, E> {
+        if u <= i64::max_value() as u64 {
+            Ok(Datum::Integer(u as i64))
+        } else {
+            Err(E::custom(format!("{} is too large", u)))
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, f: f64) -> Result<Datum, E> {
+        Ok(Datum::Float(f))
+    }
+
+    fn visit_char<E: de::Error>(self, c: char) -> Result<Datum, E> {
+        Ok(Datum::Char(c))
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Datum, E> {
+        Ok(Datum::String(s.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, s: String) -> Result<Datum, E> {
+        Ok(Datum::String(s))
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Datum, E> {
+        Ok(Datum::Bytes(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Datum, E> {
+        Ok(Datum::Bytes(bytes))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Datum, E> {
+        Ok(Datum::Boolean(false))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Datum, E> {
+        Ok(Datum::Boolean(false))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Datum, D::Error> {
+        Datum::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Datum, D::Error>
+        where D: Deserializer<'de>
+    {
+        Datum::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Datum, A::Error> {
+        let mut elements = vec![];
+        while let Some(element) = try!(seq.next_element()) {
+            elements.push(element)
+        }
+        Ok(Datum::List(elements))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Datum, A::Error> {
+        let mut entries = vec![];
+        while let Some(entry) = try!(map.next_entry()) {
+            entries.push(entry)
+        }
+        Ok(Datum::Map(entries))
+    }
+}
+
+impl<'de> Deserialize<'de> for Datum {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(DatumVisitor)
+    }
+}
+
+impl ser::Error for SchemeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SchemeError::new(message.to_string())
+    }
+}
+
+impl de::Error for SchemeError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        SchemeError::new(message.to_string())
+    }
+}
+
+impl<'de> IntoDeserializer<'de, SchemeError> for Datum {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Visits the sequence `elements`, checking that all of them are used.
+fn visit_seq<'de, V>(visitor: V, elements: Vec<Datum>) -> Result<V::Value, SchemeError>
+    where V: Visitor<'de>
+{
+    let mut seq = SeqDeserializer::new(elements.into_iter());
+    let value = try!(visitor.visit_seq(&mut seq));
+    try!(seq.end());
+    Ok(value)
+}
+
+/// Visits the map `entries`, checking that all of them are used.
+fn visit_map<'de, V, K>(visitor: V, entries: Vec<(K, Datum)>) -> Result<V::Value, SchemeError>
+    where V: Visitor<'de>,
+          K: IntoDeserializer<'de, SchemeError>
+{
+    let mut map = MapDeserializer::new(entries.into_iter());
+    let value = try!(visitor.visit_map(&mut map));
+    try!(map.end());
+    Ok(value)
+}
+
+/// Deserializing other types from a `Datum`.
+impl<'de> Deserializer<'de> for Datum {
+    type Error = SchemeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemeError> {
+        match self {
+            Datum::Boolean(b) => visitor.visit_bool(b),
+            Datum::Integer(i) => visitor.visit_i64(i),
+            Datum::Float(f) => visitor.visit_f64(f),
+            Datum::Char(c) => visitor.visit_char(c),
+            Datum::String(s) | Datum::Symbol(s) => visitor.visit_string(s),
+            Datum::Bytes(bytes) => visitor.visit_byte_buf(bytes),
+            Datum::List(elements) | Datum::Vector(elements) => visit_seq(visitor, elements),
+            Datum::Map(entries) => visit_map(visitor, entries),
+            Datum::Record(_, fields) => visit_map(visitor, fields),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemeError> {
+        match self {
+            Datum::Boolean(false) => visitor.visit_none(),
+            datum => visitor.visit_some(datum),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemeError> {
+        match self {
+            Datum::Boolean(false) => visitor.visit_unit(),
+            datum => datum.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self,
+                                     _: &'static str,
+                                     visitor: V)
+                                     -> Result<V::Value, SchemeError>
+        where V: Visitor<'de>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(self,
+                           _: &'static str,
+                           _: &'static [&'static str],
+                           visitor: V)
+                           -> Result<V::Value, SchemeError>
+        where V: Visitor<'de>
+    {
+        match self {
+            Datum::String(variant) | Datum::Symbol(variant) => {
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            _ => Err(SchemeError::new("expected the name of a unit variant")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Deserializing from a borrowed `Datum`, which is copied.
+impl<'de> Deserializer<'de> for &'de Datum {
+    type Error = SchemeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemeError> {
+        self.clone().deserialize_any(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use api::Interpreter;
+    use super::Datum;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Color {
+        Red,
+        Green,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Config {
+        name: String,
+        sizes: Vec<u32>,
+        color: Color,
+        parent: Option<String>,
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut interp = Interpreter::new();
+        let value = interp.eval_str("(define table (make-hash-table equal?))
+                                     (hash-table-set! table \"b\" (bytevector 1 2))
+                                     (list 1 2.5 #\\x \"s\" 'sym (vector #t '()) table)")
+                          .unwrap();
+        let datum: Datum = interp.get(&value).unwrap();
+        assert_eq!(serde_json::to_string(&datum).unwrap(),
+                   r#"[1,2.5,"x","s","sym",[true,[]],{"b":[1,2]}]"#);
+        let value = interp.value(datum.clone()).unwrap();
+        assert_eq!(interp.get(&value), Ok(datum));
+        let datum: Datum = serde_json::from_str(r#"{"a": [1, null], "b": 2}"#).unwrap();
+        let value = interp.value(datum).unwrap();
+        interp.define("table", &value).unwrap();
+        let value = interp.eval_str("(map (lambda (k) (hash-table-ref table k)) '(\"a\" \"b\"))")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value), "((1 #f) 2)");
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn deserialize() {
+        let mut interp = Interpreter::new();
+        let value = interp.eval_str("(define <config>
+                                       (make-record-type-descriptor
+                                        'config #f #f #f #f
+                                        '((immutable name) (immutable sizes)
+                                          (immutable color) (immutable parent))))
+                                     ((record-constructor <config>) \"top\" '(1 2) 'Green #f)")
+                          .unwrap();
+        assert_eq!(interp.deserialize(&value).ok(),
+                   Some(Config {
+                       name: "top".to_owned(),
+                       sizes: vec![1, 2],
+                       color: Color::Green,
+                       parent: None,
+                   }));
+        let value = interp.eval_str("'(1 \"two\" #\\3)").unwrap();
+        assert_eq!(interp.deserialize::<(u8, String, char)>(&value).ok(),
+                   Some((1, "two".to_owned(), '3')));
+        assert!(interp.deserialize::<(u8, String)>(&value).is_err());
+        let value = interp.eval_str("(let ((l (list 1))) (set-cdr! l l) l)").unwrap();
+        assert!(interp.get::<Datum>(&value).is_err());
+        let value = interp.eval_str("car").unwrap();
+        assert!(interp.get::<Datum>(&value).is_err());
+        assert!(interp.state().is_empty());
+    }
+}
@@ -0,0 +1,1081 @@
+//! `Interpreter`, a facade over `State` for programs that just want to run
+//! Scheme code and use its results, without managing the stack.
+//!
+//! Values are handed out as `Root`s, which stay valid across garbage
+//! collections for as long as they are kept.  The stack is left as it was
+//! found, even when evaluation fails.  Failures are `SchemeError`s, which
+//! say whether reading, compiling or running the code failed; runtime
+//! errors carry the object that was raised, if any, and a backtrace.
+//!
+//! Rust closures can be registered as Scheme procedures with `define_fn`
+//! and `define_variadic_fn`.  They are passed a `Context`, through which
+//! they make and inspect values, and their arguments.  Those registered
+//! with `define_async_fn` return futures, which code evaluated by
+//! `eval_async` waits for without blocking its executor.  Any Rust object can
+//! be handed to Scheme code with `rust_data`, and recovered with
+//! `downcast_ref`; it is dropped once it is garbage.
+//!
+//! ```rust
+//! let mut interp = rusty_scheme::Interpreter::new();
+//! interp.eval_str("(define (square x) (* x x))").unwrap();
+//! let seven = interp.value(7usize).unwrap();
+//! let result = interp.call("square", &[seven]).unwrap();
+//! assert_eq!(interp.get::<usize>(&result), Ok(49));
+//!
+//! interp.define_fn("twice", 1, |cx, args| {
+//!     let n: usize = try!(cx.get(&args[0]));
+//!     cx.value(2 * n)
+//! }).unwrap();
+//! let result = interp.eval_str("(twice (square 3))").unwrap();
+//! assert_eq!(interp.get::<usize>(&result), Ok(18));
+//! ```
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{self, Poll};
+use alloc::Heap;
+use builtins::{self, Function, Pending};
+use print;
+use value::{Closure, Kind};
+use super::{BacktraceFrame, FromScheme, Fuel, Input, ReadError, Root, SandboxConfig,
+            SchemeRecord, State, Suspension, ToScheme};
+use super::record;
+#[cfg(feature = "serde")]
+use serde::de::{Deserialize, DeserializeOwned};
+#[cfg(feature = "serde")]
+use super::Datum;
+
+/// A Scheme value held by the embedder.
+pub type Value = Root;
+
+/// Why reading, compiling or running code, or a conversion, failed.
+#[derive(Debug)]
+pub enum SchemeError {
+    /// The source text could not be read.
+    Read(ReadError),
+
+    /// The code could not be expanded or compiled.
+    Compile(String),
+
+    /// Running the code failed, or a conversion did.
+    Runtime {
+        /// The error message, as Scheme code would see it.
+        message: String,
+
+        /// The object that was raised, if the error was an exception that
+        /// no handler caught.
+        condition: Option<Root>,
+
+        /// The procedure calls that the error unwound, innermost first.
+        backtrace: Vec<BacktraceFrame>,
+    },
+}
+
+/// The error type of the embedding API.
+pub type Error = SchemeError;
+
+impl SchemeError {
+    /// A runtime error with no condition or backtrace, as native procedures
+    /// and failed conversions return.
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        SchemeError::Runtime {
+            message: message.into(),
+            condition: None,
+            backtrace: vec![],
+        }
+    }
+
+    /// The error message.
+    pub fn message(&self) -> String {
+        match *self {
+            SchemeError::Read(ref error) => format!("read error: {:?}", error),
+            SchemeError::Compile(ref message) |
+            SchemeError::Runtime { ref message, .. } => message.clone(),
+        }
+    }
+
+    /// The object that was raised, for an exception that no handler caught.
+    pub fn condition(&self) -> Option<&Root> {
+        match *self {
+            SchemeError::Runtime { ref condition, .. } => condition.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The procedure calls that a runtime error unwound, innermost first.
+    pub fn backtrace(&self) -> &[BacktraceFrame] {
+        match *self {
+            SchemeError::Runtime { ref backtrace, .. } => backtrace,
+            _ => &[],
+        }
+    }
+}
+
+/// Errors are equal if they are of the same kind, with the same message.
+impl PartialEq for SchemeError {
+    fn eq(&self, other: &Self) -> bool {
+        mem::discriminant(self) == mem::discriminant(other) && self.message() == other.message()
+    }
+}
+
+impl Eq for SchemeError {}
+
+impl From<String> for SchemeError {
+    fn from(message: String) -> Self {
+        SchemeError::new(message)
+    }
+}
+
+impl From<ReadError> for SchemeError {
+    fn from(error: ReadError) -> Self {
+        SchemeError::Read(error)
+    }
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message())
+    }
+}
+
+impl error::Error for SchemeError {
+    fn description(&self) -> &str {
+        match *self {
+            SchemeError::Read(_) => "read error",
+            SchemeError::Compile(ref message) |
+            SchemeError::Runtime { ref message, .. } => message,
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            SchemeError::Read(ReadError::IoError(ref error)) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// What a Rust closure called from Scheme can do with values.
+pub struct Context<'a> {
+    heap: &'a mut Heap,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(heap: &'a mut Heap) -> Self {
+        Context { heap: heap }
+    }
+
+    /// The heap, for the C API.
+    pub(crate) fn heap(&mut self) -> &mut Heap {
+        self.heap
+    }
+
+    /// Converts a Rust value to a Scheme value (see `ToScheme`).
+    pub fn value<T: ToScheme>(&mut self, value: T) -> Result<Value, Error> {
+        try!(value.to_scheme(self.heap));
+        let value = self.heap.stack.pop().unwrap();
+        Ok(self.heap.root(value))
+    }
+
+    /// Converts a Scheme value to a Rust value (see `FromScheme`).
+    pub fn get<T: FromScheme>(&mut self, value: &Value) -> Result<T, Error> {
+        T::from_scheme(self.heap, &value.get()).map_err(Error::new)
+    }
+
+    /// Deserializes a Rust value from a Scheme value, by way of `Datum`.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: DeserializeOwned>(&mut self, value: &Value) -> Result<T, Error> {
+        let datum: Datum = try!(self.get(value));
+        T::deserialize(datum)
+    }
+
+    /// `value`, printed as by `write`.
+    pub fn write_string(&self, value: &Value) -> String {
+        print::to_string(&value.get(), print::Mode::Write)
+    }
+
+    /// Moves `object` into the heap, which drops it once Scheme code and the
+    /// embedder no longer refer to it.
+    pub fn rust_data<T: Any>(&mut self, object: T) -> Result<Value, Error> {
+        try!(self.heap.alloc_rustdata(Box::new(object)));
+        let data = self.heap.stack.pop().unwrap();
+        Ok(self.heap.root(data))
+    }
+
+    /// The Rust object owned by `value`, if it is a `T`.
+    pub fn downcast_ref<'b, T: Any>(&self, value: &'b Value) -> Option<&'b T> {
+        downcast_ref(value)
+    }
+}
+
+fn downcast_ref<T: Any>(value: &Value) -> Option<&T> {
+    match value.get().kind() {
+        Kind::RustData(data) => unsafe { (*data).downcast_ref() },
+        _ => None,
+    }
+}
+
+/// The evaluation of Scheme code as a future (see
+/// `Interpreter::eval_async`).  Its value is that of the last datum.
+pub struct Evaluation<'a> {
+    interp: &'a mut Interpreter,
+
+    /// The length of the stack to leave behind.
+    len: usize,
+
+    /// The data still to evaluate.
+    data: VecDeque<Value>,
+
+    /// The evaluation of the current datum, if it waits for a future.
+    suspension: Option<Suspension>,
+
+    /// The value of the last datum evaluated.
+    last: Option<Value>,
+
+    /// The error that reading the code failed with, if any.
+    error: Option<Error>,
+}
+
+impl<'a> Evaluation<'a> {
+    /// Runs the code until it finishes, fails, or waits for a future that
+    /// is not ready.
+    fn run(&mut self, cx: &mut task::Context) -> Poll<Result<Value, Error>> {
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Err(e))
+        }
+        loop {
+            let fuel = match self.suspension.take() {
+                Some(suspension) => {
+                    match self.interp.state.poll_await(cx) {
+                        Poll::Ready(Ok(())) => self.interp.state.resume(suspension, usize::MAX),
+                        Poll::Ready(Err(e)) => Err(e),
+                        Poll::Pending => {
+                            self.suspension = Some(suspension);
+                            return Poll::Pending
+                        }
+                    }
+                }
+                None => {
+                    match self.data.pop_front() {
+                        Some(datum) => {
+                            self.interp.state.push_root(&datum);
+                            if let Err(e) = self.interp.state.compile() {
+                                return Poll::Ready(Err(SchemeError::Compile(e)))
+                            }
+                            self.interp.state.run_compiled_with_fuel(usize::MAX)
+                        }
+                        None => {
+                            let last = self.last.take();
+                            return Poll::Ready(last.ok_or_else(|| {
+                                Error::new("No expression to evaluate")
+                            }))
+                        }
+                    }
+                }
+            };
+            match fuel {
+                Ok(Fuel::Finished) => {
+                    match self.interp.state.root() {
+                        Ok(value) => self.last = Some(value),
+                        Err(e) => return Poll::Ready(Err(Error::new(e))),
+                    }
+                }
+                Ok(Fuel::Awaiting(suspension)) => self.suspension = Some(suspension),
+                Ok(Fuel::Suspended(_)) => {
+                    return Poll::Ready(Err(self.interp.runtime_error("Out of fuel".to_owned())))
+                }
+                Err(e) => return Poll::Ready(Err(self.interp.runtime_error(e))),
+            }
+        }
+    }
+}
+
+impl<'a> Future for Evaluation<'a> {
+    type Output = Result<Value, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        let result = self.run(cx);
+        if result.is_ready() {
+            let len = self.len;
+            self.interp.state.heap().stack.truncate(len)
+        }
+        result
+    }
+}
+
+/// The future of an async procedure, with its result boxed as `Pending`
+/// needs.
+struct Boxed<R>(Pin<Box<R>>);
+
+impl<R, T> Future for Boxed<R>
+    where R: Future<Output = Result<T, Error>>,
+          T: ToScheme + 'static
+{
+    type Output = Result<Box<dyn ToScheme>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        match self.0.as_mut().poll(cx) {
+            Poll::Ready(result) => {
+                Poll::Ready(result.map(|result| Box::new(result) as Box<dyn ToScheme>))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// An interpreter, with its heap and global environment.
+#[derive(Default)]
+pub struct Interpreter {
+    state: State,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter { state: State::new() }
+    }
+
+    /// Creates an interpreter without the Scheme part of `(scheme base)`
+    /// (see `State::new_bare`).
+    pub fn new_bare() -> Self {
+        Interpreter { state: State::new_bare() }
+    }
+
+    /// Creates an interpreter whose nondeterminism is derived from `seed`
+    /// (see `State::new_deterministic`).
+    pub fn new_deterministic(seed: u64) -> Self {
+        Interpreter { state: State::new_deterministic(seed) }
+    }
+
+    /// Creates an interpreter for untrusted code (see `SandboxConfig`).
+    pub fn sandboxed(config: SandboxConfig) -> Self {
+        Interpreter { state: State::sandboxed(config) }
+    }
+
+    /// The stack-based API underneath, for what the facade does not cover.
+    pub fn state(&mut self) -> &mut State {
+        &mut self.state
+    }
+
+    /// Runs `body`, then pops whatever it left on the stack.
+    fn balanced<T, F>(&mut self, body: F) -> Result<T, Error>
+        where F: FnOnce(&mut Self) -> Result<T, Error>
+    {
+        let len = self.state.len();
+        let result = body(self);
+        self.state.heap().stack.truncate(len);
+        result
+    }
+
+    /// Evaluates each datum in `source` at top level, in order, and returns
+    /// the value of the last one.
+    pub fn eval_str(&mut self, source: &str) -> Result<Value, Error> {
+        self.balanced(|interp| {
+            let mut input = Input::new(source.as_bytes(), "eval");
+            let mut last = None;
+            while try!(interp.state.read(&mut input)) {
+                try!(interp.state.compile().map_err(SchemeError::Compile));
+                if let Err(e) = interp.state.run_compiled() {
+                    return Err(interp.runtime_error(e))
+                }
+                last = Some(try!(interp.state.root()))
+            }
+            last.ok_or_else(|| Error::new("No expression to evaluate"))
+        })
+    }
+
+    /// Evaluates each datum in `source` at top level, in order, like
+    /// `eval_str`, as a future, which waits for the futures of the async
+    /// procedures that the code calls (see `define_async_fn`).  It works
+    /// with any executor: polling it polls them, with the same waker.
+    pub fn eval_async(&mut self, source: &str) -> Evaluation {
+        let len = self.state.len();
+        let mut input = Input::new(source.as_bytes(), "eval");
+        let mut data = VecDeque::new();
+        let mut error = None;
+        loop {
+            match self.state.read(&mut input) {
+                Ok(true) => {
+                    match self.state.root() {
+                        Ok(datum) => data.push_back(datum),
+                        Err(e) => error = Some(Error::new(e)),
+                    }
+                }
+                Ok(false) => break,
+                Err(e) => error = Some(Error::from(e)),
+            }
+            if error.is_some() {
+                break
+            }
+        }
+        Evaluation {
+            interp: self,
+            len: len,
+            data: data,
+            suspension: None,
+            last: None,
+            error: error,
+        }
+    }
+
+    /// Runs compiled code at top level (see `State::load_compiled`).
+    pub fn load_compiled(&mut self, name: &str, bytes: &[u8]) -> Result<(), Error> {
+        self.balanced(|interp| match interp.state.load_compiled(name, bytes) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(interp.runtime_error(e)),
+        })
+    }
+
+    /// Writes out an image of the interpreter (see `State::save_image`).
+    pub fn save_image<W: ::std::io::Write>(&mut self, writer: &mut W) -> Result<(), Error> {
+        self.state.save_image(writer).map_err(Error::new)
+    }
+
+    /// Restores an image written by `save_image` into this interpreter,
+    /// which should be new.  Procedures that the embedder defined must be
+    /// defined again.
+    pub fn load_image<R: ::std::io::Read>(&mut self, reader: &mut R) -> Result<(), Error> {
+        self.balanced(|interp| match interp.state.load_image(reader) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(interp.runtime_error(e)),
+        })
+    }
+
+    /// The error for `message`, which running code returned, with its
+    /// condition and backtrace.
+    fn runtime_error(&self, message: String) -> Error {
+        SchemeError::Runtime {
+            condition: self.state.uncaught(&message),
+            backtrace: self.state.backtrace().to_vec(),
+            message: message,
+        }
+    }
+
+    /// Pushes the value of the global variable `name`.
+    fn push_global(&mut self, name: &str) -> Result<(), Error> {
+        let heap = self.state.heap();
+        heap.intern(name);
+        let symbol = heap.stack.pop().unwrap();
+        match symbol.kind() {
+            Kind::Symbol(ptr) if unsafe { (*ptr).bound.get() } => {
+                Ok(heap.stack.push(unsafe { (*(*ptr).contents.get()).clone() }))
+            }
+            _ => Err(Error::new(format!("Unbound variable: {}", name))),
+        }
+    }
+
+    /// The value of the global variable `name`.
+    pub fn lookup(&mut self, name: &str) -> Result<Value, Error> {
+        self.balanced(|interp| {
+            try!(interp.push_global(name));
+            Ok(try!(interp.state.root()))
+        })
+    }
+
+    /// Calls the procedure that is the value of the global variable `name`
+    /// with `args`, and returns its result.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, Error> {
+        self.balanced(|interp| {
+            try!(interp.push_global(name));
+            for arg in args {
+                interp.state.push_root(arg)
+            }
+            if let Err(e) = interp.state.call(args.len()) {
+                return Err(interp.runtime_error(e))
+            }
+            Ok(try!(interp.state.root()))
+        })
+    }
+
+    /// Checks that `procedure` is a procedure, and roots it for
+    /// `call_closure`.  Built-in procedures are accepted too.
+    pub fn closure(&self, procedure: &Value) -> Result<Root<Closure>, Error> {
+        match procedure.get().kind() {
+            Kind::Closure(_) | Kind::Primitive(_) => Ok(procedure.clone().cast()),
+            _ => Err(Error::new(format!("Not a procedure: {}", self.write_string(procedure)))),
+        }
+    }
+
+    /// Calls `closure` with `args`, and returns its result.  This is how
+    /// Scheme procedures are used as callbacks: `closure` and `args` stay
+    /// rooted, so they may be kept across calls and collections.
+    pub fn call_closure(&mut self,
+                        closure: &Root<Closure>,
+                        args: &[&Value])
+                        -> Result<Value, Error> {
+        self.balanced(|interp| {
+            interp.state.push_root(closure);
+            for arg in args {
+                interp.state.push_root(arg)
+            }
+            if let Err(e) = interp.state.call(args.len()) {
+                return Err(interp.runtime_error(e))
+            }
+            Ok(try!(interp.state.root()))
+        })
+    }
+
+    /// Defines the global variable `name` as `value`, as `define` does at
+    /// top level.
+    pub fn define(&mut self, name: &str, value: &Value) -> Result<(), Error> {
+        self.balanced(|interp| {
+            let heap = interp.state.heap();
+            heap.intern(name);
+            let symbol = heap.stack.last().unwrap().clone();
+            Ok(try!(heap.set_global(&symbol, value.get())))
+        })
+    }
+
+    /// Defines the record type of `T` and its procedures (see
+    /// `SchemeRecord`), after which values of type `T` convert to and from
+    /// records of that type.
+    pub fn define_record<T: SchemeRecord>(&mut self) -> Result<(), Error> {
+        self.eval_str(&record::definitions::<T>()).map(|_| ())
+    }
+
+    /// Defines the global variable `name` as a procedure of `arity`
+    /// arguments, which calls `function`.
+    pub fn define_fn<F>(&mut self, name: &str, arity: usize, function: F) -> Result<(), Error>
+        where F: Fn(&mut Context, &[Value]) -> Result<Value, Error> + 'static
+    {
+        self.define_native(name, arity, Some(arity), Function::Sync(Rc::new(function)))
+    }
+
+    /// Defines the global variable `name` as a procedure of at least
+    /// `min_args` arguments, which calls `function`.
+    pub fn define_variadic_fn<F>(&mut self,
+                                 name: &str,
+                                 min_args: usize,
+                                 function: F)
+                                 -> Result<(), Error>
+        where F: Fn(&mut Context, &[Value]) -> Result<Value, Error> + 'static
+    {
+        self.define_native(name, min_args, None, Function::Sync(Rc::new(function)))
+    }
+
+    /// Defines the global variable `name` as a procedure of `arity`
+    /// arguments, which calls `function`, and returns the result of the
+    /// future that it returns.  Only code run by `eval_async` can wait for
+    /// the future; called from anywhere else, the procedure fails.
+    pub fn define_async_fn<F, R, T>(&mut self,
+                                    name: &str,
+                                    arity: usize,
+                                    function: F)
+                                    -> Result<(), Error>
+        where F: Fn(&mut Context, &[Value]) -> Result<R, Error> + 'static,
+              R: Future<Output = Result<T, Error>> + 'static,
+              T: ToScheme + 'static
+    {
+        let function = move |cx: &mut Context, args: &[Value]| -> Result<Pending, Error> {
+            let future = try!(function(cx, args));
+            Ok(Box::pin(Boxed(Box::pin(future))))
+        };
+        self.define_native(name, arity, Some(arity), Function::Async(Rc::new(function)))
+    }
+
+    pub(crate) fn define_native(&mut self,
+                                name: &str,
+                                min_args: usize,
+                                max_args: Option<usize>,
+                                function: Function)
+                                -> Result<(), Error> {
+        self.balanced(|interp| {
+            let heap = interp.state.heap();
+            try!(builtins::alloc_native(heap, name, min_args, max_args, function));
+            heap.intern(name);
+            let symbol = heap.stack.pop().unwrap();
+            let procedure = heap.stack.last().unwrap().clone();
+            Ok(try!(heap.set_global(&symbol, procedure)))
+        })
+    }
+
+    fn context(&mut self) -> Context {
+        Context::new(self.state.heap())
+    }
+
+    /// Converts a Rust value to a Scheme value (see `ToScheme`).
+    pub fn value<T: ToScheme>(&mut self, value: T) -> Result<Value, Error> {
+        self.context().value(value)
+    }
+
+    /// Converts a Scheme value to a Rust value (see `FromScheme`).
+    pub fn get<T: FromScheme>(&mut self, value: &Value) -> Result<T, Error> {
+        self.context().get(value)
+    }
+
+    /// Deserializes a Rust value from a Scheme value (see `Datum`).
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: DeserializeOwned>(&mut self, value: &Value) -> Result<T, Error> {
+        self.context().deserialize(value)
+    }
+
+    /// `value`, printed as by `write`.
+    pub fn write_string(&self, value: &Value) -> String {
+        print::to_string(&value.get(), print::Mode::Write)
+    }
+
+    /// Moves `object` into the heap, which drops it once Scheme code and the
+    /// embedder no longer refer to it.
+    pub fn rust_data<T: Any>(&mut self, object: T) -> Result<Value, Error> {
+        self.context().rust_data(object)
+    }
+
+    /// The Rust object owned by `value`, if it is a `T`.
+    pub fn downcast_ref<'b, T: Any>(&self, value: &'b Value) -> Option<&'b T> {
+        downcast_ref(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use api::{Fields, SchemeRecord, ToScheme};
+
+    #[test]
+    fn eval_call_and_define() {
+        let mut interp = Interpreter::new();
+        let value = interp.eval_str("(define (add x y) (+ x y)) (add 1 2)").unwrap();
+        assert_eq!(interp.get::<usize>(&value), Ok(3));
+        let (one, two) = (interp.value(1usize).unwrap(), interp.value(2usize).unwrap());
+        let sum = interp.call("add", &[one, two]).unwrap();
+        assert_eq!(interp.get::<usize>(&sum), Ok(3));
+        let name = interp.value("shared".to_owned()).unwrap();
+        interp.define("name", &name).unwrap();
+        interp.state().gc();
+        let value = interp.eval_str("(list name)").unwrap();
+        assert_eq!(interp.write_string(&value), "(\"shared\")");
+        assert_eq!(interp.call("missing", &[]).map(|_| ()).map_err(|e| e.to_string()),
+                   Err("Unbound variable: missing".to_owned()));
+        assert!(interp.eval_str("(car 1)").is_err());
+        assert!(interp.eval_str("(car").is_err());
+        assert!(interp.eval_str("").is_err());
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn native_functions() {
+        let mut interp = Interpreter::new();
+        interp.define_variadic_fn("count", 1, |cx, args| {
+                  let extra: usize = try!(cx.get(&args[0]));
+                  cx.value(args.len() - 1 + extra)
+              })
+              .unwrap();
+        interp.define_fn("fail", 0, |_, _| Err(Error::new("failed"))).unwrap();
+        let value = interp.eval_str("(map count '(10 20) '(a b))").unwrap();
+        assert_eq!(interp.write_string(&value), "(11 21)");
+        let value = interp.eval_str("(count 0 'a 'b 'c)").unwrap();
+        assert_eq!(interp.get::<usize>(&value), Ok(3));
+        assert_eq!(interp.eval_str("(fail)").map(|_| ()), Err(Error::new("fail: failed")));
+        assert!(interp.eval_str("(fail 1)").is_err());
+        assert!(interp.eval_str("(count)").is_err());
+        let value = interp.eval_str("(guard (e (#t 'caught)) (fail))").unwrap();
+        assert_eq!(interp.write_string(&value), "caught");
+        interp.define_fn("boom", 0, |_, _| panic!("kaboom")).unwrap();
+        let value = interp.eval_str("(guard (e ((error-object? e) (error-object-message e)))
+                                       (vector (boom)))")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value), "\"boom: panicked: kaboom\"");
+        assert_eq!(interp.eval_str("(boom)").map(|_| ()),
+                   Err(Error::new("boom: panicked: kaboom")));
+        let value = interp.eval_str("(count 1 'a)").unwrap();
+        assert_eq!(interp.get::<usize>(&value), Ok(2));
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn panicking_native_functions() {
+        let mut interp = Interpreter::new();
+        interp.define_fn("boom", 1, |cx, args| {
+                  let value = try!(cx.value(vec![1usize, 2, 3]));
+                  let _ = cx.get::<usize>(&args[0]);
+                  panic!("kaboom {}", cx.write_string(&value))
+              })
+              .unwrap();
+        let value = interp.eval_str("(define p (make-parameter 1))
+                                     (define log '())
+                                     (define (note x) (set! log (cons x log)))
+                                     (guard (e ((error-object? e) (note (error-object-message e))))
+                                       (parameterize ((p 2))
+                                         (dynamic-wind (lambda () (note 'before))
+                                                       (lambda () (boom (p)))
+                                                       (lambda () (note 'after)))))
+                                     (list (p) (reverse log))")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value),
+                   "(1 (before after \"boom: panicked: kaboom (1 2 3)\"))");
+        for _ in 0..3 {
+            assert_eq!(interp.eval_str("(vector-map boom #(1))").map(|_| ()),
+                       Err(Error::new("boom: panicked: kaboom (1 2 3)")));
+            assert!(interp.state().is_empty());
+        }
+        interp.state().gc();
+        let value = interp.eval_str("(map (lambda (x) (p)) '(a b c))").unwrap();
+        assert_eq!(interp.write_string(&value), "(1 1 1)");
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn errors() {
+        let mut interp = Interpreter::new();
+        match interp.eval_str("(car") {
+            Err(SchemeError::Read(_)) => {}
+            result => panic!("expected a read error, got {:?}", result),
+        }
+        match interp.eval_str("(if)") {
+            Err(SchemeError::Compile(_)) => {}
+            result => panic!("expected a compile error, got {:?}", result),
+        }
+        let error = interp.eval_str("(define (inner) (raise (list 'oops)))
+                                     (define (outer) (inner) 1)
+                                     (outer)")
+                          .unwrap_err();
+        assert_eq!(error.to_string(), "Uncaught exception: (oops)");
+        interp.state().gc();
+        assert_eq!(error.condition().map(|condition| interp.write_string(condition)),
+                   Some("(oops)".to_owned()));
+        assert_eq!(error.backtrace()[0].name, Some("inner".to_owned()));
+        let error = interp.call("car", &[]).unwrap_err();
+        assert!(error.condition().is_none());
+        let error = interp.eval_str("(with-exception-handler (lambda (e) 0) (lambda () (car 1)))")
+                          .unwrap_err();
+        assert!(error.condition().is_some());
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn sandbox() {
+        let mut interp = Interpreter::sandboxed(SandboxConfig {
+            network: true,
+            fuel: Some(10_000),
+            ..Default::default()
+        });
+        for source in &["(open-input-file \"/etc/passwd\")",
+                        "(load \"untrusted.scm\")",
+                        "(get-environment-variable \"HOME\")",
+                        "(exit)"] {
+            let error = interp.eval_str(source).unwrap_err();
+            assert!(error.to_string().starts_with("Unbound variable"));
+        }
+        assert!(interp.eval_str("(procedure? tcp-connect)").is_ok());
+        assert_eq!(interp.eval_str("(let loop () (loop))").map(|_| ()),
+                   Err(Error::new("Out of fuel")));
+        let value = interp.eval_str("(let loop ((i 0)) (if (< i 100) (loop (+ i 1)) i))").unwrap();
+        assert_eq!(interp.get::<usize>(&value), Ok(100));
+        assert!(interp.eval_str("(make-vector 100000000 0)").is_err());
+        let value = interp.eval_str("(define (open-input-file name) name) (open-input-file \"x\")")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value), "\"x\"");
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn sandboxed_threads() {
+        let mut interp = Interpreter::sandboxed(Default::default());
+        let error = interp.eval_str("(make-thread (lambda () 1))").unwrap_err();
+        assert!(error.to_string().starts_with("Unbound variable"));
+
+        let mut interp = Interpreter::sandboxed(SandboxConfig {
+            threads: true,
+            fuel: Some(10_000),
+            ..Default::default()
+        });
+        let error = interp.eval_str("(thread-join!
+                                       (make-thread
+                                         (lambda () (open-input-file \"/etc/passwd\"))))")
+                          .unwrap_err();
+        assert!(error.to_string().starts_with("Unbound variable"));
+        assert_eq!(interp.eval_str("(thread-join! (make-thread (lambda () (let loop () (loop)))))")
+                         .map(|_| ()),
+                   Err(Error::new("Out of fuel")));
+        let value = interp.eval_str("(thread-join! (make-thread (lambda () 'done)))").unwrap();
+        assert_eq!(interp.write_string(&value), "done");
+    }
+
+    #[test]
+    fn images() {
+        let source = "(define-syntax swap!
+                        (syntax-rules () ((_ a b) (let ((t a)) (set! a b) (set! b t)))))
+                      (define-library (counter)
+                        (export next!)
+                        (import (scheme base))
+                        (begin (define n 0) (define (next!) (set! n (+ n 1)) n)))
+                      (import (counter))
+                      (define cycle (list 1 2))
+                      (set-cdr! (cdr cycle) cycle)
+                      (define table (make-equal-hashtable))
+                      (hashtable-set! table \"key\" (vector cycle 'x 2.5))
+                      (define x 'a)
+                      (define y 'b)
+                      (next!)";
+        let mut interp = Interpreter::new();
+        interp.eval_str(source).unwrap();
+        interp.define_fn("native", 0, |cx, _| cx.value(0usize)).unwrap();
+        let mut image = vec![];
+        interp.save_image(&mut image).unwrap();
+
+        let mut restored = Interpreter::new();
+        restored.load_image(&mut &image[..]).unwrap();
+        let value = restored.eval_str("(swap! x y)
+                                       (let ((entry (hashtable-ref table \"key\" #f)))
+                                         (list x y (next!) (eq? (vector-ref entry 0) cycle)
+                                               (eq? (cddr cycle) cycle) (vector-ref entry 2)))")
+                            .unwrap();
+        assert_eq!(restored.write_string(&value), "(b a 2 #t #t 2.5)");
+        assert!(restored.lookup("native").is_err());
+        assert!(Interpreter::new().load_image(&mut &b"not an image"[..]).is_err());
+
+        interp.eval_str("(define port (open-input-string \"\"))").unwrap();
+        assert!(interp.save_image(&mut vec![]).is_err());
+        assert!(restored.state().is_empty());
+    }
+
+    #[test]
+    fn compiled_code() {
+        let source = "(define-syntax twice (syntax-rules () ((_ e) (begin e e))))
+                      (define n 0)
+                      (twice (set! n (+ n 1)))";
+        let compiled = Interpreter::new().state().compile_str("counter.scm", source).unwrap();
+        let mut interp = Interpreter::new();
+        interp.load_compiled("counter.scm", &compiled).unwrap();
+        let value = interp.eval_str("(twice (set! n (* n 10))) n").unwrap();
+        assert_eq!(interp.write_string(&value), "200");
+        assert_eq!(interp.load_compiled("bad", source.as_bytes()).map_err(|e| e.message()),
+                   Err("bad: not a compiled file".to_owned()));
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn deterministic() {
+        let source = "(define table (make-eq-hashtable))
+                      (for-each (lambda (key) (hashtable-set! table key #t))
+                                '(falcon hawk kestrel owl osprey))
+                      (random-source-randomize! (default-random-source))
+                      (list (current-jiffy) (current-jiffy) (current-second)
+                            (random-integer 1000000) (bytevector-u8-ref (random-entropy 1) 0)
+                            (hashtable-keys table) (equal-hash (list 'falcon car table)))";
+        let run = |seed| {
+            let mut interp = Interpreter::new_deterministic(seed);
+            let value = interp.eval_str(source).unwrap();
+            interp.write_string(&value)
+        };
+        let first = run(42);
+        assert_eq!(first, run(42));
+        assert!(first.starts_with("(0 1 "));
+        assert!(first.contains("(falcon hawk kestrel owl osprey)"));
+        assert!(first != run(43));
+    }
+
+    #[test]
+    fn conversions() {
+        use std::collections::HashMap;
+        let mut interp = Interpreter::new();
+        interp.define_fn("describe", 3, |cx, args| {
+                  let (name, scores): (String, Vec<i32>) = try!(cx.get(&args[0]));
+                  let weight: Option<f64> = try!(cx.get(&args[1]));
+                  let tags: HashMap<String, bool> = try!(cx.get(&args[2]));
+                  let total = scores.iter().sum::<i32>() as f64 * weight.unwrap_or(1.0);
+                  cx.value((name, total, tags.len(), tags.get("new").cloned()))
+              })
+              .unwrap();
+        let value = interp.eval_str("(define tags (make-hash-table equal?))
+                                     (hash-table-set! tags \"new\" #t)
+                                     (describe '(\"a\" (1 -2 4)) 0.5 tags)")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value), "(\"a\" 1.5 1 #t)");
+        assert_eq!(interp.get::<(String, f64, u8, bool)>(&value),
+                   Ok(("a".to_owned(), 1.5, 1, true)));
+        assert!(interp.get::<(String, f64)>(&value).is_err());
+        let value = interp.eval_str("#(-1 300)").unwrap();
+        assert_eq!(interp.get::<Vec<i16>>(&value), Ok(vec![-1, 300]));
+        assert!(interp.get::<Vec<u8>>(&value).is_err());
+        let value = interp.value(vec![None, Some('x')]).unwrap();
+        assert_eq!(interp.write_string(&value), "(#f #\\x)");
+        assert!(interp.value(u64::max_value()).is_err());
+        assert!(interp.eval_str("(describe 1 #f tags)").is_err());
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn callbacks() {
+        let mut interp = Interpreter::new();
+        interp.eval_str("(define clicks '())").unwrap();
+        let handler = interp.eval_str("(lambda (x) (set! clicks (cons x clicks)) (length clicks))")
+                            .unwrap();
+        let handler = interp.closure(&handler).unwrap();
+        let add = interp.lookup("+").unwrap();
+        let add = interp.closure(&add).unwrap();
+        for i in 0..3usize {
+            let x = interp.value(i).unwrap();
+            interp.state().gc();
+            let count = interp.call_closure(&handler, &[&x]).unwrap();
+            let sum = interp.call_closure(&add, &[&count, &x]).unwrap();
+            assert_eq!(interp.get::<usize>(&sum), Ok(2 * i + 1));
+        }
+        let clicks = interp.lookup("clicks").unwrap();
+        assert_eq!(interp.write_string(&clicks), "(2 1 0)");
+        assert!(interp.call_closure(&handler, &[]).is_err());
+        let car = interp.eval_str("car").unwrap();
+        let car = interp.closure(&car).unwrap();
+        assert!(interp.call_closure(&car, &[&clicks]).is_ok());
+        assert!(interp.call_closure(&car, &[&handler.clone().cast()]).is_err());
+        assert_eq!(interp.closure(&clicks).map(|_| ()),
+                   Err(Error::new("Not a procedure: (2 1 0)")));
+        assert!(interp.state().is_empty());
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i32,
+        label: Option<String>,
+    }
+
+    impl SchemeRecord for Point {
+        const NAME: &'static str = "point";
+        const FIELDS: &'static [&'static str] = &["x", "label"];
+
+        fn fields(&self) -> Vec<&dyn ToScheme> {
+            vec![&self.x as &dyn ToScheme, &self.label]
+        }
+
+        fn from_fields(fields: &mut Fields) -> Result<Self, String> {
+            Ok(Point {
+                x: try!(fields.next()),
+                label: try!(fields.next()),
+            })
+        }
+    }
+
+    #[test]
+    fn records() {
+        let mut interp = Interpreter::new();
+        assert!(interp.value(Point { x: 1, label: None }).is_err());
+        interp.define_record::<Point>().unwrap();
+        let value = interp.value(Point { x: 1, label: None }).unwrap();
+        interp.define("p", &value).unwrap();
+        let value = interp.eval_str("(set-point-label! p \"origin\")
+                                     (list (point? p) (point-x p) (point-label p))")
+                          .unwrap();
+        assert_eq!(interp.write_string(&value), "(#t 1 \"origin\")");
+        let value = interp.eval_str("(make-point 2 #f)").unwrap();
+        assert_eq!(interp.get::<Point>(&value), Ok(Point { x: 2, label: None }));
+        let value = interp.lookup("p").unwrap();
+        assert_eq!(interp.get(&value),
+                   Ok(Point {
+                       x: 1,
+                       label: Some("origin".to_owned()),
+                   }));
+        let value = interp.eval_str("(vector 1)").unwrap();
+        assert!(interp.get::<Point>(&value).is_err());
+        assert!(interp.state().is_empty());
+    }
+
+    #[test]
+    fn rust_data() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        struct Flag(Rc<Cell<bool>>);
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                self.0.set(true)
+            }
+        }
+        let dropped = Rc::new(Cell::new(false));
+        let mut interp = Interpreter::new();
+        let data = interp.rust_data(Flag(dropped.clone())).unwrap();
+        interp.define("data", &data).unwrap();
+        drop(data);
+        interp.state().gc();
+        let data = interp.eval_str("(car (list data))").unwrap();
+        assert!(interp.downcast_ref::<Flag>(&data).is_some());
+        assert!(interp.downcast_ref::<usize>(&data).is_none());
+        assert_eq!(interp.write_string(&data), "#<rust-data>");
+        drop(data);
+        interp.eval_str("(set! data #f)").unwrap();
+        assert!(!dropped.get());
+        interp.state().gc();
+        assert!(dropped.get());
+    }
+
+    #[test]
+    fn async_procedures() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::ptr;
+        use std::task::{self, Poll, RawWaker, RawWakerVTable, Waker};
+
+        /// Ready on its second poll, with `value`, or an error if it is
+        /// negative.  Panics instead if it is 13.
+        struct Later {
+            value: i64,
+            polled: bool,
+        }
+
+        impl Future for Later {
+            type Output = Result<i64, Error>;
+
+            fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+                if !self.polled {
+                    self.polled = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                } else if self.value == 13 {
+                    panic!("unlucky")
+                } else if self.value < 0 {
+                    Poll::Ready(Err(Error::new("negative")))
+                } else {
+                    Poll::Ready(Ok(self.value))
+                }
+            }
+        }
+
+        fn block_on<F: Future>(mut future: F) -> F::Output {
+            fn clone(_: *const ()) -> RawWaker {
+                RawWaker::new(ptr::null(), &VTABLE)
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            let waker = unsafe { Waker::from_raw(clone(ptr::null())) };
+            let mut cx = task::Context::from_waker(&waker);
+            let mut future = unsafe { Pin::new_unchecked(&mut future) };
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output
+                }
+            }
+        }
+
+        let mut interp = Interpreter::new();
+        interp.define_async_fn("later", 1, |cx, args| {
+                  let value = try!(cx.get(&args[0]));
+                  Ok(Later {
+                      value: value,
+                      polled: false,
+                  })
+              })
+              .unwrap();
+        let source = "(define (f x) (+ 1 (later x))) (define (g x) (later x)) (list (f 41) (g 2))";
+        let value = block_on(interp.eval_async(source)).unwrap();
+        assert_eq!(interp.write_string(&value), "(42 2)");
+        let caught = "(call/cc (lambda (k)
+                        (with-exception-handler
+                          (lambda (e) (k 'caught))
+                          (lambda () (later -1)))))";
+        let value = block_on(interp.eval_async(caught)).unwrap();
+        assert_eq!(interp.write_string(&value), "caught");
+        let error = block_on(interp.eval_async("(later -1)")).unwrap_err();
+        assert_eq!(error.message(), "later: negative");
+        let error = block_on(interp.eval_async("(later 13)")).unwrap_err();
+        assert_eq!(error.message(), "later: panicked: unlucky");
+        let value = block_on(interp.eval_async("(g 5)")).unwrap();
+        assert_eq!(interp.write_string(&value), "5");
+        let one = interp.value(1i64).unwrap();
+        assert_eq!(interp.call("later", &[one]).unwrap_err().message(),
+                   "later: cannot wait for it here");
+        assert!(interp.state().is_empty());
+    }
+}
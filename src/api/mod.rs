@@ -1,7 +1,7 @@
 //! The public Rust embedding API of `RustyScheme`.  Very unstable.
 //!
 //! This API is similar to Lua's embedding API, in that an explicit stack is
-//! used.
+//! used.  `Interpreter` (see `interpreter`) is a simpler facade over it.
 //!
 //! Example:
 //!
@@ -27,32 +27,88 @@
 //! //assert!(interp.eval("(+ 1 5").is_err());
 //! ```
 
+#[cfg(not(target_arch = "wasm32"))]
 extern crate env_logger;
 
+mod convert;
+#[cfg(feature = "serde")]
+mod datum;
+mod interpreter;
 mod pool;
+mod record;
+mod sandbox;
 
 use interp;
 use value;
 use alloc;
 use arith;
+use print;
+use compiler;
+use read;
+use builtins;
+use platform;
+
+pub use alloc::{Root, HeapConfig, OutOfMemoryHandler, ObjectInfo, Snapshot};
+pub use self::convert::{FromScheme, ToScheme};
+#[cfg(feature = "serde")]
+pub use self::datum::Datum;
+pub use self::interpreter::{Context, Error, Evaluation, Interpreter, SchemeError, Value};
+pub use self::record::{Fields, SchemeRecord};
+pub use self::sandbox::SandboxConfig;
+pub use interp::BacktraceFrame;
+pub use read::{Input, ReadError};
+pub use port::{Buffering, Bytes, Sink, Source};
+pub use value::Closure;
+
 pub struct State {
     state: interp::State,
     fp: usize,
 }
 
+/// How far `State::eval_with_fuel` or `State::resume` got.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Fuel {
+    /// The evaluation finished, and its value was pushed.
+    Finished,
+
+    /// The fuel ran out first.
+    Suspended(Suspension),
+
+    /// The evaluation waits for the future of an async procedure, which
+    /// `State::poll_await` polls.
+    Awaiting(Suspension),
+}
+
+/// An evaluation that ran out of fuel or waits for a future, which
+/// `State::resume` continues.  Only the latest one can be resumed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Suspension(usize);
+
+impl Fuel {
+    /// The outcome of an evaluation in `heap` that made the suspension
+    /// numbered `suspension`, if any (see `interp::execute_with_fuel`).
+    fn new(heap: &alloc::Heap, suspension: Option<usize>) -> Self {
+        match suspension {
+            Some(suspension) if heap.control.awaits() => Fuel::Awaiting(Suspension(suspension)),
+            Some(suspension) => Fuel::Suspended(Suspension(suspension)),
+            None => Fuel::Finished,
+        }
+    }
+}
+
 
 // Unsafe because the return value is not rooted
 pub unsafe trait SchemeValue: Sized {
-    fn to_value(&self, heap: &mut alloc::Heap) -> value::Value;
+    fn to_value(&self, heap: &mut alloc::Heap) -> Result<value::Value, String>;
     fn of_value(val: &value::Value) -> Result<Self, String>;
 }
 
 unsafe impl SchemeValue for usize {
-    fn to_value(&self, _: &mut alloc::Heap) -> value::Value {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, String> {
         if self & 3 << (size_of!(usize) * 8 - 2) != 0 {
-            panic!("bignums not yet supported")
+            Err(format!("{} is too large for a fixnum", self))
         } else {
-            value::Value::new(self << 2)
+            Ok(value::Value::new(self << 2))
         }
     }
     fn of_value(val: &value::Value) -> Result<Self, String> {
@@ -61,12 +117,12 @@ unsafe impl SchemeValue for usize {
 }
 
 unsafe impl SchemeValue for bool {
-    fn to_value(&self, _: &mut alloc::Heap) -> value::Value {
-        value::Value::new(if *self {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, String> {
+        Ok(value::Value::new(if *self {
             value::TRUE
         } else {
             value::FALSE
-        })
+        }))
     }
     fn of_value(val: &value::Value) -> Result<Self, String> {
         match val.get() {
@@ -77,6 +133,29 @@ unsafe impl SchemeValue for bool {
     }
 }
 
+unsafe impl SchemeValue for char {
+    fn to_value(&self, _: &mut alloc::Heap) -> Result<value::Value, String> {
+        Ok(value::Value::new_char(*self))
+    }
+    fn of_value(val: &value::Value) -> Result<Self, String> {
+        val.as_char().map_err(|x| x.to_owned())
+    }
+}
+
+unsafe impl SchemeValue for f64 {
+    fn to_value(&self, heap: &mut alloc::Heap) -> Result<value::Value, String> {
+        try!(heap.alloc_float(*self));
+        Ok(heap.stack.pop().unwrap())
+    }
+    fn of_value(val: &value::Value) -> Result<Self, String> {
+        if val.flonump() {
+            Ok(unsafe { value::float_val(val) })
+        } else {
+            Err("Value is not a flonum".to_owned())
+        }
+    }
+}
+
 impl Default for State {
     fn default() -> Self {
         Self::new()
@@ -90,13 +169,256 @@ impl State {
         }
     }
 
-    pub fn execute_bytecode(&mut self) -> Result<(), String> {
-        interp::interpret_bytecode(&mut self.state)
+    /// Creates an interpreter whose heap follows the given sizing policy.
+    pub fn with_heap_config(config: HeapConfig) -> Self {
+        let mut state = State::new();
+        state.state.heap = alloc::Heap::with_config(config);
+        state
+    }
+
+    /// Creates an interpreter for untrusted code, which can only do what
+    /// `config` allows.
+    pub fn sandboxed(config: SandboxConfig) -> Self {
+        let mut state = State::with_heap_config(config.heap_config());
+        for group in config.denied() {
+            state.state.heap.deny(group)
+        }
+        if let Some(fuel) = config.fuel {
+            state.state.heap.control.set_fuel_limit(fuel)
+        }
+        state
+    }
+
+    /// Creates an interpreter without the part of `(scheme base)` that is
+    /// written in Scheme, such as `cond` and `cadr`, which saves the time
+    /// and memory it takes to load it.
+    pub fn new_bare() -> Self {
+        let mut state = State::new();
+        state.state.heap.macros.libraries.set_bare();
+        state
+    }
+
+    /// Creates an interpreter in which the clock, random numbers, the order
+    /// of hash table entries and `equal-hash` are derived from `seed`, so
+    /// that runs can be replayed.  The clock starts at the epoch, and moves
+    /// forward a microsecond each time it is read.
+    pub fn new_deterministic(seed: u64) -> Self {
+        let mut state = State::new();
+        state.state.heap.control.set_deterministic(seed);
+        state
+    }
+
+    /// Takes a snapshot of the object graph, for debugging leaks.
+    pub fn snapshot(&self) -> Snapshot {
+        self.state.heap.snapshot()
+    }
+
+    /// Writes a dump of the heap (see `Snapshot`).
+    pub fn dump<W: ::std::io::Write>(&self, writer: &mut W) -> ::std::io::Result<()> {
+        self.state.heap.dump(writer)
+    }
+
+    /// Searches `directory` for the files of imported libraries, after the
+    /// directories already searched (initially, the current directory).
+    pub fn add_library_directory<P: AsRef<::std::path::Path>>(&mut self, directory: P) {
+        self.state.heap.macros.libraries.add_directory(directory.as_ref().to_path_buf())
+    }
+
+    /// Sets what `(command-line)` returns: the name of the program, and its
+    /// arguments.  It is empty by default.
+    pub fn set_command_line(&mut self, arguments: Vec<String>) {
+        self.state.heap.control.set_command_line(arguments)
+    }
+
+    /// Runs the code in `file` at top level.  A first line starting with
+    /// `#!` is skipped.
+    pub fn load_file<P: AsRef<::std::path::Path>>(&mut self, file: P) -> Result<(), String> {
+        interp::load(&mut self.state.heap, file.as_ref())
+    }
+
+    /// Runs the code in `input` at top level, and writes out what it
+    /// compiled to to `output`, which `load_file` runs much faster.
+    pub fn compile_file<P, Q>(&mut self, input: P, output: Q) -> Result<(), String>
+        where P: AsRef<::std::path::Path>,
+              Q: AsRef<::std::path::Path>
+    {
+        interp::compile_file(&mut self.state.heap, input.as_ref(), output.as_ref())
+    }
+
+    /// Runs the source code `source` at top level, as `compile_file` does,
+    /// and returns what it compiled to, which `load_compiled` runs.  `name`
+    /// is the file that errors refer to.
+    pub fn compile_str(&mut self, name: &str, source: &str) -> Result<Vec<u8>, String> {
+        interp::compile(&mut self.state.heap, name, source.as_bytes())
+    }
+
+    /// Runs the code that `compile_str` or `compile_file` compiled, such as
+    /// the bytes that `include_scheme!` embeds, at top level.
+    pub fn load_compiled(&mut self, name: &str, bytes: &[u8]) -> Result<(), String> {
+        interp::load_compiled(&mut self.state.heap, name, bytes)
+    }
+
+    /// Writes out an image of the interpreter: its global variables,
+    /// libraries, and the macros and imports of the top level.  Global
+    /// variables bound to procedures defined by the embedder are left out.
+    pub fn save_image<W: ::std::io::Write>(&mut self, writer: &mut W) -> Result<(), String> {
+        let image = try!(interp::save_image(&mut self.state.heap));
+        writer.write_all(&image).map_err(|e| format!("save-image: {}", e))
+    }
+
+    /// Restores an image written by `save_image` into this interpreter.
+    /// The top-level forms that defined macros are evaluated again, and the
+    /// global variables are then set.
+    pub fn load_image<R: ::std::io::Read>(&mut self, reader: &mut R) -> Result<(), String> {
+        let mut image = vec![];
+        try!(reader.read_to_end(&mut image).map_err(|e| format!("load-image: {}", e)));
+        interp::load_image(&mut self.state.heap, &image)
+    }
+
+    /// The identifiers that mean something at top level, sorted: primitives,
+    /// special forms, global variables, and the macros and imports of the
+    /// top level.
+    pub fn bound_names(&self) -> Vec<String> {
+        compiler::bound_names(&self.state.heap)
+    }
+
+    /// Turns the optimization of compiled code on or off.  It is on by
+    /// default; turning it off helps when debugging the compiler.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.state.heap.macros.unoptimized = !optimize
+    }
+
+    /// Makes running Scheme code raise an `interrupt` condition when `flag`
+    /// is set, at its next procedure call or backward jump.  The flag is
+    /// cleared then.  Unhandled, the condition makes the code fail with
+    /// "Interrupted".  The flag is meant to be set by a signal handler or
+    /// another thread.
+    pub fn set_interrupt(&mut self, flag: &'static ::std::sync::atomic::AtomicBool) {
+        self.state.heap.control.set_interrupt(flag)
+    }
+
+    /// Sets the number of slots past which the stack overflows, making calls
+    /// fail with "Stack overflow".  That error can be handled, like others.
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.state.heap.control.set_stack_limit(limit)
+    }
+
+    /// Makes the standard ports read and write through `console` instead
+    /// of the standard streams of the process, such as to capture what
+    /// Scheme code prints.
+    pub fn set_console(&mut self, console: ::std::rc::Rc<dyn platform::Console>) {
+        self.state.heap.control.set_console(console)
+    }
+
+    /// Pushes a new input port reading from `reader`, such as a socket or a
+    /// decompressor (any `io::Read` is a `Source`).  `name` is what the port
+    /// prints as.  A textual port decodes UTF-8.
+    pub fn push_input_port(&mut self,
+                           name: &str,
+                           reader: Box<dyn Source>,
+                           textual: bool)
+                           -> Result<(), String> {
+        self.state.heap.alloc_port_from_reader(name, reader, textual)
+    }
+
+    /// Pushes a new output port writing to `writer`, which is flushed as
+    /// `buffering` says, and when the port is closed or collected.
+    pub fn push_output_port(&mut self,
+                            name: &str,
+                            writer: Box<dyn Sink>,
+                            textual: bool,
+                            buffering: Buffering)
+                            -> Result<(), String> {
+        self.state.heap.alloc_port_from_writer(name, writer, textual, buffering)
+    }
+
+    /// Flushes the current output and error ports, so that what Scheme code
+    /// wrote there comes before what the embedder writes next.
+    pub fn flush_output(&mut self) -> Result<(), String> {
+        builtins::port::flush_current(&mut self.state.heap)
+    }
+
+    /// Reads a datum from `input` and pushes it.  Returns `false`, pushing
+    /// nothing, at the end of the input.
+    pub fn read<R: ::std::io::BufRead>(&mut self, input: &mut Input<R>) -> Result<bool, ReadError> {
+        try!(read::read(self, input));
+        if self.state.heap.stack.last().unwrap().get() == value::EOF {
+            self.state.heap.stack.pop();
+            return Ok(false)
+        }
+        Ok(true)
+    }
+
+    /// Compiles and runs the datum on top of the stack, replacing it with
+    /// its value.
+    pub fn execute(&mut self) -> Result<(), String> {
+        interp::execute(&mut self.state.heap)
+    }
+
+    /// Evaluates the first datum in `source`, running at most `fuel`
+    /// instructions.  If the fuel runs out, the evaluation is suspended, and
+    /// nothing is pushed.  It runs out in the middle of a procedure called
+    /// by a primitive only if that procedure does not return, which fails
+    /// with "Out of fuel" instead.
+    pub fn eval_with_fuel(&mut self, source: &str, fuel: usize) -> Result<Fuel, String> {
+        let mut input = Input::new(source.as_bytes(), "eval");
+        match self.read(&mut input) {
+            Ok(true) => {}
+            Ok(false) => return Err("No expression to evaluate".to_owned()),
+            Err(e) => return Err(format!("read error: {:?}", e)),
+        }
+        let result = interp::execute_with_fuel(&mut self.state.heap, fuel);
+        result.map(|suspension| Fuel::new(&self.state.heap, suspension))
+    }
+
+    /// Runs the code on top of the stack that `compile` made, like
+    /// `run_compiled`, with at most `fuel` instructions, like
+    /// `eval_with_fuel`.
+    pub fn run_compiled_with_fuel(&mut self, fuel: usize) -> Result<Fuel, String> {
+        let result = interp::execute_compiled_with_fuel(&mut self.state.heap, fuel);
+        result.map(|suspension| Fuel::new(&self.state.heap, suspension))
+    }
+
+    /// Continues a suspended evaluation with `fuel` more instructions, like
+    /// `eval_with_fuel`.  An evaluation that waits for a future can only be
+    /// continued once `poll_await` has found the future ready.
+    pub fn resume(&mut self, suspension: Suspension, fuel: usize) -> Result<Fuel, String> {
+        let result = interp::resume_with_fuel(&mut self.state.heap, suspension.0, fuel);
+        result.map(|suspension| Fuel::new(&self.state.heap, suspension))
+    }
+
+    /// Polls the future that the suspended evaluation waits for, which
+    /// wakes the task of `cx` when it can make progress.  Once it is ready,
+    /// the evaluation can be resumed, and the call that returned the future
+    /// returns its result.
+    pub fn poll_await(&mut self,
+                      cx: &mut ::std::task::Context)
+                      -> ::std::task::Poll<Result<(), String>> {
+        interp::poll_await(&mut self.state.heap, cx)
+    }
+
+    /// Calls the procedure `nargs + 1` slots from the top of the stack with
+    /// the `nargs` values above it, replacing them with its result.
+    pub fn call(&mut self, nargs: usize) -> Result<(), String> {
+        interp::call(&mut self.state.heap, nargs)
+    }
+
+    /// The Scheme procedure calls that the last error returned by `execute`
+    /// or `call` unwound, innermost first.
+    pub fn backtrace(&self) -> &[interp::BacktraceFrame] {
+        self.state.heap.control.backtrace()
+    }
+
+    /// The object raised by the last error returned by `execute` or `call`,
+    /// if that error is `message` and was an exception that no handler
+    /// caught.
+    pub fn uncaught(&self, message: &str) -> Option<Root> {
+        self.state.heap.control.uncaught(message).cloned()
     }
 
     pub fn push<T: SchemeValue>(&mut self, value: T) -> Result<(), ()> {
         let state = &mut self.state;
-        let new_val = value.to_value(&mut state.heap);
+        let new_val = try!(value.to_value(&mut state.heap).map_err(|_| ()));
         Ok(state.heap.stack.push(new_val))
     }
 
@@ -121,12 +443,11 @@ impl State {
     pub fn cons(&mut self) -> Result<(), String> {
         let len = self.state.heap.stack.len();
         debug_assert!(len > 1);
-        self.state.heap.alloc_pair(len - 2, len - 1);
-        Ok(())
+        self.state.heap.alloc_pair(len - 2, len - 1)
     }
 
-    /// Creates a list whose elements are the top `arg - 1` elements of the
-    /// stack.  The top of the stack becomes the `cdr` of the last pair.
+    /// Creates a list whose elements are the `arg` elements below the top of
+    /// the stack, which becomes the `cdr` of the last pair.
     pub fn list_with_tail(&mut self, arg: usize) -> Result<(), String> {
         let len = self.len();
         if arg > len - 1 {
@@ -137,7 +458,7 @@ impl State {
         for _ in 0..(arg) {
             let q = self.len();
             try!(self.cons());
-            self.store(2, 0);
+            self.store(0, 2);
             self.state.heap.stack.pop();
             self.state.heap.stack.pop();
             debug_assert_eq!(q, self.len() + 1)
@@ -180,13 +501,15 @@ impl State {
         // The hot paths are fixnums and flonums.  They are inlined.
         // Most scripts probably do not heavily use complex numbers.
         // Bignums or rationals will always be slow.
-        let (fst, snd) = (heap.stack[src - fp].get(), heap.stack[src2 - fp].get());
-        heap.stack.push(if fst & snd & 3 == 0 {
-            value::Value::new(fst.wrapping_add(snd)) // TODO: bignums
-        } else {
-            return Err(());
-        });
-        Ok(())
+        let (fst, snd) = (heap.stack[src - fp].clone(), heap.stack[src2 - fp].clone());
+        if fst.both_fixnums(&snd) {
+            let sum = (fst.get() as isize).checked_add(snd.get() as isize);
+            if let Some(sum) = sum.and_then(value::Value::from_fixnum_word) {
+                return Ok(heap.stack.push(sum))
+            }
+        }
+        let sum = try!(arith::add(heap, &fst, &snd).map_err(|_| ()));
+        Ok(heap.stack.push(sum))
     }
 
     pub fn subtract(&mut self, src: usize, src2: usize) -> Result<(), String> {
@@ -225,13 +548,16 @@ impl State {
 
     pub fn vector(&mut self, src: usize, src2: usize) -> Result<(), String> {
         debug_assert!(src2 >= src);
-        Ok(alloc::Heap::alloc_vector(&mut self.state.heap, src, src2))
+        alloc::Heap::alloc_vector(&mut self.state.heap, src, src2)
     }
 
     pub fn array_set(&mut self, index: usize, src: usize, dst: usize) -> Result<(), String> {
         let fp = self.fp;
         let heap = &mut self.state.heap;
-        heap.stack[dst - fp].array_set(index, &heap.stack[src])
+        try!(heap.stack[dst - fp].array_set(index, &heap.stack[src]));
+        let (object, new) = (heap.stack[dst - fp].clone(), heap.stack[src].clone());
+        heap.write_barrier(&object, &new);
+        Ok(())
     }
 
     pub fn array_get(&mut self, index: usize, src: usize, dst: usize) -> Result<(), String> {
@@ -257,6 +583,20 @@ impl State {
         heap.stack.push(value::Value::new(value::NIL))
     }
 
+    /// Pushes `number`, which is converted to a flonum if it is too big for
+    /// a fixnum.
+    pub fn push_integer(&mut self, number: isize) -> Result<(), ()> {
+        match number.checked_mul(4).and_then(value::Value::from_fixnum_word) {
+            Some(value) => Ok(self.state.heap.stack.push(value)),
+            None => self.push(number as f64),
+        }
+    }
+
+    pub fn push_eof(&mut self) {
+        let heap = &mut self.state.heap;
+        heap.stack.push(value::Value::new(value::EOF))
+    }
+
     pub fn load_global(&mut self) -> Result<(), String> {
         self.state.heap.load_global()
     }
@@ -267,6 +607,45 @@ impl State {
         stack.push(val);
     }
 
+    /// Records that the pair on top of the stack was read at `location`.
+    pub fn set_location(&mut self, location: &alloc::Location) -> Result<(), String> {
+        let heap = &mut self.state.heap;
+        let top = heap.stack.len() - 1;
+        heap.set_location(top, location)
+    }
+
+    /// The location the value on top of the stack was read at, if known.
+    pub fn location(&mut self) -> Option<alloc::Location> {
+        let heap = &mut self.state.heap;
+        let top = heap.stack.last().cloned();
+        top.and_then(|value| heap.location(&value))
+    }
+
+    /// Compiles the datum on top of the stack, replacing it with the code
+    /// of a procedure of no arguments that evaluates it.
+    pub fn compile(&mut self) -> Result<(), String> {
+        compiler::compile(&mut self.state.heap)
+    }
+
+    /// Runs the code on top of the stack that `compile` made, replacing it
+    /// with its value.
+    pub fn run_compiled(&mut self) -> Result<(), String> {
+        interp::execute_compiled(&mut self.state.heap)
+    }
+
+    /// The heap, for use by the rest of the crate.
+    pub(crate) fn heap(&mut self) -> &mut alloc::Heap {
+        &mut self.state.heap
+    }
+
+    /// The top of the stack, printed as by `write`.
+    pub fn write_string(&self) -> Result<String, String> {
+        match self.state.heap.stack.last() {
+            Some(value) => Ok(print::to_string(value, print::Mode::Write)),
+            None => Err("Attempt to print from empty stack".to_owned()),
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.state.heap.stack.len()
     }
@@ -287,6 +666,17 @@ impl State {
     pub fn gc(&mut self) {
         alloc::collect(&mut self.state.heap)
     }
+
+    /// Pops the top of the stack into a `Root`, which keeps it alive
+    /// (and up to date) across garbage collections until dropped.
+    pub fn root(&mut self) -> Result<Root, String> {
+        self.state.heap.root_top()
+    }
+
+    /// Pushes the value held by a `Root` onto the stack.
+    pub fn push_root<T: ?Sized>(&mut self, root: &Root<T>) {
+        self.state.heap.stack.push(root.get())
+    }
 }
 
 #[cfg(test)]
@@ -301,6 +691,28 @@ mod tests {
         assert_eq!(x.unwrap(), 127)
     }
 
+    #[test]
+    fn fixnum_overflow_gives_flonums() {
+        use arith;
+        let mut interp = State::new();
+        let big = (1usize << (value::FIXNUM_WORD_BITS - 3)) - 1;
+        let heap = &mut interp.state.heap;
+        let fixnum = value::Value::new_fixnum(big);
+        let sum = arith::add(heap, &fixnum, &fixnum).unwrap();
+        assert_eq!(f64::of_value(&sum), Ok(2.0 * big as f64));
+        let product = arith::multiply(heap, &fixnum, &value::Value::new_fixnum(3)).unwrap();
+        assert_eq!(f64::of_value(&product), Ok(3.0 * big as f64));
+        let two = value::Value::new_fixnum(2);
+        assert_eq!(arith::multiply(heap, &two, &two), Ok(value::Value::new_fixnum(4)));
+        assert_eq!(arith::divide(heap, &two, &two), Ok(value::Value::new_fixnum(1)));
+        let half = arith::divide(heap, &value::Value::new_fixnum(1), &two).unwrap();
+        assert_eq!(f64::of_value(&half), Ok(0.5));
+        assert!(arith::divide(heap, &two, &value::Value::new_fixnum(0)).is_err());
+        interp.push(1.5).unwrap();
+        interp.gc();
+        assert_eq!(interp.pop(), Ok(1.5));
+    }
+
     #[test]
     fn intern_many_strings() {
         let _ = env_logger::init();
@@ -342,4 +754,119 @@ mod tests {
         interp.gc();
         assert_eq!(interp.state.heap.symbol_table.contents.len(), 0)
     }
+
+    #[test]
+    fn rooted_symbols_survive_gc() {
+        let mut interp = State::new();
+        interp.intern("rooted").unwrap();
+        let root = interp.root().unwrap();
+        assert!(interp.is_empty());
+        interp.gc();
+        assert_eq!(interp.state.heap.symbol_table.contents.len(), 1);
+        interp.push_root(&root);
+        assert_eq!(interp.len(), 1);
+        drop(root);
+        interp.drop().unwrap();
+        interp.gc();
+        assert_eq!(interp.state.heap.symbol_table.contents.len(), 0)
+    }
+
+    #[test]
+    fn evaluation_runs_out_of_fuel() {
+        let mut interp = State::new();
+        let reverse = "(let loop ((list '(1 2 3 4 5 6 7 8)) (reversed '()))
+                         (if (null? list)
+                             reversed
+                             (loop (cdr list) (cons (car list) reversed))))";
+        let mut outcome = interp.eval_with_fuel(reverse, 5).unwrap();
+        let mut suspensions = 0;
+        while let Fuel::Suspended(suspension) = outcome {
+            assert!(interp.is_empty());
+            interp.gc();
+            suspensions += 1;
+            outcome = interp.resume(suspension, 5).unwrap()
+        }
+        assert!(suspensions > 1);
+        assert_eq!(interp.write_string(), Ok("(8 7 6 5 4 3 2 1)".to_owned()));
+        interp.drop().unwrap();
+        assert_eq!(interp.eval_with_fuel("(car '(1 2))", 1000), Ok(Fuel::Finished));
+        assert_eq!(interp.pop(), Ok(1usize));
+        // Only the latest suspension can be resumed.
+        let first = interp.eval_with_fuel("(let loop () (loop))", 100).unwrap();
+        let second = interp.eval_with_fuel("(let loop () (loop))", 100).unwrap();
+        match (first, second) {
+            (Fuel::Suspended(first), Fuel::Suspended(second)) => {
+                assert_eq!(interp.resume(first, 100),
+                           Err("Suspension is no longer valid".to_owned()));
+                assert!(interp.resume(second, 100).unwrap() != Fuel::Finished)
+            }
+            outcomes => panic!("loops finished: {:?}", outcomes),
+        }
+        assert!(interp.is_empty())
+    }
+    #[test]
+    fn ports_from_rust() {
+        use std::cell::RefCell;
+        use std::io::{self, Cursor, Write};
+        use std::rc::Rc;
+        struct Sink(Rc<RefCell<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(bytes)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut interp = State::new();
+        let source = Box::new(Cursor::new(b"line\n".to_vec()));
+        interp.push_input_port("source", source, true).unwrap();
+        interp.intern("in").unwrap();
+        interp.store_global().unwrap();
+        let contents = Rc::new(RefCell::new(vec![]));
+        let sink = Box::new(Sink(contents.clone()));
+        interp.push_output_port("sink", sink, true, Buffering::Line).unwrap();
+        interp.intern("out").unwrap();
+        interp.store_global().unwrap();
+        let copy = "(begin (write-string (read-line in) out) (newline out) (read-line in))";
+        assert_eq!(interp.eval_with_fuel(copy, 1000), Ok(Fuel::Finished));
+        assert_eq!(interp.write_string(), Ok("#<eof>".to_owned()));
+        assert_eq!(*contents.borrow(), b"line\n");
+    }
+
+    #[test]
+    fn console() {
+        use std::cell::RefCell;
+        use std::io::{self, Cursor, Write};
+        use std::rc::Rc;
+        use platform::Console;
+        #[derive(Clone)]
+        struct Captured(Rc<RefCell<Vec<u8>>>);
+        impl Write for Captured {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(bytes)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Console for Captured {
+            fn input(&self) -> Box<dyn Source> {
+                Box::new(Cursor::new(b"typed\n".to_vec()))
+            }
+            fn output(&self) -> Box<dyn Sink> {
+                Box::new(self.clone())
+            }
+            fn error(&self) -> Box<dyn Sink> {
+                Box::new(self.clone())
+            }
+        }
+        let mut interp = State::new();
+        let captured = Captured(Rc::new(RefCell::new(vec![])));
+        interp.set_console(Rc::new(captured.clone()));
+        let echo = "(begin (display (read-line)) (newline) (write-string \"!\" (current-error-port)))";
+        assert_eq!(interp.eval_with_fuel(echo, 1000), Ok(Fuel::Finished));
+        interp.flush_output().unwrap();
+        assert_eq!(*captured.0.borrow(), b"typed\n!");
+    }
 }
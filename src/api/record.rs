@@ -0,0 +1,152 @@
+//! Rust structs as Scheme records.
+//!
+//! A struct implementing `SchemeRecord`, usually with
+//! `#[derive(SchemeRecord)]` from the `rusty_scheme_derive` crate, converts
+//! to and from a record of the type named `NAME`, with one field per entry
+//! of `FIELDS`.  `Interpreter::define_record` makes the record type and its
+//! procedures; for a type `point` with a field `x`, these are `<point>`,
+//! `make-point`, `point?`, `point-x` and `set-point-x!`.
+//!
+//! Conversions find the record type through the global variable `<point>`,
+//! so they fail until `define_record` has been called.
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{FromScheme, ToScheme};
+use super::convert::{from_stack, push_all};
+
+/// A Rust struct that converts to and from a Scheme record.
+pub trait SchemeRecord: Sized {
+    /// The name of the record type.
+    const NAME: &'static str;
+
+    /// The names of the fields, in order.
+    const FIELDS: &'static [&'static str];
+
+    /// The fields, in the order of `FIELDS`.
+    fn fields(&self) -> Vec<&dyn ToScheme>;
+
+    /// Makes a `Self` from the fields of a record, in the order of `FIELDS`.
+    fn from_fields(fields: &mut Fields) -> Result<Self, String>;
+}
+
+/// The fields of a record being converted to Rust, which are kept on the
+/// stack while they are converted.
+pub struct Fields<'a> {
+    heap: &'a mut Heap,
+    start: usize,
+    len: usize,
+    next: usize,
+}
+
+impl<'a> Fields<'a> {
+    /// Converts the next field.
+    pub fn next<T: FromScheme>(&mut self) -> Result<T, String> {
+        if self.next == self.len {
+            return Err("too few fields".to_owned())
+        }
+        self.next += 1;
+        from_stack(self.heap, self.start + self.next - 1)
+    }
+}
+
+/// The name of the global variable holding the record type `name`.
+pub fn descriptor_name(name: &str) -> String {
+    format!("<{}>", name)
+}
+
+/// The Scheme definitions of the record type of `T` and its procedures.
+pub fn definitions<T: SchemeRecord>() -> String {
+    let rtd = descriptor_name(T::NAME);
+    let specs: Vec<String> = T::FIELDS.iter()
+                                      .map(|field| format!("(mutable {})", field))
+                                      .collect();
+    let mut source = format!("(define {} (make-record-type-descriptor '{} #f #f #f #f '({})))\n\
+                              (define make-{1} (record-constructor {0}))\n\
+                              (define {1}? (record-predicate {0}))\n",
+                             rtd,
+                             T::NAME,
+                             specs.join(" "));
+    for (index, field) in T::FIELDS.iter().enumerate() {
+        source.push_str(&format!("(define {}-{} (record-accessor {} {}))\n\
+                                  (define set-{0}-{1}! (record-mutator {2} {3}))\n",
+                                 T::NAME,
+                                 field,
+                                 rtd,
+                                 index))
+    }
+    source
+}
+
+/// The record type `name`, which must have `count` fields, from its global
+/// variable.
+fn descriptor(heap: &mut Heap, name: &str, count: usize) -> Result<Value, String> {
+    heap.intern(&descriptor_name(name));
+    let symbol = heap.stack.pop().unwrap();
+    let rtd = match symbol.kind() {
+        Kind::Symbol(ptr) if unsafe { (*ptr).bound.get() } => unsafe {
+            (*(*ptr).contents.get()).clone()
+        },
+        _ => return Err(format!("record type {} is not defined", name)),
+    };
+    match rtd.kind() {
+        Kind::Record(record) if unsafe { (*record).descriptor.get() } == value::FALSE => {
+            let fields = unsafe { (*(record as *mut value::RecordDescriptor)).field_count.get() };
+            if fields >> 2 == count {
+                return Ok(rtd)
+            }
+        }
+        _ => {}
+    }
+    Err(format!("{} is not a record type with {} fields", descriptor_name(name), count))
+}
+
+/// Pushes a record of the type `name`, with `fields`.
+pub fn push_record(heap: &mut Heap, name: &str, fields: &[&dyn ToScheme]) -> Result<(), String> {
+    let rtd = try!(descriptor(heap, name, fields.len()));
+    let start = heap.stack.len();
+    heap.stack.push(rtd);
+    if let Err(e) = push_all(heap, fields) {
+        heap.stack.truncate(start);
+        return Err(e)
+    }
+    let end = heap.stack.len();
+    let result = heap.alloc_record(start, end);
+    let record = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    try!(result);
+    Ok(heap.stack.push(record))
+}
+
+impl<T: SchemeRecord> ToScheme for T {
+    fn to_scheme(&self, heap: &mut Heap) -> Result<(), String> {
+        push_record(heap, T::NAME, &self.fields())
+    }
+}
+
+impl<T: SchemeRecord> FromScheme for T {
+    fn from_scheme(heap: &mut Heap, value: &Value) -> Result<Self, String> {
+        // `value` is kept on the stack, since finding the type can allocate.
+        let base = heap.stack.len();
+        heap.stack.push(value.clone());
+        let result = descriptor(heap, T::NAME, T::FIELDS.len()).and_then(|rtd| {
+            let value = heap.stack[base].clone();
+            match value.kind() {
+                Kind::Record(record) if unsafe { (*record).descriptor.clone() } == rtd => {
+                    for i in 0..T::FIELDS.len() {
+                        heap.stack.push(unsafe { (*record).field(i) }.clone())
+                    }
+                }
+                _ => return Err(format!("expected a record of type {}", T::NAME)),
+            }
+            T::from_fields(&mut Fields {
+                heap: heap,
+                start: base + 1,
+                len: T::FIELDS.len(),
+                next: 0,
+            })
+        });
+        heap.stack.truncate(base);
+        result
+    }
+}
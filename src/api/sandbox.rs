@@ -0,0 +1,65 @@
+//! Profiles for running untrusted code.
+
+use builtins::Group;
+use super::HeapConfig;
+
+/// What code run by a sandboxed interpreter may do (see
+/// `State::sandboxed`).  The default allows none of the groups of
+/// primitives, with a heap of at most 64 MiB, and 10 million instructions
+/// per evaluation.
+///
+/// The primitives of a group that is not allowed are left unbound, so code
+/// that uses them fails with "Unbound variable".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SandboxConfig {
+    /// Whether files and directories may be used, and source files loaded
+    /// with `load` or by importing a library that is not yet loaded.
+    pub file_io: bool,
+
+    /// Whether the process and its environment may be inspected or exited,
+    /// and other programs run.
+    pub process: bool,
+
+    /// Whether sockets may be used.
+    pub network: bool,
+
+    /// Whether threads may be started, and channels used.  The threads
+    /// are as limited as the interpreter that starts them.
+    pub threads: bool,
+
+    /// The maximum size of the heap, in bytes.  `None` means no limit.
+    pub max_heap_bytes: Option<usize>,
+
+    /// The number of instructions that each evaluation may run, past which
+    /// it fails with "Out of fuel".  `None` means no limit.
+    pub fuel: Option<usize>,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            file_io: false,
+            process: false,
+            network: false,
+            threads: false,
+            max_heap_bytes: Some(64 << 20),
+            fuel: Some(10_000_000),
+        }
+    }
+}
+
+impl SandboxConfig {
+    /// The groups of primitives that are not allowed.
+    pub(crate) fn denied(&self) -> Vec<Group> {
+        let groups = [(self.file_io, Group::File),
+                      (self.process, Group::Process),
+                      (self.network, Group::Network),
+                      (self.threads, Group::Threads)];
+        groups.iter().filter(|&&(allowed, _)| !allowed).map(|&(_, group)| group).collect()
+    }
+
+    /// The configuration of the heap.
+    pub(crate) fn heap_config(&self) -> HeapConfig {
+        HeapConfig { max_bytes: self.max_heap_bytes, ..Default::default() }
+    }
+}
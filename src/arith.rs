@@ -1,77 +1,110 @@
+use std::cmp::Ordering;
 use alloc;
-use value::Value;
+use value::{self, Value};
 pub fn exponential(_: Value, _: Value) -> ! {
     unimplemented!()
 }
-pub fn slow_add(_alloc: alloc::Heap, _first: &mut Value, _other: &mut Value) -> ! {
-    unimplemented!()
+
+/// Converts a number to an `f64`, or returns `None` if `value` is not a
+/// number.
+pub fn to_float(value: &Value) -> Option<f64> {
+    if value.fixnump() {
+        Some((value.get() as isize >> 2) as f64)
+    } else if value.flonump() {
+        Some(unsafe { value::float_val(value) })
+    } else {
+        None
+    }
+}
+
+/// Compares two numbers, or returns `Err` if either is not a number.  The
+/// result is `None` if either is a NaN.
+pub fn compare(first: &Value, other: &Value) -> Result<Option<Ordering>, String> {
+    if first.both_fixnums(other) {
+        return Ok(Some((first.get() as isize).cmp(&(other.get() as isize))))
+    }
+    match (to_float(first), to_float(other)) {
+        (Some(x), Some(y)) => Ok(x.partial_cmp(&y)),
+        _ => Err("wrong type for comparison".to_owned()),
+    }
 }
+
+/// Allocates a boxed float holding `float`.  The result is not rooted.
+pub fn new_float(alloc: &mut alloc::Heap, float: f64) -> Result<Value, String> {
+    try!(alloc.alloc_float(float));
+    Ok(alloc.stack.pop().unwrap())
+}
+
+/// The slow path of arithmetic: converts both arguments to floats, and
+/// applies `op`.  Used for flonums, and for fixnum results that overflow.
+#[inline(never)]
+fn float_op(alloc: &mut alloc::Heap,
+            first: &Value,
+            other: &Value,
+            op: fn(f64, f64) -> f64)
+            -> Result<Value, String> {
+    match (to_float(first), to_float(other)) {
+        (Some(x), Some(y)) => new_float(alloc, op(x, y)),
+        _ => Err("wrong type for arithmetic".to_owned()),
+    }
+}
+
 /// Add two `Value`s, according to Scheme semantics.
 ///
-/// The cases where both are fixnums or both are flonums is special-cased
-/// as a fast path function, which is inlined into the interpreter.  The general case is much slower and put in a seperate function, which is not inlined.
-/// function
+/// The case where both are fixnums is special-cased as a fast path, which is
+/// inlined into the interpreter.  The general case (flonums, and fixnum sums
+/// that overflow into flonums) is much slower and put in a seperate function,
+/// which is not inlined.
 // #[inline(always)]
-pub fn add(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn add(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_add(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-        /*
-        if res.contents > first.contents {
-            // Overflow!
-            value::Bignum::new_from_fixnums(first.contents, other.contents)
-        } else {
-            Ok(res)
-        }*/
-    } else if first.flonump() && other.flonump() {
-        // Multiply the `f64` values pointed to by the arguments
-        //Ok(alloc.alloc_float(unsafe { float_val(first) * float_val(other) }))
-        //unimplemented!()
-        Err("flonums not yet implemented".to_owned())
-    } else {
-        // Slow path.
-        Err("non-fixnum addition not yet implemented".to_owned())
-        //
-        //self::slow_add(alloc, first, other)
+        let res = (first.get() as isize).checked_add(other.get() as isize);
+        if let Some(res) = res.and_then(Value::from_fixnum_word) {
+            return Ok(res)
+        }
     }
+    float_op(alloc, first, other, |x, y| x + y)
 }
 //#[inline(always)]
-pub fn subtract(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn subtract(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_sub(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
-    } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        let res = (first.get() as isize).checked_sub(other.get() as isize);
+        if let Some(res) = res.and_then(Value::from_fixnum_word) {
+            return Ok(res)
+        }
     }
+    float_op(alloc, first, other, |x, y| x - y)
 }
 
 //#[inline(always)]
-pub fn multiply(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn multiply(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let res = (first.get() & !1).checked_mul(other.get());
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
-    } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        // Only one of the factors may keep its tag bits.
+        let res = (first.get() as isize).checked_mul(other.get() as isize >> 2);
+        if let Some(res) = res.and_then(Value::from_fixnum_word) {
+            return Ok(res)
+        }
     }
+    float_op(alloc, first, other, |x, y| x * y)
 }
 
+/// Divide two `Value`s.  The quotient of two fixnums is a fixnum if it is
+/// exact, and a flonum otherwise.
 //#[inline(always)]
-pub fn divide(_alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
+pub fn divide(alloc: &mut alloc::Heap, first: &Value, other: &Value) -> Result<Value, String> {
     if first.both_fixnums(other) {
-        let (first, other) = (first.get() & !3, other.get() & !3);
-        let res = first.checked_div(other);
-        res.ok_or("overflow not yet implemented".to_owned())
-           .map(Value::new)
-    } else if first.flonump() && other.flonump() {
-        Err("flonums not yet implemented".to_owned())
-    } else {
-        Err("non-fixnum addition not yet implemented".to_owned())
+        let (first, other) = (first.get() as isize >> 2, other.get() as isize >> 2);
+        if other == 0 {
+            return Err("Division by zero".to_owned())
+        }
+        if first % other == 0 {
+            // Might not fit in a fixnum, if `first` is the smallest fixnum
+            // and `other` is -1.
+            if let Some(res) = (first / other).checked_mul(4).and_then(Value::from_fixnum_word) {
+                return Ok(res)
+            }
+        }
+        return new_float(alloc, first as f64 / other as f64)
     }
+    float_op(alloc, first, other, |x, y| x / y)
 }
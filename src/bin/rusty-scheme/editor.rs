@@ -0,0 +1,362 @@
+//! A small line editor, for terminals that understand ANSI escapes.
+//!
+//! While a line is being edited, the terminal is in raw mode, so Ctrl-C
+//! arrives as a key instead of a signal.  The keys are those of Emacs and
+//! readline: arrows, Home, End and Delete, and Ctrl-A, Ctrl-E, Ctrl-B,
+//! Ctrl-F, Ctrl-P, Ctrl-N, Ctrl-K, Ctrl-U, Ctrl-L and Ctrl-D.  When the
+//! input is not a terminal, lines are read as they are, without a prompt.
+//!
+//! Tab completes the word before the cursor as far as its completions
+//! agree, and lists them if that adds nothing.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use libc;
+
+/// The most lines of history kept.
+const HISTORY_SIZE: usize = 1000;
+
+/// What `Editor::read_line` read.
+pub enum Line {
+    Text(String),
+
+    /// The user pressed Ctrl-C.
+    Interrupted,
+
+    /// The end of the input, or Ctrl-D on an empty line.
+    Eof,
+}
+
+pub struct Editor {
+    /// The lines entered so far, oldest first.
+    history: Vec<String>,
+
+    /// The file that history is saved to, if any.
+    history_file: Option<PathBuf>,
+
+    terminal: bool,
+}
+
+/// Puts the terminal in raw mode until it is dropped.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn new() -> io::Result<RawMode> {
+        unsafe {
+            let mut original = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(io::Error::last_os_error())
+            }
+            let mut raw = original;
+            raw.c_iflag &= !(libc::BRKINT | libc::ICRNL | libc::INPCK | libc::ISTRIP | libc::IXON);
+            raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+            raw.c_cflag |= libc::CS8;
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+                return Err(io::Error::last_os_error())
+            }
+            Ok(RawMode(original))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.0);
+        }
+    }
+}
+
+/// A key, as far as editing is concerned.
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    KillToEnd,
+    KillToStart,
+    Clear,
+    Complete,
+    Interrupt,
+    EndOfFile,
+    Other,
+}
+
+/// Reads a byte from the terminal, or `None` at the end of the input.
+fn read_byte() -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match try!(io::stdin().read(&mut byte)) {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Reads a key from the terminal, or `None` at the end of the input.
+fn read_key() -> io::Result<Option<Key>> {
+    let byte = match try!(read_byte()) {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    Ok(Some(match byte {
+        b'\r' | b'\n' => Key::Enter,
+        1 => Key::Home,
+        2 => Key::Left,
+        3 => Key::Interrupt,
+        4 => Key::EndOfFile,
+        5 => Key::End,
+        6 => Key::Right,
+        8 | 127 => Key::Backspace,
+        9 => Key::Complete,
+        11 => Key::KillToEnd,
+        12 => Key::Clear,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::KillToStart,
+        27 => try!(read_escape()),
+        0...31 => Key::Other,
+        _ => {
+            // The rest of a UTF-8 sequence.
+            let len = match byte {
+                0xc0...0xdf => 2,
+                0xe0...0xef => 3,
+                0xf0...0xf7 => 4,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                match try!(read_byte()) {
+                    Some(byte) => bytes.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            match String::from_utf8(bytes) {
+                Ok(text) => Key::Char(text.chars().next().unwrap()),
+                Err(_) => Key::Other,
+            }
+        }
+    }))
+}
+
+/// Reads the rest of an escape sequence, after the escape.
+fn read_escape() -> io::Result<Key> {
+    let (first, second) = match (try!(read_byte()), try!(read_byte())) {
+        (Some(first), Some(second)) => (first, second),
+        _ => return Ok(Key::Other),
+    };
+    Ok(match (first, second) {
+        (b'[', b'A') => Key::Up,
+        (b'[', b'B') => Key::Down,
+        (b'[', b'C') => Key::Right,
+        (b'[', b'D') => Key::Left,
+        (b'[', b'H') | (b'O', b'H') => Key::Home,
+        (b'[', b'F') | (b'O', b'F') => Key::End,
+        (b'[', digit @ b'0'...b'9') => {
+            // `ESC [ n ~`
+            let mut code = vec![digit];
+            loop {
+                match try!(read_byte()) {
+                    Some(b'~') => break,
+                    Some(byte @ b'0'...b'9') => code.push(byte),
+                    _ => return Ok(Key::Other),
+                }
+            }
+            match &code[..] {
+                b"1" | b"7" => Key::Home,
+                b"3" => Key::Delete,
+                b"4" | b"8" => Key::End,
+                _ => Key::Other,
+            }
+        }
+        _ => Key::Other,
+    })
+}
+
+impl Editor {
+    /// An editor whose history is loaded from, and saved to,
+    /// `history_file`.
+    pub fn new(history_file: Option<PathBuf>) -> Self {
+        let mut history = vec![];
+        if let Some(file) = history_file.as_ref().and_then(|file| File::open(file).ok()) {
+            history.extend(BufReader::new(file).lines().filter_map(Result::ok))
+        }
+        let len = history.len();
+        history.drain(..len.saturating_sub(HISTORY_SIZE));
+        Editor {
+            history: history,
+            history_file: history_file,
+            terminal: unsafe { libc::isatty(libc::STDIN_FILENO) == 1 },
+        }
+    }
+
+    /// Reads a line, prompting with `prompt`.  `complete` gives the
+    /// completions of a word.
+    pub fn read_line<F>(&mut self, prompt: &str, complete: F) -> io::Result<Line>
+        where F: Fn(&str) -> Vec<String>
+    {
+        if !self.terminal {
+            let mut line = String::new();
+            if try!(io::stdin().read_line(&mut line)) == 0 {
+                return Ok(Line::Eof)
+            }
+            while line.ends_with('\n') || line.ends_with('\r') {
+                line.pop();
+            }
+            return Ok(Line::Text(line))
+        }
+        let line = {
+            let _raw = try!(RawMode::new());
+            try!(self.edit(prompt, complete))
+        };
+        try!(io::stdout().write_all(b"\n"));
+        if let Line::Text(ref text) = line {
+            self.add_history(text)
+        }
+        Ok(line)
+    }
+
+    /// Edits a line in raw mode.
+    fn edit<F>(&mut self, prompt: &str, complete: F) -> io::Result<Line>
+        where F: Fn(&str) -> Vec<String>
+    {
+        let mut line: Vec<char> = vec![];
+        let mut cursor = 0;
+        // The history entry being edited, or `history.len()` for the new
+        // line, which is stashed while history is browsed.
+        let (mut entry, mut stash) = (self.history.len(), vec![]);
+        try!(refresh(prompt, &line, cursor));
+        loop {
+            let key = match try!(read_key()) {
+                Some(key) => key,
+                None if line.is_empty() => return Ok(Line::Eof),
+                None => Key::Enter,
+            };
+            match key {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1
+                }
+                Key::Enter => return Ok(Line::Text(line.into_iter().collect())),
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::EndOfFile if line.is_empty() => return Ok(Line::Eof),
+                Key::EndOfFile if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left if cursor > 0 => cursor -= 1,
+                Key::Right if cursor < line.len() => cursor += 1,
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::KillToEnd => line.truncate(cursor),
+                Key::KillToStart => {
+                    line.drain(..cursor);
+                    cursor = 0
+                }
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up if entry > 0 => entry - 1,
+                        Key::Down if entry < self.history.len() => entry + 1,
+                        _ => continue,
+                    };
+                    if entry == self.history.len() {
+                        stash = line.clone()
+                    }
+                    line = match self.history.get(next) {
+                        Some(text) => text.chars().collect(),
+                        None => stash.clone(),
+                    };
+                    entry = next;
+                    cursor = line.len()
+                }
+                Key::Clear => try!(io::stdout().write_all(b"\x1b[H\x1b[2J")),
+                Key::Complete => {
+                    let start = line[..cursor]
+                                    .iter()
+                                    .rposition(|&c| c.is_whitespace() || DELIMITERS.contains(c))
+                                    .map_or(0, |i| i + 1);
+                    let word: String = line[start..cursor].iter().cloned().collect();
+                    let completions = if word.is_empty() { vec![] } else { complete(&word) };
+                    let common = common_prefix(&completions);
+                    if completions.is_empty() {
+                        try!(io::stdout().write_all(b"\x07"))
+                    } else if common.len() > word.chars().count() {
+                        for c in common.into_iter().skip(cursor - start) {
+                            line.insert(cursor, c);
+                            cursor += 1
+                        }
+                    } else if completions.len() > 1 {
+                        let list = format!("\r\n{}\r\n", completions.join("  "));
+                        try!(io::stdout().write_all(list.as_bytes()))
+                    }
+                }
+                Key::Interrupt => {
+                    try!(io::stdout().write_all(b"^C"));
+                    return Ok(Line::Interrupted)
+                }
+                _ => continue,
+            }
+            try!(refresh(prompt, &line, cursor))
+        }
+    }
+
+    /// Adds `line` to the history, unless it is blank or repeats the last
+    /// line, and appends it to the history file.
+    fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map_or(false, |last| last == line) {
+            return
+        }
+        self.history.push(line.to_owned());
+        if self.history.len() > HISTORY_SIZE {
+            self.history.remove(0);
+        }
+        if let Some(ref path) = self.history_file {
+            // History is a convenience, so failing to save it is not an
+            // error.
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            let _ = file.and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+}
+
+/// The characters that end a word, besides whitespace.
+const DELIMITERS: &'static str = "()[]'`,\";";
+
+/// The longest prefix that `words` have in common.
+fn common_prefix(words: &[String]) -> Vec<char> {
+    let mut prefix: Vec<char> = match words.first() {
+        Some(word) => word.chars().collect(),
+        None => return vec![],
+    };
+    for word in &words[1..] {
+        let len = prefix.iter().zip(word.chars()).take_while(|&(a, b)| *a == b).count();
+        prefix.truncate(len)
+    }
+    prefix
+}
+
+/// Redraws `line`, and puts the cursor at `cursor`.
+fn refresh(prompt: &str, line: &[char], cursor: usize) -> io::Result<()> {
+    let text: String = line.iter().cloned().collect();
+    let mut output = format!("\r{}{}\x1b[K\r", prompt, text);
+    let column = prompt.chars().count() + cursor;
+    if column > 0 {
+        output.push_str(&format!("\x1b[{}C", column))
+    }
+    let mut stdout = io::stdout();
+    try!(stdout.write_all(output.as_bytes()));
+    stdout.flush()
+}
@@ -0,0 +1,139 @@
+//! `rusty-scheme`: an interactive REPL, or, as `rusty-scheme script.scm
+//! args...`, a script runner.  `(command-line)` is then the script and its
+//! arguments, and a first line starting with `#!` is skipped.  With `--bare`
+//! first, the part of `(scheme base)` written in Scheme is left out.
+//!
+//! In the REPL, each expression is evaluated once its parentheses are balanced, which
+//! may take several lines, and its value is written out.  Ctrl-C abandons
+//! the expression being typed, or interrupts the one being evaluated.  Tab
+//! completes the identifiers bound at top level.  History is kept in
+//! `~/.rusty_scheme_history`.
+
+extern crate libc;
+extern crate rusty_scheme;
+
+mod editor;
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use rusty_scheme::{Input, State};
+use editor::{Editor, Line};
+
+/// Set when Ctrl-C is pressed during evaluation.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst)
+}
+
+/// Evaluates the expressions in `source`, writing out their values.
+/// Returns `false`, evaluating nothing, if the last expression is not
+/// complete yet.
+fn eval(interp: &mut State, source: &str) -> bool {
+    let mut input = Input::new(source.as_bytes(), "stdin");
+    let base = interp.len();
+    let mut count = 0;
+    loop {
+        match interp.read(&mut input) {
+            Ok(true) => count += 1,
+            Ok(false) => break,
+            Err(e) => {
+                truncate(interp, base);
+                if e.is_incomplete() {
+                    return false
+                }
+                eprintln!("read error: {:?}", e);
+                return true
+            }
+        }
+    }
+    for i in 0..count {
+        // The expressions are on the stack, the first one lowest.
+        interp.load(count - 1 - i);
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        let result = interp.execute().and_then(|()| interp.write_string());
+        truncate(interp, base + count);
+        if let Err(e) = interp.flush_output() {
+            eprintln!("error: {}", e)
+        }
+        match result {
+            Ok(ref text) if text == "#<unspecified>" => {}
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                report(interp, &e);
+                break
+            }
+        }
+    }
+    truncate(interp, base);
+    true
+}
+
+/// Prints `error`, and the backtrace of the calls it unwound.
+fn report(interp: &State, error: &str) {
+    eprintln!("error: {}", error);
+    for frame in interp.backtrace() {
+        eprintln!("  in {}", frame)
+    }
+}
+
+/// Pops values until `len` are left on the stack.
+fn truncate(interp: &mut State, len: usize) {
+    while interp.len() > len {
+        interp.drop().unwrap()
+    }
+}
+
+fn main() {
+    let mut arguments: Vec<String> = env::args().collect();
+    let mut interp = if arguments.get(1).map_or(false, |argument| argument == "--bare") {
+        arguments.remove(1);
+        State::new_bare()
+    } else {
+        State::new()
+    };
+    if arguments.len() > 1 {
+        interp.set_command_line(arguments[1..].to_vec());
+        let result = interp.load_file(&arguments[1]);
+        let _ = interp.flush_output();
+        if let Err(e) = result {
+            report(&interp, &e);
+            process::exit(1)
+        }
+        return
+    }
+    interp.set_command_line(arguments);
+    interp.set_interrupt(&INTERRUPTED);
+    unsafe {
+        libc::signal(libc::SIGINT, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".rusty_scheme_history"));
+    let mut editor = Editor::new(history);
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        let complete = |word: &str| {
+            interp.bound_names().into_iter().filter(|name| name.starts_with(word)).collect()
+        };
+        match editor.read_line(prompt, complete) {
+            Ok(Line::Text(line)) => {
+                source.push_str(&line);
+                source.push('\n')
+            }
+            Ok(Line::Interrupted) => {
+                source.clear();
+                continue
+            }
+            Ok(Line::Eof) => break,
+            Err(e) => {
+                eprintln!("error reading input: {}", e);
+                break
+            }
+        }
+        if eval(&mut interp, &source) {
+            source.clear()
+        }
+    }
+}
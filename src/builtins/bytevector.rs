@@ -0,0 +1,187 @@
+//! R7RS bytevector procedures.
+
+use alloc::Heap;
+use value::{self, Value};
+use super::{Primitive, args, boolean, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 9] =
+    [Primitive {
+         name: "bytevector?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_bytevector,
+     },
+     Primitive {
+         name: "make-bytevector",
+         min_args: 1,
+         max_args: Some(2),
+         function: make_bytevector,
+     },
+     Primitive {
+         name: "bytevector",
+         min_args: 0,
+         max_args: None,
+         function: bytevector,
+     },
+     Primitive {
+         name: "bytevector-length",
+         min_args: 1,
+         max_args: Some(1),
+         function: bytevector_length,
+     },
+     Primitive {
+         name: "bytevector-u8-ref",
+         min_args: 2,
+         max_args: Some(2),
+         function: bytevector_u8_ref,
+     },
+     Primitive {
+         name: "bytevector-u8-set!",
+         min_args: 3,
+         max_args: Some(3),
+         function: bytevector_u8_set,
+     },
+     Primitive {
+         name: "bytevector-copy",
+         min_args: 1,
+         max_args: Some(3),
+         function: bytevector_copy,
+     },
+     Primitive {
+         name: "bytevector-copy!",
+         min_args: 3,
+         max_args: Some(5),
+         function: bytevector_copy_to,
+     },
+     Primitive {
+         name: "bytevector-append",
+         min_args: 0,
+         max_args: None,
+         function: bytevector_append,
+     }];
+
+/// Converts an argument to a byte.
+fn byte_arg(value: &Value, procedure: &str) -> Result<u8, String> {
+    let byte = try!(fixnum_arg(value, procedure));
+    if byte > 0xFF {
+        return Err(format!("{}: {} is not a byte", procedure, byte))
+    }
+    Ok(byte as u8)
+}
+
+/// Copies the contents of `bytevector`.
+fn contents(heap: &Heap, bytevector: &Value) -> Result<Vec<u8>, String> {
+    Ok(unsafe { try!(heap.bytevector_as_slice(bytevector)) }.to_vec())
+}
+
+/// Converts optional `start` and `end` arguments into a range of a
+/// bytevector of length `len`.
+fn range(args: &[Value], len: usize, procedure: &str) -> Result<(usize, usize), String> {
+    let start = match args.get(0) {
+        Some(start) => try!(fixnum_arg(start, procedure)),
+        None => 0,
+    };
+    let end = match args.get(1) {
+        Some(end) => try!(fixnum_arg(end, procedure)),
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(format!("{}: range {}..{} out of bounds", procedure, start, end))
+    }
+    Ok((start, end))
+}
+
+/// Allocates a bytevector holding `bytes`, and returns it.  The stack is left
+/// as it was.
+fn new_bytevector(heap: &mut Heap, bytes: &[u8]) -> Result<Value, String> {
+    try!(heap.alloc_bytevector_from(bytes));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(bytevector? obj)`
+fn is_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let result = heap.bytevector_len(&args[0]).is_ok();
+    Ok(boolean(result))
+}
+
+/// `(make-bytevector k [byte])`
+fn make_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(fixnum_arg(&args[0], "make-bytevector"));
+    let fill = match args.get(1) {
+        Some(fill) => try!(byte_arg(fill, "make-bytevector")),
+        None => 0,
+    };
+    try!(heap.alloc_bytevector(len, fill));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(bytevector byte ...)`
+fn bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut bytes = Vec::with_capacity(nargs);
+    for arg in &args(heap, nargs) {
+        bytes.push(try!(byte_arg(arg, "bytevector")))
+    }
+    new_bytevector(heap, &bytes)
+}
+
+/// `(bytevector-length bytevector)`
+fn bytevector_length(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    Ok(Value::new_fixnum(try!(heap.bytevector_len(&args[0]))))
+}
+
+/// `(bytevector-u8-ref bytevector k)`
+fn bytevector_u8_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let index = try!(fixnum_arg(&args[1], "bytevector-u8-ref"));
+    let contents = unsafe { try!(heap.bytevector_as_slice(&args[0])) };
+    match contents.get(index) {
+        Some(&byte) => Ok(Value::new_fixnum(byte as usize)),
+        None => Err(format!("bytevector-u8-ref: index {} out of bounds", index)),
+    }
+}
+
+/// `(bytevector-u8-set! bytevector k byte)`
+fn bytevector_u8_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let index = try!(fixnum_arg(&args[1], "bytevector-u8-set!"));
+    let byte = try!(byte_arg(&args[2], "bytevector-u8-set!"));
+    if args[0].immutablep() {
+        return Err("bytevector-u8-set!: cannot modify a constant bytevector".to_owned())
+    }
+    let contents = unsafe { try!(heap.bytevector_as_mut_slice(&args[0])) };
+    match contents.get_mut(index) {
+        Some(place) => *place = byte,
+        None => return Err(format!("bytevector-u8-set!: index {} out of bounds", index)),
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(bytevector-copy bytevector [start [end]])`
+fn bytevector_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let bytes = try!(contents(heap, &args[0]));
+    let (start, end) = try!(range(&args[1..], bytes.len(), "bytevector-copy"));
+    new_bytevector(heap, &bytes[start..end])
+}
+
+/// `(bytevector-copy! to at from [start [end]])`
+fn bytevector_copy_to(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let at = try!(fixnum_arg(&args[1], "bytevector-copy!"));
+    let bytes = try!(contents(heap, &args[2]));
+    let (start, end) = try!(range(&args[3..], bytes.len(), "bytevector-copy!"));
+    try!(heap.bytevector_copy_from(&args[0], at, &bytes[start..end]));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(bytevector-append bytevector ...)`
+fn bytevector_append(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut bytes = vec![];
+    for arg in &args(heap, nargs) {
+        bytes.extend(try!(contents(heap, arg)))
+    }
+    new_bytevector(heap, &bytes)
+}
@@ -0,0 +1,206 @@
+//! R7RS character procedures.
+
+use std::char;
+use alloc::Heap;
+use value::Value;
+use super::{Primitive, args, boolean, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 15] =
+    [Primitive {
+         name: "char?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_char,
+     },
+     Primitive {
+         name: "char->integer",
+         min_args: 1,
+         max_args: Some(1),
+         function: char_to_integer,
+     },
+     Primitive {
+         name: "integer->char",
+         min_args: 1,
+         max_args: Some(1),
+         function: integer_to_char,
+     },
+     Primitive {
+         name: "char-upcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: char_upcase,
+     },
+     Primitive {
+         name: "char-downcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: char_downcase,
+     },
+     Primitive {
+         name: "char-foldcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: char_downcase,
+     },
+     Primitive {
+         name: "char-alphabetic?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_char_alphabetic,
+     },
+     Primitive {
+         name: "char-numeric?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_char_numeric,
+     },
+     Primitive {
+         name: "char-whitespace?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_char_whitespace,
+     },
+     Primitive {
+         name: "char=?",
+         min_args: 1,
+         max_args: None,
+         function: char_eq,
+     },
+     Primitive {
+         name: "char<?",
+         min_args: 1,
+         max_args: None,
+         function: char_lt,
+     },
+     Primitive {
+         name: "char>?",
+         min_args: 1,
+         max_args: None,
+         function: char_gt,
+     },
+     Primitive {
+         name: "char<=?",
+         min_args: 1,
+         max_args: None,
+         function: char_le,
+     },
+     Primitive {
+         name: "char>=?",
+         min_args: 1,
+         max_args: None,
+         function: char_ge,
+     },
+     Primitive {
+         name: "char-ci=?",
+         min_args: 1,
+         max_args: None,
+         function: char_ci_eq,
+     }];
+
+/// Converts an argument to a character.
+fn char_arg(value: &Value, procedure: &str) -> Result<char, String> {
+    value.as_char().map_err(|_| format!("{}: expected a character", procedure))
+}
+
+/// The single-character case mapping of `c`, or `c` itself if the mapping
+/// is not a single character.
+fn map_case<I: Iterator<Item = char>>(c: char, mut mapped: I) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c,
+    }
+}
+
+/// Checks that every argument is a character, and that `ordered` holds for
+/// each pair of adjacent arguments.
+fn compare(heap: &Heap,
+           nargs: usize,
+           procedure: &str,
+           ordered: fn(char, char) -> bool)
+           -> Result<Value, String> {
+    let mut chars = vec![];
+    for arg in &args(heap, nargs) {
+        chars.push(try!(char_arg(arg, procedure)))
+    }
+    Ok(boolean(chars.windows(2).all(|pair| ordered(pair[0], pair[1]))))
+}
+
+/// `(char? obj)`
+fn is_char(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].charp()))
+}
+
+/// `(char->integer char)`
+fn char_to_integer(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let c = try!(char_arg(&args(heap, nargs)[0], "char->integer"));
+    Ok(Value::new_fixnum(c as usize))
+}
+
+/// `(integer->char n)`
+fn integer_to_char(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let n = try!(fixnum_arg(&args(heap, nargs)[0], "integer->char"));
+    match char::from_u32(n as u32) {
+        Some(c) if n <= 0x10FFFF => Ok(Value::new_char(c)),
+        _ => Err(format!("integer->char: {} is not a Unicode scalar value", n)),
+    }
+}
+
+/// `(char-upcase char)`
+fn char_upcase(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let c = try!(char_arg(&args(heap, nargs)[0], "char-upcase"));
+    Ok(Value::new_char(map_case(c, c.to_uppercase())))
+}
+
+/// `(char-downcase char)` and `(char-foldcase char)`
+fn char_downcase(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let c = try!(char_arg(&args(heap, nargs)[0], "char-downcase"));
+    Ok(Value::new_char(map_case(c, c.to_lowercase())))
+}
+
+/// `(char-alphabetic? char)`
+fn is_char_alphabetic(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(try!(char_arg(&args(heap, nargs)[0], "char-alphabetic?")).is_alphabetic()))
+}
+
+/// `(char-numeric? char)`
+fn is_char_numeric(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(try!(char_arg(&args(heap, nargs)[0], "char-numeric?")).is_numeric()))
+}
+
+/// `(char-whitespace? char)`
+fn is_char_whitespace(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(try!(char_arg(&args(heap, nargs)[0], "char-whitespace?")).is_whitespace()))
+}
+
+/// `(char=? char1 char2 ...)`
+fn char_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "char=?", |a, b| a == b)
+}
+
+/// `(char<? char1 char2 ...)`
+fn char_lt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "char<?", |a, b| a < b)
+}
+
+/// `(char>? char1 char2 ...)`
+fn char_gt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "char>?", |a, b| a > b)
+}
+
+/// `(char<=? char1 char2 ...)`
+fn char_le(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "char<=?", |a, b| a <= b)
+}
+
+/// `(char>=? char1 char2 ...)`
+fn char_ge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "char>=?", |a, b| a >= b)
+}
+
+/// `(char-ci=? char1 char2 ...)`
+fn char_ci_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap,
+            nargs,
+            "char-ci=?",
+            |a, b| map_case(a, a.to_lowercase()) == map_case(b, b.to_lowercase()))
+}
@@ -0,0 +1,139 @@
+//! `apply`, first-class continuations, `dynamic-wind`, and exception
+//! handlers.
+//!
+//! `call/cc` needs the interpreter's registers, so the interpreter calls it
+//! itself (see `interp::call_cc`).  So does `apply`, which it replaces with
+//! the procedure applied, and the elements of the list with the rest of the
+//! arguments (see `interp::splice`), so that a tail call through `apply` is
+//! still a tail call.  The continuations it makes are closures
+//! over `CONTINUATION`, capturing copies of the stack and the control stack.
+//!
+//! Likewise, the interpreter replaces `dynamic-wind`,
+//! `with-exception-handler` and `%parameterize` by procedures that it runs
+//! like any other (see `interp::Procedure`).
+
+use alloc::Heap;
+use interp::{self, Procedure};
+use value::{Kind, Value};
+use super::{Primitive, callee, parameter, values};
+
+pub static PRIMITIVES: [Primitive; 5] =
+    [Primitive {
+         name: "call-with-current-continuation",
+         min_args: 1,
+         max_args: Some(1),
+         function: call_cc,
+     },
+     Primitive {
+         name: "call/cc",
+         min_args: 1,
+         max_args: Some(1),
+         function: call_cc,
+     },
+     Primitive {
+         name: "dynamic-wind",
+         min_args: 3,
+         max_args: Some(3),
+         function: call_bytecode_procedure,
+     },
+     Primitive {
+         name: "with-exception-handler",
+         min_args: 2,
+         max_args: Some(2),
+         function: call_bytecode_procedure,
+     },
+     Primitive {
+         name: "apply",
+         min_args: 2,
+         max_args: None,
+         function: apply,
+     }];
+
+/// The code of every continuation.  It is not bound to a global variable.
+pub static CONTINUATION: Primitive = Primitive {
+    name: "continuation",
+    min_args: 0,
+    max_args: None,
+    function: continuation,
+};
+
+/// Checks if `procedure` is `call/cc`.
+pub fn is_call_cc(procedure: &Value) -> bool {
+    match procedure.kind() {
+        Kind::Primitive(primitive) => {
+            PRIMITIVES[..2].iter().any(|call_cc| call_cc as *const Primitive == primitive)
+        }
+        _ => false,
+    }
+}
+
+/// Checks if `procedure` is `apply`.
+pub fn is_apply(procedure: &Value) -> bool {
+    match procedure.kind() {
+        Kind::Primitive(primitive) => primitive == &PRIMITIVES[4] as *const Primitive,
+        _ => false,
+    }
+}
+
+/// The procedure that the interpreter runs instead of `procedure`, when it
+/// is called with `nargs` arguments.
+pub fn bytecode_procedure(procedure: &Value, nargs: usize) -> Option<Procedure> {
+    let primitive = match procedure.kind() {
+        Kind::Primitive(primitive) => primitive,
+        _ => return None,
+    };
+    let (substitute, primitive) = if primitive == &PRIMITIVES[2] as *const Primitive {
+        (Procedure::DynamicWind, &PRIMITIVES[2])
+    } else if primitive == &PRIMITIVES[3] as *const Primitive {
+        (Procedure::WithExceptionHandler, &PRIMITIVES[3])
+    } else if primitive == &parameter::PRIMITIVES[2] as *const Primitive {
+        (Procedure::Parameterize, &parameter::PRIMITIVES[2])
+    } else {
+        return None
+    };
+    if primitive.min_args == nargs {
+        Some(substitute)
+    } else {
+        None
+    }
+}
+
+/// `(call/cc proc)`, when called by a primitive rather than by Scheme code.
+fn call_cc(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("call/cc: cannot capture the continuation of a primitive".to_owned())
+}
+
+/// `dynamic-wind`, `with-exception-handler` or `%parameterize`, when called
+/// by a primitive rather than by Scheme code.
+pub fn call_bytecode_procedure(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let procedure = bytecode_procedure(&callee(heap, nargs), nargs).unwrap();
+    let code = try!(interp::procedure_code(heap, procedure));
+    heap.stack.push(code);
+    for i in first..first + nargs {
+        let arg = heap.stack[i].clone();
+        heap.stack.push(arg)
+    }
+    try!(interp::call(heap, nargs));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(apply proc arg ... list)`, when called by a primitive rather than by
+/// Scheme code.
+fn apply(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    for i in first..first + nargs {
+        let arg = heap.stack[i].clone();
+        heap.stack.push(arg)
+    }
+    let nargs = try!(interp::splice(heap, first + nargs));
+    try!(interp::call(heap, nargs));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// Invokes a continuation with the values passed to it.
+fn continuation(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = try!(values::values(heap, nargs));
+    let continuation = callee(heap, nargs);
+    Err(interp::throw(heap, continuation, value))
+}
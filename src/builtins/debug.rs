@@ -0,0 +1,33 @@
+//! Tools for debugging the compiler.
+
+use std::io::{self, Write};
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args};
+
+pub static PRIMITIVES: [Primitive; 1] = [Primitive {
+                                             name: "disassemble",
+                                             min_args: 1,
+                                             max_args: Some(1),
+                                             function: disassemble,
+                                         }];
+
+/// `(disassemble procedure)`: writes out the bytecode of a procedure that
+/// was compiled from Scheme (see `bytecode::BCO`'s `Display`).
+fn disassemble(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let text = match args(heap, nargs)[0].kind() {
+        Kind::Closure(closure) => {
+            match unsafe { (*closure).code.kind() } {
+                Kind::Bytecode(bco) => unsafe { (*bco).to_string() },
+                _ => return Err("disassemble: expected a compiled procedure".to_owned()),
+            }
+        }
+        _ => return Err("disassemble: expected a compiled procedure".to_owned()),
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    try!(stdout.write_all(text.as_bytes())
+               .and_then(|()| stdout.flush())
+               .map_err(|e| format!("error writing to standard output: {}", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
@@ -0,0 +1,144 @@
+//! Growable vectors (see `alloc::dynvector`), which `vector-push!` appends
+//! to in amortized constant time.
+//!
+//! These are exported by `(rusty dynvector)`.
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 8] =
+    [Primitive {
+         name: "dynvector?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_dynvector,
+     },
+     Primitive {
+         name: "make-dynvector",
+         min_args: 0,
+         max_args: Some(1),
+         function: make_dynvector,
+     },
+     Primitive {
+         name: "dynvector-length",
+         min_args: 1,
+         max_args: Some(1),
+         function: dynvector_length,
+     },
+     Primitive {
+         name: "dynvector-ref",
+         min_args: 2,
+         max_args: Some(2),
+         function: dynvector_ref,
+     },
+     Primitive {
+         name: "dynvector-set!",
+         min_args: 3,
+         max_args: Some(3),
+         function: dynvector_set,
+     },
+     Primitive {
+         name: "vector-push!",
+         min_args: 2,
+         max_args: Some(2),
+         function: vector_push,
+     },
+     Primitive {
+         name: "dynvector-pop!",
+         min_args: 1,
+         max_args: Some(1),
+         function: dynvector_pop,
+     },
+     Primitive {
+         name: "dynvector->vector",
+         min_args: 1,
+         max_args: Some(1),
+         function: dynvector_to_vector,
+     }];
+
+/// The growable vector `value`, an argument of the primitive `name`.
+fn dynvector_arg(value: &Value, name: &str) -> Result<*mut value::DynVector, String> {
+    match value.kind() {
+        Kind::DynVector(dynvector) => Ok(dynvector),
+        _ => Err(format!("{}: expected a growable vector", name)),
+    }
+}
+
+/// The index `value` into the growable vector `dynvector`, an argument of
+/// the primitive `name`.
+fn index_arg(dynvector: *mut value::DynVector, value: &Value, name: &str) -> Result<usize, String> {
+    let index = try!(fixnum_arg(value, name));
+    if index >= unsafe { (*dynvector).len() } {
+        return Err(format!("{}: index out of range", name))
+    }
+    Ok(index)
+}
+
+/// `(dynvector? obj)`
+fn is_dynvector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(dynvector_arg(&args(heap, nargs)[0], "dynvector?").is_ok()))
+}
+
+/// `(make-dynvector [capacity])`, which is empty.
+fn make_dynvector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let capacity = match args(heap, nargs).get(0) {
+        Some(capacity) => try!(fixnum_arg(capacity, "make-dynvector")),
+        None => 0,
+    };
+    try!(heap.alloc_dynvector(capacity));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(dynvector-length dynvector)`
+fn dynvector_length(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let dynvector = try!(dynvector_arg(&args(heap, nargs)[0], "dynvector-length"));
+    Ok(Value::new_fixnum(unsafe { (*dynvector).len() }))
+}
+
+/// `(dynvector-ref dynvector k)`
+fn dynvector_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let dynvector = try!(dynvector_arg(&args[0], "dynvector-ref"));
+    let index = try!(index_arg(dynvector, &args[1], "dynvector-ref"));
+    Ok(unsafe { (*dynvector).element(index).clone() })
+}
+
+/// `(dynvector-set! dynvector k obj)`
+fn dynvector_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let dynvector = try!(dynvector_arg(&args[0], "dynvector-set!"));
+    let index = try!(index_arg(dynvector, &args[1], "dynvector-set!"));
+    let storage = unsafe {
+        (*dynvector).element(index).set(args[2].clone());
+        (*dynvector).storage.clone()
+    };
+    heap.write_barrier(&storage, &args[2]);
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-push! dynvector obj)`, which appends `obj`.
+fn vector_push(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(dynvector_arg(&heap.stack[first], "vector-push!"));
+    try!(heap.dynvector_push(first, first + 1));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(dynvector-pop! dynvector)`, which removes the last element and
+/// returns it.
+fn dynvector_pop(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let dynvector = args(heap, nargs)[0].clone();
+    try!(dynvector_arg(&dynvector, "dynvector-pop!"));
+    heap.dynvector_pop(&dynvector).map_err(|_| "dynvector-pop!: empty growable vector".to_owned())
+}
+
+/// `(dynvector->vector dynvector)`, a new vector of the elements.
+fn dynvector_to_vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let dynvector = args(heap, nargs)[0].clone();
+    try!(dynvector_arg(&dynvector, "dynvector->vector"));
+    heap.stack.push(dynvector);
+    let result = heap.dynvector_to_vector();
+    let vector = heap.stack.pop().unwrap();
+    result.map(|()| vector)
+}
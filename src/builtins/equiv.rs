@@ -0,0 +1,44 @@
+//! The R7RS equivalence predicates.
+
+use alloc::Heap;
+use equiv;
+use value::Value;
+use super::{Primitive, args, boolean};
+
+pub static PRIMITIVES: [Primitive; 3] =
+    [Primitive {
+         name: "eq?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_eq,
+     },
+     Primitive {
+         name: "eqv?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_eqv,
+     },
+     Primitive {
+         name: "equal?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_equal,
+     }];
+
+/// `(eq? obj1 obj2)`
+fn is_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    Ok(boolean(equiv::eq(&args[0], &args[1])))
+}
+
+/// `(eqv? obj1 obj2)`
+fn is_eqv(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    Ok(boolean(equiv::eqv(&args[0], &args[1])))
+}
+
+/// `(equal? obj1 obj2)`
+fn is_equal(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    Ok(boolean(equiv::equal(&args[0], &args[1])))
+}
@@ -0,0 +1,224 @@
+//! `eval` and environments.
+//!
+//! An environment is a closure over `ENVIRONMENT`, capturing `#t` for the
+//! interaction environment, or else the list of import sets it imports.
+//! `eval` expands its expression at top level in the interaction
+//! environment, and otherwise in a new scope that imports those sets, so
+//! that it does not see the macros and imports of the top level (see
+//! `expand::expand`).  Definitions made there define global variables.
+//!
+//! `load` runs the code in a file the same way, one datum at a time, and
+//! `compile-file` runs it while writing out what it compiles to.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use alloc::Heap;
+use fasl;
+use interp;
+use read::{self, Input};
+use value::{self, Kind, Value};
+use super::{Primitive, args, fixnum_arg};
+use super::pair::list;
+
+pub static PRIMITIVES: [Primitive; 6] =
+    [Primitive {
+         name: "eval",
+         min_args: 1,
+         max_args: Some(2),
+         function: eval,
+     },
+     Primitive {
+         name: "load",
+         min_args: 1,
+         max_args: Some(2),
+         function: load_primitive,
+     },
+     Primitive {
+         name: "compile-file",
+         min_args: 1,
+         max_args: Some(2),
+         function: compile_file,
+     },
+     Primitive {
+         name: "environment",
+         min_args: 0,
+         max_args: None,
+         function: environment,
+     },
+     Primitive {
+         name: "interaction-environment",
+         min_args: 0,
+         max_args: Some(0),
+         function: interaction_environment,
+     },
+     Primitive {
+         name: "scheme-report-environment",
+         min_args: 1,
+         max_args: Some(1),
+         function: scheme_report_environment,
+     }];
+
+/// The code of every environment.  It is not bound to a global variable.
+static ENVIRONMENT: Primitive = Primitive {
+    name: "environment",
+    min_args: 0,
+    max_args: None,
+    function: call_environment,
+};
+
+/// What `value` captures, if it is an environment.
+fn imports(value: &Value) -> Option<Value> {
+    match value.kind() {
+        Kind::Closure(closure) => unsafe {
+            match (*closure).code.kind() {
+                Kind::Primitive(primitive) if primitive == &ENVIRONMENT as *const Primitive => {
+                    Some((*closure).captured(0).clone())
+                }
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// A new environment, capturing `imports`.
+fn new_environment(heap: &mut Heap, imports: Value) -> Result<Value, String> {
+    let start = heap.stack.len();
+    heap.stack.push(ENVIRONMENT.to_value());
+    heap.stack.push(imports);
+    let result = heap.alloc_closure(start, start + 2).map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    result
+}
+
+/// Called when an environment is called as a procedure.
+fn call_environment(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("Attempt to call an environment".to_owned())
+}
+
+/// What the optional environment argument `environment` of `procedure`
+/// captures: `#t` for the interaction environment, which is the default.
+fn environment_arg(environment: Option<&Value>, procedure: &str) -> Result<Value, String> {
+    match environment.map(imports) {
+        None => Ok(Value::new(value::TRUE)),
+        Some(Some(imports)) => Ok(imports),
+        Some(None) => Err(format!("{}: expected an environment", procedure)),
+    }
+}
+
+/// Reads the source file `file`.  A first line starting with `#!` and a
+/// space or a slash is skipped, so that scripts can be run directly.
+pub fn read_source(file: &Path) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    if let Err(e) = File::open(file).and_then(|mut reader| reader.read_to_end(&mut bytes)) {
+        return Err(format!("{}: {}", file.display(), e))
+    }
+    if bytes.starts_with(b"#!/") || bytes.starts_with(b"#! ") {
+        // Turned into a comment, which keeps source locations right.
+        bytes[0] = b';';
+        bytes[1] = b';'
+    }
+    Ok(bytes)
+}
+
+/// Runs the code in `file`, in the environment that captures `imports`
+/// (see `eval`).  Compiled files (see `fasl`) can only be run at top level.
+pub fn load(heap: &mut Heap, file: &Path, imports: Value) -> Result<(), String> {
+    let bytes = try!(read_source(file));
+    if fasl::is_compiled(&bytes) {
+        if imports.get() != value::TRUE {
+            return Err(format!("{}: compiled files can only be loaded at top level",
+                               file.display()))
+        }
+        return fasl::load(heap, file, &bytes)
+    }
+    let mut input = Input::new(&bytes[..], &file.to_string_lossy());
+    let base = heap.stack.len();
+    heap.stack.push(imports);
+    let result = loop {
+        if let Err(e) = read::read_to_heap(heap, &mut input) {
+            break Err(format!("{}: read error: {:?}", file.display(), e))
+        }
+        if heap.stack[base + 1].get() == value::EOF {
+            break Ok(())
+        }
+        let imports = heap.stack[base].clone();
+        let imports = if imports.get() == value::TRUE { None } else { Some(&imports) };
+        if let Err(e) = interp::eval(heap, imports) {
+            break Err(e)
+        }
+        heap.stack.truncate(base + 1)
+    };
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(eval expr-or-def [environment])`, in the interaction environment by
+/// default.
+fn eval(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let imports = try!(environment_arg(args.get(1), "eval"));
+    let base = heap.stack.len();
+    heap.stack.push(args[0].clone());
+    let imports = if imports.get() == value::TRUE { None } else { Some(&imports) };
+    let result = interp::eval(heap, imports).map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(load filename [environment])`
+fn load_primitive(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let imports = try!(environment_arg(args.get(1), "load"));
+    let file = match args[0].kind() {
+        Kind::String(string) => unsafe { (*string).as_str().to_owned() },
+        _ => return Err("load: expected a string".to_owned()),
+    };
+    try!(load(heap, Path::new(&file), imports));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(compile-file filename [output])`, where `output` is `filename` with
+/// its extension replaced by `.fasl` by default.
+fn compile_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let mut files = vec![];
+    for arg in &args {
+        match arg.kind() {
+            Kind::String(string) => files.push(PathBuf::from(unsafe { (*string).as_str() })),
+            _ => return Err("compile-file: expected a string".to_owned()),
+        }
+    }
+    let output = files.get(1).cloned().unwrap_or_else(|| files[0].with_extension("fasl"));
+    try!(fasl::compile_file(heap, &files[0], &output));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(environment import-set ...)`
+fn environment(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let sets = try!(list(heap, nargs));
+    new_environment(heap, sets)
+}
+
+/// `(interaction-environment)`: the top level.
+fn interaction_environment(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    new_environment(heap, Value::new(value::TRUE))
+}
+
+/// `(scheme-report-environment version)`, which only supports R7RS: the
+/// environment of `(scheme base)`.
+fn scheme_report_environment(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    if try!(fixnum_arg(&args(heap, nargs)[0], "scheme-report-environment")) != 7 {
+        return Err("scheme-report-environment: unsupported version".to_owned())
+    }
+    let base = heap.stack.len();
+    heap.intern("scheme");
+    heap.intern("base");
+    let result = list(heap, 2).and_then(|name| {
+        heap.stack.push(name);
+        list(heap, 1)
+    });
+    heap.stack.truncate(base);
+    new_environment(heap, try!(result))
+}
@@ -0,0 +1,164 @@
+//! Raising exceptions, and R7RS error objects.
+//!
+//! Error objects are conditions (`value::Condition`), which record a type
+//! as well as a message and irritants.  The errors that primitives and the
+//! interpreter return are raised as conditions of type `error`, with the
+//! error message as message.  Interrupts are raised as continuable
+//! conditions of type `interrupt` (see `interp::Control`).
+
+use alloc::Heap;
+use interp;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean};
+
+pub static PRIMITIVES: [Primitive; 9] =
+    [Primitive {
+         name: "raise",
+         min_args: 1,
+         max_args: Some(1),
+         function: raise,
+     },
+     Primitive {
+         name: "raise-continuable",
+         min_args: 1,
+         max_args: Some(1),
+         function: raise_continuable,
+     },
+     Primitive {
+         name: "error",
+         min_args: 1,
+         max_args: None,
+         function: error,
+     },
+     Primitive {
+         name: "error-object?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_error_object,
+     },
+     Primitive {
+         name: "error-object-message",
+         min_args: 1,
+         max_args: Some(1),
+         function: error_object_message,
+     },
+     Primitive {
+         name: "error-object-irritants",
+         min_args: 1,
+         max_args: Some(1),
+         function: error_object_irritants,
+     },
+     Primitive {
+         name: "read-error?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_read_error,
+     },
+     Primitive {
+         name: "file-error?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_file_error,
+     },
+     Primitive {
+         name: "interrupt?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_interrupt,
+     }];
+
+/// Converts the argument `value` of `procedure` to a condition.
+fn condition_arg(value: &Value, procedure: &str) -> Result<*mut value::Condition, String> {
+    match value.kind() {
+        Kind::Condition(condition) => Ok(condition),
+        _ => Err(format!("{}: expected an error object", procedure)),
+    }
+}
+
+/// Checks if `value` is a condition of type `kind`.
+fn is_condition_of_type(value: &Value, kind: &str) -> bool {
+    match value.kind() {
+        Kind::Condition(condition) => {
+            match unsafe { (*condition).kind.kind() } {
+                Kind::Symbol(symbol) => unsafe { *(*symbol).name() == kind },
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// `(raise obj)`
+fn raise(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let object = args(heap, nargs)[0].clone();
+    heap.stack.push(object);
+    interp::raise(heap, false)
+}
+
+/// `(raise-continuable obj)`
+fn raise_continuable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let object = args(heap, nargs)[0].clone();
+    heap.stack.push(object);
+    interp::raise(heap, true)
+}
+
+/// `(error message obj ...)`
+fn error(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let base = heap.stack.len();
+    heap.intern("error");
+    let message = heap.stack[first].clone();
+    heap.stack.push(message);
+    heap.stack.push(Value::new(value::NIL));
+    for i in (first + 1..base).rev() {
+        let irritant = heap.stack[i].clone();
+        heap.stack.push(irritant);
+        let len = heap.stack.len();
+        let result = heap.alloc_pair(len - 1, len - 2);
+        let list = heap.stack.pop();
+        heap.stack.truncate(len - 2);
+        if let Err(e) = result {
+            heap.stack.truncate(base);
+            return Err(e)
+        }
+        heap.stack.push(list.unwrap())
+    }
+    let result = heap.alloc_condition(base);
+    let condition = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(result);
+    heap.stack.push(condition.unwrap());
+    interp::raise(heap, false)
+}
+
+/// `(error-object? obj)`
+fn is_error_object(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(condition_arg(&args(heap, nargs)[0], "").is_ok()))
+}
+
+/// `(error-object-message error-object)`
+fn error_object_message(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let condition = try!(condition_arg(&args(heap, nargs)[0], "error-object-message"));
+    Ok(unsafe { (*condition).message.clone() })
+}
+
+/// `(error-object-irritants error-object)`
+fn error_object_irritants(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let condition = try!(condition_arg(&args(heap, nargs)[0], "error-object-irritants"));
+    Ok(unsafe { (*condition).irritants.clone() })
+}
+
+/// `(read-error? obj)`
+fn is_read_error(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(is_condition_of_type(&args(heap, nargs)[0], "read-error")))
+}
+
+/// `(file-error? obj)`
+fn is_file_error(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(is_condition_of_type(&args(heap, nargs)[0], "file-error")))
+}
+
+/// `(interrupt? obj)`
+fn is_interrupt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(is_condition_of_type(&args(heap, nargs)[0], "interrupt")))
+}
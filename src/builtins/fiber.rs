@@ -0,0 +1,51 @@
+//! Cooperative fibers.
+//!
+//! `(spawn thunk)` makes a fiber that calls `thunk`, and `(yield)` lets the
+//! other fibers run.  The fibers take turns, in the order in which they
+//! last yielded.  The scheduler is part of the interpreter, which switches
+//! fibers when `yield` is called by Scheme code (see `interp::Control`).
+//! When it is called by a primitive, or by a procedure that a primitive
+//! called, it just returns.
+
+use alloc::Heap;
+use interp;
+use value::{self, Kind, Value};
+use super::{Primitive, args};
+
+pub static PRIMITIVES: [Primitive; 2] =
+    [Primitive {
+         name: "spawn",
+         min_args: 1,
+         max_args: Some(1),
+         function: spawn,
+     },
+     Primitive {
+         name: "yield",
+         min_args: 0,
+         max_args: Some(0),
+         function: yield_,
+     }];
+
+/// Checks if `procedure` is `yield`.
+pub fn is_yield(procedure: &Value) -> bool {
+    match procedure.kind() {
+        Kind::Primitive(primitive) => primitive == &PRIMITIVES[1] as *const Primitive,
+        _ => false,
+    }
+}
+
+/// `(spawn thunk)`
+fn spawn(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let thunk = args(heap, nargs)[0].clone();
+    match thunk.kind() {
+        Kind::Closure(_) | Kind::Primitive(_) => {}
+        _ => return Err("spawn: expected a procedure".to_owned()),
+    }
+    try!(interp::spawn(heap, thunk));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(yield)`, when no other fiber can run.
+fn yield_(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Ok(Value::new(value::UNSPECIFIED))
+}
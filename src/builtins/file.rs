@@ -0,0 +1,325 @@
+//! The R7RS file library, and extensions for directories and metadata.
+//!
+//! Files are opened as ports (see `port`): output ports are block
+//! buffered.  `with-input-from-file` and `with-output-to-file` parameterize
+//! the current port around the thunk, and the `call-with-` procedures pass
+//! the port to the procedure.  All four close the port when the procedure
+//! returns, but not when it escapes.
+//!
+//! Errors from the file system raise conditions of type `file-error`, which
+//! `file-error?` recognizes.
+
+use std::fs::{self, File};
+use std::io;
+use std::time::UNIX_EPOCH;
+use alloc::Heap;
+use interp;
+use port::{Buffering, Port};
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, call};
+use super::pair::list;
+use super::parameter;
+use super::port::{self as ports, INPUT, OUTPUT};
+
+pub static PRIMITIVES: [Primitive; 15] =
+    [Primitive {
+         name: "file-exists?",
+         min_args: 1,
+         max_args: Some(1),
+         function: file_exists,
+     },
+     Primitive {
+         name: "delete-file",
+         min_args: 1,
+         max_args: Some(1),
+         function: delete_file,
+     },
+     Primitive {
+         name: "open-input-file",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_input_file,
+     },
+     Primitive {
+         name: "open-binary-input-file",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_binary_input_file,
+     },
+     Primitive {
+         name: "open-output-file",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_output_file,
+     },
+     Primitive {
+         name: "open-binary-output-file",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_binary_output_file,
+     },
+     Primitive {
+         name: "call-with-input-file",
+         min_args: 2,
+         max_args: Some(2),
+         function: call_with_input_file,
+     },
+     Primitive {
+         name: "call-with-output-file",
+         min_args: 2,
+         max_args: Some(2),
+         function: call_with_output_file,
+     },
+     Primitive {
+         name: "with-input-from-file",
+         min_args: 2,
+         max_args: Some(2),
+         function: with_input_from_file,
+     },
+     Primitive {
+         name: "with-output-to-file",
+         min_args: 2,
+         max_args: Some(2),
+         function: with_output_to_file,
+     },
+     Primitive {
+         name: "directory-list",
+         min_args: 1,
+         max_args: Some(1),
+         function: directory_list,
+     },
+     Primitive {
+         name: "rename-file",
+         min_args: 2,
+         max_args: Some(2),
+         function: rename_file,
+     },
+     Primitive {
+         name: "file-directory?",
+         min_args: 1,
+         max_args: Some(1),
+         function: file_directory,
+     },
+     Primitive {
+         name: "file-size",
+         min_args: 1,
+         max_args: Some(1),
+         function: file_size,
+     },
+     Primitive {
+         name: "file-modification-time",
+         min_args: 1,
+         max_args: Some(1),
+         function: file_modification_time,
+     }];
+
+/// The file name argument `value` of the primitive `name`.
+fn file_arg(value: &Value, name: &str) -> Result<String, String> {
+    match value.kind() {
+        Kind::String(string) => Ok(unsafe { (*string).as_str() }.to_owned()),
+        _ => Err(format!("{}: expected a file name", name)),
+    }
+}
+
+/// Raises a `file-error` for `error`, which happened when the primitive
+/// `name` used `file`.  The raise is not continuable, so it fails.
+fn file_error(heap: &mut Heap, name: &str, file: &str, error: io::Error) -> String {
+    let message = format!("{}: {}: {}", name, file, error);
+    match interp::raise_condition(heap, "file-error", &message, false) {
+        Ok(_) => bug!("returned from a non-continuable raise"),
+        Err(e) => e,
+    }
+}
+
+/// Opens `file` as a port for the primitive `name`, and returns it.
+fn open(heap: &mut Heap,
+        file: &str,
+        input: bool,
+        textual: bool,
+        name: &str)
+        -> Result<Value, String> {
+    let port = if input {
+        File::open(file).map(|f| Port::input(file, Box::new(f), textual))
+    } else {
+        File::create(file).map(|f| Port::output(file, Box::new(f), textual, Buffering::Block))
+    };
+    match port {
+        Ok(port) => {
+            try!(heap.alloc_port(port));
+            Ok(heap.stack.pop().unwrap())
+        }
+        Err(e) => Err(file_error(heap, name, file, e)),
+    }
+}
+
+/// Opens the file named by the first argument, as a port for the primitive
+/// `name`.
+fn open_arg(heap: &mut Heap,
+            nargs: usize,
+            input: bool,
+            textual: bool,
+            name: &str)
+            -> Result<Value, String> {
+    let file = try!(file_arg(&args(heap, nargs)[0], name));
+    open(heap, &file, input, textual, name)
+}
+
+/// Opens the file named by the first argument as a textual port, calls the
+/// second one, and closes the port.  If `current` is set, the procedure is
+/// a thunk, called with the current input or output port set to the port;
+/// otherwise, it is called with the port.
+fn call_with_file(heap: &mut Heap,
+                  nargs: usize,
+                  input: bool,
+                  current: bool,
+                  name: &str)
+                  -> Result<Value, String> {
+    let port = try!(open_arg(heap, nargs, input, true, name));
+    let procedure = args(heap, nargs)[1].clone();
+    let base = heap.stack.len();
+    heap.stack.push(port.clone());
+    let result = if current {
+        let which = if input { INPUT } else { OUTPUT };
+        heap.stack.push(parameter::PRIMITIVES[2].to_value());
+        heap.stack.push(ports::PRIMITIVES[which].to_value());
+        heap.stack.push(port);
+        heap.stack.push(procedure);
+        call(heap, 3)
+    } else {
+        heap.stack.push(procedure);
+        heap.stack.push(port);
+        call(heap, 1)
+    };
+    let value = result.map(|()| heap.stack.pop().unwrap());
+    let port = heap.stack[base].clone();
+    heap.stack.truncate(base);
+    let value = try!(value);
+    if let Some(port) = ports::state(&port) {
+        try!(port.close().map_err(|e| format!("{}: {}", name, e)))
+    }
+    Ok(value)
+}
+
+/// `(file-exists? filename)`
+fn file_exists(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let file = try!(file_arg(&args(heap, nargs)[0], "file-exists?"));
+    Ok(boolean(fs::metadata(&file).is_ok()))
+}
+
+/// `(delete-file filename)`
+fn delete_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let file = try!(file_arg(&args(heap, nargs)[0], "delete-file"));
+    match fs::remove_file(&file) {
+        Ok(()) => Ok(Value::new(value::UNSPECIFIED)),
+        Err(e) => Err(file_error(heap, "delete-file", &file, e)),
+    }
+}
+
+/// `(open-input-file filename)`
+fn open_input_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    open_arg(heap, nargs, true, true, "open-input-file")
+}
+
+/// `(open-binary-input-file filename)`
+fn open_binary_input_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    open_arg(heap, nargs, true, false, "open-binary-input-file")
+}
+
+/// `(open-output-file filename)`
+fn open_output_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    open_arg(heap, nargs, false, true, "open-output-file")
+}
+
+/// `(open-binary-output-file filename)`
+fn open_binary_output_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    open_arg(heap, nargs, false, false, "open-binary-output-file")
+}
+
+/// `(call-with-input-file filename proc)`
+fn call_with_input_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    call_with_file(heap, nargs, true, false, "call-with-input-file")
+}
+
+/// `(call-with-output-file filename proc)`
+fn call_with_output_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    call_with_file(heap, nargs, false, false, "call-with-output-file")
+}
+
+/// `(with-input-from-file filename thunk)`
+fn with_input_from_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    call_with_file(heap, nargs, true, true, "with-input-from-file")
+}
+
+/// `(with-output-to-file filename thunk)`
+fn with_output_to_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    call_with_file(heap, nargs, false, true, "with-output-to-file")
+}
+
+/// `(directory-list directory)`: the names of the entries of the directory,
+/// sorted.
+fn directory_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let directory = try!(file_arg(&args(heap, nargs)[0], "directory-list"));
+    let entries = fs::read_dir(&directory).and_then(|entries| {
+        entries.map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+               .collect::<io::Result<Vec<String>>>()
+    });
+    let mut names = match entries {
+        Ok(names) => names,
+        Err(e) => return Err(file_error(heap, "directory-list", &directory, e)),
+    };
+    names.sort();
+    let base = heap.stack.len();
+    let mut result = Ok(());
+    for name in &names {
+        result = heap.alloc_string(name);
+        if result.is_err() {
+            break
+        }
+    }
+    let result = result.and_then(|()| list(heap, names.len()));
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(rename-file from to)`
+fn rename_file(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let from = try!(file_arg(&args[0], "rename-file"));
+    let to = try!(file_arg(&args[1], "rename-file"));
+    match fs::rename(&from, &to) {
+        Ok(()) => Ok(Value::new(value::UNSPECIFIED)),
+        Err(e) => Err(file_error(heap, "rename-file", &from, e)),
+    }
+}
+
+/// The metadata of the file named by the argument of the primitive `name`.
+fn metadata(heap: &mut Heap, nargs: usize, name: &str) -> Result<fs::Metadata, String> {
+    let file = try!(file_arg(&args(heap, nargs)[0], name));
+    fs::metadata(&file).map_err(|e| file_error(heap, name, &file, e))
+}
+
+/// `(file-directory? filename)`
+fn file_directory(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let file = try!(file_arg(&args(heap, nargs)[0], "file-directory?"));
+    Ok(boolean(fs::metadata(&file).map(|metadata| metadata.is_dir()).unwrap_or(false)))
+}
+
+/// `(file-size filename)`: the size of the file in bytes.
+fn file_size(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let metadata = try!(metadata(heap, nargs, "file-size"));
+    Ok(Value::new_fixnum(metadata.len() as usize))
+}
+
+/// `(file-modification-time filename)`: when the file was last modified, in
+/// seconds since the Unix epoch.
+fn file_modification_time(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let metadata = try!(metadata(heap, nargs, "file-modification-time"));
+    let seconds = metadata.modified()
+                          .ok()
+                          .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                          .map(|duration| duration.as_secs());
+    match seconds {
+        Some(seconds) => Ok(Value::new_fixnum(seconds as usize)),
+        None => Err("file-modification-time: the time is not available".to_owned()),
+    }
+}
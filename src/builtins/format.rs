@@ -0,0 +1,222 @@
+//! `format`, from SRFI 28 and SRFI 48.
+//!
+//! `(format format-string obj ...)` returns the formatted string, as in
+//! SRFI 28.  With a destination first, as in SRFI 48, `#f` also returns
+//! the string, while `#t` writes it to the current output port and a port
+//! writes it to that port.
+//!
+//! A directive is a `~`, an optional field width, an optional comma and
+//! number of digits, and a letter, in either case:
+//!
+//! - `~a`, `~s` and `~w` print the next argument as `display`, `write` and
+//!   `write-shared` do, padded on the right to the field width.
+//! - `~d`, `~x`, `~o` and `~b` print the next argument, a number, in
+//!   decimal, hexadecimal, octal and binary, padded on the left.  Only exact
+//!   integers can be printed in bases other than 10.
+//! - `~f` prints the next argument, a number, with the given number of
+//!   digits after the point, if any, padded on the left.
+//! - `~c` prints the next argument, a character.
+//! - `~y` pretty-prints the next argument, as `pretty-print` does but
+//!   without the final newline.
+//! - `~?` and `~k` format the next argument, a format string, with the one
+//!   after it, a list of arguments.
+//! - `~%` and `~n` print a newline, and `~&` prints one unless the output so
+//!   far is empty or ends with one.  `~t` prints a tab, `~_` a space, and
+//!   `~~` a tilde.
+//!
+//! It is an error for there to be arguments left over.  Nothing is printed
+//! until the whole output has been formatted, so errors print nothing.
+
+use alloc::Heap;
+use arith;
+use print::{self, Mode};
+use value::{self, Kind, Value};
+use super::{Primitive, args};
+use super::port::{OUTPUT, port, prefixed};
+use super::write::PRETTY_WIDTH;
+
+pub static PRIMITIVES: [Primitive; 1] = [Primitive {
+                                             name: "format",
+                                             min_args: 1,
+                                             max_args: None,
+                                             function: format,
+                                         }];
+
+/// The arguments that directives print, in order.
+type Args<'a> = ::std::slice::Iter<'a, Value>;
+
+/// The next argument, for the directive `directive`.
+fn next<'a>(args: &mut Args<'a>, directive: char) -> Result<&'a Value, String> {
+    args.next().ok_or_else(|| format!("missing argument for ~{}", directive))
+}
+
+/// The number at the start of `chars`, if there is one.
+fn number(chars: &mut ::std::iter::Peekable<::std::str::Chars>) -> Option<usize> {
+    let mut number = None;
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        chars.next();
+        number = Some(number.unwrap_or(0) * 10 + digit as usize)
+    }
+    number
+}
+
+/// Appends `text` to `out`, padded with spaces to `width` characters, on
+/// the left if `left` is set and on the right otherwise.
+fn pad(out: &mut String, text: &str, width: Option<usize>, left: bool) {
+    let padding = width.unwrap_or(0).saturating_sub(text.chars().count());
+    if !left {
+        out.push_str(text)
+    }
+    out.extend((0..padding).map(|_| ' '));
+    if left {
+        out.push_str(text)
+    }
+}
+
+/// `value`, a number, printed in base `radix`.
+fn number_string(value: &Value, radix: u32, directive: char) -> Result<String, String> {
+    if value.fixnump() {
+        Ok(print::integer_to_string(value.get() as isize >> 2, radix))
+    } else if radix == 10 && value.flonump() {
+        Ok(print::to_string(value, Mode::Display))
+    } else if radix == 10 {
+        Err(format!("~{}: expected a number", directive))
+    } else {
+        Err(format!("~{}: expected an exact integer", directive))
+    }
+}
+
+/// The elements of `list`, which must be a proper list.
+fn list_elements(list: &Value) -> Result<Vec<Value>, String> {
+    let mut elements = vec![];
+    let mut rest = list.clone();
+    while let Kind::Pair(pair) = rest.kind() {
+        elements.push(unsafe { (*pair).car.clone() });
+        rest = unsafe { (*pair).cdr.clone() };
+    }
+    if rest.get() == value::NIL {
+        Ok(elements)
+    } else {
+        Err("~?: expected a list of arguments".to_owned())
+    }
+}
+
+/// Formats `string` with `args` onto the end of `out`, checking that every
+/// argument is used.  `depth` counts the `~?` directives being expanded.
+fn format_to(out: &mut String,
+             string: &str,
+             args: &[Value],
+             depth: usize)
+             -> Result<(), String> {
+    let mut args = args.iter();
+    let mut chars = string.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue
+        }
+        let width = number(&mut chars);
+        let digits = if chars.peek() == Some(&',') {
+            chars.next();
+            number(&mut chars)
+        } else {
+            None
+        };
+        let directive = match chars.next() {
+            Some(directive) => directive,
+            None => return Err("incomplete directive at end of format string".to_owned()),
+        };
+        match directive {
+            'a' | 'A' | 's' | 'S' | 'w' | 'W' => {
+                let mode = match directive {
+                    'a' | 'A' => Mode::Display,
+                    's' | 'S' => Mode::Write,
+                    _ => Mode::Shared,
+                };
+                let text = print::to_string(try!(next(&mut args, directive)), mode);
+                pad(out, &text, width, false)
+            }
+            'd' | 'D' | 'x' | 'X' | 'o' | 'O' | 'b' | 'B' => {
+                let radix = match directive {
+                    'd' | 'D' => 10,
+                    'x' | 'X' => 16,
+                    'o' | 'O' => 8,
+                    _ => 2,
+                };
+                let text = try!(number_string(try!(next(&mut args, directive)), radix, directive));
+                pad(out, &text, width, true)
+            }
+            'f' | 'F' => {
+                let value = try!(next(&mut args, directive));
+                let text = match (arith::to_float(value), digits) {
+                    (Some(float), Some(digits)) => format!("{:.*}", digits, float),
+                    (Some(_), None) => print::to_string(value, Mode::Display),
+                    (None, _) => return Err(format!("~{}: expected a number", directive)),
+                };
+                pad(out, &text, width, true)
+            }
+            'y' | 'Y' => {
+                let value = try!(next(&mut args, directive));
+                out.push_str(&print::pretty(value, Mode::Write, PRETTY_WIDTH))
+            }
+            'c' | 'C' => {
+                match try!(next(&mut args, directive)).kind() {
+                    Kind::Char(c) => out.push(c),
+                    _ => return Err(format!("~{}: expected a character", directive)),
+                }
+            }
+            '?' | 'k' | 'K' => {
+                if depth == 100 {
+                    return Err(format!("~{}: nested too deeply", directive))
+                }
+                let string = try!(next(&mut args, directive));
+                let list = try!(next(&mut args, directive));
+                let string = match string.kind() {
+                    Kind::String(s) => unsafe { (*s).as_str() }.to_owned(),
+                    _ => return Err(format!("~{}: expected a format string", directive)),
+                };
+                try!(format_to(out, &string, &try!(list_elements(list)), depth + 1))
+            }
+            '%' | 'n' | 'N' => out.push('\n'),
+            '&' => {
+                if !out.is_empty() && !out.ends_with('\n') {
+                    out.push('\n')
+                }
+            }
+            't' | 'T' => out.push('\t'),
+            '_' => out.push(' '),
+            '~' => out.push('~'),
+            _ => return Err(format!("unknown directive ~{}", directive)),
+        }
+    }
+    if args.next().is_some() {
+        return Err("too many arguments".to_owned())
+    }
+    Ok(())
+}
+
+/// `(format [destination] format-string obj ...)`
+fn format(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (destination, first) = match args(heap, nargs)[0].kind() {
+        Kind::String(_) => (None, 0),
+        _ => (Some(args(heap, nargs)[0].get()), 1),
+    };
+    let string = match args(heap, nargs).get(first).map(Value::kind) {
+        Some(Kind::String(s)) => unsafe { (*s).as_str() }.to_owned(),
+        _ => return Err("format: expected a format string".to_owned()),
+    };
+    let mut out = String::new();
+    try!(format_to(&mut out, &string, &args(heap, nargs)[first + 1..], 0)
+        .map_err(|e| prefixed("format", e)));
+    let index = match destination {
+        None | Some(value::FALSE) => {
+            try!(heap.alloc_string(&out));
+            return Ok(heap.stack.pop().unwrap())
+        }
+        Some(value::TRUE) => nargs,
+        Some(_) => 0,
+    };
+    let port = try!(port(heap, nargs, index, OUTPUT, true, "format"));
+    try!(port.write_str(&out).map_err(|e| prefixed("format", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
@@ -0,0 +1,26 @@
+//! Primitives for inspecting the garbage collector.
+
+use alloc::{Heap, as_micros};
+use value::Value;
+use super::{Primitive, alist};
+
+pub static PRIMITIVES: [Primitive; 1] = [Primitive {
+                                              name: "gc-stats",
+                                              min_args: 0,
+                                              max_args: Some(0),
+                                              function: gc_stats,
+                                          }];
+
+/// `(gc-stats)`: returns an association list of heap statistics.  Times are
+/// in microseconds.
+fn gc_stats(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let stats = heap.stats();
+    alist(heap,
+          &[("bytes-allocated", stats.bytes_allocated),
+            ("collections", stats.collections),
+            ("minor-collections", stats.minor_collections),
+            ("live-bytes", stats.live_bytes),
+            ("last-pause", as_micros(stats.last_pause)),
+            ("max-pause", as_micros(stats.max_pause)),
+            ("total-pause", as_micros(stats.total_pause))])
+}
@@ -0,0 +1,1047 @@
+//! R6RS hash table procedures, and those of SRFI 125.
+//!
+//! `hashtable-keys` returns a list rather than a vector.
+//!
+//! SRFI 125 tables are the same objects as R6RS ones.  Where SRFI 125 takes
+//! a comparator, these procedures take an equality predicate: `eq?`,
+//! `eqv?`, `equal?` and `string=?` use the built-in hashes, and any other
+//! predicate must be followed by a hash function.  Every table is mutable,
+//! so `hash-table-copy` ignores its second argument.  The deprecated
+//! argument orders of `hash-table-walk`, `hash-table-for-each` and
+//! `hash-table-fold`, with the table first, are accepted too.
+//!
+//! The procedures that iterate over a table push its keys and values on
+//! the stack first, so the procedures they call may change the table.
+//! These are exported by `(srfi 125)`.
+
+use alloc::{Heap, HashKind};
+use equiv;
+use value::{self, Kind, Value};
+use super::{Primitive, args, balanced, boolean, call, fixnum_arg};
+use super::pair::list;
+
+pub static PRIMITIVES: [Primitive; 50] =
+    [Primitive {
+         name: "make-eq-hashtable",
+         min_args: 0,
+         max_args: Some(1),
+         function: make_eq_hashtable,
+     },
+     Primitive {
+         name: "make-eqv-hashtable",
+         min_args: 0,
+         max_args: Some(1),
+         function: make_eqv_hashtable,
+     },
+     Primitive {
+         name: "make-equal-hashtable",
+         min_args: 0,
+         max_args: Some(1),
+         function: make_equal_hashtable,
+     },
+     Primitive {
+         name: "make-hashtable",
+         min_args: 2,
+         max_args: Some(3),
+         function: make_hashtable,
+     },
+     Primitive {
+         name: "hashtable?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_hashtable,
+     },
+     Primitive {
+         name: "hashtable-size",
+         min_args: 1,
+         max_args: Some(1),
+         function: hashtable_size,
+     },
+     Primitive {
+         name: "hashtable-ref",
+         min_args: 3,
+         max_args: Some(3),
+         function: hashtable_ref,
+     },
+     Primitive {
+         name: "hashtable-set!",
+         min_args: 3,
+         max_args: Some(3),
+         function: hashtable_set,
+     },
+     Primitive {
+         name: "hashtable-delete!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hashtable_delete,
+     },
+     Primitive {
+         name: "hashtable-contains?",
+         min_args: 2,
+         max_args: Some(2),
+         function: hashtable_contains,
+     },
+     Primitive {
+         name: "hashtable-clear!",
+         min_args: 1,
+         max_args: Some(2),
+         function: hashtable_clear,
+     },
+     Primitive {
+         name: "hashtable-keys",
+         min_args: 1,
+         max_args: Some(1),
+         function: hashtable_keys,
+     },
+     Primitive {
+         name: "equal-hash",
+         min_args: 1,
+         max_args: Some(1),
+         function: equal_hash,
+     },
+     Primitive {
+         name: "string-hash",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_hash,
+     },
+     Primitive {
+         name: "make-hash-table",
+         min_args: 1,
+         max_args: None,
+         function: make_hash_table,
+     },
+     Primitive {
+         name: "hash-table",
+         min_args: 1,
+         max_args: None,
+         function: hash_table,
+     },
+     Primitive {
+         name: "alist->hash-table",
+         min_args: 2,
+         max_args: None,
+         function: alist_to_hash_table,
+     },
+     Primitive {
+         name: "hash-table?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_hash_table,
+     },
+     Primitive {
+         name: "hash-table-contains?",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_contains,
+     },
+     Primitive {
+         name: "hash-table-exists?",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_contains,
+     },
+     Primitive {
+         name: "hash-table-empty?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_hash_table_empty,
+     },
+     Primitive {
+         name: "hash-table-mutable?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_hash_table_mutable,
+     },
+     Primitive {
+         name: "hash-table-ref",
+         min_args: 2,
+         max_args: Some(4),
+         function: hash_table_ref,
+     },
+     Primitive {
+         name: "hash-table-ref/default",
+         min_args: 3,
+         max_args: Some(3),
+         function: hash_table_ref_default,
+     },
+     Primitive {
+         name: "hash-table-set!",
+         min_args: 1,
+         max_args: None,
+         function: hash_table_set,
+     },
+     Primitive {
+         name: "hash-table-delete!",
+         min_args: 1,
+         max_args: None,
+         function: hash_table_delete,
+     },
+     Primitive {
+         name: "hash-table-intern!",
+         min_args: 3,
+         max_args: Some(3),
+         function: hash_table_intern,
+     },
+     Primitive {
+         name: "hash-table-update!",
+         min_args: 3,
+         max_args: Some(5),
+         function: hash_table_update,
+     },
+     Primitive {
+         name: "hash-table-update!/default",
+         min_args: 4,
+         max_args: Some(4),
+         function: hash_table_update_default,
+     },
+     Primitive {
+         name: "hash-table-pop!",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_pop,
+     },
+     Primitive {
+         name: "hash-table-clear!",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_clear,
+     },
+     Primitive {
+         name: "hash-table-size",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_size,
+     },
+     Primitive {
+         name: "hash-table-keys",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_keys,
+     },
+     Primitive {
+         name: "hash-table-values",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_values,
+     },
+     Primitive {
+         name: "hash-table-entries",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_entries,
+     },
+     Primitive {
+         name: "hash-table-find",
+         min_args: 3,
+         max_args: Some(3),
+         function: hash_table_find,
+     },
+     Primitive {
+         name: "hash-table-count",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_count,
+     },
+     Primitive {
+         name: "hash-table-walk",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_walk,
+     },
+     Primitive {
+         name: "hash-table-for-each",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_walk,
+     },
+     Primitive {
+         name: "hash-table-map!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_map_in_place,
+     },
+     Primitive {
+         name: "hash-table-map->list",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_map_to_list,
+     },
+     Primitive {
+         name: "hash-table-fold",
+         min_args: 3,
+         max_args: Some(3),
+         function: hash_table_fold,
+     },
+     Primitive {
+         name: "hash-table-prune!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_prune,
+     },
+     Primitive {
+         name: "hash-table-copy",
+         min_args: 1,
+         max_args: Some(2),
+         function: hash_table_copy,
+     },
+     Primitive {
+         name: "hash-table-empty-copy",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_empty_copy,
+     },
+     Primitive {
+         name: "hash-table->alist",
+         min_args: 1,
+         max_args: Some(1),
+         function: hash_table_to_alist,
+     },
+     Primitive {
+         name: "hash-table-union!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_union,
+     },
+     Primitive {
+         name: "hash-table-intersection!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_intersection,
+     },
+     Primitive {
+         name: "hash-table-difference!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_difference,
+     },
+     Primitive {
+         name: "hash-table-xor!",
+         min_args: 2,
+         max_args: Some(2),
+         function: hash_table_xor,
+     }];
+
+/// Converts a hash to a non-negative fixnum.
+fn hash_value(hash: u64) -> Value {
+    Value::new_fixnum(hash as usize & (::std::usize::MAX >> 18))
+}
+
+/// Checks the optional initial capacity argument, which is otherwise
+/// ignored.
+fn capacity_arg(heap: &Heap, nargs: usize, procedure: &str) -> Result<(), String> {
+    match args(heap, nargs).get(0) {
+        Some(capacity) => fixnum_arg(capacity, procedure).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+fn new_hashtable(heap: &mut Heap, kind: HashKind) -> Result<Value, String> {
+    try!(heap.alloc_hash_table(kind));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(make-eq-hashtable [k])`
+fn make_eq_hashtable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    try!(capacity_arg(heap, nargs, "make-eq-hashtable"));
+    new_hashtable(heap, HashKind::Eq)
+}
+
+/// `(make-eqv-hashtable [k])`
+fn make_eqv_hashtable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    try!(capacity_arg(heap, nargs, "make-eqv-hashtable"));
+    new_hashtable(heap, HashKind::Eqv)
+}
+
+/// `(make-equal-hashtable [k])`
+fn make_equal_hashtable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    try!(capacity_arg(heap, nargs, "make-equal-hashtable"));
+    new_hashtable(heap, HashKind::Equal)
+}
+
+/// `(make-hashtable hash-function equiv [k])`
+fn make_hashtable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    if let Some(capacity) = args(heap, nargs).get(2) {
+        try!(fixnum_arg(capacity, "make-hashtable"));
+    }
+    try!(heap.alloc_custom_hash_table(first, first + 1));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(hashtable? obj)`
+fn is_hashtable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let table = args(heap, nargs)[0].clone();
+    Ok(boolean(heap.hash_table_len(&table).is_ok()))
+}
+
+/// `(hashtable-size hashtable)`
+fn hashtable_size(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let table = args(heap, nargs)[0].clone();
+    Ok(Value::new_fixnum(try!(heap.hash_table_len(&table))))
+}
+
+/// `(hashtable-ref hashtable key default)`
+fn hashtable_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    match try!(heap.hash_table_get(first, first + 1)) {
+        Some(value) => Ok(value),
+        None => Ok(heap.stack[first + 2].clone()),
+    }
+}
+
+/// `(hashtable-set! hashtable key obj)`
+fn hashtable_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(heap.hash_table_set(first, first + 1, first + 2));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hashtable-delete! hashtable key)`
+fn hashtable_delete(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(heap.hash_table_remove(first, first + 1));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hashtable-contains? hashtable key)`
+fn hashtable_contains(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    Ok(boolean(try!(heap.hash_table_get(first, first + 1)).is_some()))
+}
+
+/// `(hashtable-clear! hashtable [k])`
+fn hashtable_clear(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    if let Some(capacity) = args.get(1) {
+        try!(fixnum_arg(capacity, "hashtable-clear!"));
+    }
+    try!(heap.hash_table_clear(&args[0]));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hashtable-keys hashtable)`
+fn hashtable_keys(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let table = args(heap, nargs)[0].clone();
+    let entries = try!(heap.hash_table_entries(&table));
+    // The keys are rooted by the table, but may move while the list is
+    // consed up, so they are pushed first.
+    let base = heap.stack.len();
+    heap.stack.extend(entries.into_iter().map(|(key, _)| key));
+    let end = heap.stack.len();
+    heap.stack.push(Value::new(value::NIL));
+    for i in (base..end).rev() {
+        if let Err(e) = heap.alloc_pair(i, end) {
+            heap.stack.truncate(base);
+            return Err(e)
+        }
+        heap.stack[end] = heap.stack.pop().unwrap();
+    }
+    let keys = heap.stack.pop().unwrap();
+    heap.stack.truncate(base);
+    Ok(keys)
+}
+
+/// `(equal-hash obj)`, which does not depend on addresses in deterministic
+/// mode.
+fn equal_hash(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let obj = args(heap, nargs)[0].clone();
+    if heap.control.is_deterministic() {
+        return Ok(hash_value(equiv::content_hash(&obj)))
+    }
+    Ok(hash_value(equiv::equal_hash(&obj).0))
+}
+
+/// `(string-hash string)`
+fn string_hash(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let string = args(heap, nargs)[0].clone();
+    try!(heap.string_len(&string).map_err(|_| "string-hash: expected a string".to_owned()));
+    Ok(hash_value(equiv::equal_hash(&string).0))
+}
+
+/// Checks that the value at stack index `table` is a hash table, for the
+/// primitive `name`, and returns its number of entries.
+fn table_arg(heap: &mut Heap, table: usize, name: &str) -> Result<usize, String> {
+    let table = heap.stack[table].clone();
+    heap.hash_table_len(&table).map_err(|_| format!("{}: expected a hash table", name))
+}
+
+/// Whether `value` is a procedure.
+fn is_procedure(value: &Value) -> bool {
+    match value.kind() {
+        Kind::Primitive(_) | Kind::Closure(_) => true,
+        _ => false,
+    }
+}
+
+/// Calls `procedure` with `args`, and returns its result.
+fn call_procedure(heap: &mut Heap, procedure: Value, args: &[Value]) -> Result<Value, String> {
+    let top = heap.stack.len();
+    heap.stack.push(procedure);
+    heap.stack.extend_from_slice(args);
+    match call(heap, args.len()) {
+        Ok(()) => Ok(heap.stack.pop().unwrap()),
+        Err(e) => {
+            heap.stack.truncate(top);
+            Err(e)
+        }
+    }
+}
+
+/// Calls the procedure at stack index `procedure` with the values at the
+/// stack indices `args`, and returns its result.
+fn call_at(heap: &mut Heap, procedure: usize, args: &[usize]) -> Result<Value, String> {
+    let procedure = heap.stack[procedure].clone();
+    let args: Vec<Value> = args.iter().map(|&i| heap.stack[i].clone()).collect();
+    call_procedure(heap, procedure, &args)
+}
+
+/// Pushes the keys of the hash table at stack index `table`, an argument
+/// of the primitive `name`, and then its values.  Returns the stack index
+/// of the first key and the number of entries.
+fn push_entries(heap: &mut Heap, table: usize, name: &str) -> Result<(usize, usize), String> {
+    try!(table_arg(heap, table, name));
+    let table = heap.stack[table].clone();
+    let entries = try!(heap.hash_table_entries(&table));
+    let base = heap.stack.len();
+    heap.stack.extend(entries.iter().map(|&(ref key, _)| key.clone()));
+    heap.stack.extend(entries.into_iter().map(|(_, value)| value));
+    Ok((base, (heap.stack.len() - base) / 2))
+}
+
+/// The kind of table whose keys are compared by `equality`, if it is one
+/// of the primitives with a built-in hash.
+fn builtin_kind(equality: &Value) -> Option<HashKind> {
+    match equality.kind() {
+        Kind::Primitive(primitive) => {
+            match unsafe { (*primitive).name } {
+                "eq?" => Some(HashKind::Eq),
+                "eqv?" => Some(HashKind::Eqv),
+                "equal?" | "string=?" => Some(HashKind::Equal),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Pushes a new hash table whose keys are compared by the procedure at
+/// stack index `equality`, for the primitive `name`.  If `hashed` is true,
+/// the procedure after it is the hash function.
+fn new_table(heap: &mut Heap, equality: usize, hashed: bool, name: &str) -> Result<(), String> {
+    let kind = builtin_kind(&heap.stack[equality]);
+    match kind {
+        _ if hashed => heap.alloc_custom_hash_table(equality + 1, equality),
+        Some(kind) => heap.alloc_hash_table(kind),
+        None => Err(format!("{}: expected a hash function for a custom equality predicate", name)),
+    }
+}
+
+/// `(make-hash-table equality [hash] arg ...)`
+fn make_hash_table(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let hashed = nargs > 1 && is_procedure(&heap.stack[first + 1]);
+    try!(new_table(heap, first, hashed, "make-hash-table"));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(hash-table equality [key value] ...)`
+fn hash_table(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    if nargs % 2 == 0 {
+        return Err("hash-table: expected keys and values in pairs".to_owned())
+    }
+    let first = heap.stack.len() - nargs;
+    try!(new_table(heap, first, false, "hash-table"));
+    let table = heap.stack.len() - 1;
+    for key in (first + 1..table).filter(|key| (key - first) % 2 == 1) {
+        if let Err(e) = heap.hash_table_set(table, key, key + 1) {
+            heap.stack.pop();
+            return Err(e)
+        }
+    }
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(alist->hash-table alist equality [hash] arg ...)`: when a key occurs
+/// more than once, the first association wins.
+fn alist_to_hash_table(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let mut entries = vec![];
+    let mut rest = heap.stack[first].clone();
+    while let Ok(entry) = rest.car() {
+        let key = try!(entry.car().map_err(|()| "alist->hash-table: expected an alist".to_owned()));
+        entries.push((key, entry.cdr().unwrap()));
+        rest = rest.cdr().unwrap()
+    }
+    if rest.get() != value::NIL {
+        return Err("alist->hash-table: expected an alist".to_owned())
+    }
+    let hashed = nargs > 2 && is_procedure(&heap.stack[first + 2]);
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        for (key, value) in entries {
+            heap.stack.push(key);
+            heap.stack.push(value)
+        }
+        let end = heap.stack.len();
+        try!(new_table(heap, first + 1, hashed, "alist->hash-table"));
+        for key in (base..end).rev().filter(|key| (key - base) % 2 == 0) {
+            try!(heap.hash_table_set(end, key, key + 1))
+        }
+        Ok(heap.stack[end].clone())
+    })
+}
+
+/// `(hash-table? obj)`
+fn is_hash_table(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    Ok(boolean(table_arg(heap, first, "hash-table?").is_ok()))
+}
+
+/// `(hash-table-contains? hash-table key)` and
+/// `(hash-table-exists? hash-table key)`
+fn hash_table_contains(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-contains?"));
+    Ok(boolean(try!(heap.hash_table_get(first, first + 1)).is_some()))
+}
+
+/// `(hash-table-empty? hash-table)`
+fn is_hash_table_empty(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    Ok(boolean(try!(table_arg(heap, first, "hash-table-empty?")) == 0))
+}
+
+/// `(hash-table-mutable? hash-table)`
+fn is_hash_table_mutable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-mutable?"));
+    Ok(boolean(true))
+}
+
+/// The value of the key at stack index `key` in the table at `table`, an
+/// argument of the primitive `name`, passed to the procedure at `success`
+/// if there is one.  If the key is missing, the result of calling the
+/// procedure at `failure`, or an error if there is none.
+fn reference(heap: &mut Heap,
+             table: usize,
+             key: usize,
+             failure: Option<usize>,
+             success: Option<usize>,
+             name: &str)
+             -> Result<Value, String> {
+    try!(table_arg(heap, table, name));
+    match (try!(heap.hash_table_get(table, key)), success, failure) {
+        (Some(value), Some(success), _) => {
+            let success = heap.stack[success].clone();
+            call_procedure(heap, success, &[value])
+        }
+        (Some(value), None, _) => Ok(value),
+        (None, _, Some(failure)) => call_at(heap, failure, &[]),
+        (None, _, None) => Err(format!("{}: key not found", name)),
+    }
+}
+
+/// The stack index of argument `index` of the `nargs` arguments starting at
+/// `first`, if there is one.
+fn optional(first: usize, nargs: usize, index: usize) -> Option<usize> {
+    if index < nargs { Some(first + index) } else { None }
+}
+
+/// `(hash-table-ref hash-table key [failure [success]])`
+fn hash_table_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    reference(heap,
+              first,
+              first + 1,
+              optional(first, nargs, 2),
+              optional(first, nargs, 3),
+              "hash-table-ref")
+}
+
+/// `(hash-table-ref/default hash-table key default)`
+fn hash_table_ref_default(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-ref/default"));
+    match try!(heap.hash_table_get(first, first + 1)) {
+        Some(value) => Ok(value),
+        None => Ok(heap.stack[first + 2].clone()),
+    }
+}
+
+/// `(hash-table-set! hash-table [key value] ...)`
+fn hash_table_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-set!"));
+    if nargs % 2 == 0 {
+        return Err("hash-table-set!: expected keys and values in pairs".to_owned())
+    }
+    for key in (first + 1..first + nargs).filter(|key| (key - first) % 2 == 1) {
+        try!(heap.hash_table_set(first, key, key + 1))
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hash-table-delete! hash-table key ...)`: the number of keys that were
+/// present.
+fn hash_table_delete(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-delete!"));
+    let mut count = 0;
+    for key in first + 1..first + nargs {
+        if try!(heap.hash_table_remove(first, key)) {
+            count += 1
+        }
+    }
+    Ok(Value::new_fixnum(count))
+}
+
+/// Associates the key at stack index `key` with `value` in the table at
+/// `table`.
+fn set_value(heap: &mut Heap, table: usize, key: usize, value: Value) -> Result<(), String> {
+    heap.stack.push(value);
+    let value = heap.stack.len() - 1;
+    let result = heap.hash_table_set(table, key, value);
+    heap.stack.pop();
+    result
+}
+
+/// `(hash-table-intern! hash-table key failure)`: the value of `key`,
+/// which is set to the result of `(failure)` first if it is missing.
+fn hash_table_intern(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-intern!"));
+    if let Some(value) = try!(heap.hash_table_get(first, first + 1)) {
+        return Ok(value)
+    }
+    let value = try!(call_at(heap, first + 2, &[]));
+    heap.stack.push(value);
+    let result = heap.hash_table_set(first, first + 1, first + nargs);
+    let value = heap.stack.pop().unwrap();
+    result.map(|()| value)
+}
+
+/// Sets the key at `first + 1` in the table at `first` to the result of
+/// calling the procedure at `first + 2` on `current`.
+fn update(heap: &mut Heap, first: usize, current: Value) -> Result<Value, String> {
+    let updater = heap.stack[first + 2].clone();
+    let new = try!(call_procedure(heap, updater, &[current]));
+    try!(set_value(heap, first, first + 1, new));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hash-table-update! hash-table key updater [failure [success]])`
+fn hash_table_update(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let current = try!(reference(heap,
+                                 first,
+                                 first + 1,
+                                 optional(first, nargs, 3),
+                                 optional(first, nargs, 4),
+                                 "hash-table-update!"));
+    update(heap, first, current)
+}
+
+/// `(hash-table-update!/default hash-table key updater default)`
+fn hash_table_update_default(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-update!/default"));
+    let current = match try!(heap.hash_table_get(first, first + 1)) {
+        Some(value) => value,
+        None => heap.stack[first + 3].clone(),
+    };
+    update(heap, first, current)
+}
+
+/// `(hash-table-pop! hash-table)`: removes an arbitrary entry, and returns
+/// its key and value as two values.
+fn hash_table_pop(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first, "hash-table-pop!"));
+        if len == 0 {
+            return Err("hash-table-pop!: the table is empty".to_owned())
+        }
+        try!(heap.hash_table_remove(first, base));
+        let (key, value) = (heap.stack[base].clone(), heap.stack[base + len].clone());
+        heap.stack.push(key);
+        heap.stack.push(value);
+        let end = heap.stack.len();
+        try!(heap.alloc_values(end - 2, end));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// `(hash-table-clear! hash-table)`
+fn hash_table_clear(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-clear!"));
+    let table = heap.stack[first].clone();
+    try!(heap.hash_table_clear(&table));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(hash-table-size hash-table)`
+fn hash_table_size(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    table_arg(heap, first, "hash-table-size").map(Value::new_fixnum)
+}
+
+/// `(hash-table-keys hash-table)`
+fn hash_table_keys(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first, "hash-table-keys"));
+        heap.stack.truncate(base + len);
+        list(heap, len)
+    })
+}
+
+/// `(hash-table-values hash-table)`
+fn hash_table_values(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (_, len) = try!(push_entries(heap, first, "hash-table-values"));
+        list(heap, len)
+    })
+}
+
+/// `(hash-table-entries hash-table)`: a list of the keys and a list of the
+/// values, in the same order, as two values.
+fn hash_table_entries(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first, "hash-table-entries"));
+        let values = try!(list(heap, len));
+        heap.stack.truncate(base + len);
+        // The list of values goes below the keys, which are consed next.
+        heap.stack.insert(base, values);
+        let keys = try!(list(heap, len));
+        heap.stack.push(keys);
+        let values = heap.stack[base].clone();
+        heap.stack.push(values);
+        let end = heap.stack.len();
+        try!(heap.alloc_values(end - 2, end));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// `(hash-table-find proc hash-table failure)`: the first true result of
+/// `(proc key value)`, or the result of `(failure)`.
+fn hash_table_find(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + 1, "hash-table-find"));
+        for i in base..base + len {
+            let result = try!(call_at(heap, first, &[i, i + len]));
+            if result.get() != value::FALSE {
+                return Ok(result)
+            }
+        }
+        call_at(heap, first + 2, &[])
+    })
+}
+
+/// `(hash-table-count pred hash-table)`
+fn hash_table_count(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + 1, "hash-table-count"));
+        let mut count = 0;
+        for i in base..base + len {
+            if try!(call_at(heap, first, &[i, i + len])).get() != value::FALSE {
+                count += 1
+            }
+        }
+        Ok(Value::new_fixnum(count))
+    })
+}
+
+/// The stack indices of the table and of the procedure among the two
+/// arguments from `first` on, which may come in either order.
+fn table_and_procedure(heap: &mut Heap, first: usize) -> (usize, usize) {
+    let table = heap.stack[first].clone();
+    if heap.hash_table_len(&table).is_ok() {
+        (first, first + 1)
+    } else {
+        (first + 1, first)
+    }
+}
+
+/// `(hash-table-walk hash-table proc)` and
+/// `(hash-table-for-each proc hash-table)`: calls `(proc key value)` for
+/// each entry.
+fn hash_table_walk(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let (table, procedure) = table_and_procedure(heap, first);
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, table, "hash-table-walk"));
+        for i in base..base + len {
+            try!(call_at(heap, procedure, &[i, i + len]));
+        }
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// `(hash-table-map! proc hash-table)`: sets the value of each key to
+/// `(proc key value)`.
+fn hash_table_map_in_place(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + 1, "hash-table-map!"));
+        for i in base..base + len {
+            let value = try!(call_at(heap, first, &[i, i + len]));
+            try!(set_value(heap, first + 1, i, value))
+        }
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// `(hash-table-map->list proc hash-table)`: the results of
+/// `(proc key value)`.
+fn hash_table_map_to_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + 1, "hash-table-map->list"));
+        for i in base..base + len {
+            let result = try!(call_at(heap, first, &[i, i + len]));
+            heap.stack.push(result)
+        }
+        list(heap, len)
+    })
+}
+
+/// `(hash-table-fold proc seed hash-table)`, or
+/// `(hash-table-fold hash-table proc seed)`: calls
+/// `(proc key value accumulator)` for each entry, starting with `seed`.
+fn hash_table_fold(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let table = heap.stack[first].clone();
+    let (table, procedure, seed) = if heap.hash_table_len(&table).is_ok() {
+        (first, first + 1, first + 2)
+    } else {
+        (first + 2, first, first + 1)
+    };
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, table, "hash-table-fold"));
+        let accumulator = heap.stack[seed].clone();
+        heap.stack.push(accumulator);
+        let accumulator = heap.stack.len() - 1;
+        for i in base..base + len {
+            let result = try!(call_at(heap, procedure, &[i, i + len, accumulator]));
+            heap.stack[accumulator] = result
+        }
+        Ok(heap.stack[accumulator].clone())
+    })
+}
+
+/// `(hash-table-prune! proc hash-table)`: removes the entries for which
+/// `(proc key value)` is true.
+fn hash_table_prune(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + 1, "hash-table-prune!"));
+        for i in base..base + len {
+            if try!(call_at(heap, first, &[i, i + len])).get() != value::FALSE {
+                try!(heap.hash_table_remove(first + 1, i));
+            }
+        }
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// `(hash-table-copy hash-table [mutable?])`
+fn hash_table_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, "hash-table-copy"));
+    try!(heap.hash_table_copy(first));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(hash-table-empty-copy hash-table)`
+fn hash_table_empty_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let copy = try!(hash_table_copy(heap, nargs));
+    try!(heap.hash_table_clear(&copy));
+    Ok(copy)
+}
+
+/// `(hash-table->alist hash-table)`
+fn hash_table_to_alist(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first, "hash-table->alist"));
+        for i in base..base + len {
+            try!(heap.alloc_pair(i, i + len))
+        }
+        list(heap, len)
+    })
+}
+
+/// Calls `each` with the table from the first of the two arguments of the
+/// primitive `name`, the table from the second, and the stack indices of
+/// the key and value of each entry of `entries_of`, one of the two.
+/// Returns the first table.
+fn combine<F>(heap: &mut Heap,
+              nargs: usize,
+              name: &str,
+              entries_of: usize,
+              mut each: F)
+              -> Result<Value, String>
+    where F: FnMut(&mut Heap, usize, usize, usize, usize) -> Result<(), String>
+{
+    let first = heap.stack.len() - nargs;
+    try!(table_arg(heap, first, name));
+    try!(table_arg(heap, first + 1, name));
+    balanced(heap, |heap| {
+        let (base, len) = try!(push_entries(heap, first + entries_of, name));
+        for i in base..base + len {
+            try!(each(heap, first, first + 1, i, i + len))
+        }
+        Ok(heap.stack[first].clone())
+    })
+}
+
+/// `(hash-table-union! hash-table1 hash-table2)`: adds the entries of
+/// `hash-table2` whose keys are not in `hash-table1`.
+fn hash_table_union(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    combine(heap, nargs, "hash-table-union!", 1, |heap, table1, _, key, value| {
+        if try!(heap.hash_table_get(table1, key)).is_none() {
+            try!(heap.hash_table_set(table1, key, value))
+        }
+        Ok(())
+    })
+}
+
+/// `(hash-table-intersection! hash-table1 hash-table2)`: removes the
+/// entries whose keys are not in `hash-table2`.
+fn hash_table_intersection(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    combine(heap, nargs, "hash-table-intersection!", 0, |heap, table1, table2, key, _| {
+        if try!(heap.hash_table_get(table2, key)).is_none() {
+            try!(heap.hash_table_remove(table1, key));
+        }
+        Ok(())
+    })
+}
+
+/// `(hash-table-difference! hash-table1 hash-table2)`: removes the entries
+/// whose keys are in `hash-table2`.
+fn hash_table_difference(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    combine(heap, nargs, "hash-table-difference!", 0, |heap, table1, table2, key, _| {
+        if try!(heap.hash_table_get(table2, key)).is_some() {
+            try!(heap.hash_table_remove(table1, key));
+        }
+        Ok(())
+    })
+}
+
+/// `(hash-table-xor! hash-table1 hash-table2)`: removes the entries whose
+/// keys are in `hash-table2`, and adds those of `hash-table2` whose keys
+/// were not in `hash-table1`.
+fn hash_table_xor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    combine(heap, nargs, "hash-table-xor!", 1, |heap, table1, _, key, value| {
+        if !try!(heap.hash_table_remove(table1, key)) {
+            try!(heap.hash_table_set(table1, key, value))
+        }
+        Ok(())
+    })
+}
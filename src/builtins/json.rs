@@ -0,0 +1,520 @@
+//! Reading and writing JSON, through textual ports (see `port`).
+//!
+//! JSON values map to Scheme values as follows: objects are alists whose
+//! keys are symbols (or, if asked for, `eq?` hash tables with the same
+//! keys), arrays are vectors, strings are strings, numbers are fixnums if
+//! they are integers that fit and flonums otherwise, `true` and `false` are
+//! `#t` and `#f`, and `null` is the symbol `null`.  `json-write` takes the
+//! same values back, except that keys may also be strings, and `()` is the
+//! empty object.
+//!
+//! Input is parsed into a `Json` tree before anything is allocated, so that
+//! a syntax error leaves nothing behind on the heap.  Output is written to
+//! a string first, so that a value that cannot be written writes nothing.
+//! These are exported by `(rusty-scheme json)`.
+
+use alloc::{Heap, HashKind};
+use port::Port;
+use value::{self, Kind, Value};
+use super::{Primitive, args};
+use super::pair::list;
+use super::port::{INPUT, OUTPUT, port, prefixed};
+
+pub static PRIMITIVES: [Primitive; 2] =
+    [Primitive {
+         name: "json-read",
+         min_args: 0,
+         max_args: Some(2),
+         function: json_read,
+     },
+     Primitive {
+         name: "json-write",
+         min_args: 1,
+         max_args: Some(2),
+         function: json_write,
+     }];
+
+/// How deeply arrays and objects may nest, in either direction.  This keeps
+/// hostile input, and circular structure, from overflowing the Rust stack.
+const MAX_DEPTH: usize = 512;
+
+/// A parsed JSON value.
+#[derive(Debug)]
+enum Json {
+    Null,
+    Boolean(bool),
+    Integer(isize),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Parses JSON from a port, one character at a time.
+struct Parser<'a> {
+    port: &'a mut Port,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&mut self) -> Result<Option<char>, String> {
+        self.port.peek_char()
+    }
+
+    fn next(&mut self) -> Result<char, String> {
+        match try!(self.port.read_char()) {
+            Some(c) => Ok(c),
+            None => Err("unexpected end of input".to_owned()),
+        }
+    }
+
+    /// Skips whitespace, and returns the character after it, if any, which
+    /// is left to be read.
+    fn skip_whitespace(&mut self) -> Result<Option<char>, String> {
+        loop {
+            match try!(self.peek()) {
+                Some(' ') | Some('\t') | Some('\n') | Some('\r') => {
+                    try!(self.next());
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match try!(self.next()) {
+            c if c == expected => Ok(()),
+            c => Err(format!("expected {:?}, found {:?}", expected, c)),
+        }
+    }
+
+    /// The next value, which starts with `c`.
+    fn value(&mut self, c: char) -> Result<Json, String> {
+        match c {
+            '{' => self.nested(Parser::object),
+            '[' => self.nested(Parser::array),
+            '"' => Ok(Json::String(try!(self.string()))),
+            't' => self.literal("true", Json::Boolean(true)),
+            'f' => self.literal("false", Json::Boolean(false)),
+            'n' => self.literal("null", Json::Null),
+            '-' | '0'...'9' => self.number(),
+            c => Err(format!("unexpected {:?}", c)),
+        }
+    }
+
+    /// Skips whitespace, then reads a value.
+    fn next_value(&mut self) -> Result<Json, String> {
+        match try!(self.skip_whitespace()) {
+            Some(c) => self.value(c),
+            None => Err("unexpected end of input".to_owned()),
+        }
+    }
+
+    fn nested<F>(&mut self, body: F) -> Result<Json, String>
+        where F: FnOnce(&mut Self) -> Result<Json, String>
+    {
+        if self.depth == MAX_DEPTH {
+            return Err("nested too deeply".to_owned())
+        }
+        self.depth += 1;
+        let result = body(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn literal(&mut self, word: &str, json: Json) -> Result<Json, String> {
+        for expected in word.chars() {
+            try!(self.expect(expected))
+        }
+        Ok(json)
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        try!(self.expect('{'));
+        let mut members = vec![];
+        if try!(self.skip_whitespace()) == Some('}') {
+            try!(self.next());
+            return Ok(Json::Object(members))
+        }
+        loop {
+            match try!(self.skip_whitespace()) {
+                Some('"') => {}
+                _ => return Err("expected a string as key".to_owned()),
+            }
+            let key = try!(self.string());
+            try!(self.skip_whitespace());
+            try!(self.expect(':'));
+            members.push((key, try!(self.next_value())));
+            try!(self.skip_whitespace());
+            match try!(self.next()) {
+                ',' => {}
+                '}' => return Ok(Json::Object(members)),
+                c => return Err(format!("expected ',' or '}}', found {:?}", c)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        try!(self.expect('['));
+        let mut elements = vec![];
+        if try!(self.skip_whitespace()) == Some(']') {
+            try!(self.next());
+            return Ok(Json::Array(elements))
+        }
+        loop {
+            elements.push(try!(self.next_value()));
+            try!(self.skip_whitespace());
+            match try!(self.next()) {
+                ',' => {}
+                ']' => return Ok(Json::Array(elements)),
+                c => return Err(format!("expected ',' or ']', found {:?}", c)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        try!(self.expect('"'));
+        let mut string = String::new();
+        loop {
+            match try!(self.next()) {
+                '"' => return Ok(string),
+                '\\' => string.push(try!(self.escape())),
+                c if (c as u32) < 0x20 => {
+                    return Err(format!("unescaped control character {:?} in string", c))
+                }
+                c => string.push(c),
+            }
+        }
+    }
+
+    /// The character written by the escape after a backslash.
+    fn escape(&mut self) -> Result<char, String> {
+        Ok(match try!(self.next()) {
+            '"' => '"',
+            '\\' => '\\',
+            '/' => '/',
+            'b' => '\x08',
+            'f' => '\x0c',
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'u' => {
+                let first = try!(self.hex4());
+                let code = if first >= 0xd800 && first < 0xdc00 {
+                    try!(self.expect('\\'));
+                    try!(self.expect('u'));
+                    let second = try!(self.hex4());
+                    if second < 0xdc00 || second >= 0xe000 {
+                        return Err("unpaired surrogate in string".to_owned())
+                    }
+                    0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+                } else {
+                    first
+                };
+                match ::std::char::from_u32(code) {
+                    Some(c) => c,
+                    None => return Err("unpaired surrogate in string".to_owned()),
+                }
+            }
+            c => return Err(format!("invalid escape \\{} in string", c)),
+        })
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let c = try!(self.next());
+            match c.to_digit(16) {
+                Some(digit) => code = code * 16 + digit,
+                None => return Err(format!("invalid hex digit {:?} in \\u escape", c)),
+            }
+        }
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some(c) = try!(self.peek()) {
+            match c {
+                '-' | '+' | '.' | 'e' | 'E' | '0'...'9' => {
+                    text.push(try!(self.next()));
+                }
+                _ => break,
+            }
+        }
+        if !valid_number(&text) {
+            return Err(format!("invalid number {}", text))
+        }
+        if !text.contains(|c| c == '.' || c == 'e' || c == 'E') {
+            if let Ok(integer) = text.parse::<isize>() {
+                if integer.checked_mul(4).and_then(Value::from_fixnum_word).is_some() {
+                    return Ok(Json::Integer(integer))
+                }
+            }
+        }
+        text.parse().map(Json::Float).map_err(|_| format!("invalid number {}", text))
+    }
+}
+
+/// Whether `text` follows the JSON grammar for numbers, which is stricter
+/// than Rust's: no leading zeros, no `+` in front, and digits on both sides
+/// of the point.
+fn valid_number(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while *i < bytes.len() && b'0' <= bytes[*i] && bytes[*i] <= b'9' {
+            *i += 1
+        }
+        *i - start
+    };
+    if bytes.get(i) == Some(&b'-') {
+        i += 1
+    }
+    let start = i;
+    match digits(&mut i) {
+        0 => return false,
+        1 => {}
+        _ if bytes[start] == b'0' => return false,
+        _ => {}
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        if digits(&mut i) == 0 {
+            return false
+        }
+    }
+    if bytes.get(i) == Some(&b'e') || bytes.get(i) == Some(&b'E') {
+        i += 1;
+        if bytes.get(i) == Some(&b'+') || bytes.get(i) == Some(&b'-') {
+            i += 1
+        }
+        if digits(&mut i) == 0 {
+            return false
+        }
+    }
+    i == bytes.len()
+}
+
+/// Pushes the Scheme value for `json`, with objects as hash tables if
+/// `tables` is set.
+fn push(heap: &mut Heap, json: &Json, tables: bool) -> Result<(), String> {
+    match *json {
+        Json::Null => heap.intern("null"),
+        Json::Boolean(b) => heap.stack.push(Value::new(if b { value::TRUE } else { value::FALSE })),
+        Json::Integer(i) => heap.stack.push(Value::new((i << 2) as usize)),
+        Json::Float(f) => try!(heap.alloc_float(f)),
+        Json::String(ref s) => try!(heap.alloc_string(s)),
+        Json::Array(ref elements) => {
+            let start = heap.stack.len();
+            for element in elements {
+                try!(push(heap, element, tables))
+            }
+            try!(heap.alloc_vector(start, start + elements.len()));
+            replace(heap, start)
+        }
+        Json::Object(ref members) if tables => {
+            try!(heap.alloc_hash_table(HashKind::Eq));
+            let table = heap.stack.len() - 1;
+            for &(ref key, ref member) in members {
+                heap.intern(key);
+                try!(push(heap, member, tables));
+                try!(heap.hash_table_set(table, table + 1, table + 2));
+                heap.stack.truncate(table + 1)
+            }
+        }
+        Json::Object(ref members) => {
+            let start = heap.stack.len();
+            for &(ref key, ref member) in members {
+                heap.intern(key);
+                try!(push(heap, member, tables));
+                let pair = heap.stack.len() - 2;
+                try!(heap.alloc_pair(pair, pair + 1));
+                replace(heap, pair)
+            }
+            let result = try!(list(heap, members.len()));
+            heap.stack.truncate(start);
+            heap.stack.push(result)
+        }
+    }
+    Ok(())
+}
+
+/// Replaces everything on the stack from `start` on with the value on top.
+fn replace(heap: &mut Heap, start: usize) {
+    let result = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    heap.stack.push(result)
+}
+
+/// `(json-read [port [object-type]])`: the next JSON value, or the EOF
+/// object if there is only whitespace left.  Objects are alists, unless
+/// `object-type` is the symbol `hash-table`.
+fn json_read(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let tables = match args(heap, nargs).get(1).map(Value::kind) {
+        None => false,
+        Some(Kind::Symbol(symbol)) => {
+            match unsafe { (*symbol).name() }.as_str() {
+                "alist" => false,
+                "hash-table" => true,
+                _ => return Err("json-read: expected alist or hash-table".to_owned()),
+            }
+        }
+        Some(_) => return Err("json-read: expected alist or hash-table".to_owned()),
+    };
+    let json = {
+        let port = try!(port(heap, nargs, 0, INPUT, true, "json-read"));
+        let mut parser = Parser {
+            port: port,
+            depth: 0,
+        };
+        match try!(parser.skip_whitespace().map_err(|e| prefixed("json-read", e))) {
+            None => return Ok(Value::new(value::EOF)),
+            Some(c) => try!(parser.value(c).map_err(|e| prefixed("json-read", e))),
+        }
+    };
+    let start = heap.stack.len();
+    let result = push(heap, &json, tables).map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    result
+}
+
+/// Writes `value` to `out` as JSON.
+fn write_value(heap: &mut Heap,
+               value: &Value,
+               out: &mut String,
+               depth: usize)
+               -> Result<(), String> {
+    if depth == MAX_DEPTH {
+        return Err("nested too deeply".to_owned())
+    }
+    match value.kind() {
+        Kind::Constant(value::TRUE) => out.push_str("true"),
+        Kind::Constant(value::FALSE) => out.push_str("false"),
+        Kind::Constant(value::NIL) => out.push_str("{}"),
+        Kind::Symbol(symbol) if unsafe { (*symbol).name() }.as_str() == "null" => {
+            out.push_str("null")
+        }
+        Kind::Fixnum(_) => out.push_str(&format!("{}", value.get() as isize >> 2)),
+        Kind::Float(f) => {
+            if !f.is_finite() {
+                return Err(format!("cannot write {} as JSON", f))
+            } else if f.fract() == 0.0 && f.abs() < 1e16 {
+                out.push_str(&format!("{:.1}", f))
+            } else {
+                out.push_str(&format!("{}", f))
+            }
+        }
+        Kind::String(s) => write_string(unsafe { (*s).as_str() }, out),
+        Kind::Vector(vector) => {
+            let len = unsafe { (*vector).len() };
+            out.push('[');
+            for i in 0..len {
+                if i != 0 {
+                    out.push(',')
+                }
+                let element = unsafe { (*vector).element(i) }.clone();
+                try!(write_value(heap, &element, out, depth + 1))
+            }
+            out.push(']')
+        }
+        Kind::Pair(_) => {
+            let mut members = vec![];
+            let mut rest = value.clone();
+            while let Kind::Pair(pair) = rest.kind() {
+                if members.len() == MAX_DEPTH * MAX_DEPTH {
+                    return Err("alist too long, or circular".to_owned())
+                }
+                let member = unsafe { (*pair).car.clone() };
+                match member.kind() {
+                    Kind::Pair(member) => unsafe {
+                        members.push(((*member).car.clone(), (*member).cdr.clone()))
+                    },
+                    _ => return Err("expected an alist".to_owned()),
+                }
+                rest = unsafe { (*pair).cdr.clone() };
+            }
+            if rest.get() != value::NIL {
+                return Err("expected an alist".to_owned())
+            }
+            try!(write_object(heap, members, out, depth))
+        }
+        Kind::HashTable(_) => {
+            let entries = try!(heap.hash_table_entries(value));
+            try!(write_object(heap, entries, out, depth))
+        }
+        _ => {
+            return Err(format!("cannot write {} as JSON",
+                               ::print::to_string(value, ::print::Mode::Write)))
+        }
+    }
+    Ok(())
+}
+
+/// Writes the members of an object to `out`.
+fn write_object(heap: &mut Heap,
+                members: Vec<(Value, Value)>,
+                out: &mut String,
+                depth: usize)
+                -> Result<(), String> {
+    out.push('{');
+    for (i, (key, member)) in members.into_iter().enumerate() {
+        if i != 0 {
+            out.push(',')
+        }
+        match key.kind() {
+            Kind::Symbol(symbol) => write_string(&unsafe { (*symbol).name() }, out),
+            Kind::String(s) => write_string(unsafe { (*s).as_str() }, out),
+            _ => return Err("object keys must be symbols or strings".to_owned()),
+        }
+        out.push(':');
+        try!(write_value(heap, &member, out, depth + 1))
+    }
+    out.push('}');
+    Ok(())
+}
+
+/// Writes `s` to `out` as a JSON string.
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\x08' => out.push_str("\\b"),
+            '\x0c' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"')
+}
+
+/// `(json-write obj [port])`
+fn json_write(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = args(heap, nargs)[0].clone();
+    let mut text = String::new();
+    try!(write_value(heap, &value, &mut text, 0).map_err(|e| prefixed("json-write", e)));
+    let port = try!(port(heap, nargs, 1, OUTPUT, true, "json-write"));
+    try!(port.write_str(&text).map_err(|e| prefixed("json-write", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::valid_number;
+
+    #[test]
+    fn numbers() {
+        for text in &["0", "-0", "12", "1.5", "-1.5e10", "2E-3", "10e+2"] {
+            assert!(valid_number(text), "{}", text)
+        }
+        for text in &["", "-", "01", "+1", "1.", ".5", "1e", "1-", "0x10", "--1"] {
+            assert!(!valid_number(text), "{}", text)
+        }
+    }
+}
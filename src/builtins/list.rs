@@ -0,0 +1,2082 @@
+//! The list library of SRFI 1.
+//!
+//! Every procedure of SRFI 1 is a primitive, including those that R7RS
+//! shares with it, like `map`, `append` and `assoc`.  The linear-update
+//! variants, like `filter!`, are allowed not to reuse their arguments, and
+//! are the same as their pure counterparts.  Procedures taking several lists
+//! stop at the end of the shortest one, and accept circular lists as long as
+//! one of the lists is finite.  Procedures taking an equality predicate call
+//! it as `(= x y)`, with `x` from the earlier argument, and use `equal?` if
+//! it is missing; `eq?`, `eqv?` and `equal?` are not actually called.
+//!
+//! The procedures that take other procedures call them through `call`, in
+//! order.  Elements are kept on the stack while they run, where the GC can
+//! find them.  These are exported by `(srfi 1)`.
+
+use std::cmp;
+use alloc::Heap;
+use arith;
+use equiv;
+use value::{self, Kind, Value};
+use super::{Primitive, args, balanced, boolean, call, callee, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 114] =
+    [Primitive {
+         name: "xcons",
+         min_args: 2,
+         max_args: Some(2),
+         function: xcons,
+     },
+     Primitive {
+         name: "cons*",
+         min_args: 1,
+         max_args: None,
+         function: cons_star,
+     },
+     Primitive {
+         name: "make-list",
+         min_args: 1,
+         max_args: Some(2),
+         function: make_list,
+     },
+     Primitive {
+         name: "list-tabulate",
+         min_args: 2,
+         max_args: Some(2),
+         function: list_tabulate,
+     },
+     Primitive {
+         name: "list-copy",
+         min_args: 1,
+         max_args: Some(1),
+         function: list_copy,
+     },
+     Primitive {
+         name: "circular-list",
+         min_args: 1,
+         max_args: None,
+         function: circular_list,
+     },
+     Primitive {
+         name: "iota",
+         min_args: 1,
+         max_args: Some(3),
+         function: iota,
+     },
+     Primitive {
+         name: "proper-list?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_proper_list,
+     },
+     Primitive {
+         name: "circular-list?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_circular_list,
+     },
+     Primitive {
+         name: "dotted-list?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_dotted_list,
+     },
+     Primitive {
+         name: "not-pair?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_not_pair,
+     },
+     Primitive {
+         name: "null-list?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_null_list,
+     },
+     Primitive {
+         name: "list=",
+         min_args: 1,
+         max_args: None,
+         function: list_equal,
+     },
+     Primitive {
+         name: "first",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "second",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "third",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "fourth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "fifth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "sixth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "seventh",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "eighth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "ninth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "tenth",
+         min_args: 1,
+         max_args: Some(1),
+         function: ordinal,
+     },
+     Primitive {
+         name: "car+cdr",
+         min_args: 1,
+         max_args: Some(1),
+         function: car_cdr,
+     },
+     Primitive {
+         name: "list-ref",
+         min_args: 2,
+         max_args: Some(2),
+         function: list_ref,
+     },
+     Primitive {
+         name: "list-tail",
+         min_args: 2,
+         max_args: Some(2),
+         function: drop,
+     },
+     Primitive {
+         name: "take",
+         min_args: 2,
+         max_args: Some(2),
+         function: take,
+     },
+     Primitive {
+         name: "take!",
+         min_args: 2,
+         max_args: Some(2),
+         function: take,
+     },
+     Primitive {
+         name: "drop",
+         min_args: 2,
+         max_args: Some(2),
+         function: drop,
+     },
+     Primitive {
+         name: "take-right",
+         min_args: 2,
+         max_args: Some(2),
+         function: take_right,
+     },
+     Primitive {
+         name: "drop-right",
+         min_args: 2,
+         max_args: Some(2),
+         function: drop_right,
+     },
+     Primitive {
+         name: "drop-right!",
+         min_args: 2,
+         max_args: Some(2),
+         function: drop_right,
+     },
+     Primitive {
+         name: "split-at",
+         min_args: 2,
+         max_args: Some(2),
+         function: split_at,
+     },
+     Primitive {
+         name: "split-at!",
+         min_args: 2,
+         max_args: Some(2),
+         function: split_at,
+     },
+     Primitive {
+         name: "last",
+         min_args: 1,
+         max_args: Some(1),
+         function: last,
+     },
+     Primitive {
+         name: "last-pair",
+         min_args: 1,
+         max_args: Some(1),
+         function: last_pair,
+     },
+     Primitive {
+         name: "length",
+         min_args: 1,
+         max_args: Some(1),
+         function: length,
+     },
+     Primitive {
+         name: "length+",
+         min_args: 1,
+         max_args: Some(1),
+         function: length_plus,
+     },
+     Primitive {
+         name: "append",
+         min_args: 0,
+         max_args: None,
+         function: append,
+     },
+     Primitive {
+         name: "append!",
+         min_args: 0,
+         max_args: None,
+         function: append,
+     },
+     Primitive {
+         name: "concatenate",
+         min_args: 1,
+         max_args: Some(1),
+         function: concatenate,
+     },
+     Primitive {
+         name: "concatenate!",
+         min_args: 1,
+         max_args: Some(1),
+         function: concatenate,
+     },
+     Primitive {
+         name: "reverse",
+         min_args: 1,
+         max_args: Some(1),
+         function: reverse,
+     },
+     Primitive {
+         name: "reverse!",
+         min_args: 1,
+         max_args: Some(1),
+         function: reverse,
+     },
+     Primitive {
+         name: "append-reverse",
+         min_args: 2,
+         max_args: Some(2),
+         function: append_reverse,
+     },
+     Primitive {
+         name: "append-reverse!",
+         min_args: 2,
+         max_args: Some(2),
+         function: append_reverse,
+     },
+     Primitive {
+         name: "zip",
+         min_args: 1,
+         max_args: None,
+         function: zip,
+     },
+     Primitive {
+         name: "unzip1",
+         min_args: 1,
+         max_args: Some(1),
+         function: unzip,
+     },
+     Primitive {
+         name: "unzip2",
+         min_args: 1,
+         max_args: Some(1),
+         function: unzip,
+     },
+     Primitive {
+         name: "unzip3",
+         min_args: 1,
+         max_args: Some(1),
+         function: unzip,
+     },
+     Primitive {
+         name: "unzip4",
+         min_args: 1,
+         max_args: Some(1),
+         function: unzip,
+     },
+     Primitive {
+         name: "unzip5",
+         min_args: 1,
+         max_args: Some(1),
+         function: unzip,
+     },
+     Primitive {
+         name: "count",
+         min_args: 2,
+         max_args: None,
+         function: count,
+     },
+     Primitive {
+         name: "fold",
+         min_args: 3,
+         max_args: None,
+         function: fold,
+     },
+     Primitive {
+         name: "fold-right",
+         min_args: 3,
+         max_args: None,
+         function: fold_right,
+     },
+     Primitive {
+         name: "pair-fold",
+         min_args: 3,
+         max_args: None,
+         function: pair_fold,
+     },
+     Primitive {
+         name: "pair-fold-right",
+         min_args: 3,
+         max_args: None,
+         function: pair_fold_right,
+     },
+     Primitive {
+         name: "reduce",
+         min_args: 3,
+         max_args: Some(3),
+         function: reduce,
+     },
+     Primitive {
+         name: "reduce-right",
+         min_args: 3,
+         max_args: Some(3),
+         function: reduce_right,
+     },
+     Primitive {
+         name: "unfold",
+         min_args: 4,
+         max_args: Some(5),
+         function: unfold,
+     },
+     Primitive {
+         name: "unfold-right",
+         min_args: 4,
+         max_args: Some(5),
+         function: unfold_right,
+     },
+     Primitive {
+         name: "map",
+         min_args: 2,
+         max_args: None,
+         function: map,
+     },
+     Primitive {
+         name: "map!",
+         min_args: 2,
+         max_args: None,
+         function: map,
+     },
+     Primitive {
+         name: "map-in-order",
+         min_args: 2,
+         max_args: None,
+         function: map,
+     },
+     Primitive {
+         name: "for-each",
+         min_args: 2,
+         max_args: None,
+         function: for_each,
+     },
+     Primitive {
+         name: "append-map",
+         min_args: 2,
+         max_args: None,
+         function: append_map,
+     },
+     Primitive {
+         name: "append-map!",
+         min_args: 2,
+         max_args: None,
+         function: append_map,
+     },
+     Primitive {
+         name: "pair-for-each",
+         min_args: 2,
+         max_args: None,
+         function: pair_for_each,
+     },
+     Primitive {
+         name: "filter-map",
+         min_args: 2,
+         max_args: None,
+         function: filter_map,
+     },
+     Primitive {
+         name: "filter",
+         min_args: 2,
+         max_args: Some(2),
+         function: filter,
+     },
+     Primitive {
+         name: "filter!",
+         min_args: 2,
+         max_args: Some(2),
+         function: filter,
+     },
+     Primitive {
+         name: "partition",
+         min_args: 2,
+         max_args: Some(2),
+         function: partition,
+     },
+     Primitive {
+         name: "partition!",
+         min_args: 2,
+         max_args: Some(2),
+         function: partition,
+     },
+     Primitive {
+         name: "remove",
+         min_args: 2,
+         max_args: Some(2),
+         function: remove,
+     },
+     Primitive {
+         name: "remove!",
+         min_args: 2,
+         max_args: Some(2),
+         function: remove,
+     },
+     Primitive {
+         name: "find",
+         min_args: 2,
+         max_args: Some(2),
+         function: find,
+     },
+     Primitive {
+         name: "find-tail",
+         min_args: 2,
+         max_args: Some(2),
+         function: find_tail,
+     },
+     Primitive {
+         name: "any",
+         min_args: 2,
+         max_args: None,
+         function: any,
+     },
+     Primitive {
+         name: "every",
+         min_args: 2,
+         max_args: None,
+         function: every,
+     },
+     Primitive {
+         name: "list-index",
+         min_args: 2,
+         max_args: None,
+         function: list_index,
+     },
+     Primitive {
+         name: "take-while",
+         min_args: 2,
+         max_args: Some(2),
+         function: take_while,
+     },
+     Primitive {
+         name: "take-while!",
+         min_args: 2,
+         max_args: Some(2),
+         function: take_while,
+     },
+     Primitive {
+         name: "drop-while",
+         min_args: 2,
+         max_args: Some(2),
+         function: drop_while,
+     },
+     Primitive {
+         name: "span",
+         min_args: 2,
+         max_args: Some(2),
+         function: span,
+     },
+     Primitive {
+         name: "span!",
+         min_args: 2,
+         max_args: Some(2),
+         function: span,
+     },
+     Primitive {
+         name: "break",
+         min_args: 2,
+         max_args: Some(2),
+         function: break_,
+     },
+     Primitive {
+         name: "break!",
+         min_args: 2,
+         max_args: Some(2),
+         function: break_,
+     },
+     Primitive {
+         name: "member",
+         min_args: 2,
+         max_args: Some(3),
+         function: member,
+     },
+     Primitive {
+         name: "memq",
+         min_args: 2,
+         max_args: Some(2),
+         function: memq,
+     },
+     Primitive {
+         name: "memv",
+         min_args: 2,
+         max_args: Some(2),
+         function: memv,
+     },
+     Primitive {
+         name: "delete",
+         min_args: 2,
+         max_args: Some(3),
+         function: delete,
+     },
+     Primitive {
+         name: "delete!",
+         min_args: 2,
+         max_args: Some(3),
+         function: delete,
+     },
+     Primitive {
+         name: "delete-duplicates",
+         min_args: 1,
+         max_args: Some(2),
+         function: delete_duplicates,
+     },
+     Primitive {
+         name: "delete-duplicates!",
+         min_args: 1,
+         max_args: Some(2),
+         function: delete_duplicates,
+     },
+     Primitive {
+         name: "assoc",
+         min_args: 2,
+         max_args: Some(3),
+         function: assoc,
+     },
+     Primitive {
+         name: "assq",
+         min_args: 2,
+         max_args: Some(2),
+         function: assq,
+     },
+     Primitive {
+         name: "assv",
+         min_args: 2,
+         max_args: Some(2),
+         function: assv,
+     },
+     Primitive {
+         name: "alist-cons",
+         min_args: 3,
+         max_args: Some(3),
+         function: alist_cons,
+     },
+     Primitive {
+         name: "alist-copy",
+         min_args: 1,
+         max_args: Some(1),
+         function: alist_copy,
+     },
+     Primitive {
+         name: "alist-delete",
+         min_args: 2,
+         max_args: Some(3),
+         function: alist_delete,
+     },
+     Primitive {
+         name: "alist-delete!",
+         min_args: 2,
+         max_args: Some(3),
+         function: alist_delete,
+     },
+     Primitive {
+         name: "lset<=",
+         min_args: 1,
+         max_args: None,
+         function: lset_subset,
+     },
+     Primitive {
+         name: "lset=",
+         min_args: 1,
+         max_args: None,
+         function: lset_equal,
+     },
+     Primitive {
+         name: "lset-adjoin",
+         min_args: 2,
+         max_args: None,
+         function: lset_adjoin,
+     },
+     Primitive {
+         name: "lset-union",
+         min_args: 1,
+         max_args: None,
+         function: lset_union,
+     },
+     Primitive {
+         name: "lset-union!",
+         min_args: 1,
+         max_args: None,
+         function: lset_union,
+     },
+     Primitive {
+         name: "lset-intersection",
+         min_args: 2,
+         max_args: None,
+         function: lset_intersection,
+     },
+     Primitive {
+         name: "lset-intersection!",
+         min_args: 2,
+         max_args: None,
+         function: lset_intersection,
+     },
+     Primitive {
+         name: "lset-difference",
+         min_args: 2,
+         max_args: None,
+         function: lset_difference,
+     },
+     Primitive {
+         name: "lset-difference!",
+         min_args: 2,
+         max_args: None,
+         function: lset_difference,
+     },
+     Primitive {
+         name: "lset-xor",
+         min_args: 1,
+         max_args: None,
+         function: lset_xor,
+     },
+     Primitive {
+         name: "lset-xor!",
+         min_args: 1,
+         max_args: None,
+         function: lset_xor,
+     },
+     Primitive {
+         name: "lset-diff+intersection",
+         min_args: 2,
+         max_args: None,
+         function: lset_diff_intersection,
+     },
+     Primitive {
+         name: "lset-diff+intersection!",
+         min_args: 2,
+         max_args: None,
+         function: lset_diff_intersection,
+     }];
+
+/// The selectors from `first` to `tenth`, in order.
+static ORDINALS: [&'static str; 10] =
+    ["first", "second", "third", "fourth", "fifth", "sixth", "seventh", "eighth", "ninth", "tenth"];
+
+/// The `unzip` procedures, in order.
+static UNZIPS: [&'static str; 5] = ["unzip1", "unzip2", "unzip3", "unzip4", "unzip5"];
+
+/// How a chain of pairs ends.
+enum Shape {
+    /// With `()`, after that many pairs.
+    Proper(usize),
+
+    /// With something else, after that many pairs.
+    Dotted(usize),
+
+    Circular,
+}
+
+/// The shape of `list`, found without looping on circular lists.
+fn shape(list: &Value) -> Shape {
+    let mut slow = list.clone();
+    let mut fast = list.clone();
+    let mut len = 0;
+    loop {
+        for _ in 0..2 {
+            match fast.cdr() {
+                Ok(next) => {
+                    fast = next;
+                    len += 1
+                }
+                Err(()) if fast.get() == value::NIL => return Shape::Proper(len),
+                Err(()) => return Shape::Dotted(len),
+            }
+        }
+        slow = slow.cdr().unwrap();
+        if slow == fast {
+            return Shape::Circular
+        }
+    }
+}
+
+/// The length of the proper list `value`, an argument of the primitive
+/// `name`.
+fn list_arg(value: &Value, name: &str) -> Result<usize, String> {
+    match shape(value) {
+        Shape::Proper(len) => Ok(len),
+        _ => Err(format!("{}: expected a list", name)),
+    }
+}
+
+/// The number of pairs in the proper or dotted list `value`, an argument
+/// of the primitive `name`.
+fn pairs_arg(value: &Value, name: &str) -> Result<usize, String> {
+    match shape(value) {
+        Shape::Proper(len) | Shape::Dotted(len) => Ok(len),
+        Shape::Circular => Err(format!("{}: expected a list that is not circular", name)),
+    }
+}
+
+/// What is left of `list` after `len` pairs, for the primitive `name`.
+fn nth_tail(list: &Value, len: usize, name: &str) -> Result<Value, String> {
+    let mut rest = list.clone();
+    for _ in 0..len {
+        rest = try!(rest.cdr().map_err(|()| format!("{}: the list is too short", name)))
+    }
+    Ok(rest)
+}
+
+fn is_true(value: &Value) -> bool {
+    value.get() != value::FALSE
+}
+
+/// Runs `body` with the index on the stack of the first argument, then pops
+/// whatever it pushed.
+fn with_args<F>(heap: &mut Heap, nargs: usize, body: F) -> Result<Value, String>
+    where F: FnOnce(&mut Heap, usize) -> Result<Value, String>
+{
+    let first = heap.stack.len() - nargs;
+    balanced(heap, |heap| body(heap, first))
+}
+
+/// Calls `procedure` with `args`, which must have been read from the stack
+/// since the last allocation, and returns the result, which is not rooted.
+fn call_procedure(heap: &mut Heap, procedure: Value, args: &[Value]) -> Result<Value, String> {
+    let top = heap.stack.len();
+    heap.stack.push(procedure);
+    heap.stack.extend_from_slice(args);
+    match call(heap, args.len()) {
+        Ok(()) => Ok(heap.stack.pop().unwrap()),
+        Err(e) => {
+            heap.stack.truncate(top);
+            Err(e)
+        }
+    }
+}
+
+/// Calls the procedure at `procedure` on the stack with the values at
+/// `args`, and returns whether the result is true.
+fn test(heap: &mut Heap, procedure: usize, args: &[usize]) -> Result<bool, String> {
+    let procedure = heap.stack[procedure].clone();
+    let args: Vec<Value> = args.iter().map(|&i| heap.stack[i].clone()).collect();
+    Ok(is_true(&try!(call_procedure(heap, procedure, &args))))
+}
+
+/// Whether the values at `x` and `y` on the stack are the same according to
+/// the equality predicate at `equality`.
+fn same(heap: &mut Heap, equality: usize, x: usize, y: usize) -> Result<bool, String> {
+    if let Kind::Primitive(primitive) = heap.stack[equality].kind() {
+        let (x, y) = (&heap.stack[x], &heap.stack[y]);
+        match unsafe { (*primitive).name } {
+            "eq?" => return Ok(equiv::eq(x, y)),
+            "eqv?" => return Ok(equiv::eqv(x, y)),
+            "equal?" => return Ok(equiv::equal(x, y)),
+            _ => {}
+        }
+    }
+    test(heap, equality, &[x, y])
+}
+
+/// Whether the value at `x` on the stack is the same as one of the values
+/// at `among`, according to the equality predicate at `equality`.  `x` is
+/// passed first if `x_first` is set.
+fn contains(heap: &mut Heap,
+            equality: usize,
+            x: usize,
+            among: &[usize],
+            x_first: bool)
+            -> Result<bool, String> {
+    for &y in among {
+        let found = if x_first {
+            try!(same(heap, equality, x, y))
+        } else {
+            try!(same(heap, equality, y, x))
+        };
+        if found {
+            return Ok(true)
+        }
+    }
+    Ok(false)
+}
+
+/// The index on the stack of the equality predicate: argument `index`, or
+/// `equal?` if it is missing.
+fn equality_arg(heap: &mut Heap, first: usize, nargs: usize, index: usize) -> usize {
+    if index < nargs {
+        return first + index
+    }
+    heap.stack.push(super::lookup("equal?").unwrap().to_value());
+    heap.stack.len() - 1
+}
+
+/// The list of the values at the indices `items` on the stack, ending with
+/// `tail`.
+fn list_of(heap: &mut Heap, items: &[usize], tail: Value) -> Result<Value, String> {
+    heap.stack.push(tail);
+    let list = heap.stack.len() - 1;
+    for &i in items.iter().rev() {
+        try!(heap.alloc_pair(i, list));
+        let pair = heap.stack.pop().unwrap();
+        heap.stack[list] = pair
+    }
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// The list of the values at `start..end` on the stack.
+fn list_from(heap: &mut Heap, start: usize, end: usize) -> Result<Value, String> {
+    list_of(heap, &(start..end).collect::<Vec<usize>>(), Value::new(value::NIL))
+}
+
+/// Returns the values at `start..end` on the stack, as multiple values.
+fn values(heap: &mut Heap, start: usize, end: usize) -> Result<Value, String> {
+    try!(heap.alloc_values(start, end));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// Pushes `value`, and returns its index on the stack.
+fn push(heap: &mut Heap, value: Value) -> usize {
+    heap.stack.push(value);
+    heap.stack.len() - 1
+}
+
+/// List arguments, pushed on the stack as far as the shortest one goes:
+/// item `j` of list `i` is at `base + i * len + j`.  Items are elements, or
+/// the pairs holding them.
+struct Lists {
+    base: usize,
+    len: usize,
+    count: usize,
+}
+
+impl Lists {
+    fn at(&self, list: usize, index: usize) -> usize {
+        self.base + list * self.len + index
+    }
+
+    /// The indices on the stack of the items at `index` of every list.
+    fn column(&self, index: usize) -> Vec<usize> {
+        (0..self.count).map(|list| self.at(list, index)).collect()
+    }
+
+    /// The indices on the stack of the items of the first list.
+    fn first(&self) -> Vec<usize> {
+        (self.base..self.base + self.len).collect()
+    }
+}
+
+/// Pushes the lists at `first + start..first + end` on the stack, for the
+/// primitive `name`.  With `pairs`, the pairs are pushed rather than the
+/// elements.
+fn push_lists(heap: &mut Heap,
+              first: usize,
+              start: usize,
+              end: usize,
+              pairs: bool,
+              name: &str)
+              -> Result<Lists, String> {
+    let mut len = None;
+    for i in start..end {
+        match shape(&heap.stack[first + i]) {
+            Shape::Proper(n) => len = Some(len.map_or(n, |len| cmp::min(len, n))),
+            Shape::Circular => {}
+            Shape::Dotted(_) => return Err(format!("{}: expected a list", name)),
+        }
+    }
+    let len = try!(len.ok_or_else(|| format!("{}: expected a list that is not circular", name)));
+    let base = heap.stack.len();
+    for i in start..end {
+        let mut rest = heap.stack[first + i].clone();
+        for _ in 0..len {
+            let item = if pairs { rest.clone() } else { rest.car().unwrap() };
+            heap.stack.push(item);
+            rest = rest.cdr().unwrap()
+        }
+    }
+    Ok(Lists {
+        base: base,
+        len: len,
+        count: end - start,
+    })
+}
+
+/// Pushes the elements of the list at argument `index`, for the primitive
+/// `name`.
+fn push_list(heap: &mut Heap, first: usize, index: usize, name: &str) -> Result<Lists, String> {
+    push_lists(heap, first, index, index + 1, false, name)
+}
+
+/// The primitive being called, among those called `names`: its index.
+fn which(heap: &Heap, nargs: usize, names: &[&str]) -> usize {
+    let primitive = callee(heap, nargs);
+    names.iter()
+         .position(|name| super::lookup(name).unwrap().to_value() == primitive)
+         .unwrap()
+}
+
+// Constructors
+
+/// `(xcons d a)`
+fn xcons(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(heap.alloc_pair(first + 1, first));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(cons* elt ... tail)`
+fn cons_star(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let tail = heap.stack[first + nargs - 1].clone();
+        list_of(heap, &(first..first + nargs - 1).collect::<Vec<usize>>(), tail)
+    })
+}
+
+/// `(make-list n [fill])`
+fn make_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(fixnum_arg(&heap.stack[first], "make-list"));
+        let fill = if nargs > 1 { first + 1 } else { push(heap, Value::new(value::UNSPECIFIED)) };
+        list_of(heap, &vec![fill; len], Value::new(value::NIL))
+    })
+}
+
+/// `(list-tabulate n proc)`
+fn list_tabulate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(fixnum_arg(&heap.stack[first], "list-tabulate"));
+        let start = heap.stack.len();
+        for i in 0..len {
+            let procedure = heap.stack[first + 1].clone();
+            let element = try!(call_procedure(heap, procedure, &[Value::new_fixnum(i)]));
+            heap.stack.push(element)
+        }
+        let end = heap.stack.len();
+        list_from(heap, start, end)
+    })
+}
+
+/// `(list-copy list)`: copies the pairs of a proper or dotted list.
+fn list_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(pairs_arg(&heap.stack[first], "list-copy"));
+        let elements = push_prefix(heap, first, len);
+        let tail = nth_tail(&heap.stack[first], len, "list-copy").unwrap();
+        list_of(heap, &elements, tail)
+    })
+}
+
+/// Pushes the first `len` elements of the list at `list` on the stack,
+/// which must have that many, and returns their indices.
+fn push_prefix(heap: &mut Heap, list: usize, len: usize) -> Vec<usize> {
+    let start = heap.stack.len();
+    let mut rest = heap.stack[list].clone();
+    for _ in 0..len {
+        heap.stack.push(rest.car().unwrap());
+        rest = rest.cdr().unwrap()
+    }
+    (start..start + len).collect()
+}
+
+/// `(circular-list elt1 elt ...)`
+fn circular_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let list = try!(list_from(heap, first, first + nargs));
+        let last = nth_tail(&list, nargs - 1, "circular-list").unwrap();
+        last.set_cdr(list.clone()).unwrap();
+        heap.write_barrier(&last, &list);
+        Ok(list)
+    })
+}
+
+/// `(iota count [start [step]])`
+fn iota(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(fixnum_arg(&heap.stack[first], "iota"));
+        let start = if nargs > 1 { first + 1 } else { push(heap, Value::new_fixnum(0)) };
+        let step = if nargs > 2 { first + 2 } else { push(heap, Value::new_fixnum(1)) };
+        let elements = heap.stack.len();
+        for i in 0..len {
+            // Each element is computed afresh, so that errors do not add up.
+            let step_value = heap.stack[step].clone();
+            let offset = try!(arith::multiply(heap, &Value::new_fixnum(i), &step_value));
+            let offset = push(heap, offset);
+            let (start_value, offset_value) = (heap.stack[start].clone(),
+                                               heap.stack[offset].clone());
+            let element = try!(arith::add(heap, &start_value, &offset_value));
+            heap.stack[offset] = element
+        }
+        let end = heap.stack.len();
+        list_from(heap, elements, end)
+    })
+}
+
+// Predicates
+
+/// `(proper-list? obj)`
+fn is_proper_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(match shape(&args(heap, nargs)[0]) {
+        Shape::Proper(_) => true,
+        _ => false,
+    }))
+}
+
+/// `(circular-list? obj)`
+fn is_circular_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(match shape(&args(heap, nargs)[0]) {
+        Shape::Circular => true,
+        _ => false,
+    }))
+}
+
+/// `(dotted-list? obj)`: true for anything but proper and circular lists.
+fn is_dotted_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(match shape(&args(heap, nargs)[0]) {
+        Shape::Dotted(_) => true,
+        _ => false,
+    }))
+}
+
+/// `(not-pair? obj)`
+fn is_not_pair(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].tag() != value::Tags::Pair))
+}
+
+/// `(null-list? list)`
+fn is_null_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let list = args(heap, nargs)[0].clone();
+    if list.get() == value::NIL {
+        Ok(boolean(true))
+    } else if list.tag() == value::Tags::Pair {
+        Ok(boolean(false))
+    } else {
+        Err("null-list?: expected a list".to_owned())
+    }
+}
+
+/// `(list= elt= list ...)`
+fn list_equal(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        for i in 1..nargs.saturating_sub(1) {
+            let x = try!(push_list(heap, first, i, "list="));
+            let y = try!(push_list(heap, first, i + 1, "list="));
+            if list_arg(&heap.stack[first + i], "list=") !=
+               list_arg(&heap.stack[first + i + 1], "list=") {
+                return Ok(boolean(false))
+            }
+            for j in 0..x.len {
+                if !try!(test(heap, first, &[x.at(0, j), y.at(0, j)])) {
+                    return Ok(boolean(false))
+                }
+            }
+            heap.stack.truncate(x.base)
+        }
+        Ok(boolean(true))
+    })
+}
+
+// Selectors
+
+/// `(first pair)` to `(tenth pair)`
+fn ordinal(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let index = which(heap, nargs, &ORDINALS);
+    let name = ORDINALS[index];
+    let rest = try!(nth_tail(&args(heap, nargs)[0], index, name));
+    rest.car().map_err(|()| format!("{}: the list is too short", name))
+}
+
+/// `(car+cdr pair)`
+fn car_cdr(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let pair = heap.stack[first].clone();
+        let start = heap.stack.len();
+        heap.stack.push(try!(pair.car().map_err(|()| "car+cdr: expected a pair".to_owned())));
+        heap.stack.push(pair.cdr().unwrap());
+        values(heap, start, start + 2)
+    })
+}
+
+/// `(list-ref list k)`
+fn list_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let k = try!(fixnum_arg(&args[1], "list-ref"));
+    try!(nth_tail(&args[0], k, "list-ref"))
+        .car()
+        .map_err(|()| "list-ref: the list is too short".to_owned())
+}
+
+/// `(take x i)`
+fn take(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(fixnum_arg(&heap.stack[first + 1], "take"));
+        try!(nth_tail(&heap.stack[first], len, "take"));
+        let elements = push_prefix(heap, first, len);
+        list_of(heap, &elements, Value::new(value::NIL))
+    })
+}
+
+/// `(drop x i)` and `(list-tail x i)`
+fn drop(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(fixnum_arg(&args[1], "drop"));
+    nth_tail(&args[0], len, "drop")
+}
+
+/// The length of the list argument `x`, and the number `i`, for `take-right`
+/// and `drop-right`.
+fn right_args(heap: &Heap, first: usize, name: &str) -> Result<(usize, usize), String> {
+    let len = try!(pairs_arg(&heap.stack[first], name));
+    let i = try!(fixnum_arg(&heap.stack[first + 1], name));
+    if i > len {
+        return Err(format!("{}: the list is too short", name))
+    }
+    Ok((len, i))
+}
+
+/// `(take-right flist i)`
+fn take_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let (len, i) = try!(right_args(heap, first, "take-right"));
+    nth_tail(&heap.stack[first], len - i, "take-right")
+}
+
+/// `(drop-right flist i)`
+fn drop_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (len, i) = try!(right_args(heap, first, "drop-right"));
+        let elements = push_prefix(heap, first, len - i);
+        list_of(heap, &elements, Value::new(value::NIL))
+    })
+}
+
+/// `(split-at x i)`
+fn split_at(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let len = try!(fixnum_arg(&heap.stack[first + 1], "split-at"));
+        let suffix = try!(nth_tail(&heap.stack[first], len, "split-at"));
+        let start = push(heap, suffix);
+        let elements = push_prefix(heap, first, len);
+        let prefix = try!(list_of(heap, &elements, Value::new(value::NIL)));
+        heap.stack.truncate(start + 1);
+        let suffix = heap.stack[start].clone();
+        heap.stack[start] = prefix;
+        heap.stack.push(suffix);
+        values(heap, start, start + 2)
+    })
+}
+
+/// `(last-pair pair)`
+fn last_pair(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let pair = args(heap, nargs)[0].clone();
+    match pairs_arg(&pair, "last-pair") {
+        Ok(0) | Err(_) => Err("last-pair: expected a pair, and not a circular list".to_owned()),
+        Ok(len) => Ok(nth_tail(&pair, len - 1, "last-pair").unwrap()),
+    }
+}
+
+/// `(last pair)`
+fn last(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(try!(last_pair(heap, nargs)).car().unwrap())
+}
+
+// Miscellaneous
+
+/// `(length list)`
+fn length(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(Value::new_fixnum(try!(list_arg(&args(heap, nargs)[0], "length"))))
+}
+
+/// `(length+ clist)`: the length, or `#f` for a circular list.
+fn length_plus(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    match shape(&args(heap, nargs)[0]) {
+        Shape::Proper(len) => Ok(Value::new_fixnum(len)),
+        Shape::Circular => Ok(boolean(false)),
+        Shape::Dotted(_) => Err("length+: expected a list".to_owned()),
+    }
+}
+
+/// Appends the lists at `start..end` on the stack.  The last one is shared,
+/// and can be anything.
+fn append_lists(heap: &mut Heap, start: usize, end: usize, name: &str) -> Result<Value, String> {
+    if start == end {
+        return Ok(Value::new(value::NIL))
+    }
+    let mut elements = vec![];
+    for i in start..end - 1 {
+        let lists = try!(push_list(heap, i, 0, name));
+        elements.extend(lists.first())
+    }
+    let tail = heap.stack[end - 1].clone();
+    list_of(heap, &elements, tail)
+}
+
+/// `(append list ...)`
+fn append(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| append_lists(heap, first, first + nargs, "append"))
+}
+
+/// `(concatenate list-of-lists)`
+fn concatenate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 0, "concatenate"));
+        append_lists(heap, lists.base, lists.base + lists.len, "concatenate")
+    })
+}
+
+/// Conses the elements at `items` on the stack onto `tail`, in order, so
+/// that they end up reversed.
+fn reverse_onto(heap: &mut Heap, items: &[usize], tail: Value) -> Result<Value, String> {
+    let reversed: Vec<usize> = items.iter().rev().cloned().collect();
+    list_of(heap, &reversed, tail)
+}
+
+/// `(reverse list)`
+fn reverse(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 0, "reverse"));
+        reverse_onto(heap, &lists.first(), Value::new(value::NIL))
+    })
+}
+
+/// `(append-reverse rev-head tail)`
+fn append_reverse(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 0, "append-reverse"));
+        let tail = heap.stack[first + 1].clone();
+        reverse_onto(heap, &lists.first(), tail)
+    })
+}
+
+/// `(zip clist1 clist ...)`
+fn zip(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 0, nargs, false, "zip"));
+        let start = heap.stack.len();
+        for j in 0..lists.len {
+            let tuple = try!(list_of(heap, &lists.column(j), Value::new(value::NIL)));
+            heap.stack.push(tuple)
+        }
+        list_from(heap, start, start + lists.len)
+    })
+}
+
+/// `(unzip1 list)` to `(unzip5 list)`: `n` lists, as `n` values, of the
+/// first `n` elements of the lists in `list`.
+fn unzip(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let n = which(heap, nargs, &UNZIPS) + 1;
+    let name = UNZIPS[n - 1];
+    with_args(heap, nargs, |heap, first| {
+        let tuples = try!(push_list(heap, first, 0, name));
+        let start = heap.stack.len();
+        for k in 0..n {
+            let elements = heap.stack.len();
+            for j in 0..tuples.len {
+                let tuple = heap.stack[tuples.at(0, j)].clone();
+                let element = try!(try!(nth_tail(&tuple, k, name))
+                                       .car()
+                                       .map_err(|()| format!("{}: a list is too short", name)));
+                heap.stack.push(element)
+            }
+            let list = try!(list_from(heap, elements, elements + tuples.len));
+            heap.stack.truncate(elements);
+            heap.stack.push(list)
+        }
+        if n == 1 {
+            Ok(heap.stack[start].clone())
+        } else {
+            values(heap, start, start + n)
+        }
+    })
+}
+
+/// `(count pred clist1 clist ...)`
+fn count(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "count"));
+        let mut count = 0;
+        for j in 0..lists.len {
+            if try!(test(heap, first, &lists.column(j))) {
+                count += 1
+            }
+        }
+        Ok(Value::new_fixnum(count))
+    })
+}
+
+// Fold, unfold and map
+
+/// Folds `kons` over `lists`, in the order of `indices`, starting with the
+/// value at `acc`: each result replaces it.
+fn fold_over<I>(heap: &mut Heap,
+                kons: usize,
+                lists: &Lists,
+                indices: I,
+                acc: usize)
+                -> Result<Value, String>
+    where I: Iterator<Item = usize>
+{
+    for j in indices {
+        let mut items = lists.column(j);
+        items.push(acc);
+        let procedure = heap.stack[kons].clone();
+        let args: Vec<Value> = items.iter().map(|&i| heap.stack[i].clone()).collect();
+        let result = try!(call_procedure(heap, procedure, &args));
+        heap.stack[acc] = result
+    }
+    Ok(heap.stack[acc].clone())
+}
+
+/// `(fold kons knil clist1 clist ...)`
+fn fold(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 2, nargs, false, "fold"));
+        let acc = heap.stack[first + 1].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, 0..lists.len, acc)
+    })
+}
+
+/// `(fold-right kons knil clist1 clist ...)`
+fn fold_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 2, nargs, false, "fold-right"));
+        let acc = heap.stack[first + 1].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, (0..lists.len).rev(), acc)
+    })
+}
+
+/// `(pair-fold kons knil clist1 clist ...)`
+fn pair_fold(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 2, nargs, true, "pair-fold"));
+        let acc = heap.stack[first + 1].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, 0..lists.len, acc)
+    })
+}
+
+/// `(pair-fold-right kons knil clist1 clist ...)`
+fn pair_fold_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 2, nargs, true, "pair-fold-right"));
+        let acc = heap.stack[first + 1].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, (0..lists.len).rev(), acc)
+    })
+}
+
+/// `(reduce f ridentity list)`
+fn reduce(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 2, "reduce"));
+        if lists.len == 0 {
+            return Ok(heap.stack[first + 1].clone())
+        }
+        let acc = heap.stack[lists.at(0, 0)].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, 1..lists.len, acc)
+    })
+}
+
+/// `(reduce-right f ridentity list)`
+fn reduce_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 2, "reduce-right"));
+        if lists.len == 0 {
+            return Ok(heap.stack[first + 1].clone())
+        }
+        let acc = heap.stack[lists.at(0, lists.len - 1)].clone();
+        let acc = push(heap, acc);
+        fold_over(heap, first, &lists, (0..lists.len - 1).rev(), acc)
+    })
+}
+
+/// Calls the procedure at `procedure` on the stack with the value at `arg`,
+/// and returns the result.
+fn call_on(heap: &mut Heap, procedure: usize, arg: usize) -> Result<Value, String> {
+    let (procedure, arg) = (heap.stack[procedure].clone(), heap.stack[arg].clone());
+    call_procedure(heap, procedure, &[arg])
+}
+
+/// Runs the loop of `unfold` and `unfold-right`: pushes the elements, and
+/// returns the index of the final seed.
+fn unfold_seeds(heap: &mut Heap, first: usize) -> Result<usize, String> {
+    let seed = heap.stack[first + 3].clone();
+    let seed = push(heap, seed);
+    let mut elements = vec![];
+    while !is_true(&try!(call_on(heap, first, seed))) {
+        let element = try!(call_on(heap, first + 1, seed));
+        elements.push(push(heap, element));
+        let next = try!(call_on(heap, first + 2, seed));
+        heap.stack[seed] = next
+    }
+    // The seed is moved past the elements.
+    let seed_value = heap.stack[seed].clone();
+    Ok(push(heap, seed_value))
+}
+
+/// `(unfold p f g seed [tail-gen])`
+fn unfold(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let start = heap.stack.len() + 1;
+        let seed = try!(unfold_seeds(heap, first));
+        let tail = if nargs > 4 {
+            try!(call_on(heap, first + 4, seed))
+        } else {
+            Value::new(value::NIL)
+        };
+        list_of(heap, &(start..seed).collect::<Vec<usize>>(), tail)
+    })
+}
+
+/// `(unfold-right p f g seed [tail])`
+fn unfold_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let start = heap.stack.len() + 1;
+        let seed = try!(unfold_seeds(heap, first));
+        let tail = if nargs > 4 { heap.stack[first + 4].clone() } else { Value::new(value::NIL) };
+        reverse_onto(heap, &(start..seed).collect::<Vec<usize>>(), tail)
+    })
+}
+
+/// Calls the procedure that is the first argument with the items at every
+/// index of `lists`, and pushes the results.  Returns their indices.
+fn map_over(heap: &mut Heap, first: usize, lists: &Lists) -> Result<Vec<usize>, String> {
+    let mut results = vec![];
+    for j in 0..lists.len {
+        let procedure = heap.stack[first].clone();
+        let args: Vec<Value> = lists.column(j).iter().map(|&i| heap.stack[i].clone()).collect();
+        let result = try!(call_procedure(heap, procedure, &args));
+        results.push(push(heap, result))
+    }
+    Ok(results)
+}
+
+/// `(map f clist1 clist ...)`
+fn map(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "map"));
+        let results = try!(map_over(heap, first, &lists));
+        list_of(heap, &results, Value::new(value::NIL))
+    })
+}
+
+/// `(for-each f clist1 clist ...)`
+fn for_each(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "for-each"));
+        try!(map_over(heap, first, &lists));
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// `(append-map f clist1 clist ...)`
+fn append_map(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "append-map"));
+        let start = heap.stack.len();
+        try!(map_over(heap, first, &lists));
+        let end = heap.stack.len();
+        append_lists(heap, start, end, "append-map")
+    })
+}
+
+/// `(pair-for-each f clist1 clist ...)`
+fn pair_for_each(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, true, "pair-for-each"));
+        try!(map_over(heap, first, &lists));
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// `(filter-map f clist1 clist ...)`
+fn filter_map(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "filter-map"));
+        let results = try!(map_over(heap, first, &lists));
+        let kept: Vec<usize> = results.into_iter().filter(|&i| is_true(&heap.stack[i])).collect();
+        list_of(heap, &kept, Value::new(value::NIL))
+    })
+}
+
+// Filtering and partitioning
+
+/// Sorts the elements of the list argument into those that satisfy the
+/// predicate argument and the others, for the primitive `name`.
+fn split(heap: &mut Heap, first: usize, name: &str) -> Result<(Vec<usize>, Vec<usize>), String> {
+    let lists = try!(push_list(heap, first, 1, name));
+    let (mut kept, mut removed) = (vec![], vec![]);
+    for i in lists.first() {
+        if try!(test(heap, first, &[i])) {
+            kept.push(i)
+        } else {
+            removed.push(i)
+        }
+    }
+    Ok((kept, removed))
+}
+
+/// `(filter pred list)`
+fn filter(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (kept, _) = try!(split(heap, first, "filter"));
+        list_of(heap, &kept, Value::new(value::NIL))
+    })
+}
+
+/// `(remove pred list)`
+fn remove(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (_, removed) = try!(split(heap, first, "remove"));
+        list_of(heap, &removed, Value::new(value::NIL))
+    })
+}
+
+/// Returns two lists, of the values at `kept` and `removed` on the stack,
+/// as two values.
+fn two_lists(heap: &mut Heap, kept: &[usize], removed: &[usize]) -> Result<Value, String> {
+    let kept = try!(list_of(heap, kept, Value::new(value::NIL)));
+    let start = push(heap, kept);
+    let removed = try!(list_of(heap, removed, Value::new(value::NIL)));
+    heap.stack.push(removed);
+    values(heap, start, start + 2)
+}
+
+/// `(partition pred list)`
+fn partition(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (kept, removed) = try!(split(heap, first, "partition"));
+        two_lists(heap, &kept, &removed)
+    })
+}
+
+// Searching
+
+/// The index of the first item of `lists` for which the predicate argument
+/// returns true, and what it returned.
+fn search(heap: &mut Heap, first: usize, lists: &Lists) -> Result<Option<(usize, Value)>, String> {
+    for j in 0..lists.len {
+        let procedure = heap.stack[first].clone();
+        let args: Vec<Value> = lists.column(j).iter().map(|&i| heap.stack[i].clone()).collect();
+        let result = try!(call_procedure(heap, procedure, &args));
+        if is_true(&result) {
+            return Ok(Some((j, result)))
+        }
+    }
+    Ok(None)
+}
+
+/// `(find pred clist)`
+fn find(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 1, "find"));
+        Ok(match try!(search(heap, first, &lists)) {
+            Some((j, _)) => heap.stack[lists.at(0, j)].clone(),
+            None => boolean(false),
+        })
+    })
+}
+
+/// `(find-tail pred clist)`
+fn find_tail(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_list(heap, first, 1, "find-tail"));
+        Ok(match try!(search(heap, first, &lists)) {
+            Some((j, _)) => nth_tail(&heap.stack[first + 1], j, "find-tail").unwrap(),
+            None => boolean(false),
+        })
+    })
+}
+
+/// `(any pred clist1 clist ...)`
+fn any(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "any"));
+        Ok(match try!(search(heap, first, &lists)) {
+            Some((_, result)) => result,
+            None => boolean(false),
+        })
+    })
+}
+
+/// `(every pred clist1 clist ...)`: the last result, or `#t` for empty
+/// lists.
+fn every(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "every"));
+        let mut result = boolean(true);
+        for j in 0..lists.len {
+            let procedure = heap.stack[first].clone();
+            let args: Vec<Value> = lists.column(j).iter().map(|&i| heap.stack[i].clone()).collect();
+            result = try!(call_procedure(heap, procedure, &args));
+            if !is_true(&result) {
+                break
+            }
+        }
+        Ok(result)
+    })
+}
+
+/// `(list-index pred clist1 clist ...)`
+fn list_index(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let lists = try!(push_lists(heap, first, 1, nargs, false, "list-index"));
+        Ok(match try!(search(heap, first, &lists)) {
+            Some((j, _)) => Value::new_fixnum(j),
+            None => boolean(false),
+        })
+    })
+}
+
+/// The number of elements at the start of the list argument that satisfy
+/// the predicate argument, or fail it if `negate` is set.
+fn leading(heap: &mut Heap, first: usize, negate: bool, name: &str) -> Result<Lists, String> {
+    let lists = try!(push_list(heap, first, 1, name));
+    let mut len = 0;
+    while len < lists.len && try!(test(heap, first, &[lists.at(0, len)])) != negate {
+        len += 1
+    }
+    Ok(Lists {
+        base: lists.base,
+        len: len,
+        count: 1,
+    })
+}
+
+/// `(take-while pred clist)`
+fn take_while(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let leading = try!(leading(heap, first, false, "take-while"));
+        list_of(heap, &leading.first(), Value::new(value::NIL))
+    })
+}
+
+/// `(drop-while pred clist)`
+fn drop_while(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let leading = try!(leading(heap, first, false, "drop-while"));
+        nth_tail(&heap.stack[first + 1], leading.len, "drop-while")
+    })
+}
+
+/// `span` and `break`: the longest prefix whose elements satisfy the
+/// predicate, or fail it if `negate` is set, and the rest, as two values.
+fn span_or_break(heap: &mut Heap, nargs: usize, negate: bool, name: &str) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let leading = try!(leading(heap, first, negate, name));
+        let head = try!(list_of(heap, &leading.first(), Value::new(value::NIL)));
+        let start = push(heap, head);
+        let rest = nth_tail(&heap.stack[first + 1], leading.len, name).unwrap();
+        heap.stack.push(rest);
+        values(heap, start, start + 2)
+    })
+}
+
+/// `(span pred clist)`
+fn span(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    span_or_break(heap, nargs, false, "span")
+}
+
+/// `(break pred clist)`
+fn break_(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    span_or_break(heap, nargs, true, "break")
+}
+
+/// The first pair of the list argument at `index` whose car is the same as
+/// the argument `x`, according to `equality`, or `#f`.
+fn member_of(heap: &mut Heap,
+             first: usize,
+             index: usize,
+             equality: usize,
+             name: &str)
+             -> Result<Value, String> {
+    let lists = try!(push_list(heap, first, index, name));
+    for j in 0..lists.len {
+        if try!(same(heap, equality, first, lists.at(0, j))) {
+            return Ok(nth_tail(&heap.stack[first + index], j, name).unwrap())
+        }
+    }
+    Ok(boolean(false))
+}
+
+/// Pushes the primitive `name`, and returns its index on the stack.
+fn push_primitive(heap: &mut Heap, name: &str) -> usize {
+    push(heap, super::lookup(name).unwrap().to_value())
+}
+
+/// `(member x list [=])`
+fn member(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = equality_arg(heap, first, nargs, 2);
+        member_of(heap, first, 1, equality, "member")
+    })
+}
+
+/// `(memq x list)`
+fn memq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = push_primitive(heap, "eq?");
+        member_of(heap, first, 1, equality, "memq")
+    })
+}
+
+/// `(memv x list)`
+fn memv(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = push_primitive(heap, "eqv?");
+        member_of(heap, first, 1, equality, "memv")
+    })
+}
+
+// Deletion
+
+/// `(delete x list [=])`
+fn delete(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = equality_arg(heap, first, nargs, 2);
+        let lists = try!(push_list(heap, first, 1, "delete"));
+        let mut kept = vec![];
+        for i in lists.first() {
+            if !try!(same(heap, equality, first, i)) {
+                kept.push(i)
+            }
+        }
+        list_of(heap, &kept, Value::new(value::NIL))
+    })
+}
+
+/// `(delete-duplicates list [=])`: keeps the first of equal elements.
+fn delete_duplicates(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = equality_arg(heap, first, nargs, 1);
+        let lists = try!(push_list(heap, first, 0, "delete-duplicates"));
+        let mut kept = vec![];
+        for i in lists.first() {
+            if !try!(contains(heap, equality, i, &kept, false)) {
+                kept.push(i)
+            }
+        }
+        list_of(heap, &kept, Value::new(value::NIL))
+    })
+}
+
+// Association lists
+
+/// Pushes the keys of the alist argument at `index`, and returns the
+/// indices of the entries and of their keys.
+fn push_keys(heap: &mut Heap,
+             first: usize,
+             index: usize,
+             name: &str)
+             -> Result<(Vec<usize>, Vec<usize>), String> {
+    let entries = try!(push_list(heap, first, index, name)).first();
+    let mut keys = vec![];
+    for &entry in &entries {
+        let key = try!(heap.stack[entry]
+                           .car()
+                           .map_err(|()| format!("{}: expected an association list", name)));
+        keys.push(push(heap, key))
+    }
+    Ok((entries, keys))
+}
+
+/// The first entry of the alist argument at `index` whose key is the same
+/// as the first argument, according to `equality`, or `#f`.
+fn assoc_in(heap: &mut Heap,
+            first: usize,
+            index: usize,
+            equality: usize,
+            name: &str)
+            -> Result<Value, String> {
+    let (entries, keys) = try!(push_keys(heap, first, index, name));
+    for (&entry, &key) in entries.iter().zip(&keys) {
+        if try!(same(heap, equality, first, key)) {
+            return Ok(heap.stack[entry].clone())
+        }
+    }
+    Ok(boolean(false))
+}
+
+/// `(assoc key alist [=])`
+fn assoc(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = equality_arg(heap, first, nargs, 2);
+        assoc_in(heap, first, 1, equality, "assoc")
+    })
+}
+
+/// `(assq key alist)`
+fn assq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = push_primitive(heap, "eq?");
+        assoc_in(heap, first, 1, equality, "assq")
+    })
+}
+
+/// `(assv key alist)`
+fn assv(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = push_primitive(heap, "eqv?");
+        assoc_in(heap, first, 1, equality, "assv")
+    })
+}
+
+/// `(alist-cons key datum alist)`
+fn alist_cons(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        try!(heap.alloc_pair(first, first + 1));
+        let entry = heap.stack.len() - 1;
+        try!(heap.alloc_pair(entry, first + 2));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// `(alist-copy alist)`: copies the entries too.
+fn alist_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (entries, _) = try!(push_keys(heap, first, 0, "alist-copy"));
+        for &entry in &entries {
+            let (key, datum) = {
+                let entry = &heap.stack[entry];
+                (entry.car().unwrap(), entry.cdr().unwrap())
+            };
+            let key = push(heap, key);
+            heap.stack.push(datum);
+            try!(heap.alloc_pair(key, key + 1));
+            let copy = heap.stack.pop().unwrap();
+            heap.stack.truncate(key);
+            heap.stack[entry] = copy
+        }
+        list_of(heap, &entries, Value::new(value::NIL))
+    })
+}
+
+/// `(alist-delete key alist [=])`
+fn alist_delete(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let equality = equality_arg(heap, first, nargs, 2);
+        let (entries, keys) = try!(push_keys(heap, first, 1, "alist-delete"));
+        let mut kept = vec![];
+        for (&entry, &key) in entries.iter().zip(&keys) {
+            if !try!(same(heap, equality, first, key)) {
+                kept.push(entry)
+            }
+        }
+        list_of(heap, &kept, Value::new(value::NIL))
+    })
+}
+
+// Sets
+
+/// Pushes the elements of the list arguments from `start` on, and returns
+/// their indices, list by list.
+fn push_sets(heap: &mut Heap,
+             first: usize,
+             start: usize,
+             nargs: usize,
+             name: &str)
+             -> Result<Vec<Vec<usize>>, String> {
+    let mut sets = vec![];
+    for i in start..nargs {
+        sets.push(try!(push_list(heap, first, i, name)).first())
+    }
+    Ok(sets)
+}
+
+/// Whether every element of `x` is in `y`, according to `equality`.
+fn subset(heap: &mut Heap, equality: usize, x: &[usize], y: &[usize]) -> Result<bool, String> {
+    for &element in x {
+        if !try!(contains(heap, equality, element, y, true)) {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+/// `(lset<= = list ...)`
+fn lset_subset(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let sets = try!(push_sets(heap, first, 1, nargs, "lset<="));
+        for pair in sets.windows(2) {
+            if !try!(subset(heap, first, &pair[0], &pair[1])) {
+                return Ok(boolean(false))
+            }
+        }
+        Ok(boolean(true))
+    })
+}
+
+/// `(lset= = list ...)`
+fn lset_equal(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let sets = try!(push_sets(heap, first, 1, nargs, "lset="));
+        for pair in sets.windows(2) {
+            if !try!(subset(heap, first, &pair[0], &pair[1])) ||
+               !try!(subset(heap, first, &pair[1], &pair[0])) {
+                return Ok(boolean(false))
+            }
+        }
+        Ok(boolean(true))
+    })
+}
+
+/// Adds the elements of `elements` that are not in `set` yet, according to
+/// `equality`.  They are added at the front, as if consed onto it.
+fn adjoin(heap: &mut Heap,
+          equality: usize,
+          set: &mut Vec<usize>,
+          elements: &[usize])
+          -> Result<(), String> {
+    for &element in elements {
+        if !try!(contains(heap, equality, element, set, false)) {
+            set.insert(0, element)
+        }
+    }
+    Ok(())
+}
+
+/// `(lset-adjoin = list elt ...)`
+fn lset_adjoin(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let mut set = try!(push_list(heap, first, 1, "lset-adjoin")).first();
+        let elements: Vec<usize> = (first + 2..first + nargs).collect();
+        try!(adjoin(heap, first, &mut set, &elements));
+        list_of(heap, &set, Value::new(value::NIL))
+    })
+}
+
+/// `(lset-union = list ...)`
+fn lset_union(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let sets = try!(push_sets(heap, first, 1, nargs, "lset-union"));
+        let mut union = vec![];
+        for set in &sets {
+            try!(adjoin(heap, first, &mut union, set))
+        }
+        list_of(heap, &union, Value::new(value::NIL))
+    })
+}
+
+/// Sorts the elements of the first list argument into those that are in
+/// `every` (or any, if it is not set) other list argument, and the others.
+fn sort_first(heap: &mut Heap,
+              first: usize,
+              nargs: usize,
+              every: bool,
+              name: &str)
+              -> Result<(Vec<usize>, Vec<usize>), String> {
+    let sets = try!(push_sets(heap, first, 1, nargs, name));
+    let (mut inside, mut outside) = (vec![], vec![]);
+    for &element in &sets[0] {
+        let mut count = 0;
+        for set in &sets[1..] {
+            if try!(contains(heap, first, element, set, true)) {
+                count += 1
+            }
+        }
+        let found = if every { count == sets.len() - 1 } else { count > 0 };
+        if found {
+            inside.push(element)
+        } else {
+            outside.push(element)
+        }
+    }
+    Ok((inside, outside))
+}
+
+/// `(lset-intersection = list1 list ...)`
+fn lset_intersection(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (inside, _) = try!(sort_first(heap, first, nargs, true, "lset-intersection"));
+        list_of(heap, &inside, Value::new(value::NIL))
+    })
+}
+
+/// `(lset-difference = list1 list ...)`
+fn lset_difference(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (_, outside) = try!(sort_first(heap, first, nargs, false, "lset-difference"));
+        list_of(heap, &outside, Value::new(value::NIL))
+    })
+}
+
+/// `(lset-diff+intersection = list1 list ...)`: the difference and the
+/// intersection with the union of the other lists, as two values.
+fn lset_diff_intersection(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let (inside, outside) = try!(sort_first(heap, first, nargs, false,
+                                                "lset-diff+intersection"));
+        two_lists(heap, &outside, &inside)
+    })
+}
+
+/// `(lset-xor = list ...)`
+fn lset_xor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    with_args(heap, nargs, |heap, first| {
+        let sets = try!(push_sets(heap, first, 1, nargs, "lset-xor"));
+        let mut xor: Vec<usize> = vec![];
+        for set in &sets {
+            let mut next = vec![];
+            for &element in &xor {
+                if !try!(contains(heap, first, element, set, true)) {
+                    next.push(element)
+                }
+            }
+            for &element in set {
+                if !try!(contains(heap, first, element, &xor, false)) {
+                    next.push(element)
+                }
+            }
+            xor = next
+        }
+        list_of(heap, &xor, Value::new(value::NIL))
+    })
+}
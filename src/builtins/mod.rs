@@ -0,0 +1,925 @@
+//! Built-in procedures, implemented in Rust.
+//!
+//! Primitives are not allocated on the GC heap.  A `Value` referring to a
+//! primitive has tag `value::RUST_FUNC_TAG`, and points to a static
+//! `Primitive`.  The GC leaves such values alone.
+//!
+//! Primitives are bound lazily: when a symbol is first interned, its initial
+//! value is the primitive of the same name, if there is one.  This way,
+//! interpreters do not pay for primitives they never use.
+//!
+//! ## Calling convention
+//!
+//! The primitive is followed on the stack by its arguments.  The function
+//! is passed the number of arguments, which are the top values on the
+//! stack.  It may push temporaries, but must leave the stack as it found it,
+//! and return its result.  `call` then replaces the function and arguments
+//! with the result.
+//!
+//! A closure whose code is a primitive can be called like the primitive
+//! itself.  The primitive finds its captured values through `callee`.
+//!
+//! A primitive that panics fails with the panic message instead, and what
+//! it left on the stack is dropped, as for native procedures (see
+//! `native`).
+
+use std::panic::{self, AssertUnwindSafe};
+use alloc::Heap;
+use interp;
+use value::{self, Value};
+
+pub use self::control::{CONTINUATION, bytecode_procedure, is_apply, is_call_cc};
+pub use self::eval::{load, read_source};
+pub use self::fiber::is_yield;
+pub use self::native::{Function, Natives, Pending, alloc_native, is_async_native, is_native,
+                       panic_message};
+
+mod bytevector;
+mod char;
+mod control;
+mod debug;
+mod dynvector;
+mod equiv;
+mod eval;
+mod exception;
+mod fiber;
+mod format;
+mod file;
+mod gc;
+mod hashtable;
+mod json;
+mod list;
+mod native;
+mod number;
+mod pair;
+mod parameter;
+pub mod port;
+mod process;
+mod promise;
+mod random;
+mod record;
+mod socket;
+mod sort;
+mod string;
+mod symbol;
+pub mod thread;
+mod time;
+mod values;
+mod vector;
+mod write;
+
+/// A primitive procedure.
+// Aligned so that bit 3 of a pointer to a primitive is clear, which
+// distinguishes primitives from characters (see `value::CHAR_TAG`).
+#[repr(align(16))]
+pub struct Primitive {
+    /// The name of the global variable bound to the primitive.
+    pub name: &'static str,
+
+    /// The minimum number of arguments.
+    pub min_args: usize,
+
+    /// The maximum number of arguments, or `None` for variadic primitives.
+    pub max_args: Option<usize>,
+
+    /// The implementation.
+    pub function: fn(&mut Heap, usize) -> Result<Value, String>,
+}
+
+/// Every group of primitives.
+static PRIMITIVES: &'static [&'static [Primitive]] = &[&gc::PRIMITIVES,
+                                                      &number::PRIMITIVES,
+                                                      &bytevector::PRIMITIVES,
+                                                      &symbol::PRIMITIVES,
+                                                      &string::PRIMITIVES,
+                                                      &pair::PRIMITIVES,
+                                                      &vector::PRIMITIVES,
+                                                      &dynvector::PRIMITIVES,
+                                                      &sort::PRIMITIVES,
+                                                      &list::PRIMITIVES,
+                                                      &char::PRIMITIVES,
+                                                      &record::PRIMITIVES,
+                                                      &hashtable::PRIMITIVES,
+                                                      &promise::PRIMITIVES,
+                                                      &parameter::PRIMITIVES,
+                                                      &control::PRIMITIVES,
+                                                      &fiber::PRIMITIVES,
+                                                      &thread::PRIMITIVES,
+                                                      &exception::PRIMITIVES,
+                                                      &values::PRIMITIVES,
+                                                      &equiv::PRIMITIVES,
+                                                      &eval::PRIMITIVES,
+                                                      &port::PRIMITIVES,
+                                                      &file::PRIMITIVES,
+                                                      &process::PRIMITIVES,
+                                                      &socket::PRIMITIVES,
+                                                      &time::PRIMITIVES,
+                                                      &random::PRIMITIVES,
+                                                      &json::PRIMITIVES,
+                                                      &write::PRIMITIVES,
+                                                      &format::PRIMITIVES,
+                                                      &debug::PRIMITIVES];
+
+/// A group of primitives that reach outside of the interpreter, which can
+/// be withheld from untrusted code (see `Heap::deny`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Group {
+    /// Files and directories, including `load` and `compile-file`.
+    File,
+
+    /// The process and its environment, and other programs.
+    Process,
+
+    /// Sockets.
+    Network,
+
+    /// Threads and channels.
+    Threads,
+}
+
+/// The primitives outside of `file` that read or write files.
+static FILE_PRIMITIVES: [&'static str; 2] = ["load", "compile-file"];
+
+impl Group {
+    /// Whether the primitive `name` is in this group.
+    pub fn contains(self, name: &str) -> bool {
+        let primitives: &[Primitive] = match self {
+            Group::File if FILE_PRIMITIVES.contains(&name) => return true,
+            Group::File => &file::PRIMITIVES,
+            Group::Process => &process::PRIMITIVES,
+            Group::Network => &socket::PRIMITIVES,
+            Group::Threads => &thread::PRIMITIVES,
+        };
+        primitives.iter().any(|primitive| primitive.name == name)
+    }
+}
+
+impl Primitive {
+    /// The Scheme value referring to this primitive.
+    pub fn to_value(&'static self) -> Value {
+        Value::new(self as *const Primitive as usize | value::RUST_FUNC_TAG)
+    }
+}
+
+/// Looks up the primitive called `name`.
+pub fn lookup(name: &str) -> Option<&'static Primitive> {
+    PRIMITIVES.iter()
+              .flat_map(|group| group.iter())
+              .find(|primitive| primitive.name == name)
+}
+
+/// The names of the primitives bound to global variables.
+pub fn names() -> Vec<&'static str> {
+    PRIMITIVES.iter()
+              .flat_map(|group| group.iter())
+              .map(|primitive| primitive.name)
+              .collect()
+}
+
+/// Calls the procedure that is `args + 1` slots from the top of the stack,
+/// replacing it and its arguments with the result.  Scheme procedures are
+/// run by `interp::call`.
+pub fn call(heap: &mut Heap, args: usize) -> Result<(), String> {
+    let len = heap.stack.len();
+    if args >= len {
+        return Err("Attempt to call a primitive with missing arguments".to_owned())
+    }
+    let primitive = match heap.stack[len - args - 1].kind() {
+        value::Kind::Primitive(primitive) => unsafe { &*primitive },
+        value::Kind::Closure(closure) => {
+            match unsafe { (*closure).code.kind() } {
+                value::Kind::Primitive(primitive) => unsafe { &*primitive },
+                _ => return interp::call(heap, args),
+            }
+        }
+        _ => return Err("Attempt to call a non-procedure".to_owned()),
+    };
+    if args < primitive.min_args || primitive.max_args.map_or(false, |max| args > max) {
+        let arity = (primitive.min_args, primitive.max_args);
+        return Err(arity_error(Some(primitive.name), &[arity], args))
+    }
+    let depth = heap.control.depth();
+    let result = match panic::catch_unwind(AssertUnwindSafe(|| (primitive.function)(heap, args))) {
+        Ok(result) => try!(result),
+        // A panic that unwound through an activation that the primitive
+        // started is a bug in the interpreter, which left it half done.
+        Err(payload) if heap.control.depth() != depth => panic::resume_unwind(payload),
+        Err(payload) => {
+            heap.stack.truncate(len);
+            return Err(format!("{}: panicked: {}",
+                               primitive.name,
+                               native::panic_message(&*payload)))
+        }
+    };
+    debug_assert_eq!(heap.stack.len(), len, "primitive {} unbalanced the stack", primitive.name);
+    heap.stack.truncate(len - args - 1);
+    Ok(heap.stack.push(result))
+}
+
+/// The procedure called with `nargs` arguments: the primitive itself, or a
+/// closure over it.
+pub fn callee(heap: &Heap, nargs: usize) -> Value {
+    heap.stack[heap.stack.len() - nargs - 1].clone()
+}
+
+/// The arguments of a primitive called with `nargs` arguments.  They stay on
+/// the stack, so the copies returned are invalidated by any allocation.
+pub fn args(heap: &Heap, nargs: usize) -> Vec<Value> {
+    heap.stack[heap.stack.len() - nargs..].to_vec()
+}
+
+/// Runs `body`, then drops whatever it left on the stack.
+pub fn balanced<F>(heap: &mut Heap, body: F) -> Result<Value, String>
+    where F: FnOnce(&mut Heap) -> Result<Value, String>
+{
+    let base = heap.stack.len();
+    let result = body(heap);
+    heap.stack.truncate(base);
+    result
+}
+
+/// The message of the error when the procedure `name`, which accepts the
+/// numbers of arguments `arities` (each a minimum and a maximum, if there
+/// is one), is called with `got` arguments.
+pub fn arity_error(name: Option<&str>, arities: &[(usize, Option<usize>)], got: usize) -> String {
+    let mut alternatives: Vec<String> = vec![];
+    for &(min, max) in arities {
+        let alternative = match max {
+            Some(max) if max == min => min.to_string(),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("at least {}", min),
+        };
+        if !alternatives.contains(&alternative) {
+            alternatives.push(alternative)
+        }
+    }
+    let expected = match alternatives.split_last() {
+        Some((last, init)) if !init.is_empty() => format!("{} or {}", init.join(", "), last),
+        _ => alternatives.join(""),
+    };
+    let plural = if expected == "1" || expected == "at least 1" { "" } else { "s" };
+    let message = format!("expected {} argument{}, got {}", expected, plural, got);
+    argument_error(name, &message)
+}
+
+/// The message of the error `message` about the arguments of the procedure
+/// `name`.
+pub fn argument_error(name: Option<&str>, message: &str) -> String {
+    match name {
+        Some(name) => format!("{}, in procedure {}", message, name),
+        None => format!("{}, in anonymous procedure", message),
+    }
+}
+
+/// Converts the argument `value` of `procedure` to a non-negative fixnum.
+pub fn fixnum_arg(value: &Value, procedure: &str) -> Result<usize, String> {
+    if value.get() & 0b11 == 0 && (value.get() as isize) >= 0 {
+        Ok(value.get() >> 2)
+    } else {
+        Err(format!("{}: expected a non-negative fixnum", procedure))
+    }
+}
+
+/// Converts a Rust boolean to `#t` or `#f`.
+pub fn boolean(b: bool) -> Value {
+    Value::new(if b { value::TRUE } else { value::FALSE })
+}
+
+/// Builds an association list mapping symbols to fixnums.
+pub fn alist(heap: &mut Heap, entries: &[(&str, usize)]) -> Result<Value, String> {
+    let base = heap.stack.len();
+    heap.stack.push(Value::new(value::NIL));
+    for &(name, number) in entries.iter().rev() {
+        heap.intern(name);
+        heap.stack.push(Value::new_fixnum(number));
+        try!(heap.alloc_pair(base + 1, base + 2));
+        try!(heap.alloc_pair(base + 3, base));
+        heap.stack[base] = heap.stack.pop().unwrap();
+        heap.stack.truncate(base + 1);
+    }
+    Ok(heap.stack.pop().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use alloc::Heap;
+    use value::{self, Tags, Value};
+
+    #[test]
+    fn gc_stats_is_bound_lazily() {
+        let mut heap = Heap::new(1 << 4);
+        heap.intern("gc-stats");
+        heap.load_global().unwrap();
+        assert_eq!(heap.stack[0].tag(), Tags::RustFunc);
+        super::call(&mut heap, 0).unwrap();
+        assert_eq!(heap.stack.len(), 1);
+        assert_eq!(heap.stack[0].tag(), Tags::Pair);
+    }
+
+    /// Calls the primitive `name` with `args`, returning the result.
+    fn apply(heap: &mut Heap, name: &str, args: &[Value]) -> Result<Value, String> {
+        let primitive = super::lookup(name).unwrap().to_value();
+        apply_procedure(heap, primitive, args)
+    }
+
+    /// Calls the procedure `procedure` with `args`, returning the result.
+    fn apply_procedure(heap: &mut Heap, procedure: Value, args: &[Value]) -> Result<Value, String> {
+        heap.stack.push(procedure);
+        heap.stack.extend_from_slice(args);
+        try!(super::call(heap, args.len()));
+        Ok(heap.stack.pop().unwrap())
+    }
+
+    #[test]
+    fn bytevector_procedures() {
+        let mut heap = Heap::new(1 << 8);
+        let byte = Value::new_fixnum;
+        let bytevector = apply(&mut heap, "bytevector", &[byte(1), byte(2), byte(3)]).unwrap();
+        heap.stack.push(bytevector.clone());
+        assert_eq!(apply(&mut heap, "bytevector?", &[bytevector.clone()]).unwrap(),
+                   Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "bytevector?", &[byte(1)]).unwrap(),
+                   Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "bytevector-length", &[bytevector.clone()]).unwrap(),
+                   byte(3));
+        apply(&mut heap, "bytevector-u8-set!", &[bytevector.clone(), byte(0), byte(9)]).unwrap();
+        assert_eq!(apply(&mut heap, "bytevector-u8-ref", &[bytevector.clone(), byte(0)]).unwrap(),
+                   byte(9));
+        assert!(apply(&mut heap, "bytevector-u8-ref", &[bytevector.clone(), byte(3)]).is_err());
+        assert!(apply(&mut heap, "bytevector-u8-set!", &[bytevector.clone(), byte(0), byte(256)])
+                    .is_err());
+        let copy = apply(&mut heap, "bytevector-copy", &[bytevector.clone(), byte(1)]).unwrap();
+        heap.stack.push(copy.clone());
+        let appended = apply(&mut heap, "bytevector-append", &[bytevector, copy]).unwrap();
+        assert_eq!(unsafe { heap.bytevector_as_slice(&appended).unwrap() }, &[9, 2, 3, 2, 3]);
+        let filled = apply(&mut heap, "make-bytevector", &[byte(4), byte(7)]).unwrap();
+        heap.stack.push(filled.clone());
+        let source = heap.stack[0].clone();
+        apply(&mut heap, "bytevector-copy!", &[filled.clone(), byte(1), source, byte(1)])
+            .unwrap();
+        assert_eq!(unsafe { heap.bytevector_as_slice(&filled).unwrap() }, &[7, 2, 3, 7]);
+    }
+
+    #[test]
+    fn pair_procedures() {
+        let mut heap = Heap::new(1 << 8);
+        let (one, two, nil) = (Value::new_fixnum(1), Value::new_fixnum(2), Value::new(value::NIL));
+        let list = apply(&mut heap, "list", &[one.clone(), two.clone()]).unwrap();
+        heap.stack.push(list.clone());
+        assert_eq!(apply(&mut heap, "car", &[list.clone()]).unwrap(), one);
+        let rest = apply(&mut heap, "cdr", &[list.clone()]).unwrap();
+        assert_eq!(rest.car().unwrap(), two);
+        assert_eq!(rest.cdr().unwrap(), nil);
+        let pair = apply(&mut heap, "cons", &[two.clone(), one.clone()]).unwrap();
+        assert_eq!((pair.car().unwrap(), pair.cdr().unwrap()), (two, one.clone()));
+        assert_eq!(apply(&mut heap, "pair?", &[pair]).unwrap(), Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "pair?", &[nil.clone()]).unwrap(), Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "null?", &[nil]).unwrap(), Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "null?", &[list]).unwrap(), Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "car", &[one]), Err("car: expected a pair".to_owned()));
+        let (one, two, nil) = (Value::new_fixnum(1), Value::new_fixnum(2), Value::new(value::NIL));
+        let pair = apply(&mut heap, "cons", &[one.clone(), nil.clone()]).unwrap();
+        heap.stack.push(pair.clone());
+        apply(&mut heap, "set-car!", &[pair.clone(), two.clone()]).unwrap();
+        apply(&mut heap, "set-cdr!", &[pair.clone(), one.clone()]).unwrap();
+        assert_eq!((pair.car().unwrap(), pair.cdr().unwrap()), (two.clone(), one.clone()));
+        assert_eq!(apply(&mut heap, "set-car!", &[nil, one.clone()]),
+                   Err("set-car!: expected a pair".to_owned()));
+        assert_eq!(apply(&mut heap, "set-cdr!", &[one, two]),
+                   Err("set-cdr!: expected a pair".to_owned()));
+    }
+
+    #[test]
+    fn symbols_are_interned_weakly() {
+        let mut heap = Heap::new(1 << 8);
+        heap.alloc_string("falcon").unwrap();
+        let string = heap.stack[0].clone();
+        let symbol = apply(&mut heap, "string->symbol", &[string.clone()]).unwrap();
+        heap.stack.push(symbol.clone());
+        heap.intern("falcon");
+        assert_eq!(heap.stack[2], symbol);
+        assert_eq!(apply(&mut heap, "symbol?", &[symbol.clone()]).unwrap(),
+                   Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "symbol?", &[string]).unwrap(), Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "symbol=?", &[symbol.clone(), symbol.clone()]).unwrap(),
+                   Value::new(value::TRUE));
+        heap.intern("hawk");
+        let hawk = heap.stack.pop().unwrap();
+        assert_eq!(apply(&mut heap, "symbol=?", &[symbol.clone(), hawk]).unwrap(),
+                   Value::new(value::FALSE));
+        let name = apply(&mut heap, "symbol->string", &[symbol]).unwrap();
+        assert_eq!(unsafe { heap.string_as_str(&name).unwrap() }, "falcon");
+        ::alloc::collect(&mut heap);
+        assert!(heap.symbol_table.lookup("falcon").is_some());
+        assert!(heap.symbol_table.lookup("hawk").is_none());
+        heap.stack.clear();
+        ::alloc::collect(&mut heap);
+        assert!(heap.symbol_table.lookup("falcon").is_none());
+    }
+
+    #[test]
+    fn char_procedures() {
+        let mut heap = Heap::new(1 << 4);
+        let c = Value::new_char;
+        assert_eq!(apply(&mut heap, "char->integer", &[c('λ')]).unwrap(),
+                   Value::new_fixnum(0x3BB));
+        assert_eq!(apply(&mut heap, "integer->char", &[Value::new_fixnum(0x41)]).unwrap(),
+                   c('A'));
+        assert!(apply(&mut heap, "integer->char", &[Value::new_fixnum(0xD800)]).is_err());
+        assert_eq!(apply(&mut heap, "char-upcase", &[c('a')]).unwrap(), c('A'));
+        assert_eq!(apply(&mut heap, "char-upcase", &[c('ß')]).unwrap(), c('ß'));
+        assert_eq!(apply(&mut heap, "char-downcase", &[c('Λ')]).unwrap(), c('λ'));
+        assert_eq!(apply(&mut heap, "char?", &[c('a')]).unwrap(), Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "char?", &[Value::new_fixnum(97)]).unwrap(),
+                   Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "char<?", &[c('a'), c('b'), c('c')]).unwrap(),
+                   Value::new(value::TRUE));
+        assert_eq!(apply(&mut heap, "char<?", &[c('a'), c('c'), c('b')]).unwrap(),
+                   Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "char-ci=?", &[c('a'), c('A')]).unwrap(),
+                   Value::new(value::TRUE));
+        assert!(apply(&mut heap, "char=?", &[c('a'), Value::new_fixnum(97)]).is_err());
+        // Characters are immediates, and survive collection unchanged.
+        heap.stack.clear();
+        heap.stack.push(c('z'));
+        ::alloc::collect(&mut heap);
+        assert_eq!(heap.stack[0].as_char(), Ok('z'));
+    }
+
+    /// Makes a list of `elements`.
+    fn list(heap: &mut Heap, elements: &[Value]) -> Value {
+        let base = heap.stack.len();
+        heap.stack.push(Value::new(value::NIL));
+        for element in elements.iter().rev() {
+            heap.stack.push(element.clone());
+            heap.alloc_pair(base + 1, base).unwrap();
+            heap.stack[base] = heap.stack.pop().unwrap();
+            heap.stack.truncate(base + 1);
+        }
+        heap.stack.pop().unwrap()
+    }
+
+    /// Interns `name`, returning the symbol.
+    fn symbol(heap: &mut Heap, name: &str) -> Value {
+        heap.intern(name);
+        heap.stack.pop().unwrap()
+    }
+
+    #[test]
+    fn record_types() {
+        let mut heap = Heap::new(1 << 8);
+        let (t, f, n) = (Value::new(value::TRUE), Value::new(value::FALSE), Value::new_fixnum);
+        let (mutable, immutable) = (symbol(&mut heap, "mutable"), symbol(&mut heap, "immutable"));
+        let (x, y, z) = (symbol(&mut heap, "x"), symbol(&mut heap, "y"), symbol(&mut heap, "z"));
+        let (point, point3) = (symbol(&mut heap, "point"), symbol(&mut heap, "point3"));
+        // The stack holds the descriptors of point and point3 (a sealed
+        // subtype), a point3, and the point predicate.
+        let stack = |heap: &Heap, i: usize| heap.stack[i].clone();
+        let spec = list(&mut heap, &[mutable.clone(), x]);
+        heap.stack.push(spec);
+        let spec = list(&mut heap, &[immutable, y]);
+        let specs = [heap.stack.pop().unwrap(), spec];
+        let fields = list(&mut heap, &specs);
+        let rtd = apply(&mut heap,
+                        "make-record-type-descriptor",
+                        &[point.clone(), f.clone(), f.clone(), f.clone(), f.clone(), fields])
+                      .unwrap();
+        heap.stack.push(rtd.clone());
+        let spec = list(&mut heap, &[mutable, z]);
+        let fields = list(&mut heap, &[spec]);
+        let rtd = stack(&heap, 0);
+        let rtd3 = apply(&mut heap,
+                         "make-record-type-descriptor",
+                         &[point3, rtd.clone(), f.clone(), t.clone(), f.clone(), fields])
+                       .unwrap();
+        heap.stack.push(rtd3.clone());
+        assert_eq!(apply(&mut heap, "record-type-name", &[rtd.clone()]).unwrap(), point);
+        assert_eq!(apply(&mut heap, "record-type-parent", &[rtd3.clone()]).unwrap(), rtd);
+        assert_eq!(apply(&mut heap, "record-type-descriptor?", &[rtd3.clone()]).unwrap(), t);
+
+        // A point3 is a point, with the inherited fields first.
+        let make_point3 = apply(&mut heap, "record-constructor", &[rtd3.clone()]).unwrap();
+        assert!(apply_procedure(&mut heap, make_point3.clone(), &[n(1), n(2)]).is_err());
+        heap.stack.truncate(2);
+        let p = apply_procedure(&mut heap, make_point3, &[n(1), n(2), n(3)]).unwrap();
+        heap.stack.push(p.clone());
+        let is_point = apply(&mut heap, "record-predicate", &[rtd.clone()]).unwrap();
+        heap.stack.push(is_point.clone());
+        assert_eq!(apply_procedure(&mut heap, is_point.clone(), &[p.clone()]).unwrap(), t);
+        assert_eq!(apply_procedure(&mut heap, is_point, &[n(1)]).unwrap(), f);
+        let z_of = apply(&mut heap, "record-accessor", &[rtd3.clone(), n(0)]).unwrap();
+        assert_eq!(apply_procedure(&mut heap, z_of, &[p.clone()]).unwrap(), n(3));
+
+        // Field 1 of point is immutable, and point3 is sealed.
+        assert_eq!(apply(&mut heap, "record-field-mutable?", &[rtd.clone(), n(1)]).unwrap(), f);
+        assert!(apply(&mut heap, "record-mutator", &[rtd.clone(), n(1)]).is_err());
+        heap.stack.truncate(4);
+        let set_x = apply(&mut heap, "record-mutator", &[rtd.clone(), n(0)]).unwrap();
+        apply_procedure(&mut heap, set_x, &[p, n(10)]).unwrap();
+        assert!(apply(&mut heap,
+                      "make-record-type-descriptor",
+                      &[point, rtd3, f.clone(), f.clone(), f, Value::new(value::NIL)])
+                    .is_err());
+        heap.stack.truncate(4);
+
+        // Records survive collection, with their descriptors.
+        ::alloc::collect(&mut heap);
+        let (rtd, rtd3, p, is_point) =
+            (stack(&heap, 0), stack(&heap, 1), stack(&heap, 2), stack(&heap, 3));
+        let x_of = apply(&mut heap, "record-accessor", &[rtd, n(0)]).unwrap();
+        assert_eq!(apply_procedure(&mut heap, x_of, &[p.clone()]).unwrap(), n(10));
+        assert_eq!(apply(&mut heap, "record-rtd", &[p]).unwrap(), rtd3);
+        assert_eq!(apply_procedure(&mut heap, is_point, &[rtd3]).unwrap(),
+                   Value::new(value::FALSE));
+    }
+
+    #[test]
+    fn hashtables() {
+        let mut heap = Heap::new(1 << 8);
+        let (t, f, n) = (Value::new(value::TRUE), Value::new(value::FALSE), Value::new_fixnum);
+        // The stack holds an eq? table, an equal? table, and a pair key.
+        let stack = |heap: &Heap, i: usize| heap.stack[i].clone();
+        let eq = apply(&mut heap, "make-eq-hashtable", &[]).unwrap();
+        heap.stack.push(eq);
+        let equal = apply(&mut heap, "make-equal-hashtable", &[]).unwrap();
+        heap.stack.push(equal);
+        let key = list(&mut heap, &[n(1), n(2)]);
+        heap.stack.push(key.clone());
+        for i in 0..100 {
+            let eq = stack(&heap, 0);
+            apply(&mut heap, "hashtable-set!", &[eq, n(i), n(i * i)]).unwrap();
+        }
+        let eq = stack(&heap, 0);
+        apply(&mut heap, "hashtable-set!", &[eq.clone(), key.clone(), t.clone()]).unwrap();
+        apply(&mut heap, "hashtable-delete!", &[eq.clone(), n(7)]).unwrap();
+        assert_eq!(apply(&mut heap, "hashtable-size", &[eq.clone()]).unwrap(), n(100));
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[eq.clone(), n(9), f.clone()]).unwrap(),
+                   n(81));
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[eq.clone(), n(7), f.clone()]).unwrap(),
+                   f);
+        // An equal list is not the same key in an eq? table.
+        let other = list(&mut heap, &[n(1), n(2)]);
+        let eq = stack(&heap, 0);
+        assert_eq!(apply(&mut heap, "hashtable-contains?", &[eq, other]).unwrap(), f);
+
+        // equal? tables compare strings by contents.
+        heap.alloc_string("key").unwrap();
+        let string = heap.stack.pop().unwrap();
+        let equal = stack(&heap, 1);
+        apply(&mut heap, "hashtable-set!", &[equal, string, n(1)]).unwrap();
+        heap.alloc_string("key").unwrap();
+        let string = heap.stack.pop().unwrap();
+        let equal = stack(&heap, 1);
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[equal, string, f.clone()]).unwrap(),
+                   n(1));
+
+        // Keys hashed by address are found after the collector moves them.
+        ::alloc::collect(&mut heap);
+        let (eq, key) = (stack(&heap, 0), stack(&heap, 2));
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[eq.clone(), key, f.clone()]).unwrap(),
+                   t);
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[eq, n(99), f.clone()]).unwrap(),
+                   n(9801));
+        heap.alloc_string("key").unwrap();
+        let string = heap.stack.pop().unwrap();
+        let equal = stack(&heap, 1);
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[equal, string, f.clone()]).unwrap(),
+                   n(1));
+
+        // User-supplied hash and equality procedures.
+        let hash = super::lookup("char->integer").unwrap().to_value();
+        let equality = super::lookup("char=?").unwrap().to_value();
+        let custom = apply(&mut heap, "make-hashtable", &[hash, equality]).unwrap();
+        let c = Value::new_char;
+        apply(&mut heap, "hashtable-set!", &[custom.clone(), c('a'), n(1)]).unwrap();
+        assert_eq!(apply(&mut heap, "hashtable-ref", &[custom.clone(), c('a'), f.clone()])
+                       .unwrap(),
+                   n(1));
+        assert!(apply(&mut heap, "hashtable-ref", &[custom, n(1), f]).is_err());
+    }
+
+
+    static THUNK_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A thunk returning a promise of 42, for `%make-lazy-promise`.
+    fn thunk(heap: &mut Heap, _: usize) -> Result<Value, String> {
+        THUNK_CALLS.fetch_add(1, Ordering::SeqCst);
+        heap.stack.push(Value::new_fixnum(42));
+        let value = heap.stack.len() - 1;
+        try!(heap.alloc_promise(true, value));
+        let promise = heap.stack.pop().unwrap();
+        heap.stack.pop();
+        Ok(promise)
+    }
+
+    static THUNK: super::Primitive = super::Primitive {
+        name: "thunk",
+        min_args: 0,
+        max_args: Some(0),
+        function: thunk,
+    };
+
+    #[test]
+    fn promises() {
+        let mut heap = Heap::new(1 << 8);
+        let (t, f, n) = (Value::new(value::TRUE), Value::new(value::FALSE), Value::new_fixnum);
+        let promise = apply(&mut heap, "make-promise", &[n(5)]).unwrap();
+        assert_eq!(apply(&mut heap, "promise?", &[promise.clone()]).unwrap(), t);
+        assert_eq!(apply(&mut heap, "promise?", &[n(5)]).unwrap(), f);
+        assert_eq!(apply(&mut heap, "make-promise", &[promise.clone()]).unwrap(), promise);
+        assert_eq!(apply(&mut heap, "force", &[promise]).unwrap(), n(5));
+        assert_eq!(apply(&mut heap, "force", &[n(6)]).unwrap(), n(6));
+
+        // A lazy promise runs its thunk once, and survives collection.
+        let lazy = apply(&mut heap, "%make-lazy-promise", &[THUNK.to_value()]).unwrap();
+        heap.stack.push(lazy.clone());
+        assert_eq!(apply(&mut heap, "record?", &[lazy]).unwrap(), f);
+        ::alloc::collect(&mut heap);
+        for _ in 0..2 {
+            let lazy = heap.stack[0].clone();
+            assert_eq!(apply(&mut heap, "force", &[lazy]).unwrap(), n(42));
+        }
+        assert_eq!(THUNK_CALLS.load(Ordering::SeqCst), 1);
+        ::alloc::collect(&mut heap);
+        let lazy = heap.stack[0].clone();
+        assert_eq!(apply(&mut heap, "force", &[lazy]).unwrap(), n(42));
+
+        // delay-force must produce a promise.
+        let bad = super::lookup("make-eq-hashtable").unwrap().to_value();
+        let bad = apply(&mut heap, "%make-lazy-promise", &[bad]).unwrap();
+        assert!(apply(&mut heap, "force", &[bad]).is_err());
+    }
+
+
+    /// A producer returning the values 1, 2, and 3.
+    fn one_two_three(heap: &mut Heap, _: usize) -> Result<Value, String> {
+        let n = Value::new_fixnum;
+        apply(heap, "values", &[n(1), n(2), n(3)])
+    }
+
+    static ONE_TWO_THREE: super::Primitive = super::Primitive {
+        name: "one-two-three",
+        min_args: 0,
+        max_args: Some(0),
+        function: one_two_three,
+    };
+
+    #[test]
+    fn multiple_values() {
+        let mut heap = Heap::new(1 << 8);
+        let n = Value::new_fixnum;
+        assert_eq!(apply(&mut heap, "values", &[n(1)]).unwrap(), n(1));
+        let none = apply(&mut heap, "values", &[]).unwrap();
+        match none.kind() {
+            value::Kind::Values(values) => assert_eq!(unsafe { (*values).len() }, 0),
+            _ => panic!("(values) is not a multiple-values object"),
+        }
+        assert_eq!(apply(&mut heap, "record?", &[none]).unwrap(), Value::new(value::FALSE));
+
+        // (call-with-values one-two-three bytevector)
+        let consumer = super::lookup("bytevector").unwrap().to_value();
+        let producer = ONE_TWO_THREE.to_value();
+        let bytevector = apply(&mut heap, "call-with-values", &[producer, consumer]).unwrap();
+        assert_eq!(unsafe { heap.bytevector_as_slice(&bytevector) }.unwrap(), &[1, 2, 3]);
+        assert!(heap.stack.is_empty());
+
+        // A single value is passed as itself.
+        let producer = super::lookup("make-eq-hashtable").unwrap().to_value();
+        let consumer = super::lookup("hashtable?").unwrap().to_value();
+        assert_eq!(apply(&mut heap, "call-with-values", &[producer, consumer]).unwrap(),
+                   Value::new(value::TRUE));
+    }
+
+
+    #[test]
+    fn equivalence_predicates() {
+        let mut heap = Heap::new(1 << 8);
+        let (t, f, n) = (Value::new(value::TRUE), Value::new(value::FALSE), Value::new_fixnum);
+        assert_eq!(apply(&mut heap, "eq?", &[n(1), n(1)]).unwrap(), t);
+        heap.alloc_float(1.5).unwrap();
+        heap.alloc_float(1.5).unwrap();
+        let (x, y) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert_eq!(apply(&mut heap, "eqv?", &[x, y]).unwrap(), t);
+        heap.stack.clear();
+        heap.alloc_string("abc").unwrap();
+        heap.alloc_string("abc").unwrap();
+        let (x, y) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert_eq!(apply(&mut heap, "eqv?", &[x.clone(), y.clone()]).unwrap(), f);
+        assert_eq!(apply(&mut heap, "equal?", &[x, y]).unwrap(), t);
+
+        // Two circular lists (1 2 1 2 ...) and (1 2 1 2 1 2 ...), of
+        // different periods, are equal.
+        heap.stack.clear();
+        let first = list(&mut heap, &[n(1), n(2)]);
+        first.cdr().unwrap().set_cdr(first.clone()).unwrap();
+        heap.stack.push(first);
+        let second = list(&mut heap, &[n(1), n(2), n(1), n(2)]);
+        let mut last = second.clone();
+        while last.cdr().unwrap().get() != value::NIL {
+            last = last.cdr().unwrap()
+        }
+        last.set_cdr(second.clone()).unwrap();
+        heap.stack.push(second);
+        let (first, second) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert_eq!(apply(&mut heap, "equal?", &[first.clone(), second.clone()]).unwrap(), t);
+        assert_eq!(apply(&mut heap, "eqv?", &[first.clone(), second]).unwrap(), f);
+        let third = list(&mut heap, &[n(1), n(3)]);
+        let first = heap.stack[0].clone();
+        assert_eq!(apply(&mut heap, "equal?", &[first, third]).unwrap(), f);
+
+        // Vectors are compared element by element.
+        heap.stack.clear();
+        heap.alloc_string("abc").unwrap();
+        heap.stack.push(n(1));
+        heap.alloc_vector(0, 2).unwrap();
+        heap.alloc_vector(0, 2).unwrap();
+        let (x, y) = (heap.stack[2].clone(), heap.stack[3].clone());
+        assert_eq!(apply(&mut heap, "equal?", &[x.clone(), y]).unwrap(), t);
+        heap.alloc_vector(0, 1).unwrap();
+        let z = heap.stack.pop().unwrap();
+        assert_eq!(apply(&mut heap, "equal?", &[x, z]).unwrap(), f);
+    }
+
+
+    #[test]
+    fn constants_are_immutable() {
+        let mut heap = Heap::new(1 << 8);
+        let n = Value::new_fixnum;
+        heap.alloc_bytevector_from(&[1, 2, 3]).unwrap();
+        let bytevector = heap.stack[0].clone();
+        let list = list(&mut heap, &[n(1), bytevector]);
+        heap.stack.push(list.clone());
+        list.make_constant();
+        assert!(list.immutablep() && list.cdr().unwrap().immutablep());
+        // The immutable bit survives collection.
+        ::alloc::collect(&mut heap);
+        let (bytevector, list) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert!(bytevector.immutablep() && list.immutablep());
+        assert!(apply(&mut heap, "bytevector-u8-set!", &[bytevector.clone(), n(0), n(9)])
+                    .is_err());
+        heap.stack.truncate(2);
+        assert_eq!(apply(&mut heap, "bytevector-u8-ref", &[bytevector.clone(), n(0)]).unwrap(),
+                   n(1));
+        let copy = apply(&mut heap, "bytevector-copy", &[bytevector]).unwrap();
+        assert!(!copy.immutablep());
+    }
+
+
+    #[test]
+    fn datum_labels() {
+        use print::{to_string, Mode};
+        let mut heap = Heap::new(1 << 8);
+        let n = Value::new_fixnum;
+        // A cyclic list.
+        let cycle = list(&mut heap, &[n(1), n(2)]);
+        heap.stack.push(cycle.clone());
+        cycle.cdr().unwrap().set_cdr(cycle.clone()).unwrap();
+        assert_eq!(to_string(&cycle, Mode::Write), "#0=(1 2 . #0#)");
+        assert_eq!(to_string(&cycle, Mode::Shared), "#0=(1 2 . #0#)");
+        // Shared, but acyclic, structure.
+        let shared = list(&mut heap, &[n(1)]);
+        heap.stack.push(shared.clone());
+        let both = list(&mut heap, &[shared.clone(), shared]);
+        heap.stack.push(both.clone());
+        assert_eq!(to_string(&both, Mode::Write), "((1) (1))");
+        assert_eq!(to_string(&both, Mode::Simple), "((1) (1))");
+        assert_eq!(to_string(&both, Mode::Shared), "(#0=(1) #0#)");
+        // A vector containing itself.
+        let base = heap.stack.len();
+        heap.stack.push(both.car().unwrap());
+        heap.stack.push(both.car().unwrap());
+        heap.alloc_string("a \"b\"").unwrap();
+        heap.stack.push(Value::new_char(' '));
+        heap.stack.push(Value::new(value::FALSE));
+        heap.alloc_vector(base, base + 5).unwrap();
+        let vector = heap.stack.pop().unwrap();
+        match vector.kind() {
+            value::Kind::Vector(ptr) => unsafe { (*ptr).element(4).set(vector.clone()) },
+            _ => panic!("not a vector"),
+        }
+        heap.stack.push(vector.clone());
+        assert_eq!(to_string(&vector, Mode::Write),
+                   "#0=#((1) (1) \"a \\\"b\\\"\" #\\space #0#)");
+        assert_eq!(to_string(&vector, Mode::Shared),
+                   "#0=#(#1=(1) #1# \"a \\\"b\\\"\" #\\space #0#)");
+        assert_eq!(to_string(&vector, Mode::Display), "#0=#((1) (1) a \"b\"   #0#)");
+        let strange = symbol(&mut heap, "a b");
+        assert_eq!(to_string(&strange, Mode::Write), "|a b|");
+        assert_eq!(to_string(&strange, Mode::Display), "a b");
+        assert_eq!(to_string(&Value::new(-12isize as usize), Mode::Write), "-3");
+        heap.alloc_float(2.0).unwrap();
+        assert_eq!(to_string(&heap.stack.pop().unwrap(), Mode::Write), "2.0");
+    }
+
+    #[test]
+    fn port_procedures() {
+        use std::cell::RefCell;
+        use std::io::{self, Cursor, Write};
+        use std::rc::Rc;
+        use port::{Buffering, Port};
+        use print::{to_string, Mode};
+        /// A sink whose contents the test can see.
+        struct Sink(Rc<RefCell<Vec<u8>>>);
+        impl Write for Sink {
+            fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().write(bytes)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut heap = Heap::new(1 << 8);
+        let source = Cursor::new("\u{3bb}x\ny".as_bytes().to_vec());
+        heap.alloc_port(Port::input("text", Box::new(source), true)).unwrap();
+        let input = heap.stack[0].clone();
+        let read = |heap: &mut Heap, name| to_string(&apply(heap, name, &[input.clone()]).unwrap(),
+                                                      Mode::Write);
+        assert_eq!(read(&mut heap, "read-char"), "#\\\u{3bb}");
+        assert_eq!(read(&mut heap, "peek-char"), "#\\x");
+        assert_eq!(read(&mut heap, "read-line"), "\"x\"");
+        assert_eq!(read(&mut heap, "read-line"), "\"y\"");
+        assert_eq!(read(&mut heap, "read-char"), "#<eof>");
+        assert_eq!(apply(&mut heap, "read-u8", &[input.clone()]),
+                   Err("read-u8: expected a binary input port".to_owned()));
+        apply(&mut heap, "close-port", &[input.clone()]).unwrap();
+        assert_eq!(apply(&mut heap, "input-port-open?", &[input.clone()]).unwrap(),
+                   Value::new(value::FALSE));
+        assert_eq!(apply(&mut heap, "read-char", &[input.clone()]),
+                   Err("read-char: the port is closed".to_owned()));
+        // Output is buffered until it is flushed.
+        let contents = Rc::new(RefCell::new(vec![]));
+        let sink = Box::new(Sink(contents.clone()));
+        heap.alloc_port(Port::output("sink", sink, false, Buffering::Block)).unwrap();
+        let output = heap.stack[1].clone();
+        let n = Value::new_fixnum;
+        apply(&mut heap, "write-u8", &[n(1), output.clone()]).unwrap();
+        assert!(contents.borrow().is_empty());
+        apply(&mut heap, "flush-output-port", &[output.clone()]).unwrap();
+        assert_eq!(*contents.borrow(), [1]);
+        apply(&mut heap, "write-u8", &[n(2), output.clone()]).unwrap();
+        // Closing flushes too.
+        apply(&mut heap, "close-output-port", &[output.clone()]).unwrap();
+        assert_eq!(*contents.borrow(), [1, 2]);
+        assert_eq!(to_string(&output, Mode::Write), "#<output-port sink>");
+        assert_eq!(apply(&mut heap, "eof-object?", &[Value::new(value::EOF)]).unwrap(),
+                   Value::new(value::TRUE));
+    }
+
+    #[test]
+    fn memory_ports() {
+        use print::{to_string, Mode};
+        let mut heap = Heap::new(1 << 8);
+        let n = Value::new_fixnum;
+        heap.alloc_bytevector_from(&[1, 2, 3]).unwrap();
+        let bytes = heap.stack.pop().unwrap();
+        let input = apply(&mut heap, "open-input-bytevector", &[bytes]).unwrap();
+        heap.stack.push(input.clone());
+        heap.alloc_bytevector_from(&[0; 4]).unwrap();
+        let buffer = heap.stack.pop().unwrap();
+        assert_eq!(apply(&mut heap, "read-u8", &[input.clone()]).unwrap(), n(1));
+        assert_eq!(apply(&mut heap, "read-bytevector!", &[buffer.clone(), input.clone(), n(1)])
+                       .unwrap(),
+                   n(2));
+        assert_eq!(to_string(&buffer, Mode::Write), "#u8(0 2 3 0)");
+        assert_eq!(apply(&mut heap, "read-bytevector!", &[buffer.clone(), input.clone()]).unwrap(),
+                   Value::new(value::EOF));
+        let output = apply(&mut heap, "open-output-bytevector", &[]).unwrap();
+        heap.stack.push(output.clone());
+        apply(&mut heap, "write-u8", &[n(7), output.clone()]).unwrap();
+        apply(&mut heap, "write-bytevector", &[buffer, output.clone(), n(1), n(3)]).unwrap();
+        let written = apply(&mut heap, "get-output-bytevector", &[output.clone()]).unwrap();
+        assert_eq!(to_string(&written, Mode::Write), "#u8(7 2 3)");
+        assert_eq!(apply(&mut heap, "get-output-string", &[output]),
+                   Err("get-output-string: expected a string output port".to_owned()));
+        let output = apply(&mut heap, "open-output-string", &[]).unwrap();
+        heap.stack.push(output.clone());
+        apply(&mut heap, "write-char", &[Value::new_char('\u{3bb}'), output.clone()]).unwrap();
+        let written = apply(&mut heap, "get-output-string", &[output]).unwrap();
+        assert_eq!(to_string(&written, Mode::Write), "\"\u{3bb}\"");
+    }
+
+    #[test]
+    fn read_and_write_through_ports() {
+        use print::{to_string, Mode};
+        let mut heap = Heap::new(1 << 8);
+        heap.alloc_string("(a . b) c").unwrap();
+        let source = heap.stack.pop().unwrap();
+        let input = apply(&mut heap, "open-input-string", &[source]).unwrap();
+        heap.stack.push(input.clone());
+        let read = |heap: &mut Heap, name| to_string(&apply(heap, name, &[input.clone()]).unwrap(),
+                                                      Mode::Write);
+        assert_eq!(read(&mut heap, "read"), "(a . b)");
+        assert_eq!(read(&mut heap, "read-char"), "#\\space");
+        assert_eq!(read(&mut heap, "read"), "c");
+        assert_eq!(read(&mut heap, "read"), "#<eof>");
+        let output = apply(&mut heap, "open-output-string", &[]).unwrap();
+        heap.stack.push(output.clone());
+        heap.alloc_string("x").unwrap();
+        let x = heap.stack.pop().unwrap();
+        apply(&mut heap, "write", &[x.clone(), output.clone()]).unwrap();
+        apply(&mut heap, "display", &[x, output.clone()]).unwrap();
+        let written = apply(&mut heap, "get-output-string", &[output]).unwrap();
+        assert_eq!(to_string(&written, Mode::Display), "\"x\"x");
+    }
+}
@@ -0,0 +1,177 @@
+//! Procedures implemented by Rust closures that the embedder registers
+//! (see `api::Interpreter::define_fn`).
+//!
+//! The closures are kept in the heap's `Natives` table, and are never freed.
+//! A native procedure is a closure over the primitive `NATIVE`, capturing
+//! the index of its Rust closure in the table as a fixnum.  Its arguments
+//! are rooted while the Rust closure runs, so the closure may allocate.
+//!
+//! A Rust closure that panics does not unwind through the interpreter: the
+//! panic is caught, whatever the closure left on the stack is dropped, and
+//! the call fails with the panic message, which raises it as a condition.
+//! So does a future of an async native procedure that panics when polled
+//! (see `interp::poll_await`), and a primitive (see `builtins::call`).
+//! The closure cannot run Scheme code, and can only change the heap through
+//! operations that are complete or have not started when it panics, so the
+//! heap and the control state are as consistent as after an error.  What
+//! the closure did before it panicked is not undone.  A panic in the
+//! interpreter itself is a bug, and is not caught.
+//!
+//! An async native procedure's closure returns a future instead of a value
+//! (see `api::Interpreter::define_async_fn`).  Called by Scheme code in the
+//! outermost activation, it hands the future to `Control::await_future`,
+//! and the interpreter suspends the fiber until the future completes (see
+//! `interp::Control`).  Called from anywhere else, such as a primitive or
+//! Rust code, it fails, since nothing could wait for the future.
+
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::rc::Rc;
+use alloc::{Heap, Root};
+use api::{Context, Error, ToScheme};
+use value::{self, Kind, Value};
+use super::{Primitive, args, arity_error, callee};
+
+/// A Rust closure callable from Scheme, given its arguments.
+pub type NativeFn = dyn Fn(&mut Context, &[Root]) -> Result<Root, Error>;
+
+/// What an async native procedure returns: a future of its result.
+pub type Pending = Pin<Box<dyn Future<Output = Result<Box<dyn ToScheme>, Error>>>>;
+
+/// A Rust closure callable from Scheme that returns a future, given its
+/// arguments.
+pub type AsyncNativeFn = dyn Fn(&mut Context, &[Root]) -> Result<Pending, Error>;
+
+/// The Rust closure of a native procedure.
+#[derive(Clone)]
+pub enum Function {
+    Sync(Rc<NativeFn>),
+    Async(Rc<AsyncNativeFn>),
+}
+
+struct Native {
+    name: String,
+    min_args: usize,
+    max_args: Option<usize>,
+    function: Function,
+}
+
+/// The registered Rust closures, by index.
+#[derive(Default)]
+pub struct Natives {
+    natives: Vec<Native>,
+}
+
+impl fmt::Debug for Natives {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.natives.iter().map(|native| &native.name)).finish()
+    }
+}
+
+static NATIVE: Primitive = Primitive {
+    name: "native-procedure",
+    min_args: 0,
+    max_args: None,
+    function: native,
+};
+
+/// Registers `function` as the procedure `name`, which takes from
+/// `min_args` to `max_args` arguments, and pushes the procedure.
+pub fn alloc_native(heap: &mut Heap,
+                    name: &str,
+                    min_args: usize,
+                    max_args: Option<usize>,
+                    function: Function)
+                    -> Result<(), String> {
+    let index = heap.natives.natives.len();
+    heap.natives.natives.push(Native {
+        name: name.to_owned(),
+        min_args: min_args,
+        max_args: max_args,
+        function: function,
+    });
+    let start = heap.stack.len();
+    heap.stack.push(NATIVE.to_value());
+    heap.stack.push(Value::new_fixnum(index));
+    let result = heap.alloc_closure(start, start + 2);
+    let procedure = heap.stack.pop();
+    heap.stack.truncate(start);
+    try!(result);
+    Ok(heap.stack.push(procedure.unwrap()))
+}
+
+/// Whether `procedure` is a native procedure.
+pub fn is_native(procedure: &Value) -> bool {
+    match procedure.kind() {
+        Kind::Closure(closure) => unsafe { (*closure).code == NATIVE.to_value() },
+        _ => false,
+    }
+}
+
+/// Whether `procedure` is an async native procedure.
+pub fn is_async_native(heap: &Heap, procedure: &Value) -> bool {
+    is_native(procedure) &&
+    match heap.natives.natives[index(procedure)].function {
+        Function::Async(_) => true,
+        Function::Sync(_) => false,
+    }
+}
+
+/// The index of the Rust closure of the native procedure `procedure`.
+fn index(procedure: &Value) -> usize {
+    match procedure.kind() {
+        Kind::Closure(closure) => unsafe { (*closure).captured(0) }.as_fixnum().unwrap(),
+        _ => bug!("native procedure called without its closure"),
+    }
+}
+
+/// Calling a native procedure.
+fn native(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let index = index(&callee(heap, nargs));
+    let (function, name) = {
+        let native = &heap.natives.natives[index];
+        if nargs < native.min_args || native.max_args.map_or(false, |max| nargs > max) {
+            let arity = (native.min_args, native.max_args);
+            return Err(arity_error(Some(&native.name), &[arity], nargs))
+        }
+        (native.function.clone(), native.name.clone())
+    };
+    let args: Vec<Root> = args(heap, nargs).into_iter().map(|arg| heap.root(arg)).collect();
+    let len = heap.stack.len();
+    let awaitable = heap.control.take_awaitable();
+    let result = {
+        let cx = &mut Context::new(heap);
+        panic::catch_unwind(AssertUnwindSafe(|| match function {
+            Function::Sync(ref function) => function(cx, &args).map(|result| result.get()),
+            Function::Async(_) if !awaitable => Err(Error::new("cannot wait for it here")),
+            Function::Async(ref function) => {
+                function(cx, &args).map(|future| {
+                    cx.heap().control.await_future(name.clone(), future);
+                    Value::new(value::UNSPECIFIED)
+                })
+            }
+        }))
+    };
+    match result {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => Err(format!("{}: {}", name, e)),
+        Err(payload) => {
+            heap.stack.truncate(len);
+            Err(format!("{}: panicked: {}", name, panic_message(&*payload)))
+        }
+    }
+}
+
+/// The message of a panic, from its payload.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
@@ -0,0 +1,241 @@
+//! Comparing numbers, changing their exactness, and converting them to and
+//! from strings.
+//!
+//! The numbers are fixnums, which are exact, and flonums, which are not.
+//! `number->string` writes flonums with the fewest digits that read back as
+//! the same flonum (see `print`), so writing a number and reading it back
+//! gives the same number.
+
+use std::cmp::Ordering;
+use alloc::Heap;
+use arith;
+use print::{self, Mode};
+use read::{self, Event};
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 12] =
+    [Primitive {
+         name: "=",
+         min_args: 1,
+         max_args: None,
+         function: number_eq,
+     },
+     Primitive {
+         name: "<",
+         min_args: 1,
+         max_args: None,
+         function: number_lt,
+     },
+     Primitive {
+         name: ">",
+         min_args: 1,
+         max_args: None,
+         function: number_gt,
+     },
+     Primitive {
+         name: "<=",
+         min_args: 1,
+         max_args: None,
+         function: number_le,
+     },
+     Primitive {
+         name: ">=",
+         min_args: 1,
+         max_args: None,
+         function: number_ge,
+     },
+     Primitive {
+         name: "number?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_number,
+     },
+     Primitive {
+         name: "exact?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_exact,
+     },
+     Primitive {
+         name: "inexact?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_inexact,
+     },
+     Primitive {
+         name: "exact",
+         min_args: 1,
+         max_args: Some(1),
+         function: exact,
+     },
+     Primitive {
+         name: "inexact",
+         min_args: 1,
+         max_args: Some(1),
+         function: inexact,
+     },
+     Primitive {
+         name: "number->string",
+         min_args: 1,
+         max_args: Some(2),
+         function: number_to_string,
+     },
+     Primitive {
+         name: "string->number",
+         min_args: 1,
+         max_args: Some(2),
+         function: string_to_number,
+     }];
+
+/// Checks that every argument is a number, and that `ordered` holds for
+/// each pair of adjacent arguments.  Nothing is ordered with a NaN.
+fn compare(heap: &Heap,
+           nargs: usize,
+           procedure: &str,
+           ordered: fn(Ordering) -> bool)
+           -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let mut result = true;
+    for pair in args.windows(2) {
+        let order = try!(arith::compare(&pair[0], &pair[1])
+                             .map_err(|_| format!("{}: expected a number", procedure)));
+        result = result && order.map_or(false, ordered)
+    }
+    if nargs == 1 && arith::to_float(&args[0]).is_none() {
+        return Err(format!("{}: expected a number", procedure))
+    }
+    Ok(boolean(result))
+}
+
+/// `(= z1 z2 ...)`
+fn number_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "=", |order| order == Ordering::Equal)
+}
+
+/// `(< x1 x2 ...)`
+fn number_lt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "<", |order| order == Ordering::Less)
+}
+
+/// `(> x1 x2 ...)`
+fn number_gt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, ">", |order| order == Ordering::Greater)
+}
+
+/// `(<= x1 x2 ...)`
+fn number_le(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "<=", |order| order != Ordering::Greater)
+}
+
+/// `(>= x1 x2 ...)`
+fn number_ge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, ">=", |order| order != Ordering::Less)
+}
+
+/// Checks that `value`, an argument of `procedure`, is a number.
+fn number_arg(value: &Value, procedure: &str) -> Result<(), String> {
+    match arith::to_float(value) {
+        Some(_) => Ok(()),
+        None => Err(format!("{}: expected a number", procedure)),
+    }
+}
+
+/// The radix argument of `procedure`, if it was called with `nargs`
+/// arguments, or 10.
+fn radix_arg(heap: &Heap, nargs: usize, procedure: &str) -> Result<u32, String> {
+    if nargs < 2 {
+        return Ok(10)
+    }
+    match try!(fixnum_arg(&args(heap, nargs)[1], procedure)) {
+        radix @ 2 | radix @ 8 | radix @ 10 | radix @ 16 => Ok(radix as u32),
+        _ => Err(format!("{}: the radix must be 2, 8, 10, or 16", procedure)),
+    }
+}
+
+/// `(number? obj)`
+fn is_number(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(arith::to_float(&args(heap, nargs)[0]).is_some()))
+}
+
+/// `(exact? z)`
+fn is_exact(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = &args(heap, nargs)[0];
+    try!(number_arg(value, "exact?"));
+    Ok(boolean(value.fixnump()))
+}
+
+/// `(inexact? z)`
+fn is_inexact(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = &args(heap, nargs)[0];
+    try!(number_arg(value, "inexact?"));
+    Ok(boolean(!value.fixnump()))
+}
+
+/// `(exact z)`: only flonums that are integers within the range of fixnums
+/// have exact counterparts, since there are no bignums or exact rationals.
+fn exact(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = args(heap, nargs)[0].clone();
+    if value.fixnump() {
+        return Ok(value)
+    }
+    let float = match arith::to_float(&value) {
+        Some(float) => float,
+        None => return Err("exact: expected a number".to_owned()),
+    };
+    let limit = (1usize << (value::FIXNUM_WORD_BITS - 3)) as f64;
+    if float == float.trunc() && float.abs() < limit {
+        Ok(Value::new(((float as isize) << 2) as usize))
+    } else {
+        Err(format!("exact: {} has no exact representation",
+                    print::to_string(&value, Mode::Write)))
+    }
+}
+
+/// `(inexact z)`
+fn inexact(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = args(heap, nargs)[0].clone();
+    match arith::to_float(&value) {
+        Some(_) if !value.fixnump() => Ok(value),
+        Some(float) => arith::new_float(heap, float),
+        None => Err("inexact: expected a number".to_owned()),
+    }
+}
+
+/// `(number->string z [radix])`: flonums can only be written in decimal.
+fn number_to_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let radix = try!(radix_arg(heap, nargs, "number->string"));
+    let value = args(heap, nargs)[0].clone();
+    let string = match value.kind() {
+        Kind::Fixnum(_) => print::integer_to_string(value.get() as isize >> 2, radix),
+        Kind::Float(_) if radix == 10 => print::to_string(&value, Mode::Write),
+        Kind::Float(_) => {
+            return Err("number->string: inexact numbers can only be written in radix 10"
+                           .to_owned())
+        }
+        _ => return Err("number->string: expected a number".to_owned()),
+    };
+    try!(heap.alloc_string(&string));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(string->number string [radix])`: the number that `string` would be
+/// read as, or `#f`.  Radix and exactness prefixes are allowed, and a radix
+/// prefix overrides `radix`.
+fn string_to_number(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let radix = try!(radix_arg(heap, nargs, "string->number"));
+    let string = match args(heap, nargs)[0].kind() {
+        Kind::String(s) => unsafe { (*s).as_str() }.to_owned(),
+        _ => return Err("string->number: expected a string".to_owned()),
+    };
+    match read::parse_number_in(&string, radix) {
+        Some(Event::Int(n)) => {
+            match n.checked_mul(4).and_then(Value::from_fixnum_word) {
+                Some(value) => Ok(value),
+                None => arith::new_float(heap, n as f64),
+            }
+        }
+        Some(Event::Float(f)) => arith::new_float(heap, f),
+        _ => Ok(Value::new(value::FALSE)),
+    }
+}
@@ -0,0 +1,128 @@
+//! Pairs and lists.
+
+use alloc::Heap;
+use value::{self, Value};
+use super::{Primitive, args, boolean};
+
+pub static PRIMITIVES: [Primitive; 8] =
+    [Primitive {
+         name: "pair?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_pair,
+     },
+     Primitive {
+         name: "null?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_null,
+     },
+     Primitive {
+         name: "cons",
+         min_args: 2,
+         max_args: Some(2),
+         function: cons,
+     },
+     Primitive {
+         name: "car",
+         min_args: 1,
+         max_args: Some(1),
+         function: car,
+     },
+     Primitive {
+         name: "cdr",
+         min_args: 1,
+         max_args: Some(1),
+         function: cdr,
+     },
+     Primitive {
+         name: "set-car!",
+         min_args: 2,
+         max_args: Some(2),
+         function: set_car,
+     },
+     Primitive {
+         name: "set-cdr!",
+         min_args: 2,
+         max_args: Some(2),
+         function: set_cdr,
+     },
+     Primitive {
+         name: "list",
+         min_args: 0,
+         max_args: None,
+         function: list,
+     }];
+
+/// `(pair? obj)`
+fn is_pair(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].tag() == value::Tags::Pair))
+}
+
+/// `(null? obj)`
+fn is_null(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].get() == value::NIL))
+}
+
+/// `(cons obj1 obj2)`
+fn cons(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = heap.stack.len();
+    try!(heap.alloc_pair(len - nargs, len - 1));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(car pair)`
+fn car(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    args(heap, nargs)[0].car().map_err(|()| "car: expected a pair".to_owned())
+}
+
+/// `(cdr pair)`
+fn cdr(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    args(heap, nargs)[0].cdr().map_err(|()| "cdr: expected a pair".to_owned())
+}
+
+/// The pair `value`, an argument of the primitive `name` that changes it.
+fn mutable_pair_arg(value: &Value, name: &str) -> Result<(), String> {
+    if value.tag() != value::Tags::Pair {
+        return Err(format!("{}: expected a pair", name))
+    }
+    if value.immutablep() {
+        return Err(format!("{}: cannot modify a constant pair", name))
+    }
+    Ok(())
+}
+
+/// `(set-car! pair obj)`
+fn set_car(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    try!(mutable_pair_arg(&args[0], "set-car!"));
+    args[0].set_car(args[1].clone()).unwrap();
+    heap.write_barrier(&args[0], &args[1]);
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(set-cdr! pair obj)`
+fn set_cdr(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    try!(mutable_pair_arg(&args[0], "set-cdr!"));
+    args[0].set_cdr(args[1].clone()).unwrap();
+    heap.write_barrier(&args[0], &args[1]);
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(list obj ...)`
+pub fn list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = heap.stack.len();
+    heap.stack.push(Value::new(value::NIL));
+    let mut result = Ok(());
+    for i in (len - nargs..len).rev() {
+        let cdr = heap.stack.len() - 1;
+        result = heap.alloc_pair(i, cdr);
+        if result.is_err() {
+            break
+        }
+    }
+    let list = heap.stack.pop().unwrap();
+    heap.stack.truncate(len);
+    result.map(|()| list)
+}
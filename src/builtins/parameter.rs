@@ -0,0 +1,127 @@
+//! R7RS parameter objects.
+//!
+//! A parameter object is a closure over `PARAMETER`, capturing its initial
+//! value and its converter (or `#f`).  `parameterize` gives it new values
+//! for the dynamic extent of its body, through the parameterization (see
+//! `interp::Control`), which continuations save and restore.
+//!
+//! `(parameterize ((param value) ...) body ...)` evaluates the parameters
+//! and values, converts each value with `%parameter-convert`, and then
+//! calls `%parameterize` once for each parameter, with a thunk for the
+//! rest of the form.  The interpreter runs `%parameterize` as bytecode
+//! (see `interp::Procedure`).
+//!
+//! The current ports are primitives that act as parameter objects (see
+//! `port`).
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, call, callee};
+use super::control::call_bytecode_procedure;
+use super::port;
+
+pub static PRIMITIVES: [Primitive; 3] =
+    [Primitive {
+         name: "make-parameter",
+         min_args: 1,
+         max_args: Some(2),
+         function: make_parameter,
+     },
+     Primitive {
+         name: "%parameter-convert",
+         min_args: 2,
+         max_args: Some(2),
+         function: parameter_convert,
+     },
+     Primitive {
+         name: "%parameterize",
+         min_args: 3,
+         max_args: Some(3),
+         function: call_bytecode_procedure,
+     }];
+
+/// The code of every parameter object.  It is not bound to a global
+/// variable.
+pub static PARAMETER: Primitive = Primitive {
+    name: "parameter",
+    min_args: 0,
+    max_args: Some(0),
+    function: parameter,
+};
+
+/// The captured value at `index` of `value`, if it is a parameter object:
+/// its initial value or its converter.
+fn parameter_part(value: &Value, index: usize) -> Option<Value> {
+    match value.kind() {
+        Kind::Closure(closure) => unsafe {
+            match (*closure).code.kind() {
+                Kind::Primitive(primitive) if primitive == &PARAMETER as *const Primitive => {
+                    Some((*closure).captured(index).clone())
+                }
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Converts the value on top of the stack with `converter`, unless it is
+/// `#f`, popping it.
+fn convert(heap: &mut Heap, converter: Value) -> Result<Value, String> {
+    if converter.get() == value::FALSE {
+        return Ok(heap.stack.pop().unwrap())
+    }
+    let value = heap.stack.pop().unwrap();
+    heap.stack.push(converter);
+    heap.stack.push(value);
+    try!(call(heap, 1));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(make-parameter value [converter])`
+fn make_parameter(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    let converter = |heap: &Heap| if nargs == 2 {
+        heap.stack[first + 1].clone()
+    } else {
+        Value::new(value::FALSE)
+    };
+    let start = heap.stack.len();
+    heap.stack.push(PARAMETER.to_value());
+    let value = heap.stack[first].clone();
+    heap.stack.push(value);
+    let result = convert(heap, converter(heap))
+                     .and_then(|value| {
+                         heap.stack.push(value);
+                         let converter = converter(heap);
+                         heap.stack.push(converter);
+                         heap.alloc_closure(start, start + 3)
+                     })
+                     .map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    result
+}
+
+/// `(%parameter-convert parameter value)`: `value`, converted by the
+/// converter of `parameter`.
+fn parameter_convert(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    if let Some(which) = port::current_port_index(&args[0]) {
+        return port::convert_current(which, &args[1])
+    }
+    let converter = match parameter_part(&args[0], 1) {
+        Some(converter) => converter,
+        None => return Err("parameterize: not a parameter object".to_owned()),
+    };
+    heap.stack.push(args[1].clone());
+    convert(heap, converter)
+}
+
+/// Returns the current value of the parameter object being called.
+fn parameter(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let parameter = callee(heap, nargs);
+    match heap.control.parameter(&parameter) {
+        Some(value) => Ok(value),
+        None => Ok(parameter_part(&parameter, 0).unwrap()),
+    }
+}
@@ -0,0 +1,761 @@
+//! R7RS ports, and the procedures that read and write bytes and characters
+//! through them.
+//!
+//! `current-input-port`, `current-output-port` and `current-error-port`
+//! act as parameter objects, which `parameterize` accepts: their values are
+//! in the parameterization, and their initial values are the standard
+//! ports of the interpreter, made on first use (see
+//! `interp::Control::standard_ports`), reading and writing through the
+//! interpreter's `Console`.  Standard output is line buffered, and
+//! standard error is not buffered.
+//!
+//! Procedures that read or write take the port as an optional argument,
+//! which defaults to the current input or output port.  At the end of the
+//! input, they return the EOF object.
+//!
+//! String and bytevector ports read from a copy of their string or
+//! bytevector, and keep what is written to them in memory, until
+//! `get-output-string` or `get-output-bytevector` copies it out.
+
+use alloc::Heap;
+use port::{Buffering, Bytes, Port, WOULD_BLOCK};
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, callee, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 40] =
+    [Primitive {
+         name: "current-input-port",
+         min_args: 0,
+         max_args: Some(0),
+         function: current_port,
+     },
+     Primitive {
+         name: "current-output-port",
+         min_args: 0,
+         max_args: Some(0),
+         function: current_port,
+     },
+     Primitive {
+         name: "current-error-port",
+         min_args: 0,
+         max_args: Some(0),
+         function: current_port,
+     },
+     Primitive {
+         name: "port?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_port,
+     },
+     Primitive {
+         name: "input-port?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_input_port,
+     },
+     Primitive {
+         name: "output-port?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_output_port,
+     },
+     Primitive {
+         name: "textual-port?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_textual_port,
+     },
+     Primitive {
+         name: "binary-port?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_binary_port,
+     },
+     Primitive {
+         name: "input-port-open?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_input_port_open,
+     },
+     Primitive {
+         name: "output-port-open?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_output_port_open,
+     },
+     Primitive {
+         name: "close-port",
+         min_args: 1,
+         max_args: Some(1),
+         function: close_port,
+     },
+     Primitive {
+         name: "close-input-port",
+         min_args: 1,
+         max_args: Some(1),
+         function: close_input_port,
+     },
+     Primitive {
+         name: "close-output-port",
+         min_args: 1,
+         max_args: Some(1),
+         function: close_output_port,
+     },
+     Primitive {
+         name: "eof-object",
+         min_args: 0,
+         max_args: Some(0),
+         function: eof_object,
+     },
+     Primitive {
+         name: "eof-object?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_eof_object,
+     },
+     Primitive {
+         name: "read-char",
+         min_args: 0,
+         max_args: Some(1),
+         function: read_char,
+     },
+     Primitive {
+         name: "peek-char",
+         min_args: 0,
+         max_args: Some(1),
+         function: peek_char,
+     },
+     Primitive {
+         name: "read-line",
+         min_args: 0,
+         max_args: Some(1),
+         function: read_line,
+     },
+     Primitive {
+         name: "read-string",
+         min_args: 1,
+         max_args: Some(2),
+         function: read_string,
+     },
+     Primitive {
+         name: "char-ready?",
+         min_args: 0,
+         max_args: Some(1),
+         function: char_ready,
+     },
+     Primitive {
+         name: "read-u8",
+         min_args: 0,
+         max_args: Some(1),
+         function: read_u8,
+     },
+     Primitive {
+         name: "peek-u8",
+         min_args: 0,
+         max_args: Some(1),
+         function: peek_u8,
+     },
+     Primitive {
+         name: "u8-ready?",
+         min_args: 0,
+         max_args: Some(1),
+         function: u8_ready,
+     },
+     Primitive {
+         name: "read-bytevector",
+         min_args: 1,
+         max_args: Some(2),
+         function: read_bytevector,
+     },
+     Primitive {
+         name: "read-bytevector!",
+         min_args: 1,
+         max_args: Some(4),
+         function: read_bytevector_into,
+     },
+     Primitive {
+         name: "write-char",
+         min_args: 1,
+         max_args: Some(2),
+         function: write_char,
+     },
+     Primitive {
+         name: "write-string",
+         min_args: 1,
+         max_args: Some(4),
+         function: write_string,
+     },
+     Primitive {
+         name: "newline",
+         min_args: 0,
+         max_args: Some(1),
+         function: newline,
+     },
+     Primitive {
+         name: "write-u8",
+         min_args: 1,
+         max_args: Some(2),
+         function: write_u8,
+     },
+     Primitive {
+         name: "write-bytevector",
+         min_args: 1,
+         max_args: Some(4),
+         function: write_bytevector,
+     },
+     Primitive {
+         name: "flush-output-port",
+         min_args: 0,
+         max_args: Some(1),
+         function: flush_output_port,
+     },
+     Primitive {
+         name: "port-buffering",
+         min_args: 1,
+         max_args: Some(1),
+         function: port_buffering,
+     },
+     Primitive {
+         name: "set-port-buffering!",
+         min_args: 2,
+         max_args: Some(2),
+         function: set_port_buffering,
+     },
+     Primitive {
+         name: "port-name",
+         min_args: 1,
+         max_args: Some(1),
+         function: port_name,
+     },
+     Primitive {
+         name: "open-input-string",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_input_string,
+     },
+     Primitive {
+         name: "open-output-string",
+         min_args: 0,
+         max_args: Some(0),
+         function: open_output_string,
+     },
+     Primitive {
+         name: "get-output-string",
+         min_args: 1,
+         max_args: Some(1),
+         function: get_output_string,
+     },
+     Primitive {
+         name: "open-input-bytevector",
+         min_args: 1,
+         max_args: Some(1),
+         function: open_input_bytevector,
+     },
+     Primitive {
+         name: "open-output-bytevector",
+         min_args: 0,
+         max_args: Some(0),
+         function: open_output_bytevector,
+     },
+     Primitive {
+         name: "get-output-bytevector",
+         min_args: 1,
+         max_args: Some(1),
+         function: get_output_bytevector,
+     }];
+
+/// Which of the current ports: input, output or error.
+pub const INPUT: usize = 0;
+pub const OUTPUT: usize = 1;
+pub const ERROR: usize = 2;
+
+/// Which current port `value` is the parameter of, if it is one.
+pub fn current_port_index(value: &Value) -> Option<usize> {
+    match value.kind() {
+        Kind::Primitive(primitive) => {
+            PRIMITIVES[..3].iter().position(|current| current as *const Primitive == primitive)
+        }
+        _ => None,
+    }
+}
+
+/// The current port `which`.
+pub fn current(heap: &mut Heap, which: usize) -> Result<Value, String> {
+    if let Some(port) = heap.control.parameter(&PRIMITIVES[which].to_value()) {
+        return Ok(port)
+    }
+    if let Some(ref port) = heap.control.standard_ports[which] {
+        return Ok(port.get())
+    }
+    let console = heap.control.console.clone();
+    let port = match which {
+        INPUT => Port::input("stdin", console.input(), true),
+        OUTPUT => Port::output("stdout", console.output(), true, Buffering::Line),
+        _ => Port::output("stderr", console.error(), true, Buffering::None),
+    };
+    try!(heap.alloc_port(port));
+    let port = heap.stack.pop().unwrap();
+    heap.control.standard_ports[which] = Some(heap.root(port.clone()));
+    Ok(port)
+}
+
+/// Flushes the current output and error ports.
+pub fn flush_current(heap: &mut Heap) -> Result<(), String> {
+    for &which in &[OUTPUT, ERROR] {
+        let port = try!(current(heap, which));
+        match state(&port) {
+            Some(port) if port.is_open() => try!(port.flush()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `value` can be the value of the current port `which`, for
+/// `parameterize`.
+pub fn convert_current(which: usize, value: &Value) -> Result<Value, String> {
+    match state(value) {
+        Some(port) if port.is_input() == (which == INPUT) => Ok(value.clone()),
+        _ if which == INPUT => Err("parameterize: expected an input port".to_owned()),
+        _ => Err("parameterize: expected an output port".to_owned()),
+    }
+}
+
+/// The state of `value`, if it is a port.
+pub fn state<'a>(value: &Value) -> Option<&'a mut Port> {
+    match value.kind() {
+        Kind::Port(port) => Some(unsafe { (*port).state() }),
+        _ => None,
+    }
+}
+
+/// The port argument `value` of the primitive `name`.
+fn port_arg<'a>(value: &Value, name: &str) -> Result<&'a mut Port, String> {
+    state(value).ok_or_else(|| format!("{}: expected a port", name))
+}
+
+/// The port that a primitive called with `nargs` arguments reads from or
+/// writes to: its argument at `index`, if there is one, and otherwise the
+/// current port `which`.  It must be open, an input port if `which` is
+/// `INPUT` and an output port otherwise, and textual if `textual` is set,
+/// binary otherwise.
+pub fn port<'a>(heap: &mut Heap,
+                nargs: usize,
+                index: usize,
+                which: usize,
+                textual: bool,
+                name: &str)
+                -> Result<&'a mut Port, String> {
+    let value = if index < nargs {
+        args(heap, nargs)[index].clone()
+    } else {
+        try!(current(heap, which))
+    };
+    let port = match state(&value) {
+        Some(port) if port.is_input() == (which == INPUT) && port.is_textual() == textual => port,
+        _ => {
+            return Err(format!("{}: expected a {} {} port",
+                               name,
+                               if textual { "textual" } else { "binary" },
+                               if which == INPUT { "input" } else { "output" }))
+        }
+    };
+    if !port.is_open() {
+        return Err(format!("{}: the port is closed", name))
+    }
+    Ok(port)
+}
+
+/// The error message `message` of the primitive `name`.
+pub fn prefixed(name: &str, message: String) -> String {
+    format!("{}: {}", name, message)
+}
+
+/// The error message `message` of the primitive `name`, which used
+/// `port`.  If the port would block, the interpreter waits until it is
+/// ready, and calls the primitive again, where it can (see
+/// `interp::Control`).
+fn port_error(heap: &mut Heap, port: &mut Port, name: &str, message: String) -> String {
+    if message == WOULD_BLOCK {
+        if let Some(ready) = port.ready() {
+            heap.control.block(ready)
+        }
+    }
+    prefixed(name, message)
+}
+
+/// The EOF object, or `value` if there is one.
+fn or_eof<T, F: FnOnce(T) -> Value>(value: Option<T>, f: F) -> Value {
+    value.map_or(Value::new(value::EOF), f)
+}
+
+/// `(current-input-port)`, `(current-output-port)`, and
+/// `(current-error-port)`
+fn current_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let which = current_port_index(&callee(heap, nargs)).unwrap();
+    current(heap, which)
+}
+
+/// `(port? obj)`
+fn is_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).is_some()))
+}
+
+/// `(input-port? obj)`
+fn is_input_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).map_or(false, |port| port.is_input())))
+}
+
+/// `(output-port? obj)`
+fn is_output_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).map_or(false, |port| port.is_output())))
+}
+
+/// `(textual-port? obj)`
+fn is_textual_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).map_or(false, |port| port.is_textual())))
+}
+
+/// `(binary-port? obj)`
+fn is_binary_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).map_or(false, |port| !port.is_textual())))
+}
+
+/// `(input-port-open? port)`
+fn is_input_port_open(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port_arg(&args(heap, nargs)[0], "input-port-open?"));
+    Ok(boolean(port.is_input() && port.is_open()))
+}
+
+/// `(output-port-open? port)`
+fn is_output_port_open(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port_arg(&args(heap, nargs)[0], "output-port-open?"));
+    Ok(boolean(port.is_output() && port.is_open()))
+}
+
+/// Closes the argument of the primitive `name`, which must be an input
+/// port if `input` is `Some(true)`, and an output port if it is
+/// `Some(false)`.
+fn close(heap: &mut Heap, nargs: usize, input: Option<bool>, name: &str) -> Result<Value, String> {
+    let port = try!(port_arg(&args(heap, nargs)[0], name));
+    match input {
+        Some(true) if !port.is_input() => return Err(format!("{}: expected an input port", name)),
+        Some(false) if port.is_input() => return Err(format!("{}: expected an output port", name)),
+        _ => {}
+    }
+    try!(port.close().map_err(|e| port_error(heap, port, name, e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(close-port port)`
+fn close_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    close(heap, nargs, None, "close-port")
+}
+
+/// `(close-input-port port)`
+fn close_input_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    close(heap, nargs, Some(true), "close-input-port")
+}
+
+/// `(close-output-port port)`
+fn close_output_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    close(heap, nargs, Some(false), "close-output-port")
+}
+
+/// `(eof-object)`
+fn eof_object(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Ok(Value::new(value::EOF))
+}
+
+/// `(eof-object? obj)`
+fn is_eof_object(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].get() == value::EOF))
+}
+
+/// `(read-char [port])`
+fn read_char(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, true, "read-char"));
+    let c = try!(port.read_char().map_err(|e| port_error(heap, port, "read-char", e)));
+    Ok(or_eof(c, Value::new_char))
+}
+
+/// `(peek-char [port])`
+fn peek_char(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, true, "peek-char"));
+    let c = try!(port.peek_char().map_err(|e| port_error(heap, port, "peek-char", e)));
+    Ok(or_eof(c, Value::new_char))
+}
+
+/// `(read-line [port])`
+fn read_line(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, true, "read-line"));
+    match try!(port.read_line().map_err(|e| port_error(heap, port, "read-line", e))) {
+        Some(line) => {
+            try!(heap.alloc_string(&line));
+            Ok(heap.stack.pop().unwrap())
+        }
+        None => Ok(Value::new(value::EOF)),
+    }
+}
+
+/// `(read-string k [port])`
+fn read_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = try!(fixnum_arg(&args(heap, nargs)[0], "read-string"));
+    let port = try!(port(heap, nargs, 1, INPUT, true, "read-string"));
+    let string = try!(port.read_string(len)
+                          .map_err(|e| port_error(heap, port, "read-string", e)));
+    if string.is_empty() && len > 0 {
+        return Ok(Value::new(value::EOF))
+    }
+    try!(heap.alloc_string(&string));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(char-ready? [port])`
+fn char_ready(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, true, "char-ready?"));
+    Ok(boolean(try!(port.is_ready().map_err(|e| prefixed("char-ready?", e)))))
+}
+
+/// `(read-u8 [port])`
+fn read_u8(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, false, "read-u8"));
+    let byte = try!(port.read_u8().map_err(|e| port_error(heap, port, "read-u8", e)));
+    Ok(or_eof(byte, |byte| Value::new_fixnum(byte as usize)))
+}
+
+/// `(peek-u8 [port])`
+fn peek_u8(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, false, "peek-u8"));
+    let byte = try!(port.peek_u8().map_err(|e| port_error(heap, port, "peek-u8", e)));
+    Ok(or_eof(byte, |byte| Value::new_fixnum(byte as usize)))
+}
+
+/// `(u8-ready? [port])`
+fn u8_ready(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, false, "u8-ready?"));
+    Ok(boolean(try!(port.is_ready().map_err(|e| prefixed("u8-ready?", e)))))
+}
+
+/// `(read-bytevector k [port])`
+fn read_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = try!(fixnum_arg(&args(heap, nargs)[0], "read-bytevector"));
+    let port = try!(port(heap, nargs, 1, INPUT, false, "read-bytevector"));
+    let bytes = try!(port.read_bytes(len)
+                         .map_err(|e| port_error(heap, port, "read-bytevector", e)));
+    if bytes.is_empty() && len > 0 {
+        return Ok(Value::new(value::EOF))
+    }
+    try!(heap.alloc_bytevector_from(&bytes));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(read-bytevector! bytevector [port [start [end]]])`
+fn read_bytevector_into(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytevector = args(heap, nargs)[0].clone();
+    let len = match bytevector.kind() {
+        Kind::Bytevector(bytevector) => unsafe { (*bytevector).as_slice() }.len(),
+        _ => return Err("read-bytevector!: expected a bytevector".to_owned()),
+    };
+    if bytevector.immutablep() {
+        return Err("read-bytevector!: cannot modify a constant bytevector".to_owned())
+    }
+    let (start, end) = try!(range(heap, nargs, 2, len, "read-bytevector!"));
+    let port = try!(port(heap, nargs, 1, INPUT, false, "read-bytevector!"));
+    let bytes = try!(port.read_bytes(end - start)
+                         .map_err(|e| port_error(heap, port, "read-bytevector!", e)));
+    if bytes.is_empty() && end > start {
+        return Ok(Value::new(value::EOF))
+    }
+    try!(heap.bytevector_copy_from(&bytevector, start, &bytes));
+    Ok(Value::new_fixnum(bytes.len()))
+}
+
+/// The range of `len` elements selected by the optional arguments at
+/// `start` and `start + 1` of the primitive `name`.
+fn range(heap: &Heap,
+         nargs: usize,
+         start: usize,
+         len: usize,
+         name: &str)
+         -> Result<(usize, usize), String> {
+    let args = args(heap, nargs);
+    let from = if start < nargs { try!(fixnum_arg(&args[start], name)) } else { 0 };
+    let to = if start + 1 < nargs { try!(fixnum_arg(&args[start + 1], name)) } else { len };
+    if from > to || to > len {
+        return Err(format!("{}: bad range", name))
+    }
+    Ok((from, to))
+}
+
+/// `(write-char char [port])`
+fn write_char(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let c = try!(args(heap, nargs)[0].as_char().map_err(|_| "write-char: expected a character"));
+    let port = try!(port(heap, nargs, 1, OUTPUT, true, "write-char"));
+    let mut buffer = [0; 4];
+    try!(port.write_str(c.encode_utf8(&mut buffer)).map_err(|e| prefixed("write-char", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(write-string string [port [start [end]]])`
+fn write_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let string = match args(heap, nargs)[0].kind() {
+        Kind::String(string) => unsafe { (*string).as_str() }.to_owned(),
+        _ => return Err("write-string: expected a string".to_owned()),
+    };
+    let (start, end) = try!(range(heap, nargs, 2, string.chars().count(), "write-string"));
+    let string: String = string.chars().skip(start).take(end - start).collect();
+    let port = try!(port(heap, nargs, 1, OUTPUT, true, "write-string"));
+    try!(port.write_str(&string).map_err(|e| prefixed("write-string", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(newline [port])`
+fn newline(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, OUTPUT, true, "newline"));
+    try!(port.write_str("\n").map_err(|e| prefixed("newline", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(write-u8 byte [port])`
+fn write_u8(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let byte = try!(fixnum_arg(&args(heap, nargs)[0], "write-u8"));
+    if byte > 255 {
+        return Err("write-u8: expected a byte".to_owned())
+    }
+    let port = try!(port(heap, nargs, 1, OUTPUT, false, "write-u8"));
+    try!(port.write_bytes(&[byte as u8]).map_err(|e| prefixed("write-u8", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(write-bytevector bytevector [port [start [end]]])`
+fn write_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytes = match args(heap, nargs)[0].kind() {
+        Kind::Bytevector(bytevector) => unsafe { (*bytevector).as_slice() }.to_vec(),
+        _ => return Err("write-bytevector: expected a bytevector".to_owned()),
+    };
+    let (start, end) = try!(range(heap, nargs, 2, bytes.len(), "write-bytevector"));
+    let port = try!(port(heap, nargs, 1, OUTPUT, false, "write-bytevector"));
+    try!(port.write_bytes(&bytes[start..end]).map_err(|e| prefixed("write-bytevector", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(flush-output-port [port])`
+fn flush_output_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let value = if nargs > 0 {
+        args(heap, nargs)[0].clone()
+    } else {
+        try!(current(heap, OUTPUT))
+    };
+    let port = try!(port_arg(&value, "flush-output-port"));
+    if !port.is_output() {
+        return Err("flush-output-port: expected an output port".to_owned())
+    }
+    try!(port.flush().map_err(|e| port_error(heap, port, "flush-output-port", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// The names of the buffering modes.
+const BUFFERINGS: [(&'static str, Buffering); 3] = [("none", Buffering::None),
+                                                    ("line", Buffering::Line),
+                                                    ("block", Buffering::Block)];
+
+/// `(port-buffering port)`: the buffering mode of an output port, `none`,
+/// `line` or `block`.
+fn port_buffering(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port_arg(&args(heap, nargs)[0], "port-buffering"));
+    let buffering = try!(port.buffering()
+                             .ok_or("port-buffering: expected an open output port".to_owned()));
+    let &(name, _) = BUFFERINGS.iter().find(|&&(_, mode)| mode == buffering).unwrap();
+    heap.intern(name);
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(set-port-buffering! port mode)`
+fn set_port_buffering(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let port = try!(port_arg(&args[0], "set-port-buffering!"));
+    let buffering = match args[1].kind() {
+        Kind::Symbol(symbol) => {
+            let name = unsafe { (*symbol).name() };
+            BUFFERINGS.iter().find(|&&(mode, _)| *name == mode).map(|&(_, buffering)| buffering)
+        }
+        _ => None,
+    };
+    let buffering = try!(buffering.ok_or("set-port-buffering!: expected none, line or block"));
+    if !port.is_output() {
+        return Err("set-port-buffering!: expected an output port".to_owned())
+    }
+    try!(port.set_buffering(buffering).map_err(|e| prefixed("set-port-buffering!", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(port-name port)`: what the port reads or writes, as a string.
+fn port_name(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let name = try!(port_arg(&args(heap, nargs)[0], "port-name")).name().to_owned();
+    try!(heap.alloc_string(&name));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// Allocates `port` and returns it.
+fn new_port(heap: &mut Heap, port: Port) -> Result<Value, String> {
+    try!(heap.alloc_port(port));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// What has been written to the memory output port `value`, for the
+/// primitive `name`, which takes textual ports if `textual` is set.
+fn output_contents(value: &Value, textual: bool, name: &str) -> Result<Vec<u8>, String> {
+    let port = try!(port_arg(value, name));
+    if port.is_output() && port.is_textual() == textual {
+        if let Some(bytes) = try!(port.contents().map_err(|e| prefixed(name, e))) {
+            return Ok(bytes.to_vec())
+        }
+    }
+    Err(format!("{}: expected a {} port",
+                name,
+                if textual { "string output" } else { "bytevector output" }))
+}
+
+/// `(open-input-string string)`
+fn open_input_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytes = match args(heap, nargs)[0].kind() {
+        Kind::String(string) => unsafe { (*string).as_str() }.as_bytes().to_vec(),
+        _ => return Err("open-input-string: expected a string".to_owned()),
+    };
+    new_port(heap, Port::input("string", Box::new(Bytes::new(bytes)), true))
+}
+
+/// `(open-output-string)`
+fn open_output_string(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    new_port(heap, Port::memory_output("string", true))
+}
+
+/// `(get-output-string port)`
+fn get_output_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytes = try!(output_contents(&args(heap, nargs)[0], true, "get-output-string"));
+    // Only whole characters are written to textual ports.
+    try!(heap.alloc_string(&String::from_utf8(bytes).unwrap()));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(open-input-bytevector bytevector)`
+fn open_input_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytes = match args(heap, nargs)[0].kind() {
+        Kind::Bytevector(bytevector) => unsafe { (*bytevector).as_slice() }.to_vec(),
+        _ => return Err("open-input-bytevector: expected a bytevector".to_owned()),
+    };
+    new_port(heap, Port::input("bytevector", Box::new(Bytes::new(bytes)), false))
+}
+
+/// `(open-output-bytevector)`
+fn open_output_bytevector(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    new_port(heap, Port::memory_output("bytevector", false))
+}
+
+/// `(get-output-bytevector port)`
+fn get_output_bytevector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let bytes = try!(output_contents(&args(heap, nargs)[0], false, "get-output-bytevector"));
+    try!(heap.alloc_bytevector_from(&bytes));
+    Ok(heap.stack.pop().unwrap())
+}
@@ -0,0 +1,345 @@
+//! The process context, and child processes.
+//!
+//! `(exit [obj])` calls the `after` thunks of the active `dynamic-wind`
+//! calls, flushes the current output ports, and ends the program, with
+//! status 0 if `obj` is missing or `#t`, 1 if it is `#f`, and `obj` if it
+//! is an integer.  `emergency-exit` ends it right away.
+//!
+//! `(run-process program arguments [environment [directory]])` runs
+//! `program` with the list of strings `arguments`, and returns a process.
+//! `environment` is an alist of variables to set, mapping names to values,
+//! or to `#f` to remove them.  The standard input, output and error of the
+//! process are pipes, which `process-input`, `process-output` and
+//! `process-error` return as textual ports.  `(process-wait process)`
+//! closes its input, waits for it to exit, and returns its exit status, or
+//! `#f` if it was killed by a signal.
+//!
+//! A process is a closure over `PROCESS`, capturing a Rust object holding
+//! its `Child` (see `alloc::rust_data`) and its three ports.  Dropping the
+//! `Child` does not kill the process.  Processes cannot be sent to another
+//! thread or saved in an image.
+//!
+//! These are exported by `(process)`.
+
+use std::env;
+use std::process::{self, Child, Command, Stdio};
+use alloc::Heap;
+use interp;
+use port::{Buffering, Port};
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean};
+use super::pair::list;
+use super::port as ports;
+
+pub static PRIMITIVES: [Primitive; 13] =
+    [Primitive {
+         name: "command-line",
+         min_args: 0,
+         max_args: Some(0),
+         function: command_line,
+     },
+     Primitive {
+         name: "get-environment-variable",
+         min_args: 1,
+         max_args: Some(1),
+         function: get_environment_variable,
+     },
+     Primitive {
+         name: "get-environment-variables",
+         min_args: 0,
+         max_args: Some(0),
+         function: get_environment_variables,
+     },
+     Primitive {
+         name: "exit",
+         min_args: 0,
+         max_args: Some(1),
+         function: exit,
+     },
+     Primitive {
+         name: "emergency-exit",
+         min_args: 0,
+         max_args: Some(1),
+         function: emergency_exit,
+     },
+     Primitive {
+         name: "run-process",
+         min_args: 2,
+         max_args: Some(4),
+         function: run_process,
+     },
+     Primitive {
+         name: "process?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_process,
+     },
+     Primitive {
+         name: "process-input",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_input,
+     },
+     Primitive {
+         name: "process-output",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_output,
+     },
+     Primitive {
+         name: "process-error",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_error,
+     },
+     Primitive {
+         name: "process-id",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_id,
+     },
+     Primitive {
+         name: "process-wait",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_wait,
+     },
+     Primitive {
+         name: "process-kill",
+         min_args: 1,
+         max_args: Some(1),
+         function: process_kill,
+     }];
+
+/// The code of every process.  It is not bound to a global variable.
+pub static PROCESS: Primitive = Primitive {
+    name: "process",
+    min_args: 0,
+    max_args: None,
+    function: not_a_procedure,
+};
+
+/// `(command-line)`: a list of strings, the name of the program and then
+/// its arguments.
+fn command_line(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let arguments = heap.control.command_line().to_vec();
+    let base = heap.stack.len();
+    let mut result = Ok(());
+    for argument in &arguments {
+        result = heap.alloc_string(argument);
+        if result.is_err() {
+            break
+        }
+    }
+    let result = result.and_then(|()| list(heap, arguments.len()));
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(get-environment-variable name)`: the value of the variable, or `#f`.
+fn get_environment_variable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let name = try!(string_arg(&args(heap, nargs)[0], "get-environment-variable"));
+    match env::var_os(&name) {
+        Some(value) => {
+            try!(heap.alloc_string(&value.to_string_lossy()));
+            Ok(heap.stack.pop().unwrap())
+        }
+        None => Ok(Value::new(value::FALSE)),
+    }
+}
+
+/// `(get-environment-variables)`: an alist mapping the names of the
+/// variables to their values.
+fn get_environment_variables(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let variables: Vec<(String, String)> = env::vars_os()
+        .map(|(name, value)| {
+            (name.to_string_lossy().into_owned(), value.to_string_lossy().into_owned())
+        })
+        .collect();
+    let base = heap.stack.len();
+    let mut result = Ok(());
+    for &(ref name, ref value) in &variables {
+        let top = heap.stack.len();
+        result = heap.alloc_string(name)
+                     .and_then(|()| heap.alloc_string(value))
+                     .and_then(|()| heap.alloc_pair(top, top + 1));
+        if result.is_err() {
+            break
+        }
+        let pair = heap.stack.pop().unwrap();
+        heap.stack.truncate(top);
+        heap.stack.push(pair)
+    }
+    let result = result.and_then(|()| list(heap, variables.len()));
+    heap.stack.truncate(base);
+    result
+}
+
+/// The exit status that the argument of `exit` stands for.
+fn exit_status(heap: &Heap, nargs: usize) -> i32 {
+    if nargs == 0 {
+        return 0
+    }
+    let status = args(heap, nargs)[0].clone();
+    match status.kind() {
+        Kind::Fixnum(_) => (status.get() as isize >> 2) as i32,
+        _ if status.get() == value::FALSE => 1,
+        _ if status.get() == value::TRUE => 0,
+        _ => 1,
+    }
+}
+
+/// `(exit [obj])`
+fn exit(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let status = exit_status(heap, nargs);
+    try!(interp::unwind_all(heap));
+    try!(ports::flush_current(heap));
+    process::exit(status)
+}
+
+/// `(emergency-exit [obj])`
+fn emergency_exit(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    process::exit(exit_status(heap, nargs))
+}
+
+/// Calling a process.
+fn not_a_procedure(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("Attempt to call a process, which is not a procedure".to_owned())
+}
+
+/// The string `value`, an argument of the primitive `name`.
+fn string_arg(value: &Value, name: &str) -> Result<String, String> {
+    match value.kind() {
+        Kind::String(string) => Ok(unsafe { (*string).as_str() }.to_owned()),
+        _ => Err(format!("{}: expected a string", name)),
+    }
+}
+
+/// The process `value`, as its closure, for the primitive `name`.
+fn process_arg(value: &Value, name: &str) -> Result<*mut value::Closure, String> {
+    if let Kind::Closure(closure) = value.kind() {
+        if let Kind::Primitive(primitive) = unsafe { (*closure).code.kind() } {
+            if primitive == &PROCESS as *const Primitive {
+                return Ok(closure)
+            }
+        }
+    }
+    Err(format!("{}: expected a process", name))
+}
+
+/// The `Child` of the process `closure`.
+unsafe fn child<'a>(closure: *mut value::Closure) -> &'a mut Child {
+    match (*closure).captured(0).kind() {
+        Kind::RustData(data) => (*data).downcast_mut().unwrap(),
+        _ => bug!("process without a child"),
+    }
+}
+
+/// `(run-process program arguments [environment [directory]])`
+fn run_process(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let program = try!(string_arg(&args[0], "run-process"));
+    let mut command = Command::new(&program);
+    let mut rest = args[1].clone();
+    while let Ok(argument) = rest.car() {
+        command.arg(try!(string_arg(&argument, "run-process")));
+        rest = rest.cdr().unwrap()
+    }
+    if rest.get() != value::NIL {
+        return Err("run-process: expected a list of arguments".to_owned())
+    }
+    if nargs > 2 {
+        let mut rest = args[2].clone();
+        while let Ok(binding) = rest.car() {
+            let name = try!(binding.car()
+                                   .map_err(|()| "run-process: expected an alist".to_owned())
+                                   .and_then(|name| string_arg(&name, "run-process")));
+            match binding.cdr().unwrap() {
+                ref setting if setting.get() == value::FALSE => command.env_remove(name),
+                setting => command.env(name, try!(string_arg(&setting, "run-process"))),
+            };
+            rest = rest.cdr().unwrap()
+        }
+    }
+    if nargs > 3 {
+        command.current_dir(try!(string_arg(&args[3], "run-process")));
+    }
+    let mut child = try!(command.stdin(Stdio::piped())
+                                .stdout(Stdio::piped())
+                                .stderr(Stdio::piped())
+                                .spawn()
+                                .map_err(|e| format!("run-process: {}: {}", program, e)));
+    let ports = vec![Port::output(&program,
+                                  Box::new(child.stdin.take().unwrap()),
+                                  true,
+                                  Buffering::Block),
+                     Port::input(&program, Box::new(child.stdout.take().unwrap()), true),
+                     Port::input(&program, Box::new(child.stderr.take().unwrap()), true)];
+    let start = heap.stack.len();
+    heap.stack.push(PROCESS.to_value());
+    let mut result = heap.alloc_rustdata(Box::new(child));
+    for port in ports {
+        result = result.and_then(|()| heap.alloc_port(port))
+    }
+    let result = result.and_then(|()| heap.alloc_closure(start, start + 5));
+    let object = result.map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    object
+}
+
+/// `(process? obj)`
+fn is_process(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(process_arg(&args(heap, nargs)[0], "process?").is_ok()))
+}
+
+/// The port at `index` of the process argument of the primitive `name`:
+/// its input, output or error.
+fn process_port(heap: &mut Heap, nargs: usize, index: usize, name: &str)
+                -> Result<Value, String> {
+    let closure = try!(process_arg(&args(heap, nargs)[0], name));
+    // The ports are captured after the `Child`.
+    Ok(unsafe { (*closure).captured(index + 1) }.clone())
+}
+
+/// `(process-input process)`
+fn process_input(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    process_port(heap, nargs, 0, "process-input")
+}
+
+/// `(process-output process)`
+fn process_output(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    process_port(heap, nargs, 1, "process-output")
+}
+
+/// `(process-error process)`
+fn process_error(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    process_port(heap, nargs, 2, "process-error")
+}
+
+/// `(process-id process)`
+fn process_id(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let closure = try!(process_arg(&args(heap, nargs)[0], "process-id"));
+    Ok(Value::new_fixnum(unsafe { child(closure) }.id() as usize))
+}
+
+/// `(process-wait process)`
+fn process_wait(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let closure = try!(process_arg(&args(heap, nargs)[0], "process-wait"));
+    // A process reading until the end of its input could not exit otherwise.
+    if let Some(input) = ports::state(unsafe { (*closure).captured(1) }) {
+        try!(input.close().map_err(|e| format!("process-wait: {}", e)))
+    }
+    let status = try!(unsafe { child(closure) }.wait().map_err(|e| format!("process-wait: {}", e)));
+    Ok(match status.code() {
+        Some(code) => Value::from_fixnum_word(code as isize * 4).unwrap(),
+        None => Value::new(value::FALSE),
+    })
+}
+
+/// `(process-kill process)`
+fn process_kill(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let closure = try!(process_arg(&args(heap, nargs)[0], "process-kill"));
+    // Killing a process that has exited fails, harmlessly.
+    let _ = unsafe { child(closure) }.kill();
+    Ok(Value::new(value::UNSPECIFIED))
+}
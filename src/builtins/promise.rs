@@ -0,0 +1,111 @@
+//! R7RS promises.
+//!
+//! `(delay-force expression)` is `(%make-lazy-promise (lambda () expression))`,
+//! and `(delay expression)` is `(delay-force (make-promise expression))`.
+//! Forcing follows the reference implementation in R7RS section 7.3, so
+//! that chains of `delay-force` run in constant space.
+//!
+//! The thunks of lazy promises must be primitives (or closures over
+//! primitives) until compiled procedures can be called from Rust.
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, call};
+
+pub static PRIMITIVES: [Primitive; 4] =
+    [Primitive {
+         name: "promise?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_promise,
+     },
+     Primitive {
+         name: "make-promise",
+         min_args: 1,
+         max_args: Some(1),
+         function: make_promise,
+     },
+     Primitive {
+         name: "%make-lazy-promise",
+         min_args: 1,
+         max_args: Some(1),
+         function: make_lazy_promise,
+     },
+     Primitive {
+         name: "force",
+         min_args: 1,
+         max_args: Some(1),
+         function: force,
+     }];
+
+/// The state pair of `value`, if it is a promise.
+fn state(value: &Value) -> Option<Value> {
+    match value.kind() {
+        Kind::Promise(promise) => Some(unsafe { (*promise).state.clone() }),
+        _ => None,
+    }
+}
+
+/// `(promise? obj)`
+fn is_promise(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(state(&args(heap, nargs)[0]).is_some()))
+}
+
+/// `(make-promise obj)`
+fn make_promise(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    if state(&heap.stack[first]).is_some() {
+        return Ok(heap.stack[first].clone())
+    }
+    try!(heap.alloc_promise(true, first));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(%make-lazy-promise thunk)`
+fn make_lazy_promise(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let first = heap.stack.len() - nargs;
+    try!(heap.alloc_promise(false, first));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(force promise)`
+fn force(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let base = heap.stack.len();
+    let promise = args(heap, nargs)[0].clone();
+    heap.stack.push(promise);
+    let result = force_promise(heap, base);
+    heap.stack.truncate(base);
+    result
+}
+
+/// Forces the promise at stack index `base`, which is the top of the stack.
+fn force_promise(heap: &mut Heap, base: usize) -> Result<Value, String> {
+    loop {
+        let state = match state(&heap.stack[base]) {
+            Some(state) => state,
+            None => return Ok(heap.stack[base].clone()),
+        };
+        if state.car().unwrap().get() != value::FALSE {
+            return Ok(state.cdr().unwrap())
+        }
+        heap.stack.push(state.cdr().unwrap());
+        try!(call(heap, 0));
+        // The thunk may have forced this promise itself.
+        let state = self::state(&heap.stack[base]).unwrap();
+        if state.car().unwrap().get() == value::FALSE {
+            let new = heap.stack[base + 1].clone();
+            let promise = match new.kind() {
+                Kind::Promise(promise) => promise,
+                _ => return Err("force: delay-force did not produce a promise".to_owned()),
+            };
+            let new_state = unsafe { (*promise).state.clone() };
+            let (done, value) = (new_state.car().unwrap(), new_state.cdr().unwrap());
+            state.set_car(done).unwrap();
+            state.set_cdr(value.clone()).unwrap();
+            heap.write_barrier(&state, &value);
+            unsafe { (*promise).state.set(state.clone()) }
+            heap.write_barrier(&new, &state);
+        }
+        heap.stack.truncate(base + 1)
+    }
+}
@@ -0,0 +1,392 @@
+//! Random numbers, in the style of SRFI 27.
+//!
+//! `(random-integer n)` returns an integer in `[0, n)`, and `(random-real)`
+//! a float in `(0, 1)`, both drawn from the default random source.  Random
+//! sources are generators that can be seeded: `(make-random-source [seed])`
+//! makes one, `random-source-make-integers` and `random-source-make-reals`
+//! return procedures drawing numbers from one, and its state can be saved
+//! and restored as a bytevector.  The default source starts in the same
+//! state in every interpreter, so programs are repeatable until they call
+//! `random-source-randomize!`, which seeds a source from the operating
+//! system.  `(random-entropy n)` returns `n` bytes from the operating system.
+//! In deterministic mode, the default source starts in the state that the
+//! seed stands for, and entropy is drawn from a generator seeded with it.
+//!
+//! The generator is SplitMix64, which is fast but not cryptographically
+//! secure.  Its state is a single word, kept in a `value::RandomSource` on
+//! the Scheme heap.  These are exported by `(rusty-scheme random)`.
+
+use std::io;
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, callee, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 12] =
+    [Primitive {
+         name: "random-integer",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_integer,
+     },
+     Primitive {
+         name: "random-real",
+         min_args: 0,
+         max_args: Some(0),
+         function: random_real,
+     },
+     Primitive {
+         name: "default-random-source",
+         min_args: 0,
+         max_args: Some(0),
+         function: default_random_source,
+     },
+     Primitive {
+         name: "make-random-source",
+         min_args: 0,
+         max_args: Some(1),
+         function: make_random_source,
+     },
+     Primitive {
+         name: "random-source?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_random_source,
+     },
+     Primitive {
+         name: "random-source-state-ref",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_source_state_ref,
+     },
+     Primitive {
+         name: "random-source-state-set!",
+         min_args: 2,
+         max_args: Some(2),
+         function: random_source_state_set,
+     },
+     Primitive {
+         name: "random-source-randomize!",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_source_randomize,
+     },
+     Primitive {
+         name: "random-source-pseudo-randomize!",
+         min_args: 3,
+         max_args: Some(3),
+         function: random_source_pseudo_randomize,
+     },
+     Primitive {
+         name: "random-source-make-integers",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_source_make_integers,
+     },
+     Primitive {
+         name: "random-source-make-reals",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_source_make_reals,
+     },
+     Primitive {
+         name: "random-entropy",
+         min_args: 1,
+         max_args: Some(1),
+         function: random_entropy,
+     }];
+
+/// The code of the procedures returned by `random-source-make-integers`,
+/// which capture their source.
+static INTEGERS: Primitive = Primitive {
+    name: "random-integer",
+    min_args: 1,
+    max_args: Some(1),
+    function: source_integer,
+};
+
+/// The code of the procedures returned by `random-source-make-reals`.
+static REALS: Primitive = Primitive {
+    name: "random-real",
+    min_args: 0,
+    max_args: Some(0),
+    function: source_real,
+};
+
+/// The number of bytes in the state of a random source.
+const STATE_SIZE: usize = 8;
+
+/// Advances `state`, and returns the next number.
+fn next(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A number in `[0, n)`, without bias.  `n` must not be 0.
+fn below(state: &mut u64, n: u64) -> u64 {
+    // Numbers past the last multiple of `n` would favor small results.
+    let limit = u64::max_value() - u64::max_value() % n;
+    loop {
+        let number = next(state);
+        if number < limit {
+            return number % n
+        }
+    }
+}
+
+/// A float in `(0, 1)`.
+fn real(state: &mut u64) -> f64 {
+    loop {
+        let bits = next(state) >> 11;
+        if bits != 0 {
+            return bits as f64 / (1u64 << 53) as f64
+        }
+    }
+}
+
+/// `len` bytes from the operating system.
+#[cfg(unix)]
+fn entropy(len: usize) -> io::Result<Vec<u8>> {
+    use std::fs::File;
+    use std::io::Read;
+    let mut bytes = vec![0; len];
+    try!(File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes)));
+    Ok(bytes)
+}
+
+/// `len` bytes from the browser (see `platform`).
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+fn entropy(len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    ::platform::fill_random(&mut bytes);
+    Ok(bytes)
+}
+
+/// `len` bytes from the operating system, through the keys that the
+/// standard library draws for hash maps.
+#[cfg(not(any(unix, all(target_arch = "wasm32", feature = "wasm"))))]
+fn entropy(len: usize) -> io::Result<Vec<u8>> {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let mut bytes = vec![];
+    while bytes.len() < len {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(bytes.len());
+        let word = hasher.finish();
+        bytes.extend((0..8).map(|i| (word >> (i * 8)) as u8))
+    }
+    bytes.truncate(len);
+    Ok(bytes)
+}
+
+/// `len` bytes of entropy for the primitive `name`: from the operating
+/// system, or in deterministic mode, from the generator seeded for it.
+fn heap_entropy(heap: &mut Heap, len: usize, name: &str) -> Result<Vec<u8>, String> {
+    match heap.control.deterministic {
+        Some(ref mut deterministic) => {
+            let state = &mut deterministic.entropy;
+            Ok((0..len).map(|_| next(state) as u8).collect())
+        }
+        None => entropy(len).map_err(|e| format!("{}: {}", name, e)),
+    }
+}
+
+/// The random source `value`, an argument of the primitive `name`.
+fn source_arg(value: &Value, name: &str) -> Result<*mut value::RandomSource, String> {
+    match value.kind() {
+        Kind::RandomSource(source) => Ok(source),
+        _ => Err(format!("{}: expected a random source", name)),
+    }
+}
+
+/// The bound argument `value` of the primitive `name`: a positive fixnum.
+fn bound_arg(value: &Value, name: &str) -> Result<u64, String> {
+    match try!(fixnum_arg(value, name)) {
+        0 => Err(format!("{}: expected a positive fixnum", name)),
+        n => Ok(n as u64),
+    }
+}
+
+/// The default random source, which is made the first time it is needed.
+fn default_source(heap: &mut Heap) -> Result<Value, String> {
+    if let Some(ref source) = heap.control.default_random_source {
+        return Ok(source.get())
+    }
+    let seed = heap.control.deterministic.as_ref().map_or(0, |deterministic| deterministic.seed);
+    try!(heap.alloc_random_source(seed));
+    let source = heap.stack.pop().unwrap();
+    heap.control.default_random_source = Some(heap.root(source.clone()));
+    Ok(source)
+}
+
+/// Returns a float drawn from `source`.
+fn alloc_real(heap: &mut Heap, source: *mut value::RandomSource) -> Result<Value, String> {
+    let number = real(unsafe { &mut (*source).state });
+    try!(heap.alloc_float(number));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(random-integer n)`
+fn random_integer(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let n = try!(bound_arg(&args(heap, nargs)[0], "random-integer"));
+    let source = try!(source_arg(&try!(default_source(heap)), "random-integer"));
+    Ok(Value::new_fixnum(below(unsafe { &mut (*source).state }, n) as usize))
+}
+
+/// `(random-real)`
+fn random_real(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let source = try!(source_arg(&try!(default_source(heap)), "random-real"));
+    alloc_real(heap, source)
+}
+
+/// `(default-random-source)`
+fn default_random_source(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    default_source(heap)
+}
+
+/// `(make-random-source [seed])`: a source in the state that `seed`, a
+/// fixnum, stands for, or 0.
+fn make_random_source(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let seed = match args(heap, nargs).get(0) {
+        Some(seed) => try!(fixnum_arg(seed, "make-random-source")),
+        None => 0,
+    };
+    try!(heap.alloc_random_source(seed as u64));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(random-source? obj)`
+fn is_random_source(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(source_arg(&args(heap, nargs)[0], "random-source?").is_ok()))
+}
+
+/// `(random-source-state-ref source)`: the state, as a bytevector.
+fn random_source_state_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let source = try!(source_arg(&args(heap, nargs)[0], "random-source-state-ref"));
+    let state = unsafe { (*source).state };
+    let bytes: Vec<u8> = (0..STATE_SIZE).map(|i| (state >> (i * 8)) as u8).collect();
+    try!(heap.alloc_bytevector_from(&bytes));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(random-source-state-set! source state)`
+fn random_source_state_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let source = try!(source_arg(&args[0], "random-source-state-set!"));
+    let bytes = match args[1].kind() {
+        Kind::Bytevector(bytevector) => unsafe { (*bytevector).as_slice() },
+        _ => return Err("random-source-state-set!: expected a bytevector".to_owned()),
+    };
+    if bytes.len() != STATE_SIZE {
+        return Err("random-source-state-set!: bad state".to_owned())
+    }
+    let state = bytes.iter().rev().fold(0, |state, &byte| state << 8 | byte as u64);
+    unsafe { (*source).state = state }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(random-source-randomize! source)`: seeds `source` from the operating
+/// system.
+fn random_source_randomize(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let source = try!(source_arg(&args(heap, nargs)[0], "random-source-randomize!"));
+    let bytes = try!(heap_entropy(heap, STATE_SIZE, "random-source-randomize!"));
+    let state = bytes.iter().fold(0, |state, &byte| state << 8 | byte as u64);
+    unsafe { (*source).state = state }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(random-source-pseudo-randomize! source i j)`: puts `source` in a state
+/// that depends only on the fixnums `i` and `j`.
+fn random_source_pseudo_randomize(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let name = "random-source-pseudo-randomize!";
+    let args = args(heap, nargs);
+    let source = try!(source_arg(&args[0], name));
+    let i = try!(fixnum_arg(&args[1], name)) as u64;
+    let j = try!(fixnum_arg(&args[2], name)) as u64;
+    let mut state = i;
+    let state = next(&mut state) ^ j;
+    unsafe { (*source).state = state }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// Returns a closure over `code`, capturing the source argument of the
+/// primitive `name`.
+fn source_procedure(heap: &mut Heap,
+                    nargs: usize,
+                    code: &'static Primitive,
+                    name: &str)
+                    -> Result<Value, String> {
+    let source = args(heap, nargs)[0].clone();
+    try!(source_arg(&source, name));
+    let start = heap.stack.len();
+    heap.stack.push(code.to_value());
+    heap.stack.push(source);
+    let result = heap.alloc_closure(start, start + 2);
+    let procedure = heap.stack.pop();
+    heap.stack.truncate(start);
+    try!(result);
+    Ok(procedure.unwrap())
+}
+
+/// `(random-source-make-integers source)`
+fn random_source_make_integers(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    source_procedure(heap, nargs, &INTEGERS, "random-source-make-integers")
+}
+
+/// `(random-source-make-reals source)`
+fn random_source_make_reals(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    source_procedure(heap, nargs, &REALS, "random-source-make-reals")
+}
+
+/// The source captured by the procedure being called.
+fn captured_source(heap: &Heap, nargs: usize) -> *mut value::RandomSource {
+    match callee(heap, nargs).kind() {
+        Kind::Closure(closure) => {
+            match unsafe { (*closure).captured(0) }.kind() {
+                Kind::RandomSource(source) => source,
+                _ => bug!("random procedure without a source"),
+            }
+        }
+        _ => bug!("random procedure called without its closure"),
+    }
+}
+
+/// Calling a procedure made by `random-source-make-integers`.
+fn source_integer(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let n = try!(bound_arg(&args(heap, nargs)[0], "random-integer"));
+    let source = captured_source(heap, nargs);
+    Ok(Value::new_fixnum(below(unsafe { &mut (*source).state }, n) as usize))
+}
+
+/// Calling a procedure made by `random-source-make-reals`.
+fn source_real(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let source = captured_source(heap, nargs);
+    alloc_real(heap, source)
+}
+
+/// `(random-entropy n)`: a bytevector of `n` bytes from the operating
+/// system.
+fn random_entropy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = try!(fixnum_arg(&args(heap, nargs)[0], "random-entropy"));
+    let bytes = try!(heap_entropy(heap, len, "random-entropy"));
+    try!(heap.alloc_bytevector_from(&bytes));
+    Ok(heap.stack.pop().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let mut state = 0;
+        for n in 1..100 {
+            assert!(below(&mut state, n) < n);
+            let x = real(&mut state);
+            assert!(x > 0.0 && x < 1.0)
+        }
+        assert_eq!(entropy(16).unwrap().len(), 16);
+    }
+}
@@ -0,0 +1,460 @@
+//! R6RS-style records: the procedural layer and the reflective API.
+//!
+//! A record type descriptor is a record whose own descriptor is `#f` (see
+//! `value::RecordDescriptor`).  Fields are numbered from 0 starting with the
+//! first field of the type itself; inherited fields come first in the
+//! record, so their indices are offset by the parent's field count.
+//!
+//! The procedures returned by `record-constructor`, `record-predicate`,
+//! `record-accessor`, and `record-mutator` are closures over the primitives
+//! below, capturing the descriptor (and the field index).
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, callee, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 13] =
+    [Primitive {
+         name: "make-record-type-descriptor",
+         min_args: 6,
+         max_args: Some(6),
+         function: make_record_type_descriptor,
+     },
+     Primitive {
+         name: "record-type-descriptor?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_record_type_descriptor,
+     },
+     Primitive {
+         name: "record-constructor",
+         min_args: 1,
+         max_args: Some(1),
+         function: record_constructor,
+     },
+     Primitive {
+         name: "record-predicate",
+         min_args: 1,
+         max_args: Some(1),
+         function: record_predicate,
+     },
+     Primitive {
+         name: "record-accessor",
+         min_args: 2,
+         max_args: Some(2),
+         function: record_accessor,
+     },
+     Primitive {
+         name: "record-mutator",
+         min_args: 2,
+         max_args: Some(2),
+         function: record_mutator,
+     },
+     Primitive {
+         name: "record?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_record,
+     },
+     Primitive {
+         name: "record-rtd",
+         min_args: 1,
+         max_args: Some(1),
+         function: record_rtd,
+     },
+     Primitive {
+         name: "record-type-name",
+         min_args: 1,
+         max_args: Some(1),
+         function: record_type_name,
+     },
+     Primitive {
+         name: "record-type-parent",
+         min_args: 1,
+         max_args: Some(1),
+         function: record_type_parent,
+     },
+     Primitive {
+         name: "record-type-sealed?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_record_type_sealed,
+     },
+     Primitive {
+         name: "record-type-opaque?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_record_type_opaque,
+     },
+     Primitive {
+         name: "record-field-mutable?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_record_field_mutable,
+     }];
+
+/// The code of the closures returned by `record-constructor`.
+static CONSTRUCT: Primitive = Primitive {
+    name: "record-constructor",
+    min_args: 0,
+    max_args: None,
+    function: construct,
+};
+
+/// The code of the closures returned by `record-predicate`.
+static PREDICATE: Primitive = Primitive {
+    name: "record-predicate",
+    min_args: 1,
+    max_args: Some(1),
+    function: predicate,
+};
+
+/// The code of the closures returned by `record-accessor`.
+static ACCESS: Primitive = Primitive {
+    name: "record-accessor",
+    min_args: 1,
+    max_args: Some(1),
+    function: access,
+};
+
+/// The code of the closures returned by `record-mutator`.
+static MUTATE: Primitive = Primitive {
+    name: "record-mutator",
+    min_args: 2,
+    max_args: Some(2),
+    function: mutate,
+};
+
+/// Checks that `value` is a record type descriptor.
+fn descriptor(value: &Value, procedure: &str) -> Result<*mut value::RecordDescriptor, String> {
+    match value.kind() {
+        Kind::Record(record) if unsafe { (*record).descriptor.get() } == value::FALSE => {
+            Ok(record as *mut value::RecordDescriptor)
+        }
+        _ => Err(format!("{}: expected a record type descriptor", procedure)),
+    }
+}
+
+/// The descriptor of `value`, if it is a record (opaque or not).
+fn descriptor_of(value: &Value) -> Option<Value> {
+    match value.kind() {
+        Kind::Record(record) => {
+            let descriptor = unsafe { (*record).descriptor.clone() };
+            if descriptor.get() == value::FALSE {
+                None
+            } else {
+                Some(descriptor)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Checks if `value` is an instance of the type `rtd`, or of a subtype.
+fn is_instance(value: &Value, rtd: &Value) -> bool {
+    let mut current = match descriptor_of(value) {
+        Some(descriptor) => descriptor,
+        None => return false,
+    };
+    loop {
+        if current == *rtd {
+            return true
+        }
+        current = match current.kind() {
+            Kind::Record(record) => unsafe {
+                (*(record as *mut value::RecordDescriptor)).parent.clone()
+            },
+            _ => return false,
+        }
+    }
+}
+
+/// The fields defined by `rtd` itself, as `(name, mutable)` pairs.
+fn own_fields(rtd: *mut value::RecordDescriptor) -> Vec<(Value, bool)> {
+    let mut fields = vec![];
+    let mut list = unsafe { (*rtd).fields.clone() };
+    while let Ok(field) = list.car() {
+        let mutable = field.cdr().map(|m| m.get() != value::FALSE).unwrap_or(false);
+        fields.push((field.car().unwrap(), mutable));
+        list = list.cdr().unwrap()
+    }
+    fields
+}
+
+/// Finds field `k` of `rtd`: its index in the record, and whether it is
+/// mutable.
+fn field(rtd: *mut value::RecordDescriptor,
+         k: &Value,
+         procedure: &str)
+         -> Result<(usize, bool), String> {
+    let k = try!(fixnum_arg(k, procedure));
+    let fields = own_fields(rtd);
+    let count = unsafe { (*rtd).field_count.get() } >> 2;
+    match fields.get(k) {
+        Some(&(_, mutable)) => Ok((count - fields.len() + k, mutable)),
+        None => Err(format!("{}: no field {}", procedure, k)),
+    }
+}
+
+/// Parses a field specification, `(mutable name)` or `(immutable name)`.
+fn field_spec(spec: &Value) -> Result<(Value, bool), String> {
+    let bad = || "make-record-type-descriptor: bad field specification".to_owned();
+    let (keyword, rest) = try!(spec.car().and_then(|car| spec.cdr().map(|cdr| (car, cdr)))
+                                   .map_err(|()| bad()));
+    let name = try!(rest.car().map_err(|()| bad()));
+    let mutable = match keyword.kind() {
+        Kind::Symbol(symbol) => {
+            match unsafe { &**(*symbol).name() } {
+                "mutable" => true,
+                "immutable" => false,
+                _ => return Err(bad()),
+            }
+        }
+        _ => return Err(bad()),
+    };
+    match (name.kind(), rest.cdr()) {
+        (Kind::Symbol(_), Ok(ref nil)) if nil.get() == value::NIL => Ok((name, mutable)),
+        _ => Err(bad()),
+    }
+}
+
+/// Makes a closure over `code`, capturing `captured`.
+fn make_closure(heap: &mut Heap,
+                code: &'static Primitive,
+                captured: &[Value])
+                -> Result<Value, String> {
+    let base = heap.stack.len();
+    heap.stack.push(code.to_value());
+    heap.stack.extend_from_slice(captured);
+    let result = heap.alloc_closure(base, base + 1 + captured.len());
+    let closure = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(result);
+    Ok(closure.unwrap())
+}
+
+/// `(make-record-type-descriptor name parent uid sealed? opaque? fields)`
+///
+/// `fields` is a list of field specifications (R6RS uses a vector).
+/// Nongenerative types (with a `uid`) are not supported.
+fn make_record_type_descriptor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let procedure = "make-record-type-descriptor";
+    let first = heap.stack.len() - nargs;
+    let args = args(heap, nargs);
+    if args[0].tag() != value::Tags::Symbol {
+        return Err(format!("{}: the name must be a symbol", procedure))
+    }
+    let (parent_count, parent_opaque) = if args[1].get() == value::FALSE {
+        (0, false)
+    } else {
+        let parent = try!(descriptor(&args[1], procedure));
+        unsafe {
+            if (*parent).sealed.get() != value::FALSE {
+                return Err(format!("{}: the parent type is sealed", procedure))
+            }
+            ((*parent).field_count.get() >> 2, (*parent).opaque.get() != value::FALSE)
+        }
+    };
+    if args[2].get() != value::FALSE {
+        return Err(format!("{}: nongenerative record types are not supported", procedure))
+    }
+    let mut fields = vec![];
+    let mut list = args[5].clone();
+    while let Ok(spec) = list.car() {
+        fields.push(try!(field_spec(&spec)));
+        list = list.cdr().unwrap()
+    }
+    if list.get() != value::NIL {
+        return Err(format!("{}: the fields must be a list", procedure))
+    }
+
+    // Field names are symbols, which never move, so `fields` stays valid
+    // while the list is consed up.
+    let base = heap.stack.len();
+    heap.stack.push(Value::new(value::NIL));
+    for &(ref name, mutable) in fields.iter().rev() {
+        heap.stack.push(name.clone());
+        heap.stack.push(boolean(mutable));
+        let result = heap.alloc_pair(base + 1, base + 2)
+                         .and_then(|()| heap.alloc_pair(base + 3, base));
+        if let Err(e) = result {
+            heap.stack.truncate(base);
+            return Err(e)
+        }
+        heap.stack[base] = heap.stack.pop().unwrap();
+        heap.stack.truncate(base + 1);
+    }
+    let field_list = heap.stack.pop().unwrap();
+    let descriptor = [Value::new(value::FALSE),
+                      heap.stack[first].clone(),
+                      heap.stack[first + 1].clone(),
+                      boolean(args[3].get() != value::FALSE),
+                      boolean(parent_opaque || args[4].get() != value::FALSE),
+                      Value::new_fixnum(parent_count + fields.len()),
+                      field_list];
+    heap.stack.extend_from_slice(&descriptor);
+    let result = heap.alloc_record(base, base + descriptor.len());
+    let rtd = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(result);
+    Ok(rtd.unwrap())
+}
+
+/// `(record-type-descriptor? obj)`
+fn is_record_type_descriptor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(descriptor(&args(heap, nargs)[0], "").is_ok()))
+}
+
+/// `(record-constructor rtd)`
+///
+/// The constructor takes every field, inherited ones first.
+fn record_constructor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = args(heap, nargs)[0].clone();
+    try!(descriptor(&rtd, "record-constructor"));
+    make_closure(heap, &CONSTRUCT, &[rtd])
+}
+
+/// `(record-predicate rtd)`
+fn record_predicate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = args(heap, nargs)[0].clone();
+    try!(descriptor(&rtd, "record-predicate"));
+    make_closure(heap, &PREDICATE, &[rtd])
+}
+
+/// `(record-accessor rtd k)`
+fn record_accessor(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let (index, _) = try!(field(try!(descriptor(&args[0], "record-accessor")),
+                                &args[1],
+                                "record-accessor"));
+    make_closure(heap, &ACCESS, &[args[0].clone(), Value::new_fixnum(index)])
+}
+
+/// `(record-mutator rtd k)`
+fn record_mutator(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let (index, mutable) = try!(field(try!(descriptor(&args[0], "record-mutator")),
+                                      &args[1],
+                                      "record-mutator"));
+    if !mutable {
+        return Err(format!("record-mutator: field {} is immutable", args[1].get() >> 2))
+    }
+    make_closure(heap, &MUTATE, &[args[0].clone(), Value::new_fixnum(index)])
+}
+
+/// The `index`th captured value of the closure being called.
+fn captured(heap: &Heap, nargs: usize, index: usize) -> Value {
+    match callee(heap, nargs).kind() {
+        Kind::Closure(closure) => unsafe { (*closure).captured(index).clone() },
+        _ => bug!("record procedure called without its closure"),
+    }
+}
+
+/// Checks that `value` is an instance of the descriptor captured by the
+/// closure being called, and returns the record.
+fn instance(heap: &Heap, nargs: usize, value: &Value) -> Result<*mut value::Record, String> {
+    let rtd = captured(heap, nargs, 0);
+    match value.kind() {
+        Kind::Record(record) if is_instance(value, &rtd) => Ok(record),
+        _ => {
+            let name = unsafe { (*(rtd.as_ptr() as *mut value::RecordDescriptor)).name.clone() };
+            let name = match name.kind() {
+                Kind::Symbol(symbol) => unsafe { (*symbol).name() },
+                _ => bug!("record type without a name"),
+            };
+            Err(format!("expected a record of type {}", name))
+        }
+    }
+}
+
+fn construct(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = captured(heap, nargs, 0);
+    let count = unsafe { (*try!(descriptor(&rtd, "record-constructor"))).field_count.get() >> 2 };
+    if nargs != count {
+        return Err(format!("record constructor: expected {} fields, got {}", count, nargs))
+    }
+    let base = heap.stack.len();
+    heap.stack.push(rtd);
+    for i in base - nargs..base {
+        let field = heap.stack[i].clone();
+        heap.stack.push(field)
+    }
+    let result = heap.alloc_record(base, base + 1 + nargs);
+    let record = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(result);
+    Ok(record.unwrap())
+}
+
+fn predicate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = captured(heap, nargs, 0);
+    Ok(boolean(is_instance(&args(heap, nargs)[0], &rtd)))
+}
+
+fn access(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let record = try!(instance(heap, nargs, &args(heap, nargs)[0]));
+    let index = captured(heap, nargs, 1).get() >> 2;
+    Ok(unsafe { (*record).field(index).clone() })
+}
+
+fn mutate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let record = try!(instance(heap, nargs, &args[0]));
+    let index = captured(heap, nargs, 1).get() >> 2;
+    unsafe { (*record).field(index).set(args[1].clone()) }
+    heap.write_barrier(&args[0], &args[1]);
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(record? obj)`: true for records of non-opaque types.
+fn is_record(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(match descriptor_of(&args(heap, nargs)[0]) {
+        Some(rtd) => unsafe {
+            (*(rtd.as_ptr() as *mut value::RecordDescriptor)).opaque.get() == value::FALSE
+        },
+        None => false,
+    }))
+}
+
+/// `(record-rtd record)`
+fn record_rtd(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let record = args(heap, nargs)[0].clone();
+    try!(is_record(heap, nargs).and_then(|is_record| if is_record.get() == value::TRUE {
+        Ok(())
+    } else {
+        Err("record-rtd: expected a non-opaque record".to_owned())
+    }));
+    Ok(descriptor_of(&record).unwrap())
+}
+
+/// `(record-type-name rtd)`
+fn record_type_name(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = try!(descriptor(&args(heap, nargs)[0], "record-type-name"));
+    Ok(unsafe { (*rtd).name.clone() })
+}
+
+/// `(record-type-parent rtd)`
+fn record_type_parent(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = try!(descriptor(&args(heap, nargs)[0], "record-type-parent"));
+    Ok(unsafe { (*rtd).parent.clone() })
+}
+
+/// `(record-type-sealed? rtd)`
+fn is_record_type_sealed(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = try!(descriptor(&args(heap, nargs)[0], "record-type-sealed?"));
+    Ok(unsafe { (*rtd).sealed.clone() })
+}
+
+/// `(record-type-opaque? rtd)`
+fn is_record_type_opaque(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let rtd = try!(descriptor(&args(heap, nargs)[0], "record-type-opaque?"));
+    Ok(unsafe { (*rtd).opaque.clone() })
+}
+
+/// `(record-field-mutable? rtd k)`
+fn is_record_field_mutable(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let rtd = try!(descriptor(&args[0], "record-field-mutable?"));
+    let (_, mutable) = try!(field(rtd, &args[1], "record-field-mutable?"));
+    Ok(boolean(mutable))
+}
@@ -0,0 +1,268 @@
+//! TCP and UDP sockets, over `std::net`.
+//!
+//! `(tcp-connect host port [binary?])` connects to a server, and returns an
+//! input port and an output port over the connection, as two values.
+//! Textual ports are made unless `binary?` is true.  `(tcp-listen host
+//! port)` returns a listener, which `(tcp-accept listener [binary?])` waits
+//! for a connection on, returning its ports in the same way.  The output
+//! port is block buffered, so it must be flushed for the other end to see
+//! what was written.
+//!
+//! `(udp-open host port)` returns a UDP socket bound to that address.
+//! `(udp-send socket bytevector host port)` sends a datagram, and
+//! `(udp-receive socket)` waits for one, returning its contents, and the
+//! host and port it came from, as three values.
+//!
+//! Port 0 stands for any free port: `(socket-local-port socket)` returns
+//! the port that a listener or UDP socket is bound to.  `socket-close`
+//! closes either.
+//!
+//! Listeners and UDP sockets are closures over `SOCKET`, which capture a
+//! Rust object holding a `Socket` (see `alloc::rust_data`).  Its finalizer
+//! drops it, which closes it.  Like other Rust objects, sockets cannot be
+//! sent to another thread or saved in an image.  These are exported by
+//! `(rusty-scheme sockets)`.
+
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use alloc::Heap;
+use port::{Buffering, Port};
+use value::{self, Kind, Value};
+use super::{Primitive, args, fixnum_arg};
+
+pub static PRIMITIVES: [Primitive; 8] =
+    [Primitive {
+         name: "tcp-connect",
+         min_args: 2,
+         max_args: Some(3),
+         function: tcp_connect,
+     },
+     Primitive {
+         name: "tcp-listen",
+         min_args: 2,
+         max_args: Some(2),
+         function: tcp_listen,
+     },
+     Primitive {
+         name: "tcp-accept",
+         min_args: 1,
+         max_args: Some(2),
+         function: tcp_accept,
+     },
+     Primitive {
+         name: "udp-open",
+         min_args: 2,
+         max_args: Some(2),
+         function: udp_open,
+     },
+     Primitive {
+         name: "udp-send",
+         min_args: 4,
+         max_args: Some(4),
+         function: udp_send,
+     },
+     Primitive {
+         name: "udp-receive",
+         min_args: 1,
+         max_args: Some(1),
+         function: udp_receive,
+     },
+     Primitive {
+         name: "socket-local-port",
+         min_args: 1,
+         max_args: Some(1),
+         function: socket_local_port,
+     },
+     Primitive {
+         name: "socket-close",
+         min_args: 1,
+         max_args: Some(1),
+         function: socket_close,
+     }];
+
+/// The code of every listener and UDP socket.  It is not bound to a global
+/// variable.
+pub static SOCKET: Primitive = Primitive {
+    name: "socket",
+    min_args: 0,
+    max_args: None,
+    function: not_a_procedure,
+};
+
+/// The largest UDP datagram.
+const MAX_DATAGRAM: usize = 65536;
+
+/// What a listener or UDP socket refers to.
+enum Socket {
+    Listener(TcpListener),
+    Udp(UdpSocket),
+    Closed,
+}
+
+/// Calling a socket.
+fn not_a_procedure(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("Attempt to call a socket, which is not a procedure".to_owned())
+}
+
+/// The socket `value`, an argument of the primitive `name`.
+fn socket_arg<'a>(value: &Value, name: &str) -> Result<&'a mut Socket, String> {
+    if let Kind::Closure(closure) = value.kind() {
+        unsafe {
+            if let Kind::Primitive(primitive) = (*closure).code.kind() {
+                if primitive == &SOCKET as *const Primitive {
+                    if let Kind::RustData(data) = (*closure).captured(0).kind() {
+                        if let Some(socket) = (*data).downcast_mut() {
+                            return Ok(socket)
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Err(format!("{}: expected a socket", name))
+}
+
+/// The address made of the host and port arguments at `index` and
+/// `index + 1` of the primitive `name`.
+fn address_arg(args: &[Value], index: usize, name: &str) -> Result<(String, u16), String> {
+    let host = match args[index].kind() {
+        Kind::String(string) => unsafe { (*string).as_str() }.to_owned(),
+        _ => return Err(format!("{}: expected a host name", name)),
+    };
+    let port = try!(fixnum_arg(&args[index + 1], name));
+    if port > 0xffff {
+        return Err(format!("{}: bad port number {}", name, port))
+    }
+    Ok((host, port as u16))
+}
+
+/// Allocates a socket referring to `socket`, and returns it.
+fn alloc_socket(heap: &mut Heap, socket: Socket) -> Result<Value, String> {
+    let start = heap.stack.len();
+    heap.stack.push(SOCKET.to_value());
+    let result = heap.alloc_rustdata(Box::new(socket))
+                     .and_then(|()| heap.alloc_closure(start, start + 2));
+    let object = result.map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    object
+}
+
+/// Returns an input port and an output port over `stream`, as two values,
+/// for the primitive `name`.
+fn stream_ports(heap: &mut Heap,
+                stream: TcpStream,
+                textual: bool,
+                name: &str)
+                -> Result<Value, String> {
+    let peer = try!(stream.peer_addr().map_err(|e| format!("{}: {}", name, e))).to_string();
+    let reader = try!(stream.try_clone().map_err(|e| format!("{}: {}", name, e)));
+    let base = heap.stack.len();
+    let result = heap.alloc_port(Port::input(&peer, Box::new(reader), textual))
+                     .and_then(|()| {
+                         heap.alloc_port(Port::output(&peer,
+                                                      Box::new(stream),
+                                                      textual,
+                                                      Buffering::Block))
+                     })
+                     .and_then(|()| heap.alloc_values(base, base + 2));
+    let values = result.map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(base);
+    values
+}
+
+/// Whether the optional `binary?` argument at `index` is missing or false.
+fn textual_arg(args: &[Value], index: usize) -> bool {
+    args.get(index).map_or(true, |binary| binary.get() == value::FALSE)
+}
+
+/// `(tcp-connect host port [binary?])`
+fn tcp_connect(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let (host, port) = try!(address_arg(&args, 0, "tcp-connect"));
+    let stream = try!(TcpStream::connect((&*host, port))
+                          .map_err(|e| format!("tcp-connect: {}:{}: {}", host, port, e)));
+    stream_ports(heap, stream, textual_arg(&args, 2), "tcp-connect")
+}
+
+/// `(tcp-listen host port)`
+fn tcp_listen(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (host, port) = try!(address_arg(&args(heap, nargs), 0, "tcp-listen"));
+    let listener = try!(TcpListener::bind((&*host, port))
+                            .map_err(|e| format!("tcp-listen: {}:{}: {}", host, port, e)));
+    alloc_socket(heap, Socket::Listener(listener))
+}
+
+/// `(tcp-accept listener [binary?])`
+fn tcp_accept(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let stream = match *try!(socket_arg(&args[0], "tcp-accept")) {
+        Socket::Listener(ref listener) => {
+            try!(listener.accept().map_err(|e| format!("tcp-accept: {}", e))).0
+        }
+        _ => return Err("tcp-accept: expected an open listener".to_owned()),
+    };
+    stream_ports(heap, stream, textual_arg(&args, 1), "tcp-accept")
+}
+
+/// `(udp-open host port)`
+fn udp_open(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (host, port) = try!(address_arg(&args(heap, nargs), 0, "udp-open"));
+    let socket = try!(UdpSocket::bind((&*host, port))
+                          .map_err(|e| format!("udp-open: {}:{}: {}", host, port, e)));
+    alloc_socket(heap, Socket::Udp(socket))
+}
+
+/// The open UDP socket `value`, an argument of the primitive `name`.
+fn udp_arg<'a>(value: &Value, name: &str) -> Result<&'a UdpSocket, String> {
+    match *try!(socket_arg(value, name)) {
+        Socket::Udp(ref socket) => Ok(socket),
+        _ => Err(format!("{}: expected an open UDP socket", name)),
+    }
+}
+
+/// `(udp-send socket bytevector host port)`
+fn udp_send(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let socket = try!(udp_arg(&args[0], "udp-send"));
+    let bytes = match args[1].kind() {
+        Kind::Bytevector(bytevector) => unsafe { (*bytevector).as_slice() }.to_vec(),
+        _ => return Err("udp-send: expected a bytevector".to_owned()),
+    };
+    let (host, port) = try!(address_arg(&args, 2, "udp-send"));
+    try!(socket.send_to(&bytes, (&*host, port)).map_err(|e| format!("udp-send: {}", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(udp-receive socket)`
+fn udp_receive(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let socket = try!(udp_arg(&args(heap, nargs)[0], "udp-receive"));
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    let (len, from): (usize, SocketAddr) =
+        try!(socket.recv_from(&mut buffer).map_err(|e| format!("udp-receive: {}", e)));
+    let base = heap.stack.len();
+    let result = heap.alloc_bytevector_from(&buffer[..len])
+                     .and_then(|()| heap.alloc_string(&from.ip().to_string()))
+                     .and_then(|()| {
+                         heap.stack.push(Value::new_fixnum(from.port() as usize));
+                         heap.alloc_values(base, base + 3)
+                     });
+    let values = result.map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(base);
+    values
+}
+
+/// `(socket-local-port socket)`
+fn socket_local_port(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let address = match *try!(socket_arg(&args(heap, nargs)[0], "socket-local-port")) {
+        Socket::Listener(ref listener) => listener.local_addr(),
+        Socket::Udp(ref socket) => socket.local_addr(),
+        Socket::Closed => return Err("socket-local-port: the socket is closed".to_owned()),
+    };
+    let address = try!(address.map_err(|e| format!("socket-local-port: {}", e)));
+    Ok(Value::new_fixnum(address.port() as usize))
+}
+
+/// `(socket-close socket)`.  Closing a closed socket does nothing.
+fn socket_close(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    *try!(socket_arg(&args(heap, nargs)[0], "socket-close")) = Socket::Closed;
+    Ok(Value::new(value::UNSPECIFIED))
+}
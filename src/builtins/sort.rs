@@ -0,0 +1,380 @@
+//! Sorting, from SRFI 132.
+//!
+//! Every sort is a stable merge sort, so `list-sort` and `list-stable-sort`
+//! are the same, and so are the vector sorts.  The elements are sorted on
+//! the stack, where the GC can find them while the comparison procedure
+//! runs, merging runs of doubling length into a copy and back.
+//!
+//! When the comparison procedure is one of the primitives `<`, `>`,
+//! `char<?`, `char>?`, `string<?` and `string>?`, and every element is of
+//! the type it compares, the elements are compared without calling it.
+//!
+//! These are exported by `(srfi 132)`.
+
+use std::cmp::Ordering;
+use alloc::Heap;
+use arith;
+use value::{self, Kind, Value};
+use super::{Primitive, args, balanced, boolean, call};
+use super::pair::list;
+use super::vector::{elements, list_elements, mutable_arg, new_list, new_vector, range, store,
+                    vector_arg};
+
+pub static PRIMITIVES: [Primitive; 10] =
+    [Primitive {
+         name: "list-sort",
+         min_args: 2,
+         max_args: Some(2),
+         function: list_sort,
+     },
+     Primitive {
+         name: "list-stable-sort",
+         min_args: 2,
+         max_args: Some(2),
+         function: list_sort,
+     },
+     Primitive {
+         name: "vector-sort",
+         min_args: 2,
+         max_args: Some(4),
+         function: vector_sort,
+     },
+     Primitive {
+         name: "vector-stable-sort",
+         min_args: 2,
+         max_args: Some(4),
+         function: vector_sort,
+     },
+     Primitive {
+         name: "vector-sort!",
+         min_args: 2,
+         max_args: Some(4),
+         function: vector_sort_in_place,
+     },
+     Primitive {
+         name: "vector-stable-sort!",
+         min_args: 2,
+         max_args: Some(4),
+         function: vector_sort_in_place,
+     },
+     Primitive {
+         name: "list-sorted?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_list_sorted,
+     },
+     Primitive {
+         name: "vector-sorted?",
+         min_args: 2,
+         max_args: Some(4),
+         function: is_vector_sorted,
+     },
+     Primitive {
+         name: "list-merge",
+         min_args: 3,
+         max_args: Some(3),
+         function: list_merge,
+     },
+     Primitive {
+         name: "vector-merge",
+         min_args: 3,
+         max_args: Some(3),
+         function: vector_merge,
+     }];
+
+/// Compares two values of the type a known comparison procedure accepts,
+/// or returns `None` if either is of another type.
+type Order = fn(&Value, &Value) -> Option<Ordering>;
+
+/// The order of numbers.
+fn numbers(x: &Value, y: &Value) -> Option<Ordering> {
+    arith::compare(x, y).ok().and_then(|order| order)
+}
+
+/// The order of characters.
+fn chars(x: &Value, y: &Value) -> Option<Ordering> {
+    match (x.as_char(), y.as_char()) {
+        (Ok(x), Ok(y)) => Some(x.cmp(&y)),
+        _ => None,
+    }
+}
+
+/// The order of strings.
+fn strings(x: &Value, y: &Value) -> Option<Ordering> {
+    match (x.kind(), y.kind()) {
+        (Kind::String(x), Kind::String(y)) => Some(unsafe { (*x).as_str().cmp((*y).as_str()) }),
+        _ => None,
+    }
+}
+
+/// The order that the primitive `procedure` tests, and whether it tests
+/// for the reverse of it, if it is one of the known comparisons.
+fn known_order(procedure: &Value) -> Option<(Order, bool)> {
+    let name = match procedure.kind() {
+        Kind::Primitive(primitive) => unsafe { (*primitive).name },
+        _ => return None,
+    };
+    Some(match name {
+        "<" => (numbers as Order, false),
+        ">" => (numbers as Order, true),
+        "char<?" => (chars as Order, false),
+        "char>?" => (chars as Order, true),
+        "string<?" => (strings as Order, false),
+        "string>?" => (strings as Order, true),
+        _ => return None,
+    })
+}
+
+/// Whether the procedure at `procedure` on the stack returns true when
+/// called on the values at `x` and `y`.
+fn less(heap: &mut Heap, procedure: usize, x: usize, y: usize) -> Result<bool, String> {
+    let top = heap.stack.len();
+    let (procedure, x, y) = (heap.stack[procedure].clone(),
+                             heap.stack[x].clone(),
+                             heap.stack[y].clone());
+    heap.stack.push(procedure);
+    heap.stack.push(x);
+    heap.stack.push(y);
+    match call(heap, 2) {
+        Ok(()) => Ok(heap.stack.pop().unwrap().get() != value::FALSE),
+        Err(e) => {
+            heap.stack.truncate(top);
+            Err(e)
+        }
+    }
+}
+
+/// Copies the value at `from` on the stack to `to`.
+fn copy(heap: &mut Heap, from: usize, to: usize) {
+    let value = heap.stack[from].clone();
+    heap.stack[to] = value
+}
+
+/// Merges the sorted runs from `left` to `middle` and from `middle` to
+/// `end` on the stack into the slots from `to` on.  Ties go to the left.
+fn merge(heap: &mut Heap,
+         procedure: usize,
+         left: usize,
+         middle: usize,
+         end: usize,
+         to: usize)
+         -> Result<(), String> {
+    let (mut i, mut j, mut k) = (left, middle, to);
+    while i < middle && j < end {
+        if try!(less(heap, procedure, j, i)) {
+            copy(heap, j, k);
+            j += 1
+        } else {
+            copy(heap, i, k);
+            i += 1
+        }
+        k += 1
+    }
+    for from in (i..middle).chain(j..end) {
+        copy(heap, from, k);
+        k += 1
+    }
+    Ok(())
+}
+
+/// Sorts the `len` values from `base` on the stack with the known `order`,
+/// if every one of them is of the type it compares.  Returns whether it
+/// did.
+fn sort_known(heap: &mut Heap, order: Order, reverse: bool, base: usize, len: usize) -> bool {
+    let mut values = heap.stack[base..base + len].to_vec();
+    if !values.iter().all(|value| order(value, value).is_some()) {
+        return false
+    }
+    // Nothing is called, so nothing can move while the values are sorted.
+    values.sort_by(|x, y| {
+        if reverse {
+            order(y, x).unwrap()
+        } else {
+            order(x, y).unwrap()
+        }
+    });
+    for (i, value) in values.into_iter().enumerate() {
+        heap.stack[base + i] = value
+    }
+    true
+}
+
+/// Sorts the `len` values from `base` on the stack by the procedure at
+/// `procedure`.  Pushes and drops a copy of them to merge into.
+fn sort(heap: &mut Heap, procedure: usize, base: usize, len: usize) -> Result<(), String> {
+    if let Some((order, reverse)) = known_order(&heap.stack[procedure]) {
+        if sort_known(heap, order, reverse, base, len) {
+            return Ok(())
+        }
+    }
+    let scratch = heap.stack.len();
+    for i in base..base + len {
+        let value = heap.stack[i].clone();
+        heap.stack.push(value)
+    }
+    let result = merge_passes(heap, procedure, base, scratch, len);
+    heap.stack.truncate(scratch);
+    result
+}
+
+/// Merges runs of doubling length between the `len` values at `base` and
+/// those at `scratch`, leaving them sorted at `base`.
+fn merge_passes(heap: &mut Heap,
+                procedure: usize,
+                base: usize,
+                scratch: usize,
+                len: usize)
+                -> Result<(), String> {
+    let (mut from, mut to) = (base, scratch);
+    let mut width = 1;
+    while width < len {
+        let mut left = 0;
+        while left < len {
+            let middle = if left + width < len { left + width } else { len };
+            let end = if middle + width < len { middle + width } else { len };
+            try!(merge(heap, procedure, from + left, from + middle, from + end, to + left));
+            left = end
+        }
+        ::std::mem::swap(&mut from, &mut to);
+        width *= 2
+    }
+    if from != base {
+        for i in 0..len {
+            copy(heap, from + i, base + i)
+        }
+    }
+    Ok(())
+}
+
+/// `(list-sort < list)` and `(list-stable-sort < list)`
+fn list_sort(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(list_elements(&args(heap, nargs)[1], "list-sort"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        heap.stack.extend_from_slice(&elements);
+        try!(sort(heap, procedure, base, elements.len()));
+        list(heap, elements.len())
+    })
+}
+
+/// The elements of the vector that is the second of the `nargs` arguments
+/// of the primitive `name`, between the optional `[start [end]]` that
+/// follow it.
+fn vector_range(heap: &Heap, nargs: usize, name: &str) -> Result<Vec<Value>, String> {
+    let args = args(heap, nargs);
+    let vector = try!(vector_arg(&args[1], name));
+    let (start, end) = try!(range(unsafe { (*vector).len() }, &args[2..], name));
+    Ok(elements(&args[1], start, end))
+}
+
+/// `(vector-sort < vector [start [end]])` and
+/// `(vector-stable-sort < vector [start [end]])`
+fn vector_sort(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(vector_range(heap, nargs, "vector-sort"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        heap.stack.extend_from_slice(&elements);
+        try!(sort(heap, procedure, base, elements.len()));
+        try!(heap.alloc_vector(base, base + elements.len()));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// `(vector-sort! vector < [start [end]])` and
+/// `(vector-stable-sort! vector < [start [end]])`
+fn vector_sort_in_place(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    // The procedure comes between the vector and the range.
+    let args = args(heap, nargs);
+    let len = try!(mutable_arg(&args[0], "vector-sort!").map(|vector| unsafe { (*vector).len() }));
+    let (start, end) = try!(range(len, &args[2..], "vector-sort!"));
+    let elements = elements(&args[0], start, end);
+    let vector = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        heap.stack.extend_from_slice(&elements);
+        try!(sort(heap, vector + 1, base, elements.len()));
+        for i in 0..elements.len() {
+            let value = heap.stack[base + i].clone();
+            store(heap, vector, start + i, value)
+        }
+        Ok(Value::new(value::UNSPECIFIED))
+    })
+}
+
+/// Whether the `len` values from `base` on the stack are sorted by the
+/// procedure at `procedure`: no value is less than the one before it.
+fn sorted(heap: &mut Heap, procedure: usize, base: usize, len: usize) -> Result<Value, String> {
+    for i in 1..len {
+        if try!(less(heap, procedure, base + i, base + i - 1)) {
+            return Ok(boolean(false))
+        }
+    }
+    Ok(boolean(true))
+}
+
+/// `(list-sorted? < list)`
+fn is_list_sorted(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(list_elements(&args(heap, nargs)[1], "list-sorted?"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        heap.stack.extend_from_slice(&elements);
+        sorted(heap, procedure, base, elements.len())
+    })
+}
+
+/// `(vector-sorted? < vector [start [end]])`
+fn is_vector_sorted(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(vector_range(heap, nargs, "vector-sorted?"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let base = heap.stack.len();
+        heap.stack.extend_from_slice(&elements);
+        sorted(heap, procedure, base, elements.len())
+    })
+}
+
+/// Merges the sorted `left` and `right` by the procedure at `procedure`,
+/// and returns the result.  Leaves them and the result on the stack.
+fn merge_two(heap: &mut Heap,
+             procedure: usize,
+             left: &[Value],
+             right: &[Value])
+             -> Result<Vec<Value>, String> {
+    let base = heap.stack.len();
+    heap.stack.extend_from_slice(left);
+    heap.stack.extend_from_slice(right);
+    let to = heap.stack.len();
+    heap.stack.extend_from_slice(left);
+    heap.stack.extend_from_slice(right);
+    try!(merge(heap, procedure, base, base + left.len(), to, to));
+    Ok(heap.stack[to..].to_vec())
+}
+
+/// `(list-merge < list1 list2)`
+fn list_merge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let left = try!(list_elements(&args[1], "list-merge"));
+    let right = try!(list_elements(&args[2], "list-merge"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let merged = try!(merge_two(heap, procedure, &left, &right));
+        new_list(heap, &merged)
+    })
+}
+
+/// `(vector-merge < vector1 vector2)`
+fn vector_merge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let (left, right) = (try!(vector_arg(&args[1], "vector-merge")),
+                         try!(vector_arg(&args[2], "vector-merge")));
+    let left = elements(&args[1], 0, unsafe { &*left }.len());
+    let right = elements(&args[2], 0, unsafe { &*right }.len());
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let merged = try!(merge_two(heap, procedure, &left, &right));
+        new_vector(heap, &merged)
+    })
+}
@@ -0,0 +1,911 @@
+//! Strings: the procedures of R7RS, and the common part of SRFI 13.
+//!
+//! Strings are immutable and stored as UTF-8, so there is no `string-set!`
+//! or `string-fill!`.  Indices count characters; a string is walked to find
+//! the byte offset of an index, unless it is ASCII, where the two agree.
+//! Searching, trimming and case mapping work on the UTF-8 bytes directly,
+//! with `str` methods, and the case mappings are the full Unicode ones, so
+//! `(string-upcase "straße")` is `"STRASSE"`.
+//!
+//! Where SRFI 13 takes a character set, these procedures take a character
+//! or a predicate on characters.  `(string-split string [delimiter])` is
+//! not in SRFI 13: it splits `string` at each occurrence of `delimiter`, a
+//! character or a string, or at each run of whitespace if it is missing.
+//!
+//! These are exported by `(scheme base)`, `(scheme char)` and `(srfi 13)`.
+
+use std::iter;
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, boolean, call, fixnum_arg};
+use super::pair::list;
+
+pub static PRIMITIVES: [Primitive; 43] =
+    [Primitive {
+         name: "string?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_string,
+     },
+     Primitive {
+         name: "make-string",
+         min_args: 1,
+         max_args: Some(2),
+         function: make_string,
+     },
+     Primitive {
+         name: "string",
+         min_args: 0,
+         max_args: None,
+         function: string,
+     },
+     Primitive {
+         name: "string-length",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_length,
+     },
+     Primitive {
+         name: "string-ref",
+         min_args: 2,
+         max_args: Some(2),
+         function: string_ref,
+     },
+     Primitive {
+         name: "substring",
+         min_args: 2,
+         max_args: Some(3),
+         function: substring,
+     },
+     Primitive {
+         name: "string-copy",
+         min_args: 1,
+         max_args: Some(3),
+         function: string_copy,
+     },
+     Primitive {
+         name: "string-append",
+         min_args: 0,
+         max_args: None,
+         function: string_append,
+     },
+     Primitive {
+         name: "string->list",
+         min_args: 1,
+         max_args: Some(3),
+         function: string_to_list,
+     },
+     Primitive {
+         name: "list->string",
+         min_args: 1,
+         max_args: Some(1),
+         function: list_to_string,
+     },
+     Primitive {
+         name: "string-map",
+         min_args: 2,
+         max_args: None,
+         function: string_map,
+     },
+     Primitive {
+         name: "string-for-each",
+         min_args: 2,
+         max_args: None,
+         function: string_for_each,
+     },
+     Primitive {
+         name: "string=?",
+         min_args: 1,
+         max_args: None,
+         function: string_eq,
+     },
+     Primitive {
+         name: "string<?",
+         min_args: 1,
+         max_args: None,
+         function: string_lt,
+     },
+     Primitive {
+         name: "string>?",
+         min_args: 1,
+         max_args: None,
+         function: string_gt,
+     },
+     Primitive {
+         name: "string<=?",
+         min_args: 1,
+         max_args: None,
+         function: string_le,
+     },
+     Primitive {
+         name: "string>=?",
+         min_args: 1,
+         max_args: None,
+         function: string_ge,
+     },
+     Primitive {
+         name: "string-ci=?",
+         min_args: 1,
+         max_args: None,
+         function: string_ci_eq,
+     },
+     Primitive {
+         name: "string-ci<?",
+         min_args: 1,
+         max_args: None,
+         function: string_ci_lt,
+     },
+     Primitive {
+         name: "string-ci>?",
+         min_args: 1,
+         max_args: None,
+         function: string_ci_gt,
+     },
+     Primitive {
+         name: "string-ci<=?",
+         min_args: 1,
+         max_args: None,
+         function: string_ci_le,
+     },
+     Primitive {
+         name: "string-ci>=?",
+         min_args: 1,
+         max_args: None,
+         function: string_ci_ge,
+     },
+     Primitive {
+         name: "string-upcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_upcase,
+     },
+     Primitive {
+         name: "string-downcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_downcase,
+     },
+     Primitive {
+         name: "string-foldcase",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_downcase,
+     },
+     Primitive {
+         name: "string-titlecase",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_titlecase,
+     },
+     Primitive {
+         name: "string-null?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_string_null,
+     },
+     Primitive {
+         name: "string-prefix?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_string_prefix,
+     },
+     Primitive {
+         name: "string-suffix?",
+         min_args: 2,
+         max_args: Some(2),
+         function: is_string_suffix,
+     },
+     Primitive {
+         name: "string-index",
+         min_args: 2,
+         max_args: Some(4),
+         function: string_index,
+     },
+     Primitive {
+         name: "string-index-right",
+         min_args: 2,
+         max_args: Some(4),
+         function: string_index_right,
+     },
+     Primitive {
+         name: "string-count",
+         min_args: 2,
+         max_args: Some(4),
+         function: string_count,
+     },
+     Primitive {
+         name: "string-contains",
+         min_args: 2,
+         max_args: Some(4),
+         function: string_contains,
+     },
+     Primitive {
+         name: "string-trim",
+         min_args: 1,
+         max_args: Some(2),
+         function: string_trim,
+     },
+     Primitive {
+         name: "string-trim-right",
+         min_args: 1,
+         max_args: Some(2),
+         function: string_trim_right,
+     },
+     Primitive {
+         name: "string-trim-both",
+         min_args: 1,
+         max_args: Some(2),
+         function: string_trim_both,
+     },
+     Primitive {
+         name: "string-pad",
+         min_args: 2,
+         max_args: Some(3),
+         function: string_pad,
+     },
+     Primitive {
+         name: "string-pad-right",
+         min_args: 2,
+         max_args: Some(3),
+         function: string_pad_right,
+     },
+     Primitive {
+         name: "string-join",
+         min_args: 1,
+         max_args: Some(3),
+         function: string_join,
+     },
+     Primitive {
+         name: "string-split",
+         min_args: 1,
+         max_args: Some(2),
+         function: string_split,
+     },
+     Primitive {
+         name: "string-concatenate",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_concatenate,
+     },
+     Primitive {
+         name: "string-reverse",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_reverse,
+     },
+     Primitive {
+         name: "string-tabulate",
+         min_args: 2,
+         max_args: Some(2),
+         function: string_tabulate,
+     }];
+
+/// The contents of the string `value`, an argument of the primitive `name`.
+/// They are invalidated by any allocation, so must be copied first if the
+/// primitive allocates before it is done with them.
+unsafe fn str_arg<'a>(value: &Value, name: &str) -> Result<&'a str, String> {
+    match value.kind() {
+        Kind::String(string) => Ok((*string).as_str()),
+        _ => Err(format!("{}: expected a string", name)),
+    }
+}
+
+/// A copy of the string `value`, an argument of the primitive `name`.
+fn string_arg(value: &Value, name: &str) -> Result<String, String> {
+    unsafe { str_arg(value, name) }.map(|s| s.to_owned())
+}
+
+/// Converts an argument to a character.
+fn char_arg(value: &Value, name: &str) -> Result<char, String> {
+    value.as_char().map_err(|_| format!("{}: expected a character", name))
+}
+
+/// A new string with the contents `s`.
+fn new_string(heap: &mut Heap, s: &str) -> Result<Value, String> {
+    try!(heap.alloc_string(s));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// The byte offset of the character at `index` in `s`, or the length of `s`
+/// if `index` is past its end.
+fn byte_offset(s: &str, index: usize) -> usize {
+    s.char_indices().nth(index).map_or(s.len(), |(offset, _)| offset)
+}
+
+/// The byte offsets in `s` of the optional `[start [end]]` character
+/// indices `bounds`, arguments of the primitive `name`.
+fn range(s: &str, bounds: &[Value], name: &str) -> Result<(usize, usize), String> {
+    let len = s.chars().count();
+    let start = match bounds.get(0) {
+        Some(start) => try!(fixnum_arg(start, name)),
+        None => 0,
+    };
+    let end = match bounds.get(1) {
+        Some(end) => try!(fixnum_arg(end, name)),
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(format!("{}: index out of range", name))
+    }
+    if len == s.len() {
+        Ok((start, end))
+    } else {
+        Ok((byte_offset(s, start), byte_offset(s, end)))
+    }
+}
+
+/// What SRFI 13 calls a character set.
+enum Matcher {
+    Char(char),
+    Whitespace,
+    /// A predicate, at this position on the stack.
+    Predicate(usize),
+}
+
+impl Matcher {
+    /// The matcher that is argument `index` of the primitive `name`, called
+    /// with `nargs` arguments.
+    fn arg(heap: &Heap, nargs: usize, index: usize, name: &str) -> Result<Matcher, String> {
+        let position = heap.stack.len() - nargs + index;
+        let value = &heap.stack[position];
+        match value.kind() {
+            Kind::Primitive(_) | Kind::Closure(_) => Ok(Matcher::Predicate(position)),
+            _ => {
+                char_arg(value, name)
+                    .map(Matcher::Char)
+                    .map_err(|_| format!("{}: expected a character or a predicate", name))
+            }
+        }
+    }
+
+    /// Whether `c` matches, calling the predicate if there is one.
+    fn matches(&self, heap: &mut Heap, c: char) -> Result<bool, String> {
+        match *self {
+            Matcher::Char(m) => Ok(m == c),
+            Matcher::Whitespace => Ok(c.is_whitespace()),
+            Matcher::Predicate(position) => {
+                let top = heap.stack.len();
+                let predicate = heap.stack[position].clone();
+                heap.stack.push(predicate);
+                heap.stack.push(Value::new_char(c));
+                match call(heap, 1) {
+                    Ok(()) => Ok(heap.stack.pop().unwrap().get() != value::FALSE),
+                    Err(e) => {
+                        heap.stack.truncate(top);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The byte offset of the first character of `s` that matches, or of
+    /// the last one if `reverse` is true.
+    fn find(&self, heap: &mut Heap, s: &str, reverse: bool) -> Result<Option<usize>, String> {
+        if let Matcher::Char(c) = *self {
+            return Ok(if reverse { s.rfind(c) } else { s.find(c) })
+        }
+        let mut chars: Vec<(usize, char)> = s.char_indices().collect();
+        if reverse {
+            chars.reverse()
+        }
+        for (offset, c) in chars {
+            if try!(self.matches(heap, c)) {
+                return Ok(Some(offset))
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Checks that every argument is a string, and that `ordered` holds for
+/// each pair of adjacent arguments, after folding their case if `fold` is
+/// true.
+fn compare(heap: &Heap,
+           nargs: usize,
+           name: &str,
+           fold: bool,
+           ordered: fn(&str, &str) -> bool)
+           -> Result<Value, String> {
+    let mut strings = vec![];
+    for arg in &args(heap, nargs) {
+        let s = try!(unsafe { str_arg(arg, name) });
+        strings.push(if fold { s.to_lowercase() } else { s.to_owned() })
+    }
+    Ok(boolean(strings.windows(2).all(|pair| ordered(&pair[0], &pair[1]))))
+}
+
+/// `(string? obj)`
+fn is_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(unsafe { str_arg(&args(heap, nargs)[0], "string?") }.is_ok()))
+}
+
+/// `(make-string k [char])`
+fn make_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let k = try!(fixnum_arg(&args[0], "make-string"));
+    let c = match args.get(1) {
+        Some(c) => try!(char_arg(c, "make-string")),
+        None => ' ',
+    };
+    let s: String = iter::repeat(c).take(k).collect();
+    new_string(heap, &s)
+}
+
+/// `(string char ...)`
+fn string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut s = String::new();
+    for c in &args(heap, nargs) {
+        s.push(try!(char_arg(c, "string")))
+    }
+    new_string(heap, &s)
+}
+
+/// `(string-length string)`
+fn string_length(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-length") });
+    Ok(Value::new_fixnum(s.chars().count()))
+}
+
+/// `(string-ref string k)`
+fn string_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(unsafe { str_arg(&args[0], "string-ref") });
+    let k = try!(fixnum_arg(&args[1], "string-ref"));
+    s.chars().nth(k).map(Value::new_char).ok_or_else(|| "string-ref: index out of range".to_owned())
+}
+
+/// `(substring string start [end])` and `(string-copy string [start [end]])`
+fn copy(heap: &mut Heap, nargs: usize, name: &str) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(string_arg(&args[0], name));
+    let (start, end) = try!(range(&s, &args[1..], name));
+    new_string(heap, &s[start..end])
+}
+
+/// `(substring string start [end])`
+fn substring(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    copy(heap, nargs, "substring")
+}
+
+/// `(string-copy string [start [end]])`
+fn string_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    copy(heap, nargs, "string-copy")
+}
+
+/// `(string-append string ...)`
+fn string_append(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut s = String::new();
+    for arg in &args(heap, nargs) {
+        s.push_str(try!(unsafe { str_arg(arg, "string-append") }))
+    }
+    new_string(heap, &s)
+}
+
+/// `(string->list string [start [end]])`
+fn string_to_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(unsafe { str_arg(&args[0], "string->list") });
+    let (start, end) = try!(range(s, &args[1..], "string->list"));
+    let chars: Vec<Value> = s[start..end].chars().map(Value::new_char).collect();
+    let base = heap.stack.len();
+    heap.stack.extend_from_slice(&chars);
+    let result = list(heap, chars.len());
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(list->string list)`
+fn list_to_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut s = String::new();
+    let mut rest = args(heap, nargs)[0].clone();
+    while let Ok(c) = rest.car() {
+        s.push(try!(char_arg(&c, "list->string")));
+        rest = rest.cdr().unwrap()
+    }
+    if rest.get() != value::NIL {
+        return Err("list->string: expected a list".to_owned())
+    }
+    new_string(heap, &s)
+}
+
+/// Calls the procedure that is the first of the `nargs` arguments of the
+/// primitive `name` on the characters of the strings that follow it, in
+/// order, stopping at the end of the shortest one.  Returns the results.
+fn map_chars(heap: &mut Heap, nargs: usize, name: &str) -> Result<Vec<Value>, String> {
+    let args = args(heap, nargs);
+    let mut strings = vec![];
+    for arg in &args[1..] {
+        strings.push(try!(string_arg(arg, name)).chars().collect::<Vec<char>>())
+    }
+    let len = strings.iter().map(|chars| chars.len()).min().unwrap();
+    let position = heap.stack.len() - nargs;
+    let mut results = vec![];
+    for i in 0..len {
+        let top = heap.stack.len();
+        let procedure = heap.stack[position].clone();
+        heap.stack.push(procedure);
+        for chars in &strings {
+            heap.stack.push(Value::new_char(chars[i]))
+        }
+        if let Err(e) = call(heap, strings.len()) {
+            heap.stack.truncate(top);
+            return Err(e)
+        }
+        results.push(heap.stack.pop().unwrap())
+    }
+    Ok(results)
+}
+
+/// `(string-map proc string1 string2 ...)`
+fn string_map(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut s = String::new();
+    for c in &try!(map_chars(heap, nargs, "string-map")) {
+        s.push(try!(char_arg(c, "string-map")))
+    }
+    new_string(heap, &s)
+}
+
+/// `(string-for-each proc string1 string2 ...)`
+fn string_for_each(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    try!(map_chars(heap, nargs, "string-for-each"));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(string=? string1 string2 ...)`
+fn string_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string=?", false, |a, b| a == b)
+}
+
+/// `(string<? string1 string2 ...)`
+fn string_lt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string<?", false, |a, b| a < b)
+}
+
+/// `(string>? string1 string2 ...)`
+fn string_gt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string>?", false, |a, b| a > b)
+}
+
+/// `(string<=? string1 string2 ...)`
+fn string_le(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string<=?", false, |a, b| a <= b)
+}
+
+/// `(string>=? string1 string2 ...)`
+fn string_ge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string>=?", false, |a, b| a >= b)
+}
+
+/// `(string-ci=? string1 string2 ...)`
+fn string_ci_eq(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string-ci=?", true, |a, b| a == b)
+}
+
+/// `(string-ci<? string1 string2 ...)`
+fn string_ci_lt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string-ci<?", true, |a, b| a < b)
+}
+
+/// `(string-ci>? string1 string2 ...)`
+fn string_ci_gt(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string-ci>?", true, |a, b| a > b)
+}
+
+/// `(string-ci<=? string1 string2 ...)`
+fn string_ci_le(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string-ci<=?", true, |a, b| a <= b)
+}
+
+/// `(string-ci>=? string1 string2 ...)`
+fn string_ci_ge(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    compare(heap, nargs, "string-ci>=?", true, |a, b| a >= b)
+}
+
+/// `(string-upcase string)`
+fn string_upcase(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-upcase") }).to_uppercase();
+    new_string(heap, &s)
+}
+
+/// `(string-downcase string)` and `(string-foldcase string)`.  Lowercasing
+/// is full case folding for all but a few characters, like `ẞ`.
+fn string_downcase(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-downcase") }).to_lowercase();
+    new_string(heap, &s)
+}
+
+/// `(string-titlecase string)`: the first letter of each word in upper
+/// case, and the others in lower case.
+fn string_titlecase(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-titlecase") });
+    let mut result = String::with_capacity(s.len());
+    let mut in_word = false;
+    for c in s.chars() {
+        if !c.is_alphabetic() {
+            result.push(c)
+        } else if in_word {
+            result.extend(c.to_lowercase())
+        } else {
+            result.extend(c.to_uppercase())
+        }
+        in_word = c.is_alphabetic()
+    }
+    new_string(heap, &result)
+}
+
+/// `(string-null? string)`
+fn is_string_null(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-null?") });
+    Ok(boolean(s.is_empty()))
+}
+
+/// `(string-prefix? prefix string)`
+fn is_string_prefix(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let prefix = try!(unsafe { str_arg(&args[0], "string-prefix?") });
+    let s = try!(unsafe { str_arg(&args[1], "string-prefix?") });
+    Ok(boolean(s.starts_with(prefix)))
+}
+
+/// `(string-suffix? suffix string)`
+fn is_string_suffix(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let suffix = try!(unsafe { str_arg(&args[0], "string-suffix?") });
+    let s = try!(unsafe { str_arg(&args[1], "string-suffix?") });
+    Ok(boolean(s.ends_with(suffix)))
+}
+
+/// `(string-index string char/pred [start [end]])` and
+/// `(string-index-right string char/pred [start [end]])`: the index of the
+/// first or last character in the range that matches, or `#f`.
+fn index(heap: &mut Heap, nargs: usize, name: &str, reverse: bool) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(string_arg(&args[0], name));
+    let matcher = try!(Matcher::arg(heap, nargs, 1, name));
+    let (start, end) = try!(range(&s, &args[2..], name));
+    Ok(match try!(matcher.find(heap, &s[start..end], reverse)) {
+        Some(offset) => Value::new_fixnum(s[..start + offset].chars().count()),
+        None => Value::new(value::FALSE),
+    })
+}
+
+/// `(string-index string char/pred [start [end]])`
+fn string_index(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    index(heap, nargs, "string-index", false)
+}
+
+/// `(string-index-right string char/pred [start [end]])`
+fn string_index_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    index(heap, nargs, "string-index-right", true)
+}
+
+/// `(string-count string char/pred [start [end]])`
+fn string_count(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(string_arg(&args[0], "string-count"));
+    let matcher = try!(Matcher::arg(heap, nargs, 1, "string-count"));
+    let (start, end) = try!(range(&s, &args[2..], "string-count"));
+    let mut count = 0;
+    for c in s[start..end].chars() {
+        if try!(matcher.matches(heap, c)) {
+            count += 1
+        }
+    }
+    Ok(Value::new_fixnum(count))
+}
+
+/// `(string-contains string pattern [start [end]])`: the index in `string`
+/// where `pattern` first occurs in the range, or `#f`.
+fn string_contains(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(unsafe { str_arg(&args[0], "string-contains") });
+    let pattern = try!(unsafe { str_arg(&args[1], "string-contains") });
+    let (start, end) = try!(range(s, &args[2..], "string-contains"));
+    Ok(match s[start..end].find(pattern) {
+        Some(offset) => Value::new_fixnum(s[..start + offset].chars().count()),
+        None => Value::new(value::FALSE),
+    })
+}
+
+/// Removes the characters that match `[char/pred]`, or whitespace, from the
+/// start of a string if `left` is true and from its end if `right` is.
+fn trim(heap: &mut Heap,
+        nargs: usize,
+        name: &str,
+        left: bool,
+        right: bool)
+        -> Result<Value, String> {
+    let s = try!(string_arg(&args(heap, nargs)[0], name));
+    let matcher = if nargs > 1 {
+        try!(Matcher::arg(heap, nargs, 1, name))
+    } else {
+        Matcher::Whitespace
+    };
+    let mut chars: Vec<(usize, char)> = s.char_indices().collect();
+    let (mut start, mut end) = (0, chars.len());
+    while left && start < end && try!(matcher.matches(heap, chars[start].1)) {
+        start += 1
+    }
+    while right && start < end && try!(matcher.matches(heap, chars[end - 1].1)) {
+        end -= 1
+    }
+    chars.push((s.len(), ' '));
+    new_string(heap, &s[chars[start].0..chars[end].0])
+}
+
+/// `(string-trim string [char/pred])`
+fn string_trim(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    trim(heap, nargs, "string-trim", true, false)
+}
+
+/// `(string-trim-right string [char/pred])`
+fn string_trim_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    trim(heap, nargs, "string-trim-right", false, true)
+}
+
+/// `(string-trim-both string [char/pred])`
+fn string_trim_both(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    trim(heap, nargs, "string-trim-both", true, true)
+}
+
+/// Pads a string to `n` characters with `[char]`, or spaces, on the left
+/// if `left` is true and on the right otherwise.  A longer string loses
+/// characters from the same side.
+fn pad(heap: &mut Heap, nargs: usize, name: &str, left: bool) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(unsafe { str_arg(&args[0], name) });
+    let n = try!(fixnum_arg(&args[1], name));
+    let c = match args.get(2) {
+        Some(c) => try!(char_arg(c, name)),
+        None => ' ',
+    };
+    let len = s.chars().count();
+    let padding = iter::repeat(c).take(n.saturating_sub(len));
+    let result: String = if left {
+        padding.chain(s.chars().skip(len.saturating_sub(n))).collect()
+    } else {
+        s.chars().take(n).chain(padding).collect()
+    };
+    new_string(heap, &result)
+}
+
+/// `(string-pad string n [char])`
+fn string_pad(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    pad(heap, nargs, "string-pad", true)
+}
+
+/// `(string-pad-right string n [char])`
+fn string_pad_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    pad(heap, nargs, "string-pad-right", false)
+}
+
+/// The strings in `list`, an argument of the primitive `name`, concatenated
+/// with `delimiter` between them.
+fn join(list: &Value, delimiter: &str, name: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = list.clone();
+    while let Ok(s) = rest.car() {
+        if rest.get() != list.get() {
+            result.push_str(delimiter)
+        }
+        result.push_str(try!(unsafe { str_arg(&s, name) }));
+        rest = rest.cdr().unwrap()
+    }
+    if rest.get() != value::NIL {
+        return Err(format!("{}: expected a list of strings", name))
+    }
+    Ok(result)
+}
+
+/// `(string-join list [delimiter [grammar]])`: the strings in `list`, with
+/// `delimiter`, which is `" "` if it is missing, between them if `grammar`
+/// is `infix` or `strict-infix`, before each of them if it is `prefix`, and
+/// after each of them if it is `suffix`.  `strict-infix` does not allow an
+/// empty list.
+fn string_join(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let delimiter = match args.get(1) {
+        Some(delimiter) => try!(unsafe { str_arg(delimiter, "string-join") }),
+        None => " ",
+    };
+    let grammar = match args.get(2).map(|grammar| grammar.kind()) {
+        Some(Kind::Symbol(symbol)) => unsafe { (*symbol).name() }.to_string(),
+        Some(_) => return Err("string-join: expected a symbol".to_owned()),
+        None => "infix".to_owned(),
+    };
+    let mut result = try!(join(&args[0], delimiter, "string-join"));
+    let empty = args[0].get() == value::NIL;
+    match &*grammar {
+        "infix" => {}
+        "strict-infix" if empty => {
+            return Err("string-join: expected a non-empty list".to_owned())
+        }
+        "strict-infix" => {}
+        "prefix" if !empty => result.insert_str(0, delimiter),
+        "suffix" if !empty => result.push_str(delimiter),
+        "prefix" | "suffix" => {}
+        _ => return Err(format!("string-join: unknown grammar {}", grammar)),
+    }
+    new_string(heap, &result)
+}
+
+/// `(string-split string [delimiter])`
+fn string_split(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let s = try!(string_arg(&args[0], "string-split"));
+    let parts: Vec<&str> = match args.get(1) {
+        None => s.split_whitespace().collect(),
+        Some(delimiter) if delimiter.charp() => s.split(delimiter.as_char().unwrap()).collect(),
+        Some(delimiter) => {
+            let delimiter = try!(unsafe { str_arg(delimiter, "string-split") });
+            if delimiter.is_empty() {
+                return Err("string-split: empty delimiter".to_owned())
+            }
+            s.split(delimiter).collect()
+        }
+    };
+    let base = heap.stack.len();
+    let mut result = Ok(());
+    for part in &parts {
+        result = heap.alloc_string(part);
+        if result.is_err() {
+            break
+        }
+    }
+    let result = result.and_then(|()| list(heap, parts.len()));
+    heap.stack.truncate(base);
+    result
+}
+
+/// `(string-concatenate list)`
+fn string_concatenate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(join(&args(heap, nargs)[0], "", "string-concatenate"));
+    new_string(heap, &s)
+}
+
+/// `(string-reverse string)`
+fn string_reverse(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let s = try!(unsafe { str_arg(&args(heap, nargs)[0], "string-reverse") });
+    let reversed: String = s.chars().rev().collect();
+    new_string(heap, &reversed)
+}
+
+/// `(string-tabulate proc len)`: the string of the characters that `proc`
+/// returns for each index below `len`.
+fn string_tabulate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = try!(fixnum_arg(&args(heap, nargs)[1], "string-tabulate"));
+    let position = heap.stack.len() - nargs;
+    let mut s = String::new();
+    for i in 0..len {
+        let top = heap.stack.len();
+        let procedure = heap.stack[position].clone();
+        heap.stack.push(procedure);
+        heap.stack.push(Value::new_fixnum(i));
+        if let Err(e) = call(heap, 1) {
+            heap.stack.truncate(top);
+            return Err(e)
+        }
+        s.push(try!(char_arg(&heap.stack.pop().unwrap(), "string-tabulate")))
+    }
+    new_string(heap, &s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byte_offset, range};
+    use value::Value;
+
+    #[test]
+    fn offsets() {
+        let s = "aéb€c";
+        assert_eq!(byte_offset(s, 0), 0);
+        assert_eq!(byte_offset(s, 2), 3);
+        assert_eq!(byte_offset(s, 4), 7);
+        assert_eq!(byte_offset(s, 5), 8);
+        let bounds = [Value::new_fixnum(1), Value::new_fixnum(4)];
+        assert_eq!(range(s, &bounds, "test"), Ok((1, 7)));
+        assert_eq!(range("abcde", &bounds, "test"), Ok((1, 4)));
+        assert!(range(s, &[Value::new_fixnum(6)], "test").is_err());
+    }
+}
@@ -0,0 +1,74 @@
+//! R7RS symbol procedures.
+
+use alloc::Heap;
+use value::{self, Value};
+use super::{Primitive, args, boolean};
+
+pub static PRIMITIVES: [Primitive; 4] =
+    [Primitive {
+         name: "symbol?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_symbol,
+     },
+     Primitive {
+         name: "symbol=?",
+         min_args: 1,
+         max_args: None,
+         function: symbols_equal,
+     },
+     Primitive {
+         name: "symbol->string",
+         min_args: 1,
+         max_args: Some(1),
+         function: symbol_to_string,
+     },
+     Primitive {
+         name: "string->symbol",
+         min_args: 1,
+         max_args: Some(1),
+         function: string_to_symbol,
+     }];
+
+/// Checks that `value` is a symbol.
+fn symbol_arg(value: &Value, procedure: &str) -> Result<(), String> {
+    if value.tag() == value::Tags::Symbol {
+        Ok(())
+    } else {
+        Err(format!("{}: expected a symbol", procedure))
+    }
+}
+
+/// `(symbol? obj)`
+fn is_symbol(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(args(heap, nargs)[0].tag() == value::Tags::Symbol))
+}
+
+/// `(symbol=? symbol1 symbol2 ...)`
+fn symbols_equal(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    for arg in &args {
+        try!(symbol_arg(arg, "symbol=?"))
+    }
+    // Interned symbols are equal if and only if they are the same object.
+    Ok(boolean(args.iter().all(|arg| *arg == args[0])))
+}
+
+/// `(symbol->string symbol)`
+fn symbol_to_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let symbol = args(heap, nargs)[0].clone();
+    let name = match symbol.kind() {
+        value::Kind::Symbol(ptr) => unsafe { (*ptr).name() },
+        _ => return Err("symbol->string: expected a symbol".to_owned()),
+    };
+    try!(heap.alloc_string(&name));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(string->symbol string)`
+fn string_to_symbol(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let string = args(heap, nargs)[0].clone();
+    let name = unsafe { try!(heap.string_as_str(&string)) }.to_owned();
+    heap.intern(&name);
+    Ok(heap.stack.pop().unwrap())
+}
@@ -0,0 +1,279 @@
+//! Native threads, each with its own heap, and channels between them.
+//!
+//! `(make-thread thunk)` calls `thunk` in a new OS thread, in a new
+//! interpreter, and `(thread-join! thread)` waits for it and returns its
+//! value.  Nothing is shared between the heaps: the thunk, the values sent
+//! through channels and the value of the thread are copied (see
+//! `message`).  So the new interpreter has its own global variables, and
+//! sees only what the thunk captured.  It is as limited as the one that
+//! started it: it has the same denied groups, fuel limit and heap limits.
+//!
+//! `(make-channel)` makes a channel, and `(channel-send! channel value)`
+//! and `(channel-receive channel)` send values through it, in order.
+//! `channel-receive` blocks until there is a value.
+//!
+//! Channels and threads are closures over `CHANNEL` and `THREAD`, which
+//! capture a pointer to a shared `Handle`.  Copying one to another heap
+//! copies the pointer, so both heaps refer to the same channel.  Each copy
+//! holds a reference count, released by a finalizer.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use alloc::{Heap, HeapConfig};
+use interp;
+use message::Message;
+use value::{self, Kind, Value};
+use super::{Group, Primitive, args, callee};
+
+pub static PRIMITIVES: [Primitive; 5] =
+    [Primitive {
+         name: "make-thread",
+         min_args: 1,
+         max_args: Some(1),
+         function: make_thread,
+     },
+     Primitive {
+         name: "thread-join!",
+         min_args: 1,
+         max_args: Some(1),
+         function: thread_join,
+     },
+     Primitive {
+         name: "make-channel",
+         min_args: 0,
+         max_args: Some(0),
+         function: make_channel,
+     },
+     Primitive {
+         name: "channel-send!",
+         min_args: 2,
+         max_args: Some(2),
+         function: channel_send,
+     },
+     Primitive {
+         name: "channel-receive",
+         min_args: 1,
+         max_args: Some(1),
+         function: channel_receive,
+     }];
+
+/// The code of every channel.  It is not bound to a global variable.
+pub static CHANNEL: Primitive = Primitive {
+    name: "channel",
+    min_args: 0,
+    max_args: None,
+    function: not_a_procedure,
+};
+
+/// The code of every thread.  It is not bound to a global variable.
+pub static THREAD: Primitive = Primitive {
+    name: "thread",
+    min_args: 0,
+    max_args: None,
+    function: not_a_procedure,
+};
+
+/// What a channel or a thread refers to.
+#[derive(Debug)]
+pub enum Handle {
+    /// The values sent and not yet received.
+    Channel {
+        queue: Mutex<VecDeque<Message>>,
+        ready: Condvar,
+    },
+
+    /// The thread, until it is joined.
+    Thread(Mutex<Option<JoinHandle<Result<Message, String>>>>),
+}
+
+/// The handle of `value`, if it is a channel or a thread.
+pub fn handle(value: &Value) -> Option<Arc<Handle>> {
+    let closure = match value.kind() {
+        Kind::Closure(closure) => closure,
+        _ => return None,
+    };
+    unsafe {
+        match (*closure).code.kind() {
+            Kind::Primitive(primitive) if primitive == &CHANNEL as *const Primitive ||
+                                          primitive == &THREAD as *const Primitive => {}
+            _ => return None,
+        }
+        // Take another reference, leaving that of the closure alone.
+        let handle = Arc::from_raw((*closure).captured(0).get() as *const Handle);
+        let copy = handle.clone();
+        ::std::mem::forget(handle);
+        Some(copy)
+    }
+}
+
+/// Allocates a channel or a thread referring to `handle`, and pushes it on
+/// the stack.
+pub fn alloc_handle(heap: &mut Heap, handle: Arc<Handle>) -> Result<(), String> {
+    let start = heap.stack.len();
+    heap.stack.push(match *handle {
+        Handle::Channel { .. } => CHANNEL.to_value(),
+        Handle::Thread(_) => THREAD.to_value(),
+    });
+    // The pointer is aligned, so the GC takes it for a fixnum.
+    let ptr = Arc::into_raw(handle) as usize;
+    heap.stack.push(Value::new(ptr));
+    let result = heap.alloc_closure(start, start + 2);
+    let object = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    let release = move || unsafe { drop(Arc::from_raw(ptr as *const Handle)) };
+    match result {
+        Ok(()) => {
+            heap.stack.push(object.clone());
+            heap.register_finalizer(object, Box::new(release))
+        }
+        Err(e) => {
+            release();
+            Err(e)
+        }
+    }
+}
+
+/// Calling a channel or a thread.
+fn not_a_procedure(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let name = match handle(&callee(heap, nargs)).map(|handle| match *handle {
+        Handle::Channel { .. } => "channel",
+        Handle::Thread(_) => "thread",
+    }) {
+        Some(name) => name,
+        None => bug!("not_a_procedure called on a procedure"),
+    };
+    Err(format!("Attempt to call a {}, which is not a procedure", name))
+}
+
+/// The channel `value`, or an error for the primitive `name`.
+fn channel(value: &Value, name: &str) -> Result<Arc<Handle>, String> {
+    match handle(value) {
+        Some(handle) => {
+            if let Handle::Channel { .. } = *handle {
+                return Ok(handle.clone())
+            }
+        }
+        None => {}
+    }
+    Err(format!("{}: expected a channel", name))
+}
+
+/// What a thread takes from the interpreter that starts it, so that it is no
+/// less limited: the denied groups, the fuel limit, deterministic mode and
+/// the configuration of the heap.
+struct Inherited {
+    denied: Vec<Group>,
+    fuel_limit: usize,
+    deterministic: Option<interp::Deterministic>,
+    config: HeapConfig,
+}
+
+// The configuration has no out-of-memory handler (see
+// `HeapConfig::without_handler`), which is the only part that is not `Send`.
+unsafe impl Send for Inherited {}
+
+impl Inherited {
+    fn new(heap: &Heap) -> Self {
+        Inherited {
+            denied: heap.control.denied().to_vec(),
+            fuel_limit: heap.control.fuel_limit(),
+            deterministic: heap.control.deterministic.clone(),
+            config: heap.config().without_handler(),
+        }
+    }
+
+    /// A new interpreter with these limits.
+    fn interpreter(self) -> interp::State {
+        let mut heap = Heap::with_config(self.config);
+        for group in self.denied {
+            heap.deny(group)
+        }
+        heap.control.set_fuel_limit(self.fuel_limit);
+        heap.control.deterministic = self.deterministic;
+        interp::State { heap: heap }
+    }
+}
+
+/// The body of a thread: calls the copy of `thunk` in a new interpreter.
+fn run(thunk: Message, inherited: Inherited) -> Result<Message, String> {
+    let mut state = inherited.interpreter();
+    let heap = &mut state.heap;
+    try!(thunk.push_to(heap));
+    try!(interp::call(heap, 0));
+    Message::new(heap.stack.last().unwrap())
+}
+
+/// `(make-thread thunk)`
+fn make_thread(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let thunk = args(heap, nargs)[0].clone();
+    match thunk.kind() {
+        Kind::Closure(_) | Kind::Primitive(_) => {}
+        _ => return Err("make-thread: expected a procedure".to_owned()),
+    }
+    let thunk = try!(Message::new(&thunk).map_err(|e| format!("make-thread: {}", e)));
+    let inherited = Inherited::new(heap);
+    let thread = try!(thread::Builder::new()
+                          .spawn(move || run(thunk, inherited))
+                          .map_err(|e| format!("make-thread: {}", e)));
+    try!(alloc_handle(heap, Arc::new(Handle::Thread(Mutex::new(Some(thread))))));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(thread-join! thread)`
+fn thread_join(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let handle = handle(&args(heap, nargs)[0]);
+    let thread = match handle.as_ref().map(|handle| &**handle) {
+        Some(&Handle::Thread(ref thread)) => thread.lock().unwrap().take(),
+        _ => return Err("thread-join!: expected a thread".to_owned()),
+    };
+    let result = match thread {
+        Some(thread) => thread.join(),
+        None => return Err("thread-join!: the thread was already joined".to_owned()),
+    };
+    match result {
+        Ok(Ok(message)) => try!(message.push_to(heap)),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err("thread-join!: the thread panicked".to_owned()),
+    }
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(make-channel)`
+fn make_channel(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    try!(alloc_handle(heap,
+                      Arc::new(Handle::Channel {
+                          queue: Mutex::new(VecDeque::new()),
+                          ready: Condvar::new(),
+                      })));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(channel-send! channel value)`
+fn channel_send(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let channel = try!(channel(&args[0], "channel-send!"));
+    let message = try!(Message::new(&args[1]).map_err(|e| format!("channel-send!: {}", e)));
+    if let Handle::Channel { ref queue, ref ready } = *channel {
+        queue.lock().unwrap().push_back(message);
+        ready.notify_one()
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(channel-receive channel)`
+fn channel_receive(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let channel = try!(channel(&args(heap, nargs)[0], "channel-receive"));
+    let message = match *channel {
+        Handle::Channel { ref queue, ref ready } => {
+            let mut queue = queue.lock().unwrap();
+            while queue.is_empty() {
+                queue = ready.wait(queue).unwrap()
+            }
+            queue.pop_front().unwrap()
+        }
+        Handle::Thread(_) => bug!("channel-receive on a thread"),
+    };
+    try!(message.push_to(heap));
+    Ok(heap.stack.pop().unwrap())
+}
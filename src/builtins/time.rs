@@ -0,0 +1,570 @@
+//! Time, from R7RS, and dates, in the style of SRFI 19.
+//!
+//! `(current-second)` is the number of seconds since the Unix epoch, as a
+//! float, and `(current-jiffy)` the number of microseconds, as a fixnum.
+//! In deterministic mode, the clock is a virtual one, which starts at the
+//! epoch and moves forward a jiffy each time it is read.
+//!
+//! A date is a point in time on the proleptic Gregorian calendar, in a time
+//! zone given as an offset from UTC in seconds, east being positive.  Only
+//! offsets are known: `std::time` has no time zone database, so dates are in
+//! UTC unless an offset is given.  `(make-date nanosecond second minute hour
+//! day month year zone-offset)` makes one, and `(seconds->date seconds
+//! [zone-offset])` and `(date->seconds date)` convert from and to seconds
+//! since the epoch.  Seconds with a fractional part are floats.
+//!
+//! `(date->string date [format])` formats a date.  In `format`, `~`
+//! introduces a directive, as in SRFI 19:
+//!
+//! * `~a` and `~A`: the abbreviated and full name of the day of the week
+//! * `~b` and `~B`: the abbreviated and full name of the month
+//! * `~d` and `~e`: the day of the month, padded with a zero or a space
+//! * `~H`, `~M` and `~S`: the hour, minute and second, on two digits
+//! * `~N`: the nanosecond, on nine digits
+//! * `~j`: the day of the year, on three digits
+//! * `~m`: the month, on two digits
+//! * `~y` and `~Y`: the year, on two digits or in full
+//! * `~z`: the zone offset, as `+hhmm`, or `Z` for UTC
+//! * `~1`, `~3`, `~4` and `~5`: `~Y-~m-~d`, `~H:~M:~S`,
+//!   `~Y-~m-~dT~H:~M:~S~z` and `~Y-~m-~dT~H:~M:~S` (ISO 8601)
+//! * `~c`: `~a ~b ~d ~H:~M:~S~z ~Y`, the default
+//! * `~~`: a tilde
+//!
+//! Dates are closures over `DATE`, which capture their fields as fixnums.
+//! These are exported by `(rusty-scheme time)`.
+
+use alloc::Heap;
+use arith;
+use platform;
+use value::{Kind, Value};
+use super::{Primitive, args, boolean, callee};
+
+pub static PRIMITIVES: [Primitive; 18] =
+    [Primitive {
+         name: "current-second",
+         min_args: 0,
+         max_args: Some(0),
+         function: current_second,
+     },
+     Primitive {
+         name: "current-jiffy",
+         min_args: 0,
+         max_args: Some(0),
+         function: current_jiffy,
+     },
+     Primitive {
+         name: "jiffies-per-second",
+         min_args: 0,
+         max_args: Some(0),
+         function: jiffies_per_second,
+     },
+     Primitive {
+         name: "make-date",
+         min_args: 8,
+         max_args: Some(8),
+         function: make_date,
+     },
+     Primitive {
+         name: "current-date",
+         min_args: 0,
+         max_args: Some(1),
+         function: current_date,
+     },
+     Primitive {
+         name: "seconds->date",
+         min_args: 1,
+         max_args: Some(2),
+         function: seconds_to_date,
+     },
+     Primitive {
+         name: "date->seconds",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_to_seconds,
+     },
+     Primitive {
+         name: "date?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_date,
+     },
+     Primitive {
+         name: "date-nanosecond",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-second",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-minute",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-hour",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-day",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-month",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-year",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-zone-offset",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_field,
+     },
+     Primitive {
+         name: "date-week-day",
+         min_args: 1,
+         max_args: Some(1),
+         function: date_week_day,
+     },
+     Primitive {
+         name: "date->string",
+         min_args: 1,
+         max_args: Some(2),
+         function: date_to_string,
+     }];
+
+/// The code of every date.  It is not bound to a global variable.
+pub static DATE: Primitive = Primitive {
+    name: "date",
+    min_args: 0,
+    max_args: None,
+    function: not_a_procedure,
+};
+
+/// The accessors of the fields of a date, in the order they are captured.
+static FIELDS: [&'static str; 8] = ["date-nanosecond",
+                                    "date-second",
+                                    "date-minute",
+                                    "date-hour",
+                                    "date-day",
+                                    "date-month",
+                                    "date-year",
+                                    "date-zone-offset"];
+
+static WEEK_DAYS: [&'static str; 7] =
+    ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+
+static MONTHS: [&'static str; 12] = ["January",
+                                     "February",
+                                     "March",
+                                     "April",
+                                     "May",
+                                     "June",
+                                     "July",
+                                     "August",
+                                     "September",
+                                     "October",
+                                     "November",
+                                     "December"];
+
+/// The number of jiffies in a second.
+const JIFFIES_PER_SECOND: u64 = 1_000_000;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// The fields of a date, in the order of `FIELDS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Date {
+    nanosecond: i64,
+    second: i64,
+    minute: i64,
+    hour: i64,
+    day: i64,
+    month: i64,
+    year: i64,
+    offset: i64,
+}
+
+/// `n / d`, rounded down.
+fn floor_div(n: i64, d: i64) -> i64 {
+    if n < 0 { (n - d + 1) / d } else { n / d }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// The number of days from the epoch to a day of the calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years start in March, so that leap days end them.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = floor_div(year, 400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The year, month and day that are `days` days from the epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = floor_div(days, 146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (if month <= 2 { year_of_era + era * 400 + 1 } else { year_of_era + era * 400 },
+     month,
+     day)
+}
+
+impl Date {
+    /// The date `seconds` and `nanosecond` after the epoch, in the zone
+    /// `offset`.
+    fn from_seconds(seconds: i64, nanosecond: i64, offset: i64) -> Self {
+        let local = seconds + offset;
+        let days = floor_div(local, SECONDS_PER_DAY);
+        let time = local - days * SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        Date {
+            nanosecond: nanosecond,
+            second: time % 60,
+            minute: time / 60 % 60,
+            hour: time / 3600,
+            day: day,
+            month: month,
+            year: year,
+            offset: offset,
+        }
+    }
+
+    /// The number of whole seconds from the epoch to the date.
+    fn seconds(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY + self.hour * 3600 +
+        self.minute * 60 + self.second - self.offset
+    }
+
+    /// The day of the week, Sunday being 0.
+    fn week_day(&self) -> i64 {
+        // The epoch was a Thursday.
+        let days = days_from_civil(self.year, self.month, self.day) + 4;
+        days - floor_div(days, 7) * 7
+    }
+
+    /// The day of the year, January 1 being 1.
+    fn year_day(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) - days_from_civil(self.year, 1, 1) + 1
+    }
+
+    fn fields(&self) -> [i64; 8] {
+        [self.nanosecond,
+         self.second,
+         self.minute,
+         self.hour,
+         self.day,
+         self.month,
+         self.year,
+         self.offset]
+    }
+}
+
+/// Calling a date.
+fn not_a_procedure(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("Attempt to call a date, which is not a procedure".to_owned())
+}
+
+/// The fixnum `value`, an argument of the primitive `name`.
+fn integer_arg(value: &Value, name: &str) -> Result<i64, String> {
+    if value.fixnump() {
+        Ok((value.get() as isize >> 2) as i64)
+    } else {
+        Err(format!("{}: expected a fixnum", name))
+    }
+}
+
+/// A fixnum holding `n`, which is known to fit.
+fn fixnum(n: i64) -> Value {
+    Value::from_fixnum_word(n as isize * 4).unwrap()
+}
+
+/// The date `value`, an argument of the primitive `name`.
+fn date_arg(value: &Value, name: &str) -> Result<Date, String> {
+    if let Kind::Closure(closure) = value.kind() {
+        if let Kind::Primitive(primitive) = unsafe { (*closure).code.kind() } {
+            if primitive == &DATE as *const Primitive {
+                let field = |index| {
+                    (unsafe { (*closure).captured(index).get() } as isize >> 2) as i64
+                };
+                return Ok(Date {
+                    nanosecond: field(0),
+                    second: field(1),
+                    minute: field(2),
+                    hour: field(3),
+                    day: field(4),
+                    month: field(5),
+                    year: field(6),
+                    offset: field(7),
+                })
+            }
+        }
+    }
+    Err(format!("{}: expected a date", name))
+}
+
+/// The zone offset argument at `index` of the primitive `name`, or UTC if
+/// it is missing.
+fn offset_arg(args: &[Value], index: usize, name: &str) -> Result<i64, String> {
+    match args.get(index) {
+        Some(offset) => {
+            let offset = try!(integer_arg(offset, name));
+            if offset.abs() >= SECONDS_PER_DAY {
+                return Err(format!("{}: bad zone offset {}", name, offset))
+            }
+            Ok(offset)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Allocates a date, and returns it.
+fn alloc_date(heap: &mut Heap, date: &Date) -> Result<Value, String> {
+    let start = heap.stack.len();
+    heap.stack.push(DATE.to_value());
+    heap.stack.extend(date.fields().iter().map(|&field| fixnum(field)));
+    let result = heap.alloc_closure(start, start + 1 + FIELDS.len());
+    let object = heap.stack.pop();
+    heap.stack.truncate(start);
+    try!(result);
+    Ok(object.unwrap())
+}
+
+/// The time since the epoch, as seconds and nanoseconds.  In deterministic
+/// mode, this is the time on the virtual clock, which then moves forward.
+fn now(heap: &mut Heap, name: &str) -> Result<(i64, i64), String> {
+    if let Some(ref mut deterministic) = heap.control.deterministic {
+        let nanoseconds = deterministic.nanoseconds;
+        deterministic.nanoseconds += 1_000_000_000 / JIFFIES_PER_SECOND;
+        return Ok(((nanoseconds / 1_000_000_000) as i64, (nanoseconds % 1_000_000_000) as i64))
+    }
+    let duration = try!(platform::since_epoch()
+                            .ok_or_else(|| format!("{}: the clock is before 1970", name)));
+    Ok((duration.as_secs() as i64, duration.subsec_nanos() as i64))
+}
+
+/// `(current-second)`
+fn current_second(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let (seconds, nanoseconds) = try!(now(heap, "current-second"));
+    try!(heap.alloc_float(seconds as f64 + nanoseconds as f64 / 1e9));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(current-jiffy)`
+fn current_jiffy(heap: &mut Heap, _: usize) -> Result<Value, String> {
+    let (seconds, nanoseconds) = try!(now(heap, "current-jiffy"));
+    let jiffies = seconds as u64 * JIFFIES_PER_SECOND +
+                  nanoseconds as u64 * JIFFIES_PER_SECOND / 1_000_000_000;
+    Ok(Value::new_fixnum(jiffies as usize))
+}
+
+/// `(jiffies-per-second)`
+fn jiffies_per_second(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Ok(Value::new_fixnum(JIFFIES_PER_SECOND as usize))
+}
+
+/// `(make-date nanosecond second minute hour day month year zone-offset)`
+fn make_date(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let mut fields = [0; 8];
+    for (field, arg) in fields.iter_mut().zip(&args) {
+        *field = try!(integer_arg(arg, "make-date"))
+    }
+    let date = Date {
+        nanosecond: fields[0],
+        second: fields[1],
+        minute: fields[2],
+        hour: fields[3],
+        day: fields[4],
+        month: fields[5],
+        year: fields[6],
+        offset: try!(offset_arg(&args, 7, "make-date")),
+    };
+    // A second of 60 is a leap second.
+    if date.nanosecond < 0 || date.nanosecond >= 1_000_000_000 || date.second < 0 ||
+       date.second > 60 || date.minute < 0 || date.minute >= 60 ||
+       date.hour < 0 || date.hour >= 24 || date.month < 1 || date.month > 12 ||
+       date.day < 1 || date.day > days_in_month(date.year, date.month) {
+        return Err("make-date: bad date".to_owned())
+    }
+    alloc_date(heap, &date)
+}
+
+/// `(current-date [zone-offset])`
+fn current_date(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let offset = try!(offset_arg(&args(heap, nargs), 0, "current-date"));
+    let (seconds, nanosecond) = try!(now(heap, "current-date"));
+    alloc_date(heap, &Date::from_seconds(seconds, nanosecond, offset))
+}
+
+/// `(seconds->date seconds [zone-offset])`
+fn seconds_to_date(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let offset = try!(offset_arg(&args, 1, "seconds->date"));
+    let (seconds, nanosecond) = if args[0].fixnump() {
+        ((args[0].get() as isize >> 2) as i64, 0)
+    } else {
+        match arith::to_float(&args[0]) {
+            Some(seconds) if seconds.is_finite() => {
+                let whole = seconds.floor();
+                let nanosecond = ((seconds - whole) * 1e9).round() as i64;
+                (whole as i64, ::std::cmp::min(nanosecond, 999_999_999))
+            }
+            _ => return Err("seconds->date: expected a number of seconds".to_owned()),
+        }
+    };
+    alloc_date(heap, &Date::from_seconds(seconds, nanosecond, offset))
+}
+
+/// `(date->seconds date)`: an integer, unless the date has a nanosecond.
+fn date_to_seconds(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let date = try!(date_arg(&args(heap, nargs)[0], "date->seconds"));
+    if date.nanosecond == 0 {
+        return Ok(fixnum(date.seconds()))
+    }
+    try!(heap.alloc_float(date.seconds() as f64 + date.nanosecond as f64 / 1e9));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(date? obj)`
+fn is_date(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(date_arg(&args(heap, nargs)[0], "date?").is_ok()))
+}
+
+/// The accessors of the fields of dates, like `date-year`.
+fn date_field(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let primitive = callee(heap, nargs);
+    let (index, name) = FIELDS.iter()
+                              .enumerate()
+                              .find(|&(_, name)| {
+                                  super::lookup(name).unwrap().to_value() == primitive
+                              })
+                              .map(|(index, &name)| (index, name))
+                              .unwrap();
+    let date = try!(date_arg(&args(heap, nargs)[0], name));
+    Ok(fixnum(date.fields()[index]))
+}
+
+/// `(date-week-day date)`: the day of the week, Sunday being 0.
+fn date_week_day(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let date = try!(date_arg(&args(heap, nargs)[0], "date-week-day"));
+    Ok(fixnum(date.week_day()))
+}
+
+/// Formats `date` according to `format` (see the module documentation).
+fn format_date(date: &Date, format: &str) -> Result<String, String> {
+    let mut string = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            string.push(c);
+            continue
+        }
+        match chars.next() {
+            Some('~') => string.push('~'),
+            Some('a') => string.push_str(&WEEK_DAYS[date.week_day() as usize][..3]),
+            Some('A') => string.push_str(WEEK_DAYS[date.week_day() as usize]),
+            Some('b') => string.push_str(&MONTHS[date.month as usize - 1][..3]),
+            Some('B') => string.push_str(MONTHS[date.month as usize - 1]),
+            Some('d') => string.push_str(&format!("{:02}", date.day)),
+            Some('e') => string.push_str(&format!("{:2}", date.day)),
+            Some('H') => string.push_str(&format!("{:02}", date.hour)),
+            Some('M') => string.push_str(&format!("{:02}", date.minute)),
+            Some('S') => string.push_str(&format!("{:02}", date.second)),
+            Some('N') => string.push_str(&format!("{:09}", date.nanosecond)),
+            Some('j') => string.push_str(&format!("{:03}", date.year_day())),
+            Some('m') => string.push_str(&format!("{:02}", date.month)),
+            Some('y') => {
+                let year = date.year - floor_div(date.year, 100) * 100;
+                string.push_str(&format!("{:02}", year))
+            }
+            Some('Y') => string.push_str(&date.year.to_string()),
+            Some('z') if date.offset == 0 => string.push('Z'),
+            Some('z') => {
+                let sign = if date.offset < 0 { '-' } else { '+' };
+                let minutes = date.offset.abs() / 60;
+                string.push_str(&format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60))
+            }
+            Some('1') => string.push_str(&try!(format_date(date, "~Y-~m-~d"))),
+            Some('3') => string.push_str(&try!(format_date(date, "~H:~M:~S"))),
+            Some('4') => string.push_str(&try!(format_date(date, "~Y-~m-~dT~H:~M:~S~z"))),
+            Some('5') => string.push_str(&try!(format_date(date, "~Y-~m-~dT~H:~M:~S"))),
+            Some('c') => string.push_str(&try!(format_date(date, "~a ~b ~d ~H:~M:~S~z ~Y"))),
+            Some(c) => return Err(format!("date->string: unknown directive ~{}", c)),
+            None => return Err("date->string: the format ends with ~".to_owned()),
+        }
+    }
+    Ok(string)
+}
+
+/// `(date->string date [format])`
+fn date_to_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let date = try!(date_arg(&args[0], "date->string"));
+    let string = match args.get(1).map(|format| format.kind()) {
+        Some(Kind::String(format)) => {
+            try!(format_date(&date, unsafe { (*format).as_str() }))
+        }
+        Some(_) => return Err("date->string: expected a format string".to_owned()),
+        None => try!(format_date(&date, "~c")),
+    };
+    try!(heap.alloc_string(&string));
+    Ok(heap.stack.pop().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calendar() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        for &days in &[-719468, -1, 59, 365 * 30 + 7, 11016, 1000000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days)
+        }
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        let date = Date::from_seconds(-1, 0, 0);
+        assert_eq!((date.year, date.month, date.day, date.hour, date.second),
+                   (1969, 12, 31, 23, 59));
+        assert_eq!(date.week_day(), 3);
+        assert_eq!(date.year_day(), 365);
+    }
+}
@@ -0,0 +1,67 @@
+//! Multiple values.
+//!
+//! `(values obj)` returns `obj` itself.  Any other number of values is
+//! returned as a `value::MultipleValues` object, which `call-with-values`
+//! spreads onto the stack as the arguments of its consumer.  The
+//! `define-values` and `let-values` forms expand to `call-with-values`.
+
+use alloc::Heap;
+use value::{Kind, Value};
+use super::{Primitive, call};
+
+pub static PRIMITIVES: [Primitive; 2] =
+    [Primitive {
+         name: "values",
+         min_args: 0,
+         max_args: None,
+         function: values,
+     },
+     Primitive {
+         name: "call-with-values",
+         min_args: 2,
+         max_args: Some(2),
+         function: call_with_values,
+     }];
+
+/// `(values obj ...)`
+pub fn values(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let len = heap.stack.len();
+    if nargs == 1 {
+        return Ok(heap.stack[len - 1].clone())
+    }
+    try!(heap.alloc_values(len - nargs, len));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(call-with-values producer consumer)`
+fn call_with_values(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let base = heap.stack.len();
+    let result = spread_and_call(heap, base - nargs);
+    heap.stack.truncate(base);
+    result
+}
+
+/// Calls the producer at stack index `first`, then the consumer after it
+/// with the values the producer returned.
+fn spread_and_call(heap: &mut Heap, first: usize) -> Result<Value, String> {
+    let producer = heap.stack[first].clone();
+    heap.stack.push(producer);
+    try!(call(heap, 0));
+    let produced = heap.stack.pop().unwrap();
+    let consumer = heap.stack[first + 1].clone();
+    heap.stack.push(consumer);
+    let nargs = match produced.kind() {
+        Kind::Values(values) => unsafe {
+            for i in 0..(*values).len() {
+                heap.stack.push((*values).value(i).clone())
+            }
+            (*values).len()
+        },
+        _ => {
+            heap.stack.push(produced);
+            1
+        }
+    };
+    try!(call(heap, nargs));
+    Ok(heap.stack.pop().unwrap())
+}
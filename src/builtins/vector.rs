@@ -0,0 +1,805 @@
+//! Vectors: the procedures of R7RS, and the vector library of SRFI 133.
+//!
+//! The procedures that take several vectors stop at the end of the shortest
+//! one.  Those that take other procedures call them through `call`, and
+//! keep the vectors and intermediate results on the stack while they run,
+//! so the GC can find them.  The in-place variants, like `vector-map!`
+//! and `vector-reverse!`, change their argument without allocating, and
+//! fail on the constant vectors made by `quote`.
+//!
+//! `(subvector vector start end)` is from SRFI 43, and is the same as
+//! `vector-copy` with both bounds.  The rest of SRFI 43 is not provided,
+//! since its `vector-fold` and `vector-map` pass indices to their
+//! procedures, unlike those of SRFI 133.
+//!
+//! These are exported by `(scheme base)` and `(srfi 133)`.
+
+use alloc::Heap;
+use value::{self, Kind, Value};
+use super::{Primitive, args, balanced, boolean, call, fixnum_arg};
+use super::pair::list;
+
+pub static PRIMITIVES: [Primitive; 36] =
+    [Primitive {
+         name: "vector?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_vector,
+     },
+     Primitive {
+         name: "make-vector",
+         min_args: 1,
+         max_args: Some(2),
+         function: make_vector,
+     },
+     Primitive {
+         name: "vector",
+         min_args: 0,
+         max_args: None,
+         function: vector,
+     },
+     Primitive {
+         name: "vector-length",
+         min_args: 1,
+         max_args: Some(1),
+         function: vector_length,
+     },
+     Primitive {
+         name: "vector-ref",
+         min_args: 2,
+         max_args: Some(2),
+         function: vector_ref,
+     },
+     Primitive {
+         name: "vector-set!",
+         min_args: 3,
+         max_args: Some(3),
+         function: vector_set,
+     },
+     Primitive {
+         name: "vector-empty?",
+         min_args: 1,
+         max_args: Some(1),
+         function: is_vector_empty,
+     },
+     Primitive {
+         name: "vector->list",
+         min_args: 1,
+         max_args: Some(3),
+         function: vector_to_list,
+     },
+     Primitive {
+         name: "reverse-vector->list",
+         min_args: 1,
+         max_args: Some(3),
+         function: reverse_vector_to_list,
+     },
+     Primitive {
+         name: "list->vector",
+         min_args: 1,
+         max_args: Some(1),
+         function: list_to_vector,
+     },
+     Primitive {
+         name: "vector->string",
+         min_args: 1,
+         max_args: Some(3),
+         function: vector_to_string,
+     },
+     Primitive {
+         name: "string->vector",
+         min_args: 1,
+         max_args: Some(3),
+         function: string_to_vector,
+     },
+     Primitive {
+         name: "vector-copy",
+         min_args: 1,
+         max_args: Some(3),
+         function: vector_copy,
+     },
+     Primitive {
+         name: "subvector",
+         min_args: 3,
+         max_args: Some(3),
+         function: subvector,
+     },
+     Primitive {
+         name: "vector-reverse-copy",
+         min_args: 1,
+         max_args: Some(3),
+         function: vector_reverse_copy,
+     },
+     Primitive {
+         name: "vector-copy!",
+         min_args: 3,
+         max_args: Some(5),
+         function: vector_copy_to,
+     },
+     Primitive {
+         name: "vector-reverse-copy!",
+         min_args: 3,
+         max_args: Some(5),
+         function: vector_reverse_copy_to,
+     },
+     Primitive {
+         name: "vector-append",
+         min_args: 0,
+         max_args: None,
+         function: vector_append,
+     },
+     Primitive {
+         name: "vector-concatenate",
+         min_args: 1,
+         max_args: Some(1),
+         function: vector_concatenate,
+     },
+     Primitive {
+         name: "vector-fill!",
+         min_args: 2,
+         max_args: Some(4),
+         function: vector_fill,
+     },
+     Primitive {
+         name: "vector-swap!",
+         min_args: 3,
+         max_args: Some(3),
+         function: vector_swap,
+     },
+     Primitive {
+         name: "vector-reverse!",
+         min_args: 1,
+         max_args: Some(3),
+         function: vector_reverse,
+     },
+     Primitive {
+         name: "vector-map",
+         min_args: 2,
+         max_args: None,
+         function: vector_map,
+     },
+     Primitive {
+         name: "vector-map!",
+         min_args: 2,
+         max_args: None,
+         function: vector_map_in_place,
+     },
+     Primitive {
+         name: "vector-for-each",
+         min_args: 2,
+         max_args: None,
+         function: vector_for_each,
+     },
+     Primitive {
+         name: "vector-fold",
+         min_args: 3,
+         max_args: None,
+         function: vector_fold,
+     },
+     Primitive {
+         name: "vector-fold-right",
+         min_args: 3,
+         max_args: None,
+         function: vector_fold_right,
+     },
+     Primitive {
+         name: "vector-count",
+         min_args: 2,
+         max_args: None,
+         function: vector_count,
+     },
+     Primitive {
+         name: "vector-index",
+         min_args: 2,
+         max_args: None,
+         function: vector_index,
+     },
+     Primitive {
+         name: "vector-index-right",
+         min_args: 2,
+         max_args: None,
+         function: vector_index_right,
+     },
+     Primitive {
+         name: "vector-skip",
+         min_args: 2,
+         max_args: None,
+         function: vector_skip,
+     },
+     Primitive {
+         name: "vector-skip-right",
+         min_args: 2,
+         max_args: None,
+         function: vector_skip_right,
+     },
+     Primitive {
+         name: "vector-any",
+         min_args: 2,
+         max_args: None,
+         function: vector_any,
+     },
+     Primitive {
+         name: "vector-every",
+         min_args: 2,
+         max_args: None,
+         function: vector_every,
+     },
+     Primitive {
+         name: "vector-binary-search",
+         min_args: 3,
+         max_args: Some(3),
+         function: vector_binary_search,
+     },
+     Primitive {
+         name: "vector-cumulate",
+         min_args: 3,
+         max_args: Some(3),
+         function: vector_cumulate,
+     }];
+
+/// The vector `value`, an argument of the primitive `name`.
+pub fn vector_arg(value: &Value, name: &str) -> Result<*mut value::Vector, String> {
+    match value.kind() {
+        Kind::Vector(vector) => Ok(vector),
+        _ => Err(format!("{}: expected a vector", name)),
+    }
+}
+
+/// The vector `value`, an argument of the primitive `name` that changes it.
+pub fn mutable_arg(value: &Value, name: &str) -> Result<*mut value::Vector, String> {
+    let vector = try!(vector_arg(value, name));
+    if value.immutablep() {
+        return Err(format!("{}: cannot modify a constant vector", name))
+    }
+    Ok(vector)
+}
+
+/// The length of the vector `value`, an argument of the primitive `name`.
+fn length_arg(value: &Value, name: &str) -> Result<usize, String> {
+    vector_arg(value, name).map(|vector| unsafe { (*vector).len() })
+}
+
+/// The element at `index` of the vector at `position` on the stack.
+fn element(heap: &Heap, position: usize, index: usize) -> Value {
+    match heap.stack[position].kind() {
+        Kind::Vector(vector) => unsafe { (*vector).element(index).clone() },
+        _ => bug!("element: not a vector"),
+    }
+}
+
+/// Stores `new` at `index` in the vector at `position` on the stack.
+pub fn store(heap: &mut Heap, position: usize, index: usize, new: Value) {
+    let vector = heap.stack[position].clone();
+    match vector.kind() {
+        Kind::Vector(pointer) => unsafe { (*pointer).element(index).set(new.clone()) },
+        _ => bug!("store: not a vector"),
+    }
+    heap.write_barrier(&vector, &new)
+}
+
+/// The optional `[start [end]]` indices `bounds` into a sequence of `len`
+/// elements, arguments of the primitive `name`.
+pub fn range(len: usize, bounds: &[Value], name: &str) -> Result<(usize, usize), String> {
+    let start = match bounds.get(0) {
+        Some(start) => try!(fixnum_arg(start, name)),
+        None => 0,
+    };
+    let end = match bounds.get(1) {
+        Some(end) => try!(fixnum_arg(end, name)),
+        None => len,
+    };
+    if start > end || end > len {
+        return Err(format!("{}: index out of range", name))
+    }
+    Ok((start, end))
+}
+
+/// The index `value` into a vector of `len` elements, an argument of the
+/// primitive `name`.
+fn index_arg(value: &Value, len: usize, name: &str) -> Result<usize, String> {
+    let index = try!(fixnum_arg(value, name));
+    if index >= len {
+        return Err(format!("{}: index out of range", name))
+    }
+    Ok(index)
+}
+
+/// A new vector of `elements`.
+pub fn new_vector(heap: &mut Heap, elements: &[Value]) -> Result<Value, String> {
+    balanced(heap, |heap| {
+        let start = heap.stack.len();
+        heap.stack.extend_from_slice(elements);
+        try!(heap.alloc_vector(start, start + elements.len()));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// The elements from `start` to `end` of the vector `value`.
+pub fn elements(value: &Value, start: usize, end: usize) -> Vec<Value> {
+    match value.kind() {
+        Kind::Vector(vector) => {
+            (start..end).map(|i| unsafe { (*vector).element(i) }.clone()).collect()
+        }
+        _ => bug!("elements: not a vector"),
+    }
+}
+
+/// The positions on the stack of the vectors that are the arguments from
+/// `first` on of the primitive `name`, called with `nargs` arguments, and
+/// the length of the shortest one.
+fn vectors(heap: &Heap,
+           nargs: usize,
+           first: usize,
+           name: &str)
+           -> Result<(Vec<usize>, usize), String> {
+    let base = heap.stack.len() - nargs;
+    let positions: Vec<usize> = (base + first..base + nargs).collect();
+    let mut len = None;
+    for &position in &positions {
+        let this = try!(length_arg(&heap.stack[position], name));
+        len = Some(len.map_or(this, |len| if this < len { this } else { len }))
+    }
+    Ok((positions, len.unwrap()))
+}
+
+/// Calls the procedure at `procedure` on the stack with the values `prefix`
+/// and the elements at `index` of the vectors at `positions`, and returns
+/// the result.
+fn call_on(heap: &mut Heap,
+           procedure: usize,
+           prefix: &[Value],
+           positions: &[usize],
+           index: usize)
+           -> Result<Value, String> {
+    let top = heap.stack.len();
+    let procedure = heap.stack[procedure].clone();
+    heap.stack.push(procedure);
+    heap.stack.extend_from_slice(prefix);
+    for &position in positions {
+        let element = element(heap, position, index);
+        heap.stack.push(element)
+    }
+    match call(heap, prefix.len() + positions.len()) {
+        Ok(()) => Ok(heap.stack.pop().unwrap()),
+        Err(e) => {
+            heap.stack.truncate(top);
+            Err(e)
+        }
+    }
+}
+
+/// `(vector? obj)`
+fn is_vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    Ok(boolean(vector_arg(&args(heap, nargs)[0], "vector?").is_ok()))
+}
+
+/// `(make-vector k [fill])`
+fn make_vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let k = try!(fixnum_arg(&args[0], "make-vector"));
+    let fill = args.get(1).cloned().unwrap_or(Value::new(value::UNSPECIFIED));
+    new_vector(heap, &vec![fill; k])
+}
+
+/// `(vector obj ...)`
+fn vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let start = heap.stack.len() - nargs;
+    try!(heap.alloc_vector(start, start + nargs));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(vector-length vector)`
+fn vector_length(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    length_arg(&args(heap, nargs)[0], "vector-length").map(Value::new_fixnum)
+}
+
+/// `(vector-ref vector k)`
+fn vector_ref(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(length_arg(&args[0], "vector-ref"));
+    let index = try!(index_arg(&args[1], len, "vector-ref"));
+    Ok(elements(&args[0], index, index + 1).pop().unwrap())
+}
+
+/// `(vector-set! vector k obj)`
+fn vector_set(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let vector = try!(mutable_arg(&args[0], "vector-set!"));
+    let index = try!(index_arg(&args[1], unsafe { (*vector).len() }, "vector-set!"));
+    let position = heap.stack.len() - nargs;
+    store(heap, position, index, args[2].clone());
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-empty? vector)`
+fn is_vector_empty(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    length_arg(&args(heap, nargs)[0], "vector-empty?").map(|len| boolean(len == 0))
+}
+
+/// The elements of the vector that is the first of the `nargs` arguments
+/// of the primitive `name`, between the optional `[start [end]]` that
+/// follow it.
+fn elements_arg(heap: &Heap, nargs: usize, name: &str) -> Result<Vec<Value>, String> {
+    let args = args(heap, nargs);
+    let len = try!(length_arg(&args[0], name));
+    let (start, end) = try!(range(len, &args[1..], name));
+    Ok(elements(&args[0], start, end))
+}
+
+/// A new list of `elements`.
+pub fn new_list(heap: &mut Heap, elements: &[Value]) -> Result<Value, String> {
+    balanced(heap, |heap| {
+        heap.stack.extend_from_slice(elements);
+        list(heap, elements.len())
+    })
+}
+
+/// `(vector->list vector [start [end]])`
+fn vector_to_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(elements_arg(heap, nargs, "vector->list"));
+    new_list(heap, &elements)
+}
+
+/// `(reverse-vector->list vector [start [end]])`
+fn reverse_vector_to_list(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut elements = try!(elements_arg(heap, nargs, "reverse-vector->list"));
+    elements.reverse();
+    new_list(heap, &elements)
+}
+
+/// The elements of the proper list `list`, an argument of the primitive
+/// `name`.
+pub fn list_elements(list: &Value, name: &str) -> Result<Vec<Value>, String> {
+    let mut elements = vec![];
+    let mut rest = list.clone();
+    while let Ok(element) = rest.car() {
+        elements.push(element);
+        rest = rest.cdr().unwrap()
+    }
+    if rest.get() != value::NIL {
+        return Err(format!("{}: expected a list", name))
+    }
+    Ok(elements)
+}
+
+/// `(list->vector list)`
+fn list_to_vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(list_elements(&args(heap, nargs)[0], "list->vector"));
+    new_vector(heap, &elements)
+}
+
+/// `(vector->string vector [start [end]])`
+fn vector_to_string(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut s = String::new();
+    for element in &try!(elements_arg(heap, nargs, "vector->string")) {
+        let c = element.as_char().map_err(|_| "vector->string: expected a vector of characters");
+        s.push(try!(c.map_err(|e| e.to_owned())))
+    }
+    try!(heap.alloc_string(&s));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(string->vector string [start [end]])`
+fn string_to_vector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let chars: Vec<Value> = match args[0].kind() {
+        Kind::String(s) => unsafe { (*s).as_str() }.chars().map(Value::new_char).collect(),
+        _ => return Err("string->vector: expected a string".to_owned()),
+    };
+    let (start, end) = try!(range(chars.len(), &args[1..], "string->vector"));
+    new_vector(heap, &chars[start..end])
+}
+
+/// `(vector-copy vector [start [end]])`
+fn vector_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(elements_arg(heap, nargs, "vector-copy"));
+    new_vector(heap, &elements)
+}
+
+/// `(subvector vector start end)`
+fn subvector(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let elements = try!(elements_arg(heap, nargs, "subvector"));
+    new_vector(heap, &elements)
+}
+
+/// `(vector-reverse-copy vector [start [end]])`
+fn vector_reverse_copy(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut elements = try!(elements_arg(heap, nargs, "vector-reverse-copy"));
+    elements.reverse();
+    new_vector(heap, &elements)
+}
+
+
+/// `(vector-copy! to at from [start [end]])` and
+/// `(vector-reverse-copy! to at from [start [end]])`, which copies the
+/// elements in reverse order.
+fn copy_to(heap: &mut Heap, nargs: usize, name: &str, reverse: bool) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let room = try!(mutable_arg(&args[0], name).map(|to| unsafe { (*to).len() }));
+    let at = try!(fixnum_arg(&args[1], name));
+    let len = try!(length_arg(&args[2], name));
+    let (start, end) = try!(range(len, &args[3..], name));
+    if at > room || end - start > room - at {
+        return Err(format!("{}: not enough room in the destination", name))
+    }
+    let (to, from) = (heap.stack.len() - nargs, heap.stack.len() - nargs + 2);
+    if reverse {
+        // The ranges may overlap, so the elements are all read first.
+        let elements = elements(&args[2], start, end);
+        for (i, element) in elements.into_iter().rev().enumerate() {
+            store(heap, to, at + i, element)
+        }
+    } else if at <= start {
+        for i in 0..end - start {
+            let element = element(heap, from, start + i);
+            store(heap, to, at + i, element)
+        }
+    } else {
+        // Copying from the end reads each element before it is overwritten.
+        for i in (0..end - start).rev() {
+            let element = element(heap, from, start + i);
+            store(heap, to, at + i, element)
+        }
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-copy! to at from [start [end]])`
+fn vector_copy_to(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    copy_to(heap, nargs, "vector-copy!", false)
+}
+
+/// `(vector-reverse-copy! to at from [start [end]])`
+fn vector_reverse_copy_to(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    copy_to(heap, nargs, "vector-reverse-copy!", true)
+}
+
+/// `(vector-append vector ...)`
+fn vector_append(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut all = vec![];
+    for arg in &args(heap, nargs) {
+        let len = try!(length_arg(arg, "vector-append"));
+        all.extend(elements(arg, 0, len))
+    }
+    new_vector(heap, &all)
+}
+
+/// `(vector-concatenate list)`
+fn vector_concatenate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut all = vec![];
+    for vector in &try!(list_elements(&args(heap, nargs)[0], "vector-concatenate")) {
+        let len = try!(length_arg(vector, "vector-concatenate"));
+        all.extend(elements(vector, 0, len))
+    }
+    new_vector(heap, &all)
+}
+
+/// `(vector-fill! vector fill [start [end]])`
+fn vector_fill(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(mutable_arg(&args[0], "vector-fill!").map(|vector| unsafe { (*vector).len() }));
+    let (start, end) = try!(range(len, &args[2..], "vector-fill!"));
+    let position = heap.stack.len() - nargs;
+    for i in start..end {
+        store(heap, position, i, args[1].clone())
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-swap! vector i j)`
+fn vector_swap(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(mutable_arg(&args[0], "vector-swap!").map(|vector| unsafe { (*vector).len() }));
+    let i = try!(index_arg(&args[1], len, "vector-swap!"));
+    let j = try!(index_arg(&args[2], len, "vector-swap!"));
+    let position = heap.stack.len() - nargs;
+    let (x, y) = (element(heap, position, i), element(heap, position, j));
+    store(heap, position, i, y);
+    store(heap, position, j, x);
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-reverse! vector [start [end]])`
+fn vector_reverse(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let len = try!(mutable_arg(&args[0], "vector-reverse!")
+                       .map(|vector| unsafe { (*vector).len() }));
+    let (mut start, mut end) = try!(range(len, &args[1..], "vector-reverse!"));
+    let position = heap.stack.len() - nargs;
+    while end > start + 1 {
+        end -= 1;
+        let (x, y) = (element(heap, position, start), element(heap, position, end));
+        store(heap, position, start, y);
+        store(heap, position, end, x);
+        start += 1
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-map proc vector1 vector2 ...)`
+fn vector_map(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-map"));
+    let procedure = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let start = heap.stack.len();
+        for i in 0..len {
+            let result = try!(call_on(heap, procedure, &[], &positions, i));
+            heap.stack.push(result)
+        }
+        try!(heap.alloc_vector(start, start + len));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
+
+/// `(vector-map! proc vector1 vector2 ...)`: stores the results in
+/// `vector1`.
+fn vector_map_in_place(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    try!(mutable_arg(&args(heap, nargs)[1], "vector-map!"));
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-map!"));
+    let procedure = heap.stack.len() - nargs;
+    for i in 0..len {
+        let result = try!(call_on(heap, procedure, &[], &positions, i));
+        store(heap, positions[0], i, result)
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-for-each proc vector1 vector2 ...)`
+fn vector_for_each(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-for-each"));
+    let procedure = heap.stack.len() - nargs;
+    for i in 0..len {
+        try!(call_on(heap, procedure, &[], &positions, i));
+    }
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(vector-fold kons knil vector1 vector2 ...)` and
+/// `(vector-fold-right kons knil vector1 vector2 ...)`: calls
+/// `(kons state element1 element2 ...)` from the left or from the right.
+fn fold(heap: &mut Heap, nargs: usize, name: &str, right: bool) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 2, name));
+    let (kons, state) = (heap.stack.len() - nargs, heap.stack.len() - nargs + 1);
+    for i in 0..len {
+        let i = if right { len - 1 - i } else { i };
+        let seed = heap.stack[state].clone();
+        let result = try!(call_on(heap, kons, &[seed], &positions, i));
+        heap.stack[state] = result
+    }
+    Ok(heap.stack[state].clone())
+}
+
+/// `(vector-fold kons knil vector1 vector2 ...)`
+fn vector_fold(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    fold(heap, nargs, "vector-fold", false)
+}
+
+/// `(vector-fold-right kons knil vector1 vector2 ...)`
+fn vector_fold_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    fold(heap, nargs, "vector-fold-right", true)
+}
+
+/// `(vector-count pred vector1 vector2 ...)`
+fn vector_count(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-count"));
+    let pred = heap.stack.len() - nargs;
+    let mut count = 0;
+    for i in 0..len {
+        if try!(call_on(heap, pred, &[], &positions, i)).get() != value::FALSE {
+            count += 1
+        }
+    }
+    Ok(Value::new_fixnum(count))
+}
+
+/// The index of the first element of the vectors, or of the last one if
+/// `right` is true, for which `pred` returns `wanted`, or `#f`.
+fn search(heap: &mut Heap,
+          nargs: usize,
+          name: &str,
+          right: bool,
+          wanted: bool)
+          -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, name));
+    let pred = heap.stack.len() - nargs;
+    for i in 0..len {
+        let i = if right { len - 1 - i } else { i };
+        if (try!(call_on(heap, pred, &[], &positions, i)).get() != value::FALSE) == wanted {
+            return Ok(Value::new_fixnum(i))
+        }
+    }
+    Ok(Value::new(value::FALSE))
+}
+
+/// `(vector-index pred vector1 vector2 ...)`
+fn vector_index(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    search(heap, nargs, "vector-index", false, true)
+}
+
+/// `(vector-index-right pred vector1 vector2 ...)`
+fn vector_index_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    search(heap, nargs, "vector-index-right", true, true)
+}
+
+/// `(vector-skip pred vector1 vector2 ...)`
+fn vector_skip(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    search(heap, nargs, "vector-skip", false, false)
+}
+
+/// `(vector-skip-right pred vector1 vector2 ...)`
+fn vector_skip_right(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    search(heap, nargs, "vector-skip-right", true, false)
+}
+
+/// `(vector-any pred vector1 vector2 ...)`: the first true result of
+/// `pred`, or `#f`.
+fn vector_any(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-any"));
+    let pred = heap.stack.len() - nargs;
+    for i in 0..len {
+        let result = try!(call_on(heap, pred, &[], &positions, i));
+        if result.get() != value::FALSE {
+            return Ok(result)
+        }
+    }
+    Ok(Value::new(value::FALSE))
+}
+
+/// `(vector-every pred vector1 vector2 ...)`: the last result of `pred`
+/// if none is false, `#t` if there are no elements, and `#f` otherwise.
+fn vector_every(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 1, "vector-every"));
+    let pred = heap.stack.len() - nargs;
+    let mut result = Value::new(value::TRUE);
+    for i in 0..len {
+        result = try!(call_on(heap, pred, &[], &positions, i));
+        if result.get() == value::FALSE {
+            break
+        }
+    }
+    Ok(result)
+}
+
+/// `(vector-binary-search vector value cmp)`: the index of an element of
+/// the sorted `vector` for which `(cmp element value)` is zero, or `#f`.
+/// `cmp` returns a negative number if `element` comes first, and a
+/// positive one if it comes after.
+fn vector_binary_search(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let args = args(heap, nargs);
+    let (mut low, mut high) = (0, try!(length_arg(&args[0], "vector-binary-search")));
+    let (vector, cmp) = (heap.stack.len() - nargs, heap.stack.len() - nargs + 2);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let value = heap.stack[vector + 1].clone();
+        let element = element(heap, vector, middle);
+        let order = try!(call_on(heap, cmp, &[element, value], &[], 0));
+        if !order.fixnump() {
+            return Err("vector-binary-search: expected cmp to return an integer".to_owned())
+        }
+        match (order.get() as isize).signum() {
+            0 => return Ok(Value::new_fixnum(middle)),
+            -1 => low = middle + 1,
+            _ => high = middle,
+        }
+    }
+    Ok(Value::new(value::FALSE))
+}
+
+/// `(vector-cumulate f knil vector)`: the vector of the states of a fold
+/// over `vector`, each computed as `(f state element)`.
+fn vector_cumulate(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let (positions, len) = try!(vectors(heap, nargs, 2, "vector-cumulate"));
+    let f = heap.stack.len() - nargs;
+    balanced(heap, |heap| {
+        let start = heap.stack.len();
+        let mut state = f + 1;
+        for i in 0..len {
+            let seed = heap.stack[state].clone();
+            let result = try!(call_on(heap, f, &[seed], &positions, i));
+            heap.stack.push(result);
+            state = heap.stack.len() - 1
+        }
+        try!(heap.alloc_vector(start, start + len));
+        Ok(heap.stack.pop().unwrap())
+    })
+}
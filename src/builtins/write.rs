@@ -0,0 +1,110 @@
+//! R7RS procedures that read and write data, through textual ports (see
+//! `port`), and `pretty-print`.  The port is an optional argument, which
+//! defaults to the current input or output port.
+
+use alloc::Heap;
+use port::Reader;
+use print::{self, Mode};
+use read::{self, Input};
+use value::{self, Value};
+use super::{Primitive, args};
+use super::port::{INPUT, OUTPUT, port, prefixed};
+
+pub static PRIMITIVES: [Primitive; 6] =
+    [Primitive {
+         name: "read",
+         min_args: 0,
+         max_args: Some(1),
+         function: read_primitive,
+     },
+     Primitive {
+         name: "write",
+         min_args: 1,
+         max_args: Some(2),
+         function: write,
+     },
+     Primitive {
+         name: "write-shared",
+         min_args: 1,
+         max_args: Some(2),
+         function: write_shared,
+     },
+     Primitive {
+         name: "write-simple",
+         min_args: 1,
+         max_args: Some(2),
+         function: write_simple,
+     },
+     Primitive {
+         name: "display",
+         min_args: 1,
+         max_args: Some(2),
+         function: display,
+     },
+     Primitive {
+         name: "pretty-print",
+         min_args: 1,
+         max_args: Some(2),
+         function: pretty_print,
+     }];
+
+/// The width of the lines that `pretty-print` fills.
+pub const PRETTY_WIDTH: usize = 79;
+
+/// `(read [port])`: the next datum, or the EOF object.  The byte that the
+/// reader peeks at past the end of the datum is given back to the port.
+fn read_primitive(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let port = try!(port(heap, nargs, 0, INPUT, true, "read"));
+    let name = port.name().to_owned();
+    let (result, peeked) = {
+        let mut input = Input::new(Reader(&mut *port), &name);
+        let result = read::read_to_heap(heap, &mut input);
+        (result, input.take_peeked())
+    };
+    if let Some(byte) = peeked {
+        try!(port.unread_u8(byte).map_err(|e| prefixed("read", e)))
+    }
+    match result {
+        Ok(()) => Ok(heap.stack.pop().unwrap()),
+        Err(e) => Err(format!("read: read error: {:?}", e)),
+    }
+}
+
+/// Writes the first argument with `mode` to the port that is the second
+/// one, if any.
+fn output(heap: &mut Heap, nargs: usize, mode: Mode, name: &str) -> Result<Value, String> {
+    let text = print::to_string(&args(heap, nargs)[0], mode);
+    let port = try!(port(heap, nargs, 1, OUTPUT, true, name));
+    try!(port.write_str(&text).map_err(|e| prefixed(name, e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
+
+/// `(write obj [port])`
+fn write(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    output(heap, nargs, Mode::Write, "write")
+}
+
+/// `(write-shared obj [port])`
+fn write_shared(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    output(heap, nargs, Mode::Shared, "write-shared")
+}
+
+/// `(write-simple obj [port])`
+fn write_simple(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    output(heap, nargs, Mode::Simple, "write-simple")
+}
+
+/// `(display obj [port])`
+fn display(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    output(heap, nargs, Mode::Display, "display")
+}
+
+/// `(pretty-print obj [port])`: writes `obj` as `write` does, broken across
+/// lines and indented (see `print::pretty`), and then a newline.
+fn pretty_print(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let mut text = print::pretty(&args(heap, nargs)[0], Mode::Write, PRETTY_WIDTH);
+    text.push('\n');
+    let port = try!(port(heap, nargs, 1, OUTPUT, true, "pretty-print"));
+    try!(port.write_str(&text).map_err(|e| prefixed("pretty-print", e)));
+    Ok(Value::new(value::UNSPECIFIED))
+}
@@ -1,11 +1,12 @@
-use std::ptr;
-use value;
-use alloc;
+use value::{self, Kind, Value};
+use alloc::Location;
+use print::{self, Mode};
 use std::cell;
+use std::fmt;
+use std::rc::Rc;
 
 /// A bytecode object.  Consists of a header, the length of the bytecodes,
-/// the actual bytecodes, and finally the constants vector (not actually part
-/// of the BCO, but always allocated after it).
+/// the constants vector, and finally the actual bytecodes.
 pub struct BCO {
     /// The standard header object
     header: usize,
@@ -21,9 +22,146 @@ pub fn get_constants_vector(bco: &BCO) -> &cell::UnsafeCell<value::Value> {
     &bco.constants_vector
 }
 
-/// The opcodes
+impl BCO {
+    /// The number of instructions.
+    pub fn len(&self) -> usize {
+        self.bytecode_length / 4
+    }
+
+    /// The instruction at `pc`, which must be less than `len()`.
+    pub fn instruction(&self, pc: usize) -> Bytecode {
+        debug_assert!(pc < self.len());
+        unsafe {
+            let start = size_of!(BCO) + 4 * pc;
+            let bytes = (self as *const BCO as *const u8).offset(start as isize);
+            Bytecode {
+                opcode: Opcode::from_u8(*bytes).expect("bad opcode"),
+                src: *bytes.offset(1),
+                src2: *bytes.offset(2),
+                dst: *bytes.offset(3),
+            }
+        }
+    }
+
+    /// The constants vector.
+    pub fn constants(&self) -> value::Value {
+        unsafe { (*self.constants_vector.get()).clone() }
+    }
+
+    /// Element `index` of the constants vector.
+    fn constant(&self, index: usize) -> Value {
+        let constants = self.constants();
+        unsafe { (*(constants.as_ptr() as *const value::Vector)).element(index).clone() }
+    }
+
+    /// The name of the procedure, if it has one.
+    pub fn name(&self) -> Option<Rc<String>> {
+        match self.constant(0).kind() {
+            Kind::Symbol(symbol) => Some(unsafe { (*symbol).name() }),
+            _ => None,
+        }
+    }
+
+    /// The location of the form that the instruction at `pc` was compiled
+    /// from, if it is known: the last entry of the source map at or before
+    /// `pc`.
+    pub fn location(&self, pc: usize) -> Option<Location> {
+        let map = self.constant(1);
+        let map = unsafe { &*(map.as_ptr() as *const value::Vector) };
+        let mut location = None;
+        for entry in 0..map.len() / 4 {
+            let field = |i| unsafe { map.element(4 * entry + i).clone() };
+            if field(0).as_fixnum().unwrap() > pc {
+                break
+            }
+            let file = match field(1).kind() {
+                Kind::Symbol(symbol) => unsafe { (*symbol).name() },
+                _ => continue,
+            };
+            location = Some(Location {
+                file: file,
+                line: field(2).as_fixnum().unwrap(),
+                column: field(3).as_fixnum().unwrap(),
+            })
+        }
+        location
+    }
+
+    /// The number of registers of the frame that are live while the
+    /// instruction at `pc` runs, if the code has a stack map: a bytevector
+    /// holding that number for each instruction, after the entries of the
+    /// source map.  The other registers are scratch space.
+    pub fn live_registers(&self, pc: usize) -> Option<usize> {
+        let map = self.constant(1);
+        let map = unsafe { &*(map.as_ptr() as *const value::Vector) };
+        if map.len() % 4 != 1 {
+            return None
+        }
+        match unsafe { map.element(map.len() - 1) }.kind() {
+            Kind::Bytevector(live) => unsafe { (*live).as_slice().get(pc).map(|&n| n as usize) },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BCO {
+    /// Lists the instructions, with the constants they refer to and the
+    /// locations of the forms they were compiled from, and then the code of
+    /// the closures it makes.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => try!(writeln!(f, "{}:", name)),
+            None => try!(writeln!(f, "anonymous procedure:")),
+        }
+        let count = match self.constants().kind() {
+            Kind::Vector(vector) => unsafe { (*vector).len() },
+            _ => 0,
+        };
+        let (mut location, mut nested) = (None, vec![]);
+        for pc in 0..self.len() {
+            let here = self.location(pc);
+            if here.is_some() && here != location {
+                try!(writeln!(f, "        ; {}", here.as_ref().unwrap()));
+                location = here
+            }
+            let instruction = self.instruction(pc);
+            let index = instruction.wide_operand();
+            match instruction.opcode {
+                Opcode::LoadConstant | Opcode::LoadGlobal | Opcode::StoreGlobal |
+                Opcode::DefineGlobal | Opcode::Closure |
+                Opcode::LoadConstantCall if index < count => {
+                    let constant = self.constant(index);
+                    let text = match constant.kind() {
+                        Kind::Bytecode(code) => {
+                            nested.push(code);
+                            match unsafe { (*code).name() } {
+                                Some(name) => format!("#<code {}>", name),
+                                None => "#<code>".to_owned(),
+                            }
+                        }
+                        _ => print::to_string(&constant, Mode::Write),
+                    };
+                    let instruction = instruction.to_string();
+                    try!(writeln!(f, "  {:4}  {:<24}; {}", pc, instruction, text))
+                }
+                _ => try!(writeln!(f, "  {:4}  {}", pc, instruction)),
+            }
+        }
+        for code in nested {
+            try!(write!(f, "\n{}", unsafe { &*code }))
+        }
+        Ok(())
+    }
+}
+
+/// The opcodes.
+///
+/// Instructions work on the registers of the frame of the running procedure
+/// (see `interp`): its arguments, then its local variables, and then the
+/// temporaries the compiler allocates.  Each opcode says which of its
+/// operands name registers.
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Opcode {
     /// Implements `cons`.  `src` is the stack index of the source,
     /// `src2` is the stack index of the destination.  `dst` must be 0, 1, or 2
@@ -77,55 +215,180 @@ pub enum Opcode {
     /// Length of vector
     ArrayLen,
 
-    /// Function call
+    /// Call the procedure in register `src` with the `src2` arguments in
+    /// the registers after it, and put its result in register `src`.  The
+    /// registers after `src` are clobbered.
     Call,
 
-    /// Tail call
+    /// Like `Call`, but the callee replaces the current frame, and its
+    /// result is returned.
     TailCall,
 
-    /// Return from a function
+    /// Return the value in register `src`.
     Return,
 
-    /// Create a closure
+    /// Put a closure over the code at `wide()` in the constants vector into
+    /// register `src`, capturing the values in the registers after it, as
+    /// many as the code expects (see `Enter`).
     Closure,
 
     /// Mutation of stack slots
     Set,
 
-    /// Load from constant vector
+    /// Load the constant at `wide()` into register `src`.
     LoadConstant,
 
-    /// Load from environment
-    LoadEnvironment,
+    /// Only in the stack format (see `stack_bytecode`): push the variable
+    /// at `wide()` in the current frame.
+    LoadLocal,
+
+    /// Load the value at `wide()` captured by the closure being run into
+    /// register `src`.
+    LoadCaptured,
 
     /// Load from argument
     LoadArgument,
 
-    /// Load from global
+    /// Load the global named by the symbol at `wide()` in the constants
+    /// vector into register `src`.
     LoadGlobal,
 
-    /// Load `#f`
+    /// Load `#f` into register `src`.
     LoadFalse,
 
-    /// Load `#t`
+    /// Load `#t` into register `src`.
     LoadTrue,
 
-    /// Load the empty list
+    /// Load the empty list into register `src`.
     LoadNil,
 
-    /// Store to environment.  `src` is the stack index of the source.
-    /// `dst` is the stack index of the destination.
-    StoreEnvironment,
+    /// Only in the stack format: pop a value into the variable at `wide()`
+    /// in the current frame.
+    StoreLocal,
 
     /// Store to argument.  `src` is the index of the argument.
     StoreArgument,
 
-    /// Store to global.  `src` is the index of the global in the constants
-    /// vector.
+    /// Store register `src` into the global named by the symbol at `wide()`
+    /// in the constants vector, which must be bound.
     StoreGlobal,
+
+    /// Load the unspecified value into register `src`.
+    LoadUnspecified,
+
+    /// Only in the stack format: discard the top of the stack.
+    Pop,
+
+    /// Jump to the instruction at `long()`.
+    Jump,
+
+    /// Jump to the instruction at `wide()` if register `src` is `#f`.
+    JumpIfFalse,
+
+    /// The first instruction of a procedure without a rest argument.  `src`
+    /// is the number of arguments, `src2` the number of values that the
+    /// closures running the code capture, and `dst` the number of
+    /// registers, which start with the arguments.
+    Enter,
+
+    /// Define the global named by the symbol at `wide()` in the constants
+    /// vector as register `src`.
+    DefineGlobal,
+
+    /// Push `(before . after)`, from registers `src` and `src2`, onto the
+    /// wind list (see `interp::Control`).
+    Wind,
+
+    /// Pop the wind list.
+    Unwind,
+
+    /// Push the exception handler in register `src` onto the handler stack.
+    PushHandler,
+
+    /// Pop the handler stack.
+    PopHandler,
+
+    /// Push `(parameter . value)`, from registers `src` and `src2`, onto the
+    /// parameterization (see `interp::Control`).
+    PushParameter,
+
+    /// Pop the parameterization.
+    PopParameter,
+
+    /// Replace register `src` with a box holding its value.
+    Box,
+
+    /// Load the contents of the box in register `src2` into register `src`.
+    Unbox,
+
+    /// Store register `src2` into the box in register `src`.
+    SetBox,
+
+    /// Copy register `src2` into register `src`.
+    Move,
+
+    /// Like `Enter`, for a procedure with a rest argument, which comes
+    /// after the `src` required arguments.
+    EnterRest,
+
+    /// A superinstruction: `LoadConstant`, and then the call after it,
+    /// which is `Call`, `TailCall` or `CallJumpIfFalse`.  The call is left
+    /// in place, for jumps to it.  Superinstructions are made by the
+    /// optimizer (see `compiler::optimize`) and save dispatching the
+    /// second instruction.
+    LoadConstantCall,
+
+    /// A superinstruction: `Move`, and then the call after it, as for
+    /// `LoadConstantCall`.
+    MoveCall,
+
+    /// A superinstruction: `Call`, and then, if the callee is a primitive,
+    /// the `JumpIfFalse` on register `src` after it.  When it is not, the
+    /// callee's frame returns to the `JumpIfFalse`.
+    CallJumpIfFalse,
+
+    /// The only instruction of the code of a `case-lambda` procedure, in
+    /// place of `Enter`.  The `src2` values that its closures capture are
+    /// the procedures of its clauses, and the call goes on to the first of
+    /// them that accepts the arguments, in the same frame.
+    CaseLambda,
+
+    /// Like `Enter`, for a procedure with optional or keyword parameters,
+    /// which `Optional` describes.  Those that are not passed get the
+    /// default object (`value::DEFAULT`), which the code then replaces
+    /// with their defaults (see `JumpIfSupplied`).
+    EnterOptional,
+
+    /// Never run, only the second instruction after `EnterOptional`: the
+    /// procedure has `src` optional parameters after the required ones,
+    /// then a rest parameter if `src2` is 1, then `dst` keyword
+    /// parameters, named by the symbols at the start of the constants
+    /// vector, from index 2.  The registers start with them all.
+    Optional,
+
+    /// Jump to the instruction at `wide()` unless register `src` is the
+    /// default object.
+    JumpIfSupplied,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The number of opcodes.
+pub const OPCODE_COUNT: u8 = Opcode::JumpIfSupplied as u8 + 1;
+
+impl Opcode {
+    /// The opcode numbered `byte`, if there is one.
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        if byte < OPCODE_COUNT {
+            Some(unsafe { ::std::mem::transmute(byte) })
+        } else {
+            None
+        }
+    }
+}
+
+/// An instruction.  How the operands are used depends on the opcode: some
+/// use `src2` and `dst` together as a 16-bit operand (`wide()`), and some
+/// use all three as a 24-bit operand (`long()`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Bytecode {
     pub opcode: Opcode,
     pub src: u8,
@@ -133,121 +396,394 @@ pub struct Bytecode {
     pub dst: u8,
 }
 
-pub enum BadByteCode {
-    StackUnderflow {
-        index: usize,
-        depth: usize,
-        min: usize,
-    },
-    EnvOutOfRange {
-        index: usize,
-        required_length: usize,
-        actual_length: usize,
-    },
+impl Bytecode {
+    /// An instruction with operands `src` and `wide()`.
+    pub fn wide(opcode: Opcode, src: u8, wide: u16) -> Self {
+        Bytecode {
+            opcode: opcode,
+            src: src,
+            src2: wide as u8,
+            dst: (wide >> 8) as u8,
+        }
+    }
+
+    /// An instruction with operand `long()`, which must be less than
+    /// `1 << 24`.
+    pub fn long(opcode: Opcode, long: usize) -> Self {
+        debug_assert!(long < 1 << 24);
+        Bytecode {
+            opcode: opcode,
+            src: long as u8,
+            src2: (long >> 8) as u8,
+            dst: (long >> 16) as u8,
+        }
+    }
+
+    /// `src2` and `dst` as a 16-bit operand.
+    pub fn wide_operand(&self) -> usize {
+        self.src2 as usize | (self.dst as usize) << 8
+    }
+
+    /// `src`, `src2` and `dst` as a 24-bit operand.
+    pub fn long_operand(&self) -> usize {
+        self.src as usize | self.wide_operand() << 8
+    }
+
+    /// The instruction, as stored in a BCO.
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [self.opcode as u8, self.src, self.src2, self.dst]
+    }
 }
 
-pub fn allocate_bytecode(obj: &[u8], heap: &mut alloc::Heap) {
-    use value::HeaderTag;
-    let (val, _) = heap.alloc_raw((size_of!(BCO) + obj.len() + (size_of!(usize) - 1)) /
-                                  size_of!(value::Value),
-                                  HeaderTag::Bytecode);
-    let bco_obj = val as *mut BCO;
-    let consts_vector = heap.stack.pop().unwrap();
-    heap.stack.push(value::Value::new(val as usize | value::RUST_DATA_TAG));
-    unsafe {
-        (*bco_obj).bytecode_length = obj.len();
-        (*(*bco_obj).constants_vector.get()) = consts_vector;
-        ptr::copy_nonoverlapping(obj.as_ptr(),
-                                 (val as *mut u8).offset(size_of!(BCO) as isize),
-                                 obj.len())
+impl fmt::Display for Bytecode {
+    /// The opcode, followed by the operands it uses.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:?}", self.opcode));
+        match self.opcode {
+            Opcode::LoadConstant | Opcode::LoadCaptured | Opcode::LoadGlobal |
+            Opcode::StoreGlobal | Opcode::DefineGlobal | Opcode::Closure | Opcode::JumpIfFalse |
+            Opcode::LoadConstantCall | Opcode::JumpIfSupplied => {
+                write!(f, " {} {}", self.src, self.wide_operand())
+            }
+            Opcode::Call | Opcode::TailCall | Opcode::Move | Opcode::Unbox | Opcode::SetBox |
+            Opcode::Wind | Opcode::PushParameter | Opcode::MoveCall |
+            Opcode::CallJumpIfFalse => write!(f, " {} {}", self.src, self.src2),
+            Opcode::CaseLambda => write!(f, " {}", self.src2),
+            Opcode::LoadTrue | Opcode::LoadFalse | Opcode::LoadNil | Opcode::LoadUnspecified |
+            Opcode::Return | Opcode::Box | Opcode::PushHandler => write!(f, " {}", self.src),
+            Opcode::Jump => write!(f, " {}", self.long_operand()),
+            Opcode::LoadLocal | Opcode::StoreLocal => write!(f, " {}", self.wide_operand()),
+            Opcode::Unwind | Opcode::PopHandler | Opcode::PopParameter | Opcode::Pop => Ok(()),
+            _ => write!(f, " {} {} {}", self.src, self.src2, self.dst),
+        }
     }
 }
 
-pub enum SchemeResult {
-    BadBytecode(BadByteCode),
+/// Checks that running `bco`, and the code of the closures it makes, cannot
+/// go wrong in ways that the interpreter does not check for, since it
+/// trusts the code it runs: every jump stays in the code, register,
+/// constant and captured value operands are in range, constants are of the
+/// right kinds, and nothing but what the compiler generates is used.  Code
+/// that does not come from the compiler, such as that of compiled files,
+/// must be checked before it is run.  Registers need not be written before
+/// they are read, since `Enter` fills them in.
+pub fn verify(bco: &BCO) -> Result<(), String> {
+    if bco.len() > 0 && bco.instruction(0).src2 != 0 {
+        return Err("bad bytecode: top-level code cannot capture values".to_owned())
+    }
+    verify_code(bco)
 }
-#[cfg(none)]
-pub fn verify_bytecodes(b: &[Bytecode],
-                        argcount: u16,
-                        is_vararg: bool,
-                        environment_length: usize)
-                        -> Result<(), BadByteCode> {
-    let argcount: usize = argcount.into();
-    let mut i = 0;
-    let mut max_stack = 0;
-    let mut current_depth: usize = 0;
-    let mut current_stack: Vec<usize> = vec![];
-    let iter = b.iter();
-
-    macro_rules! check_stack {
-        ($min: expr) => (if current_depth <= $exp {
-            return Err(BadByteCode::StackUnderflow { index: i - 1,
-                                                     depth: current_depth,
-                                                     min: $min, })
-        } else {})
-    }
-    macro_rules! check_argument {
-        ($min: expr) => (if argcount <= $expr {
-            return Err(BadByteCode::StackUnderflow { index: i - 1,
-                                                     depth: current_depth,
-                                                     min: $min, })
-        } else {})
-    }
-    macro_rules! check_env {
-        ($min: expr) => (if environment_length <= $expr {
-            return Err(BadByteCode::EnvOutOfRange { index: i - 1,
-                                                    required_length: $expr + 1,
-                                                    actual_length:
-                                                    environment_length,
-            })
-        })
-    }
-    while let Some(opcode) = iter.next() {
-        i += 1;
-        match try!(byte_to_opcode(opcode)) {
-            Opcode::Cons => {
-                check_stack!(2);
-                current_depth -= 1;
-            }
-            Opcode::Car | Opcode::Cdr => {
-                check_stack!(1);
-            }
-            Opcode::SetCar | Opcode::SetCdr => {
-                check_stack!(2);
-            }
-            Opcode::IsPair => {
-                check_stack!(1);
+
+/// Checks `bco`, which runs in closures capturing the values its `Enter`
+/// says.
+fn verify_code(bco: &BCO) -> Result<(), String> {
+    let constants = match bco.constants().kind() {
+        Kind::Vector(vector) => vector,
+        _ => return Err("bad bytecode: its constants are not a vector".to_owned()),
+    };
+    let count = unsafe { (*constants).len() };
+    let constant = |index: usize| {
+        if index < count {
+            Some(unsafe { (*constants).element(index).clone() })
+        } else {
+            None
+        }
+    };
+    let map_ok = match constant(1).map(|map| map.kind()) {
+        Some(Kind::Vector(map)) => unsafe {
+            let len = (*map).len();
+            let stack_map_ok = match len % 4 {
+                0 => true,
+                1 => {
+                    match (*map).element(len - 1).kind() {
+                        Kind::Bytevector(live) => (*live).len == bco.len(),
+                        _ => false,
+                    }
+                }
+                _ => false,
+            };
+            stack_map_ok &&
+            (0..len - len % 4).all(|i| i % 4 == 1 || (*map).element(i).as_fixnum().is_ok())
+        },
+        _ => false,
+    };
+    if !map_ok {
+        return Err("bad bytecode: bad source map".to_owned())
+    }
+    let name = match bco.name() {
+        Some(name) => name.to_string(),
+        None => "anonymous procedure".to_owned(),
+    };
+    let error = |pc: usize, message: &str| {
+        Err(format!("bad bytecode in {} at pc {}: {}", name, pc, message))
+    };
+    let len = bco.len();
+    let enter = if len == 0 { None } else { Some(bco.instruction(0)) };
+    if let Some(Bytecode { opcode: Opcode::CaseLambda, .. }) = enter {
+        // What the clauses are is only known when it runs.
+        return if len == 1 {
+            Ok(())
+        } else {
+            error(1, "code after CaseLambda")
+        }
+    }
+    let (arguments, captured, registers) = match enter {
+        Some(Bytecode { opcode: Opcode::Enter, src, src2, dst }) => (src as usize, src2, dst),
+        Some(Bytecode { opcode: Opcode::EnterRest, src, src2, dst }) => {
+            (src as usize + 1, src2, dst)
+        }
+        Some(Bytecode { opcode: Opcode::EnterOptional, src, src2, dst }) if len > 1 => {
+            let optional = bco.instruction(1);
+            if optional.opcode != Opcode::Optional {
+                return error(1, "EnterOptional not followed by Optional")
             }
-            Opcode::PushTrue | Opcode::PushFalse | Opcode::PushNil => {
-                current_depth += 1;
+            let keys = optional.dst as usize;
+            let named = (2..2 + keys).all(|index| {
+                match constant(index).map(|key| key.kind()) {
+                    Some(Kind::Symbol(_)) => true,
+                    _ => false,
+                }
+            });
+            if !named {
+                return error(1, "keyword parameter not named by a symbol")
             }
-            Opcode::LoadGlobal | Opcode::LoadConstant => {
-                check_env!(src);
-                current_depth += 1;
+            (src as usize + optional.src as usize + optional.src2 as usize + keys, src2, dst)
+        }
+        _ => return error(0, "it does not start with Enter"),
+    };
+    if arguments > registers as usize {
+        return error(0, "the arguments do not fit in the registers")
+    }
+    // Whether registers `first..first + count` exist.
+    let fits = |first: u8, count: usize| first as usize + count <= registers as usize;
+
+    // The first instruction to run after the `Enter`.
+    let start = if enter.unwrap().opcode == Opcode::EnterOptional { 2 } else { 1 };
+    if len == start {
+        return error(start - 1, "it continues past the end of the code")
+    }
+    let mut reached = vec![false; len];
+    let mut pending = vec![start];
+    while let Some(pc) = pending.pop() {
+        if reached[pc] {
+            continue
+        }
+        reached[pc] = true;
+        let instruction = bco.instruction(pc);
+        let (src, src2, wide) = (instruction.src, instruction.src2, instruction.wide_operand());
+        // The instruction after a superinstruction is run as part of it.
+        let follows = |opcodes: &[Opcode]| {
+            pc + 1 < len && opcodes.contains(&bco.instruction(pc + 1).opcode)
+        };
+        let fused = match instruction.opcode {
+            Opcode::LoadConstantCall | Opcode::MoveCall => {
+                follows(&[Opcode::Call, Opcode::TailCall, Opcode::CallJumpIfFalse])
             }
-            Opcode::LoadArgument => {
-                check_argument!(src);
-                current_depth += 1
+            Opcode::CallJumpIfFalse => {
+                follows(&[Opcode::JumpIfFalse]) && bco.instruction(pc + 1).src == src
             }
-            Opcode::StoreArgument => {
-                check_argument!(src);
-                current_depth += 1
+            _ => true,
+        };
+        if !fused {
+            return error(pc, "superinstruction without the instruction it runs")
+        }
+        let ok = match instruction.opcode {
+            Opcode::LoadTrue | Opcode::LoadFalse | Opcode::LoadNil | Opcode::LoadUnspecified |
+            Opcode::Return | Opcode::Box => fits(src, 1),
+            Opcode::Move | Opcode::MoveCall | Opcode::Unbox | Opcode::SetBox => {
+                fits(src, 1) && fits(src2, 1)
             }
-            Opcode::LoadEnvironment => {
-                check_stack!(src);
-                current_depth += 1;
+            Opcode::Jump => true,
+            Opcode::LoadConstant | Opcode::LoadConstantCall => fits(src, 1) && wide < count,
+            Opcode::LoadCaptured => fits(src, 1) && wide < captured as usize,
+            Opcode::JumpIfFalse | Opcode::JumpIfSupplied => fits(src, 1),
+            Opcode::Call | Opcode::TailCall | Opcode::CallJumpIfFalse => {
+                fits(src, src2 as usize + 1)
             }
-            Opcode::ArraySet => {
-                check_stack!(2);
-                current_depth -= 1;
+            Opcode::LoadGlobal | Opcode::StoreGlobal | Opcode::DefineGlobal => {
+                match constant(wide).map(|symbol| symbol.kind()) {
+                    Some(Kind::Symbol(_)) => {}
+                    _ => return error(pc, "global variable not named by a symbol"),
+                }
+                fits(src, 1)
             }
-            Opcode::ArrayGet => {
-                check_stack!(2);
+            Opcode::Closure => {
+                match constant(wide).map(|code| code.kind()) {
+                    Some(Kind::Bytecode(code)) => {
+                        let code = unsafe { &*code };
+                        try!(verify_code(code));
+                        fits(src, code.instruction(0).src2 as usize + 1)
+                    }
+                    _ => return error(pc, "closure of something other than code"),
+                }
             }
-            Opcode::IsArray => {
-                check_stack!(1);
+            opcode => return error(pc, &format!("unexpected {:?}", opcode)),
+        };
+        if !ok {
+            return error(pc, "operand out of range")
+        }
+        let next = match instruction.opcode {
+            Opcode::Return | Opcode::TailCall => vec![],
+            Opcode::Jump => vec![instruction.long_operand()],
+            Opcode::JumpIfFalse | Opcode::JumpIfSupplied => vec![wide, pc + 1],
+            _ => vec![pc + 1],
+        };
+        for next in next {
+            if next >= len {
+                return error(pc, "it continues past the end of the code")
             }
-            Opcode::Vector => try!(iter.next().ok_or(BadByteCode::EOF)).into(),
+            pending.push(next)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::Heap;
+    use value::{self, Kind, Value};
+    use super::*;
+
+    fn op(opcode: Opcode, src: u8, wide: u16) -> Bytecode {
+        Bytecode::wide(opcode, src, wide)
+    }
+
+    /// An instruction with operands `src` and `src2`.
+    fn pair(opcode: Opcode, src: u8, src2: u8) -> Bytecode {
+        Bytecode {
+            opcode: opcode,
+            src: src,
+            src2: src2,
+            dst: 0,
+        }
+    }
+
+    fn enter(arguments: u8, captured: u8, registers: u8) -> Bytecode {
+        Bytecode {
+            opcode: Opcode::Enter,
+            src: arguments,
+            src2: captured,
+            dst: registers,
+        }
+    }
+
+    /// Makes a BCO of `code`, whose constants after its name and source map
+    /// are the top `constants` values on the stack, which it pops.
+    fn make(heap: &mut Heap, code: &[Bytecode], constants: usize) -> Value {
+        let base = heap.stack.len() - constants;
+        heap.stack.insert(base, Value::new(value::FALSE));
+        let len = heap.stack.len();
+        heap.alloc_vector(len, len).unwrap();
+        let map = heap.stack.pop().unwrap();
+        heap.stack.insert(base + 1, map);
+        let len = heap.stack.len();
+        heap.alloc_vector(base, len).unwrap();
+        let constants = heap.stack.pop().unwrap();
+        heap.stack.truncate(base);
+        heap.stack.push(constants);
+        let mut bytes = vec![];
+        for instruction in code {
+            bytes.extend_from_slice(&instruction.to_bytes())
         }
+        heap.alloc_bytecode(&bytes).unwrap();
+        heap.stack.pop().unwrap()
+    }
+
+    fn check(heap: &mut Heap, code: &[Bytecode], constants: usize) -> Result<(), String> {
+        match make(heap, code, constants).kind() {
+            Kind::Bytecode(bco) => verify(unsafe { &*bco }),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn verify_accepts_valid_code() {
+        let mut heap = Heap::new(1 << 10);
+        heap.intern("f");
+        heap.stack.push(Value::new_fixnum(1));
+        let code = [enter(1, 0, 2),
+                    op(Opcode::JumpIfFalse, 0, 4),
+                    op(Opcode::LoadTrue, 1, 0),
+                    op(Opcode::Return, 1, 0),
+                    op(Opcode::LoadGlobal, 0, 2),
+                    op(Opcode::LoadConstant, 1, 3),
+                    pair(Opcode::TailCall, 0, 1)];
+        assert_eq!(check(&mut heap, &code, 2), Ok(()));
+        // Closures see the values they capture.
+        let inner = make(&mut heap,
+                         &[enter(0, 1, 1),
+                           op(Opcode::LoadCaptured, 0, 0),
+                           pair(Opcode::Unbox, 0, 0),
+                           op(Opcode::Return, 0, 0)],
+                         0);
+        heap.stack.push(inner);
+        let code = [enter(1, 0, 2),
+                    op(Opcode::Box, 0, 0),
+                    pair(Opcode::Move, 1, 0),
+                    op(Opcode::Closure, 0, 2),
+                    op(Opcode::Return, 0, 0)];
+        assert_eq!(check(&mut heap, &code, 1), Ok(()));
+        assert!(heap.stack.is_empty());
+    }
+
+    #[test]
+    fn verify_rejects_bad_code() {
+        let mut heap = Heap::new(1 << 10);
+        let error = |pc: usize, message: &str| {
+            Err(format!("bad bytecode in anonymous procedure at pc {}: {}", pc, message))
+        };
+        let (load, ret) = (op(Opcode::LoadTrue, 0, 0), op(Opcode::Return, 0, 0));
+        assert_eq!(check(&mut heap, &[load, ret], 0),
+                   error(0, "it does not start with Enter"));
+        assert_eq!(check(&mut heap, &[enter(2, 0, 1), ret], 0),
+                   error(0, "the arguments do not fit in the registers"));
+        assert_eq!(check(&mut heap, &[enter(0, 0, 0), ret], 0),
+                   error(1, "operand out of range"));
+        assert_eq!(check(&mut heap, &[enter(0, 0, 1), load], 0),
+                   error(1, "it continues past the end of the code"));
+        assert_eq!(check(&mut heap, &[enter(0, 0, 1), Bytecode::long(Opcode::Jump, 9)], 0),
+                   error(1, "it continues past the end of the code"));
+        assert_eq!(check(&mut heap, &[enter(0, 0, 1), op(Opcode::JumpIfFalse, 0, 9), ret], 0),
+                   error(1, "it continues past the end of the code"));
+        let code = [enter(1, 0, 1), op(Opcode::LoadCaptured, 0, 0), ret];
+        assert_eq!(check(&mut heap, &code, 0), error(1, "operand out of range"));
+        let code = [enter(0, 0, 1), op(Opcode::LoadConstant, 0, 2), ret];
+        assert_eq!(check(&mut heap, &code, 0), error(1, "operand out of range"));
+        let code = [enter(0, 0, 1), pair(Opcode::Call, 0, 1), ret];
+        assert_eq!(check(&mut heap, &code, 0), error(1, "operand out of range"));
+        heap.stack.push(Value::new_fixnum(1));
+        let code = [enter(0, 0, 1), op(Opcode::LoadGlobal, 0, 2), ret];
+        assert_eq!(check(&mut heap, &code, 1),
+                   error(1, "global variable not named by a symbol"));
+        heap.stack.push(Value::new_fixnum(1));
+        let code = [enter(0, 0, 1), op(Opcode::Closure, 0, 2), ret];
+        assert_eq!(check(&mut heap, &code, 1),
+                   error(1, "closure of something other than code"));
+        let code = [enter(0, 0, 2), pair(Opcode::MoveCall, 1, 0), ret];
+        assert_eq!(check(&mut heap, &code, 0),
+                   error(1, "superinstruction without the instruction it runs"));
+        let code = [enter(0, 0, 2),
+                    pair(Opcode::CallJumpIfFalse, 0, 0),
+                    op(Opcode::JumpIfFalse, 1, 3),
+                    ret];
+        assert_eq!(check(&mut heap, &code, 0),
+                   error(1, "superinstruction without the instruction it runs"));
+        let code = [enter(0, 0, 1), op(Opcode::PushHandler, 0, 0), ret];
+        assert_eq!(check(&mut heap, &code, 0), error(1, "unexpected PushHandler"));
+        assert_eq!(check(&mut heap, &[enter(0, 1, 1), ret], 0),
+                   Err("bad bytecode: top-level code cannot capture values".to_owned()));
+        // Closures are checked too.
+        let inner = make(&mut heap,
+                         &[enter(0, 1, 1), op(Opcode::LoadCaptured, 0, 1), ret],
+                         0);
+        heap.stack.push(inner);
+        let code = [enter(0, 0, 2), op(Opcode::Closure, 0, 2), ret];
+        assert_eq!(check(&mut heap, &code, 1), error(1, "operand out of range"));
+        // And they must fit the registers they capture.
+        let inner = make(&mut heap, &[enter(0, 2, 1), ret], 0);
+        heap.stack.push(inner);
+        let code = [enter(0, 0, 2), op(Opcode::Closure, 0, 2), ret];
+        assert_eq!(check(&mut heap, &code, 1), error(1, "operand out of range"));
+        assert!(heap.stack.is_empty());
     }
 }
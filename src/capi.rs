@@ -0,0 +1,584 @@
+//! The C API, with the `capi` feature, for embedding the interpreter in
+//! programs not written in Rust.  `include/rusty_scheme.h` declares it.
+//!
+//! An interpreter is a `rusty_scheme *`, made by `rusty_scheme_new` and
+//! freed by `rusty_scheme_free`.  Values are made and inspected through a
+//! `rusty_scheme_context *`: `rusty_scheme_context` returns the one of an
+//! interpreter, and C callbacks are passed one.  A value is a
+//! `rusty_scheme_value *`, which keeps the Scheme value alive until it is
+//! freed by `rusty_scheme_value_free`; the arguments passed to a callback
+//! are only valid during the call.
+//!
+//! Functions that can fail return `NULL` or a negative number.  Those
+//! taking an interpreter keep the error message, which
+//! `rusty_scheme_error` returns until the next call that fails; those
+//! taking a context keep it in the context, so a callback that returns
+//! `NULL` fails with it, unless it passes another message to
+//! `rusty_scheme_set_error`.  Strings returned to C are
+//! freed by `rusty_scheme_string_free`.
+//!
+//! Pointers passed to these functions must be valid or `NULL`.  A `NULL`
+//! interpreter or context makes the call fail without a message, and any
+//! other `NULL` pointer makes it fail, except that a `NULL` string of
+//! length 0 is empty and freeing `NULL` does nothing.
+//!
+//! A panic, in the interpreter or in a callback written in Rust, does not
+//! unwind into C: the call fails with it instead.  The interpreter may then
+//! be left in any state, so it should only be freed.
+//!
+//! None of this may be called from another thread than the one that made
+//! the interpreter.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_double, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::rc::Rc;
+use std::slice;
+use std::str;
+use alloc::Heap;
+use api::{Context, Error, Interpreter, Value};
+use builtins::{Function, panic_message};
+use value::Kind;
+
+/// The types of values, as `rusty_scheme_type` returns them.
+pub const RUSTY_SCHEME_OTHER: c_int = 0;
+pub const RUSTY_SCHEME_BOOLEAN: c_int = 1;
+pub const RUSTY_SCHEME_INTEGER: c_int = 2;
+pub const RUSTY_SCHEME_REAL: c_int = 3;
+pub const RUSTY_SCHEME_STRING: c_int = 4;
+pub const RUSTY_SCHEME_SYMBOL: c_int = 5;
+pub const RUSTY_SCHEME_NULL: c_int = 6;
+pub const RUSTY_SCHEME_PAIR: c_int = 7;
+pub const RUSTY_SCHEME_VECTOR: c_int = 8;
+pub const RUSTY_SCHEME_PROCEDURE: c_int = 9;
+
+/// A C callback, called with the context, the arguments and their number,
+/// and the data it was registered with.  One written in Rust may panic.
+pub type Callback = extern "C-unwind" fn(*mut CContext, *const *const Value, usize, *mut c_void)
+                                         -> *mut Value;
+
+/// A `rusty_scheme_context`: the heap that values are made in, and the
+/// error that a callback set.
+pub struct CContext {
+    heap: *mut Heap,
+    error: Option<String>,
+}
+
+impl CContext {
+    fn context(&mut self) -> Context {
+        Context::new(unsafe { &mut *self.heap })
+    }
+}
+
+/// A `rusty_scheme`: an interpreter, its context, and the last error.
+pub struct CScheme {
+    interp: Interpreter,
+    context: CContext,
+    error: CString,
+}
+
+impl CScheme {
+    /// Keeps the message of `error` for `rusty_scheme_error`.
+    fn fail<T>(&mut self, error: Error, failed: T) -> T {
+        self.error = c_string(error.to_string());
+        failed
+    }
+}
+
+/// `string` as a C string, without the NULs that it cannot hold.
+fn c_string(string: String) -> CString {
+    CString::new(string.replace('\0', "")).unwrap_or_default()
+}
+
+/// Calls `body`, unless it panics, in which case `failed` reports the panic
+/// and returns what the call returns on failure.
+fn catch<T, F, G>(body: F, failed: G) -> T
+    where F: FnOnce() -> T,
+          G: FnOnce(Error) -> T
+{
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(payload) => failed(Error::new(format!("panicked: {}", panic_message(&*payload)))),
+    }
+}
+
+/// Keeps `error` in `context`, for the callback to fail with, and returns
+/// `failed`.
+unsafe fn fail<T>(context: *mut CContext, error: Error, failed: T) -> T {
+    (*context).error = Some(error.to_string());
+    failed
+}
+
+/// The UTF-8 string at `string`, of `len` bytes.
+unsafe fn utf8<'a>(string: *const c_char, len: usize) -> Result<&'a str, Error> {
+    if string.is_null() {
+        return if len == 0 { Ok("") } else { Err(Error::new("NULL string")) }
+    }
+    str::from_utf8(slice::from_raw_parts(string as *const u8, len))
+        .map_err(|_| Error::new("invalid UTF-8"))
+}
+
+/// The UTF-8 string that ends at the first NUL at `name`.
+unsafe fn c_str<'a>(name: *const c_char) -> Result<&'a str, Error> {
+    if name.is_null() {
+        return Err(Error::new("NULL name"))
+    }
+    CStr::from_ptr(name).to_str().map_err(|_| Error::new("invalid UTF-8"))
+}
+
+/// The value at `value`.
+unsafe fn value_at<'a>(value: *const Value) -> Result<&'a Value, Error> {
+    if value.is_null() {
+        return Err(Error::new("NULL value"))
+    }
+    Ok(&*value)
+}
+
+/// Makes a value in `context`.
+unsafe fn boxed<F>(context: *mut CContext, value: F) -> *mut Value
+    where F: FnOnce(&mut Context) -> Result<Value, Error>
+{
+    if context.is_null() {
+        return ptr::null_mut()
+    }
+    let result = catch(|| value(&mut (*context).context()), Err);
+    match result {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(e) => fail(context, e, ptr::null_mut()),
+    }
+}
+
+/// Makes an interpreter, or returns `NULL` if that panics.
+#[no_mangle]
+pub extern "C" fn rusty_scheme_new() -> *mut CScheme {
+    let new = || {
+        let mut scheme = Box::new(CScheme {
+            interp: Interpreter::new(),
+            context: CContext {
+                heap: ptr::null_mut(),
+                error: None,
+            },
+            error: CString::default(),
+        });
+        scheme.context.heap = scheme.interp.state().heap();
+        Box::into_raw(scheme)
+    };
+    catch(new, |_| ptr::null_mut())
+}
+
+/// Frees an interpreter.  The values made in it must be freed first.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_free(scheme: *mut CScheme) {
+    if !scheme.is_null() {
+        catch(|| drop(Box::from_raw(scheme)), |_| ())
+    }
+}
+
+/// The context of an interpreter, which stays valid as long as it does.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_context(scheme: *mut CScheme) -> *mut CContext {
+    if scheme.is_null() {
+        return ptr::null_mut()
+    }
+    &mut (*scheme).context
+}
+
+/// The message of the last error, which stays valid until the next one, or
+/// `NULL` if `scheme` is.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_error(scheme: *const CScheme) -> *const c_char {
+    if scheme.is_null() {
+        return ptr::null()
+    }
+    (*scheme).error.as_ptr()
+}
+
+/// Evaluates each datum of the `len` bytes of UTF-8 at `source` at top
+/// level, and returns the value of the last one.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_eval(scheme: *mut CScheme,
+                                           source: *const c_char,
+                                           len: usize)
+                                           -> *mut Value {
+    if scheme.is_null() {
+        return ptr::null_mut()
+    }
+    let eval = || utf8(source, len).and_then(|source| (*scheme).interp.eval_str(source));
+    match catch(eval, Err) {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(e) => (*scheme).fail(e, ptr::null_mut()),
+    }
+}
+
+/// Calls the procedure that is the value of the global variable `name`
+/// with the `nargs` values at `args`, and returns its result.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_call(scheme: *mut CScheme,
+                                           name: *const c_char,
+                                           args: *const *const Value,
+                                           nargs: usize)
+                                           -> *mut Value {
+    if scheme.is_null() {
+        return ptr::null_mut()
+    }
+    let call = || {
+        let name = try!(c_str(name));
+        if args.is_null() && nargs > 0 {
+            return Err(Error::new("NULL arguments"))
+        }
+        let mut values = Vec::with_capacity(nargs);
+        for i in 0..nargs {
+            values.push(try!(value_at(*args.offset(i as isize))).clone())
+        }
+        (*scheme).interp.call(name, &values)
+    };
+    match catch(call, Err) {
+        Ok(value) => Box::into_raw(Box::new(value)),
+        Err(e) => (*scheme).fail(e, ptr::null_mut()),
+    }
+}
+
+/// Defines the global variable `name` as a procedure taking at least
+/// `min_args` arguments, and at most `max_args` unless it is negative,
+/// which calls `callback` with `data`.  Returns 0, or -1 on failure,
+/// including if `callback` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_define_fn(scheme: *mut CScheme,
+                                                name: *const c_char,
+                                                min_args: usize,
+                                                max_args: isize,
+                                                callback: Option<Callback>,
+                                                data: *mut c_void)
+                                                -> c_int {
+    if scheme.is_null() {
+        return -1
+    }
+    let define = || {
+        let name = try!(c_str(name));
+        let callback = try!(callback.ok_or_else(|| Error::new("NULL callback")));
+        // A panic in `callback` is caught, as in any native function.
+        let function = Rc::new(move |cx: &mut Context, args: &[Value]| {
+            let mut context = CContext {
+                heap: cx.heap(),
+                error: None,
+            };
+            let args: Vec<*const Value> = args.iter().map(|arg| arg as *const Value).collect();
+            let result = callback(&mut context, args.as_ptr(), args.len(), data);
+            if result.is_null() {
+                let message = context.error.unwrap_or_else(|| "failed".to_owned());
+                return Err(Error::new(message))
+            }
+            Ok(*Box::from_raw(result))
+        });
+        let max_args = if max_args < 0 { None } else { Some(max_args as usize) };
+        (*scheme).interp.define_native(name, min_args, max_args, Function::Sync(function))
+    };
+    match catch(define, Err) {
+        Ok(()) => 0,
+        Err(e) => (*scheme).fail(e, -1),
+    }
+}
+
+/// Makes a callback fail with `message` once it returns `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_set_error(context: *mut CContext, message: *const c_char) {
+    if context.is_null() || message.is_null() {
+        return
+    }
+    (*context).error = Some(CStr::from_ptr(message).to_string_lossy().into_owned())
+}
+
+/// Frees a value.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_value_free(value: *mut Value) {
+    if !value.is_null() {
+        catch(|| drop(Box::from_raw(value)), |_| ())
+    }
+}
+
+/// The type of `value`, one of the `RUSTY_SCHEME_*` constants, or -1 if
+/// `value` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_type(value: *const Value) -> c_int {
+    if value.is_null() {
+        return -1
+    }
+    let value = (*value).get();
+    match value.kind() {
+        Kind::Constant(::value::TRUE) |
+        Kind::Constant(::value::FALSE) => RUSTY_SCHEME_BOOLEAN,
+        Kind::Constant(::value::NIL) => RUSTY_SCHEME_NULL,
+        Kind::Fixnum(_) => RUSTY_SCHEME_INTEGER,
+        Kind::Float(_) => RUSTY_SCHEME_REAL,
+        Kind::String(_) => RUSTY_SCHEME_STRING,
+        Kind::Symbol(_) => RUSTY_SCHEME_SYMBOL,
+        Kind::Pair(_) => RUSTY_SCHEME_PAIR,
+        Kind::Vector(_) => RUSTY_SCHEME_VECTOR,
+        Kind::Closure(_) | Kind::Primitive(_) => RUSTY_SCHEME_PROCEDURE,
+        _ => RUSTY_SCHEME_OTHER,
+    }
+}
+
+/// Whether `value` counts as true: whether it is not `#f`.  Returns 1 or
+/// 0, or -1 if `value` is `NULL`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_is_true(value: *const Value) -> c_int {
+    if value.is_null() {
+        return -1
+    }
+    ((*value).get().get() != ::value::FALSE) as c_int
+}
+
+/// Stores the integer `value` in `out`.  Returns 0, or -1 if it is not an
+/// integer that fits.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_to_int64(context: *mut CContext,
+                                               value: *const Value,
+                                               out: *mut i64)
+                                               -> c_int {
+    if context.is_null() {
+        return -1
+    }
+    if out.is_null() {
+        return fail(context, Error::new("NULL output"), -1)
+    }
+    let get = || value_at(value).and_then(|value| (*context).context().get::<i64>(value));
+    match catch(get, Err) {
+        Ok(number) => {
+            *out = number;
+            0
+        }
+        Err(e) => fail(context, e, -1),
+    }
+}
+
+/// Stores the number `value` in `out`.  Returns 0, or -1 if it is not a
+/// real number.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_to_double(context: *mut CContext,
+                                                value: *const Value,
+                                                out: *mut c_double)
+                                                -> c_int {
+    if context.is_null() {
+        return -1
+    }
+    if out.is_null() {
+        return fail(context, Error::new("NULL output"), -1)
+    }
+    let get = || value_at(value).and_then(|value| (*context).context().get::<f64>(value));
+    match catch(get, Err) {
+        Ok(number) => {
+            *out = number;
+            0
+        }
+        Err(e) => fail(context, e, -1),
+    }
+}
+
+/// The contents of the string `value`, or `NULL` if it is not a string.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_to_string(context: *mut CContext,
+                                                value: *const Value)
+                                                -> *mut c_char {
+    if context.is_null() {
+        return ptr::null_mut()
+    }
+    let get = || value_at(value).and_then(|value| (*context).context().get::<String>(value));
+    match catch(get, Err) {
+        Ok(string) => c_string(string).into_raw(),
+        Err(e) => fail(context, e, ptr::null_mut()),
+    }
+}
+
+/// `value`, printed as by `write`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_write_string(context: *mut CContext,
+                                                   value: *const Value)
+                                                   -> *mut c_char {
+    if context.is_null() {
+        return ptr::null_mut()
+    }
+    let write = || value_at(value).map(|value| (*context).context().write_string(value));
+    match catch(write, Err) {
+        Ok(string) => c_string(string).into_raw(),
+        Err(e) => fail(context, e, ptr::null_mut()),
+    }
+}
+
+/// Frees a string returned by the C API.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string))
+    }
+}
+
+/// Makes an integer.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_from_int64(context: *mut CContext,
+                                                 number: i64)
+                                                 -> *mut Value {
+    boxed(context, |cx| cx.value(number))
+}
+
+/// Makes a real number.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_from_double(context: *mut CContext,
+                                                  number: c_double)
+                                                  -> *mut Value {
+    boxed(context, |cx| cx.value(number))
+}
+
+/// Makes `#t` if `boolean` is not 0, and `#f` otherwise.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_from_bool(context: *mut CContext,
+                                                boolean: c_int)
+                                                -> *mut Value {
+    boxed(context, |cx| cx.value(boolean != 0))
+}
+
+/// Makes a string of the `len` bytes of UTF-8 at `string`.
+#[no_mangle]
+pub unsafe extern "C" fn rusty_scheme_from_string(context: *mut CContext,
+                                                  string: *const c_char,
+                                                  len: usize)
+                                                  -> *mut Value {
+    boxed(context, |cx| utf8(string, len).and_then(|string| cx.value(string)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use super::*;
+
+    extern "C-unwind" fn add(cx: *mut CContext,
+                             args: *const *const Value,
+                             nargs: usize,
+                             data: *mut c_void)
+                             -> *mut Value {
+        unsafe {
+            let mut sum = *(data as *const i64);
+            for i in 0..nargs {
+                let mut n = 0;
+                if rusty_scheme_to_int64(cx, *args.offset(i as isize), &mut n) != 0 {
+                    rusty_scheme_set_error(cx, b"expected integers\0".as_ptr() as *const _);
+                    return ptr::null_mut()
+                }
+                sum += n
+            }
+            rusty_scheme_from_int64(cx, sum)
+        }
+    }
+
+    #[test]
+    fn embedding_from_c() {
+        unsafe {
+            let scheme = rusty_scheme_new();
+            let cx = rusty_scheme_context(scheme);
+            let mut base = 100i64;
+            let name = b"add\0".as_ptr() as *const c_char;
+            let data = &mut base as *mut i64 as *mut c_void;
+            assert_eq!(rusty_scheme_define_fn(scheme, name, 0, -1, Some(add), data), 0);
+
+            let source = "(add 1 2 3)";
+            let value = rusty_scheme_eval(scheme, source.as_ptr() as *const _, source.len());
+            assert_eq!(rusty_scheme_type(value), RUSTY_SCHEME_INTEGER);
+            let mut n = 0;
+            assert_eq!(rusty_scheme_to_int64(cx, value, &mut n), 0);
+            assert_eq!(n, 106);
+
+            let one = rusty_scheme_from_int64(cx, 1);
+            let two = rusty_scheme_from_string(cx, "two".as_ptr() as *const _, 3);
+            let args = [one as *const Value, two as *const Value];
+            assert!(rusty_scheme_call(scheme, name, args.as_ptr(), 2).is_null());
+            assert_eq!(CStr::from_ptr(rusty_scheme_error(scheme)).to_str(),
+                       Ok("add: expected integers"));
+            let written = rusty_scheme_write_string(cx, two);
+            assert_eq!(CStr::from_ptr(written).to_str(), Ok("\"two\""));
+
+            let source = "(car '())";
+            assert!(rusty_scheme_eval(scheme, source.as_ptr() as *const _, source.len()).is_null());
+            rusty_scheme_string_free(written);
+            for &value in &[value, one, two] {
+                rusty_scheme_value_free(value)
+            }
+            rusty_scheme_free(scheme)
+        }
+    }
+
+    extern "C-unwind" fn explode(_: *mut CContext,
+                                 _: *const *const Value,
+                                 _: usize,
+                                 _: *mut c_void)
+                                 -> *mut Value {
+        panic!("kaboom")
+    }
+
+    #[test]
+    fn panicking_callbacks() {
+        unsafe {
+            let scheme = rusty_scheme_new();
+            let name = b"explode\0".as_ptr() as *const c_char;
+            let data = ptr::null_mut();
+            assert_eq!(rusty_scheme_define_fn(scheme, name, 0, 0, Some(explode), data), 0);
+            assert!(rusty_scheme_call(scheme, name, ptr::null(), 0).is_null());
+            assert_eq!(CStr::from_ptr(rusty_scheme_error(scheme)).to_str(),
+                       Ok("explode: panicked: kaboom"));
+            let source = "(explode)";
+            assert!(rusty_scheme_eval(scheme, source.as_ptr() as *const _, source.len()).is_null());
+            assert_eq!(CStr::from_ptr(rusty_scheme_error(scheme)).to_str(),
+                       Ok("explode: panicked: kaboom"));
+            rusty_scheme_free(scheme)
+        }
+    }
+
+    #[test]
+    fn null_pointers() {
+        unsafe {
+            let scheme = rusty_scheme_new();
+            let cx = rusty_scheme_context(scheme);
+            let error = || CStr::from_ptr(rusty_scheme_error(scheme)).to_str().unwrap().to_owned();
+            assert!(rusty_scheme_eval(scheme, ptr::null(), 3).is_null());
+            assert_eq!(error(), "NULL string");
+            let name = b"list\0".as_ptr() as *const c_char;
+            assert!(rusty_scheme_call(scheme, ptr::null(), ptr::null(), 0).is_null());
+            assert_eq!(error(), "NULL name");
+            assert!(rusty_scheme_call(scheme, name, ptr::null(), 1).is_null());
+            assert_eq!(error(), "NULL arguments");
+            let args = [ptr::null()];
+            assert!(rusty_scheme_call(scheme, name, args.as_ptr(), 1).is_null());
+            assert_eq!(error(), "NULL value");
+            assert_eq!(rusty_scheme_define_fn(scheme, name, 0, 0, None, ptr::null_mut()), -1);
+            assert_eq!(error(), "NULL callback");
+            let value = rusty_scheme_call(scheme, name, ptr::null(), 0);
+            assert_eq!(rusty_scheme_type(value), RUSTY_SCHEME_NULL);
+            assert_eq!(rusty_scheme_to_int64(cx, ptr::null(), &mut 0), -1);
+            assert_eq!(rusty_scheme_to_int64(cx, value, ptr::null_mut()), -1);
+            assert!(rusty_scheme_write_string(cx, ptr::null()).is_null());
+            let empty = rusty_scheme_from_string(cx, ptr::null(), 0);
+            assert_eq!(rusty_scheme_type(empty), RUSTY_SCHEME_STRING);
+            assert_eq!(rusty_scheme_type(ptr::null()), -1);
+            assert!(rusty_scheme_context(ptr::null_mut()).is_null());
+            assert!(rusty_scheme_eval(ptr::null_mut(), ptr::null(), 0).is_null());
+            assert!(rusty_scheme_from_int64(ptr::null_mut(), 1).is_null());
+            for &value in &[value, empty] {
+                rusty_scheme_value_free(value)
+            }
+            rusty_scheme_free(scheme)
+        }
+    }
+
+    #[test]
+    fn panics_do_not_reach_c() {
+        assert_eq!(catch(|| -> c_int { panic!("kaboom") },
+                         |e| {
+                             assert_eq!(e.to_string(), "panicked: kaboom");
+                             -1
+                         }),
+                   -1);
+        assert_eq!(catch(|| 0, |_| -1), 0);
+    }
+}
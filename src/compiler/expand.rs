@@ -0,0 +1,1435 @@
+//! The macro expander, which runs before the parser.
+//!
+//! Expansion rewrites a datum into one that only uses the special forms the
+//! parser knows, replacing each use of a macro defined by `define-syntax`,
+//! `let-syntax` or `letrec-syntax` by its expansion.  A macro is defined by
+//! `syntax-rules` (see `syntax_rules`), or by a procedure written in Scheme
+//! (see `explicit_renaming`).
+//!
+//! Macros are hygienic.  Each identifier a macro inserts is renamed: it
+//! remembers the identifier in the template, the environment of the macro's
+//! definition, and the number of the expansion.  A renamed identifier only
+//! binds (and is only bound by) identifiers of the same expansion, and if
+//! it is free in the expansion, it means what the identifier in the template
+//! means where the macro was defined.  Source code is a `Syntax::Datum`,
+//! whose symbols are identifiers that have not been renamed.
+//!
+//! The parser resolves variables by name, so the output must not let a
+//! local variable capture a reference to anything else.  When it would, the
+//! variable is given a name that cannot be read as a symbol.  Nothing else
+//! is renamed, so code that does not use macros expands to itself, and
+//! keeps the pairs of the source, and so their locations.
+//!
+//! Macros defined at top level are kept in the `Macros` of the heap, so that
+//! later forms can use them, along with the bindings of `import` (see
+//! `library`).
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use alloc::{Heap, Location, Root};
+use read::PARAMETER_MARKERS;
+use value::{self, Kind, Value};
+use super::SPECIAL_FORMS;
+use super::explicit_renaming::{Expansion, Transformer};
+use super::library::Libraries;
+use super::syntax_rules::Rules;
+
+/// The special forms that the expander handles itself.
+pub const MACRO_FORMS: &'static [&'static str] = &["define-syntax", "let-syntax", "letrec-syntax",
+                                                   "syntax-rules", "er-macro-transformer",
+                                                   "define-library", "import"];
+
+/// The number of times in a row that a form may expand into a macro use,
+/// before expansion is assumed not to terminate.
+const MAX_EXPANSIONS: usize = 10000;
+
+/// What the expander keeps between top-level forms.
+#[derive(Debug, Default)]
+pub struct Macros {
+    /// The macros defined at top level, and the bindings imported there.
+    table: HashMap<String, Denotation>,
+
+    /// The number of the next expansion.
+    next_mark: usize,
+
+    /// The uses of procedural macros being expanded, innermost last.
+    pub expansions: Vec<Expansion>,
+
+    pub libraries: Libraries,
+
+    /// What expanding top-level forms did besides producing code, while a
+    /// file is being compiled (see `fasl`).
+    pub effects: Option<Vec<Effect>>,
+
+    /// Whether the compiler leaves out optimization, so that its code
+    /// follows the source more closely.
+    pub unoptimized: bool,
+
+    /// The top-level forms that changed the macros or imports of the top
+    /// level, or defined libraries that cannot be saved, in order.  Images
+    /// replay them (see `image`).
+    pub toplevel_forms: Vec<Root>,
+}
+
+/// Something that expanding a top-level form does besides producing code.
+#[derive(Debug, PartialEq)]
+pub enum Effect {
+    /// It changed the macros or imports of the top level.
+    Toplevel,
+
+    /// It defined the library with this name.
+    Library(String),
+
+    /// It imported the library whose name has these parts, which is not
+    /// built in.
+    Import(Vec<String>),
+}
+
+impl Macros {
+    /// Records `effect`, if effects are being recorded.
+    pub fn record(&mut self, effect: Effect) {
+        if let Some(ref mut effects) = self.effects {
+            effects.push(effect)
+        }
+    }
+
+    /// Keeps the top-level form `form`, which expanded with `effects`, if
+    /// an image must replay it.
+    pub fn remember(&mut self, form: Root, effects: &[Effect]) {
+        let replayed = effects.iter().any(|effect| match *effect {
+            Effect::Toplevel => true,
+            Effect::Library(ref name) => self.libraries.saved_exports(name).is_none(),
+            Effect::Import(_) => false,
+        });
+        if replayed {
+            self.toplevel_forms.push(form)
+        }
+    }
+
+    /// The names of the macros and imports of the top level.
+    pub fn names(&self) -> Vec<String> {
+        self.table.keys().cloned().collect()
+    }
+
+    /// The macro that `name` is bound to at top level, if it is.
+    pub fn toplevel_macro(&self, name: &str) -> Option<Denotation> {
+        match self.table.get(name) {
+            Some(denotation @ &Denotation::Macro(_)) => Some(denotation.clone()),
+            _ => None,
+        }
+    }
+
+    /// What `identifier` refers to in `env`.
+    pub fn resolve(&self, env: &Env, identifier: &Rc<Identifier>) -> Denotation {
+        let (mut env, mut identifier) = (env.clone(), identifier.clone());
+        // Library code, and code evaluated in an environment other than the
+        // interaction environment, does not see the macros and imports of
+        // the top level.
+        let mut in_library;
+        loop {
+            let mut scope = env.clone();
+            in_library = false;
+            while let Some(current) = scope {
+                for &(ref bound, ref denotation) in current.bindings.borrow().iter().rev() {
+                    if bound.same(&identifier) {
+                        return denotation.clone()
+                    }
+                }
+                in_library = current.isolated;
+                scope = current.parent.clone()
+            }
+            let renamed = match identifier.renamed {
+                Some((ref original, ref definition, _)) => (original.clone(), definition.clone()),
+                None => break,
+            };
+            identifier = renamed.0;
+            env = renamed.1;
+        }
+        match self.table.get(&*identifier.name) {
+            Some(denotation) if !in_library => denotation.clone(),
+            _ => builtin(&identifier.name),
+        }
+    }
+}
+
+/// What `name` refers to if nothing binds it: a special form, or a global
+/// variable.
+pub fn builtin(name: &Rc<String>) -> Denotation {
+    match SPECIAL_FORMS.iter().chain(MACRO_FORMS).find(|special| ***special == **name) {
+        Some(special) => Denotation::Special(special),
+        None => Denotation::Global(name.clone()),
+    }
+}
+
+/// A macro.
+#[derive(Debug)]
+pub enum Macro {
+    Rules(Rules),
+    Procedure(Transformer),
+}
+
+impl Macro {
+    /// The macro called `name` that `transformer` defines in `env`.
+    fn new(expander: &mut Expander,
+           name: &Rc<Identifier>,
+           transformer: &Syntax,
+           env: &Env)
+           -> Result<Macro, String> {
+        match expander.special(env, transformer) {
+            Some("syntax-rules") => {
+                Ok(Macro::Rules(try!(Rules::new(expander, name, transformer, env))))
+            }
+            Some("er-macro-transformer") => {
+                Ok(Macro::Procedure(try!(Transformer::new(expander, transformer, env))))
+            }
+            _ => expander.error(transformer, "unsupported macro transformer"),
+        }
+    }
+
+    /// Expands `form`, a use of the macro in `env`.
+    fn expand(&self, expander: &mut Expander, env: &Env, form: &Syntax) -> Result<Syntax, String> {
+        match *self {
+            Macro::Rules(ref rules) => rules.expand(expander, env, form),
+            Macro::Procedure(ref transformer) => transformer.expand(expander, env, form),
+        }
+    }
+}
+
+/// An identifier.
+#[derive(Debug)]
+pub struct Identifier {
+    pub name: Rc<String>,
+
+    /// For an identifier inserted by a macro: the identifier in the
+    /// template, the environment of the macro's definition, and the number
+    /// of the expansion.
+    renamed: Option<(Rc<Identifier>, Env, usize)>,
+}
+
+impl Identifier {
+    /// The identifier inserted for `original` by expansion `mark` of a macro
+    /// defined in `env`.
+    pub fn rename(original: &Rc<Identifier>, env: &Env, mark: usize) -> Rc<Identifier> {
+        Rc::new(Identifier {
+            name: original.name.clone(),
+            renamed: Some((original.clone(), env.clone(), mark)),
+        })
+    }
+
+    /// Checks if binding `self` would bind `other` (`bound-identifier=?`).
+    pub fn same(&self, other: &Identifier) -> bool {
+        if self.name != other.name {
+            return false
+        }
+        let (mut first, mut second) = (self, other);
+        loop {
+            match (&first.renamed, &second.renamed) {
+                (&None, &None) => return true,
+                (&Some((ref x, _, m)), &Some((ref y, _, n))) if m == n => {
+                    first = x;
+                    second = y
+                }
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// A lexical environment: its innermost scope, or `None` at top level.
+pub type Env = Option<Rc<Scope>>;
+
+/// The identifiers bound by a binding form, or by a body.
+#[derive(Debug)]
+pub struct Scope {
+    bindings: RefCell<Vec<(Rc<Identifier>, Denotation)>>,
+    parent: Env,
+
+    /// Whether it is the top level of a library or of an environment given
+    /// to `eval`.
+    isolated: bool,
+}
+
+/// What an identifier refers to.
+#[derive(Clone, Debug)]
+pub enum Denotation {
+    Variable(Rc<Variable>),
+    Macro(Rc<Macro>),
+    Special(&'static str),
+    Global(Rc<String>),
+}
+
+impl Denotation {
+    /// Checks if two identifiers with these denotations are
+    /// `free-identifier=?`.
+    pub fn same(&self, other: &Denotation) -> bool {
+        match (self, other) {
+            (&Denotation::Variable(ref x), &Denotation::Variable(ref y)) => Rc::ptr_eq(x, y),
+            (&Denotation::Macro(ref x), &Denotation::Macro(ref y)) => Rc::ptr_eq(x, y),
+            (&Denotation::Special(x), &Denotation::Special(y)) => x == y,
+            (&Denotation::Global(ref x), &Denotation::Global(ref y)) => x == y,
+            _ => false,
+        }
+    }
+}
+
+/// A local variable.
+#[derive(Debug)]
+pub struct Variable {
+    name: Rc<String>,
+
+    /// Whether the variable must be renamed, since it would otherwise
+    /// capture a reference to something else.
+    renamed: Cell<bool>,
+
+    /// A number unique to the variable, for its new name.
+    number: usize,
+}
+
+impl Variable {
+    /// The name of the variable in the output.
+    fn output_name(&self) -> String {
+        if self.renamed.get() {
+            format!("{} {}", self.name, self.number)
+        } else {
+            (*self.name).clone()
+        }
+    }
+}
+
+/// Code being expanded.
+#[derive(Clone, Debug)]
+pub enum Syntax {
+    /// A datum, whose symbols are identifiers that have not been renamed.
+    Datum(Rc<Root>),
+
+    /// An identifier inserted by a macro.
+    Identifier(Rc<Identifier>),
+
+    /// A pair made by a macro, and the location of the macro use.
+    Pair(Rc<(Syntax, Syntax)>, Option<Location>),
+
+    /// A vector made by a macro.
+    Vector(Rc<Vec<Syntax>>),
+}
+
+/// The shape of a `Syntax`.
+pub enum View {
+    Identifier(Rc<Identifier>),
+    Pair(Syntax, Syntax),
+    Vector(Vec<Syntax>),
+    Nil,
+
+    /// Any other datum.  It is not rooted.
+    Other(Value),
+}
+
+impl Syntax {
+    /// `value` as syntax.
+    pub fn datum(heap: &Heap, value: Value) -> Syntax {
+        Syntax::Datum(Rc::new(heap.root(value)))
+    }
+
+    /// The list of `items`, followed by `tail`.
+    pub fn list(items: Vec<Syntax>, tail: Syntax, location: &Option<Location>) -> Syntax {
+        items.into_iter()
+             .rev()
+             .fold(tail, |tail, item| Syntax::Pair(Rc::new((item, tail)), location.clone()))
+    }
+
+    pub fn view(&self, heap: &Heap) -> View {
+        match *self {
+            Syntax::Datum(ref root) => {
+                let value = root.get();
+                match value.kind() {
+                    Kind::Symbol(symbol) => {
+                        View::Identifier(Rc::new(Identifier {
+                            name: unsafe { (*symbol).name() },
+                            renamed: None,
+                        }))
+                    }
+                    Kind::Pair(_) => {
+                        View::Pair(Syntax::datum(heap, value.car().unwrap()),
+                                   Syntax::datum(heap, value.cdr().unwrap()))
+                    }
+                    Kind::Vector(vector) => unsafe {
+                        View::Vector((0..(*vector).len())
+                                         .map(|i| Syntax::datum(heap, (*vector).element(i).clone()))
+                                         .collect())
+                    },
+                    Kind::Constant(value::NIL) => View::Nil,
+                    _ => View::Other(value),
+                }
+            }
+            Syntax::Identifier(ref identifier) => View::Identifier(identifier.clone()),
+            Syntax::Pair(ref pair, _) => View::Pair(pair.0.clone(), pair.1.clone()),
+            Syntax::Vector(ref items) => View::Vector((**items).clone()),
+        }
+    }
+
+    /// Checks if `self` and `other` are the same object.
+    fn is(&self, other: &Syntax) -> bool {
+        match (self, other) {
+            (&Syntax::Datum(ref x), &Syntax::Datum(ref y)) => x.get().get() == y.get().get(),
+            (&Syntax::Pair(ref x, _), &Syntax::Pair(ref y, _)) => Rc::ptr_eq(x, y),
+            _ => false,
+        }
+    }
+
+    /// The elements of the (possibly improper) list `self`, and its final
+    /// cdr.  `None` if the list is circular.
+    pub fn elements(&self, heap: &Heap) -> Option<(Vec<Syntax>, Syntax)> {
+        let mut elements = vec![];
+        let (mut rest, mut slow) = (self.clone(), self.clone());
+        while let View::Pair(car, cdr) = rest.view(heap) {
+            elements.push(car);
+            rest = cdr;
+            if elements.len() % 2 == 0 {
+                slow = match slow.view(heap) {
+                    View::Pair(_, cdr) => cdr,
+                    _ => unreachable!(),
+                };
+                if slow.is(&rest) {
+                    return None
+                }
+            }
+        }
+        Some((elements, rest))
+    }
+
+    /// The elements of `self`, if it is a proper list.
+    pub fn proper_list(&self, heap: &Heap) -> Option<Vec<Syntax>> {
+        match self.elements(heap) {
+            Some((elements, ref tail)) if is_nil(heap, tail) => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+/// Checks if `syntax` is the empty list.
+fn is_nil(heap: &Heap, syntax: &Syntax) -> bool {
+    match syntax.view(heap) {
+        View::Nil => true,
+        _ => false,
+    }
+}
+
+/// The result of expansion.  It only allocates once expansion is over, so
+/// the data from the source must be rooted.
+enum Out {
+    /// Source data, as is.
+    Datum(Rc<Root>),
+
+    /// A symbol, which is not a local variable.
+    Symbol(Rc<String>),
+
+    /// A local variable.
+    Variable(Rc<Variable>),
+
+    /// A list, with its final cdr, the source list it was made from, and
+    /// its location.  The source list is used instead if it has the same
+    /// elements.
+    List(Vec<Out>, Box<Out>, Option<Rc<Root>>, Option<Location>),
+
+    Vector(Vec<Out>),
+}
+
+pub struct Expander<'a> {
+    pub heap: &'a mut Heap,
+
+    /// The number of the next local variable.
+    next_variable: usize,
+
+    /// The scopes made so far.  They are emptied when expansion is over,
+    /// since macros and the scopes they were defined in refer to each
+    /// other.
+    scopes: Vec<Rc<Scope>>,
+}
+
+impl<'a> Drop for Expander<'a> {
+    fn drop(&mut self) {
+        for scope in &self.scopes {
+            scope.bindings.borrow_mut().clear()
+        }
+    }
+}
+
+impl<'a> Expander<'a> {
+    /// An error in `form`, prefixed by its location if known.
+    pub fn error<T>(&mut self, form: &Syntax, message: &str) -> Result<T, String> {
+        Err(match self.location(form) {
+            Some(location) => format!("{}: {}", location, message),
+            None => message.to_owned(),
+        })
+    }
+
+    /// The location of `form`, if known.
+    pub fn location(&mut self, form: &Syntax) -> Option<Location> {
+        match *form {
+            Syntax::Datum(ref root) => self.heap.location(&root.get()),
+            Syntax::Pair(_, ref location) => location.clone(),
+            _ => None,
+        }
+    }
+
+    /// The number of a new expansion.
+    pub fn mark(&mut self) -> usize {
+        self.heap.macros.next_mark += 1;
+        self.heap.macros.next_mark
+    }
+
+    /// A new scope inside `env`.
+    fn scope(&mut self, env: &Env) -> Env {
+        let scope = Rc::new(Scope {
+            bindings: RefCell::new(vec![]),
+            parent: env.clone(),
+            isolated: false,
+        });
+        self.scopes.push(scope.clone());
+        Some(scope)
+    }
+
+    /// Binds `identifier` to `denotation` in the innermost scope of `env`,
+    /// or at top level.
+    pub fn bind_denotation(&mut self,
+                           env: &Env,
+                           identifier: &Rc<Identifier>,
+                           denotation: Denotation) {
+        match *env {
+            Some(ref scope) => scope.bindings.borrow_mut().push((identifier.clone(), denotation)),
+            None => {
+                self.heap.macros.table.insert((*identifier.name).clone(), denotation);
+                self.heap.macros.record(Effect::Toplevel)
+            }
+        }
+    }
+
+    /// Binds `identifier` to a new local variable in the innermost scope of
+    /// `env`.
+    fn bind(&mut self, env: &Env, identifier: &Rc<Identifier>) -> Rc<Variable> {
+        let variable = Rc::new(Variable {
+            name: identifier.name.clone(),
+            renamed: Cell::new(false),
+            number: self.next_variable,
+        });
+        self.next_variable += 1;
+        env.as_ref()
+           .unwrap()
+           .bindings
+           .borrow_mut()
+           .push((identifier.clone(), Denotation::Variable(variable.clone())));
+        variable
+    }
+
+    /// What `identifier` refers to in `env`.
+    pub fn resolve(&self, env: &Env, identifier: &Rc<Identifier>) -> Denotation {
+        self.heap.macros.resolve(env, identifier)
+    }
+
+    /// The special form `form` is, if it is one.
+    pub fn special(&self, env: &Env, form: &Syntax) -> Option<&'static str> {
+        let head = match form.view(self.heap) {
+            View::Pair(head, _) => head,
+            _ => return None,
+        };
+        match head.view(self.heap) {
+            View::Identifier(identifier) => {
+                match self.resolve(env, &identifier) {
+                    Denotation::Special(name) => Some(name),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Makes sure the parser does not take `name` for a local variable other
+    /// than `target` in `env`, by renaming those that would shadow it.
+    fn unshadow(&self, env: &Env, name: &str, target: Option<&Rc<Variable>>) {
+        let mut scope = env.clone();
+        while let Some(current) = scope {
+            for &(_, ref denotation) in current.bindings.borrow().iter().rev() {
+                if let Denotation::Variable(ref variable) = *denotation {
+                    if target.map_or(false, |target| Rc::ptr_eq(target, variable)) {
+                        return
+                    }
+                    if *variable.name == name {
+                        variable.renamed.set(true)
+                    }
+                }
+            }
+            scope = current.parent.clone()
+        }
+    }
+
+    /// The symbol `name`, which the parser must not take for a local
+    /// variable.
+    fn keyword(&self, env: &Env, name: &str) -> Out {
+        self.unshadow(env, name, None);
+        Out::Symbol(Rc::new(name.to_owned()))
+    }
+
+    /// `form` as it is, but with renamed identifiers replaced by their
+    /// names.
+    fn unchanged(&mut self, form: &Syntax) -> Out {
+        match *form {
+            Syntax::Datum(ref root) => Out::Datum(root.clone()),
+            Syntax::Identifier(ref identifier) => Out::Symbol(identifier.name.clone()),
+            Syntax::Pair(ref pair, ref location) => {
+                Out::List(vec![self.unchanged(&pair.0)],
+                          Box::new(self.unchanged(&pair.1)),
+                          None,
+                          location.clone())
+            }
+            Syntax::Vector(ref items) => {
+                Out::Vector(items.iter().map(|item| self.unchanged(item)).collect())
+            }
+        }
+    }
+
+    /// Pushes `form` on the stack as a datum, with renamed identifiers
+    /// replaced by their names.
+    pub fn strip(&mut self, form: &Syntax) -> Result<(), String> {
+        let out = self.unchanged(form);
+        build(self.heap, &out)
+    }
+
+    /// The list `form`, rebuilt from `items` and `tail`.
+    fn rebuild(&mut self, form: &Syntax, items: Vec<Out>, tail: Out) -> Out {
+        let original = match *form {
+            Syntax::Datum(ref root) => Some(root.clone()),
+            _ => None,
+        };
+        let location = self.location(form);
+        Out::List(items, Box::new(tail), original, location)
+    }
+
+    /// The empty list.
+    fn nil(&self) -> Out {
+        Out::Datum(Rc::new(self.heap.root(Value::new(value::NIL))))
+    }
+
+    /// Expands `form` until it is not a macro use.
+    fn expand_macros(&mut self, env: &Env, form: &Syntax) -> Result<Syntax, String> {
+        let mut form = form.clone();
+        for _ in 0..MAX_EXPANSIONS {
+            let head = match form.view(self.heap) {
+                View::Pair(head, _) => head,
+                _ => return Ok(form),
+            };
+            let definition = match head.view(self.heap) {
+                View::Identifier(identifier) => {
+                    match self.resolve(env, &identifier) {
+                        Denotation::Macro(definition) => definition,
+                        _ => return Ok(form),
+                    }
+                }
+                _ => return Ok(form),
+            };
+            form = try!(definition.expand(self, env, &form))
+        }
+        self.error(&form, "macro expansion does not terminate")
+    }
+
+    /// Expands a top-level form, in the scope `env` of an environment given
+    /// to `eval`, if it is set.
+    fn toplevel(&mut self, env: &Env, form: &Syntax) -> Result<Out, String> {
+        let form = try!(self.expand_macros(env, form));
+        match self.special(env, &form) {
+            Some("define") => self.definition(env, &form, None),
+            Some("begin") => {
+                let elements = match form.proper_list(self.heap) {
+                    Some(elements) => elements,
+                    None => return Ok(self.unchanged(&form)),
+                };
+                let mut items = vec![self.keyword(env, "begin")];
+                for element in &elements[1..] {
+                    items.push(try!(self.toplevel(env, element)))
+                }
+                let nil = self.nil();
+                Ok(self.rebuild(&form, items, nil))
+            }
+            Some("define-syntax") => {
+                let (name, definition) = try!(self.define_syntax(env, &form));
+                self.bind_denotation(env, &name, Denotation::Macro(definition));
+                Ok(self.empty_begin(&form))
+            }
+            Some("import") => {
+                try!(self.import(env, &form));
+                Ok(self.empty_begin(&form))
+            }
+            Some("define-library") => self.define_library(&form),
+            _ => self.expression(env, &form),
+        }
+    }
+
+    /// `(begin)`, in place of `form`.
+    fn empty_begin(&mut self, form: &Syntax) -> Out {
+        let (begin, nil) = (self.keyword(&None, "begin"), self.nil());
+        Out::List(vec![begin], Box::new(nil), None, self.location(form))
+    }
+
+    /// `(import import-set ...)`, in `env`: the top level, or that of a
+    /// library.
+    fn import(&mut self, env: &Env, form: &Syntax) -> Result<(), String> {
+        let sets = form.proper_list(self.heap).unwrap_or(vec![]);
+        self.import_sets(env, &sets[1..])
+    }
+
+    /// Imports the bindings of `sets` into `env`.
+    fn import_sets(&mut self, env: &Env, sets: &[Syntax]) -> Result<(), String> {
+        for set in sets {
+            for (name, denotation) in try!(self.import_set(set)) {
+                let identifier = Rc::new(Identifier {
+                    name: name,
+                    renamed: None,
+                });
+                self.bind_denotation(env, &identifier, denotation)
+            }
+        }
+        Ok(())
+    }
+
+    /// `(define-library name declaration ...)`, which becomes a `begin` of
+    /// the definitions and expressions of the library.  Each definition
+    /// defines a global variable named after the library and the variable,
+    /// which cannot be read as a symbol.
+    fn define_library(&mut self, form: &Syntax) -> Result<Out, String> {
+        let elements = form.proper_list(self.heap).unwrap_or(vec![]);
+        let name = match elements.get(1).and_then(|name| self.library_name(name)) {
+            Some((name, _)) => Rc::new(name),
+            None => return self.error(form, "bad syntax in define-library"),
+        };
+        // The scope is not emptied after expansion, since exported macros
+        // refer to it.
+        let env = Some(Rc::new(Scope {
+            bindings: RefCell::new(vec![]),
+            parent: None,
+            isolated: true,
+        }));
+        let (mut exports, mut forms) = (vec![], vec![]);
+        for declaration in &elements[2..] {
+            let keyword = match declaration.view(self.heap) {
+                View::Pair(keyword, _) => keyword.view(self.heap),
+                _ => return self.error(declaration, "bad library declaration"),
+            };
+            let parts = declaration.proper_list(self.heap).unwrap_or(vec![]);
+            match keyword {
+                View::Identifier(ref keyword) if *keyword.name == "export" => {
+                    exports.extend(parts.into_iter().skip(1))
+                }
+                View::Identifier(ref keyword) if *keyword.name == "import" => {
+                    try!(self.import(&env, declaration))
+                }
+                View::Identifier(ref keyword) if *keyword.name == "begin" => {
+                    forms.extend(parts.into_iter().skip(1))
+                }
+                _ => return self.error(declaration, "bad library declaration"),
+            }
+        }
+        let items = try!(self.library_body(&env, &name, forms));
+        let mut bindings = vec![];
+        for export in exports {
+            let (internal, external) = match export.view(self.heap) {
+                View::Identifier(identifier) => (identifier.clone(), identifier.name.clone()),
+                _ => {
+                    match self.export_rename(&export) {
+                        Some(names) => names,
+                        None => return self.error(&export, "bad export specification"),
+                    }
+                }
+            };
+            bindings.push((external, self.resolve(&env, &internal)))
+        }
+        self.define_exports(&name, bindings);
+        let mut all = vec![self.keyword(&None, "begin")];
+        all.extend(items);
+        let (nil, location) = (self.nil(), self.location(form));
+        Ok(Out::List(all, Box::new(nil), None, location))
+    }
+
+    /// The names `(rename internal external)` exports, if it is well-formed.
+    fn export_rename(&self, export: &Syntax) -> Option<(Rc<Identifier>, Rc<String>)> {
+        match self.identifiers(export) {
+            Some(ref names) if names.len() == 3 && *names[0].name == "rename" => {
+                Some((names[1].clone(), names[2].name.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Expands the body of the library `library`, whose scope is `env`.
+    /// Like a body, its definitions are bound before anything is expanded,
+    /// but they can follow expressions.
+    fn library_body(&mut self,
+                    env: &Env,
+                    library: &Rc<String>,
+                    forms: Vec<Syntax>)
+                    -> Result<Vec<Out>, String> {
+        let mut pending: Vec<Syntax> = forms.into_iter().rev().collect();
+        let mut expanded = vec![];
+        while let Some(next) = pending.pop() {
+            let next = try!(self.expand_macros(env, &next));
+            match self.special(env, &next) {
+                Some("define") => {
+                    let name = match self.defined_name(&next) {
+                        Some(name) => name,
+                        None => return self.error(&next, "bad syntax in define"),
+                    };
+                    let global = Rc::new(format!("{} {}", library, name.name));
+                    self.bind_denotation(env, &name, Denotation::Global(global.clone()));
+                    expanded.push((next, Some(global)))
+                }
+                Some("begin") if next.proper_list(self.heap).is_some() => {
+                    let elements = next.proper_list(self.heap).unwrap();
+                    pending.extend(elements[1..].iter().rev().cloned())
+                }
+                Some("define-syntax") => {
+                    let (name, definition) = try!(self.define_syntax(env, &next));
+                    self.bind_denotation(env, &name, Denotation::Macro(definition))
+                }
+                _ => expanded.push((next, None)),
+            }
+        }
+        let mut items = vec![];
+        for (form, global) in expanded {
+            items.push(try!(match global {
+                Some(global) => self.definition(env, &form, Some(Out::Symbol(global))),
+                None => self.expression(env, &form),
+            }))
+        }
+        Ok(items)
+    }
+
+    /// The identifier that the definition `form` defines, if any.
+    fn defined_name(&self, form: &Syntax) -> Option<Rc<Identifier>> {
+        match form.proper_list(self.heap)
+                  .and_then(|elements| elements.get(1).cloned())
+                  .map(|target| target.view(self.heap)) {
+            Some(View::Identifier(name)) => Some(name),
+            Some(View::Pair(head, _)) => {
+                match head.view(self.heap) {
+                    View::Identifier(name) => Some(name),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Expands `form`, an expression.
+    fn expression(&mut self, env: &Env, form: &Syntax) -> Result<Out, String> {
+        let form = try!(self.expand_macros(env, form));
+        match form.view(self.heap) {
+            View::Identifier(identifier) => self.reference(env, &identifier, &form),
+            View::Pair(..) => {
+                if let Some(special) = self.special(env, &form) {
+                    return self.special_form(env, special, &form)
+                }
+                let elements = match form.proper_list(self.heap) {
+                    Some(elements) => elements,
+                    None => return Ok(self.unchanged(&form)),
+                };
+                let items = try!(self.expressions(env, &elements));
+                let nil = self.nil();
+                Ok(self.rebuild(&form, items, nil))
+            }
+            _ => Ok(self.unchanged(&form)),
+        }
+    }
+
+    fn expressions(&mut self, env: &Env, forms: &[Syntax]) -> Result<Vec<Out>, String> {
+        let mut items = vec![];
+        for form in forms {
+            items.push(try!(self.expression(env, form)))
+        }
+        Ok(items)
+    }
+
+    /// A reference to `identifier`, which is part of `form`.
+    fn reference(&mut self,
+                 env: &Env,
+                 identifier: &Rc<Identifier>,
+                 form: &Syntax)
+                 -> Result<Out, String> {
+        match self.resolve(env, identifier) {
+            Denotation::Variable(variable) => {
+                if !variable.renamed.get() {
+                    self.unshadow(env, &variable.name, Some(&variable))
+                }
+                Ok(Out::Variable(variable))
+            }
+            Denotation::Special(name) => Ok(self.keyword(env, name)),
+            Denotation::Global(name) => Ok(self.keyword(env, &name)),
+            Denotation::Macro(_) => {
+                self.error(form, &format!("syntax keyword used as a variable: {}", identifier.name))
+            }
+        }
+    }
+
+    /// Expands the special form `name`.  Malformed forms are left for the
+    /// parser to report, unless only the expander knows them.
+    fn special_form(&mut self,
+                    env: &Env,
+                    name: &'static str,
+                    form: &Syntax)
+                    -> Result<Out, String> {
+        let elements = match form.proper_list(self.heap) {
+            Some(elements) => elements,
+            None => return Ok(self.unchanged(form)),
+        };
+        let args = &elements[1..];
+        let head = self.keyword(env, name);
+        let mut items = vec![head];
+        match name {
+            "quote" if args.len() == 1 => items.push(self.unchanged(&args[0])),
+            "if" if args.len() == 2 || args.len() == 3 => {
+                items.extend(try!(self.expressions(env, args)))
+            }
+            "set!" if args.len() == 2 => {
+                match args[0].view(self.heap) {
+                    View::Identifier(identifier) => {
+                        items.push(try!(self.reference(env, &identifier, form)))
+                    }
+                    _ => return Ok(self.unchanged(form)),
+                }
+                items.push(try!(self.expression(env, &args[1])))
+            }
+            "lambda" if args.len() >= 2 => {
+                let scope = self.scope(env);
+                match try!(self.formals(&scope, &args[0])) {
+                    Some(formals) => items.push(formals),
+                    None => return Ok(self.unchanged(form)),
+                }
+                items.extend(try!(self.body(&scope, &args[1..])))
+            }
+            "case-lambda" => {
+                for clause in args {
+                    let elements = match clause.proper_list(self.heap) {
+                        Some(elements) => elements,
+                        None => return Ok(self.unchanged(form)),
+                    };
+                    if elements.len() < 2 {
+                        return Ok(self.unchanged(form))
+                    }
+                    let scope = self.scope(env);
+                    let mut clause_items = match try!(self.formals(&scope, &elements[0])) {
+                        Some(formals) => vec![formals],
+                        None => return Ok(self.unchanged(form)),
+                    };
+                    clause_items.extend(try!(self.body(&scope, &elements[1..])));
+                    let nil = self.nil();
+                    items.push(self.rebuild(clause, clause_items, nil))
+                }
+            }
+            "begin" if !args.is_empty() => items.extend(try!(self.expressions(env, args))),
+            "let" if args.len() >= 3 => {
+                if let View::Identifier(name) = args[0].view(self.heap) {
+                    return self.named_let(env, form, items, &name, args)
+                }
+                return self.let_form(env, form, items, args, false)
+            }
+            "let" if args.len() == 2 => return self.let_form(env, form, items, args, false),
+            "letrec" | "letrec*" if args.len() >= 2 => {
+                return self.let_form(env, form, items, args, true)
+            }
+            "guard" if args.len() >= 2 => return self.guard(env, form, items, args),
+            "parameterize" if args.len() >= 2 => {
+                let bindings = match self.bindings(&args[0]) {
+                    Some(bindings) => bindings,
+                    None => return Ok(self.unchanged(form)),
+                };
+                let mut outs = vec![];
+                for (binding, elements) in bindings {
+                    let values = try!(self.expressions(env, &elements));
+                    let nil = self.nil();
+                    outs.push(self.rebuild(&binding, values, nil))
+                }
+                let nil = self.nil();
+                items.push(self.rebuild(&args[0], outs, nil));
+                let scope = self.scope(env);
+                items.extend(try!(self.body(&scope, &args[1..])))
+            }
+            "let-syntax" | "letrec-syntax" if args.len() >= 2 => {
+                return self.let_syntax(env, form, name == "letrec-syntax", args)
+            }
+            "define" | "define-syntax" => {
+                return self.error(form, "definition in expression context")
+            }
+            "import" | "define-library" => {
+                return self.error(form, &format!("{} in expression context", name))
+            }
+            "let-syntax" | "letrec-syntax" | "syntax-rules" | "er-macro-transformer" => {
+                return self.error(form, &format!("bad syntax in {}", name))
+            }
+            _ => return Ok(self.unchanged(form)),
+        }
+        let nil = self.nil();
+        Ok(self.rebuild(form, items, nil))
+    }
+
+    /// Binds the parameter list `formals` in `scope`, returning it with the
+    /// variables, or `None` if it is malformed.  The defaults of optional
+    /// and keyword parameters (see `Parser::parameters`) are expanded where
+    /// the parameters before them are bound.
+    fn formals(&mut self, scope: &Env, formals: &Syntax) -> Result<Option<Out>, String> {
+        let (elements, tail) = match formals.elements(self.heap) {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        let mut items = vec![];
+        // Whether parameters can have defaults here.
+        let mut defaults = false;
+        for element in &elements {
+            match element.view(self.heap) {
+                View::Identifier(identifier) => {
+                    if PARAMETER_MARKERS.contains(&&**identifier.name) {
+                        defaults = *identifier.name != "#!rest";
+                        items.push(self.unchanged(element))
+                    } else {
+                        items.push(Out::Variable(self.bind(scope, &identifier)))
+                    }
+                }
+                View::Pair(..) if defaults => {
+                    let parts = match element.proper_list(self.heap) {
+                        Some(parts) => parts,
+                        None => return Ok(None),
+                    };
+                    if parts.len() != 2 {
+                        return Ok(None)
+                    }
+                    let identifier = match parts[0].view(self.heap) {
+                        View::Identifier(identifier) => identifier,
+                        _ => return Ok(None),
+                    };
+                    let default = try!(self.expression(scope, &parts[1]));
+                    let variable = Out::Variable(self.bind(scope, &identifier));
+                    let nil = self.nil();
+                    items.push(self.rebuild(element, vec![variable, default], nil))
+                }
+                _ => return Ok(None),
+            }
+        }
+        let tail = match tail.view(self.heap) {
+            View::Identifier(identifier) => Out::Variable(self.bind(scope, &identifier)),
+            View::Nil => self.nil(),
+            _ => return Ok(None),
+        };
+        if items.is_empty() {
+            return Ok(Some(tail))
+        }
+        Ok(Some(self.rebuild(formals, items, tail)))
+    }
+
+    /// The bindings `((name value) ...)` of a binding form, with their
+    /// elements, or `None` if they are malformed.
+    fn bindings(&mut self, list: &Syntax) -> Option<Vec<(Syntax, Vec<Syntax>)>> {
+        let list = match list.proper_list(self.heap) {
+            Some(list) => list,
+            None => return None,
+        };
+        let mut bindings = vec![];
+        for binding in list {
+            match binding.proper_list(self.heap) {
+                Some(elements) => {
+                    if elements.len() != 2 {
+                        return None
+                    }
+                    bindings.push((binding, elements))
+                }
+                None => return None,
+            }
+        }
+        Some(bindings)
+    }
+
+    /// The identifiers bound by `bindings`, if they are all identifiers.
+    fn names(&mut self, bindings: &[(Syntax, Vec<Syntax>)]) -> Option<Vec<Rc<Identifier>>> {
+        let mut names = vec![];
+        for &(_, ref elements) in bindings {
+            match elements[0].view(self.heap) {
+                View::Identifier(identifier) => names.push(identifier),
+                _ => return None,
+            }
+        }
+        Some(names)
+    }
+
+    /// The bindings `bindings`, with the variables `variables` and the
+    /// values `values`.
+    fn rebuild_bindings(&mut self,
+                        list: &Syntax,
+                        bindings: &[(Syntax, Vec<Syntax>)],
+                        variables: Vec<Rc<Variable>>,
+                        values: Vec<Out>)
+                        -> Out {
+        let mut outs = vec![];
+        for ((&(ref binding, _), variable), value) in bindings.iter().zip(variables).zip(values) {
+            let nil = self.nil();
+            outs.push(self.rebuild(binding, vec![Out::Variable(variable), value], nil))
+        }
+        let nil = self.nil();
+        self.rebuild(list, outs, nil)
+    }
+
+    /// `let`, or `letrec` and `letrec*` if `recursive` is set.
+    fn let_form(&mut self,
+                env: &Env,
+                form: &Syntax,
+                mut items: Vec<Out>,
+                args: &[Syntax],
+                recursive: bool)
+                -> Result<Out, String> {
+        let bindings = match self.bindings(&args[0]) {
+            Some(bindings) => bindings,
+            None => return Ok(self.unchanged(form)),
+        };
+        let names = match self.names(&bindings) {
+            Some(names) => names,
+            None => return Ok(self.unchanged(form)),
+        };
+        let scope = self.scope(env);
+        let mut values = vec![];
+        if !recursive {
+            for &(_, ref elements) in &bindings {
+                values.push(try!(self.expression(env, &elements[1])))
+            }
+        }
+        let variables: Vec<_> = names.iter().map(|name| self.bind(&scope, name)).collect();
+        if recursive {
+            for &(_, ref elements) in &bindings {
+                values.push(try!(self.expression(&scope, &elements[1])))
+            }
+        }
+        items.push(self.rebuild_bindings(&args[0], &bindings, variables, values));
+        items.extend(try!(self.body(&scope, &args[1..])));
+        let nil = self.nil();
+        Ok(self.rebuild(form, items, nil))
+    }
+
+    /// `(let name bindings body ...)`
+    fn named_let(&mut self,
+                 env: &Env,
+                 form: &Syntax,
+                 mut items: Vec<Out>,
+                 name: &Rc<Identifier>,
+                 args: &[Syntax])
+                 -> Result<Out, String> {
+        let bindings = match self.bindings(&args[1]) {
+            Some(bindings) => bindings,
+            None => return Ok(self.unchanged(form)),
+        };
+        let names = match self.names(&bindings) {
+            Some(names) => names,
+            None => return Ok(self.unchanged(form)),
+        };
+        let mut values = vec![];
+        for &(_, ref elements) in &bindings {
+            values.push(try!(self.expression(env, &elements[1])))
+        }
+        let outer = self.scope(env);
+        items.push(Out::Variable(self.bind(&outer, name)));
+        let scope = self.scope(&outer);
+        let variables: Vec<_> = names.iter().map(|name| self.bind(&scope, name)).collect();
+        items.push(self.rebuild_bindings(&args[1], &bindings, variables, values));
+        items.extend(try!(self.body(&scope, &args[2..])));
+        let nil = self.nil();
+        Ok(self.rebuild(form, items, nil))
+    }
+
+    /// `(guard (variable clause ...) body ...)`.  The keywords of the
+    /// clauses, `else` and `=>`, are expanded like variables, so that the
+    /// parser recognizes them unless they are bound.
+    fn guard(&mut self,
+             env: &Env,
+             form: &Syntax,
+             mut items: Vec<Out>,
+             args: &[Syntax])
+             -> Result<Out, String> {
+        let spec = match args[0].proper_list(self.heap) {
+            Some(spec) => spec,
+            None => return Ok(self.unchanged(form)),
+        };
+        let variable = match spec.first().map(|variable| variable.view(self.heap)) {
+            Some(View::Identifier(variable)) => variable,
+            _ => return Ok(self.unchanged(form)),
+        };
+        let scope = self.scope(env);
+        let mut outs = vec![Out::Variable(self.bind(&scope, &variable))];
+        for clause in &spec[1..] {
+            let elements = match clause.proper_list(self.heap) {
+                Some(elements) => elements,
+                None => return Ok(self.unchanged(form)),
+            };
+            let clause_items = try!(self.expressions(&scope, &elements));
+            let nil = self.nil();
+            outs.push(self.rebuild(clause, clause_items, nil))
+        }
+        let nil = self.nil();
+        items.push(self.rebuild(&args[0], outs, nil));
+        let scope = self.scope(env);
+        items.extend(try!(self.body(&scope, &args[1..])));
+        let nil = self.nil();
+        Ok(self.rebuild(form, items, nil))
+    }
+
+    /// `(let-syntax ((keyword transformer) ...) body ...)`, or
+    /// `letrec-syntax` if `recursive` is set, which becomes
+    /// `(let () body ...)`.
+    fn let_syntax(&mut self,
+                  env: &Env,
+                  form: &Syntax,
+                  recursive: bool,
+                  args: &[Syntax])
+                  -> Result<Out, String> {
+        let bindings = match self.bindings(&args[0]) {
+            Some(bindings) => bindings,
+            None => return self.error(form, "bad syntax in let-syntax"),
+        };
+        let names = match self.names(&bindings) {
+            Some(names) => names,
+            None => return self.error(form, "bad syntax in let-syntax"),
+        };
+        let scope = self.scope(env);
+        let definition_env = if recursive { scope.clone() } else { env.clone() };
+        let mut definitions = vec![];
+        for (name, &(_, ref elements)) in names.iter().zip(&bindings) {
+            let definition = try!(Macro::new(self, name, &elements[1], &definition_env));
+            definitions.push((name.clone(), Denotation::Macro(Rc::new(definition))))
+        }
+        scope.as_ref().unwrap().bindings.borrow_mut().extend(definitions);
+        let mut items = vec![self.keyword(env, "let"), self.nil()];
+        items.extend(try!(self.body(&scope, &args[1..])));
+        let (nil, location) = (self.nil(), self.location(form));
+        Ok(Out::List(items, Box::new(nil), None, location))
+    }
+
+    /// `(define-syntax keyword transformer)`
+    fn define_syntax(&mut self,
+                     env: &Env,
+                     form: &Syntax)
+                     -> Result<(Rc<Identifier>, Rc<Macro>), String> {
+        let elements = form.proper_list(self.heap).unwrap_or(vec![]);
+        if elements.len() != 3 {
+            return self.error(form, "bad syntax in define-syntax")
+        }
+        let name = match elements[1].view(self.heap) {
+            View::Identifier(name) => name,
+            _ => return self.error(form, "bad syntax in define-syntax"),
+        };
+        let definition = try!(Macro::new(self, &name, &elements[2], env));
+        Ok((name, Rc::new(definition)))
+    }
+
+    /// A definition of `name`, in the body or library whose scope is `env`
+    /// if it is set, and otherwise at top level.
+    fn definition(&mut self,
+                  env: &Env,
+                  form: &Syntax,
+                  mut name: Option<Out>)
+                  -> Result<Out, String> {
+        let elements = match form.proper_list(self.heap) {
+            Some(ref elements) if elements.len() >= 3 => elements.clone(),
+            _ => return Ok(self.unchanged(form)),
+        };
+        let mut items = vec![self.keyword(env, "define")];
+        let mut name = |expander: &mut Self, identifier: &Rc<Identifier>| {
+            match name.take() {
+                Some(name) => name,
+                None => {
+                    let macros = &mut expander.heap.macros;
+                    if env.is_none() && macros.table.remove(&*identifier.name).is_some() {
+                        macros.record(Effect::Toplevel)
+                    }
+                    Out::Symbol(identifier.name.clone())
+                }
+            }
+        };
+        match elements[1].view(self.heap) {
+            View::Identifier(ref identifier) if elements.len() == 3 => {
+                items.push(name(self, identifier));
+                items.push(try!(self.expression(env, &elements[2])))
+            }
+            View::Pair(head, formals) => {
+                let identifier = match head.view(self.heap) {
+                    View::Identifier(identifier) => identifier,
+                    _ => return Ok(self.unchanged(form)),
+                };
+                let scope = self.scope(env);
+                let formals = match try!(self.formals(&scope, &formals)) {
+                    Some(Out::List(items, tail, _, _)) => (items, tail),
+                    Some(tail) => (vec![], Box::new(tail)),
+                    None => return Ok(self.unchanged(form)),
+                };
+                let mut signature = vec![name(self, &identifier)];
+                signature.extend(formals.0);
+                items.push(self.rebuild(&elements[1], signature, *formals.1));
+                items.extend(try!(self.body(&scope, &elements[2..])))
+            }
+            _ => return Ok(self.unchanged(form)),
+        }
+        let nil = self.nil();
+        Ok(self.rebuild(form, items, nil))
+    }
+
+    /// Expands a body, whose scope is `env`: definitions, then expressions.
+    fn body(&mut self, env: &Env, forms: &[Syntax]) -> Result<Vec<Out>, String> {
+        let mut pending: Vec<Syntax> = forms.iter().rev().cloned().collect();
+        let mut definitions = vec![];
+        while let Some(next) = pending.pop() {
+            let next = try!(self.expand_macros(env, &next));
+            match self.special(env, &next) {
+                Some("define") => {
+                    match self.defined_name(&next) {
+                        Some(name) => {
+                            let variable = self.bind(env, &name);
+                            definitions.push((next, variable))
+                        }
+                        None => return self.error(&next, "bad syntax in define"),
+                    }
+                }
+                Some("begin") if next.proper_list(self.heap).is_some() => {
+                    let elements = next.proper_list(self.heap).unwrap();
+                    pending.extend(elements[1..].iter().rev().cloned())
+                }
+                Some("define-syntax") => {
+                    let (name, definition) = try!(self.define_syntax(env, &next));
+                    self.bind_denotation(env, &name, Denotation::Macro(definition))
+                }
+                _ => {
+                    pending.push(next);
+                    break
+                }
+            }
+        }
+        let mut items = vec![];
+        for (definition, variable) in definitions {
+            items.push(try!(self.definition(env, &definition, Some(Out::Variable(variable)))))
+        }
+        while let Some(next) = pending.pop() {
+            items.push(try!(self.expression(env, &next)))
+        }
+        Ok(items)
+    }
+}
+
+/// Pushes the datum `out` on the stack.
+fn build(heap: &mut Heap, out: &Out) -> Result<(), String> {
+    match *out {
+        Out::Datum(ref root) => Ok(heap.stack.push(root.get())),
+        Out::Symbol(ref name) => Ok(heap.intern(name)),
+        Out::Variable(ref variable) => Ok(heap.intern(&variable.output_name())),
+        Out::List(ref items, ref tail, ref original, ref location) => {
+            let base = heap.stack.len();
+            let mut result = Ok(());
+            for item in items.iter().chain(Some(&**tail)) {
+                result = build(heap, item);
+                if result.is_err() {
+                    break
+                }
+            }
+            if result.is_ok() {
+                result = match *original {
+                    Some(ref original) if same_list(heap, &original.get(), base) => {
+                        Ok(heap.stack.push(original.get()))
+                    }
+                    _ => build_list(heap, base, items.len(), location),
+                };
+            }
+            finish(heap, base, result)
+        }
+        Out::Vector(ref items) => {
+            let base = heap.stack.len();
+            let mut result = Ok(());
+            for item in items {
+                result = build(heap, item);
+                if result.is_err() {
+                    break
+                }
+            }
+            if result.is_ok() {
+                result = heap.alloc_vector(base, base + items.len())
+            }
+            finish(heap, base, result)
+        }
+    }
+}
+
+/// Checks if the list `original` has the elements and final cdr that are
+/// on the stack from `base`.
+fn same_list(heap: &Heap, original: &Value, base: usize) -> bool {
+    let mut rest = original.clone();
+    for item in &heap.stack[base..heap.stack.len() - 1] {
+        match (rest.car(), rest.cdr()) {
+            (Ok(ref car), Ok(cdr)) if car.get() == item.get() => rest = cdr,
+            _ => return false,
+        }
+    }
+    rest.get() == heap.stack[heap.stack.len() - 1].get()
+}
+
+/// Pushes a list of the `len` elements on the stack from `base`, followed
+/// by its final cdr, recording its location.
+fn build_list(heap: &mut Heap, base: usize, len: usize, location: &Option<Location>)
+              -> Result<(), String> {
+    for i in (0..len).rev() {
+        let cdr = heap.stack.len() - 1;
+        try!(heap.alloc_pair(base + i, cdr))
+    }
+    match *location {
+        Some(ref location) if len > 0 => {
+            let index = heap.stack.len() - 1;
+            heap.set_location(index, location)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Replaces the values on the stack from `base` by the one on top, if
+/// `result` is `Ok`.
+fn finish(heap: &mut Heap, base: usize, result: Result<(), String>) -> Result<(), String> {
+    let value = heap.stack.pop();
+    heap.stack.truncate(base);
+    try!(result);
+    Ok(heap.stack.push(value.unwrap()))
+}
+
+/// Expands the macros in the datum on top of the stack, replacing it with
+/// the result.  It is expanded at top level if `imports` is `None`, and
+/// otherwise in a scope of its own that imports the list of import sets
+/// `imports`.
+pub fn expand(heap: &mut Heap, imports: Option<&Value>) -> Result<(), String> {
+    let datum = heap.stack[heap.stack.len() - 1].clone();
+    let form = Syntax::datum(heap, datum);
+    let imports = imports.map(|imports| Syntax::datum(heap, imports.clone()));
+    let out = {
+        let mut expander = Expander {
+            heap: heap,
+            next_variable: 0,
+            scopes: vec![],
+        };
+        let env = match imports {
+            None => None,
+            Some(imports) => {
+                let sets = match imports.proper_list(expander.heap) {
+                    Some(sets) => sets,
+                    None => return Err("eval: bad environment".to_owned()),
+                };
+                let scope = Rc::new(Scope {
+                    bindings: RefCell::new(vec![]),
+                    parent: None,
+                    isolated: true,
+                });
+                expander.scopes.push(scope.clone());
+                try!(expander.import_sets(&Some(scope.clone()), &sets));
+                Some(scope)
+            }
+        };
+        try!(expander.toplevel(&env, &form))
+    };
+    try!(build(heap, &out));
+    let expanded = heap.stack.pop().unwrap();
+    let len = heap.stack.len();
+    heap.stack[len - 1] = expanded;
+    Ok(())
+}
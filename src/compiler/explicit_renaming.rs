@@ -0,0 +1,336 @@
+//! Explicit-renaming macros.
+//!
+//! `(er-macro-transformer expression)` defines a macro by a procedure, the
+//! value of `expression`, which is evaluated in the global environment when
+//! the macro is defined.  A use of the macro is replaced by the result of
+//! `(procedure form rename compare)`, where:
+//!
+//! * `form` is the use, as a datum.
+//! * `(rename identifier)` is an identifier that means what `identifier`
+//!   means where the macro was defined, and that neither binds nor is bound
+//!   by the identifiers of the use.  Renaming an identifier twice in the
+//!   same expansion gives identifiers that bind each other.
+//! * `(compare x y)` checks if the identifiers `x` and `y` mean the same
+//!   thing where the macro is used.
+//!
+//! Symbols that the procedure inserts without renaming them are not
+//! hygienic: they mean what they mean where the macro is used.
+//!
+//! Renamed identifiers are not symbols.  They are closures over `ALIAS`,
+//! capturing their index in the `Expansion` that made them and its mark, so
+//! they mean nothing once the procedure has returned.
+
+use std::collections::HashSet;
+use std::rc::Rc;
+use alloc::{Heap, Location, Root};
+use builtins::{Primitive, args, boolean, callee};
+use interp;
+use value::{Kind, Tags, Value};
+use super::expand::{Env, Expander, Identifier, Syntax, View};
+
+/// A macro defined by `er-macro-transformer`.
+#[derive(Debug)]
+pub struct Transformer {
+    /// The environment of its definition.
+    env: Env,
+
+    procedure: Root,
+}
+
+/// A use of a procedural macro being expanded.
+#[derive(Debug)]
+pub struct Expansion {
+    mark: usize,
+
+    /// The environment of the use, where `compare` resolves identifiers.
+    env: Env,
+
+    /// The environment of the macro's definition.
+    definition: Env,
+
+    /// The identifiers passed to the procedure or made by `rename`.
+    aliases: Vec<Rc<Identifier>>,
+}
+
+/// The code of renamed identifiers.
+static ALIAS: Primitive = Primitive {
+    name: "identifier",
+    min_args: 0,
+    max_args: None,
+    function: call_alias,
+};
+
+/// The code of the `rename` procedure of an expansion.
+static RENAME: Primitive = Primitive {
+    name: "rename",
+    min_args: 1,
+    max_args: Some(1),
+    function: rename,
+};
+
+/// The code of the `compare` procedure of an expansion.
+static COMPARE: Primitive = Primitive {
+    name: "compare",
+    min_args: 2,
+    max_args: Some(2),
+    function: compare,
+};
+
+impl Transformer {
+    /// The macro that the `er-macro-transformer` form `transformer` defines
+    /// in `env`.
+    pub fn new(expander: &mut Expander,
+               transformer: &Syntax,
+               env: &Env)
+               -> Result<Transformer, String> {
+        let elements = transformer.proper_list(expander.heap).unwrap_or(vec![]);
+        if elements.len() != 2 {
+            return expander.error(transformer, "bad syntax in er-macro-transformer")
+        }
+        let base = expander.heap.stack.len();
+        let result = expander.strip(&elements[1]).and_then(|()| interp::execute(expander.heap));
+        if let Err(e) = result {
+            expander.heap.stack.truncate(base);
+            return expander.error(transformer, &e)
+        }
+        let procedure = expander.heap.stack.pop().unwrap();
+        match procedure.kind() {
+            Kind::Closure(_) | Kind::Primitive(_) => {}
+            _ => return expander.error(transformer, "er-macro-transformer: expected a procedure"),
+        }
+        Ok(Transformer {
+            env: env.clone(),
+            procedure: expander.heap.root(procedure),
+        })
+    }
+
+    /// Expands `form`, a use of the macro in `env`.
+    pub fn expand(&self,
+                  expander: &mut Expander,
+                  env: &Env,
+                  form: &Syntax)
+                  -> Result<Syntax, String> {
+        let mark = expander.mark();
+        let location = expander.location(form);
+        let result = {
+            let heap = &mut *expander.heap;
+            heap.macros.expansions.push(Expansion {
+                mark: mark,
+                env: env.clone(),
+                definition: self.env.clone(),
+                aliases: vec![],
+            });
+            let base = heap.stack.len();
+            let result = transform(heap, self.procedure.get(), form, mark).and_then(|()| {
+                let value = heap.stack[base].clone();
+                syntax(heap, &value, &location, &mut HashSet::new())
+                    .map(|syntax| syntax.unwrap_or_else(|| Syntax::datum(heap, value)))
+            });
+            heap.stack.truncate(base);
+            heap.macros.expansions.pop();
+            result
+        };
+        result.or_else(|e| expander.error(form, &e))
+    }
+}
+
+/// Calls `procedure` with `form` and the `rename` and `compare` procedures
+/// of expansion `mark`, pushing the result.
+fn transform(heap: &mut Heap, procedure: Value, form: &Syntax, mark: usize) -> Result<(), String> {
+    heap.stack.push(procedure);
+    let position = heap.macros.expansions.len() - 1;
+    try!(push_form(heap, position, form));
+    try!(push_closure(heap, &RENAME, &[Value::new_fixnum(mark)]));
+    try!(push_closure(heap, &COMPARE, &[Value::new_fixnum(mark)]));
+    interp::call(heap, 3)
+}
+
+/// Pushes a closure over `code`, capturing `captured`.
+fn push_closure(heap: &mut Heap, code: &'static Primitive, captured: &[Value])
+                -> Result<(), String> {
+    let start = heap.stack.len();
+    heap.stack.push(code.to_value());
+    heap.stack.extend_from_slice(captured);
+    let end = heap.stack.len();
+    let result = heap.alloc_closure(start, end).map(|()| heap.stack.pop().unwrap());
+    heap.stack.truncate(start);
+    result.map(|closure| heap.stack.push(closure))
+}
+
+/// Pushes `identifier`, as a renamed identifier of the expansion at
+/// `position`.
+fn push_alias(heap: &mut Heap, position: usize, identifier: Rc<Identifier>)
+              -> Result<(), String> {
+    let (index, mark) = {
+        let expansion = &mut heap.macros.expansions[position];
+        expansion.aliases.push(identifier);
+        (expansion.aliases.len() - 1, expansion.mark)
+    };
+    push_closure(heap, &ALIAS, &[Value::new_fixnum(index), Value::new_fixnum(mark)])
+}
+
+/// Pushes `form` as a datum, for the procedure of the expansion at
+/// `position`.
+fn push_form(heap: &mut Heap, position: usize, form: &Syntax) -> Result<(), String> {
+    let base = heap.stack.len();
+    match *form {
+        Syntax::Datum(ref root) => return Ok(heap.stack.push(root.get())),
+        Syntax::Identifier(ref identifier) => return push_alias(heap, position, identifier.clone()),
+        Syntax::Pair(ref pair, _) => {
+            try!(push_form(heap, position, &pair.0));
+            try!(push_form(heap, position, &pair.1));
+            try!(heap.alloc_pair(base, base + 1))
+        }
+        Syntax::Vector(ref items) => {
+            for item in items.iter() {
+                try!(push_form(heap, position, item))
+            }
+            try!(heap.alloc_vector(base, base + items.len()))
+        }
+    }
+    let value = heap.stack.pop().unwrap();
+    heap.stack.truncate(base);
+    Ok(heap.stack.push(value))
+}
+
+/// The index and mark of `value`, if it is a renamed identifier.
+fn alias_parts(value: &Value) -> Option<(usize, usize)> {
+    match value.kind() {
+        Kind::Closure(closure) => unsafe {
+            match (*closure).code.kind() {
+                Kind::Primitive(primitive) if primitive == &ALIAS as *const Primitive => {
+                    Some(((*closure).captured(0).as_fixnum().unwrap(),
+                          (*closure).captured(1).as_fixnum().unwrap()))
+                }
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// The identifier `value` is, if it is one.  Renamed identifiers must
+/// belong to an expansion in progress.
+fn identifier(heap: &Heap, value: &Value) -> Result<Option<Rc<Identifier>>, String> {
+    if let Some((index, mark)) = alias_parts(value) {
+        return match heap.macros.expansions.iter().find(|expansion| expansion.mark == mark) {
+            Some(expansion) => Ok(Some(expansion.aliases[index].clone())),
+            None => Err("identifier used outside of its macro expansion".to_owned()),
+        }
+    }
+    if value.tag() != Tags::Symbol {
+        return Ok(None)
+    }
+    match Syntax::datum(heap, value.clone()).view(heap) {
+        View::Identifier(identifier) => Ok(Some(identifier)),
+        _ => unreachable!(),
+    }
+}
+
+/// The result `value` of a procedure as syntax, or `None` if it contains
+/// no renamed identifiers, so that it can be used as is.  `path` holds the
+/// pairs and vectors being converted, so that cycles are left alone.
+fn syntax(heap: &mut Heap,
+          value: &Value,
+          location: &Option<Location>,
+          path: &mut HashSet<usize>)
+          -> Result<Option<Syntax>, String> {
+    if alias_parts(value).is_some() {
+        return identifier(heap, value).map(|identifier| identifier.map(Syntax::Identifier))
+    }
+    match value.kind() {
+        Kind::Pair(_) => {
+            let (mut items, mut rest, mut changed) = (vec![], value.clone(), false);
+            let mut spine = vec![];
+            while rest.tag() == Tags::Pair && path.insert(rest.get()) {
+                spine.push(rest.get());
+                let car = rest.car().unwrap();
+                items.push(match try!(syntax(heap, &car, location, path)) {
+                    Some(item) => {
+                        changed = true;
+                        item
+                    }
+                    None => Syntax::datum(heap, car),
+                });
+                rest = rest.cdr().unwrap()
+            }
+            let tail = try!(syntax(heap, &rest, location, path));
+            for pair in spine {
+                path.remove(&pair);
+            }
+            if !changed && tail.is_none() {
+                return Ok(None)
+            }
+            let tail = tail.unwrap_or_else(|| Syntax::datum(heap, rest));
+            let location = heap.location(value).or_else(|| location.clone());
+            Ok(Some(Syntax::list(items, tail, &location)))
+        }
+        Kind::Vector(vector) => {
+            if !path.insert(value.get()) {
+                return Ok(None)
+            }
+            let elements: Vec<_> = unsafe {
+                (0..(*vector).len()).map(|i| (*vector).element(i).clone()).collect()
+            };
+            let (mut items, mut changed) = (vec![], false);
+            for element in elements {
+                items.push(match try!(syntax(heap, &element, location, path)) {
+                    Some(item) => {
+                        changed = true;
+                        item
+                    }
+                    None => Syntax::datum(heap, element),
+                })
+            }
+            path.remove(&value.get());
+            Ok(if changed { Some(Syntax::Vector(Rc::new(items))) } else { None })
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The position of the expansion whose `rename` or `compare` procedure was
+/// called with `nargs` arguments.
+fn expansion(heap: &Heap, nargs: usize, procedure: &str) -> Result<usize, String> {
+    let mark = match callee(heap, nargs).kind() {
+        Kind::Closure(closure) => unsafe { (*closure).captured(0).as_fixnum().unwrap() },
+        _ => unreachable!(),
+    };
+    match heap.macros.expansions.iter().position(|expansion| expansion.mark == mark) {
+        Some(position) => Ok(position),
+        None => Err(format!("{}: called outside of its macro expansion", procedure)),
+    }
+}
+
+/// Called when a renamed identifier is called as a procedure.
+fn call_alias(_: &mut Heap, _: usize) -> Result<Value, String> {
+    Err("Attempt to call an identifier".to_owned())
+}
+
+/// `(rename identifier)`
+fn rename(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let position = try!(expansion(heap, nargs, "rename"));
+    let original = match try!(identifier(heap, &args(heap, nargs)[0])) {
+        Some(identifier) => identifier,
+        None => return Err("rename: expected an identifier".to_owned()),
+    };
+    let renamed = {
+        let expansion = &heap.macros.expansions[position];
+        Identifier::rename(&original, &expansion.definition, expansion.mark)
+    };
+    try!(push_alias(heap, position, renamed));
+    Ok(heap.stack.pop().unwrap())
+}
+
+/// `(compare x y)`
+fn compare(heap: &mut Heap, nargs: usize) -> Result<Value, String> {
+    let position = try!(expansion(heap, nargs, "compare"));
+    let args = args(heap, nargs);
+    match (try!(identifier(heap, &args[0])), try!(identifier(heap, &args[1]))) {
+        (Some(x), Some(y)) => {
+            let env = &heap.macros.expansions[position].env;
+            Ok(boolean(heap.macros.resolve(env, &x).same(&heap.macros.resolve(env, &y))))
+        }
+        _ => Ok(boolean(args[0] == args[1])),
+    }
+}
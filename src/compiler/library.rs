@@ -0,0 +1,410 @@
+//! R7RS libraries.
+//!
+//! `(define-library name declaration ...)` is expanded at top level (see
+//! `Expander::define_library`).  Its top level is a scope of its own, which
+//! binds what it imports and defines; other identifiers refer to special
+//! forms and global variables, as they do at top level.  What a library
+//! exports is recorded in the `Libraries` of the heap when it is expanded.
+//!
+//! `import` looks up the libraries of its import sets, and loads those
+//! that are not defined yet from the search path: `(foo bar)` is read from
+//! `foo/bar.sld` in the first directory that has it, or from `foo/bar.fasl`
+//! if it was compiled since (see `fasl`).  The libraries called
+//! `(scheme ...)` are built in, and export every primitive and special form,
+//! and so are the extension libraries called `(rusty-scheme ...)`, such as
+//! `(rusty-scheme file-system)`, `(rusty dynvector)`, `(process)`, and the
+//! SRFIs in `BUILTIN_SRFIS`, such as `(srfi 1)`.
+//!
+//! The rest of `(scheme base)`, such as `cond` and `cadr`, is written in
+//! Scheme, in `scheme/base.scm`, and embedded in the library.  It is loaded
+//! into the top level of an interpreter just before the interpreter first
+//! compiles or runs code (see `load_base`), and the built-in libraries
+//! export it too, unless the interpreter is bare.  The first interpreter on
+//! a thread compiles it, and later ones load the compiled code.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use alloc::{Heap, Location};
+use builtins;
+use fasl;
+use value::{self, Kind, Value};
+use super::SPECIAL_FORMS;
+use super::expand::{self, Denotation, Effect, Expander, Identifier, MACRO_FORMS, Syntax, View};
+
+/// What a library exports.
+#[derive(Debug)]
+pub struct Library {
+    exports: Vec<(Rc<String>, Denotation)>,
+}
+
+/// The libraries defined so far, and where to find the others.
+#[derive(Debug)]
+pub struct Libraries {
+    table: HashMap<String, Rc<Library>>,
+
+    /// The directories searched for library files, in order.
+    path: Vec<PathBuf>,
+
+    /// The libraries being loaded, to catch circular imports.
+    loading: Vec<String>,
+
+    /// Whether the Scheme part of `(scheme base)` is loaded.
+    base: Base,
+}
+
+/// Whether the Scheme part of `(scheme base)` is loaded into an
+/// interpreter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Base {
+    Pending,
+    Loaded,
+
+    /// It is never loaded.
+    Bare,
+}
+
+impl Default for Libraries {
+    fn default() -> Self {
+        Libraries {
+            table: HashMap::new(),
+            path: vec![PathBuf::from(".")],
+            loading: vec![],
+            base: Base::Pending,
+        }
+    }
+}
+
+impl Libraries {
+    /// Leaves out the Scheme part of `(scheme base)`, which must not have
+    /// been loaded yet.
+    pub fn set_bare(&mut self) {
+        self.base = Base::Bare
+    }
+
+    /// Searches `directory` for library files, after the directories
+    /// already on the search path.
+    pub fn add_directory(&mut self, directory: PathBuf) {
+        self.path.push(directory)
+    }
+
+    /// The names of the libraries that have been defined, rather than built
+    /// in, sorted.
+    pub fn defined(&self) -> Vec<String> {
+        let builtin = |name: &String| {
+            let parts: Vec<String> = name.trim_matches(|c| c == '(' || c == ')')
+                                         .split(' ')
+                                         .map(str::to_owned)
+                                         .collect();
+            is_builtin(&parts)
+        };
+        let mut names: Vec<String> = self.table.keys().filter(|name| !builtin(name)).cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// What the library `name` exports, as pairs of names and the names of
+    /// the global variables or special forms they denote.  `None` if it is
+    /// not defined, or if it exports macros, which cannot be saved.
+    pub fn saved_exports(&self, name: &str) -> Option<Vec<(String, String)>> {
+        let library = match self.table.get(name) {
+            Some(library) => library,
+            None => return None,
+        };
+        library.exports
+               .iter()
+               .map(|&(ref name, ref denotation)| {
+                   let target = match *denotation {
+                       Denotation::Global(ref global) => (**global).clone(),
+                       Denotation::Special(special) => special.to_owned(),
+                       _ => return None,
+                   };
+                   Some(((**name).clone(), target))
+               })
+               .collect()
+    }
+
+    /// Defines the library `name`, which exports `exports`, as given by
+    /// `saved_exports`.
+    pub fn restore(&mut self, name: String, exports: Vec<(String, String)>) {
+        let exports = exports.into_iter()
+                             .map(|(name, target)| {
+                                 (Rc::new(name), expand::builtin(&Rc::new(target)))
+                             })
+                             .collect();
+        self.table.insert(name, Rc::new(Library { exports: exports }));
+    }
+}
+
+/// The names of the primitives and special forms, except internal ones.
+pub fn builtin_names() -> Vec<&'static str> {
+    builtins::names()
+        .into_iter()
+        .filter(|name| !name.starts_with('%'))
+        .chain(SPECIAL_FORMS.iter().cloned())
+        .chain(MACRO_FORMS.iter().cloned())
+        .collect()
+}
+
+/// The source of the Scheme part of `(scheme base)`.
+const BASE_SOURCE: &'static str = include_str!("../scheme/base.scm");
+
+/// The file that errors in `BASE_SOURCE` refer to.
+const BASE_FILE: &'static str = "scheme/base.scm";
+
+/// The names that `BASE_SOURCE` defines.
+static BASE_NAMES: [&'static str; 22] =
+    ["and", "or", "when", "unless", "cond", "case", "let*", "do", "not", "boolean?",
+     "boolean=?", "caar", "cadr", "cdar", "cddr", "list?", "zero?", "positive?", "negative?",
+     "max", "min", "call-with-port"];
+
+thread_local! {
+    /// `BASE_SOURCE`, compiled by the first interpreter on this thread that
+    /// loaded it.
+    static COMPILED_BASE: RefCell<Option<Rc<Vec<u8>>>> = RefCell::new(None);
+}
+
+/// The names that the Scheme part of `(scheme base)` defines, unless the
+/// interpreter is bare.
+pub fn base_names(libraries: &Libraries) -> &'static [&'static str] {
+    match libraries.base {
+        Base::Bare => &[],
+        Base::Pending | Base::Loaded => &BASE_NAMES,
+    }
+}
+
+/// Loads the Scheme part of `(scheme base)` into the top level, unless it
+/// is loaded already or the interpreter is bare.
+pub fn load_base(heap: &mut Heap) -> Result<(), String> {
+    if heap.macros.libraries.base != Base::Pending {
+        return Ok(())
+    }
+    heap.macros.libraries.base = Base::Loaded;
+    // Like a library, it is not part of a file being compiled.
+    let effects = heap.macros.effects.take();
+    let result = match COMPILED_BASE.with(|compiled| compiled.borrow().clone()) {
+        Some(compiled) => fasl::load(heap, Path::new(BASE_FILE), &compiled),
+        None => {
+            fasl::compile(heap, Path::new(BASE_FILE), BASE_SOURCE.as_bytes()).map(|compiled| {
+                COMPILED_BASE.with(|cell| *cell.borrow_mut() = Some(Rc::new(compiled)))
+            })
+        }
+    };
+    heap.macros.effects = effects;
+    result
+}
+
+/// The numbers of the SRFIs whose libraries are built in.
+static BUILTIN_SRFIS: [&'static str; 7] = ["1", "13", "125", "132", "133", "28", "48"];
+
+/// Whether the library whose name has the parts `parts` is built in.
+fn is_builtin(parts: &[String]) -> bool {
+    parts[0] == "scheme" || parts[0] == "rusty-scheme" || parts == ["rusty", "dynvector"] ||
+    parts == ["process"] ||
+    parts.len() == 2 && parts[0] == "srfi" && BUILTIN_SRFIS.contains(&&*parts[1])
+}
+
+/// The built-in library, which exports every primitive and special form,
+/// and the Scheme part of `(scheme base)`.
+fn builtin_library(heap: &Heap) -> Library {
+    let base = base_names(&heap.macros.libraries).iter().cloned();
+    let exports = builtin_names().into_iter().chain(base).map(|name| {
+        let name = Rc::new(name.to_owned());
+        let denotation = heap.macros.toplevel_macro(&name);
+        (name.clone(), denotation.unwrap_or_else(|| expand::builtin(&name)))
+    });
+    Library { exports: exports.collect() }
+}
+
+impl<'a> Expander<'a> {
+    /// The library name `form`, written out, and its parts, if it is one.
+    pub fn library_name(&self, form: &Syntax) -> Option<(String, Vec<String>)> {
+        let mut parts = vec![];
+        for part in form.proper_list(self.heap).unwrap_or(vec![]) {
+            match part.view(self.heap) {
+                View::Identifier(identifier) => parts.push((*identifier.name).clone()),
+                View::Other(value) => {
+                    match value.kind() {
+                        Kind::Fixnum(number) => parts.push(number.to_string()),
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        if parts.is_empty() {
+            return None
+        }
+        Some((format!("({})", parts.join(" ")), parts))
+    }
+
+    /// Records that the library `name` exports `exports`.
+    pub fn define_exports(&mut self, name: &str, exports: Vec<(Rc<String>, Denotation)>) {
+        let library = Rc::new(Library { exports: exports });
+        self.heap.macros.libraries.table.insert(name.to_owned(), library);
+        self.heap.macros.record(Effect::Library(name.to_owned()))
+    }
+
+    /// The bindings that the import set `set` imports.
+    pub fn import_set(&mut self, set: &Syntax) -> Result<Vec<(Rc<String>, Denotation)>, String> {
+        let elements = set.proper_list(self.heap).unwrap_or(vec![]);
+        let keyword = match elements.first().map(|keyword| keyword.view(self.heap)) {
+            Some(View::Identifier(keyword)) => keyword.name.clone(),
+            _ => return self.error(set, "bad import set"),
+        };
+        let keyword = &keyword[..];
+        match keyword {
+            "only" | "except" | "prefix" | "rename" if elements.len() >= 2 => {}
+            _ => {
+                let library = try!(self.library(set));
+                return Ok(library.exports.clone())
+            }
+        }
+        let bindings = try!(self.import_set(&elements[1]));
+        // The names each modifier is about, with their new names.
+        let mut names = vec![];
+        for element in &elements[2..] {
+            let names_in = match element.view(self.heap) {
+                View::Identifier(name) if keyword != "rename" => Some(vec![name]),
+                _ if keyword == "rename" => self.identifiers(element),
+                _ => None,
+            };
+            match names_in {
+                Some(ref pair) if pair.len() == 2 => {
+                    names.push((pair[0].name.clone(), pair[1].name.clone()))
+                }
+                Some(ref name) if name.len() == 1 && keyword != "rename" => {
+                    names.push((name[0].name.clone(), name[0].name.clone()))
+                }
+                _ => return self.error(set, "bad import set"),
+            }
+        }
+        if keyword == "prefix" {
+            if names.len() != 1 {
+                return self.error(set, "bad import set")
+            }
+            return Ok(bindings.into_iter()
+                              .map(|(name, denotation)| {
+                                  (Rc::new(format!("{}{}", names[0].0, name)), denotation)
+                              })
+                              .collect())
+        }
+        for &(ref name, _) in &names {
+            if !bindings.iter().any(|binding| binding.0 == *name) {
+                return self.error(set, &format!("identifier not in import set: {}", name))
+            }
+        }
+        let new_name = |name: &Rc<String>| {
+            names.iter().find(|names| names.0 == *name).map(|names| names.1.clone())
+        };
+        Ok(bindings.into_iter()
+                   .filter_map(|(name, denotation)| {
+                       match new_name(&name) {
+                           None if keyword == "only" => None,
+                           Some(_) if keyword == "except" => None,
+                           Some(name) => Some((name, denotation)),
+                           None => Some((name, denotation)),
+                       }
+                   })
+                   .collect())
+    }
+
+    /// The elements of `form`, if it is a list of identifiers.
+    pub fn identifiers(&self, form: &Syntax) -> Option<Vec<Rc<Identifier>>> {
+        let mut identifiers = vec![];
+        for element in form.proper_list(self.heap).unwrap_or(vec![]) {
+            match element.view(self.heap) {
+                View::Identifier(identifier) => identifiers.push(identifier),
+                _ => return None,
+            }
+        }
+        Some(identifiers)
+    }
+
+    /// The library called `form`, loading it if needed.
+    fn library(&mut self, form: &Syntax) -> Result<Rc<Library>, String> {
+        let (name, parts) = match self.library_name(form) {
+            Some(name) => name,
+            None => return self.error(form, "bad import set"),
+        };
+        if parts[0] != "scheme" {
+            self.heap.macros.record(Effect::Import(parts.clone()))
+        }
+        let location = self.location(form);
+        find_library(self.heap, &name, &parts, &location)
+    }
+}
+
+/// The library called `name`, whose parts are `parts`, loading it if
+/// needed.  `location` is where it is imported, for error messages.
+pub fn find_library(heap: &mut Heap,
+                    name: &str,
+                    parts: &[String],
+                    location: &Option<Location>)
+                    -> Result<Rc<Library>, String> {
+    let error = |message: String| {
+        Err(match *location {
+            Some(ref location) => format!("{}: {}", location, message),
+            None => message,
+        })
+    };
+    if let Some(library) = heap.macros.libraries.table.get(name) {
+        return Ok(library.clone())
+    }
+    if is_builtin(parts) {
+        let library = Rc::new(builtin_library(heap));
+        heap.macros.libraries.table.insert(name.to_owned(), library.clone());
+        return Ok(library)
+    }
+    if heap.macros.libraries.loading.iter().any(|loading| loading == name) {
+        return error(format!("circular import of library {}", name))
+    }
+    if !heap.control.allows("load") {
+        return error(format!("cannot load library {}: file access is denied", name))
+    }
+    if !parts.iter().all(|part| is_file_name(part)) {
+        return error(format!("bad library name for a file: {}", name))
+    }
+    let file = match library_file(&heap.macros.libraries.path, parts) {
+        Some(file) => file,
+        None => return error(format!("library not found: {}", name)),
+    };
+    heap.macros.libraries.loading.push(name.to_owned());
+    // Libraries loaded while a file is compiled are not part of it.
+    let effects = heap.macros.effects.take();
+    let result = builtins::load(heap, &file, Value::new(value::TRUE));
+    heap.macros.effects = effects;
+    heap.macros.libraries.loading.pop();
+    try!(result);
+    match heap.macros.libraries.table.get(name) {
+        Some(library) => Ok(library.clone()),
+        None => error(format!("{} does not define library {}", file.display(), name)),
+    }
+}
+
+/// Whether `part` can be part of the name of a library file: it must name a
+/// file in its directory, not the directory itself, its parent or a path.
+fn is_file_name(part: &str) -> bool {
+    !part.is_empty() && part != "." && part != ".." &&
+    !part.chars().any(::std::path::is_separator)
+}
+
+/// The file of the library whose name has the parts `parts`, from the first
+/// directory on `path` that has one: its compiled file, unless its source
+/// file is newer.
+fn library_file(path: &[PathBuf], parts: &[String]) -> Option<PathBuf> {
+    let relative = parts.join("/");
+    let modified = |file: &PathBuf| file.metadata().and_then(|metadata| metadata.modified()).ok();
+    for directory in path {
+        let source = directory.join(format!("{}.sld", relative));
+        let compiled = directory.join(format!("{}.fasl", relative));
+        if compiled.is_file() && (!source.is_file() || modified(&compiled) >= modified(&source)) {
+            return Some(compiled)
+        }
+        if source.is_file() {
+            return Some(source)
+        }
+    }
+    None
+}
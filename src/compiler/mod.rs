@@ -1,33 +1,2101 @@
-use api;
-
-pub fn compile_list(vec: &mut Vec<Opcode>, s: &mut api::State)
-                    -> Result<usize, &'static str> {
-    try!(s.dup());
-    let mut length = 1usize;
-    while let Ok(x) = s.car() {
-        try!(compile_form(vec, s));
-        length += 1;
-        if (s.marked()) {
-            return Err("circular list not allowed")
-        }
-        s.mark();
-        try!(s.cdr());
-        try!(s.dup());
-    }
-    let retval = if s.null() {
-        Ok(length)
+//! The compiler, which turns Scheme data into bytecode.
+//!
+//! Compilation has four phases:
+//!
+//! 1. Parsing (`Parser`) turns a datum into an `Expr` tree, recognizing the
+//!    special forms and resolving variables.  It does not allocate, so the
+//!    objects of the datum stay put while it runs.  The values the code
+//!    needs — constants, and the symbols naming globals and procedures — are
+//!    collected in a side table.
+//! 2. Code generation (`generate`) turns each `Lambda` into a `Function`: a
+//!    list of instructions, and the constants they refer to.
+//! 3. Optimization (`optimize`) simplifies the instructions, unless it is
+//!    turned off to debug the compiler.
+//! 4. Assembly (`assemble`) allocates a BCO for each `Function`.  The side
+//!    table is pushed on the stack first, so that its values survive the
+//!    allocations.
+//!
+//! The variables of a procedure are its first registers: the arguments,
+//! and then the variables bound by `let`, `letrec` and internal definitions
+//! in its body.  The temporaries that hold intermediate values come after
+//! them, and are allocated like a stack as expressions are compiled: an
+//! expression is compiled into the register its value is wanted in, and a
+//! variable is used in place where an operand can be.  `Enter` makes room
+//! for all the registers of the procedure.  Closures are flat: making one
+//! copies the variables of enclosing procedures that its code refers to into
+//! the closure, where the code finds them by index.  A variable whose copies
+//! could get out of date is kept in a box instead (see `boxed_variables`),
+//! and the box is copied.
+//!
+//! The constants vector of a BCO holds the name of the procedure (or `#f`)
+//! at index 0, a source map at index 1, and the constants its code refers to
+//! after that.  The source map is a vector `#(pc file line column ...)`
+//! giving the location of the procedure (at pc 0), and of the form each
+//! call and global variable reference came from, followed by the stack
+//! map: a bytevector giving the number of registers in use while each
+//! instruction runs, which is how far the temporaries had been allocated
+//! when it was generated.  The collector clears the registers above them
+//! (see `alloc::stack_map`).
+
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use alloc::{Heap, Location};
+use builtins;
+use bytecode::{Bytecode, Opcode};
+use read;
+use value::{self, Kind, Value};
+
+pub use self::expand::{Effect, Macros};
+pub use self::library::{find_library, load_base};
+
+mod expand;
+mod explicit_renaming;
+mod library;
+mod optimize;
+mod syntax_rules;
+
+/// The special forms, which are recognized by name unless shadowed by a
+/// local variable.
+const SPECIAL_FORMS: &'static [&'static str] = &["quote", "lambda", "if", "define", "set!",
+                                                 "let", "letrec", "letrec*", "begin", "guard",
+                                                 "parameterize", "case-lambda"];
+
+/// The stack map entry of an instruction that all registers are live at,
+/// such as the `Enter` of a procedure, when the frame holds its arguments.
+const ALL_LIVE: u8 = 255;
+
+/// A parsed expression.
+enum Expr {
+    /// The constant `values[index]`.
+    Constant(usize),
+
+    /// `#t`, `#f`, `()` or the unspecified value, loaded by the opcode.
+    Immediate(Opcode),
+
+    /// A local variable: the nesting level of the procedure that binds it,
+    /// and its slot among that procedure's variables.
+    Local(usize, usize),
+
+    /// The global variable named by `values[index]`.
+    Global(usize),
+
+    /// `set!` of a local variable.
+    SetLocal(usize, usize, Box<Expr>),
+
+    /// The initialization of a local variable by the procedure that binds
+    /// it, as in `let`, `letrec` and internal definitions.
+    InitLocal(usize, usize, Box<Expr>),
+
+    /// The initialization of an optional or keyword parameter that was not
+    /// passed, to its default.
+    Default(usize, usize, Box<Expr>),
+    SetGlobal(usize, Box<Expr>),
+    Define(usize, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    Lambda(Box<Lambda>),
+
+    /// `case-lambda`: its clauses, as procedures with the name of the
+    /// whole, and its location.
+    CaseLambda(Vec<Lambda>, Option<usize>, Option<Location>),
+    Sequence(Vec<Expr>),
+
+    /// A procedure call, and the location of its form.
+    Apply(Box<Expr>, Vec<Expr>, Option<Location>),
+}
+
+/// A parsed `lambda` expression.
+struct Lambda {
+    /// The name of the procedure, as an index into `values`.
+    name: Option<usize>,
+
+    /// The number of required arguments.
+    required: usize,
+
+    /// The number of optional arguments.
+    optional: usize,
+
+    /// Whether there is a rest argument.
+    rest: bool,
+
+    /// The names of the keyword parameters, as indices into `values`.
+    keys: Vec<usize>,
+
+    /// The number of variables, including the arguments.
+    slots: usize,
+
+    body: Expr,
+    location: Option<Location>,
+}
+
+/// A local variable in scope.
+struct Binding {
+    name: Rc<String>,
+    level: usize,
+    slot: usize,
+}
+
+/// How a definition computes its value.
+enum Definition {
+    /// `(define name expression)`
+    Value(Value),
+
+    /// `(define (name . formals) body ...)`
+    Procedure(Value, Vec<Value>),
+}
+
+/// The parameters of a procedure, in the order of its variables.
+#[derive(Default)]
+struct Parameters {
+    required: Vec<Value>,
+
+    /// The optional parameters, and their defaults, if given.
+    optional: Vec<(Value, Option<Value>)>,
+    rest: Option<Value>,
+
+    /// The keyword parameters, and their defaults, if given.
+    keys: Vec<(Value, Option<Value>)>,
+}
+
+/// The name of `value`, if it is a symbol.
+fn symbol_name(value: &Value) -> Option<Rc<String>> {
+    match value.kind() {
+        Kind::Symbol(symbol) => Some(unsafe { (*symbol).name() }),
+        _ => None,
+    }
+}
+
+/// `exprs` as a single expression.
+fn sequence(mut exprs: Vec<Expr>) -> Expr {
+    if exprs.len() == 1 {
+        exprs.pop().unwrap()
     } else {
-        Err("dotted list not allowed")
+        Expr::Sequence(exprs)
+    }
+}
+
+struct Parser<'a> {
+    heap: &'a mut Heap,
+
+    /// The values the code refers to, and their indices by address.
+    values: Vec<Value>,
+    indices: HashMap<usize, usize>,
+
+    /// The local variables in scope, innermost last.
+    bindings: Vec<Binding>,
+
+    /// The number of slots used so far by each enclosing procedure,
+    /// outermost first.
+    slots: Vec<usize>,
+}
+
+impl<'a> Parser<'a> {
+    /// The index of `value` in `values`, adding it if need be.
+    fn value(&mut self, value: &Value) -> usize {
+        let values = &mut self.values;
+        *self.indices.entry(value.get()).or_insert_with(|| {
+            values.push(value.clone());
+            values.len() - 1
+        })
+    }
+
+    /// An error in `form`, prefixed by its location if known.
+    fn error<T>(&mut self, form: &Value, message: &str) -> Result<T, String> {
+        Err(match self.heap.location(form) {
+            Some(location) => format!("{}: {}", location, message),
+            None => message.to_owned(),
+        })
+    }
+
+    /// The elements of the proper list `list`, which is part of `form`.
+    fn elements(&mut self, list: &Value, form: &Value) -> Result<Vec<Value>, String> {
+        let mut elements = vec![];
+        let (mut rest, mut slow) = (list.clone(), list.clone());
+        loop {
+            match rest.kind() {
+                Kind::Pair(_) => {
+                    elements.push(rest.car().unwrap());
+                    rest = rest.cdr().unwrap();
+                    if elements.len() % 2 == 0 {
+                        slow = slow.cdr().unwrap();
+                        if slow.get() == rest.get() {
+                            return self.error(form, "circular list in code")
+                        }
+                    }
+                }
+                Kind::Constant(value::NIL) => return Ok(elements),
+                _ => return self.error(form, "improper list in code"),
+            }
+        }
+    }
+
+    /// The innermost local variable called `name`, if any.
+    fn lookup(&self, name: &str) -> Option<&Binding> {
+        self.bindings.iter().rev().find(|binding| *binding.name == name)
+    }
+
+    /// Binds `symbol` to a new variable slot of the innermost
+    /// procedure.  Returns its level and slot.
+    fn bind(&mut self, symbol: &Value) -> (usize, usize) {
+        self.bind_name(symbol_name(symbol).unwrap())
+    }
+
+    /// Binds `name`, which need not be the name of a symbol.  Names that
+    /// cannot be read as symbols are invisible to the code being compiled.
+    fn bind_name(&mut self, name: Rc<String>) -> (usize, usize) {
+        let level = self.slots.len() - 1;
+        let slot = self.slots[level];
+        self.slots[level] += 1;
+        self.bindings.push(Binding {
+            name: name,
+            level: level,
+            slot: slot,
+        });
+        (level, slot)
+    }
+
+    /// A reference to the primitive `name`, which cannot be shadowed.
+    fn primitive(&mut self, name: &str) -> Expr {
+        let primitive = builtins::lookup(name).unwrap().to_value();
+        Expr::Constant(self.value(&primitive))
+    }
+
+    /// Checks if `value` is the symbol `name`, and not a local variable.
+    fn is_keyword(&self, value: &Value, name: &str) -> bool {
+        symbol_name(value).map_or(false, |symbol| *symbol == name && self.lookup(name).is_none())
+    }
+
+    /// The name of the special form `form` is, if it is one.
+    fn special_form(&self, form: &Value) -> Option<Rc<String>> {
+        let name = match form.car().ok().and_then(|head| symbol_name(&head)) {
+            Some(name) => name,
+            None => return None,
+        };
+        if SPECIAL_FORMS.contains(&&name[..]) && self.lookup(&name).is_none() {
+            Some(name)
+        } else {
+            None
+        }
+    }
+
+    /// Parses a top-level form, where definitions are global.
+    fn toplevel(&mut self, form: &Value) -> Result<Expr, String> {
+        match self.special_form(form) {
+            Some(ref name) if **name == "define" => {
+                let elements = try!(self.elements(form, form));
+                let (name, definition) = try!(self.definition(form, &elements));
+                let value = try!(self.define_value(form, &name, definition));
+                Ok(Expr::Define(self.value(&name), Box::new(value)))
+            }
+            Some(ref name) if **name == "begin" => {
+                let elements = try!(self.elements(form, form));
+                let mut exprs = vec![];
+                for element in &elements[1..] {
+                    exprs.push(try!(self.toplevel(element)))
+                }
+                if exprs.is_empty() {
+                    exprs.push(Expr::Immediate(Opcode::LoadUnspecified))
+                }
+                Ok(sequence(exprs))
+            }
+            _ => self.expression(form),
+        }
+    }
+
+    fn expression(&mut self, form: &Value) -> Result<Expr, String> {
+        match form.kind() {
+            Kind::Symbol(_) => Ok(self.variable(form)),
+            Kind::Pair(_) => self.compound(form),
+            Kind::Constant(value::NIL) => self.error(form, "empty procedure call ()"),
+            _ => Ok(self.constant(form)),
+        }
+    }
+
+    fn variable(&mut self, symbol: &Value) -> Expr {
+        match self.lookup(&symbol_name(symbol).unwrap()) {
+            Some(binding) => return Expr::Local(binding.level, binding.slot),
+            None => {}
+        }
+        Expr::Global(self.value(symbol))
+    }
+
+    /// The quoted or self-evaluating `datum`.
+    fn constant(&mut self, datum: &Value) -> Expr {
+        match datum.kind() {
+            Kind::Constant(value::TRUE) => Expr::Immediate(Opcode::LoadTrue),
+            Kind::Constant(value::FALSE) => Expr::Immediate(Opcode::LoadFalse),
+            Kind::Constant(value::NIL) => Expr::Immediate(Opcode::LoadNil),
+            _ => {
+                datum.make_constant();
+                Expr::Constant(self.value(datum))
+            }
+        }
+    }
+
+    fn compound(&mut self, form: &Value) -> Result<Expr, String> {
+        let elements = try!(self.elements(form, form));
+        if let Some(name) = self.special_form(form) {
+            return self.special(&name, form, &elements[1..])
+        }
+        let operator = try!(self.expression(&elements[0]));
+        let mut operands = vec![];
+        for element in &elements[1..] {
+            operands.push(try!(self.expression(element)))
+        }
+        Ok(Expr::Apply(Box::new(operator), operands, self.heap.location(form)))
+    }
+
+    /// Parses the special form `name`, whose operands are `args`.
+    fn special(&mut self, name: &str, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        match name {
+            "quote" if args.len() == 1 => Ok(self.constant(&args[0])),
+            "if" if args.len() == 2 || args.len() == 3 => {
+                let test = try!(self.expression(&args[0]));
+                let consequent = try!(self.expression(&args[1]));
+                let alternative = match args.get(2) {
+                    Some(alternative) => try!(self.expression(alternative)),
+                    None => Expr::Immediate(Opcode::LoadUnspecified),
+                };
+                Ok(Expr::If(Box::new(test), Box::new(consequent), Box::new(alternative)))
+            }
+            "set!" if args.len() == 2 => {
+                let name = match symbol_name(&args[0]) {
+                    Some(name) => name,
+                    None => return self.error(form, "set! of a non-variable"),
+                };
+                let value = Box::new(try!(self.expression(&args[1])));
+                match self.lookup(&name) {
+                    Some(binding) => return Ok(Expr::SetLocal(binding.level, binding.slot, value)),
+                    None => {}
+                }
+                Ok(Expr::SetGlobal(self.value(&args[0]), value))
+            }
+            "lambda" if args.len() >= 2 => {
+                let lambda = try!(self.lambda(form, &args[0], &args[1..], None));
+                Ok(Expr::Lambda(Box::new(lambda)))
+            }
+            "case-lambda" => self.case_lambda(form, args, None),
+            "begin" if !args.is_empty() => {
+                let mut exprs = vec![];
+                for arg in args {
+                    exprs.push(try!(self.expression(arg)))
+                }
+                Ok(sequence(exprs))
+            }
+            "let" if args.len() >= 2 && symbol_name(&args[0]).is_some() => {
+                self.named_let(form, args)
+            }
+            "let" if args.len() >= 2 => self.let_form(form, args),
+            "letrec" | "letrec*" if args.len() >= 2 => self.letrec(form, args),
+            "guard" if args.len() >= 2 => self.guard(form, args),
+            "parameterize" if args.len() >= 2 => self.parameterize(form, args),
+            "define" => self.error(form, "definition in expression context"),
+            _ => self.error(form, &format!("bad syntax in {}", name)),
+        }
+    }
+
+    /// Like `expression`, but if `form` is a `lambda` expression, the
+    /// procedure is called `name`.
+    fn named_expression(&mut self, form: &Value, name: &Value) -> Result<Expr, String> {
+        match self.special_form(form) {
+            Some(ref special) if **special == "lambda" => {
+                let elements = try!(self.elements(form, form));
+                if elements.len() >= 3 {
+                    let lambda = try!(self.lambda(form, &elements[1], &elements[2..], Some(name)));
+                    return Ok(Expr::Lambda(Box::new(lambda)))
+                }
+            }
+            Some(ref special) if **special == "case-lambda" => {
+                let elements = try!(self.elements(form, form));
+                return self.case_lambda(form, &elements[1..], Some(name))
+            }
+            _ => {}
+        }
+        self.expression(form)
+    }
+
+    /// Parses `(case-lambda (formals body ...) ...)`, with operands `args`,
+    /// which is the value of the variable `name`, if any.
+    fn case_lambda(&mut self,
+                   form: &Value,
+                   args: &[Value],
+                   name: Option<&Value>)
+                   -> Result<Expr, String> {
+        if args.len() > 255 {
+            return self.error(form, "too many clauses in case-lambda")
+        }
+        let mut clauses = vec![];
+        for clause in args {
+            let elements = try!(self.elements(clause, form));
+            if elements.len() < 2 {
+                return self.error(form, "bad clause in case-lambda")
+            }
+            clauses.push(try!(self.lambda(clause, &elements[0], &elements[1..], name)))
+        }
+        let name = name.map(|name| self.value(name));
+        Ok(Expr::CaseLambda(clauses, name, self.heap.location(form)))
+    }
+
+    /// Splits the definition `form` into the variable defined and how its
+    /// value is computed.
+    fn definition(&mut self,
+                  form: &Value,
+                  elements: &[Value])
+                  -> Result<(Value, Definition), String> {
+        match elements.get(1).map(Value::kind) {
+            Some(Kind::Symbol(_)) if elements.len() == 3 => {
+                Ok((elements[1].clone(), Definition::Value(elements[2].clone())))
+            }
+            Some(Kind::Pair(_)) if elements.len() >= 3 => {
+                let name = elements[1].car().unwrap();
+                if symbol_name(&name).is_none() {
+                    return self.error(form, "bad syntax in define")
+                }
+                let formals = elements[1].cdr().unwrap();
+                Ok((name, Definition::Procedure(formals, elements[2..].to_vec())))
+            }
+            _ => self.error(form, "bad syntax in define"),
+        }
+    }
+
+    /// Parses the value of the variable `name`, defined by `form`.
+    fn define_value(&mut self,
+                    form: &Value,
+                    name: &Value,
+                    definition: Definition)
+                    -> Result<Expr, String> {
+        match definition {
+            Definition::Value(value) => self.named_expression(&value, name),
+            Definition::Procedure(formals, body) => {
+                let lambda = try!(self.lambda(form, &formals, &body, Some(name)));
+                Ok(Expr::Lambda(Box::new(lambda)))
+            }
+        }
+    }
+
+    fn lambda(&mut self,
+              form: &Value,
+              formals: &Value,
+              body: &[Value],
+              name: Option<&Value>)
+              -> Result<Lambda, String> {
+        let parameters = try!(self.parameters(form, formals));
+        self.procedure(form, &parameters, body, name)
+    }
+
+    /// Parses the parameter list `formals` of `form`:
+    ///
+    /// ```scheme
+    /// (required ... [#!optional optional ...] [#!rest rest] [#!key key ...])
+    /// ```
+    ///
+    /// where an optional or keyword parameter is a variable, or `(variable
+    /// default)`, and a dotted tail is a rest parameter too.  A default is
+    /// computed, if the argument is not passed, where the parameters before
+    /// it are bound, and is `#f` if it is left out.  Keyword arguments
+    /// follow the optional ones, each as the symbol naming the parameter
+    /// and then its value.
+    fn parameters(&mut self, form: &Value, formals: &Value) -> Result<Parameters, String> {
+        let mut parameters = Parameters::default();
+        // The section of the list: required, optional, rest or keyword
+        // parameters.
+        let mut section = 0;
+        let mut count = 0;
+        let mut list = formals.clone();
+        while let Kind::Pair(_) = list.kind() {
+            let element = list.car().unwrap();
+            list = list.cdr().unwrap();
+            let name = symbol_name(&element);
+            let marker = name.as_ref().and_then(|name| {
+                read::PARAMETER_MARKERS.iter().position(|marker| **name == *marker)
+            });
+            if let Some(marker) = marker {
+                if marker < section || (section == 2 && parameters.rest.is_none()) {
+                    return self.error(form, "bad parameter list")
+                }
+                section = marker + 1;
+                continue
+            }
+            count += 1;
+            if count > 255 {
+                return self.error(form, "too many parameters")
+            }
+            if section == 0 || section == 2 {
+                if name.is_none() {
+                    return self.error(form, "parameter is not a symbol")
+                }
+                if section == 0 {
+                    parameters.required.push(element)
+                } else if parameters.rest.is_none() {
+                    parameters.rest = Some(element)
+                } else {
+                    return self.error(form, "bad parameter list")
+                }
+                continue
+            }
+            let (variable, default) = match element.kind() {
+                Kind::Pair(_) => {
+                    let elements = try!(self.elements(&element, form));
+                    if elements.len() != 2 {
+                        return self.error(form, "bad parameter list")
+                    }
+                    (elements[0].clone(), Some(elements[1].clone()))
+                }
+                _ => (element, None),
+            };
+            if symbol_name(&variable).is_none() {
+                return self.error(form, "parameter is not a symbol")
+            }
+            if section == 1 {
+                parameters.optional.push((variable, default))
+            } else if parameters.keys.iter().any(|&(ref key, _)| key.get() == variable.get()) {
+                return self.error(form, "duplicate keyword parameter")
+            } else {
+                parameters.keys.push((variable, default))
+            }
+        }
+        match list.kind() {
+            Kind::Constant(value::NIL) => {}
+            Kind::Symbol(_) if parameters.rest.is_none() && parameters.keys.is_empty() => {
+                parameters.rest = Some(list)
+            }
+            _ => return self.error(form, "bad parameter list"),
+        }
+        if section == 2 && parameters.rest.is_none() {
+            return self.error(form, "bad parameter list")
+        }
+        Ok(parameters)
+    }
+
+    /// Parses a procedure with parameters `parameters`.
+    fn procedure(&mut self,
+                 form: &Value,
+                 parameters: &Parameters,
+                 body: &[Value],
+                 name: Option<&Value>)
+                 -> Result<Lambda, String> {
+        let name = name.map(|name| self.value(name));
+        let location = self.heap.location(form);
+        let outer = self.bindings.len();
+        self.slots.push(0);
+        let body = self.parameters_and_body(form, parameters, body);
+        self.bindings.truncate(outer);
+        let slots = self.slots.pop().unwrap();
+        let keys = parameters.keys.iter().map(|&(ref key, _)| self.value(key)).collect();
+        Ok(Lambda {
+            name: name,
+            required: parameters.required.len(),
+            optional: parameters.optional.len(),
+            rest: parameters.rest.is_some(),
+            keys: keys,
+            slots: slots,
+            body: try!(body),
+            location: location,
+        })
+    }
+
+    /// Binds `parameters`, and parses `body` in their scope, after the
+    /// initialization of the optional and keyword parameters that are not
+    /// passed.
+    fn parameters_and_body(&mut self,
+                           form: &Value,
+                           parameters: &Parameters,
+                           body: &[Value])
+                           -> Result<Expr, String> {
+        for param in &parameters.required {
+            self.bind(param);
+        }
+        let mut exprs = vec![];
+        for &(ref param, ref default) in &parameters.optional {
+            exprs.push(try!(self.default(param, default.as_ref())))
+        }
+        if let Some(ref rest) = parameters.rest {
+            self.bind(rest);
+        }
+        for &(ref param, ref default) in &parameters.keys {
+            exprs.push(try!(self.default(param, default.as_ref())))
+        }
+        exprs.push(try!(self.body(form, body)));
+        Ok(sequence(exprs))
+    }
+
+    /// Binds the optional or keyword parameter `param`, returning its
+    /// initialization to `default`, which is parsed before it is bound.
+    fn default(&mut self, param: &Value, default: Option<&Value>) -> Result<Expr, String> {
+        let default = match default {
+            Some(default) => try!(self.expression(default)),
+            None => Expr::Immediate(Opcode::LoadFalse),
+        };
+        let (level, slot) = self.bind(param);
+        Ok(Expr::Default(level, slot, Box::new(default)))
+    }
+
+    /// Parses a body: definitions, then at least one expression.  The
+    /// definitions behave like `letrec*`.
+    fn body(&mut self, form: &Value, body: &[Value]) -> Result<Expr, String> {
+        let mut pending: Vec<Value> = body.iter().rev().cloned().collect();
+        let mut definitions = vec![];
+        while let Some(next) = pending.pop() {
+            match self.special_form(&next) {
+                Some(ref name) if **name == "define" => {
+                    let elements = try!(self.elements(&next, &next));
+                    let (name, definition) = try!(self.definition(&next, &elements));
+                    definitions.push((next, name, definition))
+                }
+                Some(ref name) if **name == "begin" => {
+                    let elements = try!(self.elements(&next, &next));
+                    pending.extend(elements[1..].iter().rev().cloned())
+                }
+                _ => {
+                    pending.push(next);
+                    break
+                }
+            }
+        }
+        if pending.is_empty() {
+            return self.error(form, "no expression in body")
+        }
+        let outer = self.bindings.len();
+        let slots: Vec<_> = definitions.iter().map(|&(_, ref name, _)| self.bind(name)).collect();
+        let mut exprs = vec![];
+        for ((definition_form, name, definition), (level, slot)) in
+            definitions.into_iter().zip(slots) {
+            let value = try!(self.define_value(&definition_form, &name, definition));
+            exprs.push(Expr::InitLocal(level, slot, Box::new(value)))
+        }
+        while let Some(next) = pending.pop() {
+            exprs.push(try!(self.expression(&next)))
+        }
+        self.bindings.truncate(outer);
+        Ok(sequence(exprs))
+    }
+
+    /// The variables and initializers of the bindings `list` of a `let` or
+    /// `letrec` form.
+    fn let_bindings(&mut self, form: &Value, list: &Value) -> Result<Vec<(Value, Value)>, String> {
+        let mut bindings = vec![];
+        for binding in try!(self.elements(list, form)) {
+            let elements = try!(self.elements(&binding, form));
+            if elements.len() != 2 || symbol_name(&elements[0]).is_none() {
+                return self.error(form, "bad binding")
+            }
+            bindings.push((elements[0].clone(), elements[1].clone()))
+        }
+        Ok(bindings)
+    }
+
+    fn let_form(&mut self, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        let bindings = try!(self.let_bindings(form, &args[0]));
+        let mut inits = vec![];
+        for &(ref name, ref init) in &bindings {
+            inits.push(try!(self.named_expression(init, name)))
+        }
+        let outer = self.bindings.len();
+        let mut exprs = vec![];
+        for (&(ref name, _), init) in bindings.iter().zip(inits) {
+            let (level, slot) = self.bind(name);
+            exprs.push(Expr::InitLocal(level, slot, Box::new(init)))
+        }
+        let body = self.body(form, &args[1..]);
+        self.bindings.truncate(outer);
+        exprs.push(try!(body));
+        Ok(sequence(exprs))
+    }
+
+    /// `(let name bindings body ...)`, which is
+    /// `((letrec ((name (lambda variables body ...))) name) inits ...)`.
+    fn named_let(&mut self, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        if args.len() < 3 {
+            return self.error(form, "bad syntax in let")
+        }
+        let bindings = try!(self.let_bindings(form, &args[1]));
+        let mut inits = vec![];
+        for &(_, ref init) in &bindings {
+            inits.push(try!(self.expression(init)))
+        }
+        let outer = self.bindings.len();
+        let (level, slot) = self.bind(&args[0]);
+        let parameters = Parameters {
+            required: bindings.into_iter().map(|(variable, _)| variable).collect(),
+            ..Parameters::default()
+        };
+        let procedure = self.procedure(form, &parameters, &args[2..], Some(&args[0]));
+        self.bindings.truncate(outer);
+        let procedure = Expr::Lambda(Box::new(try!(procedure)));
+        Ok(Expr::Sequence(vec![Expr::InitLocal(level, slot, Box::new(procedure)),
+                               Expr::Apply(Box::new(Expr::Local(level, slot)),
+                                           inits,
+                                           self.heap.location(form))]))
+    }
+
+    /// `(guard (variable clause ...) body ...)`, which is
+    ///
+    /// ```scheme
+    /// ((call/cc
+    ///    (lambda (k)
+    ///      (with-exception-handler
+    ///        (lambda (variable)
+    ///          (k (lambda ()
+    ///               (cond clause ... (else (raise-continuable variable))))))
+    ///        (lambda ()
+    ///          (let ((result (let () body ...)))
+    ///            (lambda () result)))))))
+    /// ```
+    ///
+    /// where `k` and `result` are invisible to the clauses and the body.  A
+    /// condition that no clause handles is raised again in the dynamic
+    /// environment of the `guard` form, rather than that of the original
+    /// `raise`.
+    fn guard(&mut self, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        let spec = try!(self.elements(&args[0], form));
+        if spec.is_empty() || symbol_name(&spec[0]).is_none() {
+            return self.error(form, "bad syntax in guard")
+        }
+        let location = self.heap.location(form);
+        let outer = self.bindings.len();
+        let lambda = |slots, required, body| {
+            Expr::Lambda(Box::new(Lambda {
+                name: None,
+                required: required,
+                optional: 0,
+                rest: false,
+                keys: vec![],
+                slots: slots,
+                body: body,
+                location: location.clone(),
+            }))
+        };
+
+        self.slots.push(0);
+        let k = self.bind_name(Rc::new("guard k".to_owned()));
+
+        self.slots.push(0);
+        let variable = self.bind(&spec[0]);
+        self.slots.push(0);
+        let reraise = Expr::Apply(Box::new(self.primitive("raise-continuable")),
+                                  vec![Expr::Local(variable.0, variable.1)],
+                                  location.clone());
+        let clauses = self.clauses(form, &spec[1..], reraise);
+        let clauses = lambda(self.slots.pop().unwrap(), 0, try!(clauses));
+        let handler = lambda(self.slots.pop().unwrap(),
+                             1,
+                             Expr::Apply(Box::new(Expr::Local(k.0, k.1)),
+                                         vec![clauses],
+                                         location.clone()));
+        self.bindings.truncate(outer + 1);
+
+        self.slots.push(0);
+        let result = self.bind_name(Rc::new("guard result".to_owned()));
+        let body = self.body(form, &args[1..]);
+        self.slots.push(0);
+        let result_thunk = lambda(self.slots.pop().unwrap(), 0, Expr::Local(result.0, result.1));
+        let body = Expr::InitLocal(result.0, result.1, Box::new(try!(body)));
+        let thunk = lambda(self.slots.pop().unwrap(), 0, Expr::Sequence(vec![body, result_thunk]));
+        self.bindings.truncate(outer);
+
+        let receiver = lambda(self.slots.pop().unwrap(),
+                              1,
+                              Expr::Apply(Box::new(self.primitive("with-exception-handler")),
+                                          vec![handler, thunk],
+                                          location.clone()));
+        let call_cc = Expr::Apply(Box::new(self.primitive("call/cc")),
+                                  vec![receiver],
+                                  location.clone());
+        Ok(Expr::Apply(Box::new(call_cc), vec![], location))
+    }
+
+    /// `(parameterize ((parameter value) ...) body ...)`, which is
+    ///
+    /// ```scheme
+    /// (let ((p parameter) (v value) ...)
+    ///   (set! v (%parameter-convert p v)) ...
+    ///   (%parameterize p v (lambda () ... (let () body ...))))
+    /// ```
+    ///
+    /// with one nested call to `%parameterize` for each parameter, where the
+    /// `p` and `v` variables are invisible to the body.
+    fn parameterize(&mut self, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        let mut inits = vec![];
+        for binding in try!(self.elements(&args[0], form)) {
+            let elements = try!(self.elements(&binding, form));
+            if elements.len() != 2 {
+                return self.error(form, "bad syntax in parameterize")
+            }
+            inits.push(try!(self.expression(&elements[0])));
+            inits.push(try!(self.expression(&elements[1])))
+        }
+        let location = self.heap.location(form);
+        let outer = self.bindings.len();
+        let mut exprs = vec![];
+        let mut variables = vec![];
+        for (i, init) in inits.into_iter().enumerate() {
+            let name = if i % 2 == 0 { "parameterize parameter" } else { "parameterize value" };
+            let (level, slot) = self.bind_name(Rc::new(name.to_owned()));
+            exprs.push(Expr::InitLocal(level, slot, Box::new(init)));
+            variables.push((level, slot))
+        }
+        for pair in variables.chunks(2) {
+            let (parameter, value) = (pair[0], pair[1]);
+            let convert = Expr::Apply(Box::new(self.primitive("%parameter-convert")),
+                                      vec![Expr::Local(parameter.0, parameter.1),
+                                           Expr::Local(value.0, value.1)],
+                                      location.clone());
+            exprs.push(Expr::InitLocal(value.0, value.1, Box::new(convert)))
+        }
+        for _ in 0..variables.len() / 2 {
+            self.slots.push(0)
+        }
+        let mut result = self.body(form, &args[1..]);
+        self.bindings.truncate(outer);
+        for pair in variables.chunks(2).rev() {
+            let (parameter, value) = (pair[0], pair[1]);
+            let thunk = Expr::Lambda(Box::new(Lambda {
+                name: None,
+                required: 0,
+                optional: 0,
+                rest: false,
+                keys: vec![],
+                slots: self.slots.pop().unwrap(),
+                body: try!(result),
+                location: location.clone(),
+            }));
+            result = Ok(Expr::Apply(Box::new(self.primitive("%parameterize")),
+                                    vec![Expr::Local(parameter.0, parameter.1),
+                                         Expr::Local(value.0, value.1),
+                                         thunk],
+                                    location.clone()))
+        }
+        exprs.push(try!(result));
+        Ok(sequence(exprs))
+    }
+
+    /// The `cond` clauses `clauses` of `form`, followed by `otherwise` if
+    /// none of them applies.
+    fn clauses(&mut self,
+               form: &Value,
+               clauses: &[Value],
+               otherwise: Expr)
+               -> Result<Expr, String> {
+        let mut result = otherwise;
+        for (i, clause) in clauses.iter().enumerate().rev() {
+            let elements = try!(self.elements(clause, form));
+            if elements.is_empty() {
+                return self.error(form, "empty clause")
+            }
+            if self.is_keyword(&elements[0], "else") {
+                if i + 1 != clauses.len() || elements.len() < 2 {
+                    return self.error(form, "bad else clause")
+                }
+                let mut exprs = vec![];
+                for element in &elements[1..] {
+                    exprs.push(try!(self.expression(element)))
+                }
+                result = sequence(exprs);
+                continue
+            }
+            let test = try!(self.expression(&elements[0]));
+            if elements.len() > 1 && !self.is_keyword(&elements[1], "=>") {
+                let mut exprs = vec![];
+                for element in &elements[1..] {
+                    exprs.push(try!(self.expression(element)))
+                }
+                result = Expr::If(Box::new(test), Box::new(sequence(exprs)), Box::new(result));
+                continue
+            }
+            // `(test)` and `(test => receiver)` need the value of the test.
+            let (level, slot) = self.bind_name(Rc::new("cond test".to_owned()));
+            self.bindings.pop();
+            let consequent = match elements.len() {
+                1 => Expr::Local(level, slot),
+                3 => {
+                    let receiver = try!(self.expression(&elements[2]));
+                    Expr::Apply(Box::new(receiver),
+                                vec![Expr::Local(level, slot)],
+                                self.heap.location(clause))
+                }
+                _ => return self.error(form, "bad => clause"),
+            };
+            result = Expr::Sequence(vec![Expr::InitLocal(level, slot, Box::new(test)),
+                                         Expr::If(Box::new(Expr::Local(level, slot)),
+                                                  Box::new(consequent),
+                                                  Box::new(result))]);
+        }
+        Ok(result)
+    }
+
+    fn letrec(&mut self, form: &Value, args: &[Value]) -> Result<Expr, String> {
+        let bindings = try!(self.let_bindings(form, &args[0]));
+        let outer = self.bindings.len();
+        let slots: Vec<_> = bindings.iter().map(|&(ref name, _)| self.bind(name)).collect();
+        let mut exprs = vec![];
+        for (&(ref name, ref init), (level, slot)) in bindings.iter().zip(slots) {
+            let init = try!(self.named_expression(init, name));
+            exprs.push(Expr::InitLocal(level, slot, Box::new(init)))
+        }
+        let body = self.body(form, &args[1..]);
+        self.bindings.truncate(outer);
+        exprs.push(try!(body));
+        Ok(sequence(exprs))
+    }
+}
+
+/// A constant of a `Function`.
+enum Constant {
+    /// `values[index]`
+    Value(usize),
+
+    /// The code of a nested procedure.
+    Function(Function),
+}
+
+/// A procedure, ready to be assembled.
+struct Function {
+    name: Option<usize>,
+    code: Vec<Bytecode>,
+    constants: Vec<Constant>,
+
+    /// The source map: the location of the instruction at each pc.
+    locations: Vec<(usize, Location)>,
+
+    /// The stack map: the number of live registers at each pc.
+    live: Vec<u8>,
+}
+
+/// A variable of an enclosing procedure that a closure captures: its
+/// level and slot, and whether it is boxed.
+#[derive(Clone, Copy)]
+struct Capture {
+    level: usize,
+    slot: usize,
+    boxed: bool,
+}
+
+struct Generator {
+    /// The nesting level of the procedure.
+    level: usize,
+
+    /// Whether each variable of the procedure is boxed.
+    boxed: Vec<bool>,
+
+    /// The variables captured by the procedure, in the order of its
+    /// captured values.
+    captured: Vec<Capture>,
+
+    /// The number of variables of the procedure, which are its first
+    /// registers.  The temporaries come after them.
+    slots: usize,
+
+    /// The first free temporary.
+    next: usize,
+
+    /// The number of registers used so far.
+    registers: usize,
+
+    function: Function,
+
+    /// The indices in the constants vector of the values used so far.
+    indices: HashMap<usize, u16>,
+
+    /// The location of the innermost form being compiled, if known.
+    location: Option<Location>,
+}
+
+impl Expr {
+    /// Calls `f` on each subexpression of `self` in the order they are
+    /// evaluated, counting the body of a `lambda` as a subexpression.
+    fn each_child(&self, f: &mut dyn FnMut(&Expr)) {
+        match *self {
+            Expr::SetLocal(_, _, ref value) |
+            Expr::InitLocal(_, _, ref value) |
+            Expr::Default(_, _, ref value) |
+            Expr::SetGlobal(_, ref value) |
+            Expr::Define(_, ref value) => f(value),
+            Expr::If(ref test, ref consequent, ref alternative) => {
+                f(test);
+                f(consequent);
+                f(alternative)
+            }
+            Expr::Lambda(ref lambda) => f(&lambda.body),
+            Expr::CaseLambda(ref clauses, _, _) => {
+                for clause in clauses {
+                    f(&clause.body)
+                }
+            }
+            Expr::Sequence(ref exprs) => {
+                for expr in exprs {
+                    f(expr)
+                }
+            }
+            Expr::Apply(ref operator, ref operands, _) => {
+                f(operator);
+                for operand in operands {
+                    f(operand)
+                }
+            }
+            Expr::Constant(_) | Expr::Immediate(_) | Expr::Local(..) | Expr::Global(_) => {}
+        }
+    }
+}
+
+/// Adds the variables of procedures at levels below `level` that `expr`
+/// refers to to `free`, in order of appearance.
+fn free_variables(expr: &Expr, level: usize, free: &mut Vec<(usize, usize)>) {
+    match *expr {
+        Expr::Local(outer, slot) |
+        Expr::SetLocal(outer, slot, _) |
+        Expr::InitLocal(outer, slot, _) |
+        Expr::Default(outer, slot, _) if outer < level && !free.contains(&(outer, slot)) => {
+            free.push((outer, slot))
+        }
+        _ => {}
+    }
+    expr.each_child(&mut |child| free_variables(child, level, free))
+}
+
+/// Which of the `slots` variables of `lambda`, which is at `level`, must be
+/// boxed.
+///
+/// A closure gets a copy of each variable it refers to, so a variable that
+/// is stored to after a closure capturing it is made must be boxed, for the
+/// closure to see the change.  This covers the procedures defined by
+/// `letrec` and internal definitions that refer to themselves or to those
+/// defined after them.  Variables assigned by `set!` are always boxed,
+/// even when no closure captures them: a continuation copies the stack, so
+/// a change to a variable on the stack would be undone by invoking a
+/// continuation captured before it.
+fn boxed_variables(lambda: &Lambda, level: usize) -> Vec<bool> {
+    fn scan(expr: &Expr, level: usize, captured: &mut [bool], boxed: &mut [bool]) {
+        if let Expr::Lambda(_) | Expr::CaseLambda(..) = *expr {
+            return expr.each_child(&mut |body| capture(body, level, captured, boxed))
+        }
+        expr.each_child(&mut |child| scan(child, level, captured, boxed));
+        match *expr {
+            Expr::SetLocal(outer, slot, _) if outer == level => boxed[slot] = true,
+            Expr::InitLocal(outer, slot, _) |
+            Expr::Default(outer, slot, _) if outer == level && captured[slot] => {
+                boxed[slot] = true
+            }
+            _ => {}
+        }
+    }
+
+    /// Marks the variables that `expr`, in a nested procedure, refers to.
+    fn capture(expr: &Expr, level: usize, captured: &mut [bool], boxed: &mut [bool]) {
+        match *expr {
+            Expr::Local(outer, slot) |
+            Expr::InitLocal(outer, slot, _) |
+            Expr::Default(outer, slot, _) if outer == level => captured[slot] = true,
+            Expr::SetLocal(outer, slot, _) if outer == level => {
+                captured[slot] = true;
+                boxed[slot] = true
+            }
+            _ => {}
+        }
+        expr.each_child(&mut |child| capture(child, level, captured, boxed))
+    }
+
+    let mut captured = vec![false; lambda.slots];
+    let mut boxed = vec![false; lambda.slots];
+    scan(&lambda.body, level, &mut captured, &mut boxed);
+    boxed
+}
+
+/// Generates the code of `lambda`, which is at nesting level `level` and
+/// captures the variables `captured`.
+fn generate(lambda: &Lambda, level: usize, captured: Vec<Capture>) -> Result<Function, String> {
+    if lambda.slots > 255 {
+        return Err("too many local variables".to_owned())
+    }
+    let mut generator = Generator {
+        level: level,
+        boxed: boxed_variables(lambda, level),
+        captured: captured,
+        slots: lambda.slots,
+        next: lambda.slots,
+        registers: lambda.slots,
+        function: Function {
+            name: lambda.name,
+            code: vec![],
+            constants: vec![],
+            locations: vec![],
+            live: vec![],
+        },
+        indices: HashMap::new(),
+        location: lambda.location.clone(),
+    };
+    generator.record_location();
+    // Patched below, once the number of registers is known.
+    generator.emit_simple(Opcode::Enter);
+    let optional = lambda.optional > 0 || !lambda.keys.is_empty();
+    if optional {
+        generator.emit(Bytecode {
+            opcode: Opcode::Optional,
+            src: lambda.optional as u8,
+            src2: lambda.rest as u8,
+            dst: lambda.keys.len() as u8,
+        });
+        // The names of the keyword parameters come first.
+        for &key in &lambda.keys {
+            try!(generator.value(key));
+        }
+    }
+    for slot in 0..lambda.slots {
+        if generator.boxed[slot] {
+            generator.emit(Bytecode::wide(Opcode::Box, slot as u8, 0));
+        }
+    }
+    // The arguments are not in their registers yet.
+    for live in &mut generator.function.live {
+        *live = ALL_LIVE
+    }
+    try!(generator.tail(&lambda.body));
+    let mut function = generator.function;
+    if function.code.len() > 0xffff {
+        return Err("procedure too long".to_owned())
+    }
+    function.code[0] = Bytecode {
+        opcode: if optional {
+            Opcode::EnterOptional
+        } else if lambda.rest {
+            Opcode::EnterRest
+        } else {
+            Opcode::Enter
+        },
+        src: lambda.required as u8,
+        src2: generator.captured.len() as u8,
+        dst: generator.registers as u8,
     };
-    s.drop();
-    retval
-}
-
-pub fn compile_form(vec: &mut Vec<Opcode>, s: &mut api::State)
-                    -> Result<usize, &'static str> {
-    match s.typeof() {
-        Typeof::Pair => {
-            try!(s.dup());
-            try!(s.car());
-            try!(s.cdr());
-            match 
+    Ok(function)
+}
+
+impl Generator {
+    fn emit(&mut self, instruction: Bytecode) -> usize {
+        self.function.code.push(instruction);
+        self.function.live.push(self.next as u8);
+        self.function.code.len() - 1
+    }
+
+    /// Emits an instruction without operands.
+    fn emit_simple(&mut self, opcode: Opcode) -> usize {
+        self.emit(Bytecode::wide(opcode, 0, 0))
+    }
+
+    /// Emits an instruction with the registers `src` and `src2`.
+    fn emit_pair(&mut self, opcode: Opcode, src: u8, src2: u8) -> usize {
+        self.emit(Bytecode {
+            opcode: opcode,
+            src: src,
+            src2: src2,
+            dst: 0,
+        })
+    }
+
+    /// Makes the jump at `pc` go to the next instruction.
+    fn patch(&mut self, pc: usize) {
+        let target = self.function.code.len();
+        let jump = self.function.code[pc];
+        self.function.code[pc] = match jump.opcode {
+            Opcode::JumpIfFalse | Opcode::JumpIfSupplied => {
+                Bytecode::wide(jump.opcode, jump.src, target as u16)
+            }
+            opcode => Bytecode::long(opcode, target),
+        }
+    }
+
+    /// Records that the next instruction comes from the current location.
+    fn record_location(&mut self) {
+        if let Some(ref location) = self.location {
+            self.function.locations.push((self.function.code.len(), location.clone()))
+        }
+    }
+
+    /// The index of `constant` in the constants vector.
+    fn constant(&mut self, constant: Constant) -> Result<u16, String> {
+        let index = self.function.constants.len() + 2;
+        if index > 0xffff {
+            return Err("too many constants".to_owned())
+        }
+        self.function.constants.push(constant);
+        Ok(index as u16)
+    }
+
+    /// The index of `values[index]` in the constants vector.
+    fn value(&mut self, index: usize) -> Result<u16, String> {
+        if let Some(&constant) = self.indices.get(&index) {
+            return Ok(constant)
+        }
+        let constant = try!(self.constant(Constant::Value(index)));
+        self.indices.insert(index, constant);
+        Ok(constant)
+    }
+
+    /// Allocates a temporary.  It stays allocated until `next` is reset.
+    fn temporary(&mut self) -> Result<u8, String> {
+        let register = self.next;
+        if register >= 255 {
+            return Err("procedure needs too many registers".to_owned())
+        }
+        self.next += 1;
+        if self.next > self.registers {
+            self.registers = self.next
+        }
+        Ok(register as u8)
+    }
+
+    /// The register to call a procedure or make a closure in, whose value
+    /// is wanted in `dst`: `dst` itself if it is the last temporary in use,
+    /// since the registers above the callee are lost in a call, and a new
+    /// temporary otherwise.
+    fn base(&mut self, dst: u8) -> Result<u8, String> {
+        if dst as usize >= self.slots && dst as usize + 1 == self.next {
+            Ok(dst)
+        } else {
+            self.temporary()
+        }
+    }
+
+    /// Whether slot `slot` of the procedure at `level` is a register of
+    /// this procedure that holds the value of the variable itself.
+    fn is_register(&self, level: usize, slot: usize) -> bool {
+        level == self.level && !self.boxed[slot]
+    }
+
+    /// Loads slot `slot` of the procedure at `level` into `dst`, or its
+    /// box, returning whether it is boxed.
+    fn variable(&mut self, level: usize, slot: usize, dst: u8) -> bool {
+        if level == self.level {
+            self.emit_pair(Opcode::Move, dst, slot as u8);
+            return self.boxed[slot]
+        }
+        let index = self.captured
+                        .iter()
+                        .position(|capture| capture.level == level && capture.slot == slot)
+                        .unwrap();
+        self.emit(Bytecode::wide(Opcode::LoadCaptured, dst, index as u16));
+        self.captured[index].boxed
+    }
+
+    /// Generates code for `expr`, leaving its value in a register, which
+    /// is returned.  It is either a variable or a new temporary.
+    fn operand(&mut self, expr: &Expr) -> Result<u8, String> {
+        match *expr {
+            Expr::Local(level, slot) if self.is_register(level, slot) => Ok(slot as u8),
+            _ => {
+                let register = try!(self.temporary());
+                try!(self.expression(expr, register));
+                Ok(register)
+            }
+        }
+    }
+
+    /// Generates code for `expr`, discarding its value.
+    fn statement(&mut self, expr: &Expr) -> Result<(), String> {
+        let next = self.next;
+        match *expr {
+            Expr::SetLocal(level, slot, ref value) |
+            Expr::InitLocal(level, slot, ref value) => {
+                if self.is_register(level, slot) {
+                    try!(self.expression(value, slot as u8))
+                } else {
+                    let value = try!(self.operand(value));
+                    let register = if level == self.level {
+                        slot as u8
+                    } else {
+                        let register = try!(self.temporary());
+                        self.variable(level, slot, register);
+                        register
+                    };
+                    self.emit_pair(Opcode::SetBox, register, value);
+                }
+            }
+            Expr::Default(level, slot, ref default) => {
+                // Only the procedure's own parameters have defaults.
+                let register = if self.is_register(level, slot) {
+                    slot as u8
+                } else {
+                    let register = try!(self.temporary());
+                    self.emit_pair(Opcode::Unbox, register, slot as u8);
+                    register
+                };
+                let jump = self.emit(Bytecode::wide(Opcode::JumpIfSupplied, register, 0));
+                try!(self.expression(default, register));
+                if register != slot as u8 {
+                    self.emit_pair(Opcode::SetBox, slot as u8, register);
+                }
+                self.patch(jump)
+            }
+            Expr::SetGlobal(index, ref value) => {
+                let value = try!(self.operand(value));
+                let constant = try!(self.value(index));
+                self.emit(Bytecode::wide(Opcode::StoreGlobal, value, constant));
+            }
+            Expr::Define(index, ref value) => {
+                let value = try!(self.operand(value));
+                let constant = try!(self.value(index));
+                self.emit(Bytecode::wide(Opcode::DefineGlobal, value, constant));
+            }
+            Expr::If(ref test, ref consequent, ref alternative) => {
+                let test = try!(self.operand(test));
+                self.next = next;
+                let jump_if_false = self.emit(Bytecode::wide(Opcode::JumpIfFalse, test, 0));
+                try!(self.statement(consequent));
+                let jump = self.emit(Bytecode::long(Opcode::Jump, 0));
+                self.patch(jump_if_false);
+                try!(self.statement(alternative));
+                self.patch(jump)
+            }
+            Expr::Sequence(ref exprs) => {
+                for expr in exprs {
+                    try!(self.statement(expr))
+                }
+            }
+            // Nothing to do, since these cannot fail.
+            Expr::Constant(_) | Expr::Immediate(_) | Expr::Local(..) | Expr::Lambda(_) |
+            Expr::CaseLambda(..) => {}
+            _ => {
+                try!(self.operand(expr));
+            }
+        }
+        self.next = next;
+        Ok(())
+    }
+
+    /// Generates code for `expr`, leaving its value in register `dst`.
+    fn expression(&mut self, expr: &Expr, dst: u8) -> Result<(), String> {
+        let next = self.next;
+        match *expr {
+            Expr::If(ref test, ref consequent, ref alternative) => {
+                let test = try!(self.operand(test));
+                self.next = next;
+                let jump_if_false = self.emit(Bytecode::wide(Opcode::JumpIfFalse, test, 0));
+                try!(self.expression(consequent, dst));
+                let jump = self.emit(Bytecode::long(Opcode::Jump, 0));
+                self.patch(jump_if_false);
+                try!(self.expression(alternative, dst));
+                self.patch(jump)
+            }
+            Expr::Sequence(ref exprs) => {
+                let (last, init) = exprs.split_last().unwrap();
+                for expr in init {
+                    try!(self.statement(expr))
+                }
+                try!(self.expression(last, dst))
+            }
+            Expr::Apply(..) => {
+                let base = try!(self.base(dst));
+                try!(self.call(expr, base, Opcode::Call));
+                if base != dst {
+                    self.emit_pair(Opcode::Move, dst, base);
+                }
+            }
+            Expr::Constant(index) => {
+                let constant = try!(self.value(index));
+                self.emit(Bytecode::wide(Opcode::LoadConstant, dst, constant));
+            }
+            Expr::Immediate(opcode) => {
+                self.emit(Bytecode::wide(opcode, dst, 0));
+            }
+            Expr::Local(level, slot) => {
+                if level == self.level && self.boxed[slot] {
+                    self.emit_pair(Opcode::Unbox, dst, slot as u8);
+                } else if self.variable(level, slot, dst) {
+                    self.emit_pair(Opcode::Unbox, dst, dst);
+                }
+            }
+            Expr::Global(index) => {
+                let constant = try!(self.value(index));
+                self.record_location();
+                self.emit(Bytecode::wide(Opcode::LoadGlobal, dst, constant));
+            }
+            Expr::SetLocal(..) | Expr::InitLocal(..) | Expr::Default(..) | Expr::SetGlobal(..) |
+            Expr::Define(..) => {
+                try!(self.statement(expr));
+                self.emit(Bytecode::wide(Opcode::LoadUnspecified, dst, 0));
+            }
+            Expr::Lambda(ref lambda) => try!(self.closure(lambda, dst)),
+            Expr::CaseLambda(ref clauses, name, ref location) => {
+                // A closure over the procedures of the clauses.
+                let base = try!(self.base(dst));
+                for clause in clauses {
+                    let register = try!(self.temporary());
+                    try!(self.closure(clause, register))
+                }
+                let function = Function {
+                    name: name,
+                    code: vec![Bytecode {
+                                   opcode: Opcode::CaseLambda,
+                                   src: 0,
+                                   src2: clauses.len() as u8,
+                                   dst: 0,
+                               }],
+                    constants: vec![],
+                    locations: location.iter().map(|location| (0, location.clone())).collect(),
+                    live: vec![ALL_LIVE],
+                };
+                let constant = try!(self.constant(Constant::Function(function)));
+                self.emit(Bytecode::wide(Opcode::Closure, base, constant));
+                if base != dst {
+                    self.emit_pair(Opcode::Move, dst, base);
+                }
+            }
+        }
+        self.next = next;
+        Ok(())
+    }
+
+    /// Generates code that makes a closure running `lambda` in register
+    /// `dst`.
+    fn closure(&mut self, lambda: &Lambda, dst: u8) -> Result<(), String> {
+        let next = self.next;
+        let mut free = vec![];
+        free_variables(&lambda.body, self.level + 1, &mut free);
+        if free.len() > 255 {
+            return Err("too many captured variables".to_owned())
+        }
+        let base = try!(self.base(dst));
+        let mut captured = vec![];
+        for (level, slot) in free {
+            let register = try!(self.temporary());
+            let boxed = self.variable(level, slot, register);
+            captured.push(Capture {
+                level: level,
+                slot: slot,
+                boxed: boxed,
+            })
+        }
+        let function = try!(generate(lambda, self.level + 1, captured));
+        let constant = try!(self.constant(Constant::Function(function)));
+        self.emit(Bytecode::wide(Opcode::Closure, base, constant));
+        if base != dst {
+            self.emit_pair(Opcode::Move, dst, base);
+        }
+        self.next = next;
+        Ok(())
+    }
+
+    /// Generates code that calls the procedure `apply` with `opcode`, with
+    /// the callee in register `base`, which is the last one in use.
+    fn call(&mut self, apply: &Expr, base: u8, opcode: Opcode) -> Result<(), String> {
+        let (operator, operands, location) = match *apply {
+            Expr::Apply(ref operator, ref operands, ref location) => (operator, operands, location),
+            _ => bug!("call of something other than a procedure call"),
+        };
+        if operands.len() > 255 {
+            return Err("too many arguments".to_owned())
+        }
+        let outer = match *location {
+            Some(ref location) => mem::replace(&mut self.location, Some(location.clone())),
+            None => self.location.clone(),
+        };
+        try!(self.expression(operator, base));
+        for operand in operands {
+            let register = try!(self.temporary());
+            try!(self.expression(operand, register))
+        }
+        self.record_location();
+        self.emit_pair(opcode, base, operands.len() as u8);
+        self.location = outer;
+        Ok(())
+    }
+
+    /// Generates code that returns the value of `expr`.
+    fn tail(&mut self, expr: &Expr) -> Result<(), String> {
+        let next = self.next;
+        match *expr {
+            Expr::If(ref test, ref consequent, ref alternative) => {
+                let test = try!(self.operand(test));
+                self.next = next;
+                let jump_if_false = self.emit(Bytecode::wide(Opcode::JumpIfFalse, test, 0));
+                try!(self.tail(consequent));
+                self.patch(jump_if_false);
+                try!(self.tail(alternative))
+            }
+            Expr::Sequence(ref exprs) => {
+                let (last, init) = exprs.split_last().unwrap();
+                for expr in init {
+                    try!(self.statement(expr))
+                }
+                try!(self.tail(last))
+            }
+            Expr::Apply(..) => {
+                let base = try!(self.temporary());
+                try!(self.call(expr, base, Opcode::TailCall))
+            }
+            _ => {
+                let value = try!(self.operand(expr));
+                self.emit(Bytecode::wide(Opcode::Return, value, 0));
+            }
+        }
+        self.next = next;
+        Ok(())
+    }
+}
+
+/// Replaces `stack[start..]` with a vector of its elements.
+fn collapse(heap: &mut Heap, start: usize) -> Result<(), String> {
+    let end = heap.stack.len();
+    try!(heap.alloc_vector(start, end));
+    let vector = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    Ok(heap.stack.push(vector))
+}
+
+/// Allocates the BCO of `function`, and pushes it on the stack.  `values`
+/// is at `stack[base..]`.
+fn assemble(heap: &mut Heap, function: &Function, base: usize) -> Result<(), String> {
+    let start = heap.stack.len();
+    let name = match function.name {
+        Some(index) => heap.stack[base + index].clone(),
+        None => Value::new(value::FALSE),
+    };
+    heap.stack.push(name);
+    let map_start = heap.stack.len();
+    for &(pc, ref location) in &function.locations {
+        heap.stack.push(Value::new_fixnum(pc));
+        heap.intern(&location.file);
+        heap.stack.push(Value::new_fixnum(location.line));
+        heap.stack.push(Value::new_fixnum(location.column))
+    }
+    try!(heap.alloc_bytevector_from(&function.live));
+    try!(collapse(heap, map_start));
+    for constant in &function.constants {
+        match *constant {
+            Constant::Value(index) => {
+                let value = heap.stack[base + index].clone();
+                heap.stack.push(value)
+            }
+            Constant::Function(ref function) => try!(assemble(heap, function, base)),
+        }
+    }
+    try!(collapse(heap, start));
+    let mut code = Vec::with_capacity(4 * function.code.len());
+    for instruction in &function.code {
+        code.extend_from_slice(&instruction.to_bytes())
+    }
+    heap.alloc_bytecode(&code)
+}
+
+/// The identifiers that mean something at top level, sorted: primitives,
+/// special forms, global variables, and the macros and imports of the top
+/// level.  The internal names of primitives and of the variables of
+/// libraries are left out.
+pub fn bound_names(heap: &Heap) -> Vec<String> {
+    let globals = heap.symbol_table
+                      .globals()
+                      .into_iter()
+                      .filter(|name| !name.starts_with('%') && !name.starts_with('('))
+                      .map(|name| (*name).clone());
+    let base = library::base_names(&heap.macros.libraries).iter();
+    let mut names: Vec<_> = library::builtin_names()
+                                .into_iter()
+                                .chain(base.cloned())
+                                .map(str::to_owned)
+                                .chain(globals)
+                                .chain(heap.macros.names())
+                                .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Compiles the datum on top of the stack, replacing it with the BCO of a
+/// procedure of no arguments that evaluates it.  On error, the datum is
+/// left on the stack.
+pub fn compile(heap: &mut Heap) -> Result<(), String> {
+    compile_in(heap, None)
+}
+
+/// Like `compile`, but the datum is compiled in an environment that
+/// imports the list of import sets `imports`, if it is set (see
+/// `expand::expand`).
+pub fn compile_in(heap: &mut Heap, imports: Option<&Value>) -> Result<(), String> {
+    if heap.stack.is_empty() {
+        return Err("Attempt to compile from empty stack".to_owned())
+    }
+    try!(load_base(heap));
+    // A file being compiled records the effects itself (see `fasl`).
+    if heap.macros.effects.is_some() {
+        return compile_form(heap, imports)
+    }
+    let form = heap.stack.last().unwrap().clone();
+    let form = heap.root(form);
+    heap.macros.effects = Some(vec![]);
+    let result = compile_form(heap, imports);
+    let effects = heap.macros.effects.take().unwrap();
+    if result.is_ok() {
+        heap.macros.remember(form, &effects)
+    }
+    result
+}
+
+/// Compiles the datum on top of the stack, as `compile_in` does.
+fn compile_form(heap: &mut Heap, imports: Option<&Value>) -> Result<(), String> {
+    try!(expand::expand(heap, imports));
+    let datum = heap.stack.last().unwrap().clone();
+    let (lambda, values) = {
+        let mut parser = Parser {
+            heap: heap,
+            values: vec![],
+            indices: HashMap::new(),
+            bindings: vec![],
+            slots: vec![0],
+        };
+        let body = try!(parser.toplevel(&datum));
+        let lambda = Lambda {
+            name: None,
+            required: 0,
+            optional: 0,
+            rest: false,
+            keys: vec![],
+            slots: parser.slots[0],
+            body: body,
+            location: parser.heap.location(&datum),
+        };
+        (lambda, parser.values)
+    };
+    let mut function = try!(generate(&lambda, 0, vec![]));
+    if !heap.macros.unoptimized {
+        optimize::optimize(&mut function)
+    }
+    let base = heap.stack.len();
+    heap.stack.extend_from_slice(&values);
+    match assemble(heap, &function, base) {
+        Ok(()) => {
+            let bco = heap.stack.pop().unwrap();
+            heap.stack.truncate(base - 1);
+            Ok(heap.stack.push(bco))
+        }
+        Err(e) => {
+            heap.stack.truncate(base);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api;
+    use bytecode::{Bytecode, Opcode};
+    use read::{self, Input};
+    use value::{self, Kind, Value};
+
+    /// Reads and compiles `source`, leaving the BCO on top of the stack.
+    fn compile(source: &str) -> Result<api::State, String> {
+        let mut interp = api::State::new();
+        read::read(&mut interp, &mut Input::new(source.as_bytes(), "test")).unwrap();
+        try!(interp.compile());
+        Ok(interp)
+    }
+
+    fn code(bco: &Value) -> Vec<Bytecode> {
+        match bco.kind() {
+            Kind::Bytecode(bco) => unsafe {
+                (0..(*bco).len()).map(|pc| (*bco).instruction(pc)).collect()
+            },
+            _ => panic!("not a BCO"),
+        }
+    }
+
+    fn constant(bco: &Value, index: usize) -> Value {
+        let constants = match bco.kind() {
+            Kind::Bytecode(bco) => unsafe { (*bco).constants() },
+            _ => panic!("not a BCO"),
+        };
+        match constants.kind() {
+            Kind::Vector(vector) => unsafe { (*vector).element(index).clone() },
+            _ => panic!("bad constants vector"),
+        }
+    }
+
+    fn top(interp: &mut api::State) -> Value {
+        interp.heap().stack.last().unwrap().clone()
+    }
+
+    fn op(opcode: Opcode, src: u8, wide: u16) -> Bytecode {
+        Bytecode::wide(opcode, src, wide)
+    }
+
+    /// An instruction with the registers `src` and `src2`.
+    fn pair(opcode: Opcode, src: u8, src2: u8) -> Bytecode {
+        Bytecode {
+            opcode: opcode,
+            src: src,
+            src2: src2,
+            dst: 0,
+        }
+    }
+
+    fn enter(arguments: u8, captured: u8, registers: u8) -> Bytecode {
+        Bytecode {
+            opcode: Opcode::Enter,
+            src: arguments,
+            src2: captured,
+            dst: registers,
+        }
+    }
+
+    #[test]
+    fn compile_constants() {
+        let mut interp = compile("42").unwrap();
+        let bco = top(&mut interp);
+        assert_eq!(code(&bco), vec![enter(0, 0, 1),
+                                    op(Opcode::LoadConstant, 0, 2),
+                                    op(Opcode::Return, 0, 0)]);
+        assert_eq!(constant(&bco, 2), Value::new_fixnum(42));
+        let mut interp = compile("'(1 2)").unwrap();
+        let list = constant(&top(&mut interp), 2);
+        assert!(list.immutablep() && list.cdr().unwrap().immutablep());
+        let mut interp = compile("'()").unwrap();
+        assert_eq!(code(&top(&mut interp))[1], op(Opcode::LoadNil, 0, 0));
+    }
+
+    #[test]
+    fn compile_calls() {
+        let mut interp = compile("(f (g 1)\n   x)").unwrap();
+        let bco = top(&mut interp);
+        // Each call is made in the register its value is wanted in.
+        assert_eq!(code(&bco), vec![enter(0, 0, 3),
+                                    op(Opcode::LoadGlobal, 0, 2),
+                                    op(Opcode::LoadGlobal, 1, 3),
+                                    op(Opcode::LoadConstantCall, 2, 4),
+                                    pair(Opcode::Call, 1, 1),
+                                    op(Opcode::LoadGlobal, 2, 5),
+                                    pair(Opcode::TailCall, 0, 2)]);
+        interp.heap().stack.push(constant(&bco, 1));
+        assert_eq!(interp.write_string().unwrap(),
+                   "#(0 test 1 1 1 test 1 1 2 test 1 4 4 test 1 4 5 test 1 1 6 test 1 1 \
+                     #u8(255 1 2 3 3 3 3))");
+        assert_eq!(constant(&bco, 0), Value::new(value::FALSE));
+    }
+
+    #[test]
+    fn compile_conditionals() {
+        let mut interp = compile("(begin (set! x (if a 1)) (if b 2 3))").unwrap();
+        assert_eq!(code(&top(&mut interp)),
+                   vec![enter(0, 0, 2),
+                        op(Opcode::LoadGlobal, 1, 2),
+                        op(Opcode::JumpIfFalse, 1, 5),
+                        op(Opcode::LoadConstant, 0, 3),
+                        Bytecode::long(Opcode::Jump, 6),
+                        op(Opcode::LoadUnspecified, 0, 0),
+                        op(Opcode::StoreGlobal, 0, 4),
+                        op(Opcode::LoadGlobal, 0, 5),
+                        op(Opcode::JumpIfFalse, 0, 11),
+                        op(Opcode::LoadConstant, 0, 6),
+                        op(Opcode::Return, 0, 0),
+                        op(Opcode::LoadConstant, 0, 7),
+                        op(Opcode::Return, 0, 0)]);
+    }
+
+    #[test]
+    fn optimize() {
+        let code_of = |source: &str| code(&top(&mut compile(source).unwrap()));
+        assert_eq!(code_of("(if #t 1 2)"),
+                   vec![enter(0, 0, 1),
+                        op(Opcode::LoadTrue, 0, 0),
+                        op(Opcode::LoadConstant, 0, 2),
+                        op(Opcode::Return, 0, 0)]);
+        assert_eq!(code_of("(if #f 2 1)")[2], op(Opcode::LoadConstant, 0, 3));
+        // Values without side effects are not computed just to be
+        // discarded.
+        assert_eq!(code_of("(begin 1 (lambda () 1) x)"),
+                   vec![enter(0, 0, 1),
+                        op(Opcode::LoadGlobal, 0, 2),
+                        op(Opcode::Return, 0, 0)]);
+        // A jump to a jump goes to the final target.
+        assert_eq!(code_of("(set! a (if a (if b 1 2) 3))"),
+                   vec![enter(0, 0, 3),
+                        op(Opcode::LoadGlobal, 2, 2),
+                        op(Opcode::JumpIfFalse, 2, 9),
+                        op(Opcode::LoadGlobal, 2, 3),
+                        op(Opcode::JumpIfFalse, 2, 7),
+                        op(Opcode::LoadConstant, 1, 4),
+                        Bytecode::long(Opcode::Jump, 10),
+                        op(Opcode::LoadConstant, 1, 5),
+                        Bytecode::long(Opcode::Jump, 10),
+                        op(Opcode::LoadConstant, 1, 6),
+                        op(Opcode::StoreGlobal, 1, 2),
+                        op(Opcode::LoadUnspecified, 0, 0),
+                        op(Opcode::Return, 0, 0)]);
+
+        let mut interp = api::State::new();
+        interp.set_optimize(false);
+        read::read(&mut interp, &mut Input::new("(if #t 1 2)".as_bytes(), "test")).unwrap();
+        interp.compile().unwrap();
+        assert_eq!(code(&top(&mut interp)),
+                   vec![enter(0, 0, 1),
+                        op(Opcode::LoadTrue, 0, 0),
+                        op(Opcode::JumpIfFalse, 0, 5),
+                        op(Opcode::LoadConstant, 0, 2),
+                        op(Opcode::Return, 0, 0),
+                        op(Opcode::LoadConstant, 0, 3),
+                        op(Opcode::Return, 0, 0)]);
+    }
+
+    #[test]
+    fn superinstructions() {
+        let code_of = |source: &str| code(&top(&mut compile(source).unwrap()));
+        let ret = op(Opcode::Return, 0, 0);
+        assert_eq!(code_of("(f 'x)"),
+                   vec![enter(0, 0, 2),
+                        op(Opcode::LoadGlobal, 0, 2),
+                        op(Opcode::LoadConstantCall, 1, 3),
+                        pair(Opcode::TailCall, 0, 1)]);
+        assert_eq!(code_of("(if (f 'x) 1 2)"),
+                   vec![enter(0, 0, 2),
+                        op(Opcode::LoadGlobal, 0, 2),
+                        op(Opcode::LoadConstantCall, 1, 3),
+                        pair(Opcode::CallJumpIfFalse, 0, 1),
+                        op(Opcode::JumpIfFalse, 0, 7),
+                        op(Opcode::LoadConstant, 0, 4),
+                        ret,
+                        op(Opcode::LoadConstant, 0, 5),
+                        ret]);
+        // A call whose result is not tested stays a `Call`.
+        assert_eq!(code_of("(begin (f 1) (g))"),
+                   vec![enter(0, 0, 2),
+                        op(Opcode::LoadGlobal, 0, 2),
+                        op(Opcode::LoadConstantCall, 1, 3),
+                        pair(Opcode::Call, 0, 1),
+                        op(Opcode::LoadGlobal, 0, 4),
+                        pair(Opcode::TailCall, 0, 0)]);
+        let lambda = constant(&top(&mut compile("(lambda (x) (f x))").unwrap()), 2);
+        assert_eq!(code(&lambda),
+                   vec![enter(1, 0, 3),
+                        op(Opcode::LoadGlobal, 1, 2),
+                        pair(Opcode::MoveCall, 2, 0),
+                        pair(Opcode::TailCall, 1, 1)]);
+
+        let mut interp = api::State::new();
+        interp.set_optimize(false);
+        read::read(&mut interp, &mut Input::new("(if (f 'x) 1 2)".as_bytes(), "test")).unwrap();
+        interp.compile().unwrap();
+        assert!(code(&top(&mut interp)).iter().all(|instruction| {
+            match instruction.opcode {
+                Opcode::LoadConstantCall | Opcode::MoveCall | Opcode::CallJumpIfFalse => false,
+                _ => true,
+            }
+        }));
+    }
+
+    #[test]
+    fn disassemble() {
+        let mut interp = compile("(define (first x)\n  (if (pair? x) (car x) '(none)))").unwrap();
+        let bco = constant(&top(&mut interp), 2);
+        let text = match bco.kind() {
+            Kind::Bytecode(bco) => unsafe { (*bco).to_string() },
+            _ => panic!("not a BCO"),
+        };
+        assert_eq!(text,
+                   "first:
+        ; test:1:1
+     0  Enter 1 0 3
+        ; test:2:7
+     1  LoadGlobal 1 2          ; pair?
+     2  MoveCall 2 0
+     3  CallJumpIfFalse 1 1
+     4  JumpIfFalse 1 8
+        ; test:2:17
+     5  LoadGlobal 1 3          ; car
+     6  MoveCall 2 0
+     7  TailCall 1 1
+     8  LoadConstant 1 4        ; (none)
+     9  Return 1
+");
+        assert_eq!(Bytecode::long(Opcode::Jump, 300).to_string(), "Jump 300");
+    }
+
+    #[test]
+    fn compile_lambdas() {
+        let mut interp = compile("(lambda (x) (lambda (y) x))").unwrap();
+        let bco = top(&mut interp);
+        assert_eq!(code(&bco), vec![enter(0, 0, 1),
+                                    op(Opcode::Closure, 0, 2),
+                                    op(Opcode::Return, 0, 0)]);
+        let outer = constant(&bco, 2);
+        assert_eq!(code(&outer), vec![enter(1, 0, 3),
+                                      pair(Opcode::Move, 2, 0),
+                                      op(Opcode::Closure, 1, 2),
+                                      op(Opcode::Return, 1, 0)]);
+        assert_eq!(code(&constant(&outer, 2)), vec![enter(1, 1, 2),
+                                                    op(Opcode::LoadCaptured, 1, 0),
+                                                    op(Opcode::Return, 1, 0)]);
+        // Closures capture only the variables they refer to, including those
+        // their own closures refer to.
+        let mut interp = compile("(lambda (x . y) (lambda () (lambda () y)))").unwrap();
+        let outer = constant(&top(&mut interp), 2);
+        assert_eq!(code(&outer),
+                   vec![Bytecode { opcode: Opcode::EnterRest, src: 1, src2: 0, dst: 4 },
+                        pair(Opcode::Move, 3, 1),
+                        op(Opcode::Closure, 2, 2),
+                        op(Opcode::Return, 2, 0)]);
+        let middle = constant(&outer, 2);
+        assert_eq!(code(&middle), vec![enter(0, 1, 2),
+                                       op(Opcode::LoadCaptured, 1, 0),
+                                       op(Opcode::Closure, 0, 2),
+                                       op(Opcode::Return, 0, 0)]);
+        assert_eq!(code(&constant(&middle, 2)), vec![enter(0, 1, 1),
+                                                     op(Opcode::LoadCaptured, 0, 0),
+                                                     op(Opcode::Return, 0, 0)]);
+        // Special forms can be shadowed.
+        let mut interp = compile("(lambda (if) (if 1))").unwrap();
+        let inner = constant(&top(&mut interp), 2);
+        assert_eq!(code(&inner), vec![enter(1, 0, 3),
+                                      pair(Opcode::Move, 1, 0),
+                                      op(Opcode::LoadConstantCall, 2, 2),
+                                      pair(Opcode::TailCall, 1, 1)]);
+        // Optional and keyword parameters that are not passed are set to
+        // their defaults first.  The names of the keyword parameters are
+        // the first constants.
+        let mut interp = compile("(lambda (a #!optional (b 1) #!key k) a)").unwrap();
+        let inner = constant(&top(&mut interp), 2);
+        assert_eq!(code(&inner),
+                   vec![Bytecode { opcode: Opcode::EnterOptional, src: 1, src2: 0, dst: 3 },
+                        Bytecode { opcode: Opcode::Optional, src: 1, src2: 0, dst: 1 },
+                        op(Opcode::JumpIfSupplied, 1, 4),
+                        op(Opcode::LoadConstant, 1, 3),
+                        op(Opcode::JumpIfSupplied, 2, 6),
+                        op(Opcode::LoadFalse, 2, 0),
+                        op(Opcode::Return, 0, 0)]);
+        interp.heap().stack.push(constant(&inner, 2));
+        assert_eq!(interp.write_string().unwrap(), "k");
+        // `case-lambda` makes a closure over the procedures of its clauses.
+        let mut interp = compile("(case-lambda ((x) x) (() 1))").unwrap();
+        let bco = top(&mut interp);
+        assert_eq!(code(&bco), vec![enter(0, 0, 3),
+                                    op(Opcode::Closure, 1, 2),
+                                    op(Opcode::Closure, 2, 3),
+                                    op(Opcode::Closure, 0, 4),
+                                    op(Opcode::Return, 0, 0)]);
+        assert_eq!(code(&constant(&bco, 2)), vec![enter(1, 0, 1), op(Opcode::Return, 0, 0)]);
+        assert_eq!(code(&constant(&bco, 4)), vec![pair(Opcode::CaseLambda, 0, 2)]);
+    }
+
+    #[test]
+    fn compile_toplevel_forms() {
+        // A toplevel form is a procedure of no arguments, even if it makes
+        // procedures with optional parameters.
+        let mut interp = compile("(define (f #!optional (x 1)) x)").unwrap();
+        let toplevel = code(&top(&mut interp));
+        assert_eq!(toplevel[0].opcode, Opcode::Enter);
+        assert_eq!(toplevel[0].src, 0);
+        assert!(toplevel.iter().all(|instruction| instruction.opcode != Opcode::Optional));
+    }
+
+    #[test]
+    fn compile_let_and_letrec() {
+        let mut interp = compile("(let ((x 1)) (letrec ((f (lambda () x))) f))").unwrap();
+        let bco = top(&mut interp);
+        // Variables are registers, which values are computed into and
+        // returned from directly.
+        assert_eq!(code(&bco), vec![enter(0, 0, 4),
+                                    op(Opcode::LoadConstant, 0, 2),
+                                    pair(Opcode::Move, 3, 0),
+                                    op(Opcode::Closure, 2, 3),
+                                    pair(Opcode::Move, 1, 2),
+                                    op(Opcode::Return, 1, 0)]);
+        let f = constant(&bco, 3);
+        interp.heap().stack.push(constant(&f, 0));
+        assert_eq!(interp.write_string().unwrap(), "f");
+        assert_eq!(code(&f), vec![enter(0, 1, 1),
+                                  op(Opcode::LoadCaptured, 0, 0),
+                                  op(Opcode::Return, 0, 0)]);
+
+        // A procedure that refers to itself captures itself before it is
+        // stored, so it is boxed.
+        let mut interp = compile("(let loop ((i 0)) (loop i))").unwrap();
+        let bco = top(&mut interp);
+        assert_eq!(code(&bco), vec![enter(0, 0, 3),
+                                    op(Opcode::Box, 0, 0),
+                                    pair(Opcode::Move, 2, 0),
+                                    op(Opcode::Closure, 1, 2),
+                                    pair(Opcode::SetBox, 0, 1),
+                                    pair(Opcode::Unbox, 1, 0),
+                                    op(Opcode::LoadConstantCall, 2, 3),
+                                    pair(Opcode::TailCall, 1, 1)]);
+        assert_eq!(code(&constant(&bco, 2)), vec![enter(1, 1, 3),
+                                                  op(Opcode::LoadCaptured, 1, 0),
+                                                  pair(Opcode::Unbox, 1, 1),
+                                                  pair(Opcode::MoveCall, 2, 0),
+                                                  pair(Opcode::TailCall, 1, 1)]);
+    }
+
+    #[test]
+    fn compile_definitions() {
+        let mut interp = compile("(define (f x) (define y x) (set! x y) y)").unwrap();
+        let bco = top(&mut interp);
+        assert_eq!(code(&bco), vec![enter(0, 0, 2),
+                                    op(Opcode::Closure, 1, 2),
+                                    op(Opcode::DefineGlobal, 1, 3),
+                                    op(Opcode::LoadUnspecified, 0, 0),
+                                    op(Opcode::Return, 0, 0)]);
+        let f = constant(&bco, 2);
+        assert_eq!(constant(&f, 0), constant(&bco, 3));
+        // Variables assigned by `set!` are boxed.
+        assert_eq!(code(&f), vec![enter(1, 0, 2),
+                                  op(Opcode::Box, 0, 0),
+                                  pair(Opcode::Unbox, 1, 0),
+                                  pair(Opcode::SetBox, 0, 1),
+                                  op(Opcode::Return, 1, 0)]);
+        let mut interp = compile("(set! x 1)").unwrap();
+        assert_eq!(code(&top(&mut interp))[2], op(Opcode::StoreGlobal, 1, 3));
+    }
+
+    #[test]
+    fn compile_errors() {
+        for &(source, message) in &[("()", "empty procedure call ()"),
+                                    ("(if)", "test:1:1: bad syntax in if"),
+                                    ("(f . x)", "test:1:1: improper list in code"),
+                                    ("(lambda (1) 1)", "test:1:1: parameter is not a symbol"),
+                                    ("(lambda (x #!rest) x)", "test:1:1: bad parameter list"),
+                                    ("(lambda (#!key (k)) k)", "test:1:1: bad parameter list"),
+                                    ("(case-lambda ((x)))",
+                                     "test:1:1: bad clause in case-lambda"),
+                                    ("(lambda (x) (define y 1))",
+                                     "test:1:1: no expression in body"),
+                                    ("(let ((x)) x)", "test:1:1: bad binding"),
+                                    ("(guard (1) 2)", "test:1:1: bad syntax in guard"),
+                                    ("(guard (e (else 1) (#t 2)) 3)",
+                                     "test:1:1: bad else clause"),
+                                    ("(parameterize ((p)) 1)",
+                                     "test:1:1: bad syntax in parameterize"),
+                                    ("(let-syntax ((m (syntax-rules () ((_) 1))))\n (m 1))",
+                                     "test:2:2: no matching syntax rule for m"),
+                                    ("(let-syntax ((m (syntax-rules () ((_) 1))))\n (f m))",
+                                     "syntax keyword used as a variable: m"),
+                                    ("(let-syntax ((m (lambda (x) x))) 1)",
+                                     "test:1:17: unsupported macro transformer"),
+                                    ("(let-syntax ((m (syntax-rules () ((_ x) (x ...)))))\n (m 1))",
+                                     "test:2:2: no pattern variable to repeat in template"),
+                                    ("(letrec-syntax ((m (syntax-rules () ((_) (m)))))\n (m))",
+                                     "test:2:2: macro expansion does not terminate"),
+                                    ("(let-syntax ((m (er-macro-transformer))) 1)",
+                                     "test:1:17: bad syntax in er-macro-transformer"),
+                                    ("(let-syntax ((m (er-macro-transformer 1))) 1)",
+                                     "test:1:17: er-macro-transformer: expected a procedure"),
+                                    ("(f (import (x)))",
+                                     "test:1:4: import in expression context"),
+                                    ("(g\n (define x 1))",
+                                     "test:2:2: definition in expression context")] {
+            let mut interp = api::State::new();
+            read::read(&mut interp, &mut Input::new(source.as_bytes(), "test")).unwrap();
+            assert_eq!(interp.compile(), Err(message.to_owned()));
+            assert_eq!(interp.len(), 1);
+        }
+    }
+
+    #[test]
+    fn code_survives_collection() {
+        let mut interp = compile("(lambda (x) '(a b))").unwrap();
+        for _ in 0..3 {
+            interp.gc();
+            let inner = constant(&top(&mut interp), 2);
+            assert_eq!(code(&inner)[1], op(Opcode::LoadConstant, 1, 2));
+            interp.heap().stack.push(constant(&inner, 2));
+            assert_eq!(interp.write_string().unwrap(), "(a b)");
+            interp.heap().stack.push(inner);
+            assert_eq!(interp.write_string().unwrap(), "#<code>");
+            interp.heap().stack.truncate(1);
+        }
+    }
+}
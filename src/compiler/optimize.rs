@@ -0,0 +1,227 @@
+//! The peephole optimizer, which simplifies the code that `generate` makes.
+//!
+//! It repeats these rewrites until none applies:
+//!
+//! * A conditional jump on a register that the previous instruction loaded
+//!   with a known value becomes a jump, or nothing.
+//! * A jump to a jump goes to the final target instead, and a jump to
+//!   `Return` returns.
+//! * A jump to the next instruction is removed.
+//! * Instructions that cannot be reached are removed.
+//!
+//! Instructions are only combined if nothing jumps to the second, which
+//! would then run without the first.  The source map follows the code, an
+//! entry for a removed instruction moving to the next one left, and so does
+//! the stack map.
+//!
+//! Last, common pairs become superinstructions, which run the instruction
+//! after them too: a `Call` that a `JumpIfFalse` tests becomes
+//! `CallJumpIfFalse`, and a `LoadConstant` or `Move` before a call becomes
+//! `LoadConstantCall` or `MoveCall`.  The second instruction stays where it
+//! is, so jumps to it and the source map are unaffected.
+
+use bytecode::{Bytecode, Opcode};
+use super::{Constant, Function};
+
+/// Optimizes `function`, and the procedures nested in it.
+pub fn optimize(function: &mut Function) {
+    for constant in &mut function.constants {
+        if let Constant::Function(ref mut nested) = *constant {
+            optimize(nested)
+        }
+    }
+    loop {
+        let mut removed = vec![false; function.code.len()];
+        let changed = rewrite(&mut function.code, &mut removed);
+        remove_unreachable(&function.code, &mut removed);
+        if !changed && !removed.contains(&true) {
+            break
+        }
+        compact(function, &removed)
+    }
+    fuse(&mut function.code)
+}
+
+/// Turns pairs of instructions into superinstructions.
+fn fuse(code: &mut [Bytecode]) {
+    for pc in 1..code.len().saturating_sub(1) {
+        let (instruction, next) = (code[pc], code[pc + 1]);
+        if instruction.opcode == Opcode::Call && next.opcode == Opcode::JumpIfFalse &&
+           next.src == instruction.src {
+            code[pc].opcode = Opcode::CallJumpIfFalse
+        }
+    }
+    for pc in 1..code.len().saturating_sub(1) {
+        match code[pc + 1].opcode {
+            Opcode::Call | Opcode::TailCall | Opcode::CallJumpIfFalse => {}
+            _ => continue,
+        }
+        code[pc].opcode = match code[pc].opcode {
+            Opcode::LoadConstant => Opcode::LoadConstantCall,
+            Opcode::Move => Opcode::MoveCall,
+            opcode => opcode,
+        }
+    }
+}
+
+/// Whether the instruction loads a true value into register `src`.
+/// `LoadConstant` never loads `#f`, which is `LoadFalse`.
+fn loads_true(instruction: Bytecode) -> bool {
+    match instruction.opcode {
+        Opcode::LoadConstant | Opcode::LoadTrue | Opcode::LoadNil | Opcode::LoadUnspecified |
+        Opcode::Closure => true,
+        _ => false,
+    }
+}
+
+/// Whether `instruction` is a jump.
+fn is_jump(instruction: Bytecode) -> bool {
+    match instruction.opcode {
+        Opcode::Jump | Opcode::JumpIfFalse | Opcode::JumpIfSupplied => true,
+        _ => false,
+    }
+}
+
+/// The target of the jump `instruction`.
+fn target(instruction: Bytecode) -> usize {
+    match instruction.opcode {
+        Opcode::JumpIfFalse | Opcode::JumpIfSupplied => instruction.wide_operand(),
+        _ => instruction.long_operand(),
+    }
+}
+
+/// The jump `instruction`, going to `target` instead.
+fn retarget(instruction: Bytecode, target: usize) -> Bytecode {
+    match instruction.opcode {
+        Opcode::JumpIfFalse | Opcode::JumpIfSupplied => {
+            Bytecode::wide(instruction.opcode, instruction.src, target as u16)
+        }
+        opcode => Bytecode::long(opcode, target),
+    }
+}
+
+/// Where the jump at `pc` ends up, following jumps.
+fn final_target(code: &[Bytecode], pc: usize) -> usize {
+    let mut target = self::target(code[pc]);
+    // Bounded, in case of a loop of jumps.
+    for _ in 0..code.len() {
+        if code[target].opcode != Opcode::Jump || target == pc {
+            break
+        }
+        target = code[target].long_operand()
+    }
+    target
+}
+
+/// Rewrites instructions in place, and marks those to remove.  Returns
+/// whether anything changed.
+fn rewrite(code: &mut [Bytecode], removed: &mut [bool]) -> bool {
+    let mut targets = vec![false; code.len()];
+    for instruction in code.iter() {
+        if is_jump(*instruction) {
+            targets[target(*instruction)] = true
+        }
+    }
+    let mut changed = false;
+    // The first instruction is always `Enter`.
+    for pc in 1..code.len() {
+        if removed[pc] {
+            continue
+        }
+        let opcode = code[pc].opcode;
+        if is_jump(code[pc]) {
+            let target = final_target(code, pc);
+            if target != self::target(code[pc]) {
+                code[pc] = retarget(code[pc], target);
+                changed = true
+            }
+            if opcode == Opcode::Jump && code[target].opcode == Opcode::Return {
+                code[pc] = code[target];
+                changed = true
+            } else if target == pc + 1 {
+                removed[pc] = true
+            }
+            continue
+        }
+        if pc + 1 == code.len() || removed[pc + 1] || targets[pc + 1] {
+            continue
+        }
+        let next = code[pc + 1];
+        if next.opcode != Opcode::JumpIfFalse || next.src != code[pc].src {
+            continue
+        }
+        if opcode == Opcode::LoadFalse {
+            code[pc + 1] = Bytecode::long(Opcode::Jump, next.wide_operand());
+            changed = true
+        } else if loads_true(code[pc]) {
+            removed[pc + 1] = true
+        }
+    }
+    changed
+}
+
+/// Marks the instructions that cannot be reached from the first.  Those
+/// already marked are skipped over.
+fn remove_unreachable(code: &[Bytecode], removed: &mut [bool]) {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        if pc >= code.len() || reached[pc] {
+            continue
+        }
+        reached[pc] = true;
+        if removed[pc] {
+            pending.push(pc + 1);
+            continue
+        }
+        match code[pc].opcode {
+            Opcode::Return | Opcode::TailCall => {}
+            Opcode::Jump => pending.push(code[pc].long_operand()),
+            Opcode::JumpIfFalse | Opcode::JumpIfSupplied => {
+                pending.push(code[pc].wide_operand());
+                pending.push(pc + 1)
+            }
+            _ => pending.push(pc + 1),
+        }
+    }
+    for (removed, reached) in removed.iter_mut().zip(reached) {
+        *removed = *removed || !reached
+    }
+}
+
+/// Deletes the instructions marked in `removed`, and adjusts the jumps, the
+/// source map and the stack map.
+fn compact(function: &mut Function, removed: &[bool]) {
+    // The new index of each instruction, or of the next one kept.
+    let mut indices = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for &removed in removed {
+        indices.push(kept);
+        kept += !removed as usize
+    }
+    indices.push(kept);
+    let code = function.code
+                       .iter()
+                       .zip(removed)
+                       .filter(|&(_, &removed)| !removed)
+                       .map(|(&instruction, _)| {
+                           if is_jump(instruction) {
+                               retarget(instruction, indices[target(instruction)])
+                           } else {
+                               instruction
+                           }
+                       })
+                       .collect();
+    function.code = code;
+    let live = function.live
+                       .iter()
+                       .zip(removed)
+                       .filter(|&(_, &removed)| !removed)
+                       .map(|(&live, _)| live)
+                       .collect();
+    function.live = live;
+    for entry in &mut function.locations {
+        entry.0 = indices[entry.0]
+    }
+    function.locations.retain(|&(pc, _)| pc < kept)
+}
@@ -0,0 +1,404 @@
+//! `syntax-rules` macros.
+//!
+//! A use of a macro is matched against the pattern of each rule in turn,
+//! binding the pattern variables, and replaced by the template of the first
+//! rule that matches.  Identifiers in the template that are not pattern
+//! variables are renamed (see `expand`).
+//!
+//! Patterns and templates follow R7RS section 4.3.2: literals, `_`,
+//! ellipses anywhere in a list or vector (followed by more patterns, or by a
+//! dotted tail), a custom ellipsis, and `(... template)` to insert an
+//! ellipsis in the output.
+
+use std::rc::Rc;
+use alloc::Location;
+use equiv;
+use value::{self, Value};
+use super::expand::{Env, Expander, Identifier, Syntax, View};
+
+/// A macro defined by `syntax-rules`.
+#[derive(Debug)]
+pub struct Rules {
+    /// The keyword it was defined as, for error messages.
+    name: Rc<String>,
+
+    /// The environment of its definition.
+    env: Env,
+
+    /// The custom ellipsis, if there is one.
+    ellipsis: Option<Rc<Identifier>>,
+
+    literals: Vec<Rc<Identifier>>,
+
+    /// The patterns, without the keyword, and their templates.
+    rules: Vec<(Syntax, Syntax)>,
+}
+
+/// What a pattern variable matched: a form, or one match per repetition of
+/// the subpattern it is in.
+#[derive(Clone)]
+enum Match {
+    One(Syntax),
+    Many(Vec<Match>),
+}
+
+/// The pattern variables bound so far.  Later bindings shadow earlier ones.
+type Bindings = Vec<(Rc<Identifier>, Match)>;
+
+/// What `identifier` is bound to in `bindings`, if anything.
+fn lookup<'a>(bindings: &'a Bindings, identifier: &Identifier) -> Option<&'a Match> {
+    bindings.iter()
+            .rev()
+            .find(|&&(ref variable, _)| variable.same(identifier))
+            .map(|&(_, ref binding)| binding)
+}
+
+impl Rules {
+    /// The macro called `name` that the `syntax-rules` form `transformer`
+    /// defines in `env`.
+    pub fn new(expander: &mut Expander,
+               name: &Rc<Identifier>,
+               transformer: &Syntax,
+               env: &Env)
+               -> Result<Rules, String> {
+        let elements = transformer.proper_list(expander.heap).unwrap_or(vec![]);
+        let (ellipsis, rest) = match elements.get(1).map(|spec| spec.view(expander.heap)) {
+            Some(View::Identifier(ellipsis)) => (Some(ellipsis), &elements[2..]),
+            _ => (None, &elements[1..]),
+        };
+        let literals = rest.first().and_then(|literals| literals.proper_list(expander.heap));
+        let literals = match literals {
+            Some(literals) => literals,
+            None => return expander.error(transformer, "bad syntax in syntax-rules"),
+        };
+        let mut identifiers = vec![];
+        for literal in literals {
+            match literal.view(expander.heap) {
+                View::Identifier(literal) => identifiers.push(literal),
+                _ => return expander.error(transformer, "bad syntax in syntax-rules"),
+            }
+        }
+        let mut rules = vec![];
+        for rule in &rest[1..] {
+            let elements = rule.proper_list(expander.heap).unwrap_or(vec![]);
+            let pattern = match elements.first().map(|pattern| pattern.view(expander.heap)) {
+                Some(View::Pair(_, pattern)) if elements.len() == 2 => pattern,
+                _ => return expander.error(transformer, "bad syntax rule"),
+            };
+            rules.push((pattern, elements[1].clone()))
+        }
+        Ok(Rules {
+            name: name.name.clone(),
+            env: env.clone(),
+            ellipsis: ellipsis,
+            literals: identifiers,
+            rules: rules,
+        })
+    }
+
+    /// Expands `form`, a use of the macro in `env`.
+    pub fn expand(&self,
+                  expander: &mut Expander,
+                  env: &Env,
+                  form: &Syntax)
+                  -> Result<Syntax, String> {
+        let operands = match form.view(expander.heap) {
+            View::Pair(_, operands) => operands,
+            _ => unreachable!(),
+        };
+        for &(ref pattern, ref template) in &self.rules {
+            let mut bindings = vec![];
+            if self.matches(expander, env, pattern, &operands, &mut bindings) {
+                let mark = expander.mark();
+                let use_site = Use {
+                    form: form,
+                    mark: mark,
+                    location: expander.location(form),
+                };
+                return self.instantiate(expander, &use_site, template, &bindings, false)
+            }
+        }
+        expander.error(form, &format!("no matching syntax rule for {}", self.name))
+    }
+
+    fn is_literal(&self, identifier: &Identifier) -> bool {
+        self.literals.iter().any(|literal| literal.same(identifier))
+    }
+
+    /// Checks if `syntax` is the ellipsis.
+    fn is_ellipsis(&self, expander: &Expander, syntax: &Syntax) -> bool {
+        let identifier = match syntax.view(expander.heap) {
+            View::Identifier(identifier) => identifier,
+            _ => return false,
+        };
+        let ellipsis = match self.ellipsis {
+            Some(ref ellipsis) => ellipsis.same(&identifier),
+            None => *identifier.name == "...",
+        };
+        ellipsis && !self.is_literal(&identifier)
+    }
+
+    /// The pattern variables in `pattern`.
+    fn variables(&self, expander: &Expander, pattern: &Syntax) -> Vec<Rc<Identifier>> {
+        match pattern.view(expander.heap) {
+            View::Identifier(identifier) => {
+                if self.is_literal(&identifier) || *identifier.name == "_" ||
+                   self.is_ellipsis(expander, pattern) {
+                    vec![]
+                } else {
+                    vec![identifier]
+                }
+            }
+            View::Pair(car, cdr) => {
+                let mut variables = self.variables(expander, &car);
+                variables.extend(self.variables(expander, &cdr));
+                variables
+            }
+            View::Vector(items) => {
+                items.iter().flat_map(|item| self.variables(expander, item)).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Checks if `form`, in `env`, matches `pattern`, adding the bindings of
+    /// the pattern variables to `bindings`.
+    fn matches(&self,
+               expander: &mut Expander,
+               env: &Env,
+               pattern: &Syntax,
+               form: &Syntax,
+               bindings: &mut Bindings)
+               -> bool {
+        match pattern.view(expander.heap) {
+            View::Identifier(identifier) => {
+                if self.is_literal(&identifier) {
+                    return match form.view(expander.heap) {
+                        View::Identifier(other) => {
+                            let denotation = expander.resolve(env, &other);
+                            denotation.same(&expander.resolve(&self.env, &identifier))
+                        }
+                        _ => false,
+                    }
+                }
+                if *identifier.name != "_" {
+                    bindings.push((identifier, Match::One(form.clone())))
+                }
+                true
+            }
+            View::Pair(car, cdr) => {
+                if let View::Pair(next, after) = cdr.view(expander.heap) {
+                    if self.is_ellipsis(expander, &next) {
+                        return self.matches_repetition(expander, env, &car, &after, form, bindings)
+                    }
+                }
+                match form.view(expander.heap) {
+                    View::Pair(first, rest) => {
+                        self.matches(expander, env, &car, &first, bindings) &&
+                        self.matches(expander, env, &cdr, &rest, bindings)
+                    }
+                    _ => false,
+                }
+            }
+            View::Vector(patterns) => {
+                match form.view(expander.heap) {
+                    View::Vector(items) => {
+                        let nil = Syntax::datum(expander.heap, Value::new(value::NIL));
+                        let pattern = Syntax::list(patterns, nil.clone(), &None);
+                        let form = Syntax::list(items, nil, &None);
+                        self.matches(expander, env, &pattern, &form, bindings)
+                    }
+                    _ => false,
+                }
+            }
+            View::Nil => {
+                match form.view(expander.heap) {
+                    View::Nil => true,
+                    _ => false,
+                }
+            }
+            View::Other(datum) => {
+                match form.view(expander.heap) {
+                    View::Other(other) => equiv::equal(&datum, &other),
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// Checks if `form` matches `(repeated ... . after)`.
+    fn matches_repetition(&self,
+                          expander: &mut Expander,
+                          env: &Env,
+                          repeated: &Syntax,
+                          after: &Syntax,
+                          form: &Syntax,
+                          bindings: &mut Bindings)
+                          -> bool {
+        let (items, after_items) = match (form.elements(expander.heap),
+                                          after.elements(expander.heap)) {
+            (Some((items, _)), Some((after_items, _))) => (items, after_items),
+            _ => return false,
+        };
+        if items.len() < after_items.len() {
+            return false
+        }
+        let count = items.len() - after_items.len();
+        let mut repetitions = vec![];
+        for item in &items[..count] {
+            let mut repetition = vec![];
+            if !self.matches(expander, env, repeated, item, &mut repetition) {
+                return false
+            }
+            repetitions.push(repetition)
+        }
+        for variable in self.variables(expander, repeated) {
+            let matches = repetitions.iter()
+                                     .map(|repetition| {
+                                         lookup(repetition, &variable).unwrap().clone()
+                                     })
+                                     .collect();
+            bindings.push((variable, Match::Many(matches)))
+        }
+        let mut rest = form.clone();
+        for _ in 0..count {
+            rest = match rest.view(expander.heap) {
+                View::Pair(_, cdr) => cdr,
+                _ => unreachable!(),
+            }
+        }
+        self.matches(expander, env, after, &rest, bindings)
+    }
+
+    /// Instantiates `template` with `bindings`.  If `escaped` is set,
+    /// ellipses are ordinary identifiers.
+    fn instantiate(&self,
+                   expander: &mut Expander,
+                   use_site: &Use,
+                   template: &Syntax,
+                   bindings: &Bindings,
+                   escaped: bool)
+                   -> Result<Syntax, String> {
+        match template.view(expander.heap) {
+            View::Identifier(identifier) => {
+                match lookup(bindings, &identifier) {
+                    Some(&Match::One(ref form)) => Ok(form.clone()),
+                    Some(&Match::Many(_)) => {
+                        expander.error(use_site.form,
+                                       &format!("missing ellipsis after {} in template",
+                                                identifier.name))
+                    }
+                    None => {
+                        Ok(Syntax::Identifier(Identifier::rename(&identifier,
+                                                                 &self.env,
+                                                                 use_site.mark)))
+                    }
+                }
+            }
+            View::Pair(car, cdr) => {
+                if !escaped && self.is_ellipsis(expander, &car) {
+                    return match cdr.proper_list(expander.heap) {
+                        Some(ref rest) if rest.len() == 1 => {
+                            self.instantiate(expander, use_site, &rest[0], bindings, true)
+                        }
+                        _ => expander.error(use_site.form, "bad ellipsis in template"),
+                    }
+                }
+                let mut depth = 0;
+                let mut rest = cdr;
+                while let View::Pair(next, after) = rest.view(expander.heap) {
+                    if escaped || !self.is_ellipsis(expander, &next) {
+                        break
+                    }
+                    depth += 1;
+                    rest = after
+                }
+                let rest = try!(self.instantiate(expander, use_site, &rest, bindings, escaped));
+                let items = if depth == 0 {
+                    vec![try!(self.instantiate(expander, use_site, &car, bindings, escaped))]
+                } else {
+                    try!(self.repeat(expander, use_site, &car, depth, bindings))
+                };
+                Ok(Syntax::list(items, rest, &use_site.location))
+            }
+            View::Vector(templates) => {
+                let nil = Syntax::datum(expander.heap, Value::new(value::NIL));
+                let list = Syntax::list(templates, nil, &None);
+                let list = try!(self.instantiate(expander, use_site, &list, bindings, escaped));
+                let items = list.proper_list(expander.heap).unwrap();
+                Ok(Syntax::Vector(Rc::new(items)))
+            }
+            _ => Ok(template.clone()),
+        }
+    }
+
+    /// Instantiates `template`, followed by `depth` ellipses, once for each
+    /// repetition of the pattern variables in it.
+    fn repeat(&self,
+              expander: &mut Expander,
+              use_site: &Use,
+              template: &Syntax,
+              depth: usize,
+              bindings: &Bindings)
+              -> Result<Vec<Syntax>, String> {
+        let mut repeated: Vec<(Rc<Identifier>, Vec<Match>)> = vec![];
+        for identifier in self.identifiers(expander, template) {
+            if let Some(&Match::Many(ref matches)) = lookup(bindings, &identifier) {
+                if !repeated.iter().any(|&(ref other, _)| other.same(&identifier)) {
+                    repeated.push((identifier.clone(), matches.clone()))
+                }
+            }
+        }
+        let count = match repeated.first() {
+            Some(&(_, ref matches)) => matches.len(),
+            None => {
+                return expander.error(use_site.form,
+                                      "no pattern variable to repeat in template")
+            }
+        };
+        if repeated.iter().any(|&(_, ref matches)| matches.len() != count) {
+            return expander.error(use_site.form,
+                                  "pattern variables repeat different numbers of times")
+        }
+        let mut items = vec![];
+        for i in 0..count {
+            let mut inner = bindings.clone();
+            for &(ref variable, ref matches) in &repeated {
+                inner.push((variable.clone(), matches[i].clone()))
+            }
+            if depth > 1 {
+                items.extend(try!(self.repeat(expander, use_site, template, depth - 1, &inner)))
+            } else {
+                items.push(try!(self.instantiate(expander, use_site, template, &inner, false)))
+            }
+        }
+        Ok(items)
+    }
+
+    /// The identifiers in `template`.
+    fn identifiers(&self, expander: &Expander, template: &Syntax) -> Vec<Rc<Identifier>> {
+        match template.view(expander.heap) {
+            View::Identifier(identifier) => vec![identifier],
+            View::Pair(car, cdr) => {
+                let mut identifiers = self.identifiers(expander, &car);
+                identifiers.extend(self.identifiers(expander, &cdr));
+                identifiers
+            }
+            View::Vector(items) => {
+                items.iter().flat_map(|item| self.identifiers(expander, item)).collect()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+/// A use of a macro being expanded.
+struct Use<'a> {
+    form: &'a Syntax,
+
+    /// The number of the expansion.
+    mark: usize,
+
+    /// The location of the use, which the pairs of the expansion get.
+    location: Option<Location>,
+}
@@ -0,0 +1,212 @@
+//! The equivalence predicates `eq?`, `eqv?`, and `equal?`, and the hashes
+//! that go with them.
+//!
+//! `equal?` must terminate on cyclic structures.  It uses the union-find
+//! algorithm of Adams and Dybvig ("Efficient Nondestructive Equality
+//! Checking for Trees and Graphs"): when two pairs or vectors are compared,
+//! they are first merged into the same equivalence class, so that meeting
+//! them again (through a cycle) succeeds at once.  Any difference found
+//! along the way makes the whole comparison fail, so this assumption is
+//! safe.  Nothing is allocated on the Scheme heap, so the addresses of
+//! objects are stable while `equal?` runs.
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use value::{self, Kind, Value};
+
+/// The number of pairs and vectors `equal_hash` looks into before giving
+/// up.
+const EQUAL_HASH_BUDGET: usize = 32;
+
+fn mix(bits: usize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write_usize(bits);
+    hasher.finish()
+}
+
+/// Checks if `value` is a heap object that may be moved by the GC.
+fn movable(value: &Value) -> bool {
+    !value.immediatep() && value.tag() != value::Tags::Symbol &&
+    value.tag() != value::Tags::RustFunc
+}
+
+/// The `eq?` hash of `value`, and whether it depends on an address.
+pub fn eq_hash(value: &Value) -> (u64, bool) {
+    (mix(value.get()), movable(value))
+}
+
+/// The `eqv?` hash of `value`, and whether it depends on an address.
+pub fn eqv_hash(value: &Value) -> (u64, bool) {
+    match value.kind() {
+        Kind::Float(float) => (mix(float.to_bits() as usize), false),
+        _ => eq_hash(value),
+    }
+}
+
+/// The `equal?` hash of `value`, and whether it depends on an address.
+/// Only the first few pairs and vectors of a structure are looked into, so
+/// the hash of a cyclic structure is finite.
+pub fn equal_hash(value: &Value) -> (u64, bool) {
+    let mut hasher = DefaultHasher::new();
+    let mut budget = EQUAL_HASH_BUDGET;
+    let moves = equal_hash_into(value, &mut hasher, &mut budget, true);
+    (hasher.finish(), moves)
+}
+
+/// A hash like `equal_hash`, that does not depend on addresses: objects
+/// compared by identity only count by their type, and symbols by their
+/// name.  Used in deterministic mode.
+pub fn content_hash(value: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut budget = EQUAL_HASH_BUDGET;
+    equal_hash_into(value, &mut hasher, &mut budget, false);
+    hasher.finish()
+}
+
+/// Feeds `value` to `hasher`, returning whether that depends on an
+/// address.  Objects compared by identity are fed by their address only if
+/// `addresses` is set.
+fn equal_hash_into(value: &Value,
+                   hasher: &mut DefaultHasher,
+                   budget: &mut usize,
+                   addresses: bool)
+                   -> bool {
+    match value.kind() {
+        Kind::Pair(_) | Kind::Vector(_) if *budget == 0 => {
+            hasher.write_u8(0);
+            false
+        }
+        Kind::Pair(_) => {
+            *budget -= 1;
+            let car = equal_hash_into(&value.car().unwrap(), hasher, budget, addresses);
+            car | equal_hash_into(&value.cdr().unwrap(), hasher, budget, addresses)
+        }
+        Kind::Vector(vector) => unsafe {
+            *budget -= 1;
+            hasher.write_usize((*vector).len());
+            let mut moves = false;
+            for i in 0..(*vector).len() {
+                moves |= equal_hash_into((*vector).element(i), hasher, budget, addresses)
+            }
+            moves
+        },
+        Kind::String(string) => {
+            hasher.write(unsafe { (*string).as_str() }.as_bytes());
+            false
+        }
+        Kind::Bytevector(bytevector) => {
+            hasher.write(unsafe { (*bytevector).as_slice() });
+            false
+        }
+        Kind::Symbol(symbol) if !addresses => {
+            hasher.write(unsafe { (*symbol).name() }.as_bytes());
+            false
+        }
+        Kind::Primitive(primitive) if !addresses => {
+            hasher.write(unsafe { (*primitive).name }.as_bytes());
+            false
+        }
+        Kind::Float(_) => {
+            let (hash, moves) = eqv_hash(value);
+            hasher.write_u64(hash);
+            moves
+        }
+        ref kind if !addresses && !value.immediatep() => {
+            mem::discriminant(kind).hash(hasher);
+            false
+        }
+        _ => {
+            let (hash, moves) = eqv_hash(value);
+            hasher.write_u64(hash);
+            moves
+        }
+    }
+}
+
+/// Checks if `first` and `second` are `eq?`.
+pub fn eq(first: &Value, second: &Value) -> bool {
+    first == second
+}
+
+/// Checks if `first` and `second` are `eqv?`.
+pub fn eqv(first: &Value, second: &Value) -> bool {
+    eq(first, second) ||
+    match (first.kind(), second.kind()) {
+        (Kind::Float(x), Kind::Float(y)) => x.to_bits() == y.to_bits(),
+        _ => false,
+    }
+}
+
+/// Equivalence classes of objects, keyed by address.
+#[derive(Default)]
+struct UnionFind {
+    parents: HashMap<usize, usize>,
+}
+
+impl UnionFind {
+    fn find(&mut self, object: usize) -> usize {
+        let parent = *self.parents.get(&object).unwrap_or(&object);
+        if parent == object {
+            return object
+        }
+        let root = self.find(parent);
+        self.parents.insert(object, root);
+        root
+    }
+
+    /// Merges the classes of `first` and `second`.  Returns `false` if they
+    /// were already the same.
+    fn union(&mut self, first: usize, second: usize) -> bool {
+        let (first, second) = (self.find(first), self.find(second));
+        if first == second {
+            return false
+        }
+        self.parents.insert(first, second);
+        true
+    }
+}
+
+/// Checks if `first` and `second` are `equal?`: pairs and vectors are
+/// compared element by element, and strings and bytevectors by contents.
+/// Terminates on cyclic structures.
+pub fn equal(first: &Value, second: &Value) -> bool {
+    let mut classes = UnionFind::default();
+    let mut pending = vec![(first.clone(), second.clone())];
+    while let Some((first, second)) = pending.pop() {
+        if eqv(&first, &second) {
+            continue
+        }
+        match (first.kind(), second.kind()) {
+            (Kind::Pair(_), Kind::Pair(_)) => {
+                if classes.union(first.get(), second.get()) {
+                    pending.push((first.cdr().unwrap(), second.cdr().unwrap()));
+                    pending.push((first.car().unwrap(), second.car().unwrap()))
+                }
+            }
+            (Kind::Vector(x), Kind::Vector(y)) => unsafe {
+                if (*x).len() != (*y).len() {
+                    return false
+                }
+                if classes.union(first.get(), second.get()) {
+                    for i in (0..(*x).len()).rev() {
+                        pending.push(((*x).element(i).clone(), (*y).element(i).clone()))
+                    }
+                }
+            },
+            (Kind::String(x), Kind::String(y)) => unsafe {
+                if (*x).as_str() != (*y).as_str() {
+                    return false
+                }
+            },
+            (Kind::Bytevector(x), Kind::Bytevector(y)) => unsafe {
+                if (*x).as_slice() != (*y).as_slice() {
+                    return false
+                }
+            },
+            _ => return false,
+        }
+    }
+    true
+}
@@ -0,0 +1,469 @@
+//! Compiled files ("FASL", for fast load).
+//!
+//! `compile-file` runs a source file as `load` does, and writes out the BCO
+//! that each of its forms compiled to, so that loading the result skips
+//! reading, expanding and compiling them.  Expanding a form can do more than
+//! produce code (see `expand::Effect`), and the compiled file records that
+//! too.  Macros cannot be written out, so forms that change the macros or
+//! imports of the top level, and libraries that export macros, are written
+//! out as they are, and expanded again when the file is loaded.
+//!
+//! A compiled file is `MAGIC`, the version of the format, and entries, each
+//! starting with its kind:
+//!
+//! * `CODE` and a BCO, which is run.
+//! * `REQUIRE` and the parts of the name of a library that the next code
+//!   imports.  It is loaded if it is not defined yet.
+//! * `LIBRARY`, the name of a library that the previous code defined, and
+//!   its exports (see `Libraries::saved_exports`).
+//! * `SOURCE` and a datum, which is evaluated.
+//!
+//! Values are written out by kind, so a compiled file does not depend on
+//! how values are represented.  Numbers are 8 bytes, little-endian, and
+//! strings are their length and UTF-8 contents.  Only the kinds of values
+//! that code can contain can be written out.
+
+use std::char;
+use std::fs::File;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::str;
+use alloc::Heap;
+use builtins;
+use bytecode::Opcode;
+use compiler::{self, Effect};
+use interp;
+use print;
+use read::{self, Input};
+#[cfg(feature = "stack-bytecode")]
+use stack_bytecode;
+use value::{self, Kind, Value};
+
+/// The start of every compiled file.
+const MAGIC: &'static [u8] = b"\0rusty-scheme fasl\0";
+
+/// The version of the format.  It must change whenever the format or the
+/// bytecode does.
+const VERSION: u8 = 3;
+
+/// The previous version, whose code is in the stack format.  Files of this
+/// version can be loaded with the `stack-bytecode` feature, which
+/// translates their code (see `stack_bytecode`).
+const STACK_VERSION: u8 = 2;
+
+// The kinds of entries.
+const CODE: u8 = 0;
+const REQUIRE: u8 = 1;
+const LIBRARY: u8 = 2;
+const SOURCE: u8 = 3;
+
+// The kinds of values.  A list is written out as its length, its elements,
+// and its tail.
+const NIL: u8 = 0;
+const TRUE: u8 = 1;
+const FALSE: u8 = 2;
+const UNSPECIFIED: u8 = 3;
+const EOF: u8 = 4;
+const FIXNUM: u8 = 5;
+const CHAR: u8 = 6;
+const FLOAT: u8 = 7;
+const SYMBOL: u8 = 8;
+const STRING: u8 = 9;
+const LIST: u8 = 10;
+const VECTOR: u8 = 11;
+const BYTEVECTOR: u8 = 12;
+const BYTECODE: u8 = 13;
+const PRIMITIVE: u8 = 14;
+
+/// Checks if `bytes` are the contents of a compiled file.
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn write_number(out: &mut Vec<u8>, number: u64) {
+    for i in 0..8 {
+        out.push((number >> (8 * i)) as u8)
+    }
+}
+
+pub fn write_string(out: &mut Vec<u8>, string: &str) {
+    write_number(out, string.len() as u64);
+    out.extend_from_slice(string.as_bytes())
+}
+
+/// Writes out `value`.
+pub fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+    match value.kind() {
+        Kind::Constant(value::NIL) => out.push(NIL),
+        Kind::Constant(value::TRUE) => out.push(TRUE),
+        Kind::Constant(value::FALSE) => out.push(FALSE),
+        Kind::Constant(value::EOF) => out.push(EOF),
+        Kind::Constant(_) => out.push(UNSPECIFIED),
+        Kind::Fixnum(number) => {
+            out.push(FIXNUM);
+            write_number(out, number as u64)
+        }
+        Kind::Char(c) => {
+            out.push(CHAR);
+            write_number(out, c as u64)
+        }
+        Kind::Float(float) => {
+            out.push(FLOAT);
+            write_number(out, float.to_bits())
+        }
+        Kind::Symbol(symbol) => {
+            out.push(SYMBOL);
+            write_string(out, unsafe { &(*symbol).name() })
+        }
+        Kind::String(string) => {
+            out.push(STRING);
+            write_string(out, unsafe { (*string).as_str() })
+        }
+        Kind::Pair(_) => {
+            let mut elements = vec![];
+            let mut rest = value.clone();
+            while let (Ok(car), Ok(cdr)) = (rest.car(), rest.cdr()) {
+                elements.push(car);
+                rest = cdr
+            }
+            out.push(LIST);
+            write_number(out, elements.len() as u64);
+            for element in elements.iter().chain(Some(&rest)) {
+                try!(write_value(out, element))
+            }
+        }
+        Kind::Vector(vector) => {
+            let len = unsafe { (*vector).len() };
+            out.push(VECTOR);
+            write_number(out, len as u64);
+            for i in 0..len {
+                try!(write_value(out, unsafe { (*vector).element(i) }))
+            }
+        }
+        Kind::Bytevector(bytevector) => {
+            out.push(BYTEVECTOR);
+            let bytes = unsafe { (*bytevector).as_slice() };
+            write_number(out, bytes.len() as u64);
+            out.extend_from_slice(bytes)
+        }
+        Kind::Bytecode(bco) => {
+            let len = unsafe { (*bco).len() };
+            out.push(BYTECODE);
+            write_number(out, 4 * len as u64);
+            for pc in 0..len {
+                out.extend_from_slice(&unsafe { (*bco).instruction(pc) }.to_bytes())
+            }
+            try!(write_value(out, &unsafe { (*bco).constants() }))
+        }
+        Kind::Primitive(primitive) => {
+            out.push(PRIMITIVE);
+            write_string(out, unsafe { (*primitive).name })
+        }
+        _ => {
+            let value = print::to_string(value, print::Mode::Write);
+            return Err(format!("compile-file: cannot write out {}", value))
+        }
+    }
+    Ok(())
+}
+
+/// Writes out the entries for the form at `stack[base]`, which compiled to
+/// the BCO on top of the stack with `effects`.
+fn write_entries(heap: &Heap, out: &mut Vec<u8>, base: usize, effects: &[Effect])
+                 -> Result<(), String> {
+    let mut libraries = vec![];
+    let mut source = effects.contains(&Effect::Toplevel);
+    for effect in effects {
+        if let Effect::Library(ref name) = *effect {
+            match heap.macros.libraries.saved_exports(name) {
+                Some(exports) => libraries.push((name, exports)),
+                None => source = true,
+            }
+        }
+    }
+    if source {
+        out.push(SOURCE);
+        return write_value(out, &heap.stack[base])
+    }
+    for effect in effects {
+        if let Effect::Import(ref parts) = *effect {
+            out.push(REQUIRE);
+            write_number(out, parts.len() as u64);
+            for part in parts {
+                write_string(out, part)
+            }
+        }
+    }
+    out.push(CODE);
+    try!(write_value(out, heap.stack.last().unwrap()));
+    for (name, exports) in libraries {
+        out.push(LIBRARY);
+        write_string(out, name);
+        write_number(out, exports.len() as u64);
+        for (name, target) in exports {
+            write_string(out, &name);
+            write_string(out, &target)
+        }
+    }
+    Ok(())
+}
+
+/// Runs the code in `input` at top level, as `load` does, and writes out
+/// what it compiled to to `output`.
+pub fn compile_file(heap: &mut Heap, input: &Path, output: &Path) -> Result<(), String> {
+    let bytes = try!(builtins::read_source(input));
+    let out = try!(compile(heap, input, &bytes));
+    File::create(output)
+        .and_then(|mut file| file.write_all(&out))
+        .map_err(|e| format!("{}: {}", output.display(), e))
+}
+
+/// Runs the source code `bytes`, which come from `input`, at top level, and
+/// returns what it compiled to, as the contents of a compiled file.
+pub fn compile(heap: &mut Heap, input: &Path, bytes: &[u8]) -> Result<Vec<u8>, String> {
+    if is_compiled(bytes) {
+        return Err(format!("{}: already compiled", input.display()))
+    }
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    let mut source = Input::new(bytes, &input.to_string_lossy());
+    let base = heap.stack.len();
+    let outer = mem::replace(&mut heap.macros.effects, None);
+    let result = loop {
+        if let Err(e) = read::read_to_heap(heap, &mut source) {
+            break Err(format!("{}: read error: {:?}", input.display(), e))
+        }
+        let datum = heap.stack[base].clone();
+        if datum.get() == value::EOF {
+            break Ok(())
+        }
+        heap.stack.push(datum);
+        heap.macros.effects = Some(vec![]);
+        let compiled = compiler::compile(heap);
+        let effects = heap.macros.effects.take().unwrap();
+        if compiled.is_ok() {
+            let form = heap.stack[base].clone();
+            let form = heap.root(form);
+            heap.macros.remember(form, &effects)
+        }
+        let result = compiled.and_then(|()| write_entries(heap, &mut out, base, &effects))
+                             .and_then(|()| interp::run_compiled(heap));
+        heap.stack.truncate(base);
+        if let Err(e) = result {
+            break Err(e)
+        }
+    };
+    heap.macros.effects = outer;
+    heap.stack.truncate(base);
+    result.map(|()| out)
+}
+
+/// Reads a compiled file, or the values in an image (see `image`).
+pub struct Reader<'a> {
+    file: &'a Path,
+    bytes: &'a [u8],
+    position: usize,
+
+    /// The version of the format of the file.
+    version: u8,
+}
+
+impl<'a> Reader<'a> {
+    /// A reader of `bytes`, which come from `file`, in the current version
+    /// of the format.
+    pub fn new(file: &'a Path, bytes: &'a [u8]) -> Self {
+        Reader {
+            file: file,
+            bytes: bytes,
+            position: 0,
+            version: VERSION,
+        }
+    }
+
+    pub fn error<T>(&self) -> Result<T, String> {
+        Err(format!("{}: bad compiled file", self.file.display()))
+    }
+
+    /// The next byte, which is not consumed.
+    pub fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).cloned()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.position < len {
+            return self.error()
+        }
+        self.position += len;
+        Ok(&self.bytes[self.position - len..self.position])
+    }
+
+    pub fn byte(&mut self) -> Result<u8, String> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    pub fn number(&mut self) -> Result<u64, String> {
+        let bytes = try!(self.bytes(8));
+        Ok((0..8).fold(0, |number, i| number | (bytes[i] as u64) << (8 * i)))
+    }
+
+    pub fn count(&mut self) -> Result<usize, String> {
+        let number = try!(self.number());
+        if number > (self.bytes.len() - self.position) as u64 {
+            return self.error()
+        }
+        Ok(number as usize)
+    }
+
+    pub fn string(&mut self) -> Result<&'a str, String> {
+        let len = try!(self.count());
+        let bytes = try!(self.bytes(len));
+        str::from_utf8(bytes).or_else(|_| self.error())
+    }
+
+    /// Reads a value, and pushes it.  Everything in it but code is marked
+    /// immutable if `constant` is set, as `quote` does.
+    pub fn value(&mut self, heap: &mut Heap, constant: bool) -> Result<(), String> {
+        let base = heap.stack.len();
+        match try!(self.byte()) {
+            NIL => heap.stack.push(Value::new(value::NIL)),
+            TRUE => heap.stack.push(Value::new(value::TRUE)),
+            FALSE => heap.stack.push(Value::new(value::FALSE)),
+            UNSPECIFIED => heap.stack.push(Value::new(value::UNSPECIFIED)),
+            EOF => heap.stack.push(Value::new(value::EOF)),
+            FIXNUM => heap.stack.push(Value::new_fixnum(try!(self.number()) as usize)),
+            CHAR => {
+                match char::from_u32(try!(self.number()) as u32) {
+                    Some(c) => heap.stack.push(Value::new_char(c)),
+                    None => return self.error(),
+                }
+            }
+            FLOAT => try!(heap.alloc_float(f64::from_bits(try!(self.number())))),
+            SYMBOL => heap.intern(try!(self.string())),
+            STRING => try!(heap.alloc_string(try!(self.string()))),
+            LIST => {
+                let len = try!(self.count());
+                for _ in 0..len + 1 {
+                    try!(self.value(heap, constant))
+                }
+                for i in (base..base + len).rev() {
+                    let cdr = heap.stack.len() - 1;
+                    try!(heap.alloc_pair(i, cdr))
+                }
+            }
+            VECTOR => {
+                let len = try!(self.count());
+                for _ in 0..len {
+                    try!(self.value(heap, constant))
+                }
+                try!(heap.alloc_vector(base, base + len))
+            }
+            BYTEVECTOR => {
+                let len = try!(self.count());
+                try!(heap.alloc_bytevector_from(try!(self.bytes(len))))
+            }
+            BYTECODE => {
+                let len = try!(self.count());
+                let code = try!(self.bytes(len));
+                if len % 4 != 0 || code.chunks(4).any(|bytes| Opcode::from_u8(bytes[0]).is_none()) {
+                    return self.error()
+                }
+                try!(self.value(heap, constant));
+                if self.version == STACK_VERSION {
+                    try!(self.stack_bytecode(heap, code))
+                } else {
+                    try!(heap.alloc_bytecode(code))
+                }
+            }
+            PRIMITIVE => {
+                match builtins::lookup(try!(self.string())) {
+                    Some(primitive) => heap.stack.push(primitive.to_value()),
+                    None => return self.error(),
+                }
+            }
+            _ => return self.error(),
+        }
+        let value = heap.stack.pop().unwrap();
+        heap.stack.truncate(base);
+        if constant {
+            if let Kind::Bytecode(_) = value.kind() {
+            } else {
+                value.make_immutable();
+            }
+        }
+        Ok(heap.stack.push(value))
+    }
+
+    /// Makes a BCO of `code`, which is in the stack format, with the
+    /// constants on top of the stack, by translating it.
+    #[cfg(feature = "stack-bytecode")]
+    fn stack_bytecode(&self, heap: &mut Heap, code: &[u8]) -> Result<(), String> {
+        let (code, pcs) = try!(stack_bytecode::translate(code)
+                                   .map_err(|e| format!("{}: {}", self.file.display(), e)));
+        stack_bytecode::remap_locations(heap.stack.last().unwrap(), &pcs);
+        heap.alloc_bytecode(&code)
+    }
+
+    #[cfg(not(feature = "stack-bytecode"))]
+    fn stack_bytecode(&self, _: &mut Heap, _: &[u8]) -> Result<(), String> {
+        bug!("stack bytecode read without the stack-bytecode feature")
+    }
+
+    /// Reads and runs an entry.
+    fn entry(&mut self, heap: &mut Heap) -> Result<(), String> {
+        match try!(self.byte()) {
+            CODE => {
+                try!(self.value(heap, true));
+                interp::run_compiled(heap)
+            }
+            SOURCE => {
+                try!(self.value(heap, false));
+                interp::eval(heap, None)
+            }
+            REQUIRE => {
+                let mut parts = vec![];
+                for _ in 0..try!(self.count()) {
+                    parts.push(try!(self.string()).to_owned())
+                }
+                let name = format!("({})", parts.join(" "));
+                compiler::find_library(heap, &name, &parts, &None).map(|_| ())
+            }
+            LIBRARY => {
+                let name = try!(self.string()).to_owned();
+                let mut exports = vec![];
+                for _ in 0..try!(self.count()) {
+                    let name = try!(self.string()).to_owned();
+                    exports.push((name, try!(self.string()).to_owned()))
+                }
+                Ok(heap.macros.libraries.restore(name, exports))
+            }
+            _ => self.error(),
+        }
+    }
+}
+
+/// Runs the compiled file `file`, whose contents are `bytes`.
+pub fn load(heap: &mut Heap, file: &Path, bytes: &[u8]) -> Result<(), String> {
+    if !is_compiled(bytes) {
+        return Err(format!("{}: not a compiled file", file.display()))
+    }
+    try!(compiler::load_base(heap));
+    let mut reader = Reader {
+        file: file,
+        bytes: bytes,
+        position: MAGIC.len(),
+        version: 0,
+    };
+    reader.version = try!(reader.byte());
+    let stack = cfg!(feature = "stack-bytecode") && reader.version == STACK_VERSION;
+    if reader.version != VERSION && !stack {
+        return Err(format!("{}: compiled by another version", file.display()))
+    }
+    let base = heap.stack.len();
+    while reader.position < bytes.len() {
+        let result = reader.entry(heap);
+        heap.stack.truncate(base);
+        try!(result)
+    }
+    Ok(())
+}
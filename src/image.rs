@@ -0,0 +1,464 @@
+//! Interpreter images.
+//!
+//! An image holds what code run at top level can see again: the global
+//! variables, the libraries, and the macros and imports of the top level.
+//! `save` writes it out, and `load` restores it into another heap, so that
+//! a program whose setup takes long can start where the setup left off.
+//!
+//! The objects reachable from the global variables are written out once
+//! each, numbered in the order they are found, so sharing and cycles
+//! survive.  Macros cannot be written out, so the top-level forms that
+//! defined them are instead (see `Macros::remember`), and `load` evaluates
+//! them again, side effects included, before it sets the global variables.
+//! Libraries that export no macros are written out as their exports.
+//!
+//! An image is `MAGIC`, the version of the format, the libraries, the number
+//! of objects, a header for each object (its kind, what it holds besides
+//! values, and whether it is immutable), the values that the objects hold,
+//! the entries of the hash tables, the top-level forms, and the global
+//! variables, sorted by name.  A value is `REF` and the number of an object,
+//! or is written out as compiled files do (see `fasl`).
+//!
+//! Ports, weak tables, hash tables with custom procedures, Rust objects,
+//! native procedures, channels and threads cannot be written out: they hold
+//! what only makes sense in the process that made them, such as sockets.  Global variables bound to
+//! native procedures are skipped: the embedder defines them again.  Like a
+//! compiled file, an image is trusted: a corrupt one can corrupt the heap.
+
+use std::collections::HashMap;
+use std::path::Path;
+use alloc::{HashKind, Heap, Root};
+use builtins::{self, thread};
+use compiler;
+use fasl::{self, Reader, write_number, write_string};
+use interp;
+use print;
+use value::{self, Kind, Record, Value};
+
+/// The start of every image.
+const MAGIC: &'static [u8] = b"\0rusty-scheme image\0";
+
+/// The version of the format.  It must change whenever the format, the
+/// bytecode, or the layout of an object does.
+const VERSION: u8 = 1;
+
+// The kinds of objects.  Promises, multiple values and conditions are
+// records, whose descriptor word tells them apart.
+const PAIR: u8 = 0;
+const VECTOR: u8 = 1;
+const RECORD: u8 = 2;
+const CLOSURE: u8 = 3;
+const STRING: u8 = 4;
+const BYTEVECTOR: u8 = 5;
+const CODE: u8 = 6;
+const HASH_TABLE: u8 = 7;
+const RANDOM_SOURCE: u8 = 8;
+
+/// Starts a reference to an object.  Unused by `fasl`.
+const REF: u8 = 0xff;
+
+/// The objects of an image, by number.
+#[derive(Default)]
+struct Objects {
+    numbers: HashMap<usize, usize>,
+    objects: Vec<Value>,
+}
+
+impl Objects {
+    /// Numbers the objects reachable from `value` that are not numbered
+    /// yet.  The code of a closure is numbered before it.
+    fn add(&mut self, heap: &mut Heap, value: &Value) -> Result<(), String> {
+        let mut pending = vec![value.clone()];
+        while let Some(value) = pending.pop() {
+            if !is_object(&value) || self.numbers.contains_key(&value.get()) {
+                continue
+            }
+            if let Kind::Closure(closure) = value.kind() {
+                let code = unsafe { (*closure).code.clone() };
+                if is_object(&code) && !self.numbers.contains_key(&code.get()) {
+                    pending.push(value);
+                    pending.push(code);
+                    continue
+                }
+            }
+            self.numbers.insert(value.get(), self.objects.len());
+            self.objects.push(value.clone());
+            for value in try!(contents(heap, &value)).into_iter().rev() {
+                pending.push(value)
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes out `value`, as a reference if it is an object.
+    fn write_value(&self, out: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+        match self.numbers.get(&value.get()) {
+            Some(&number) => {
+                out.push(REF);
+                write_number(out, number as u64);
+                Ok(())
+            }
+            None => fasl::write_value(out, value),
+        }
+    }
+}
+
+/// Whether `value` is written out as an object, rather than in place.
+fn is_object(value: &Value) -> bool {
+    value.size().map_or(false, |size| size > 0) && !value.flonump()
+}
+
+/// Whether `value` is a weak table, which `Value::kind` does not know.
+fn is_weak_table(value: &Value) -> bool {
+    value.raw_tag() == value::RUST_DATA_TAG &&
+    unsafe {
+        let ptr = value.as_ptr();
+        (*ptr).get() & value::HEADER_TAG != value::HeaderTag::Bytecode as usize &&
+        (*ptr.offset(1)).get() == value::WEAK_TABLE_TYPE
+    }
+}
+
+/// The error for `value`, which cannot be written out.
+fn unsaveable(value: &Value) -> String {
+    if is_weak_table(value) {
+        return "save-image: cannot save a weak table".to_owned()
+    }
+    format!("save-image: cannot save {}", print::to_string(value, print::Mode::Write))
+}
+
+/// The words of `object` that hold values, as the offset of the first one
+/// and their number.
+fn slots(object: &Value) -> (usize, usize) {
+    match object.kind() {
+        Kind::Pair(_) => (1, 2),
+        Kind::Vector(vector) => (2, unsafe { (*vector).len() }),
+        Kind::Closure(closure) => (2, unsafe { (*closure).len() }),
+        Kind::Record(_) | Kind::Promise(_) | Kind::Values(_) | Kind::Condition(_) |
+        Kind::DynVector(_) => {
+            (1, unsafe { (*(object.as_ptr() as *mut Record)).len() } + 1)
+        }
+        _ => (0, 0),
+    }
+}
+
+/// The word at `offset` in `object`.
+unsafe fn slot(object: &Value, offset: usize) -> &Value {
+    &*object.as_ptr().offset(offset as isize)
+}
+
+/// The values that `object` holds, in the order they are written out.
+fn contents(heap: &mut Heap, object: &Value) -> Result<Vec<Value>, String> {
+    if is_weak_table(object) {
+        return Err(unsaveable(object))
+    }
+    if let Kind::HashTable(_) = object.kind() {
+        let entries = try!(heap.hash_table_entries(object));
+        return Ok(entries.into_iter().flat_map(|(key, value)| vec![key, value]).collect())
+    }
+    let (offset, len) = slots(object);
+    Ok((offset..offset + len).map(|i| unsafe { slot(object, i) }.clone()).collect())
+}
+
+/// Writes out the header of `object`.
+fn write_header(heap: &mut Heap, out: &mut Vec<u8>, objects: &Objects, object: &Value)
+                -> Result<(), String> {
+    match object.kind() {
+        Kind::Pair(_) => out.push(PAIR),
+        Kind::Vector(_) => {
+            out.push(VECTOR);
+            write_number(out, slots(object).1 as u64)
+        }
+        Kind::Record(_) | Kind::Promise(_) | Kind::Values(_) | Kind::Condition(_) |
+        Kind::DynVector(_) => {
+            out.push(RECORD);
+            write_number(out, slots(object).1 as u64)
+        }
+        Kind::Closure(_) if builtins::is_native(object) || thread::handle(object).is_some() => {
+            return Err(unsaveable(object))
+        }
+        Kind::Closure(closure) => {
+            out.push(CLOSURE);
+            write_number(out, slots(object).1 as u64);
+            try!(objects.write_value(out, unsafe { &(*closure).code }))
+        }
+        Kind::String(string) => {
+            out.push(STRING);
+            write_string(out, unsafe { (*string).as_str() })
+        }
+        Kind::Bytevector(bytevector) => {
+            let bytes = unsafe { (*bytevector).as_slice() };
+            out.push(BYTEVECTOR);
+            write_number(out, bytes.len() as u64);
+            out.extend_from_slice(bytes)
+        }
+        Kind::Bytecode(_) => {
+            out.push(CODE);
+            try!(fasl::write_value(out, object))
+        }
+        Kind::HashTable(_) => {
+            let kind = match try!(heap.hash_table_kind(object)) {
+                HashKind::Eq => 0,
+                HashKind::Eqv => 1,
+                HashKind::Equal => 2,
+                HashKind::Custom => return Err(unsaveable(object)),
+            };
+            out.push(HASH_TABLE);
+            out.push(kind)
+        }
+        Kind::RandomSource(source) => {
+            out.push(RANDOM_SOURCE);
+            write_number(out, unsafe { (*source).state })
+        }
+        _ => return Err(unsaveable(object)),
+    }
+    Ok(out.push(object.immutablep() as u8))
+}
+
+/// Writes out the image of `heap`.
+pub fn save(heap: &mut Heap) -> Result<Vec<u8>, String> {
+    let mut globals = vec![];
+    for symbol in heap.assigned_globals() {
+        let (name, value) = match symbol.kind() {
+            Kind::Symbol(ptr) if unsafe { (*ptr).bound.get() } => unsafe {
+                ((*ptr).name(), (*(*ptr).contents.get()).clone())
+            },
+            _ => continue,
+        };
+        if !builtins::is_native(&value) {
+            globals.push((name, value))
+        }
+    }
+    globals.sort_by(|a, b| a.0.cmp(&b.0));
+    let forms: Vec<Value> = heap.macros.toplevel_forms.iter().map(Root::get).collect();
+    let mut objects = Objects::default();
+    for value in forms.iter().chain(globals.iter().map(|&(_, ref value)| value)) {
+        try!(objects.add(heap, value))
+    }
+
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    let libraries: Vec<_> = heap.macros
+                                .libraries
+                                .defined()
+                                .into_iter()
+                                .filter_map(|name| {
+                                    let exports = heap.macros.libraries.saved_exports(&name);
+                                    exports.map(|exports| (name, exports))
+                                })
+                                .collect();
+    write_number(&mut out, libraries.len() as u64);
+    for (name, exports) in libraries {
+        write_string(&mut out, &name);
+        write_number(&mut out, exports.len() as u64);
+        for (name, target) in exports {
+            write_string(&mut out, &name);
+            write_string(&mut out, &target)
+        }
+    }
+    write_number(&mut out, objects.objects.len() as u64);
+    for object in &objects.objects {
+        try!(write_header(heap, &mut out, &objects, object))
+    }
+    for object in &objects.objects {
+        let (offset, len) = slots(object);
+        for i in offset..offset + len {
+            try!(objects.write_value(&mut out, unsafe { slot(object, i) }))
+        }
+    }
+    for object in &objects.objects {
+        if let Kind::HashTable(_) = object.kind() {
+            let entries = try!(heap.hash_table_entries(object));
+            write_number(&mut out, entries.len() as u64);
+            for (key, value) in entries {
+                try!(objects.write_value(&mut out, &key));
+                try!(objects.write_value(&mut out, &value))
+            }
+        }
+    }
+    write_number(&mut out, forms.len() as u64);
+    for form in &forms {
+        try!(objects.write_value(&mut out, form))
+    }
+    write_number(&mut out, globals.len() as u64);
+    for &(ref name, ref value) in &globals {
+        write_string(&mut out, name);
+        try!(objects.write_value(&mut out, value))
+    }
+    Ok(out)
+}
+
+/// Reads a value, and pushes it.  The objects are `stack[base..]`, of which
+/// the first `allocated` may be referred to.
+fn read_value(heap: &mut Heap, reader: &mut Reader, base: usize, allocated: usize)
+              -> Result<(), String> {
+    if reader.peek() == Some(REF) {
+        try!(reader.byte());
+        let number = try!(reader.number());
+        if number >= allocated as u64 {
+            return reader.error()
+        }
+        let object = heap.stack[base + number as usize].clone();
+        return Ok(heap.stack.push(object))
+    }
+    try!(reader.value(heap, false));
+    if let Kind::Primitive(primitive) = heap.stack.last().unwrap().kind() {
+        let name = unsafe { (*primitive).name };
+        if !heap.control.allows(name) {
+            return Err(format!("load-image: {} is not allowed", name))
+        }
+    }
+    Ok(())
+}
+
+/// Reads the header of an object, and pushes the object, holding `#f`
+/// instead of its values.  The objects so far are `stack[base..]`.
+fn alloc_object(heap: &mut Heap, reader: &mut Reader, base: usize) -> Result<(), String> {
+    fn placeholders(heap: &mut Heap, len: usize) {
+        for _ in 0..len {
+            heap.stack.push(Value::new(value::FALSE))
+        }
+    }
+    let start = heap.stack.len();
+    let result = match try!(reader.byte()) {
+        PAIR => {
+            placeholders(heap, 2);
+            heap.alloc_pair(start, start + 1)
+        }
+        VECTOR => {
+            let len = try!(reader.count());
+            placeholders(heap, len);
+            heap.alloc_vector(start, start + len)
+        }
+        RECORD => {
+            let len = try!(reader.count());
+            if len == 0 {
+                return reader.error()
+            }
+            placeholders(heap, len);
+            heap.alloc_record(start, start + len)
+        }
+        CLOSURE => {
+            let len = try!(reader.count());
+            try!(read_value(heap, reader, base, start - base));
+            match heap.stack[start].kind() {
+                Kind::Bytecode(_) | Kind::Primitive(_) => {}
+                _ => return reader.error(),
+            }
+            placeholders(heap, len);
+            heap.alloc_closure(start, start + len + 1)
+        }
+        STRING => heap.alloc_string(try!(reader.string())),
+        BYTEVECTOR => {
+            let len = try!(reader.count());
+            heap.alloc_bytevector_from(try!(reader.bytes(len)))
+        }
+        CODE => {
+            try!(reader.value(heap, true));
+            match heap.stack[start].kind() {
+                Kind::Bytecode(_) => Ok(()),
+                _ => return reader.error(),
+            }
+        }
+        HASH_TABLE => {
+            let kind = match try!(reader.byte()) {
+                0 => HashKind::Eq,
+                1 => HashKind::Eqv,
+                2 => HashKind::Equal,
+                _ => return reader.error(),
+            };
+            heap.alloc_hash_table(kind)
+        }
+        RANDOM_SOURCE => {
+            let state = try!(reader.number());
+            heap.alloc_random_source(state)
+        }
+        _ => return reader.error(),
+    };
+    let object = heap.stack.pop();
+    heap.stack.truncate(start);
+    try!(result);
+    Ok(heap.stack.push(object.unwrap()))
+}
+
+/// Reads the objects and what follows them, with the objects on the stack
+/// from `base`.
+fn load_objects(heap: &mut Heap, reader: &mut Reader, base: usize) -> Result<(), String> {
+    let count = try!(reader.count());
+    let mut immutable = vec![];
+    let mut hash_tables = vec![];
+    for i in 0..count {
+        try!(alloc_object(heap, reader, base));
+        if let Kind::HashTable(_) = heap.stack[base + i].kind() {
+            hash_tables.push(base + i)
+        }
+        if try!(reader.byte()) != 0 {
+            immutable.push(base + i)
+        }
+    }
+    for i in 0..count {
+        let (offset, len) = slots(&heap.stack[base + i]);
+        for j in offset..offset + len {
+            try!(read_value(heap, reader, base, count));
+            let value = heap.stack.pop().unwrap();
+            let object = heap.stack[base + i].clone();
+            unsafe { slot(&object, j).set(value.clone()) }
+            heap.write_barrier(&object, &value)
+        }
+    }
+    for table in hash_tables {
+        for _ in 0..try!(reader.count()) {
+            let key = heap.stack.len();
+            try!(read_value(heap, reader, base, count));
+            try!(read_value(heap, reader, base, count));
+            let result = heap.hash_table_set(table, key, key + 1);
+            heap.stack.truncate(key);
+            try!(result)
+        }
+    }
+    for object in immutable {
+        heap.stack[object].make_immutable();
+    }
+    for _ in 0..try!(reader.count()) {
+        try!(read_value(heap, reader, base, count));
+        try!(interp::eval(heap, None));
+        heap.stack.pop();
+    }
+    for _ in 0..try!(reader.count()) {
+        let name = try!(reader.string());
+        try!(read_value(heap, reader, base, count));
+        heap.intern(name);
+        let symbol = heap.stack.pop().unwrap();
+        let value = heap.stack.pop().unwrap();
+        try!(heap.set_global(&symbol, value))
+    }
+    match reader.peek() {
+        Some(_) => reader.error(),
+        None => Ok(()),
+    }
+}
+
+/// Restores the image `bytes` into `heap`.
+pub fn load(heap: &mut Heap, bytes: &[u8]) -> Result<(), String> {
+    if !bytes.starts_with(MAGIC) {
+        return Err("load-image: not an image".to_owned())
+    }
+    let mut reader = Reader::new(Path::new("image"), &bytes[MAGIC.len()..]);
+    if try!(reader.byte()) != VERSION {
+        return Err("load-image: saved by another version".to_owned())
+    }
+    // The global variables of the image then override those of the base.
+    try!(compiler::load_base(heap));
+    for _ in 0..try!(reader.count()) {
+        let name = try!(reader.string()).to_owned();
+        let mut exports = vec![];
+        for _ in 0..try!(reader.count()) {
+            let name = try!(reader.string()).to_owned();
+            exports.push((name, try!(reader.string()).to_owned()))
+        }
+        heap.macros.libraries.restore(name, exports)
+    }
+    let base = heap.stack.len();
+    let result = load_objects(heap, &mut reader, base);
+    heap.stack.truncate(base);
+    result
+}
@@ -1,332 +1,3950 @@
 //! The interpreter for `RustyScheme`.
 //!
 //! This is the part of `RustyScheme` that actually executes `RustyScheme`
-//! bytecode.  It is a simple `match`-based interpreter.  Future optimizations
-//! include using tail calls to implement the equivalent of computed gotos.
+//! bytecode, as produced by the compiler.  It is a simple `match`-based
+//! interpreter, or, with the `threaded-dispatch` feature, one that calls a
+//! handler for each opcode from a table (see `dispatch`).  `benches/`
+//! compares the two.
 //!
-//! The entry point is in `self::interpret_bytecode`.  Upon entering this
-//! function (ex. from a Rust API call), the called function must be at the
-//! bottom of the stack, followed by the arguments.
+//! The entry point is `call`.  Upon entering this function (ex. from a Rust
+//! API call), the called procedure must be on the stack, followed by its
+//! arguments.
 //!
-//! Upon a Scheme->Scheme function call, the data stack layout is:
+//! Each active Scheme procedure has a frame on the data stack:
 //!
 //! |--------------------|
-//! | arguments          |
-//! |--------------------|
-//! | caller function    |
-//! |--------------------|
-//!
-//! and the control stack layout is:
-//!
+//! | temporaries        |
 //! |--------------------|
-//! | return address     |
+//! | local variables    |
 //! |--------------------|
-//! | old frame pointer  |
+//! | arguments          |
 //! |--------------------|
-//! | captured?          |
+//! | closure            |  <- frame pointer
 //! |--------------------|
 //!
-//! but these three objects are all held in a single Rust struct.
+//! The closure is the procedure being executed.  Everything above it is
+//! the procedure's registers, which instructions name by number, and which
+//! `Enter` makes room for (see the `bytecode` module).  A call puts the
+//! callee and its arguments in consecutive registers, drops the registers
+//! above them, and saves the caller's frame pointer and return address on
+//! the control stack, which holds only integers: the callee's frame starts
+//! where the callee is, and its result is left there.  The caller's other
+//! registers are then restored to their full number.  A tail call instead
+//! moves the callee and its arguments down over the current frame, so that
+//! loops written as tail calls run in constant space.
 //!
-//! `const STACK_OFFSET: usize` holds the difference between the old stack
-//! pointer and the new frame pointer. `captured?` holds whether the Scheme
-//! environment has been captured.
-
-use std::ptr;
-use value;
-use alloc;
-use arith;
-
-use bytecode::{Bytecode, Opcode};
-
-const STACK_OFFSET: usize = 1;
+//! Primitives are called directly by `builtins::call`.  A primitive that
+//! calls a Scheme procedure (such as `call-with-values`) does so through
+//! `call`, which runs a nested interpreter loop.
+//!
+//! `call/cc` copies the stack and control stack of the running loop into a
+//! continuation object, and invoking it copies them back (see `Control`).
+//! The rest of the computation of a primitive that called back into Scheme
+//! is not part of the continuation.
 
-pub struct ActivationRecord {
-    return_address: usize,
-    frame_pointer: usize,
-    captured: bool,
-}
+use std::collections::VecDeque;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{self, Poll};
+use alloc::{self, Location, Root};
+use builtins;
+use equiv;
+use fasl;
+use platform::{Console, StdConsole};
+use port;
+use print;
+use bytecode::{self, BCO, Bytecode, Opcode};
+use value::{self, Kind, Value};
 
-/// The Scheme state.  It has several parts:
-///
-/// - the program counter (`program_counter`), which stores the current
-///   bytecode instruction position.
-/// - the stack pointer `sp`, which stores the current stack position.
-/// - the control stack `control_stack`, which stores control flow
-///   information.
-/// - The environment pointer `env`, which (if non-NULL) points to the current
-///   environment.
-/// - the bytecode `bytecode`, which stores the bytecode currently being
-///   executed.
+/// The Scheme state.  All of it is in the heap: the stack holds the data of
+/// the running procedures, and `Control` their control stacks.
 pub struct State {
-    program_counter: usize,
-    sp: usize,
-    control_stack: Vec<ActivationRecord>,
-    bytecode: Vec<Bytecode>,
     pub heap: alloc::Heap,
 }
 
 /// Create a new Scheme interpreter
 pub fn new() -> self::State {
     State {
-        program_counter: 0,
-        sp: 0,
-        control_stack: vec![],
         heap: alloc::Heap::new(1 <<
                                if cfg!(debug_assertions) {
             4
         } else {
             16
         }),
-        bytecode: vec![],
-    }
-}
-
-
-/// This function interprets the Scheme bytecode.
-pub fn interpret_bytecode(s: &mut State) -> Result<(), String> {
-    let pc = &mut s.program_counter;
-    let heap = &mut s.heap;
-    heap.environment = ptr::null_mut();
-    let sp = &mut s.sp;
-    let mut fp = 0;
-    loop {
-        let Bytecode { opcode, src, src2, dst } = s.bytecode[*pc];
-        let (src, src2, dst): (usize, usize, usize) = (src.into(), src2.into(), dst.into());
-        // let len = heap.stack.len();
-        match opcode {
-            Opcode::Cons => {
-                heap.alloc_pair(src, src2);
-                heap.stack[dst] = heap.stack.pop().unwrap();
-                *pc += 1;
-            }
-            Opcode::Car => {
-                heap.stack[dst] = try!(heap.stack[src]
-                                           .car()
-                                           .map_err(|()| {
-                                               "Attempt to take the \
-                                                car of a non-pair"
-                                                   .to_owned()
-                                           }));
-                *pc += 1;
-            }
-            Opcode::Cdr => {
-                heap.stack[dst] = try!(heap.stack[src]
-                                           .cdr()
-                                           .map_err(|()| {
-                                               "Attempt to take the \
-                                                cdr of a non-pair"
-                                                   .to_owned()
-                                           }));
-                *pc += 1;
-            }
-            Opcode::SetCar => {
-                try!(heap.stack[dst]
-                         .set_car(heap.stack[src].clone())
-                         .map_err(|()| "Attempt to set the car of a non-pair".to_owned()));
-                *pc += 1;
-            }
-            Opcode::SetCdr => {
-                try!(heap.stack[dst]
-                         .set_cdr(heap.stack[src].clone())
-                         .map_err(|()| "Attempt to set the cdr of a non-pair".to_owned()));
-                *pc += 1;
-            }
-            Opcode::Set => {
-                heap.stack[dst] = heap.stack[src].clone();
-                *pc += 1;
-            }
-            Opcode::Add => {
-                // The hot paths are fixnums and flonums.  They are inlined.
-                // Most scripts probably do not heavily use complex numbers.
-                // Bignums or rationals will always be slow.
-                let (fst, snd) = (heap.stack[src].get(), heap.stack[src2].get());
-                heap.stack.push(if fst & snd & 3 == 0 {
-                    value::Value::new(fst.wrapping_add(snd)) // TODO: bignumx
-                } else {
-                    return Err("wrong type to add".to_owned());
-                });
-                *pc += 1;
-            }
+    }
+}
 
-            Opcode::Subtract => {
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                // See above.
-                heap.stack[dst] = try!(arith::subtract(heap, &fst, &snd));
-                *pc += 1;
-            }
+/// A suspended procedure call.
+#[derive(Clone, Copy, Debug)]
+struct Frame {
+    /// The frame pointer of the caller.
+    fp: usize,
 
-            Opcode::Multiply => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = try!(arith::multiply(heap, &fst, &snd));
-                *pc += 1;
-            }
+    /// Where to continue the caller.
+    pc: usize,
+}
 
-            Opcode::Divide => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = try!(arith::divide(heap, &fst, &snd));
-                *pc += 1;
-            }
+/// The registers of an activation of `run`.
+struct Registers {
+    /// The serial number of the activation (see `Control`).
+    serial: usize,
 
-            Opcode::Power => {
-                // See above.
-                let (fst, snd) = (heap.stack[src].clone(), heap.stack[src2].clone());
-                heap.stack[dst] = arith::exponential(fst, snd);
-                *pc += 1;
-            }
+    /// The stack index of the first frame.
+    base: usize,
 
-            Opcode::Closure => {
-                heap.alloc_closure(src as u8, src2 as u8, dst);
-                let len = heap.stack.len();
-                heap.environment = unsafe { heap.stack[len - 1].as_ptr() } as *mut value::Vector;
-                *pc += 1;
-            }
+    /// The frame pointer.
+    fp: usize,
 
-            Opcode::MakeArray => {
-                alloc::Heap::alloc_vector(heap, src, src2);
-                *pc += 1;
-            }
+    /// The index of the next instruction.
+    pc: usize,
 
-            Opcode::SetArray => {
-                let index = try!(heap.stack[src].as_fixnum());
-                try!(heap.stack[dst].array_set(index, &heap.stack[src2]));
-                *pc += 1;
-            }
+    /// Where the control stack of the activation starts in
+    /// `Control::frames`.
+    frames: usize,
 
-            Opcode::GetArray => {
-                let index = try!(heap.stack[src].as_fixnum());
-                heap.stack[dst] = try!(heap.stack[src2]
-                                           .array_get(index)
-                                           .map(|ptr| unsafe { (*ptr).clone() }));
-                *pc += 1;
-            }
+    /// The frame that was running in the enclosing activation, if it was
+    /// running an instruction.
+    outer: Option<Frame>,
+}
 
-            // Frame layout: activation record below rest of data
-            Opcode::Call => {
-                let frame_pointer = *sp - src - 1;
-                s.control_stack.push(ActivationRecord {
-                    return_address: *pc,
-                    frame_pointer: frame_pointer,
-                    captured: !heap.environment.is_null(),
-                });
-                *pc = 0;
-                *sp = heap.stack.len();
-                fp = frame_pointer;
-            }
+/// A fiber that is not running (see `Control`).
+#[derive(Debug)]
+pub struct Fiber {
+    /// Its wind list, handler stack and parameterization, followed by its
+    /// part of the stack, which starts at the base of the outermost
+    /// activation.
+    pub stack: Vec<Value>,
 
-            Opcode::LoadFalse => {
-                heap.stack.push(value::Value::new(value::FALSE));
-            }
+    /// Its control stack, with frame pointers relative to its part of the
+    /// stack, and ending with its current frame.  Empty if it has not
+    /// started yet.
+    frames: Vec<Frame>,
 
-            Opcode::LoadTrue => {
-                heap.stack.push(value::Value::new(value::TRUE));
-            }
+    /// Whether it is the main fiber.
+    main: bool,
+}
 
-            Opcode::LoadNil => heap.stack.push(value::Value::new(value::NIL)),
-            Opcode::TailCall => {
-                let (first, rest) = heap.stack.split_at_mut(*sp - src - 1);
-                *pc = 0;
-                *sp = fp + src + 1;
-                first[fp..*sp].clone_from_slice(rest);
-            }
+/// A future that the suspended fiber waits for (see `Control`).
+struct Await {
+    /// The name of the procedure that returned it, for error messages.
+    name: String,
 
-            Opcode::Return => {
-                if let Some(return_frame) = s.control_stack.pop() {
-                    *sp = fp;
-                    *pc = return_frame.return_address;
-                    fp = return_frame.frame_pointer
-                } else {
-                    return Ok(());
-                }
-            }
+    /// The future, until it completes.
+    future: Option<Wait>,
 
-            Opcode::LoadEnvironment => {
-                let to_be_pushed = if heap.environment.is_null() {
-                    heap.stack[src + fp].clone()
-                } else {
-                    unsafe {
-                        (*value::Value::raw_array_get(heap.environment as *const _, src).unwrap())
-                            .clone()
-                    }
-                };
-                heap.stack.push(to_be_pushed.clone());
-                *pc += 1;
-            }
+    /// Its result, once it has completed.
+    result: Option<Result<Root, String>>,
 
-            Opcode::LoadConstant => {
-                let x = unsafe {
-                    (*value::Value::raw_array_get(heap.constants, src).unwrap()).clone()
-                };
-                heap.stack.push(x);
-                *pc += 1;
-            }
+    /// The slot of the call's result, relative to the base of the
+    /// activation.
+    slot: usize,
+
+    /// Whether the call was a tail call, so that the frame returns the
+    /// result.
+    returns: bool,
+
+    /// For a call to a primitive that would have blocked, the number of
+    /// its arguments, since it is made again once the port is ready.
+    retry: Option<usize>,
+}
+
+/// The kinds of futures that a fiber waits for.
+enum Wait {
+    /// The future that an async native procedure returned, of its result.
+    Result(builtins::Pending),
+
+    /// The readiness of a port that would have blocked.
+    Ready(port::Readiness),
+}
+
+impl fmt::Debug for Await {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Await")
+         .field("name", &self.name)
+         .field("result", &self.result)
+         .field("slot", &self.slot)
+         .field("returns", &self.returns)
+         .field("retry", &self.retry)
+         .finish()
+    }
+}
+
+/// The state of deterministic mode, in which the clock and the entropy
+/// that random sources are seeded from are derived from a seed.
+#[derive(Clone, Debug)]
+pub struct Deterministic {
+    /// The seed.
+    pub seed: u64,
+
+    /// The time on the virtual clock, in nanoseconds since the epoch.  It
+    /// starts at the epoch, and moves forward a microsecond each time it is
+    /// read.
+    pub nanoseconds: u64,
+
+    /// The state of the generator that entropy is drawn from.
+    pub entropy: u64,
+}
+
+/// The interpreter state that is kept outside of the stack.
+///
+/// Each activation of `run` has a serial number, which the continuations
+/// captured in it record.  A continuation is invoked by unwinding, with an
+/// error, to the activation that captured it, which then reinstates it.  If
+/// that activation has already returned, the innermost one does so instead.
+///
+/// The wind list holds the `before` and `after` thunks of the active calls
+/// to `dynamic-wind`.  Each continuation records the wind list, and
+/// reinstating it runs the thunks needed to get from one list to the other.
+///
+/// The handler stack holds the exception handlers installed by
+/// `with-exception-handler`.  Continuations record it too.  An error that a
+/// primitive or the interpreter itself returns is raised as a condition, if
+/// there is a handler for it.
+///
+/// The parameterization holds the values that `parameterize` gives to
+/// parameter objects.  Continuations record it as well, so a parameter
+/// has the value it had when the continuation was captured.
+///
+/// Setting the interrupt flag, for example from a signal handler or another
+/// thread, makes the running code raise a continuable condition of type
+/// `interrupt` at its next safe point: a procedure call, or a jump
+/// backwards.  If the handler returns, the code goes on where it was
+/// interrupted.  With no handler, it fails with "Interrupted".
+///
+/// The fibers made by `spawn` run in the outermost activation, taking
+/// turns with the main fiber, which is the computation that the
+/// activation was started with.  A fiber that calls `yield` is suspended:
+/// its part of the stack and its control stack are moved to the end of the
+/// fiber queue, and the first fiber in the queue takes their place.  Each
+/// fiber has its own wind list, handler stack and parameterization, which
+/// switching does not run any thunks for.  When a fiber other than the
+/// main one returns, its result is dropped and the next fiber runs; an
+/// error in it ends the activation, like an error in the main fiber.
+/// Fibers still in the queue when the main fiber returns run when it next
+/// yields.
+///
+/// The fuel is the number of instructions that may still run.  When it
+/// runs out, the running fiber of the outermost activation is suspended
+/// like one that yields, but kept aside, so that the embedder can resume it
+/// later (see `execute_with_fuel`).  If it runs out in an inner activation,
+/// which cannot be suspended, the code fails with "Out of fuel" instead.
+/// That error is not raised as a condition, so handlers cannot catch it.
+///
+/// A call to an async native procedure from the outermost activation
+/// suspends the running fiber the same way, until the future that the
+/// procedure returned completes (see `poll_await`).  Resuming the fiber
+/// then returns the result of the future from the call, or fails with its
+/// error, which is raised as a condition.  Code that is not run with fuel
+/// cannot be resumed, so there the call fails instead.
+/// A call to a primitive that fails because a port would block (see
+/// `port::WOULD_BLOCK`) suspends the fiber in the same way, until the port
+/// is ready, and is then made again, or fails where the fiber could not
+/// be resumed.
+///
+/// The control stacks of the running activations are kept here, with the
+/// frame that is running, so that the collector can find the code of each
+/// frame on the stack, and its stack map (see `alloc::stack_map`).
+///
+/// The stack grows as procedures are called, up to the stack limit, past
+/// which a call fails with "Stack overflow".  That error is an ordinary
+/// one, and its handlers may use `STACK_RESERVE` more slots of the stack; a
+/// call past the reserve fails for good.  Activations nest on the Rust
+/// stack, so there may be no more than `MAX_ACTIVATIONS` of them.
+#[derive(Debug)]
+pub struct Control {
+    /// The serial numbers of the running activations, innermost last.
+    activations: Vec<usize>,
+
+    /// The serial number of the next activation.
+    next_activation: usize,
+
+    /// The control stacks of the running activations, outermost first.
+    /// Each one but the innermost is followed by the frame that was
+    /// running in its activation when the next one started, if it was
+    /// running an instruction.
+    frames: Vec<Frame>,
+
+    /// The frame of the instruction that the innermost activation is
+    /// running, with the index of the next one, if it is running one.
+    current: Option<Frame>,
+
+    /// The continuation being invoked, and the value passed to it.
+    throw: Option<(Root, Root)>,
+
+    /// The wind list: `(before . after)` pairs, innermost first.  `None`
+    /// if it is empty.
+    winders: Option<Root>,
+
+    /// The handler stack, innermost first.  `None` if it is empty.
+    handlers: Option<Root>,
+
+    /// The parameterization: `(parameter . value)` pairs, innermost first.
+    /// `None` if it is empty.
+    parameterization: Option<Root>,
+
+    /// Whether the error being returned has been raised already, and so
+    /// must not be raised again by the activations it unwinds.
+    raised: bool,
+
+    /// The code of each `Procedure`, once it has been made.
+    procedures: [Option<Root>; 4],
+
+    /// The fibers waiting to run, next first.  The collector scans their
+    /// stacks.
+    pub fibers: VecDeque<Fiber>,
+
+    /// Whether the running fiber is one made by `spawn`, rather than the
+    /// main fiber.
+    spawned: bool,
+
+    /// The number of instructions that may still run.  `usize::MAX` when
+    /// there is no limit.
+    fuel: usize,
+
+    /// The fuel that code run from Rust starts with.  `usize::MAX` when
+    /// there is no limit.
+    fuel_limit: usize,
+
+    /// The fiber that ran out of fuel last, until it is resumed.
+    pub suspended: Option<Fiber>,
+
+    /// The number of times that the fuel has run out or a future has been
+    /// awaited, which numbers the suspended fiber.
+    suspensions: usize,
+
+    /// Whether the native procedure being called may return a future,
+    /// since the interpreter can wait for it.
+    awaitable: bool,
+
+    /// Whether the code is run by `with_fuel`, so that a suspended fiber
+    /// can be resumed.
+    resumable: bool,
+
+    /// What the suspended fiber waits for, if it waits for a future.
+    awaiting: Option<Await>,
+
+    /// What the primitive that just failed because a port would block
+    /// waits for, until the interpreter sees the failure.
+    blocked: Option<Await>,
+
+    /// The calls unwound by the error being returned, innermost first.
+    backtrace: Vec<BacktraceFrame>,
+
+    /// The message of the last exception that no handler caught, and the
+    /// object that was raised.
+    uncaught: Option<(String, Root)>,
+
+    /// The interrupt flag, if there is one.
+    interrupt: Option<&'static AtomicBool>,
+
+    /// The number of slots past which the stack overflows.
+    stack_limit: usize,
+
+    /// Whether the stack has overflowed, and not yet gone back below the
+    /// limit, so that the reserve is in use.
+    overflowed: bool,
+
+    /// What `command-line` returns.
+    command_line: Vec<String>,
+
+    /// The groups of primitives withheld from the code (see `Heap::deny`).
+    denied: Vec<builtins::Group>,
+
+    /// The state of deterministic mode, if it is on.
+    pub deterministic: Option<Deterministic>,
+
+    /// Where the standard ports read and write.
+    pub console: Rc<dyn Console>,
+
+    /// The standard input, output and error ports, once they have been
+    /// made (see `builtins::port`).
+    pub standard_ports: [Option<Root>; 3],
+
+    /// The default random source, once it has been made (see
+    /// `builtins::random`).
+    pub default_random_source: Option<Root>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Control {
+            activations: vec![],
+            next_activation: 0,
+            frames: vec![],
+            current: None,
+            throw: None,
+            winders: None,
+            handlers: None,
+            parameterization: None,
+            raised: false,
+            procedures: [None, None, None, None],
+            fibers: VecDeque::new(),
+            spawned: false,
+            fuel: usize::max_value(),
+            fuel_limit: usize::max_value(),
+            suspended: None,
+            suspensions: 0,
+            awaitable: false,
+            resumable: false,
+            awaiting: None,
+            blocked: None,
+            backtrace: vec![],
+            uncaught: None,
+            interrupt: None,
+            stack_limit: DEFAULT_STACK_LIMIT,
+            overflowed: false,
+            command_line: vec![],
+            denied: vec![],
+            deterministic: None,
+            console: Rc::new(StdConsole),
+            standard_ports: [None, None, None],
+            default_random_source: None,
+        }
+    }
+
+    /// The command line that the program was run with.
+    pub fn command_line(&self) -> &[String] {
+        &self.command_line
+    }
+
+    /// Sets the command line: the name of the program, and its arguments.
+    pub fn set_command_line(&mut self, arguments: Vec<String>) {
+        self.command_line = arguments
+    }
+
+    /// Makes `flag` the interrupt flag.
+    pub fn set_interrupt(&mut self, flag: &'static AtomicBool) {
+        self.interrupt = Some(flag)
+    }
+
+    /// Sets the number of slots past which the stack overflows.
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack_limit = limit
+    }
+
+    /// Limits each run of code from Rust to `fuel` instructions, past which
+    /// it fails with "Out of fuel".
+    pub fn set_fuel_limit(&mut self, fuel: usize) {
+        self.fuel_limit = fuel;
+        self.fuel = fuel
+    }
+
+    /// Withholds the primitives of `group` from the code.
+    pub fn deny(&mut self, group: builtins::Group) {
+        if !self.denied.contains(&group) {
+            self.denied.push(group)
+        }
+    }
+
+    /// The groups of primitives withheld from the code.
+    pub fn denied(&self) -> &[builtins::Group] {
+        &self.denied
+    }
+
+    /// The fuel that code run from Rust starts with.
+    pub fn fuel_limit(&self) -> usize {
+        self.fuel_limit
+    }
+
+    /// Whether the primitive `name` may be bound to its global variable.
+    pub fn allows(&self, name: &str) -> bool {
+        !self.denied.iter().any(|group| group.contains(name))
+    }
+
+    /// Derives the clock and entropy from `seed` from now on.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.deterministic = Some(Deterministic {
+            seed: seed,
+            nanoseconds: 0,
+            entropy: !seed,
+        })
+    }
+
+    /// Makes the standard ports read and write through `console`, from
+    /// their next use on.
+    pub fn set_console(&mut self, console: Rc<dyn Console>) {
+        self.console = console;
+        self.standard_ports = [None, None, None]
+    }
+
+    /// Whether deterministic mode is on.
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic.is_some()
+    }
+
+    /// Fails if the stack, which has `len` slots, is too deep to call a
+    /// procedure.
+    fn check_stack(&mut self, len: usize) -> Result<(), String> {
+        if len <= self.stack_limit {
+            self.overflowed = false
+        } else if !self.overflowed {
+            self.overflowed = true;
+            return Err(STACK_OVERFLOW.to_owned())
+        } else if len > self.stack_limit.saturating_add(STACK_RESERVE) {
+            self.raised = true;
+            return Err(STACK_OVERFLOW.to_owned())
+        }
+        Ok(())
+    }
+
+    /// Fails if the fuel has run out, and otherwise uses one unit of it.
+    #[inline(always)]
+    fn burn_fuel(&mut self) -> Result<(), String> {
+        if self.fuel == 0 {
+            self.raised = true;
+            return Err(OUT_OF_FUEL.to_owned())
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /// Checks if the native procedure being called may return a future,
+    /// which only one called by `call_async` may.
+    pub fn take_awaitable(&mut self) -> bool {
+        ::std::mem::replace(&mut self.awaitable, false)
+    }
+
+    /// Makes the running fiber wait for `future`, which the async native
+    /// procedure `name` returned, once the call returns to `call_async`.
+    pub fn await_future(&mut self, name: String, future: builtins::Pending) {
+        self.awaiting = Some(Await {
+            name: name,
+            future: Some(Wait::Result(future)),
+            result: None,
+            slot: 0,
+            returns: false,
+            retry: None,
+        })
+    }
+
+    /// Records that the primitive being called failed because a port would
+    /// block, until `ready` completes.
+    pub fn block(&mut self, ready: port::Readiness) {
+        self.blocked = Some(Await {
+            name: String::new(),
+            future: Some(Wait::Ready(ready)),
+            result: None,
+            slot: 0,
+            returns: false,
+            retry: Some(0),
+        })
+    }
+
+    /// Whether the suspended fiber waits for a future.
+    pub fn awaits(&self) -> bool {
+        self.suspended.is_some() && self.awaiting.is_some()
+    }
+
+    /// Checks if the interrupt flag is set, clearing it.
+    fn interrupted(&mut self) -> bool {
+        match self.interrupt {
+            Some(flag) => flag.load(Ordering::Relaxed) && flag.swap(false, Ordering::SeqCst),
+            None => false,
+        }
+    }
+
+    /// The calls unwound by the last error that was returned to Rust code
+    /// outside of the interpreter, innermost first.
+    pub fn backtrace(&self) -> &[BacktraceFrame] {
+        &self.backtrace
+    }
+
+    /// The object raised by the last error that was returned to Rust code
+    /// outside of the interpreter, if that error is `message` and was an
+    /// exception that no handler caught.  Errors returned by primitives
+    /// with no handler installed are never raised, so they have no object.
+    pub fn uncaught(&self, message: &str) -> Option<&Root> {
+        match self.uncaught {
+            Some((ref uncaught, ref object)) if uncaught == message => Some(object),
+            _ => None,
+        }
+    }
+
+    /// Forgets the last error returned to Rust code, and refills the fuel
+    /// for the code about to run.
+    fn clear_error(&mut self) {
+        self.fuel = self.fuel_limit;
+        self.blocked = None;
+        self.backtrace.clear();
+        self.uncaught = None
+    }
+
+    /// The wind list.
+    fn winders(&self) -> Value {
+        self.winders.as_ref().map_or(Value::new(value::NIL), Root::get)
+    }
+
+    /// The handler stack.
+    fn handlers(&self) -> Value {
+        self.handlers.as_ref().map_or(Value::new(value::NIL), Root::get)
+    }
 
-            Opcode::LoadArgument => {
-                let x = heap.stack[fp + src].clone();
-                heap.stack.push(x);
-                *pc += 1;
+    /// The parameterization.
+    fn parameterization(&self) -> Value {
+        self.parameterization.as_ref().map_or(Value::new(value::NIL), Root::get)
+    }
+
+    /// The value given to `parameter` by the innermost `parameterize` of
+    /// it, if there is one.
+    pub fn parameter(&self, parameter: &Value) -> Option<Value> {
+        let mut list = self.parameterization();
+        while let Ok(binding) = list.car() {
+            if equiv::eq(&binding.car().unwrap(), parameter) {
+                return binding.cdr().ok()
             }
+            list = list.cdr().unwrap()
+        }
+        None
+    }
+
+    /// Records the start of an activation, returning its serial number.
+    fn enter(&mut self) -> usize {
+        if self.activations.is_empty() {
+            self.raised = false
+        }
+        let serial = self.next_activation;
+        self.next_activation += 1;
+        self.activations.push(serial);
+        serial
+    }
+
+    /// The frame pointer and the index of the next instruction of each
+    /// frame of the running activations, outermost first.
+    pub fn running_frames(&self) -> Vec<(usize, usize)> {
+        self.frames.iter().chain(self.current.iter()).map(|frame| (frame.fp, frame.pc)).collect()
+    }
+
+    /// The number of running activations.
+    pub fn depth(&self) -> usize {
+        self.activations.len()
+    }
+
+    /// Records the end of the innermost activation.
+    fn leave(&mut self) {
+        self.activations.pop();
+        if self.activations.is_empty() {
+            self.raised = false
+        }
+    }
 
-            Opcode::StoreArgument => {
-                let x = heap.stack.pop().unwrap();
-                heap.stack[fp + src] = x;
-                *pc += 1;
+    /// Checks if the activation numbered `serial` must reinstate the
+    /// continuation being invoked.
+    fn catches(&self, serial: usize) -> bool {
+        match self.throw {
+            Some((ref continuation, _)) => {
+                let target = unsafe { continuation_part(&continuation.get(), 0) }
+                                 .as_fixnum()
+                                 .unwrap();
+                target == serial || !self.activations.contains(&target)
             }
+            None => false,
+        }
+    }
+}
+
+/// The list `list` as a root, or `None` if it is empty.
+fn root_list(heap: &alloc::Heap, list: Value) -> Option<Root> {
+    if list.get() == value::NIL {
+        None
+    } else {
+        Some(heap.root(list))
+    }
+}
+
+/// Replaces the wind list with `list`.
+fn set_winders(heap: &mut alloc::Heap, list: Value) {
+    heap.control.winders = root_list(heap, list)
+}
+
+/// Replaces the handler stack with `list`.
+fn set_handlers(heap: &mut alloc::Heap, list: Value) {
+    heap.control.handlers = root_list(heap, list)
+}
+
+/// Replaces the parameterization with `list`.
+fn set_parameterization(heap: &mut alloc::Heap, list: Value) {
+    heap.control.parameterization = root_list(heap, list)
+}
+
+/// Adds a fiber that calls `thunk` to the end of the fiber queue.
+pub fn spawn(heap: &mut alloc::Heap, thunk: Value) -> Result<(), String> {
+    let thunk = heap.root::<Value>(thunk);
+    let code = try!(procedure_code(heap, Procedure::Fiber));
+    let stack = vec![Value::new(value::NIL),
+                     heap.control.handlers(),
+                     heap.control.parameterization(),
+                     code,
+                     thunk.get()];
+    heap.control.fibers.push_back(Fiber {
+        stack: stack,
+        frames: vec![],
+        main: false,
+    });
+    Ok(())
+}
+
+/// Checks if `yield` can switch to another fiber: it is called in the
+/// outermost activation, and there is another fiber.
+fn can_switch(heap: &alloc::Heap) -> bool {
+    heap.control.activations.len() == 1 && !heap.control.fibers.is_empty()
+}
+
+/// Checks if a call can wait for a future: it is made in the outermost
+/// activation, which can be resumed.
+fn can_await(heap: &alloc::Heap) -> bool {
+    heap.control.activations.len() == 1 && heap.control.resumable
+}
+
+/// Calls the async native procedure `nargs + 1` slots from the top of the
+/// stack, letting it return a future, which `Control::awaiting` then holds.
+fn call_async(heap: &mut alloc::Heap, nargs: usize) -> Result<(), String> {
+    heap.control.awaitable = true;
+    let result = builtins::call(heap, nargs);
+    heap.control.awaitable = false;
+    result
+}
+
+/// Stops the activation `r` after a call to an async native procedure, so
+/// that its fiber is suspended until the future completes.  The result of
+/// the future goes to `stack[slot]`, where the result of the call is, and
+/// if `returns`, the frame then returns it.
+fn await_result(heap: &mut alloc::Heap,
+                r: &Registers,
+                slot: usize,
+                returns: bool)
+                -> Result<bool, String> {
+    let awaiting = heap.control.awaiting.as_mut().unwrap();
+    awaiting.slot = slot - r.base;
+    awaiting.returns = returns;
+    heap.control.raised = true;
+    Err(AWAITING.to_owned())
+}
+
+/// Handles the error `e` of a call to the primitive at `stack[callee]` with
+/// `nargs` arguments from the activation `r`.  If the primitive failed
+/// because a port would block, and the call can wait, the activation is
+/// stopped so that its fiber is suspended until the port is ready, and the
+/// call is then made again, as a tail call if `returns`.
+fn blocked(heap: &mut alloc::Heap,
+           r: &Registers,
+           callee: usize,
+           nargs: usize,
+           returns: bool,
+           e: String)
+           -> Result<bool, String> {
+    match heap.control.blocked.take() {
+        Some(blocked) if can_await(heap) => {
+            heap.stack.truncate(callee + 1 + nargs);
+            heap.control.awaiting = Some(Await { retry: Some(nargs), ..blocked });
+            await_result(heap, r, callee, returns)
+        }
+        _ => Err(e),
+    }
+}
 
-            Opcode::StoreEnvironment => {
-                let to_be_stored = heap.stack.pop().unwrap();
-                if heap.environment.is_null() {
-                    heap.stack[src] = to_be_stored
-                } else {
-                    unsafe {
-                        value::Value::raw_array_set(heap.environment, src, to_be_stored).unwrap()
+/// Puts the result of the future that the fiber just resumed in the
+/// activation `r` waited for, if it waited for one, where the call left its
+/// result, or makes the call again if it would have blocked.  Returns
+/// `false` if the call was a tail call from the first frame, which has now
+/// returned.
+fn deliver(heap: &mut alloc::Heap, r: &mut Registers) -> Result<bool, String> {
+    let awaiting = match heap.control.awaiting.take() {
+        Some(awaiting) => awaiting,
+        None => return Ok(true),
+    };
+    let result = try!(awaiting.result.unwrap());
+    let slot = r.base + awaiting.slot;
+    match awaiting.retry {
+        Some(nargs) if awaiting.returns => tail_call(heap, r, slot, nargs),
+        Some(nargs) => call_in_place(heap, r, slot, nargs).map(|_| true),
+        None => {
+            heap.stack[slot] = result.get();
+            Ok(!awaiting.returns || return_from(heap, r))
+        }
+    }
+}
+
+/// Polls the future that the suspended fiber waits for, with the waker of
+/// `cx`, keeping its result once it is ready, for `resume_with_fuel`.
+pub fn poll_await(heap: &mut alloc::Heap, cx: &mut task::Context) -> Poll<Result<(), String>> {
+    if !heap.control.awaits() {
+        return Poll::Ready(Err("Not awaiting a future".to_owned()))
+    }
+    let poll = {
+        let awaiting = heap.control.awaiting.as_mut().unwrap();
+        let name = &awaiting.name;
+        match awaiting.future {
+            Some(Wait::Result(ref mut future)) => {
+                match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+                    Ok(Poll::Ready(result)) => {
+                        Poll::Ready(result.map(Some).map_err(|e| format!("{}: {}", name, e)))
+                    }
+                    Ok(Poll::Pending) => Poll::Pending,
+                    Err(payload) => {
+                        let message = builtins::panic_message(&*payload);
+                        Poll::Ready(Err(format!("{}: panicked: {}", name, message)))
                     }
                 }
-                *pc += 1;
             }
+            Some(Wait::Ready(ref mut ready)) => ready.as_mut().poll(cx).map(|r| r.map(|()| None)),
+            None => return Poll::Ready(Ok(())),
+        }
+    };
+    let result = match poll {
+        Poll::Ready(Ok(Some(result))) => {
+            match result.to_scheme(heap) {
+                Ok(()) => {
+                    let result = heap.stack.pop().unwrap();
+                    Ok(heap.root(result))
+                }
+                Err(e) => Err(format!("{}: {}", heap.control.awaiting.as_ref().unwrap().name, e)),
+            }
+        }
+        Poll::Ready(Ok(None)) => Ok(heap.root(Value::new(value::UNSPECIFIED))),
+        Poll::Ready(Err(e)) => Err(e),
+        Poll::Pending => return Poll::Pending,
+    };
+    let awaiting = heap.control.awaiting.as_mut().unwrap();
+    awaiting.future = None;
+    awaiting.result = Some(result);
+    Poll::Ready(Ok(()))
+}
+
+/// Suspends the running fiber of the activation `r`, after the current
+/// instruction, and resumes the next one.
+fn switch(heap: &mut alloc::Heap, r: &mut Registers) {
+    let fiber = suspend(heap, r);
+    heap.control.fibers.push_back(fiber);
+    resume(heap, r)
+}
+
+/// Takes the running fiber of the activation `r` off the stack.
+fn suspend(heap: &mut alloc::Heap, r: &mut Registers) -> Fiber {
+    heap.control.frames.push(Frame { fp: r.fp, pc: r.pc });
+    let mut stack = vec![heap.control.winders(),
+                         heap.control.handlers(),
+                         heap.control.parameterization()];
+    stack.extend(heap.stack.drain(r.base..));
+    let base = r.base;
+    let frames = heap.control
+                     .frames
+                     .drain(r.frames..)
+                     .map(|frame| Frame { fp: frame.fp - base, ..frame })
+                     .collect();
+    Fiber {
+        stack: stack,
+        frames: frames,
+        main: !heap.control.spawned,
+    }
+}
+
+/// Drops the running fiber of the activation `r` and its part of the
+/// stack, and resumes the next one, which must exist.
+fn resume(heap: &mut alloc::Heap, r: &mut Registers) {
+    let fiber = heap.control.fibers.pop_front().unwrap();
+    heap.stack.truncate(r.base);
+    let mut stack = fiber.stack.into_iter();
+    let (winders, handlers) = (stack.next().unwrap(), stack.next().unwrap());
+    let parameterization = stack.next().unwrap();
+    set_winders(heap, winders);
+    set_handlers(heap, handlers);
+    set_parameterization(heap, parameterization);
+    heap.stack.extend(stack);
+    heap.control.spawned = !fiber.main;
+    let base = r.base;
+    heap.control.frames.truncate(r.frames);
+    heap.control.frames.extend(fiber.frames
+                                    .into_iter()
+                                    .map(|frame| Frame { fp: base + frame.fp, ..frame }));
+    match pop(heap, r) {
+        Some(frame) => {
+            r.fp = frame.fp;
+            r.pc = frame.pc
+        }
+        None => {
+            // The fiber has not started: it is a call to `Procedure::Fiber`.
+            r.fp = r.base;
+            r.pc = 0
+        }
+    }
+}
+
+/// The error that unwinds the activations abandoned by a continuation.
+const THROW: &'static str = "Continuation invoked outside of its activation";
+
+/// The error that stops the code when the fuel runs out.
+const OUT_OF_FUEL: &'static str = "Out of fuel";
+
+/// The error that stops the code when it waits for a future.
+const AWAITING: &'static str = "Awaiting a future";
+
+/// The error when the stack is too deep.
+const STACK_OVERFLOW: &'static str = "Stack overflow";
+
+/// The default stack limit, in slots.
+const DEFAULT_STACK_LIMIT: usize = 1 << 24;
+
+/// The number of slots past the stack limit that the handlers of a stack
+/// overflow may use.
+const STACK_RESERVE: usize = 1 << 12;
+
+/// The maximum number of nested activations.
+const MAX_ACTIVATIONS: usize = 1 << 10;
+
+/// The BCO of `procedure`, if it is a closure over one.
+fn code(procedure: &Value) -> Option<*const BCO> {
+    match procedure.kind() {
+        Kind::Closure(closure) => {
+            match unsafe { (*closure).code.kind() } {
+                Kind::Bytecode(bco) => Some(bco),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Element `index` of the vector `vector`.
+unsafe fn element(vector: &Value, index: usize) -> &Value {
+    (*(vector.as_ptr() as *const value::Vector)).element(index)
+}
+
+/// The length of the vector `vector`.
+unsafe fn length(vector: &Value) -> usize {
+    (*(vector.as_ptr() as *const value::Vector)).len()
+}
+
+/// `value`, which must be a box made by `Opcode::Box`: a vector of one
+/// element.  Only code that does not come from the compiler can get this
+/// wrong, which the verifier does not check.
+fn unbox(value: &Value) -> Result<Value, String> {
+    match value.kind() {
+        Kind::Vector(vector) if unsafe { (*vector).len() } == 1 => Ok(value.clone()),
+        _ => Err("bad bytecode: not a box".to_owned()),
+    }
+}
+
+/// The name of the procedure whose code is `bco`, for error messages.
+unsafe fn procedure_name(bco: *const BCO) -> String {
+    match (*bco).name() {
+        Some(name) => name.to_string(),
+        None => "anonymous procedure".to_owned(),
+    }
+}
+
+/// A procedure call that an error unwound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BacktraceFrame {
+    /// The name of the procedure, if it has one.
+    pub name: Option<String>,
+
+    /// The index of the instruction that was running.
+    pub pc: usize,
+
+    /// The location of the form that the instruction was compiled from, if
+    /// it is known.
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => try!(write!(f, "{}", name)),
+            None => try!(write!(f, "anonymous procedure")),
+        }
+        try!(write!(f, " (pc {})", self.pc));
+        match self.location {
+            Some(ref location) => write!(f, " at {}", location),
+            None => Ok(()),
+        }
+    }
+}
 
-            Opcode::LoadGlobal => {
-                *pc += 1;
-                try!(heap.load_global())
+/// The calls active in the activation `r`, innermost first.  Tail calls
+/// leave no trace.
+fn walk(heap: &alloc::Heap, r: &Registers) -> Vec<BacktraceFrame> {
+    let current = Frame { fp: r.fp, pc: r.pc };
+    let mut frames = vec![];
+    let live = &heap.control.frames[r.frames..];
+    for frame in ::std::iter::once(&current).chain(live.iter().rev()) {
+        let bco = code(&heap.stack[frame.fp]).unwrap();
+        // `pc` is that of the next instruction, so back up to the one that
+        // was running.
+        let pc = frame.pc.saturating_sub(1);
+        frames.push(unsafe {
+            BacktraceFrame {
+                name: (*bco).name().map(|name| name.to_string()),
+                pc: pc,
+                location: (*bco).location(pc),
             }
+        })
+    }
+    frames
+}
 
-            Opcode::StoreGlobal => {
-                *pc += 1;
-                try!(heap.store_global())
+/// Calls the procedure `nargs + 1` slots from the top of the stack with the
+/// `nargs` values above it, replacing them with its result.
+pub fn call(heap: &mut alloc::Heap, nargs: usize) -> Result<(), String> {
+    let len = heap.stack.len();
+    if nargs >= len {
+        return Err("Attempt to call a procedure with missing arguments".to_owned())
+    }
+    let base = len - nargs - 1;
+    if heap.control.activations.is_empty() {
+        heap.control.clear_error()
+    }
+    let result = if code(&heap.stack[base]).is_some() {
+        run(heap, base, Start::Call)
+    } else {
+        match builtins::call(heap, nargs) {
+            // A continuation whose activation has returned, invoked from
+            // Rust.  No activation has the serial number `usize::MAX`.
+            Err(_) if heap.control.catches(usize::max_value()) => {
+                heap.stack.truncate(base);
+                run(heap, base, Start::Reinstate)
             }
-            _ => unimplemented!(),
+            result => return result,
+        }
+    };
+    match result {
+        Ok(()) => {
+            let result = heap.stack.pop().unwrap();
+            heap.stack.truncate(base);
+            Ok(heap.stack.push(result))
+        }
+        Err(e) => {
+            heap.stack.truncate(base);
+            Err(e)
         }
     }
 }
 
+/// Compiles the datum on top of the stack, and runs it, replacing it with
+/// its value.
+pub fn execute(heap: &mut alloc::Heap) -> Result<(), String> {
+    heap.control.clear_error();
+    eval(heap, None)
+}
 
+/// Compiles the datum on top of the stack, and runs it with `fuel`
+/// instructions at most, replacing it with its value.  If the fuel runs
+/// out first, the code is suspended instead, and the number of the
+/// suspension is returned (see `Control`).
+pub fn execute_with_fuel(heap: &mut alloc::Heap, fuel: usize) -> Result<Option<usize>, String> {
+    heap.control.clear_error();
+    try!(::compiler::compile_in(heap, None));
+    with_fuel(heap, fuel, run_compiled)
+}
 
+/// Runs the code on top of the stack that `compiler::compile` made, like
+/// `execute_compiled`, with `fuel` instructions at most, like
+/// `execute_with_fuel`.
+pub fn execute_compiled_with_fuel(heap: &mut alloc::Heap,
+                                  fuel: usize)
+                                  -> Result<Option<usize>, String> {
+    heap.control.clear_error();
+    with_fuel(heap, fuel, run_compiled)
+}
 
-#[cfg(test)]
-mod tests {
-    use value::Value;
-    use std::cell::Cell;
-    use bytecode::{Opcode, Bytecode};
-    #[test]
-    fn can_cons() {
-        let mut bco = super::new();
-        bco.heap.stack.push(Value { contents: Cell::new(0) });
-        bco.heap.stack.push(Value { contents: Cell::new(0) });
-        assert!(bco.heap.stack.len() == 2);
-        bco.bytecode.push(Bytecode {
-            opcode: Opcode::Cons,
-            src: 0,
-            src2: 1,
-            dst: 1,
-        });
-        bco.bytecode.push(Bytecode {
-            opcode: Opcode::Return,
-            src: 0,
-            src2: 0,
-            dst: 0,
-        });
-        assert!(super::interpret_bytecode(&mut bco).is_ok());
+/// Resumes the code suspended by the suspension numbered `suspension`,
+/// with `fuel` instructions at most, like `execute_with_fuel`.  Only the
+/// last suspension can be resumed, and only once.  If the code waits for a
+/// future, `poll_await` must have found it ready.
+pub fn resume_with_fuel(heap: &mut alloc::Heap,
+                        suspension: usize,
+                        fuel: usize)
+                        -> Result<Option<usize>, String> {
+    if heap.control.suspended.is_none() || heap.control.suspensions != suspension {
+        return Err("Suspension is no longer valid".to_owned())
+    }
+    if heap.control.awaiting.as_ref().map_or(false, |awaiting| awaiting.result.is_none()) {
+        return Err("The awaited future has not completed".to_owned())
+    }
+    heap.control.clear_error();
+    with_fuel(heap, fuel, |heap| {
+        let fiber = heap.control.suspended.take().unwrap();
+        heap.control.fibers.push_front(fiber);
+        let base = heap.stack.len();
+        let result = run(heap, base, Start::Resume);
+        if result.is_ok() {
+            let result = heap.stack.pop().unwrap();
+            heap.stack.truncate(base);
+            heap.stack.push(result)
+        } else {
+            heap.stack.truncate(base)
+        }
+        result
+    })
+}
+
+/// Calls `f` with `fuel` instructions at most, and no more than the fuel
+/// limit, returning the number of the suspension if they run out, or the
+/// code waits for a future.
+fn with_fuel<F>(heap: &mut alloc::Heap, fuel: usize, f: F) -> Result<Option<usize>, String>
+    where F: FnOnce(&mut alloc::Heap) -> Result<(), String>
+{
+    let suspensions = heap.control.suspensions;
+    heap.control.fuel = ::std::cmp::min(fuel, heap.control.fuel_limit);
+    let resumable = ::std::mem::replace(&mut heap.control.resumable, true);
+    let result = f(heap);
+    heap.control.resumable = resumable;
+    heap.control.fuel = heap.control.fuel_limit;
+    match result {
+        Err(ref e) if (e == OUT_OF_FUEL || e == AWAITING) &&
+                      heap.control.suspensions != suspensions => {
+            Ok(Some(heap.control.suspensions))
+        }
+        result => result.map(|()| None),
+    }
+}
+
+/// Runs the code on top of the stack, which `compiler::compile` made,
+/// replacing it with its value.  With `compiler::compile`, this is
+/// `execute` in two steps.
+pub fn execute_compiled(heap: &mut alloc::Heap) -> Result<(), String> {
+    heap.control.clear_error();
+    run_compiled(heap)
+}
+
+/// Runs the code in `file` at top level (see `builtins::load`).
+pub fn load(heap: &mut alloc::Heap, file: &::std::path::Path) -> Result<(), String> {
+    heap.control.clear_error();
+    builtins::load(heap, file, Value::new(value::TRUE))
+}
+
+/// Runs the code in `input` at top level, and writes out what it compiled
+/// to to `output` (see `fasl::compile_file`).
+pub fn compile_file(heap: &mut alloc::Heap,
+                    input: &::std::path::Path,
+                    output: &::std::path::Path)
+                    -> Result<(), String> {
+    heap.control.clear_error();
+    fasl::compile_file(heap, input, output)
+}
+
+/// Runs the source code `source`, which comes from `name`, at top level,
+/// and returns what it compiled to (see `fasl::compile`).
+pub fn compile(heap: &mut alloc::Heap, name: &str, source: &[u8]) -> Result<Vec<u8>, String> {
+    heap.control.clear_error();
+    fasl::compile(heap, ::std::path::Path::new(name), source)
+}
+
+/// Runs the compiled code `bytes`, which comes from `name`, at top level
+/// (see `fasl::load`).
+pub fn load_compiled(heap: &mut alloc::Heap, name: &str, bytes: &[u8]) -> Result<(), String> {
+    heap.control.clear_error();
+    fasl::load(heap, ::std::path::Path::new(name), bytes)
+}
+
+/// Writes out the image of `heap` (see `image`).
+pub fn save_image(heap: &mut alloc::Heap) -> Result<Vec<u8>, String> {
+    ::image::save(heap)
+}
+
+/// Restores the image `bytes` into `heap` (see `image`).
+pub fn load_image(heap: &mut alloc::Heap, bytes: &[u8]) -> Result<(), String> {
+    heap.control.clear_error();
+    ::image::load(heap, bytes)
+}
+
+/// Evaluates the datum on top of the stack, replacing it with its value.
+/// Unlike `execute`, it can be called while Scheme code is running.  The
+/// datum is evaluated at top level, or in an environment that imports the
+/// list of import sets `imports` (see `compiler::compile_in`).
+pub fn eval(heap: &mut alloc::Heap, imports: Option<&Value>) -> Result<(), String> {
+    try!(::compiler::compile_in(heap, imports));
+    run_compiled(heap)
+}
+
+/// Runs the BCO on top of the stack, which `compiler::compile` made or a
+/// compiled file held, replacing it with the value of the code.  The code
+/// is checked first (see `bytecode::verify`).
+pub fn run_compiled(heap: &mut alloc::Heap) -> Result<(), String> {
+    match heap.stack.last().unwrap().kind() {
+        Kind::Bytecode(bco) => try!(bytecode::verify(unsafe { &*bco })),
+        _ => return Err("bad bytecode: not code".to_owned()),
+    }
+    let len = heap.stack.len();
+    let result = heap.alloc_closure(len - 1, len);
+    let closure = heap.stack.pop();
+    heap.stack.truncate(len - 1);
+    try!(result);
+    heap.stack.push(closure.unwrap());
+    call(heap, 0)
+}
+
+/// How `run` starts.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Start {
+    /// By calling the procedure at the base.
+    Call,
+
+    /// By reinstating the continuation being invoked.
+    Reinstate,
+
+    /// By resuming the first fiber in the queue, at the base.
+    Resume,
+}
+
+/// Runs the Scheme procedure at `stack[base]`, whose arguments are above
+/// it, until it returns, or starts as `start` says otherwise.  The result
+/// is left on top of the stack.
+fn run(heap: &mut alloc::Heap, base: usize, start: Start) -> Result<(), String> {
+    let outer = heap.control.current.take();
+    heap.control.frames.extend(outer);
+    let mut registers = Registers {
+        serial: heap.control.enter(),
+        base: base,
+        fp: base,
+        pc: 0,
+        frames: heap.control.frames.len(),
+        outer: outer,
+    };
+    let winders = heap.root::<Value>(heap.control.winders());
+    let handlers = heap.root::<Value>(heap.control.handlers());
+    let parameterization = heap.root::<Value>(heap.control.parameterization());
+    let mut result = match start {
+        Start::Call if heap.control.activations.len() > MAX_ACTIVATIONS => {
+            heap.control.raised = true;
+            Err(STACK_OVERFLOW.to_owned())
+        }
+        Start::Call => dispatch(heap, &mut registers),
+        Start::Reinstate => Err(THROW.to_owned()),
+        Start::Resume => {
+            resume(heap, &mut registers);
+            match deliver(heap, &mut registers) {
+                Ok(true) => dispatch(heap, &mut registers),
+                result => result.map(|_| ()),
+            }
+        }
+    };
+    let result = loop {
+        result = match result {
+            Ok(()) if heap.control.spawned && heap.control.activations.len() == 1 => {
+                // A fiber returned, so the others run.
+                resume(heap, &mut registers);
+                dispatch(heap, &mut registers)
+            }
+            Err(_) if heap.control.catches(registers.serial) => {
+                match reinstate(heap, &mut registers) {
+                    Ok(true) => dispatch(heap, &mut registers),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(ref e) if heap.control.throw.is_none() && !heap.control.raised &&
+                          heap.control.handlers.is_some() => {
+                raise_error(heap, e).map(|_| ())
+            }
+            result => break result,
+        }
+    };
+    let outermost = heap.control.activations.len() == 1;
+    if outermost && result.as_ref().err().map_or(false, |e| e == OUT_OF_FUEL || e == AWAITING) {
+        // The fiber is set aside with its own dynamic state.
+        if result.as_ref().err().map_or(false, |e| e == OUT_OF_FUEL) {
+            heap.control.awaiting = None
+        }
+        heap.control.suspended = Some(suspend(heap, &mut registers));
+        heap.control.suspensions += 1;
+        heap.control.spawned = false;
+        set_winders(heap, winders.get());
+        set_handlers(heap, handlers.get());
+        set_parameterization(heap, parameterization.get());
+        exit(heap, &registers);
+        return result
+    }
+    if result.is_err() && heap.control.spawned && outermost {
+        // The main fiber is abandoned with the one that failed.
+        heap.control.fibers.retain(|fiber| !fiber.main);
+        heap.control.spawned = false
+    }
+    if result.is_err() && heap.control.throw.is_none() {
+        // Errors leave the dynamic extents they were raised in without
+        // running their `after` thunks.
+        set_winders(heap, winders.get());
+        set_handlers(heap, handlers.get());
+        set_parameterization(heap, parameterization.get());
+        let frames = walk(heap, &registers);
+        heap.control.backtrace.extend(frames)
+    }
+    exit(heap, &registers);
+    if heap.control.activations.is_empty() && heap.stack.capacity() > 16 * STACK_RESERVE &&
+       heap.stack.len() < heap.stack.capacity() / 4 {
+        // Give back the memory of a deep recursion.
+        heap.stack.shrink_to_fit()
+    }
+    result
+}
+
+/// Records the end of the activation `r`, whose control stack is dropped.
+fn exit(heap: &mut alloc::Heap, r: &Registers) {
+    heap.control.frames.truncate(r.frames - r.outer.is_some() as usize);
+    heap.control.current = r.outer;
+    heap.control.leave()
+}
+
+/// The interpreter loop.  Returns when the first frame returns.
+///
+/// This is the plain loop, which `match`es on the opcode of each
+/// instruction (see `execute_instruction`).
+#[cfg(not(feature = "threaded-dispatch"))]
+fn dispatch(heap: &mut alloc::Heap, r: &mut Registers) -> Result<(), String> {
+    let result = loop {
+        // The BCO must be looked up again after anything that can allocate,
+        // since the GC may have moved it.
+        if let Err(e) = heap.control.burn_fuel() {
+            break Err(e)
+        }
+        let bco = code(&heap.stack[r.fp]).unwrap();
+        let instruction = unsafe { (*bco).instruction(r.pc) };
+        r.pc += 1;
+        // The collector looks at the frame of the running instruction.
+        heap.control.current = Some(Frame { fp: r.fp, pc: r.pc });
+        match execute_instruction(heap, r, bco, instruction) {
+            Ok(true) => {}
+            result => break result.map(|_| ()),
+        }
+    };
+    heap.control.current = None;
+    result
+}
+
+/// The interpreter loop, with the `threaded-dispatch` feature.  Returns
+/// when the first frame returns.
+///
+/// Each instruction is run by calling the handler for its opcode in
+/// `HANDLERS`, which is `execute_instruction` specialized to the opcode,
+/// so that each opcode has its own indirect branch instead of all sharing
+/// the one of a `match`.  Rust cannot jump from one handler directly into the next, so
+/// the handlers return here in between.
+#[cfg(feature = "threaded-dispatch")]
+fn dispatch(heap: &mut alloc::Heap, r: &mut Registers) -> Result<(), String> {
+    let result = loop {
+        if let Err(e) = heap.control.burn_fuel() {
+            break Err(e)
+        }
+        let bco = code(&heap.stack[r.fp]).unwrap();
+        let instruction = unsafe { (*bco).instruction(r.pc) };
+        r.pc += 1;
+        heap.control.current = Some(Frame { fp: r.fp, pc: r.pc });
+        match HANDLERS[instruction.opcode as usize](heap, r, bco, instruction) {
+            Ok(true) => {}
+            result => break result.map(|_| ()),
+        }
+    };
+    heap.control.current = None;
+    result
+}
+
+/// A function that runs an instruction (see `execute_instruction`).
+#[cfg(feature = "threaded-dispatch")]
+type Handler = fn(&mut alloc::Heap, &mut Registers, *const BCO, Bytecode)
+                  -> Result<bool, String>;
+
+/// Calls the macro `$then` with the names of all the opcodes, in order.
+#[cfg(any(test, feature = "threaded-dispatch"))]
+macro_rules! with_opcodes {
+    ($then: ident) => {
+        $then!(Cons, Car, Cdr, SetCar, SetCdr, IsPair, Add, Subtract, Multiply, Divide, Power,
+               MakeArray, SetArray, GetArray, IsArray, ArrayLen, Call, TailCall, Return, Closure,
+               Set, LoadConstant, LoadLocal, LoadCaptured, LoadArgument, LoadGlobal, LoadFalse,
+               LoadTrue, LoadNil, StoreLocal, StoreArgument, StoreGlobal, LoadUnspecified, Pop,
+               Jump, JumpIfFalse, Enter, DefineGlobal, Wind, Unwind, PushHandler, PopHandler,
+               PushParameter, PopParameter, Box, Unbox, SetBox, Move, EnterRest,
+               LoadConstantCall, MoveCall, CallJumpIfFalse, CaseLambda, EnterOptional, Optional,
+               JumpIfSupplied)
+    }
+}
+
+/// Makes the table of the handlers for the opcodes `$opcode`.
+#[cfg(feature = "threaded-dispatch")]
+macro_rules! handlers {
+    ($($opcode: ident),*) => {
+        [$({
+            fn handler(heap: &mut alloc::Heap,
+                       r: &mut Registers,
+                       bco: *const BCO,
+                       instruction: Bytecode)
+                       -> Result<bool, String> {
+                execute_instruction(heap, r, bco, Bytecode { opcode: Opcode::$opcode, ..instruction })
+            }
+            handler as Handler
+        }),*]
+    }
+}
+
+/// The handler for each opcode, by number.
+#[cfg(feature = "threaded-dispatch")]
+static HANDLERS: [Handler; bytecode::OPCODE_COUNT as usize] = with_opcodes!(handlers);
+
+/// Runs `instruction`, from the code `bco` of the current frame, after `pc`
+/// has been advanced past it.  Returns `false` if it returned from the
+/// first frame.  Always inlined, so that each handler in `HANDLERS` gets a
+/// copy in which the `match` is resolved.
+#[inline(always)]
+fn execute_instruction(heap: &mut alloc::Heap,
+                       r: &mut Registers,
+                       bco: *const BCO,
+                       instruction: Bytecode)
+                       -> Result<bool, String> {
+    let (src, src2, wide) = (instruction.src, instruction.src2, instruction.wide_operand());
+    // The stack slots of registers `src` and `src2`.
+    let (a, b) = (r.fp + 1 + src as usize, r.fp + 1 + src2 as usize);
+    match instruction.opcode {
+        Opcode::Enter | Opcode::EnterRest => {
+            let rest = instruction.opcode == Opcode::EnterRest;
+            try!(enter(heap, r.fp, src as usize, rest, instruction.dst as usize))
+        }
+
+        Opcode::EnterOptional => {
+            try!(enter_optional(heap, r.fp, bco));
+            r.pc = 2
+        }
+
+        Opcode::CaseLambda => try!(case_lambda(heap, r, src2 as usize)),
+
+        Opcode::LoadConstant => {
+            heap.stack[a] = unsafe { element(&(*bco).constants(), wide).clone() }
+        }
+
+        Opcode::LoadTrue => heap.stack[a] = Value::new(value::TRUE),
+        Opcode::LoadFalse => heap.stack[a] = Value::new(value::FALSE),
+        Opcode::LoadNil => heap.stack[a] = Value::new(value::NIL),
+        Opcode::LoadUnspecified => heap.stack[a] = Value::new(value::UNSPECIFIED),
+
+        Opcode::Move => heap.stack[a] = heap.stack[b].clone(),
+
+        Opcode::LoadCaptured => {
+            heap.stack[a] = match heap.stack[r.fp].kind() {
+                Kind::Closure(closure) => unsafe { (*closure).captured(wide).clone() },
+                _ => bug!("LoadCaptured in a frame without a closure"),
+            }
+        }
+
+        Opcode::Box => {
+            let value = heap.stack[a].clone();
+            let len = heap.stack.len();
+            heap.stack.push(value);
+            let result = heap.alloc_vector(len, len + 1);
+            let boxed = heap.stack.pop();
+            heap.stack.truncate(len);
+            try!(result);
+            heap.stack[a] = boxed.unwrap()
+        }
+
+        Opcode::Unbox => {
+            let boxed = try!(unbox(&heap.stack[b]));
+            heap.stack[a] = unsafe { element(&boxed, 0).clone() }
+        }
+
+        Opcode::SetBox => {
+            let boxed = try!(unbox(&heap.stack[a]));
+            let value = heap.stack[b].clone();
+            unsafe { element(&boxed, 0).set(value.clone()) }
+            heap.write_barrier(&boxed, &value)
+        }
+
+        Opcode::LoadGlobal => {
+            // The symbol is the cell of the variable, and the constants
+            // vector refers to it directly, so there is nothing to look
+            // up or to cache.
+            let symbol = unsafe { element(&(*bco).constants(), wide).clone() };
+            heap.stack[a] = match symbol.kind() {
+                Kind::Symbol(symbol) => unsafe {
+                    if !(*symbol).bound.get() {
+                        return Err(format!("Unbound variable: {}", (*symbol).name()))
+                    }
+                    (*(*symbol).contents.get()).clone()
+                },
+                _ => bug!("global variable named by a non-symbol"),
+            }
+        }
+
+        Opcode::StoreGlobal => {
+            let symbol = unsafe { element(&(*bco).constants(), wide).clone() };
+            match symbol.kind() {
+                Kind::Symbol(ptr) if unsafe { !(*ptr).bound.get() } => {
+                    return Err(format!("Unbound variable: {}", unsafe { (*ptr).name() }))
+                }
+                _ => {}
+            }
+            let value = heap.stack[a].clone();
+            try!(heap.set_global(&symbol, value))
+        }
+
+        Opcode::DefineGlobal => {
+            let symbol = unsafe { element(&(*bco).constants(), wide).clone() };
+            let value = heap.stack[a].clone();
+            try!(heap.set_global(&symbol, value))
+        }
+
+        Opcode::Closure => {
+            let code = unsafe { element(&(*bco).constants(), wide).clone() };
+            let captured = match code.kind() {
+                Kind::Bytecode(code) => unsafe { (*code).instruction(0).src2 as usize },
+                _ => bug!("closure of something other than code"),
+            };
+            let start = heap.stack.len();
+            heap.stack.push(code);
+            for i in 0..captured {
+                let value = heap.stack[a + 1 + i].clone();
+                heap.stack.push(value)
+            }
+            let result = heap.alloc_closure(start, start + 1 + captured);
+            let closure = heap.stack.pop();
+            heap.stack.truncate(start);
+            try!(result);
+            heap.stack[a] = closure.unwrap()
+        }
+
+        Opcode::Wind => {
+            let len = heap.stack.len();
+            heap.stack.push(heap.control.winders());
+            let result = heap.alloc_pair(a, b).and_then(|()| heap.alloc_pair(len + 1, len));
+            let winders = heap.stack.pop();
+            heap.stack.truncate(len);
+            try!(result);
+            set_winders(heap, winders.unwrap())
+        }
+
+        Opcode::Unwind => {
+            let winders = heap.control.winders().cdr().unwrap();
+            set_winders(heap, winders)
+        }
+
+        Opcode::PushHandler => {
+            let len = heap.stack.len();
+            heap.stack.push(heap.control.handlers());
+            let result = heap.alloc_pair(a, len);
+            let handlers = heap.stack.pop();
+            heap.stack.truncate(len);
+            try!(result);
+            set_handlers(heap, handlers.unwrap())
+        }
+
+        Opcode::PopHandler => {
+            let handlers = heap.control.handlers().cdr().unwrap();
+            set_handlers(heap, handlers)
+        }
+
+        Opcode::PushParameter => {
+            let len = heap.stack.len();
+            heap.stack.push(heap.control.parameterization());
+            let result = heap.alloc_pair(a, b).and_then(|()| heap.alloc_pair(len + 1, len));
+            let parameterization = heap.stack.pop();
+            heap.stack.truncate(len);
+            try!(result);
+            set_parameterization(heap, parameterization.unwrap())
+        }
+
+        Opcode::PopParameter => {
+            let parameterization = heap.control.parameterization().cdr().unwrap();
+            set_parameterization(heap, parameterization)
+        }
+
+        Opcode::Jump => {
+            let target = instruction.long_operand();
+            if target < r.pc {
+                try!(poll_interrupt(heap))
+            }
+            r.pc = target
+        }
+
+        Opcode::JumpIfFalse => {
+            if heap.stack[a].get() == value::FALSE {
+                r.pc = wide
+            }
+        }
+
+        Opcode::JumpIfSupplied => {
+            if heap.stack[a].get() != value::DEFAULT {
+                r.pc = wide
+            }
+        }
+
+        Opcode::Call => {
+            try!(call_in_place(heap, r, a, src2 as usize));
+        }
+
+        Opcode::TailCall => return tail_call(heap, r, a, src2 as usize),
+
+        Opcode::CallJumpIfFalse => return call_jump_if_false(heap, r, a, src2 as usize),
+
+        Opcode::LoadConstantCall => {
+            heap.stack[a] = unsafe { element(&(*bco).constants(), wide).clone() };
+            return call_next(heap, r, bco)
+        }
+
+        Opcode::MoveCall => {
+            heap.stack[a] = heap.stack[b].clone();
+            return call_next(heap, r, bco)
+        }
+
+        Opcode::Return => {
+            let result = heap.stack[a].clone();
+            heap.stack.push(result);
+            if !return_from(heap, r) {
+                return Ok(false)
+            }
+        }
+
+        opcode => return Err(format!("Unsupported opcode {:?}", opcode)),
+    }
+    Ok(true)
+}
+
+/// Runs `Call` on the callee at `stack[callee]` and its `nargs`
+/// arguments.  Returns whether the call is over, with its result in place
+/// of the callee, as it is when the callee is a primitive, rather than
+/// being run in a new frame.
+fn call_in_place(heap: &mut alloc::Heap,
+                 r: &mut Registers,
+                 callee: usize,
+                 nargs: usize)
+                 -> Result<bool, String> {
+    try!(poll_interrupt(heap));
+    try!(heap.control.check_stack(callee));
+    // The callee and its arguments end the stack, and its frame starts at
+    // `callee`.
+    heap.stack.truncate(callee + 1 + nargs);
+    if code(&heap.stack[callee]).is_some() {
+        heap.control.frames.push(Frame { fp: r.fp, pc: r.pc });
+        r.fp = callee;
+        r.pc = 0
+    } else if nargs == 1 && builtins::is_call_cc(&heap.stack[callee]) {
+        let top = Frame { fp: r.fp, pc: r.pc };
+        try!(call_cc(heap, r, callee, Some(top), callee));
+        return call_in_place(heap, r, callee, 1)
+    } else if builtins::is_apply(&heap.stack[callee]) {
+        let nargs = try!(unapply(heap, callee, nargs));
+        return call_in_place(heap, r, callee, nargs)
+    } else if nargs == 0 && builtins::is_yield(&heap.stack[callee]) && can_switch(heap) {
+        heap.stack[callee] = Value::new(value::UNSPECIFIED);
+        restore_frame(heap, r.fp);
+        switch(heap, r)
+    } else if let Some(procedure) = builtins::bytecode_procedure(&heap.stack[callee], nargs) {
+        heap.stack[callee] = try!(procedure_code(heap, procedure));
+        return call_in_place(heap, r, callee, nargs)
+    } else if builtins::is_async_native(heap, &heap.stack[callee]) && can_await(heap) {
+        try!(call_async(heap, nargs));
+        restore_frame(heap, r.fp);
+        return await_result(heap, r, callee, false)
+    } else {
+        if let Err(e) = builtins::call(heap, nargs) {
+            return blocked(heap, r, callee, nargs, false, e)
+        }
+        restore_frame(heap, r.fp);
+        return Ok(true)
+    }
+    Ok(false)
+}
+
+/// Runs `TailCall` on the callee at `stack[callee]` and its `nargs`
+/// arguments.  Returns `false` if it returned from the first frame.
+fn tail_call(heap: &mut alloc::Heap,
+             r: &mut Registers,
+             callee: usize,
+             nargs: usize)
+             -> Result<bool, String> {
+    try!(poll_interrupt(heap));
+    heap.stack.truncate(callee + 1 + nargs);
+    if code(&heap.stack[callee]).is_some() {
+        // Reuse the current frame.
+        for i in 0..nargs + 1 {
+            heap.stack[r.fp + i] = heap.stack[callee + i].clone()
+        }
+        heap.stack.truncate(r.fp + nargs + 1);
+        r.pc = 0;
+        return Ok(true)
+    }
+    if nargs == 1 && builtins::is_call_cc(&heap.stack[callee]) {
+        let end = r.fp;
+        try!(call_cc(heap, r, callee, None, end));
+        return tail_call(heap, r, callee, 1)
+    }
+    if builtins::is_apply(&heap.stack[callee]) {
+        let nargs = try!(unapply(heap, callee, nargs));
+        return tail_call(heap, r, callee, nargs)
+    }
+    if nargs == 0 && builtins::is_yield(&heap.stack[callee]) && can_switch(heap) {
+        heap.stack[callee] = Value::new(value::UNSPECIFIED);
+        if !return_from(heap, r) {
+            return Ok(false)
+        }
+        switch(heap, r);
+        return Ok(true)
+    }
+    if let Some(procedure) = builtins::bytecode_procedure(&heap.stack[callee], nargs) {
+        heap.stack[callee] = try!(procedure_code(heap, procedure));
+        return tail_call(heap, r, callee, nargs)
+    }
+    if builtins::is_async_native(heap, &heap.stack[callee]) && can_await(heap) {
+        try!(call_async(heap, nargs));
+        return await_result(heap, r, callee, true)
+    }
+    if let Err(e) = builtins::call(heap, nargs) {
+        return blocked(heap, r, callee, nargs, true, e)
+    }
+    Ok(return_from(heap, r))
+}
+
+/// Runs `CallJumpIfFalse`: the call, and if it is over at once, the
+/// `JumpIfFalse` after it on its result.
+fn call_jump_if_false(heap: &mut alloc::Heap,
+                      r: &mut Registers,
+                      callee: usize,
+                      nargs: usize)
+                      -> Result<bool, String> {
+    if try!(call_in_place(heap, r, callee, nargs)) {
+        // The primitive may have moved the BCO.
+        let bco = code(&heap.stack[r.fp]).unwrap();
+        let jump = unsafe { (*bco).instruction(r.pc) };
+        r.pc = if heap.stack[callee].get() == value::FALSE {
+            jump.wide_operand()
+        } else {
+            r.pc + 1
+        }
+    }
+    Ok(true)
+}
+
+/// Runs the call after a superinstruction that loaded its last argument,
+/// from the code `bco` of the current frame.  Returns `false` if it
+/// returned from the first frame.
+fn call_next(heap: &mut alloc::Heap, r: &mut Registers, bco: *const BCO) -> Result<bool, String> {
+    let instruction = unsafe { (*bco).instruction(r.pc) };
+    r.pc += 1;
+    let (callee, nargs) = (r.fp + 1 + instruction.src as usize, instruction.src2 as usize);
+    match instruction.opcode {
+        Opcode::Call => call_in_place(heap, r, callee, nargs).map(|_| true),
+        Opcode::TailCall => tail_call(heap, r, callee, nargs),
+        Opcode::CallJumpIfFalse => call_jump_if_false(heap, r, callee, nargs),
+        _ => bug!("superinstruction not followed by a call"),
+    }
+}
+
+/// Returns the value on top of the stack from the current frame.  Returns
+/// `false` if the frame was the first one.
+fn return_from(heap: &mut alloc::Heap, r: &mut Registers) -> bool {
+    let result = heap.stack.pop().unwrap();
+    heap.stack.truncate(r.fp);
+    heap.stack.push(result);
+    pop_frame(heap, r)
+}
+
+/// Continues the caller of the current frame, whose result is on top of
+/// the stack, where the callee was.  Returns `false` if there is none.
+fn pop_frame(heap: &mut alloc::Heap, r: &mut Registers) -> bool {
+    match pop(heap, r) {
+        Some(frame) => {
+            r.fp = frame.fp;
+            r.pc = frame.pc;
+            restore_frame(heap, r.fp);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Pops the innermost frame of the control stack of the activation `r`, if
+/// it has one.
+fn pop(heap: &mut alloc::Heap, r: &Registers) -> Option<Frame> {
+    if heap.control.frames.len() > r.frames {
+        heap.control.frames.pop()
+    } else {
+        None
+    }
+}
+
+/// Extends the stack to the last register of the frame at `fp` again,
+/// after a call from it, which overwrote the registers after the callee.
+fn restore_frame(heap: &mut alloc::Heap, fp: usize) {
+    let registers = unsafe { (*code(&heap.stack[fp]).unwrap()).instruction(0).dst as usize };
+    heap.stack.resize(fp + 1 + registers, Value::new(value::UNSPECIFIED))
+}
+
+/// Replaces `apply` at `stack[callee]` and its `nargs` arguments with the
+/// procedure it applies and the arguments to that, and returns how many
+/// those are.
+fn unapply(heap: &mut alloc::Heap, callee: usize, nargs: usize) -> Result<usize, String> {
+    if nargs < 2 {
+        return Err(builtins::arity_error(Some("apply"), &[(2, None)], nargs))
+    }
+    heap.stack.remove(callee);
+    splice(heap, callee)
+}
+
+/// Replaces the list on top of the stack, the last argument of the
+/// procedure at `stack[callee]`, with its elements, and returns the number
+/// of arguments then.  They are pushed one by one, without copying the
+/// list to a vector first.
+pub fn splice(heap: &mut alloc::Heap, callee: usize) -> Result<usize, String> {
+    let list = heap.stack.pop().unwrap();
+    let (mut tail, mut length) = (list.clone(), 0);
+    while let Ok(next) = tail.cdr() {
+        tail = next;
+        length += 1
+    }
+    if tail.get() != value::NIL {
+        return Err("apply: expected a list".to_owned())
+    }
+    heap.stack.reserve(length);
+    let mut list = list;
+    for _ in 0..length {
+        heap.stack.push(list.car().unwrap());
+        list = list.cdr().unwrap()
+    }
+    Ok(heap.stack.len() - callee - 1)
+}
+
+/// Part `index` of the continuation `continuation`: the serial number of
+/// its activation, its control stack, its stack, its wind list, its
+/// handler stack, or its parameterization.
+unsafe fn continuation_part(continuation: &Value, index: usize) -> Value {
+    match continuation.kind() {
+        Kind::Closure(closure) => (*closure).captured(index).clone(),
+        _ => bug!("continuation is not a closure"),
+    }
+}
+
+/// Replaces `call/cc` at `stack[callee]` and its argument with the argument
+/// and the current continuation, so that the call can be retried.  The
+/// continuation returns to the frame `top`, if there is one, and otherwise
+/// from the current frame, whose temporaries end at `end`.
+///
+/// The stack and the control stack are copied in full.
+fn call_cc(heap: &mut alloc::Heap,
+           r: &Registers,
+           callee: usize,
+           top: Option<Frame>,
+           end: usize)
+           -> Result<(), String> {
+    let start = heap.stack.len();
+    heap.stack.push(builtins::CONTINUATION.to_value());
+    heap.stack.push(Value::new_fixnum(r.serial));
+    for frame in heap.control.frames[r.frames..].iter().chain(top.iter()) {
+        heap.stack.push(Value::new_fixnum(frame.fp - r.base));
+        heap.stack.push(Value::new_fixnum(frame.pc));
+    }
+    let len = heap.stack.len();
+    let result = heap.alloc_vector(start + 2, len)
+                     .and_then(|()| {
+                         let frames = heap.stack.pop().unwrap();
+                         heap.stack.truncate(start + 2);
+                         heap.stack.push(frames);
+                         heap.alloc_vector(r.base, end)
+                     })
+                     .and_then(|()| {
+                         heap.stack.push(heap.control.winders());
+                         heap.stack.push(heap.control.handlers());
+                         heap.stack.push(heap.control.parameterization());
+                         heap.alloc_closure(start, start + 7)
+                     });
+    let continuation = heap.stack.pop().unwrap();
+    heap.stack.truncate(start);
+    try!(result);
+    heap.stack[callee] = heap.stack[callee + 1].clone();
+    heap.stack[callee + 1] = continuation;
+    Ok(())
+}
+
+/// Starts invoking `continuation` with `value`, returning the error that
+/// unwinds to the activation that will reinstate it.
+pub fn throw(heap: &mut alloc::Heap, continuation: Value, value: Value) -> String {
+    let continuation = heap.root(continuation);
+    let value = heap.root(value);
+    heap.control.throw = Some((continuation, value));
+    THROW.to_owned()
+}
+
+/// Reinstates the continuation being invoked in the activation `r`, and
+/// returns the value passed to it.  Returns `false` if the continuation
+/// returns from the activation.
+fn reinstate(heap: &mut alloc::Heap, r: &mut Registers) -> Result<bool, String> {
+    let (continuation, value) = heap.control.throw.take().unwrap();
+    heap.stack.truncate(r.base);
+    heap.stack.push(unsafe { continuation_part(&continuation.get(), 3) });
+    try!(rewind(heap, r.base));
+    heap.stack.truncate(r.base);
+    let (continuation, value) = (continuation.get(), value.get());
+    set_handlers(heap, unsafe { continuation_part(&continuation, 4) });
+    set_parameterization(heap, unsafe { continuation_part(&continuation, 5) });
+    heap.control.frames.truncate(r.frames);
+    unsafe {
+        let frames = continuation_part(&continuation, 1);
+        for i in 0..length(&frames) / 2 {
+            heap.control.frames.push(Frame {
+                fp: r.base + element(&frames, 2 * i).as_fixnum().unwrap(),
+                pc: element(&frames, 2 * i + 1).as_fixnum().unwrap(),
+            })
+        }
+        let stack = continuation_part(&continuation, 2);
+        for i in 0..length(&stack) {
+            heap.stack.push(element(&stack, i).clone())
+        }
+    }
+    heap.stack.push(value);
+    Ok(pop_frame(heap, r))
+}
+
+/// The length of the list `list`.
+fn list_length(list: &Value) -> usize {
+    let mut list = list.clone();
+    let mut length = 0;
+    while let Ok(tail) = list.cdr() {
+        list = tail;
+        length += 1
+    }
+    length
+}
+
+/// The tail of the list `list` after its first `k` elements.
+fn list_tail(list: &Value, k: usize) -> Value {
+    let mut list = list.clone();
+    for _ in 0..k {
+        list = list.cdr().unwrap()
+    }
+    list
+}
+
+/// Changes the wind list to `stack[to]`.  The `after` thunks of the
+/// winders that are only in the current list are called, innermost first,
+/// and then the `before` thunks of those only in the new one, outermost
+/// first.
+fn rewind(heap: &mut alloc::Heap, to: usize) -> Result<(), String> {
+    let (mut from, mut target) = (heap.control.winders(), heap.stack[to].clone());
+    let (mut from_length, mut to_length) = (list_length(&from), list_length(&target));
+    let new = to_length;
+    while from_length > to_length {
+        from = from.cdr().unwrap();
+        from_length -= 1
+    }
+    while to_length > from_length {
+        target = target.cdr().unwrap();
+        to_length -= 1
+    }
+    while from.get() != target.get() {
+        from = from.cdr().unwrap();
+        target = target.cdr().unwrap();
+        to_length -= 1
+    }
+    let common = to_length;
+    while list_length(&heap.control.winders()) > common {
+        let winders = heap.control.winders();
+        set_winders(heap, winders.cdr().unwrap());
+        heap.stack.push(winders.car().unwrap().cdr().unwrap());
+        try!(call(heap, 0));
+        heap.stack.pop();
+    }
+    for depth in (common..new).rev() {
+        let before = list_tail(&heap.stack[to], new - depth - 1).car().unwrap().car().unwrap();
+        heap.stack.push(before);
+        try!(call(heap, 0));
+        heap.stack.pop();
+        let winders = list_tail(&heap.stack[to], new - depth - 1);
+        set_winders(heap, winders)
+    }
+    Ok(())
+}
+
+/// Empties the wind list, calling all its `after` thunks, innermost first,
+/// as when the program exits.
+pub fn unwind_all(heap: &mut alloc::Heap) -> Result<(), String> {
+    let base = heap.stack.len();
+    heap.stack.push(Value::new(value::NIL));
+    let result = rewind(heap, base);
+    heap.stack.truncate(base);
+    result
+}
+
+/// The procedures that the interpreter runs as bytecode, since they must
+/// run in the activation that calls them, so that continuations captured
+/// by the procedures they call include the rest of them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Procedure {
+    /// `dynamic-wind`:
+    ///
+    /// ```scheme
+    /// (lambda (before thunk after)
+    ///   (before)
+    ///   (%wind before after)
+    ///   (let ((result (thunk)))
+    ///     (%unwind)
+    ///     (after)
+    ///     result))
+    /// ```
+    DynamicWind,
+
+    /// `with-exception-handler`:
+    ///
+    /// ```scheme
+    /// (lambda (handler thunk)
+    ///   (%push-handler handler)
+    ///   (let ((result (thunk)))
+    ///     (%pop-handler)
+    ///     result))
+    /// ```
+    WithExceptionHandler,
+
+    /// The procedure that `parameterize` calls with each parameter, its
+    /// converted value, and a thunk for the rest of the form:
+    ///
+    /// ```scheme
+    /// (lambda (parameter value thunk)
+    ///   (%push-parameter parameter value)
+    ///   (let ((result (thunk)))
+    ///     (%pop-parameter)
+    ///     result))
+    /// ```
+    Parameterize,
+
+    /// The start of a fiber made by `spawn`, which is suspended with its
+    /// own frames:
+    ///
+    /// ```scheme
+    /// (lambda (thunk) (thunk))
+    /// ```
+    Fiber,
+}
+
+impl Procedure {
+    /// The name of the procedure.
+    fn name(self) -> &'static str {
+        match self {
+            Procedure::DynamicWind => "dynamic-wind",
+            Procedure::WithExceptionHandler => "with-exception-handler",
+            Procedure::Parameterize => "parameterize",
+            Procedure::Fiber => "fiber",
+        }
+    }
+
+    /// The code of the procedure.
+    fn code(self) -> Vec<Bytecode> {
+        let pair = |opcode, src, src2| Bytecode { opcode: opcode, src: src, src2: src2, dst: 0 };
+        let simple = |opcode| pair(opcode, 0, 0);
+        let enter = |arguments, registers| {
+            Bytecode { opcode: Opcode::Enter, src: arguments, src2: 0, dst: registers }
+        };
+        let call = |register| pair(Opcode::Call, register, 0);
+        match self {
+            Procedure::DynamicWind => {
+                let (before, thunk, after) = (0, 1, 2);
+                vec![enter(3, 5),
+                     pair(Opcode::Move, 3, before),
+                     call(3),
+                     pair(Opcode::Wind, before, after),
+                     pair(Opcode::Move, 3, thunk),
+                     call(3),
+                     simple(Opcode::Unwind),
+                     pair(Opcode::Move, 4, after),
+                     call(4),
+                     pair(Opcode::Return, 3, 0)]
+            }
+            Procedure::WithExceptionHandler => {
+                let (handler, thunk) = (0, 1);
+                vec![enter(2, 3),
+                     pair(Opcode::PushHandler, handler, 0),
+                     pair(Opcode::Move, 2, thunk),
+                     call(2),
+                     simple(Opcode::PopHandler),
+                     pair(Opcode::Return, 2, 0)]
+            }
+            Procedure::Parameterize => {
+                let (parameter, value, thunk) = (0, 1, 2);
+                vec![enter(3, 4),
+                     pair(Opcode::PushParameter, parameter, value),
+                     pair(Opcode::Move, 3, thunk),
+                     call(3),
+                     simple(Opcode::PopParameter),
+                     pair(Opcode::Return, 3, 0)]
+            }
+            Procedure::Fiber => {
+                let thunk = 0;
+                vec![enter(1, 2), pair(Opcode::Move, 1, thunk), call(1), pair(Opcode::Return, 1, 0)]
+            }
+        }
+    }
+}
+
+/// The closure that runs `procedure`, which is made the first time it is
+/// needed.
+pub fn procedure_code(heap: &mut alloc::Heap, procedure: Procedure) -> Result<Value, String> {
+    if let Some(ref code) = heap.control.procedures[procedure as usize] {
+        return Ok(code.get())
+    }
+    let mut bytes = vec![];
+    for instruction in procedure.code() {
+        bytes.extend_from_slice(&instruction.to_bytes())
+    }
+    let base = heap.stack.len();
+    heap.intern(procedure.name());
+    let result = heap.alloc_vector(base, base)
+                     .and_then(|()| heap.alloc_vector(base, base + 2))
+                     .and_then(|()| {
+                         let constants = heap.stack.pop().unwrap();
+                         heap.stack.truncate(base);
+                         heap.stack.push(constants);
+                         heap.alloc_bytecode(&bytes)
+                     })
+                     .and_then(|()| heap.alloc_closure(base, base + 1));
+    let code = heap.stack.pop().unwrap();
+    heap.stack.truncate(base);
+    try!(result);
+    heap.control.procedures[procedure as usize] = Some(heap.root(code.clone()));
+    Ok(code)
+}
+
+/// Raises the object on top of the stack, popping it.  The current handler
+/// is called with it, with the outer handlers installed.  If `continuable`
+/// is set, the handler's result is returned.  Otherwise, a handler that
+/// returns raises a secondary exception, in the same dynamic environment as
+/// the handler.
+pub fn raise(heap: &mut alloc::Heap, continuable: bool) -> Result<Value, String> {
+    let handlers = heap.control.handlers();
+    if handlers.get() == value::NIL {
+        let object = heap.stack.pop().unwrap();
+        heap.control.raised = true;
+        let message = uncaught(&object);
+        heap.control.uncaught = Some((message.clone(), heap.root(object)));
+        return Err(message)
+    }
+    let base = heap.stack.len() - 1;
+    let object = heap.stack[base].clone();
+    heap.stack.push(handlers.clone());
+    heap.stack.push(handlers.car().unwrap());
+    heap.stack.push(object);
+    set_handlers(heap, handlers.cdr().unwrap());
+    let mut result = call(heap, 1).map(|()| heap.stack.pop().unwrap());
+    if result.is_ok() && !continuable {
+        result = raise_error(heap, "handler returned from non-continuable raise")
+    }
+    if result.is_err() && heap.control.throw.is_none() {
+        heap.control.raised = true
+    }
+    let handlers = heap.stack[base + 1].clone();
+    set_handlers(heap, handlers);
+    heap.stack.truncate(base);
+    result
+}
+
+/// Raises a condition of type `error` with message `message` and no
+/// irritants.
+fn raise_error(heap: &mut alloc::Heap, message: &str) -> Result<Value, String> {
+    raise_condition(heap, "error", message, false)
+}
+
+/// Raises a condition of type `interrupt` if the interrupt flag is set,
+/// clearing it.  The result of the handler is dropped.
+fn poll_interrupt(heap: &mut alloc::Heap) -> Result<(), String> {
+    if heap.control.interrupted() {
+        try!(raise_condition(heap, "interrupt", "Interrupted", true));
+    }
+    Ok(())
+}
+
+/// Raises a condition of type `kind` with message `message` and no
+/// irritants.
+pub fn raise_condition(heap: &mut alloc::Heap,
+                       kind: &str,
+                       message: &str,
+                       continuable: bool)
+                       -> Result<Value, String> {
+    let base = heap.stack.len();
+    heap.intern(kind);
+    let result = heap.alloc_string(message).and_then(|()| {
+        heap.stack.push(Value::new(value::NIL));
+        heap.alloc_condition(base)
+    });
+    let condition = heap.stack.pop().unwrap();
+    heap.stack.truncate(base);
+    if let Err(e) = result {
+        heap.control.raised = true;
+        return Err(e)
+    }
+    heap.stack.push(condition);
+    raise(heap, continuable)
+}
+
+/// The error message for the uncaught exception `object`.
+fn uncaught(object: &Value) -> String {
+    match object.kind() {
+        Kind::Condition(condition) => unsafe {
+            let mut message = match (*condition).message.kind() {
+                Kind::String(_) => print::to_string(&(*condition).message, print::Mode::Display),
+                _ => print::to_string(&(*condition).message, print::Mode::Write),
+            };
+            let mut irritants = (*condition).irritants.clone();
+            while let Ok(irritant) = irritants.car() {
+                message.push(' ');
+                message.push_str(&print::to_string(&irritant, print::Mode::Write));
+                irritants = irritants.cdr().unwrap()
+            }
+            message
+        },
+        _ => format!("Uncaught exception: {}", print::to_string(object, print::Mode::Write)),
+    }
+}
+
+/// Executes `Enter` for the procedure at `stack[fp]`: checks the number of
+/// arguments, collects the rest argument, and makes room for the rest of
+/// the `registers`.
+fn enter(heap: &mut alloc::Heap,
+         fp: usize,
+         required: usize,
+         rest: bool,
+         registers: usize)
+         -> Result<(), String> {
+    let nargs = heap.stack.len() - fp - 1;
+    if nargs < required || (nargs > required && !rest) {
+        let arity = (required, if rest { None } else { Some(required) });
+        return Err(arity_error(heap, fp, &[arity], nargs))
+    }
+    if rest {
+        heap.stack.push(Value::new(value::NIL));
+        for i in (fp + 1 + required..fp + 1 + nargs).rev() {
+            let len = heap.stack.len();
+            try!(heap.alloc_pair(i, len - 1));
+            let pair = heap.stack.pop().unwrap();
+            *heap.stack.last_mut().unwrap() = pair
+        }
+        let list = heap.stack.pop().unwrap();
+        heap.stack.truncate(fp + 1 + required);
+        heap.stack.push(list)
+    }
+    heap.stack.resize(fp + 1 + registers, Value::new(value::UNSPECIFIED));
+    Ok(())
+}
+
+/// Executes `EnterOptional` for the procedure at `stack[fp]`, whose code is
+/// `bco`.  The arguments after the optional ones go into the rest
+/// parameter, if there is one, and are keyword arguments, if there are
+/// keyword parameters: each is a symbol naming a parameter, followed by
+/// its value.
+fn enter_optional(heap: &mut alloc::Heap, fp: usize, bco: *const BCO) -> Result<(), String> {
+    let (enter, optional) = unsafe { ((*bco).instruction(0), (*bco).instruction(1)) };
+    let nargs = heap.stack.len() - fp - 1;
+    let positional = enter.src as usize + optional.src as usize;
+    let (rest, keys) = (optional.src2 != 0, optional.dst as usize);
+    if nargs < enter.src as usize || (nargs > positional && !rest && keys == 0) {
+        return Err(arity_error(heap, fp, &[arity(bco).unwrap()], nargs))
+    }
+    if nargs < positional {
+        heap.stack.resize(fp + 1 + positional, Value::new(value::DEFAULT))
+    }
+    // The arguments after the positional ones.
+    let (start, end) = (fp + 1 + positional, heap.stack.len());
+    let mut values = vec![None; keys];
+    if keys > 0 {
+        if (end - start) % 2 != 0 {
+            return Err(keyword_error(heap, fp, "keyword argument without a value"))
+        }
+        for i in (start..end).filter(|i| (i - start) % 2 == 0) {
+            let key = heap.stack[i].get();
+            let index = (0..keys).position(|index| unsafe {
+                element(&(*bco).constants(), 2 + index).get() == key
+            });
+            match index {
+                Some(index) => values[index] = values[index].or(Some(i + 1)),
+                None => {
+                    let key = print::to_string(&heap.stack[i], print::Mode::Write);
+                    let message = format!("unknown keyword argument {}", key);
+                    return Err(keyword_error(heap, fp, &message))
+                }
+            }
+        }
+    }
+    if rest {
+        heap.stack.push(Value::new(value::NIL));
+        for i in (start..end).rev() {
+            let len = heap.stack.len();
+            try!(heap.alloc_pair(i, len - 1));
+            let pair = heap.stack.pop().unwrap();
+            *heap.stack.last_mut().unwrap() = pair
+        }
+    }
+    for value in values {
+        let value = value.map_or(Value::new(value::DEFAULT), |i| heap.stack[i].clone());
+        heap.stack.push(value)
+    }
+    // Move the rest list and the keyword parameters down over the
+    // arguments.
+    let parameters = heap.stack.split_off(end);
+    heap.stack.truncate(start);
+    heap.stack.extend(parameters);
+    heap.stack.resize(fp + 1 + enter.dst as usize, Value::new(value::UNSPECIFIED));
+    Ok(())
+}
+
+/// The error `message` about the arguments of the procedure at
+/// `stack[fp]`.
+fn keyword_error(heap: &alloc::Heap, fp: usize, message: &str) -> String {
+    let name = unsafe { (*code(&heap.stack[fp]).unwrap()).name() };
+    builtins::argument_error(name.as_ref().map(|name| &name[..]), message)
+}
+
+/// The numbers of arguments that the code `bco` accepts, as a minimum and
+/// a maximum, if it starts with `Enter`, `EnterRest` or `EnterOptional`.
+fn arity(bco: *const BCO) -> Option<(usize, Option<usize>)> {
+    let enter = unsafe { (*bco).instruction(0) };
+    let required = enter.src as usize;
+    match enter.opcode {
+        Opcode::Enter => Some((required, Some(required))),
+        Opcode::EnterRest => Some((required, None)),
+        Opcode::EnterOptional => {
+            let optional = unsafe { (*bco).instruction(1) };
+            if optional.src2 != 0 || optional.dst != 0 {
+                Some((required, None))
+            } else {
+                Some((required, Some(required + optional.src as usize)))
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The error when the procedure at `stack[fp]`, which accepts `arities`
+/// numbers of arguments, is called with `nargs` of them.
+fn arity_error(heap: &alloc::Heap,
+               fp: usize,
+               arities: &[(usize, Option<usize>)],
+               nargs: usize)
+               -> String {
+    let name = unsafe { (*code(&heap.stack[fp]).unwrap()).name() };
+    builtins::arity_error(name.as_ref().map(|name| &name[..]), arities, nargs)
+}
+
+/// Executes `CaseLambda` for the procedure at `stack[fp]`, whose `clauses`
+/// captured values are the procedures of its clauses: replaces it with the
+/// first that accepts the arguments, and starts running that.
+fn case_lambda(heap: &mut alloc::Heap, r: &mut Registers, clauses: usize) -> Result<(), String> {
+    let nargs = heap.stack.len() - r.fp - 1;
+    let mut arities = vec![];
+    for i in 0..clauses {
+        let clause = match heap.stack[r.fp].kind() {
+            Kind::Closure(closure) => unsafe { (*closure).captured(i).clone() },
+            _ => bug!("CaseLambda in a frame without a closure"),
+        };
+        let arity = match code(&clause).and_then(arity) {
+            Some(arity) => arity,
+            None => return Err("bad bytecode: case-lambda clause is not a procedure".to_owned()),
+        };
+        if nargs >= arity.0 && arity.1.map_or(true, |max| nargs <= max) {
+            heap.stack[r.fp] = clause;
+            r.pc = 0;
+            return Ok(())
+        }
+        arities.push(arity)
+    }
+    Err(arity_error(heap, r.fp, &arities, nargs))
+}
+
+#[cfg(test)]
+mod tests {
+    use api;
+    use alloc::Heap;
+    use builtins::{self, Primitive};
+    use read::{self, Input};
+    use value::Value;
+
+    /// `(decrement n)`, for counting loops.
+    fn decrement(heap: &mut Heap, _: usize) -> Result<Value, String> {
+        let n = heap.stack.last().unwrap().as_fixnum().unwrap();
+        Ok(Value::new_fixnum(n - 1))
+    }
+
+    static DECREMENT: Primitive = Primitive {
+        name: "decrement",
+        min_args: 1,
+        max_args: Some(1),
+        function: decrement,
+    };
+
+    /// `(explode)`, which leaves a value on the stack and panics.
+    fn explode(heap: &mut Heap, _: usize) -> Result<Value, String> {
+        heap.stack.push(Value::new_fixnum(1));
+        panic!("boom")
+    }
+
+    static EXPLODE: Primitive = Primitive {
+        name: "explode",
+        min_args: 0,
+        max_args: Some(0),
+        function: explode,
+    };
+
+    /// Evaluates each datum in `source`, returning the last value as
+    /// printed by `write`.
+    fn eval(interp: &mut api::State, source: &str) -> Result<String, String> {
+        let mut input = Input::new(source.as_bytes(), "test");
+        let mut result = Ok(String::new());
+        loop {
+            read::read(interp, &mut input).unwrap();
+            if interp.write_string().unwrap() == "#<eof>" {
+                interp.drop().unwrap();
+                return result
+            }
+            result = interp.execute().and_then(|()| interp.write_string());
+            let _ = interp.drop();
+            if result.is_err() {
+                return result
+            }
+        }
+    }
+
+    fn new() -> api::State {
+        let mut interp = api::State::new();
+        let heap = interp.heap();
+        heap.intern("decrement");
+        let symbol = heap.stack.pop().unwrap();
+        heap.set_global(&symbol, DECREMENT.to_value()).unwrap();
+        interp
+    }
+
+    #[test]
+    fn evaluate_core_forms() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "'(1 . 2)"), Ok("(1 . 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(if #f 1 2)"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "((lambda (x . y) y) 1 2 3)"), Ok("(2 3)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(define (swap x y) (let ((x y) (y x)) (bytevector x y)))
+                         (swap 1 2)"),
+                   Ok("#u8(2 1)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(define (make-counter)
+                           (define n 5)
+                           (lambda () (set! n (decrement n)) n))
+                         (define c (make-counter))
+                         (c)
+                         (c)"),
+                   Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(set! undefined 1)"),
+                   Err("Unbound variable: undefined".to_owned()));
+        assert_eq!(eval(&mut interp, "(swap 1)"),
+                   Err("expected 2 arguments, got 1, in procedure swap".to_owned()));
+        assert_eq!(eval(&mut interp, "(1 2)"),
+                   Err("Attempt to call a non-procedure".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn closures_capture_variables() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "((((lambda (x) (lambda () (lambda () x))) 4)))"),
+                   Ok("4".to_owned()));
+        // Each closure has its own copy of a variable that is not assigned.
+        assert_eq!(eval(&mut interp,
+                        "(define (collect n closures)
+                           (if (eq? n 0)
+                               closures
+                               (collect (decrement n) (cons (lambda () n) closures))))
+                         ((car (cdr (collect 3 '()))))"),
+                   Ok("2".to_owned()));
+        // Closures share assigned variables.
+        assert_eq!(eval(&mut interp,
+                        "(define counter
+                           (let ((n 5))
+                             (cons (lambda () (set! n (decrement n))) (lambda () n))))
+                         ((car counter))
+                         ((car counter))
+                         ((cdr counter))"),
+                   Ok("3".to_owned()));
+        // And see procedures defined after them.
+        assert_eq!(eval(&mut interp,
+                        "(define (odd? n)
+                           (define (even? n) (if (eq? n 0) #t (odd? (decrement n))))
+                           (define (odd? n) (if (eq? n 0) #f (even? (decrement n))))
+                           (odd? n))
+                         (odd? 7)"),
+                   Ok("#t".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn case_lambda_dispatches_on_argument_count() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define area
+                (case-lambda
+                  ((side) (area side side))
+                  ((width height) (bytevector width height))
+                  ((x y z . rest) rest)))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(area 3)"), Ok("#u8(3 3)".to_owned()));
+        assert_eq!(eval(&mut interp, "(area 1 2)"), Ok("#u8(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(area 1 2 3 4)"), Ok("(4)".to_owned()));
+        assert_eq!(eval(&mut interp, "(area)"),
+                   Err("expected 1, 2 or at least 3 arguments, got 0, in procedure area"
+                           .to_owned()));
+        // Clauses close over variables like `lambda`.
+        assert_eq!(eval(&mut interp,
+                        "(let ((n 5))
+                           ((case-lambda ((x) x) (() n))))"),
+                   Ok("5".to_owned()));
+        assert_eq!(eval(&mut interp, "((lambda (x) x))"),
+                   Err("expected 1 argument, got 0, in anonymous procedure".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn optional_and_keyword_parameters() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define (f a #!optional (b (cons a a)) c #!rest r #!key (k b) j)
+                (list a b c r k j))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(f 1)"), Ok("(1 (1 . 1) #f () (1 . 1) #f)".to_owned()));
+        assert_eq!(eval(&mut interp, "(f 1 2 3)"), Ok("(1 2 3 () 2 #f)".to_owned()));
+        assert_eq!(eval(&mut interp, "(f 1 2 3 'j 4 'k 5 'j 6)"),
+                   Ok("(1 2 3 (j 4 k 5 j 6) 5 4)".to_owned()));
+        assert_eq!(eval(&mut interp, "(f)"),
+                   Err("expected at least 1 argument, got 0, in procedure f".to_owned()));
+        assert_eq!(eval(&mut interp, "(f 1 2 3 'j)"),
+                   Err("keyword argument without a value, in procedure f".to_owned()));
+        assert_eq!(eval(&mut interp, "(f 1 2 3 'x 1)"),
+                   Err("unknown keyword argument x, in procedure f".to_owned()));
+        // Defaults see the parameters before them, and closures see the
+        // parameters after their defaults are computed.
+        assert_eq!(eval(&mut interp,
+                        "(define (g #!optional (x 1) (get (lambda () x)))
+                           (set! x 2)
+                           (get))
+                         (g)"),
+                   Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "((lambda (#!optional x) x) 1 2)"),
+                   Err("expected 0 to 1 arguments, got 2, in anonymous procedure".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_space() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define (even? n) (if (eq? n 0) #t (odd? (decrement n))))
+              (define (odd? n) (if (eq? n 0) #f (even? (decrement n))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(even? 100001)"), Ok("#f".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let loop ((i 100000) (acc '()))
+                           (if (eq? i 0) acc (loop (decrement i) i)))"),
+                   Ok("1".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn apply_spreads_its_last_argument() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(apply bytevector 1 2 '(3 4))"),
+                   Ok("#u8(1 2 3 4)".to_owned()));
+        assert_eq!(eval(&mut interp, "(apply (lambda args args) '())"), Ok("()".to_owned()));
+        assert_eq!(eval(&mut interp, "(apply call/cc (list (lambda (k) (k 7))))"),
+                   Ok("7".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(apply dynamic-wind (list (lambda () 1) (lambda () 2) (lambda () 3)))"),
+                   Ok("2".to_owned()));
+        // A call through `apply` in tail position is a tail call.
+        eval(&mut interp,
+             "(define (count-down n) (if (eq? n 0) 'done (apply count-down (decrement n) '())))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(count-down 100000)"), Ok("done".to_owned()));
+        // Called by a primitive.
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (values bytevector '(5))) apply)"),
+                   Ok("#u8(5)".to_owned()));
+        assert_eq!(eval(&mut interp, "(apply bytevector 1 2)"),
+                   Err("apply: expected a list".to_owned()));
+        assert_eq!(eval(&mut interp, "(apply bytevector)"),
+                   Err("expected at least 2 arguments, got 1, in procedure apply".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn primitives_call_back_into_scheme() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(call-with-values (lambda () (values 1 2)) bytevector)"),
+                   Ok("#u8(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(force (make-promise 3))"), Ok("3".to_owned()));
+    }
+
+    #[test]
+    fn globals_survive_collection() {
+        let mut interp = new();
+        eval(&mut interp, "(define greeting '(hello world))").unwrap();
+        for _ in 0..3 {
+            interp.gc()
+        }
+        assert_eq!(eval(&mut interp, "greeting"), Ok("(hello world)".to_owned()));
+    }
+
+    #[test]
+    fn fibers_take_turns() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define log '())
+              (define (note x) (set! log (cons x log)))
+              (define (worker name)
+                (lambda () (note name) (yield) (note name)))
+              (spawn (worker 'a))
+              (spawn (lambda () (note 'b) (yield)))")
+            .unwrap();
+        // The stacks of fibers that have not run yet survive collection.
+        interp.gc();
+        assert_eq!(eval(&mut interp, "(begin (note 'main) (yield) (note 'main) (yield) log)"),
+                   Ok("(a main b a main)".to_owned()));
+        // With no other fiber, `yield` just returns.
+        assert_eq!(eval(&mut interp, "(begin (yield) 'done)"), Ok("done".to_owned()));
+        // An error in a fiber ends the main fiber too.
+        eval(&mut interp, "(spawn (lambda () (car '())))").unwrap();
+        assert_eq!(eval(&mut interp, "(begin (yield) 'unreachable)"),
+                   Err("car: expected a pair".to_owned()));
+        assert_eq!(eval(&mut interp, "(begin (yield) 'done)"), Ok("done".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn threads_exchange_copies() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define (echo requests replies)
+                (lambda ()
+                  (let ((request (channel-receive requests)))
+                    (channel-send! replies (eq? (car request) (cdr request)))
+                    request)))")
+            .unwrap();
+        // Shared structure stays shared, and the thread has a value.
+        assert_eq!(eval(&mut interp,
+                        "(let ((requests (make-channel)) (replies (make-channel)))
+                           (let ((thread (make-thread (echo requests replies)))
+                                 (shared (list 1.5 \"two\" 'three)))
+                             (channel-send! requests (cons shared shared))
+                             (list (channel-receive replies) (thread-join! thread))))"),
+                   Ok("(#t ((1.5 \"two\" three) 1.5 \"two\" three))".to_owned()));
+        // The thread has its own globals, and its errors are reported.
+        assert_eq!(eval(&mut interp, "(thread-join! (make-thread (lambda () echo)))"),
+                   Err("Unbound variable: echo".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(define thread (make-thread (lambda () 1)))
+                         (thread-join! thread)
+                         (thread-join! thread)"),
+                   Err("thread-join!: the thread was already joined".to_owned()));
+        assert_eq!(eval(&mut interp, "(channel-send! (make-channel) (make-eq-hashtable))"),
+                   Err("channel-send!: cannot copy #<hashtable> to another heap".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn continuations_escape() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(call/cc (lambda (k) (bytevector 1 (k 2))))"),
+                   Ok("2".to_owned()));
+        // From a procedure called in tail position.
+        eval(&mut interp, "(define (f) (call/cc (lambda (k) (k 1) 3)))").unwrap();
+        assert_eq!(eval(&mut interp, "(bytevector (f) 2)"), Ok("#u8(1 2)".to_owned()));
+        // Through a nested activation, run by a primitive.
+        assert_eq!(eval(&mut interp,
+                        "(call-with-current-continuation
+                           (lambda (k) (call-with-values (lambda () (k 1)) bytevector)))"),
+                   Ok("1".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (call/cc (lambda (k) (k 1 2))))
+                                           bytevector)"),
+                   Ok("#u8(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(call-with-values (lambda () (lambda (k) 1)) call/cc)"),
+                   Err("call/cc: cannot capture the continuation of a primitive".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn continuations_can_be_reentered() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(let ((k #f) (n 3))
+                           (call/cc (lambda (c) (set! k c)))
+                           (set! n (decrement n))
+                           (if (eq? n 0) 'done (k #f)))"),
+                   Ok("done".to_owned()));
+        // After the activation that captured it has returned.
+        eval(&mut interp,
+             "(define k #f)
+              (define b (bytevector 1 (call/cc (lambda (c) (set! k c) 2))))")
+            .unwrap();
+        for &n in &[5, 7] {
+            interp.gc();
+            eval(&mut interp, &format!("(k {})", n)).unwrap();
+            assert_eq!(eval(&mut interp, "b"), Ok(format!("#u8(1 {})", n)));
+        }
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn dynamic_wind_runs_thunks_around_continuations() {
+        let mut interp = new();
+        // `(note! n)` records `n` in `trace`, filling it from the end.
+        eval(&mut interp,
+             "(define trace (make-bytevector 11 0))
+              (define i 11)
+              (define (note! n) (set! i (decrement i)) (bytevector-u8-set! trace i n))
+              (define (wind thunk)
+                (dynamic-wind (lambda () (note! 1)) thunk (lambda () (note! 3))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(wind (lambda () (note! 2) 'result))"),
+                   Ok("result".to_owned()));
+        assert_eq!(eval(&mut interp, "(call/cc (lambda (k) (wind (lambda () (k 'out)))))"),
+                   Ok("out".to_owned()));
+        eval(&mut interp,
+             "(define k #f)
+              (wind (lambda () (call/cc (lambda (c) (set! k c))) (note! 2)))")
+            .unwrap();
+        eval(&mut interp, "(if (eq? i 3) (k #f))").unwrap();
+        assert_eq!(eval(&mut interp, "trace"), Ok("#u8(3 2 1 3 2 1 3 1 3 2 1)".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn errors_leave_dynamic_extents() {
+        let mut interp = new();
+        eval(&mut interp, "(define n 5)").unwrap();
+        assert!(eval(&mut interp,
+                     "(dynamic-wind (lambda () #f)
+                                    (lambda () (undefined))
+                                    (lambda () (set! n (decrement n))))")
+                    .is_err());
+        assert_eq!(eval(&mut interp, "(call/cc (lambda (k) (k n)))"), Ok("5".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn errors_record_backtraces() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define (inner x) (undefined-thing x))
+              (define (outer x) (bytevector (inner x)))")
+            .unwrap();
+        assert!(eval(&mut interp, "(outer 1)").is_err());
+        let frames: Vec<String> = interp.backtrace().iter().map(|f| f.to_string()).collect();
+        // `(outer 1)` is a tail call, so the top-level code is not listed.
+        assert_eq!(frames, vec!["inner (pc 1) at test:1:19", "outer (pc 4) at test:2:45"]);
+        assert_eq!(interp.backtrace()[0].name, Some("inner".to_owned()));
+        assert_eq!(eval(&mut interp, "5"), Ok("5".to_owned()));
+        assert!(interp.backtrace().is_empty());
+    }
+
+    #[test]
+    fn parameterize() {
+        let mut interp = new();
+        eval(&mut interp, "(define p (make-parameter 1 bytevector))").unwrap();
+        assert_eq!(eval(&mut interp, "(p)"), Ok("#u8(1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(parameterize ((p 2)) (p))"), Ok("#u8(2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(parameterize ((p 2)) (parameterize ((p 3)) (p)))"),
+                   Ok("#u8(3)".to_owned()));
+        assert_eq!(eval(&mut interp, "(p)"), Ok("#u8(1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(parameterize ((bytevector 1)) 2)"),
+                   Err("parameterize: not a parameter object".to_owned()));
+
+        // Continuations restore the parameterization they were captured in.
+        eval(&mut interp,
+             "(define k #f)
+              (define q (make-parameter 1))
+              (define b (parameterize ((q 2) (p 3))
+                          (bytevector (call/cc (lambda (c) (set! k c) 0)) (q))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "b"), Ok("#u8(0 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(q)"), Ok("1".to_owned()));
+        eval(&mut interp, "(k 5)").unwrap();
+        assert_eq!(eval(&mut interp, "b"), Ok("#u8(5 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(q)"), Ok("1".to_owned()));
+        assert_eq!(eval(&mut interp, "(call/cc (lambda (out) (parameterize ((q 4)) (out (q)))))"),
+                   Ok("4".to_owned()));
+        assert_eq!(eval(&mut interp, "(q)"), Ok("1".to_owned()));
+        assert!(eval(&mut interp, "(parameterize ((q 5)) (undefined))").is_err());
+        assert_eq!(eval(&mut interp, "(q)"), Ok("1".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn syntax_rules_macros() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define-syntax my-let*
+                (syntax-rules ()
+                  ((_ () body ...) (let () body ...))
+                  ((_ ((x v) rest ...) body ...) (let ((x v)) (my-let* (rest ...) body ...)))))
+              (define-syntax my-cond
+                (syntax-rules (else)
+                  ((_ (else e)) e)
+                  ((_ (c e) clause ...) (if c e (my-cond clause ...)))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(my-let* ((a 1) (b (bytevector a))) b)"),
+                   Ok("#u8(1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(my-cond (#f 1) (else 2))"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(my-cond (#t 1) (else 2))"), Ok("1".to_owned()));
+
+        // Ellipses in the middle, nested, escaped, or custom, and vectors.
+        eval(&mut interp,
+             "(define-syntax my-last (syntax-rules () ((_ x ... y) 'y)))
+              (define-syntax flatten (syntax-rules () ((_ (a ...) ...) '(a ... ...))))
+              (define-syntax escape (syntax-rules () ((_ x) '(x (... ...)))))
+              (define-syntax my-list (syntax-rules ::: () ((_ x :::) '(x :::))))
+              (define-syntax swap-vector (syntax-rules () ((_ #(a b ...)) '#(b ... a))))
+              (define-syntax rest (syntax-rules () ((_ a ... . r) 'r)))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(my-last 1 2 3)"), Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(flatten (1 2) () (3))"), Ok("(1 2 3)".to_owned()));
+        assert_eq!(eval(&mut interp, "(escape 1)"), Ok("(1 ...)".to_owned()));
+        assert_eq!(eval(&mut interp, "(my-list 1 2)"), Ok("(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(swap-vector #(1 2 3))"), Ok("#(2 3 1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(rest 1 2 . 3)"), Ok("3".to_owned()));
+
+        // Local macros, and macros that expand into definitions.
+        assert_eq!(eval(&mut interp,
+                        "(let-syntax ((two (syntax-rules () ((_ e) (bytevector e e)))))
+                           (two 3))"),
+                   Ok("#u8(3 3)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(letrec-syntax ((zeros (syntax-rules ()
+                                                  ((_ (z ...)) (bytevector z ...))
+                                                  ((_ (z ...) x y ...) (zeros (z ... 0) y ...)))))
+                           (zeros () a b c))"),
+                   Ok("#u8(0 0 0)".to_owned()));
+        eval(&mut interp,
+             "(define-syntax define-constant
+                (syntax-rules () ((_ name value) (define (name) value))))
+              (define-constant five 5)
+              (define (f)
+                (define-syntax double (syntax-rules () ((_ e) (bytevector e e))))
+                (define-constant six 6)
+                (double (six)))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(bytevector (five))"), Ok("#u8(5)".to_owned()));
+        assert_eq!(eval(&mut interp, "(f)"), Ok("#u8(6 6)".to_owned()));
+
+        // A global definition replaces a macro.
+        eval(&mut interp, "(define my-last 4)").unwrap();
+        assert_eq!(eval(&mut interp, "my-last"), Ok("4".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn macros_are_hygienic() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define-syntax swap!
+                (syntax-rules () ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))
+              (define-syntax my-if (syntax-rules () ((_ c a b) (if c a b))))
+              (define (helper) 7)
+              (define-syntax call-helper (syntax-rules () ((_) (helper))))")
+            .unwrap();
+        // Bindings inserted by a macro do not capture the variables of its use.
+        assert_eq!(eval(&mut interp, "(let ((tmp 1) (y 2)) (swap! tmp y) (bytevector tmp y))"),
+                   Ok("#u8(2 1)".to_owned()));
+        eval(&mut interp, "(define tmp 3) (define y 4) (swap! tmp y)").unwrap();
+        assert_eq!(eval(&mut interp, "(bytevector tmp y)"), Ok("#u8(4 3)".to_owned()));
+        // Free identifiers in a template mean what they meant where the macro
+        // was defined.
+        assert_eq!(eval(&mut interp, "(let ((if bytevector)) (my-if #f 1 2))"),
+                   Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(let ((helper 5)) (bytevector helper (call-helper)))"),
+                   Ok("#u8(5 7)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((x 1))
+                           (let-syntax ((get-x (syntax-rules () ((_) x))))
+                             (let ((x 2)) (bytevector x (get-x)))))"),
+                   Ok("#u8(2 1)".to_owned()));
+        // Literals match identifiers that mean the same thing.
+        eval(&mut interp,
+             "(define-syntax is-else? (syntax-rules (else) ((_ else) #t) ((_ x) #f)))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(is-else? else)"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(let ((else 1)) (is-else? else))"), Ok("#f".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn explicit_renaming_macros() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define-syntax swap!
+                (er-macro-transformer
+                  (lambda (form rename compare)
+                    (let ((a (car (cdr form))) (b (car (cdr (cdr form)))))
+                      (list (rename 'let) (list (list (rename 'tmp) a))
+                            (list (rename 'set!) a b)
+                            (list (rename 'set!) b (rename 'tmp)))))))
+              (define-syntax is-else?
+                (er-macro-transformer
+                  (lambda (form rename compare) (compare (car (cdr form)) (rename 'else)))))
+              (define-syntax with-it
+                (er-macro-transformer
+                  (lambda (form rename compare)
+                    (list (rename 'let) (list (list 'it (car (cdr form))))
+                          (car (cdr (cdr form)))))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(let ((tmp 1) (y 2)) (swap! tmp y) (bytevector tmp y))"),
+                   Ok("#u8(2 1)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((set! 0) (let 0) (a 1) (b 2)) (swap! a b) (bytevector a b))"),
+                   Ok("#u8(2 1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(is-else? else)"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(let ((else 1)) (is-else? else))"), Ok("#f".to_owned()));
+        // Symbols inserted without renaming capture the variables of the use.
+        assert_eq!(eval(&mut interp, "(with-it 5 (bytevector it))"), Ok("#u8(5)".to_owned()));
+
+        // Identifiers renamed by syntax-rules keep their meaning.
+        eval(&mut interp,
+             "(define-syntax swap-with-one
+                (syntax-rules () ((_ x) (let ((tmp 1)) (swap! tmp x) tmp))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(let ((tmp 9)) (bytevector (swap-with-one tmp) tmp))"),
+                   Ok("#u8(9 1)".to_owned()));
+
+        // Renamed identifiers mean nothing after their expansion.
+        eval(&mut interp,
+             "(define saved #f)
+              (define-syntax save
+                (er-macro-transformer (lambda (form rename compare) (set! saved (rename 'x)) 1)))
+              (define-syntax restore
+                (er-macro-transformer (lambda (form rename compare) saved)))
+              (save)")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(restore)"),
+                   Err("test:1:1: identifier used outside of its macro expansion".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn libraries() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define-library (lib stack)
+                (export push! (rename items contents) with-item)
+                (import (scheme base))
+                (begin
+                  (define (push! x) (set! items (cons x items)))
+                  (define items '())
+                  (define-syntax with-item
+                    (syntax-rules () ((_ x body) (begin (push! x) body))))))
+              (define items 'mine)")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(push! 1)"),
+                   Err("Unbound variable: push!".to_owned()));
+        eval(&mut interp, "(import (lib stack))").unwrap();
+        assert_eq!(eval(&mut interp, "(push! 1) contents"), Ok("(1)".to_owned()));
+        // The library's variables are its own, and so are those its macros
+        // refer to.
+        assert_eq!(eval(&mut interp, "items"), Ok("mine".to_owned()));
+        assert_eq!(eval(&mut interp, "(let ((push! #f)) (with-item 2 contents))"),
+                   Ok("(2 1)".to_owned()));
+
+        eval(&mut interp,
+             "(import (prefix (only (lib stack) push!) s:)
+                      (rename (except (lib stack) push!) (contents stack))
+                      (prefix (scheme base) base:))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(s:push! 3) (base:if #f 0 stack)"),
+                   Ok("(3 2 1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(import (only (lib stack) items))"),
+                   Err("test:1:9: identifier not in import set: items".to_owned()));
+        assert_eq!(eval(&mut interp, "(import (lib queue))"),
+                   Err("test:1:9: library not found: (lib queue)".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn libraries_are_loaded_from_the_search_path() {
+        use std::{env, fs, process};
+        use std::io::Write;
+        let directory = env::temp_dir().join(format!("rusty-scheme-{}", process::id()));
+        fs::create_dir_all(directory.join("geometry")).unwrap();
+        let files = [("geometry/shapes.sld",
+                      "(define-library (geometry shapes)
+                         (export square)
+                         (import (scheme base) (geometry sides))
+                         (begin (define (square) (list 'square sides))))"),
+                     ("geometry/sides.sld",
+                      "(define-library (geometry sides)
+                         (export sides)
+                         (begin (define sides 4)))"),
+                     ("loop.sld", "(define-library (loop) (import (loop)))")];
+        for &(name, contents) in &files {
+            fs::File::create(directory.join(name)).unwrap().write_all(contents.as_bytes()).unwrap()
+        }
+        let mut interp = new();
+        interp.add_library_directory(&directory);
+        assert_eq!(eval(&mut interp, "(import (geometry shapes)) (square)"),
+                   Ok("(square 4)".to_owned()));
+        let error = eval(&mut interp, "(import (loop))").unwrap_err();
+        assert!(error.ends_with("loop.sld:1:32: circular import of library (loop)"),
+                "{}",
+                error);
+        for &(import, name) in &[("(import (|..| geometry sides))", "(.. geometry sides)"),
+                                 ("(import (|| sides))", "( sides)"),
+                                 ("(import (|geometry/sides|))", "(geometry/sides)"),
+                                 ("(import (|/etc/passwd|))", "(/etc/passwd)")] {
+            assert_eq!(eval(&mut interp, import),
+                       Err(format!("test:1:9: bad library name for a file: {}", name)));
+        }
+        // Libraries already loaded can still be imported once files are denied.
+        interp.heap().deny(builtins::Group::File);
+        assert_eq!(eval(&mut interp, "(import (geometry sides)) sides"), Ok("4".to_owned()));
+        let mut denied = new();
+        denied.add_library_directory(&directory);
+        denied.heap().deny(builtins::Group::File);
+        assert_eq!(eval(&mut denied, "(import (geometry shapes))"),
+                   Err("test:1:9: cannot load library (geometry shapes): file access is denied"
+                           .to_owned()));
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn load_runs_files() {
+        use std::{env, fs, process};
+        use std::io::Write;
+        let directory = env::temp_dir().join(format!("rusty-scheme-load-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let files = [("script.scm",
+                      "#!/usr/bin/env rusty-scheme\n(define loaded (command-line))\n(define-syntax \
+                       twice (syntax-rules () ((_ x) (list x x))))"),
+                     ("isolated.scm", "(define seen-twice (twice 1))"),
+                     ("bad.scm", "(define fine 1)\n  (car 1)")];
+        for &(name, contents) in &files {
+            fs::File::create(directory.join(name)).unwrap().write_all(contents.as_bytes()).unwrap()
+        }
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        let mut interp = new();
+        interp.set_command_line(vec!["script.scm".to_owned(), "arg".to_owned()]);
+        interp.load_file(path("script.scm")).unwrap();
+        assert_eq!(eval(&mut interp, "loaded"), Ok("(\"script.scm\" \"arg\")".to_owned()));
+        assert_eq!(eval(&mut interp, &format!("(load {:?}) seen-twice", path("isolated.scm"))),
+                   Ok("(1 1)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        &format!("(load {:?} (scheme-report-environment 7))",
+                                 path("isolated.scm"))),
+                   Err("Unbound variable: twice".to_owned()));
+        let error = eval(&mut interp, &format!("(load {:?})", path("bad.scm"))).unwrap_err();
+        assert_eq!(error, "car: expected a pair");
+        assert_eq!(interp.backtrace()[0].location.as_ref().map(|location| location.to_string()),
+                   Some(format!("{}:2:3", path("bad.scm"))));
+        assert_eq!(eval(&mut interp, "fine"), Ok("1".to_owned()));
+        assert!(eval(&mut interp, &format!("(load {:?})", path("missing.scm"))).is_err());
+        assert_eq!(eval(&mut interp, "(load 'file)"),
+                   Err("load: expected a string".to_owned()));
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn compiled_files() {
+        use std::{env, fs, process};
+        use std::io::Write;
+        let directory = env::temp_dir().join(format!("rusty-scheme-fasl-{}", process::id()));
+        fs::create_dir_all(directory.join("lib")).unwrap();
+        let files = [("program.scm",
+                      "(define-library (lib counter)
+                         (export tick! counter-name)
+                         (import (scheme base))
+                         (begin (define ticks '())
+                                (define counter-name \"counter\")
+                                (define (tick!) (set! ticks (cons 'tick ticks)) ticks)))
+                       (define-library (lib swap)
+                         (export swap!)
+                         (import (scheme base))
+                         (begin (define-syntax swap!
+                                  (syntax-rules ()
+                                    ((_ a b) (let ((tmp a)) (set! a b) (set! b tmp)))))))
+                       (import (lib counter) (lib swap) (lib shapes))
+                       (define-syntax twice (syntax-rules () ((_ x) (list x x))))
+                       (define data '(1 2.5 #\\x \"text\" #(a b) . tail))
+                       (define (pair-of f) (lambda (x) (cons (f x) counter-name)))
+                       (define swapped (let ((a 1) (b 2)) (swap! a b) (list a b)))
+                       (tick!)"),
+                     ("lib/shapes.sld",
+                      "(define-library (lib shapes)
+                         (export sides)
+                         (import (scheme base))
+                         (begin (define sides 4)))")];
+        for &(name, contents) in &files {
+            fs::File::create(directory.join(name)).unwrap().write_all(contents.as_bytes()).unwrap()
+        }
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        let mut interp = new();
+        interp.add_library_directory(&directory);
+        interp.compile_file(path("program.scm"), path("program.fasl")).unwrap();
+        interp.compile_file(path("lib/shapes.sld"), path("lib/shapes.fasl")).unwrap();
+        // The source of the library is not read if the compiled file is
+        // as new.
+        fs::File::create(directory.join("lib/shapes.sld")).unwrap();
+        let compiled = fs::read(directory.join("lib/shapes.fasl")).unwrap();
+        fs::File::create(directory.join("lib/shapes.fasl")).unwrap().write_all(&compiled).unwrap();
+
+        let mut interp = new();
+        interp.add_library_directory(&directory);
+        interp.load_file(path("program.fasl")).unwrap();
+        assert_eq!(eval(&mut interp, "data"),
+                   Ok("(1 2.5 #\\x \"text\" #(a b) . tail)".to_owned()));
+        assert_eq!(eval(&mut interp, "((pair-of car) (twice 1))"),
+                   Ok("(1 . \"counter\")".to_owned()));
+        assert_eq!(eval(&mut interp, "(list swapped sides (tick!))"),
+                   Ok("((2 1) 4 (tick tick))".to_owned()));
+        assert_eq!(eval(&mut interp, "(let ((x 1) (y 2)) (swap! x y) (list x y))"),
+                   Ok("(2 1)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        &format!("(load {:?} (scheme-report-environment 7))",
+                                 path("program.fasl"))),
+                   Err(format!("{}: compiled files can only be loaded at top level",
+                               path("program.fasl"))));
+
+        let bytes = fs::read(directory.join("program.fasl")).unwrap();
+        fs::File::create(directory.join("truncated.fasl"))
+            .unwrap()
+            .write_all(&bytes[..bytes.len() - 3])
+            .unwrap();
+        assert_eq!(interp.load_file(path("truncated.fasl")),
+                   Err(format!("{}: bad compiled file", path("truncated.fasl"))));
+        assert_eq!(interp.compile_file(path("program.fasl"), path("again.fasl")),
+                   Err(format!("{}: already compiled", path("program.fasl"))));
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn bound_names() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define-library (lib names)
+                (export exported)
+                (import (scheme base))
+                (begin (define exported 1)))
+              (import (lib names))
+              (define my-variable 1)
+              (define-syntax my-macro (syntax-rules () ((_) 1)))
+              (define p (make-parameter 1))
+              (parameterize ((p 2)) (p))")
+            .unwrap();
+        let names = interp.bound_names();
+        for name in &["car", "lambda", "define-syntax", "my-variable", "my-macro", "exported"] {
+            assert!(names.iter().any(|bound| bound == name), "{} is not listed", name)
+        }
+        // Internal names are left out.
+        assert!(!names.iter().any(|name| name.starts_with('%') || name.starts_with('(')));
+        let mut sorted = names.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(names, sorted);
+    }
+
+    #[test]
+    fn dynvectors() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(import (rusty dynvector))
+                         (define v (make-dynvector))
+                         (let loop ((i 10))
+                           (if (> i 0) (begin (vector-push! v i) (loop (decrement i)))))
+                         (list (dynvector-pop! v) (dynvector-length v) (dynvector-ref v 0)
+                               (dynvector->vector v) v (dynvector? v) (dynvector? #(1)))"),
+                   Ok("(1 9 10 #(10 9 8 7 6 5 4 3 2) #<dynvector 9> #t #f)".to_owned()));
+        assert_eq!(eval(&mut interp, "(dynvector-set! v 8 'x) (dynvector-ref v 8)"),
+                   Ok("x".to_owned()));
+        assert_eq!(eval(&mut interp, "(dynvector-ref v 9)"),
+                   Err("dynvector-ref: index out of range".to_owned()));
+        assert_eq!(eval(&mut interp, "(dynvector-pop! (make-dynvector 4))"),
+                   Err("dynvector-pop!: empty growable vector".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-push! #(1) 2)"),
+                   Err("vector-push!: expected a growable vector".to_owned()));
+        // The reader collects the elements of vectors in growable vectors.
+        assert_eq!(eval(&mut interp, "'#(1 #(2 3) #;4 'a #())"),
+                   Ok("#(1 #(2 3) (quote a) #())".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn scratch_registers() {
+        use bytecode::Opcode;
+        use value;
+        use super::{Frame, code};
+        let mut interp = new();
+        eval(&mut interp, "(define (f) (g (lambda () 1)) 2)").unwrap();
+        let mut input = Input::new("f".as_bytes(), "test");
+        read::read(&mut interp, &mut input).unwrap();
+        interp.execute().unwrap();
+        let heap = interp.heap();
+        // A frame of `f`, whose second register is scratch once the call
+        // is over.
+        let fp = heap.stack.len() - 1;
+        heap.stack.push(Value::new_fixnum(1));
+        heap.stack.push(Value::new_fixnum(2));
+        let bco = unsafe { &*code(&heap.stack[fp]).unwrap() };
+        let pc_of = |opcode| (0..bco.len()).find(|&pc| bco.instruction(pc).opcode == opcode);
+        let (call, load) = (pc_of(Opcode::Call).unwrap(), pc_of(Opcode::LoadConstant).unwrap());
+        assert_eq!((bco.live_registers(call), bco.live_registers(load)), (Some(2), Some(1)));
+        heap.control.current = Some(Frame { fp: fp, pc: call + 1 });
+        heap.clear_scratch_registers();
+        assert_eq!(heap.stack[fp + 2], Value::new_fixnum(2));
+        heap.control.current = Some(Frame { fp: fp, pc: load + 1 });
+        heap.clear_scratch_registers();
+        assert_eq!(heap.stack[fp + 1], Value::new_fixnum(1));
+        assert_eq!(heap.stack[fp + 2].get(), value::UNSPECIFIED);
+        heap.control.current = None;
+        heap.stack.truncate(fp);
+        // Collections find the frames of the running code.
+        assert_eq!(eval(&mut interp,
+                        "(define (loop n acc)
+                           (if (eq? n 0) acc (loop (decrement n) (cons (lambda () n) acc))))
+                         (length (loop 1000 '()))"),
+                   Ok("1000".to_owned()));
+    }
+
+    #[test]
+    fn scheme_base() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(cond ((assv 2 '((1 . one) (2 . two))) => cdr) (else 'none))"),
+                   Ok("two".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let* ((x '(1 2)) (y (cadr x)))
+                           (list (and x y) (or #f y) (case y ((1) 'one) ((2 3) 'more))))"),
+                   Ok("(2 2 more)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(do ((i 5 (decrement i)) (acc '() (cons i acc))) ((zero? i) acc))"),
+                   Ok("(1 2 3 4 5)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(list (max 1 2.5) (min 3 1) (boolean=? #t #t #t) (list? '(1 2)) (not 3))"),
+                   Ok("(2.5 1 #t #t #f)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(define-library (sign)
+                           (export sign)
+                           (import (scheme base))
+                           (begin
+                             (define (sign n)
+                               (cond ((negative? n) 'minus) ((zero? n) 'zero) (else 'plus)))))
+                         (import (sign))
+                         (sign -3)"),
+                   Ok("minus".to_owned()));
+        // The base is loaded before the first form, which can redefine it.
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(define (cadr x) 'mine) (cadr '(1 2))"),
+                   Ok("mine".to_owned()));
+
+        let mut bare = api::State::new_bare();
+        assert_eq!(eval(&mut bare, "(cadr '(1 2))"),
+                   Err("Unbound variable: cadr".to_owned()));
+        assert!(!bare.bound_names().iter().any(|name| name == "cond"));
+    }
+
+    #[test]
+    fn superinstructions_match_plain_instructions() {
+        let sources = ["(define (f x) (if (pair? x) (car x) 'none)) (list (f '(1)) (f 2))",
+                       "(define (count n acc) (if (eq? n 0) acc (count (decrement n) (cons n acc))))
+                        (count 3 '())",
+                       "(if (call/cc (lambda (k) (k #f))) 'yes 'no)",
+                       "(let ((v (vector 1 2))) (if (vector-ref v 1) (vector-ref v 0) 'no))",
+                       "('a 1)",
+                       "(if (1) 'yes 'no)",
+                       "(let ((x 5)) (x 'a))",
+                       "(let ((g 5)) (if (g) 1 2))",
+                       "(guard (e (#t 'caught)) (if ('a) 1 2))"];
+        let (mut fused, mut plain) = (new(), new());
+        plain.set_optimize(false);
+        for source in &sources {
+            assert_eq!(eval(&mut fused, source), eval(&mut plain, source), "{}", source);
+        }
+        assert_eq!(eval(&mut fused, "(if (1) 'yes 'no)"),
+                   Err("Attempt to call a non-procedure".to_owned()));
+        assert_eq!(eval(&mut fused, "(let ((x 5)) (x 'a))"),
+                   Err("Attempt to call a non-procedure".to_owned()));
+    }
+
+    #[test]
+    fn panicking_primitives() {
+        let mut interp = new();
+        {
+            let heap = interp.heap();
+            heap.intern("explode");
+            let symbol = heap.stack.pop().unwrap();
+            heap.set_global(&symbol, EXPLODE.to_value()).unwrap();
+        }
+        let len = interp.len();
+        assert_eq!(eval(&mut interp,
+                        "(guard (e ((error-object? e) (error-object-message e)))
+                           (vector-map (lambda (x) (explode)) #(1 2)))"),
+                   Ok("\"explode: panicked: boom\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(explode)"), Err("explode: panicked: boom".to_owned()));
+        assert_eq!(interp.len(), len);
+        assert_eq!(eval(&mut interp, "(vector-map decrement #(1 2))"), Ok("#(0 1)".to_owned()));
+    }
+
+    #[test]
+    fn interrupts() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use value;
+        static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+        /// `(interrupt!)`, which sets the flag.
+        fn interrupt(_: &mut Heap, _: usize) -> Result<Value, String> {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            Ok(Value::new(value::UNSPECIFIED))
+        }
+        static INTERRUPT: Primitive = Primitive {
+            name: "interrupt!",
+            min_args: 0,
+            max_args: Some(0),
+            function: interrupt,
+        };
+        let mut interp = new();
+        interp.set_interrupt(&INTERRUPTED);
+        {
+            let heap = interp.heap();
+            heap.intern("interrupt!");
+            let symbol = heap.stack.pop().unwrap();
+            heap.set_global(&symbol, INTERRUPT.to_value()).unwrap();
+        }
+        eval(&mut interp, "(define (spin) (spin))").unwrap();
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        assert_eq!(eval(&mut interp, "(spin)"), Err("Interrupted".to_owned()));
+        assert!(!INTERRUPTED.load(Ordering::SeqCst));
+        assert_eq!(eval(&mut interp, "(guard (e ((interrupt? e) 'caught)) (interrupt!) (spin))"),
+                   Ok("caught".to_owned()));
+        // A handler that returns lets the interrupted code go on.
+        eval(&mut interp, "(define count 0)").unwrap();
+        assert_eq!(eval(&mut interp,
+                        "(with-exception-handler
+                           (lambda (e) (set! count (+ count 1)) 'ignored)
+                           (lambda () (interrupt!) (list 1 2)))"),
+                   Ok("(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "count"), Ok("1".to_owned()));
+        assert_eq!(eval(&mut interp, "(guard (e (#t 'caught)) (raise 'x))"),
+                   Ok("caught".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn stack_overflows() {
+        let mut interp = new();
+        interp.set_stack_limit(1 << 10);
+        eval(&mut interp,
+             "(define (deep n) (if (eq? n 0) '() (cons n (deep (decrement n)))))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(pair? (deep 100))"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(deep 10000)"), Err("Stack overflow".to_owned()));
+        // The error can be handled, and the stack can be deep again after.
+        assert_eq!(eval(&mut interp, "(guard (e (#t (error-object-message e))) (deep 10000))"),
+                   Ok("\"Stack overflow\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(guard (e (#t 'overflow)) (deep 10000))"),
+                   Ok("overflow".to_owned()));
+        interp.set_stack_limit(1 << 20);
+        assert_eq!(eval(&mut interp, "(pair? (deep 10000))"), Ok("#t".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn eval_in_environments() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(eval '(car '(1 2)) (interaction-environment))"),
+                   Ok("1".to_owned()));
+        eval(&mut interp, "(eval '(define evaluated 'yes))").unwrap();
+        assert_eq!(eval(&mut interp, "evaluated"), Ok("yes".to_owned()));
+        eval(&mut interp,
+             "(define-syntax two (syntax-rules () ((_) 2)))
+              (define-library (lib value)
+                (export value)
+                (import (scheme base))
+                (begin (define value 'from-library)))")
+            .unwrap();
+        assert_eq!(eval(&mut interp, "(eval '(two) (interaction-environment))"),
+                   Ok("2".to_owned()));
+        // Other environments do not see the macros and imports of the top
+        // level.
+        assert_eq!(eval(&mut interp, "(eval '(if #f 1 (two)) (scheme-report-environment 7))"),
+                   Err("Unbound variable: two".to_owned()));
+        assert_eq!(eval(&mut interp, "(eval 'value (environment '(lib value)))"),
+                   Ok("from-library".to_owned()));
+        assert_eq!(eval(&mut interp, "(eval 'value (interaction-environment))"),
+                   Err("Unbound variable: value".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(eval '(let-syntax ((m (syntax-rules () ((_ x) (list x x)))))
+                                  (m 'e))
+                               (environment '(only (scheme base) list quote let-syntax)))"),
+                   Ok("(e e)".to_owned()));
+        assert_eq!(eval(&mut interp, "(scheme-report-environment 5)"),
+                   Err("scheme-report-environment: unsupported version".to_owned()));
+        assert_eq!(eval(&mut interp, "(eval 1 2)"),
+                   Err("eval: expected an environment".to_owned()));
+        assert_eq!(eval(&mut interp, "(eval 1 (environment '(lib nowhere)))"),
+                   Err("test:1:23: library not found: (lib nowhere)".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn guard_catches_raised_objects_and_errors() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(guard (e (#t (bytevector e))) (raise 7))"),
+                   Ok("#u8(7)".to_owned()));
+        assert_eq!(eval(&mut interp, "(guard (e (#f 1)) 5)"), Ok("5".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(guard (e ((error-object? e) (error-object-irritants e)))
+                           (error \"bad\" 1 2))"),
+                   Ok("(1 2)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(guard (e ((error-object? e) (error-object-message e)))
+                           (undefined-variable))"),
+                   Ok("\"Unbound variable: undefined-variable\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(guard (e ((eq? e 'x) => (lambda (t) t))) (raise 'x))"),
+                   Ok("#t".to_owned()));
+        // Unhandled conditions are raised again, to outer handlers.
+        assert_eq!(eval(&mut interp, "(guard (e (#t 'outer)) (guard (e (#f 'inner)) (raise 1)))"),
+                   Ok("outer".to_owned()));
+        assert_eq!(eval(&mut interp, "(guard (e ((eq? e 1) 'one)) (raise 'oops))"),
+                   Err("Uncaught exception: oops".to_owned()));
+        assert_eq!(eval(&mut interp, "(error \"Something bad:\" 42 'x)"),
+                   Err("Something bad: 42 x".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn exception_handlers() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(with-exception-handler
+                           (lambda (c) 42)
+                           (lambda () (bytevector (raise-continuable 'c) 1)))"),
+                   Ok("#u8(42 1)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(with-exception-handler (lambda (c) 42) (lambda () (raise 'c)))"),
+                   Err("handler returned from non-continuable raise".to_owned()));
+        // Handlers run with the outer handlers installed.
+        assert_eq!(eval(&mut interp,
+                        "(guard (e (#t (bytevector e)))
+                           (with-exception-handler
+                             (lambda (c) (raise (decrement c)))
+                             (lambda () (raise 8))))"),
+                   Ok("#u8(7)".to_owned()));
+        // Escaping from a `guard` runs the `after` thunks it leaves.
+        eval(&mut interp, "(define n 5)").unwrap();
+        assert_eq!(eval(&mut interp,
+                        "(guard (e (#t n))
+                           (dynamic-wind (lambda () #f)
+                                         (lambda () (raise 'x))
+                                         (lambda () (set! n (decrement n)))))"),
+                   Ok("4".to_owned()));
+        assert_eq!(eval(&mut interp, "(raise 'after)"),
+                   Err("Uncaught exception: after".to_owned()));
+        assert!(interp.is_empty());
+    }
+
+    #[test]
+    fn literal_constants_are_immutable() {
+        let mut interp = new();
+        eval(&mut interp, "(define (f) '(1 2))").unwrap();
+        for _ in 0..2 {
+            let mut input = Input::new("(f)".as_bytes(), "test");
+            read::read(&mut interp, &mut input).unwrap();
+            interp.execute().unwrap();
+        }
+        let heap = interp.heap();
+        let (first, second) = (heap.stack[0].clone(), heap.stack[1].clone());
+        assert_eq!(first.get(), second.get());
+        assert!(first.immutablep() && first.cdr().unwrap().immutablep());
+    }
+
+    #[test]
+    fn pairs_can_be_mutated() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(let ((p (list 1 2)))
+                           (set-car! p 'a)
+                           (set-cdr! (cdr p) p)
+                           (list (car p) (eq? (cddr p) p)))"),
+                   Ok("(a #t)".to_owned()));
+        assert!(eval(&mut interp, "(set-car! '(1 2) 3)")
+                    .unwrap_err()
+                    .contains("set-car!: cannot modify a constant pair"));
+        assert!(eval(&mut interp, "(set-cdr! '(1 . 2) 3)")
+                    .unwrap_err()
+                    .contains("set-cdr!: cannot modify a constant pair"));
+        assert!(eval(&mut interp, "(set-cdr! 1 2)").unwrap_err().contains("expected a pair"));
+    }
+
+    #[test]
+    fn opcodes_are_listed_in_order() {
+        use bytecode::{self, Opcode};
+        macro_rules! opcodes {
+            ($($opcode: ident),*) => { [$(Opcode::$opcode),*] }
+        }
+        let opcodes = with_opcodes!(opcodes);
+        assert_eq!(opcodes.len(), bytecode::OPCODE_COUNT as usize);
+        for (number, &opcode) in opcodes.iter().enumerate() {
+            assert_eq!(opcode as usize, number)
+        }
+    }
+
+    #[test]
+    fn file_system() {
+        use std::{env, fs, process};
+        let directory = env::temp_dir().join(format!("rusty-scheme-files-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| format!("{:?}", directory.join(name).to_string_lossy());
+        let mut interp = new();
+        eval(&mut interp, &format!("(define dir {})", path(""))).unwrap();
+        eval(&mut interp, &format!("(define a {})", path("a.txt"))).unwrap();
+        eval(&mut interp, &format!("(define b {})", path("b.txt"))).unwrap();
+        eval(&mut interp, "(with-output-to-file a (lambda () (write '(1 \"two\"))))").unwrap();
+        assert_eq!(eval(&mut interp, "(with-input-from-file a read)"),
+                   Ok("(1 \"two\")".to_owned()));
+        assert_eq!(eval(&mut interp, "(file-size a)"), Ok("9".to_owned()));
+        eval(&mut interp, "(rename-file a b)").unwrap();
+        assert_eq!(eval(&mut interp, "(list (file-exists? a) (directory-list dir))"),
+                   Ok("(#f (\"b.txt\"))".to_owned()));
+        assert_eq!(eval(&mut interp, "(call-with-input-file b read-line)"),
+                   Ok("\"(1 \\\"two\\\")\"".to_owned()));
+        eval(&mut interp, "(delete-file b)").unwrap();
+        assert_eq!(eval(&mut interp,
+                        "(guard (e ((file-error? e) 'missing)) (open-input-file b))"),
+                   Ok("missing".to_owned()));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn processes() {
+        let mut interp = new();
+        eval(&mut interp,
+             "(define p (run-process \"sh\" '(\"-c\" \"read x; echo $x $GREETING; exit 3\")
+                                     '((\"GREETING\" . \"hello\"))))")
+            .unwrap();
+        eval(&mut interp, "(write-string \"hi\" (process-input p))").unwrap();
+        assert_eq!(eval(&mut interp, "(process-wait p)"), Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(read-line (process-output p))"),
+                   Ok("\"hi hello\"".to_owned()));
+        assert!(eval(&mut interp, "(run-process \"/nonexistent\" '())").is_err());
+        assert_eq!(eval(&mut interp,
+                        "(import (process))
+                         (let ((p (run-process \"sh\" '(\"-c\" \"pwd; echo oops >&2\") '() \"/\")))
+                           (list (process? p) (process-wait p) (read-line (process-output p))
+                                 (read-line (process-error p))))"),
+                   Ok("(#t 0 \"/\" \"oops\")".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((p (run-process \"sleep\" '(\"10\"))))
+                           (process-kill p)
+                           (process-wait p))"),
+                   Ok("#f".to_owned()));
+        assert_eq!(eval(&mut interp, "(process-output 'p)"),
+                   Err("process-output: expected a process".to_owned()));
+        assert!(eval(&mut interp, "(channel-send! (make-channel) p)").is_err());
+        assert!(interp.save_image(&mut vec![]).is_err());
+    }
+
+    /// Runs `source` in a copy of this test process, in the test `exit`,
+    /// and returns its exit status and standard output.
+    fn exit_in_child(source: &str) -> (Option<i32>, String) {
+        use std::{env, process};
+        let output = process::Command::new(env::current_exe().unwrap())
+                         .args(&["interp::tests::exit", "--exact", "--nocapture"])
+                         .env("RUSTY_SCHEME_EXIT", source)
+                         .output()
+                         .unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    #[test]
+    fn exit() {
+        if let Ok(source) = ::std::env::var("RUSTY_SCHEME_EXIT") {
+            let mut interp = new();
+            panic!("exit returned {:?}", eval(&mut interp, &source))
+        }
+        let wind = "(display \"[before]\")
+                    (dynamic-wind (lambda () #f)
+                                  (lambda () (EXIT 3))
+                                  (lambda () (display \"[after]\")))";
+        let (status, output) = exit_in_child(&wind.replace("EXIT", "exit"));
+        assert_eq!(status, Some(3));
+        assert!(output.contains("[before][after]"), "{}", output);
+        let (status, output) = exit_in_child(&wind.replace("EXIT", "emergency-exit"));
+        assert_eq!(status, Some(3));
+        assert!(!output.contains("[before]") && !output.contains("[after]"), "{}", output);
+        assert_eq!(exit_in_child("(exit #f)").0, Some(1));
+        assert_eq!(exit_in_child("(exit)").0, Some(0));
+    }
+
+    #[test]
+    fn sockets() {
+        let mut interp = new();
+        eval(&mut interp, "(define listener (tcp-listen \"127.0.0.1\" 0))").unwrap();
+        eval(&mut interp,
+             "(define client (call-with-values
+                               (lambda () (tcp-connect \"127.0.0.1\" (socket-local-port listener)))
+                               cons))")
+            .unwrap();
+        eval(&mut interp,
+             "(write-string \"ping\n\" (cdr client)) (flush-output-port (cdr client))")
+            .unwrap();
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (tcp-accept listener))
+                           (lambda (in out) (read-line in)))"),
+                   Ok("\"ping\"".to_owned()));
+        eval(&mut interp,
+             "(define a (udp-open \"127.0.0.1\" 0)) (define b (udp-open \"127.0.0.1\" 0))")
+            .unwrap();
+        eval(&mut interp, "(udp-send a (bytevector 1 2) \"127.0.0.1\" (socket-local-port b))")
+            .unwrap();
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (udp-receive b))
+                           (lambda (bytes host port)
+                             (list bytes host (= port (socket-local-port a)))))"),
+                   Ok("(#u8(1 2) \"127.0.0.1\" #t)".to_owned()));
+        eval(&mut interp, "(socket-close a)").unwrap();
+        assert_eq!(eval(&mut interp, "(udp-send a (bytevector) \"127.0.0.1\" 1)"),
+                   Err("udp-send: expected an open UDP socket".to_owned()));
+        assert_eq!(eval(&mut interp, "(channel-send! (make-channel) b)"),
+                   Err("channel-send!: cannot copy #<rust-data> to another heap".to_owned()));
+        assert!(interp.save_image(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn images_refuse_host_objects() {
+        let mut interp = new();
+        for source in &["(define x (make-channel))",
+                        "(define x (make-thread (lambda () 1)))",
+                        "(define x (udp-open \"127.0.0.1\" 0))",
+                        "(define x (list (tcp-listen \"127.0.0.1\" 0)))"] {
+            eval(&mut interp, source).unwrap();
+            assert!(interp.save_image(&mut vec![])
+                          .unwrap_err()
+                          .starts_with("save-image: cannot save"));
+        }
+        eval(&mut interp, "(define x 1)").unwrap();
+        assert!(interp.save_image(&mut vec![]).is_ok());
+    }
+
+    #[test]
+    fn environment_variables() {
+        use std::env;
+        env::set_var("RUSTY_SCHEME_TEST", "set");
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(get-environment-variable \"RUSTY_SCHEME_TEST\")"),
+                   Ok("\"set\"".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let loop ((variables (get-environment-variables)))
+                           (if (equal? (car (car variables)) \"RUSTY_SCHEME_TEST\")
+                               (cdr (car variables))
+                               (loop (cdr variables))))"),
+                   Ok("\"set\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(get-environment-variable \"RUSTY_SCHEME_UNSET\")"),
+                   Ok("#f".to_owned()));
+    }
+
+    #[test]
+    fn time_and_dates() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(jiffies-per-second)"), Ok("1000000".to_owned()));
+        assert_eq!(eval(&mut interp, "(date? (current-date))"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(date->string (seconds->date 1000000000 3600) \"~4 ~A\")"),
+                   Ok("\"2001-09-09T02:46:40+0100 Sunday\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(date->string (seconds->date 0))"),
+                   Ok("\"Thu Jan 01 00:00:00Z 1970\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(date->seconds (make-date 0 40 46 2 9 9 2001 3600))"),
+                   Ok("1000000000".to_owned()));
+        assert_eq!(eval(&mut interp, "(date-year (seconds->date -1))"), Ok("1969".to_owned()));
+        assert_eq!(eval(&mut interp, "(date-week-day (make-date 0 0 0 0 29 2 2000 0))"),
+                   Ok("2".to_owned()));
+        assert!(eval(&mut interp, "(make-date 0 0 0 0 29 2 1900 0)").is_err());
+    }
+
+    #[test]
+    fn random_numbers() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp, "(random-integer 1)"), Ok("0".to_owned()));
+        assert_eq!(eval(&mut interp, "(random-source? (default-random-source))"),
+                   Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((a (random-source-make-integers (make-random-source 7)))
+                               (b (random-source-make-integers (make-random-source 7))))
+                           (equal? (list (a 1000) (a 1000)) (list (b 1000) (b 1000))))"),
+                   Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((source (make-random-source)))
+                           (let ((next (random-source-make-reals source))
+                                 (saved (random-source-state-ref source)))
+                             (let ((x (next)))
+                               (random-source-state-set! source saved)
+                               (eqv? x (next)))))"),
+                   Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(bytevector-length (random-entropy 4))"),
+                   Ok("4".to_owned()));
+        assert!(eval(&mut interp, "(random-integer 0)").is_err());
+    }
+
+    #[test]
+    fn srfi_1() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 1))").is_ok());
+        assert_eq!(eval(&mut interp, "(iota 5)"), Ok("(0 1 2 3 4)".to_owned()));
+        assert_eq!(eval(&mut interp, "(iota 3 1 2)"), Ok("(1 3 5)".to_owned()));
+        assert_eq!(eval(&mut interp, "(fold cons '() '(a b c))"), Ok("(c b a)".to_owned()));
+        assert_eq!(eval(&mut interp, "(fold-right cons '() '(a b c))"), Ok("(a b c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(reduce cons 'none '(a b c))"), Ok("(c b . a)".to_owned()));
+        assert_eq!(eval(&mut interp, "(filter symbol? '(a 1 b \"c\"))"), Ok("(a b)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (partition symbol? '(a 1 b 2))) list)"),
+                   Ok("((a b) (1 2))".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(call-with-values (lambda () (span symbol? '(a b 1 c))) list)"),
+                   Ok("((a b) (1 c))".to_owned()));
+        assert_eq!(eval(&mut interp, "(delete-duplicates '(a b a c b))"), Ok("(a b c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(assoc \"b\" '((\"a\" . 1) (\"b\" . 2)))"),
+                   Ok("(\"b\" . 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(list-index pair? '(1 2 (3)))"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(map list '(a b c) '(1 2))"), Ok("((a 1) (b 2))".to_owned()));
+        assert_eq!(eval(&mut interp, "(append '(a) '(b c) 'd)"), Ok("(a b c . d)".to_owned()));
+        assert_eq!(eval(&mut interp, "(take '(a b c d) 2)"), Ok("(a b)".to_owned()));
+        assert_eq!(eval(&mut interp, "(third '(a b c d))"), Ok("c".to_owned()));
+        assert_eq!(eval(&mut interp, "(lset-union eq? '(a b) '(c a d))"),
+                   Ok("(d c a b)".to_owned()));
+        assert_eq!(eval(&mut interp, "(length+ (circular-list 'a 'b))"), Ok("#f".to_owned()));
+        assert_eq!(eval(&mut interp, "(map list '(a b c) (circular-list 1))"),
+                   Ok("((a 1) (b 1) (c 1))".to_owned()));
+        assert_eq!(eval(&mut interp, "(unfold null? car cdr '(a b c))"), Ok("(a b c)".to_owned()));
+        assert!(eval(&mut interp, "(length '(a . b))").is_err());
+    }
+
+    #[test]
+    fn srfi_13() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 13))").is_ok());
+        assert_eq!(eval(&mut interp, "(string-length \"aé€\")"), Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-ref \"aéb\" 2)"), Ok("#\\b".to_owned()));
+        assert_eq!(eval(&mut interp, "(substring \"héllo\" 1 3)"), Ok("\"él\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-upcase \"straße\")"), Ok("\"STRASSE\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-titlecase \"hello wORLD\")"),
+                   Ok("\"Hello World\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string<? \"abc\" \"abd\" \"b\")"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-ci=? \"ABC\" \"abc\")"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-map char-upcase \"abc\")"), Ok("\"ABC\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-index \"héllo\" #\\l)"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-index-right \"héllo\" #\\l)"), Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-index \"a1b2\" char-numeric? 2)"),
+                   Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-contains \"héllo world\" \"wor\")"),
+                   Ok("6".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-join '(\"a\" \"b\" \"c\") \", \")"),
+                   Ok("\"a, b, c\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-join '(\"a\" \"b\") \"/\" 'prefix)"),
+                   Ok("\"/a/b\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-split \"a,b,,c\" #\\,)"),
+                   Ok("(\"a\" \"b\" \"\" \"c\")".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-split \"  a  b \")"),
+                   Ok("(\"a\" \"b\")".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-trim-both \"  hi  \")"), Ok("\"hi\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-trim \"xxhix\" #\\x)"), Ok("\"hix\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-pad \"42\" 5 #\\0)"), Ok("\"00042\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-pad \"12345\" 3)"), Ok("\"345\"".to_owned()));
+        assert_eq!(eval(&mut interp, "(string-pad-right \"ab\" 4)"), Ok("\"ab  \"".to_owned()));
+        assert!(eval(&mut interp, "(string-ref \"abc\" 3)").is_err());
+    }
+
+    #[test]
+    fn srfi_133() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 133))").is_ok());
+        assert_eq!(eval(&mut interp, "(vector-map cons '#(a b c) '#(1 2))"),
+                   Ok("#((a . 1) (b . 2))".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-fold (lambda (acc x) (cons x acc)) '() '#(a b c))"),
+                   Ok("(c b a)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-fold-right cons* '() '#(a b) '#(1 2))"),
+                   Ok("(a 1 b 2)".to_owned()));
+        assert_eq!(eval(&mut interp, "(subvector '#(a b c d) 1 3)"), Ok("#(b c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector->list '#(a b c) 1)"), Ok("(b c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(list->vector '(a b))"), Ok("#(a b)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-append '#(a) '#() '#(b c))"),
+                   Ok("#(a b c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-index symbol? '#(1 \"a\" b))"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-count pair? '#((a) b (c)))"), Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((v (vector 'a 'b 'c 'd 'e)))
+                           (vector-copy! v 1 v 0 3)
+                           v)"),
+                   Ok("#(a a b c e)".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((v (vector 'a 'b 'c 'd)))
+                           (vector-fill! v 'z 2)
+                           (vector-swap! v 0 1)
+                           (vector-map! list v)
+                           v)"),
+                   Ok("#((b) (a) (z) (z))".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((v (vector 1 2 3 4 5)))
+                           (vector-reverse! v 1)
+                           v)"),
+                   Ok("#(1 5 4 3 2)".to_owned()));
+        assert!(eval(&mut interp, "(vector-fill! '#(a b) 'c)").is_err());
+        assert!(eval(&mut interp, "(vector-ref '#(a b) 2)").is_err());
+    }
+
+    #[test]
+    fn srfi_132() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 132))").is_ok());
+        assert_eq!(eval(&mut interp, "(list-sort < '(3 1 2.5 -4))"), Ok("(-4 1 2.5 3)".to_owned()));
+        assert_eq!(eval(&mut interp, "(list-sort string>? '(\"b\" \"c\" \"a\"))"),
+                   Ok("(\"c\" \"b\" \"a\")".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(list-sort (lambda (x y) (< (car x) (car y)))
+                                    '((2 . a) (1 . b) (2 . c) (1 . d) (0 . e)))"),
+                   Ok("((0 . e) (1 . b) (1 . d) (2 . a) (2 . c))".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((v (vector 5 3 9 1 7)))
+                           (vector-sort! v (lambda (x y) (> x y)) 1)
+                           v)"),
+                   Ok("#(5 9 7 3 1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-sort char<? '#(#\\c #\\a #\\b))"),
+                   Ok("#(#\\a #\\b #\\c)".to_owned()));
+        assert_eq!(eval(&mut interp, "(list-sorted? < '(1 2 2 3))"), Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-sorted? < '#(1 3 2))"), Ok("#f".to_owned()));
+        assert_eq!(eval(&mut interp, "(list-merge < '(1 3 5) '(2 3 4))"),
+                   Ok("(1 2 3 3 4 5)".to_owned()));
+        assert_eq!(eval(&mut interp, "(vector-merge < '#(1 4) '#(2 3 5))"),
+                   Ok("#(1 2 3 4 5)".to_owned()));
+        assert!(eval(&mut interp, "(vector-merge < '#(1) '(2))").is_err());
+        assert!(eval(&mut interp, "(list-sort < '(1 a))").is_err());
+    }
+
+    #[test]
+    fn srfi_125() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 125) (srfi 132))").is_ok());
+        assert!(eval(&mut interp, "(define t (hash-table equal? \"a\" 1 \"b\" 2))").is_ok());
+        assert_eq!(eval(&mut interp, "(hash-table-ref/default t \"a\" 0)"), Ok("1".to_owned()));
+        assert_eq!(eval(&mut interp, "(hash-table-ref t \"c\" (lambda () 'none))"),
+                   Ok("none".to_owned()));
+        assert_eq!(eval(&mut interp, "(hash-table-ref t \"b\" (lambda () 'none) list)"),
+                   Ok("(2)".to_owned()));
+        assert!(eval(&mut interp, "(hash-table-ref t \"c\")").is_err());
+        assert!(eval(&mut interp, "(hash-table-update!/default t \"c\" list 3)").is_ok());
+        assert!(eval(&mut interp, "(hash-table-update! t \"a\" list)").is_ok());
+        assert_eq!(eval(&mut interp, "(list-sort string<? (hash-table-keys t))"),
+                   Ok("(\"a\" \"b\" \"c\")".to_owned()));
+        assert_eq!(eval(&mut interp, "(hash-table-ref t \"a\")"), Ok("(1)".to_owned()));
+        assert_eq!(eval(&mut interp, "(hash-table-fold (lambda (k v n) (cons k n)) '() t)"),
+                   eval(&mut interp, "(hash-table-keys t)"));
+        assert_eq!(eval(&mut interp, "(hash-table-count (lambda (k v) (pair? v)) t)"),
+                   Ok("2".to_owned()));
+        assert!(eval(&mut interp, "(define u (hash-table-copy t))").is_ok());
+        assert_eq!(eval(&mut interp, "(hash-table-delete! u \"a\" \"b\" \"d\")"),
+                   Ok("2".to_owned()));
+        assert_eq!(eval(&mut interp, "(hash-table-size t)"), Ok("3".to_owned()));
+        assert!(eval(&mut interp, "(hash-table-difference! t u)").is_ok());
+        assert_eq!(eval(&mut interp, "(list-sort string<? (hash-table-keys t))"),
+                   Ok("(\"a\" \"b\")".to_owned()));
+        assert!(eval(&mut interp, "(hash-table-union! t u)").is_ok());
+        assert_eq!(eval(&mut interp, "(hash-table-size t)"), Ok("3".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(hash-table->alist (alist->hash-table '((a . 1) (a . 2)) eq?))"),
+                   Ok("((a . 1))".to_owned()));
+        assert!(eval(&mut interp, "(hash-table-set! t 'x)").is_err());
+    }
+
+    #[test]
+    fn json() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (rusty-scheme json))").is_ok());
+        assert!(eval(&mut interp,
+                     "(define (parse s . rest) (apply json-read (open-input-string s) rest))")
+            .is_ok());
+        assert!(eval(&mut interp,
+                     "(define (unparse x)
+                        (let ((p (open-output-string))) (json-write x p) (get-output-string p)))")
+            .is_ok());
+        assert_eq!(eval(&mut interp,
+                        r#"(parse " {\"a\": [1, -2.5e1, \"\\u0041\"], \"b\": null}")"#),
+                   Ok("((a . #(1 -25.0 \"A\")) (b . null))".to_owned()));
+        assert_eq!(eval(&mut interp, "(eof-object? (parse \"  \"))"), Ok("#t".to_owned()));
+        assert!(eval(&mut interp, "(parse \"[1,]\")").is_err());
+        assert!(eval(&mut interp, "(parse \"01\")").is_err());
+        assert_eq!(eval(&mut interp, r#"(hash-table-ref (parse "{\"k\": true}" 'hash-table) 'k)"#),
+                   Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(unparse (vector 1 2.0 #f 'null '()))"),
+                   Ok("\"[1,2.0,false,null,{}]\"".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        r#"(equal? (parse (unparse '((a . "q\"\n") ("b" . #(#t)))))
+                                   '((a . "q\"\n") (b . #(#t))))"#),
+                   Ok("#t".to_owned()));
+        assert!(eval(&mut interp, "(unparse '(1 2))").is_err());
+        assert!(eval(&mut interp, "(unparse 'foo)").is_err());
+    }
+
+    #[test]
+    fn format() {
+        let mut interp = new();
+        assert!(eval(&mut interp, "(import (srfi 28) (srfi 48))").is_ok());
+        assert_eq!(eval(&mut interp, r#"(format "~a and ~s~%" "x" "y")"#),
+                   Ok(r#""x and \"y\"\n""#.to_owned()));
+        assert_eq!(eval(&mut interp, r#"(format #f "[~5a|~5d|~x|~b]" 'ab 42 255 -5)"#),
+                   Ok(r#""[ab   |   42|ff|-101]""#.to_owned()));
+        assert_eq!(eval(&mut interp, r#"(format #f "~8,3f ~~ ~?" 3.14159 "<~a>" '(1))"#),
+                   Ok(r#""   3.142 ~ <1>""#.to_owned()));
+        assert_eq!(eval(&mut interp, r#"(format #f "a~&~&b")"#), Ok(r#""a\nb""#.to_owned()));
+        assert_eq!(eval(&mut interp,
+                        r#"(let ((p (open-output-string)))
+                             (format p "~c~a" #\z 1)
+                             (get-output-string p))"#),
+                   Ok(r#""z1""#.to_owned()));
+        assert!(eval(&mut interp, r#"(format "~a")"#).is_err());
+        assert!(eval(&mut interp, r#"(format "~a" 1 2)"#).is_err());
+        assert!(eval(&mut interp, r#"(format "~x" 1.5)"#).is_err());
+        assert!(eval(&mut interp, r#"(format "~q" 1)"#).is_err());
+    }
+
+    #[test]
+    fn pretty_print() {
+        let mut interp = new();
+        assert!(eval(&mut interp,
+                     "(define (pretty x)
+                        (let ((p (open-output-string))) (pretty-print x p) (get-output-string p)))")
+            .is_ok());
+        assert_eq!(eval(&mut interp, "(pretty '(a #(b) \"c\"))"),
+                   Ok(r#""(a #(b) \"c\")\n""#.to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(pretty '(define (f x)
+                                    (let loop ((i 0))
+                                      (if (< i 10)
+                                          (loop (+ i 1))
+                                          (list aaaaaaaaaaaaaaaaaaaa
+                                                bbbbbbbbbbbbbbbbbbbb
+                                                cccccccccccccccccccc)))))"),
+                   Ok(["\"(define (f x)",
+                       "  (let loop ((i 0))",
+                       "    (if (< i 10)",
+                       "        (loop (+ i 1))",
+                       "        (list aaaaaaaaaaaaaaaaaaaa",
+                       "              bbbbbbbbbbbbbbbbbbbb",
+                       "              cccccccccccccccccccc))))\\n\""]
+                          .join("\\n")));
+        assert_eq!(eval(&mut interp, "(pretty (circular-list 1 2))"),
+                   Ok(r##""#0=(1 2 . #0#)\n""##.to_owned()));
+    }
+
+    #[test]
+    fn number_strings() {
+        let mut interp = new();
+        assert_eq!(eval(&mut interp,
+                        "(map number->string (list 255 -255 0.1 1e21 -0.0 +inf.0) \
+                                             '(16 2 10 10 10 10))"),
+                   Ok(r#"("ff" "-11111111" "0.1" "1e21" "-0.0" "+inf.0")"#.to_owned()));
+        assert!(eval(&mut interp, "(number->string 1.5 16)").is_err());
+        assert!(eval(&mut interp, "(number->string 1 3)").is_err());
+        assert_eq!(eval(&mut interp,
+                        r##"(map string->number
+                                '("ff" "#b101" "#e1.0" "#i#x10" "1/2" "x" "1e400"))"##),
+                   Ok("(#f 5 1 16.0 0.5 #f +inf.0)".to_owned()));
+        assert_eq!(eval(&mut interp, r#"(string->number "ff" 16)"#), Ok("255".to_owned()));
+        assert_eq!(eval(&mut interp, r##"(string->number "#d10" 16)"##), Ok("10".to_owned()));
+        assert_eq!(eval(&mut interp,
+                        "(let ((x (/ 1.0 3))) (= x (string->number (number->string x))))"),
+                   Ok("#t".to_owned()));
+        assert_eq!(eval(&mut interp, "(list (exact 2.0) (inexact 2) (exact? 2) (inexact? 2.5))"),
+                   Ok("(2 2.0 #t #t)".to_owned()));
+        assert!(eval(&mut interp, "(exact 2.5)").is_err());
     }
 }
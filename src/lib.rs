@@ -3,10 +3,30 @@
 #![allow(dead_code)]
 #![deny(warnings)]
 
+// Ports read and write through `port::Source` and `port::Sink`, and the
+// clocks and standard streams come from `platform`, so that those need not
+// come from `std`.  The reader, the heap dump, the symbol and library
+// tables, and the primitives for files, processes, sockets and threads
+// still use it, so there is no feature to build without `std` yet (see
+// TODO.txt).
+
 #[macro_use]
 extern crate log;
 
+#[cfg(not(target_arch = "wasm32"))]
 extern crate env_logger;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(feature = "async")]
+extern crate tokio;
 // macro_rules! debug {
 // ($($exp:expr),*) => {
 // if cfg!(debug_assertions) {
@@ -32,14 +52,32 @@ macro_rules! bug {
 mod value;
 mod state;
 mod arith;
+mod equiv;
 mod bytecode;
+#[cfg(feature = "stack-bytecode")]
+mod stack_bytecode;
 mod string;
 mod alloc;
 mod symbol;
 mod interp;
 mod read;
+mod print;
+mod fasl;
+mod image;
+mod platform;
+mod message;
+mod port;
+mod compiler;
 mod api;
+mod builtins;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "async")]
+pub mod net;
 pub use api::*;
+pub use platform::{Console, StdConsole};
 pub use bytecode::{Opcode, BCO};
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,269 @@
+//! Values copied out of a heap, so that they can be sent to another thread
+//! and copied into its heap (see `builtins::thread`).  `Heap::migrate`
+//! copies values between heaps through messages too.
+//!
+//! A message holds the objects reachable from the value it was made from,
+//! each once, referring to each other by index, so that shared structure
+//! and cycles are copied as they are.  Immediates and primitives are the
+//! same in every heap, so they are kept as they are.  Channels and threads
+//! are shared, not copied: the copy refers to the same one.
+//!
+//! Records, hash tables, promises, conditions and multiple values cannot be
+//! copied, and neither can Rust objects, such as those behind sockets.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use alloc::Heap;
+use builtins::thread::{self, Handle};
+use print;
+use value::{self, Kind, Value};
+
+/// A value in a message.
+#[derive(Clone, Debug)]
+enum Datum {
+    /// An immediate, or a primitive.
+    Immediate(usize),
+
+    /// The object with this index.
+    Object(usize),
+}
+
+/// An object in a message.  Those that can be mutated record whether they
+/// were immutable.
+#[derive(Debug)]
+enum Object {
+    /// Not filled in yet.
+    Pending,
+    Symbol(String),
+    /// A boxed float (see `alloc::float`).
+    Float(f64),
+    String(String, bool),
+    Bytevector(Vec<u8>, bool),
+    Pair(Datum, Datum, bool),
+    Vector(Vec<Datum>, bool),
+    /// The code and the constants vector.
+    Bytecode(Vec<u8>, Datum),
+    /// The code and the captured values.
+    Closure(Datum, Vec<Datum>),
+    /// A channel or a thread.
+    Handle(Arc<Handle>),
+}
+
+/// A copy of a value that belongs to no heap.
+#[derive(Debug)]
+pub struct Message {
+    objects: Vec<Object>,
+    root: Datum,
+}
+
+impl Message {
+    /// Copies `value`.
+    pub fn new(value: &Value) -> Result<Self, String> {
+        let mut copier = Copier {
+            objects: vec![],
+            indices: HashMap::new(),
+            pending: vec![],
+        };
+        let root = copier.datum(value);
+        while let Some((index, value)) = copier.pending.pop() {
+            copier.objects[index] = try!(copier.object(&value))
+        }
+        Ok(Message {
+            objects: copier.objects,
+            root: root,
+        })
+    }
+
+    /// Copies the message into `heap`, and pushes the copy.
+    pub fn push_to(&self, heap: &mut Heap) -> Result<(), String> {
+        let base = heap.stack.len();
+        let result = self.allocate(heap, base).map(|()| {
+            self.fill(heap, base);
+            self.value(heap, base, &self.root)
+        });
+        heap.stack.truncate(base);
+        Ok(heap.stack.push(try!(result)))
+    }
+
+    /// Allocates the objects, pushing each one in order.  The parts that
+    /// refer to other objects are left unspecified, except for the
+    /// constants of code and the code of closures, which must be there from
+    /// the start, so code is allocated after everything else, and closures
+    /// after code.
+    fn allocate(&self, heap: &mut Heap, base: usize) -> Result<(), String> {
+        let unspecified = Value::new(value::UNSPECIFIED);
+        for object in &self.objects {
+            let top = heap.stack.len();
+            match *object {
+                Object::Symbol(ref name) => heap.intern(name),
+                Object::Float(float) => try!(heap.alloc_float(float)),
+                Object::String(ref string, _) => try!(heap.alloc_string(string)),
+                Object::Bytevector(ref bytes, _) => try!(heap.alloc_bytevector_from(bytes)),
+                Object::Pair(..) => {
+                    heap.stack.push(unspecified.clone());
+                    try!(heap.alloc_pair(top, top));
+                }
+                Object::Vector(ref elements, _) => {
+                    heap.stack.resize(top + elements.len(), unspecified.clone());
+                    try!(heap.alloc_vector(top, top + elements.len()))
+                }
+                Object::Handle(ref handle) => try!(thread::alloc_handle(heap, handle.clone())),
+                Object::Bytecode(..) | Object::Closure(..) => heap.stack.push(unspecified.clone()),
+                Object::Pending => bug!("message with a pending object"),
+            }
+            let object = heap.stack.pop().unwrap();
+            heap.stack.truncate(top);
+            heap.stack.push(object)
+        }
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Object::Bytecode(ref code, ref constants) = *object {
+                let constants = self.value(heap, base, constants);
+                heap.stack.push(constants);
+                try!(heap.alloc_bytecode(code));
+                heap.stack[base + index] = heap.stack.pop().unwrap()
+            }
+        }
+        for (index, object) in self.objects.iter().enumerate() {
+            if let Object::Closure(ref code, ref captured) = *object {
+                let top = heap.stack.len();
+                let code = self.value(heap, base, code);
+                match code.kind() {
+                    Kind::Bytecode(_) | Kind::Primitive(_) => heap.stack.push(code),
+                    _ => return Err("bad message: closure without code".to_owned()),
+                }
+                heap.stack.resize(top + 1 + captured.len(), unspecified.clone());
+                try!(heap.alloc_closure(top, top + 1 + captured.len()));
+                heap.stack[base + index] = heap.stack.pop().unwrap();
+                heap.stack.truncate(top)
+            }
+        }
+        Ok(())
+    }
+
+    /// Fills in the parts of the objects allocated by `allocate` that refer
+    /// to other objects.
+    fn fill(&self, heap: &mut Heap, base: usize) {
+        for (index, object) in self.objects.iter().enumerate() {
+            let copy = heap.stack[base + index].clone();
+            let set = |heap: &mut Heap, field: &Value, datum: &Datum| {
+                let value = self.value(heap, base, datum);
+                field.set(value.clone());
+                heap.write_barrier(&copy, &value)
+            };
+            match (object, copy.kind()) {
+                (&Object::Pair(ref car, ref cdr, _), Kind::Pair(pair)) => unsafe {
+                    set(heap, &(*pair).car, car);
+                    set(heap, &(*pair).cdr, cdr)
+                },
+                (&Object::Vector(ref elements, _), Kind::Vector(vector)) => unsafe {
+                    for (i, element) in elements.iter().enumerate() {
+                        set(heap, (*vector).element(i), element)
+                    }
+                },
+                (&Object::Closure(_, ref captured), Kind::Closure(closure)) => unsafe {
+                    for (i, value) in captured.iter().enumerate() {
+                        set(heap, (*closure).captured(i), value)
+                    }
+                },
+                _ => {}
+            }
+        }
+        for (index, object) in self.objects.iter().enumerate() {
+            match *object {
+                Object::String(_, true) |
+                Object::Bytevector(_, true) |
+                Object::Pair(_, _, true) |
+                Object::Vector(_, true) => {
+                    heap.stack[base + index].make_immutable();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The value of `datum`, whose objects start at `stack[base]`.
+    fn value(&self, heap: &Heap, base: usize, datum: &Datum) -> Value {
+        match *datum {
+            Datum::Immediate(bits) => Value::new(bits),
+            Datum::Object(index) => heap.stack[base + index].clone(),
+        }
+    }
+}
+
+/// Copies values out of a heap.
+struct Copier {
+    objects: Vec<Object>,
+
+    /// The index of each object copied so far, by address.
+    indices: HashMap<usize, usize>,
+
+    /// The objects to fill in, with their indices.
+    pending: Vec<(usize, Value)>,
+}
+
+impl Copier {
+    /// The datum for `value`.  Objects seen for the first time are added,
+    /// to be filled in later.
+    fn datum(&mut self, value: &Value) -> Datum {
+        match value.kind() {
+            Kind::Primitive(_) => return Datum::Immediate(value.get()),
+            _ if value.immediatep() => return Datum::Immediate(value.get()),
+            _ => {}
+        }
+        let address = value.get() & !0b111;
+        if let Some(&index) = self.indices.get(&address) {
+            return Datum::Object(index)
+        }
+        let index = self.objects.len();
+        self.objects.push(Object::Pending);
+        self.indices.insert(address, index);
+        self.pending.push((index, value.clone()));
+        Datum::Object(index)
+    }
+
+    /// The object for `value`.
+    fn object(&mut self, value: &Value) -> Result<Object, String> {
+        let immutable = value.immutablep();
+        Ok(match value.kind() {
+            Kind::Symbol(symbol) => Object::Symbol(unsafe { (*symbol).name() }.to_string()),
+            Kind::Float(float) => Object::Float(float),
+            Kind::String(string) => {
+                Object::String(unsafe { (*string).as_str() }.to_owned(), immutable)
+            }
+            Kind::Bytevector(bytevector) => {
+                Object::Bytevector(unsafe { (*bytevector).as_slice() }.to_vec(), immutable)
+            }
+            Kind::Pair(pair) => unsafe {
+                let car = self.datum(&(*pair).car);
+                Object::Pair(car, self.datum(&(*pair).cdr), immutable)
+            },
+            Kind::Vector(vector) => unsafe {
+                let len = (*vector).len();
+                let elements = (0..len).map(|i| self.datum((*vector).element(i))).collect();
+                Object::Vector(elements, immutable)
+            },
+            Kind::Bytecode(bco) => unsafe {
+                let mut code = vec![];
+                for pc in 0..(*bco).len() {
+                    code.extend_from_slice(&(*bco).instruction(pc).to_bytes())
+                }
+                Object::Bytecode(code, self.datum(&(*bco).constants()))
+            },
+            Kind::Closure(closure) => {
+                if let Some(handle) = thread::handle(value) {
+                    return Ok(Object::Handle(handle))
+                }
+                unsafe {
+                    let code = self.datum(&(*closure).code);
+                    let len = (*closure).len();
+                    let captured = (0..len).map(|i| self.datum((*closure).captured(i))).collect();
+                    Object::Closure(code, captured)
+                }
+            }
+            _ => {
+                let value = print::to_string(value, print::Mode::Write);
+                return Err(format!("cannot copy {} to another heap", value))
+            }
+        })
+    }
+}
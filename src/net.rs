@@ -0,0 +1,181 @@
+//! Ports over tokio TCP streams, with the `async` feature.
+//!
+//! `tcp_ports` makes an input port and an output port over a connection.
+//! Reading from the input port never blocks the thread: when nothing has
+//! arrived, the procedure reading fails with `port::WOULD_BLOCK`, and the
+//! interpreter suspends the fiber until the stream is readable, then calls
+//! the procedure again (see `interp::Control`).  So code evaluated by
+//! `Interpreter::eval_async` can call `read-line` as if it blocked, while
+//! the executor runs other tasks.  What is written is queued, and
+//! `flush-output-port` waits until all of it has been sent.
+//!
+//! Anywhere else, such as in code evaluated by `eval_str`, those procedures
+//! fail with "Would block" instead.  What is still queued when the output
+//! port is collected is lost.  The streams must be used within a tokio
+//! runtime.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{self, Poll};
+use tokio::net::TcpStream;
+use api::{Buffering, Error, Interpreter, Value};
+use port::{Readiness, Sink, Source, WOULD_BLOCK};
+
+/// The reading side of a stream.
+struct Input(Rc<TcpStream>);
+
+/// The writing side of a stream, with what has not been sent yet.
+struct Output {
+    stream: Rc<TcpStream>,
+    queue: Vec<u8>,
+}
+
+/// A future that completes once a stream is readable, or writable if
+/// `write` is set.
+struct Ready {
+    stream: Rc<TcpStream>,
+    write: bool,
+}
+
+impl Future for Ready {
+    type Output = Result<(), String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context) -> Poll<Self::Output> {
+        let poll = if self.write {
+            self.stream.poll_write_ready(cx)
+        } else {
+            self.stream.poll_read_ready(cx)
+        };
+        poll.map(|result| result.map_err(|e| e.to_string()))
+    }
+}
+
+impl Source for Input {
+    fn read(&mut self, bytes: &mut [u8]) -> Result<usize, String> {
+        loop {
+            match self.0.try_read(bytes) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(WOULD_BLOCK.to_owned())
+                }
+                result => return result.map_err(|e| e.to_string()),
+            }
+        }
+    }
+
+    fn ready(&mut self) -> Option<Readiness> {
+        Some(Box::pin(Ready {
+            stream: self.0.clone(),
+            write: false,
+        }))
+    }
+}
+
+impl Output {
+    /// Sends as much of the queue as the stream takes without blocking.
+    fn send(&mut self) -> Result<(), String> {
+        while !self.queue.is_empty() {
+            match self.stream.try_write(&self.queue) {
+                Ok(sent) => {
+                    self.queue.drain(..sent);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Output {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.queue.extend_from_slice(bytes);
+        self.send()
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        try!(self.send());
+        if self.queue.is_empty() {
+            Ok(())
+        } else {
+            Err(WOULD_BLOCK.to_owned())
+        }
+    }
+
+    fn ready(&mut self) -> Option<Readiness> {
+        Some(Box::pin(Ready {
+            stream: self.stream.clone(),
+            write: true,
+        }))
+    }
+}
+
+/// An input port and an output port over `stream`, which are textual
+/// ports if `textual` is set, and binary ones otherwise.  The output port
+/// is block buffered, like those of `tcp-connect`.
+pub fn tcp_ports(interp: &mut Interpreter,
+                 stream: TcpStream,
+                 textual: bool)
+                 -> Result<(Value, Value), Error> {
+    let name = try!(stream.peer_addr().map_err(|e| Error::new(e.to_string()))).to_string();
+    let stream = Rc::new(stream);
+    let state = interp.state();
+    try!(state.push_input_port(&name, Box::new(Input(stream.clone())), textual));
+    let input = try!(state.root());
+    let output = Output {
+        stream: stream,
+        queue: vec![],
+    };
+    try!(state.push_output_port(&name, Box::new(output), textual, Buffering::Block));
+    Ok((input, try!(state.root())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net;
+    use std::thread;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::runtime::Builder;
+    use api::Interpreter;
+    use super::tcp_ports;
+
+    #[test]
+    fn read_line_waits_for_the_stream() {
+        let runtime = Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = runtime.enter();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = net::TcpStream::connect(address).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(b"hel").unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(b"lo\nworld\n").unwrap();
+            let mut reply = String::new();
+            BufReader::new(stream).read_line(&mut reply).unwrap();
+            reply
+        });
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let stream = TcpStream::from_std(stream).unwrap();
+
+        let mut interp = Interpreter::new();
+        let (input, output) = tcp_ports(&mut interp, stream, true).unwrap();
+        interp.define("in", &input).unwrap();
+        interp.define("out", &output).unwrap();
+        let error = interp.eval_str("(read-line in)").unwrap_err();
+        assert_eq!(error.message(), "read-line: Would block");
+        let source = "(let ((line (read-line in)))
+                        (write-string (string-append line \"!\\n\") out)
+                        (flush-output-port out)
+                        (list line (read-line in)))";
+        let value = runtime.block_on(interp.eval_async(source)).unwrap();
+        assert_eq!(interp.write_string(&value), "(\"hello\" \"world\")");
+        assert_eq!(client.join().unwrap(), "hello!\n");
+    }
+}
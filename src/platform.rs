@@ -0,0 +1,119 @@
+//! What the interpreter needs from the system it runs on: clocks, entropy,
+//! and the standard streams.
+//!
+//! On `wasm32-unknown-unknown`, the standard library has no clock, and its
+//! hash map keys are not random, so with the `wasm` feature those come from
+//! JavaScript instead: `performance.now()`, `Date.now()` and
+//! `crypto.getRandomValues()`.  The standard streams are those of the
+//! `Console` that the embedder sets; by default, they are the process's,
+//! which on the web read nothing and discard what is written.
+
+use std::fmt;
+use std::io;
+use port::{Sink, Source};
+
+/// Where the standard ports of an interpreter read and write (see
+/// `builtins::port::current`).  Each method is called once, when Scheme
+/// code first uses the port.
+pub trait Console {
+    /// The standard input.
+    fn input(&self) -> Box<dyn Source>;
+
+    /// The standard output.
+    fn output(&self) -> Box<dyn Sink>;
+
+    /// The standard error.
+    fn error(&self) -> Box<dyn Sink>;
+}
+
+impl fmt::Debug for dyn Console {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Console")
+    }
+}
+
+/// The standard streams of the process.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn input(&self) -> Box<dyn Source> {
+        Box::new(io::stdin())
+    }
+
+    fn output(&self) -> Box<dyn Sink> {
+        Box::new(io::stdout())
+    }
+
+    fn error(&self) -> Box<dyn Sink> {
+        Box::new(io::stderr())
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", feature = "wasm")))]
+mod imp {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    pub use std::time::Instant;
+
+    /// The time since the epoch, or `None` if the clock is before it.
+    pub fn since_epoch() -> Option<Duration> {
+        SystemTime::now().duration_since(UNIX_EPOCH).ok()
+    }
+}
+
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+mod imp {
+    use std::time::Duration;
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    extern "C" {
+        #[wasm_bindgen(js_namespace = performance, js_name = now)]
+        fn performance_now() -> f64;
+
+        #[wasm_bindgen(js_namespace = Date, js_name = now)]
+        fn date_now() -> f64;
+
+        #[wasm_bindgen(js_namespace = crypto, js_name = getRandomValues)]
+        fn get_random_values(bytes: &mut [u8]);
+    }
+
+    /// A point on a monotonic clock, in milliseconds since the page loaded.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Instant(f64);
+
+    impl Instant {
+        pub fn now() -> Self {
+            Instant(performance_now())
+        }
+
+        /// The time since `self`.
+        pub fn elapsed(&self) -> Duration {
+            from_millis(performance_now() - self.0)
+        }
+    }
+
+    fn from_millis(millis: f64) -> Duration {
+        Duration::from_micros((millis.max(0.0) * 1000.0) as u64)
+    }
+
+    /// The time since the epoch, or `None` if the clock is before it.
+    pub fn since_epoch() -> Option<Duration> {
+        let millis = date_now();
+        if millis < 0.0 {
+            None
+        } else {
+            Some(from_millis(millis))
+        }
+    }
+
+    /// Fills `bytes` with random bytes.
+    pub fn fill_random(bytes: &mut [u8]) {
+        // `getRandomValues` fills at most 65536 bytes per call.
+        for chunk in bytes.chunks_mut(65536) {
+            get_random_values(chunk)
+        }
+    }
+}
+
+pub use self::imp::*;
@@ -0,0 +1,579 @@
+//! The state of ports, which lives on the Rust heap (see `alloc::port`).
+//!
+//! A port is an input or an output port, and a textual or a binary one.
+//! Input ports read from their source through a buffer, so that bytes and
+//! characters can be peeked at.  Output ports collect what is written in a
+//! buffer, which they flush to their sink according to their buffering
+//! mode, when they are flushed or closed, and when they are dropped.
+//! Textual ports encode characters as UTF-8.
+//!
+//! Sources and sinks are the crate's own `Source` and `Sink` traits, rather
+//! than `io::Read` and `io::Write`, so that ports need not depend on
+//! `std::io`.  For now, every reader is a source and every writer a sink.
+//!
+//! Memory output ports have no sink: they keep everything written in their
+//! buffer, which `contents` returns.  Memory input ports read from `Bytes`.
+//!
+//! Errors are returned as messages, without the name of the procedure.
+//!
+//! A nonblocking source or sink fails with `WOULD_BLOCK` when it cannot
+//! make progress yet, and `ready` then returns a future that completes once
+//! it can.  Port operations that fail that way leave the port as they found
+//! it, so that they can be retried (see `interp::Control`), except that
+//! what was written stays queued: writes only fail that way when they are
+//! flushed explicitly, or the port is closed.
+
+use std::fmt;
+use std::future::Future;
+use std::io::{self, BufRead, Read, Write};
+use std::pin::Pin;
+use std::str;
+
+/// The size of the chunks read from sources, and the size of the buffer
+/// that a block-buffered output port flushes at.
+const BLOCK_SIZE: usize = 4096;
+
+/// When an output port flushes its buffer to its sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Buffering {
+    /// After every write.
+    None,
+
+    /// After writing a newline.
+    Line,
+
+    /// When the buffer is full.
+    Block,
+}
+
+/// The error of a nonblocking source or sink that cannot make progress
+/// yet.
+pub const WOULD_BLOCK: &'static str = "Would block";
+
+/// A future that completes once a source or sink that would block can make
+/// progress.
+pub type Readiness = Pin<Box<dyn Future<Output = Result<(), String>>>>;
+
+/// What an input port reads from.
+pub trait Source {
+    /// Reads into `bytes`, and returns the number of bytes read, which is
+    /// 0 only at the end of the input.
+    fn read(&mut self, bytes: &mut [u8]) -> Result<usize, String>;
+
+    /// After `read` failed with `WOULD_BLOCK`, a future that completes
+    /// once it can read more.
+    fn ready(&mut self) -> Option<Readiness> {
+        None
+    }
+}
+
+/// What an output port writes to.
+pub trait Sink {
+    /// Writes all of `bytes`, or queues them to be written.
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String>;
+
+    /// Flushes what has been written to its destination.
+    fn flush(&mut self) -> Result<(), String>;
+
+    /// After `flush` failed with `WOULD_BLOCK`, a future that completes
+    /// once it can write more.
+    fn ready(&mut self) -> Option<Readiness> {
+        None
+    }
+}
+
+impl<R: Read + ?Sized> Source for R {
+    fn read(&mut self, bytes: &mut [u8]) -> Result<usize, String> {
+        loop {
+            match Read::read(self, bytes) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result.map_err(|e| e.to_string()),
+            }
+        }
+    }
+}
+
+impl<W: Write + ?Sized> Sink for W {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), String> {
+        Write::write_all(self, bytes).map_err(|e| e.to_string())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        Write::flush(self).map_err(|e| e.to_string())
+    }
+}
+
+/// A source reading from bytes in memory.
+#[derive(Clone, Debug)]
+pub struct Bytes {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl Bytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Bytes {
+            bytes: bytes,
+            position: 0,
+        }
+    }
+
+    /// Copies the next bytes into `bytes`, and returns how many there were.
+    fn take(&mut self, bytes: &mut [u8]) -> usize {
+        let rest = &self.bytes[self.position..];
+        let len = ::std::cmp::min(bytes.len(), rest.len());
+        bytes[..len].copy_from_slice(&rest[..len]);
+        self.position += len;
+        len
+    }
+}
+
+impl Read for Bytes {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        Ok(self.take(bytes))
+    }
+}
+
+/// The state of an input port.
+struct Input {
+    source: Box<dyn Source>,
+
+    /// Bytes read from the source, starting at `start`.
+    buffer: Vec<u8>,
+    start: usize,
+
+    /// Whether the bytes before `start` must be kept, so that a read can be
+    /// undone (see `Port::undoably`).
+    keep: bool,
+}
+
+/// The state of an output port.
+struct Output {
+    /// Where the bytes go, or `None` if they stay in memory.
+    sink: Option<Box<dyn Sink>>,
+
+    /// Bytes written and not yet flushed, or all of them if there is no
+    /// sink.
+    buffer: Vec<u8>,
+
+    buffering: Buffering,
+}
+
+/// The state of an open port.
+enum State {
+    Input(Input),
+    Output(Output),
+    Closed,
+}
+
+/// A port.
+pub struct Port {
+    /// What the port reads or writes, for printing.
+    name: String,
+
+    /// Whether the port is textual, rather than binary.
+    textual: bool,
+
+    /// Whether the port is an input port, rather than an output port.
+    input: bool,
+
+    state: State,
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Port")
+         .field("name", &self.name)
+         .field("textual", &self.textual)
+         .field("input", &self.input)
+         .field("open", &self.is_open())
+         .finish()
+    }
+}
+
+impl Input {
+    /// Reads from the source until `len` bytes are buffered, or the source
+    /// is exhausted.
+    fn fill(&mut self, len: usize) -> Result<(), String> {
+        if self.start > 0 && !self.keep {
+            self.buffer.drain(..self.start);
+            self.start = 0
+        }
+        let mut chunk = [0; BLOCK_SIZE];
+        while self.buffer.len() < len {
+            let read = try!(self.source.read(&mut chunk));
+            if read == 0 {
+                break
+            }
+            self.buffer.extend_from_slice(&chunk[..read])
+        }
+        Ok(())
+    }
+
+    /// The buffered bytes.
+    fn buffered(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    /// Drops the first `len` buffered bytes.
+    fn consume(&mut self, len: usize) {
+        self.start += len
+    }
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.buffer.extend_from_slice(bytes);
+        let result = match self.buffering {
+            Buffering::None => self.flush(),
+            Buffering::Line if bytes.contains(&b'\n') => self.flush(),
+            Buffering::Block if self.buffer.len() >= BLOCK_SIZE => self.flush(),
+            _ => Ok(()),
+        };
+        match result {
+            // The sink has queued the bytes.
+            Err(ref e) if e == WOULD_BLOCK => Ok(()),
+            result => result,
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.sink {
+            Some(ref mut sink) => {
+                try!(sink.write_all(&self.buffer));
+                self.buffer.clear();
+                sink.flush()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The number of bytes in the UTF-8 encoding of the character that starts
+/// with `byte`, or 0 if no character does.
+fn utf8_width(byte: u8) -> usize {
+    match byte {
+        0x00...0x7f => 1,
+        0xc2...0xdf => 2,
+        0xe0...0xef => 3,
+        0xf0...0xf4 => 4,
+        _ => 0,
+    }
+}
+
+impl Port {
+    /// An input port reading from `source`.
+    pub fn input(name: &str, source: Box<dyn Source>, textual: bool) -> Self {
+        Port {
+            name: name.to_owned(),
+            textual: textual,
+            input: true,
+            state: State::Input(Input {
+                source: source,
+                buffer: vec![],
+                start: 0,
+                keep: false,
+            }),
+        }
+    }
+
+    /// An output port writing to `sink`.
+    pub fn output(name: &str, sink: Box<dyn Sink>, textual: bool, buffering: Buffering) -> Self {
+        Port {
+            name: name.to_owned(),
+            textual: textual,
+            input: false,
+            state: State::Output(Output {
+                sink: Some(sink),
+                buffer: vec![],
+                buffering: buffering,
+            }),
+        }
+    }
+
+    /// An output port keeping what is written in memory.
+    pub fn memory_output(name: &str, textual: bool) -> Self {
+        Port {
+            name: name.to_owned(),
+            textual: textual,
+            input: false,
+            state: State::Output(Output {
+                sink: None,
+                buffer: vec![],
+                buffering: Buffering::Block,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_textual(&self) -> bool {
+        self.textual
+    }
+
+    pub fn is_input(&self) -> bool {
+        self.input
+    }
+
+    pub fn is_output(&self) -> bool {
+        !self.input
+    }
+
+    pub fn is_open(&self) -> bool {
+        match self.state {
+            State::Closed => false,
+            _ => true,
+        }
+    }
+
+    /// Closes the port, flushing it if it is an output port.  Closing a
+    /// closed port does nothing.
+    pub fn close(&mut self) -> Result<(), String> {
+        let result = match self.state {
+            State::Output(ref mut output) => output.flush(),
+            _ => Ok(()),
+        };
+        if result.as_ref().err().map_or(true, |e| e != WOULD_BLOCK) {
+            self.state = State::Closed
+        }
+        result
+    }
+
+    /// After an operation failed with `WOULD_BLOCK`, a future that
+    /// completes once the source or sink can make progress.
+    pub fn ready(&mut self) -> Option<Readiness> {
+        match self.state {
+            State::Input(ref mut input) => input.source.ready(),
+            State::Output(Output { sink: Some(ref mut sink), .. }) => sink.ready(),
+            _ => None,
+        }
+    }
+
+    /// Runs the read `f`, undoing it if it fails with `WOULD_BLOCK`, so
+    /// that it can be retried.
+    fn undoably<T, F>(&mut self, f: F) -> Result<T, String>
+        where F: FnOnce(&mut Port) -> Result<T, String>
+    {
+        let start = {
+            let input = try!(self.reader());
+            input.keep = true;
+            input.start
+        };
+        let result = f(self);
+        if let Ok(input) = self.reader() {
+            input.keep = false;
+            if result.as_ref().err().map_or(false, |e| e == WOULD_BLOCK) {
+                input.start = start
+            }
+        }
+        result
+    }
+
+    fn reader(&mut self) -> Result<&mut Input, String> {
+        match self.state {
+            State::Input(ref mut input) => Ok(input),
+            State::Closed => Err("the port is closed".to_owned()),
+            State::Output(_) => bug!("reading from an output port"),
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut Output, String> {
+        match self.state {
+            State::Output(ref mut output) => Ok(output),
+            State::Closed => Err("the port is closed".to_owned()),
+            State::Input(_) => bug!("writing to an input port"),
+        }
+    }
+
+    /// The next byte, if there is one, which is left to be read again.
+    pub fn peek_u8(&mut self) -> Result<Option<u8>, String> {
+        let input = try!(self.reader());
+        try!(input.fill(1));
+        Ok(input.buffered().first().cloned())
+    }
+
+    /// Reads a byte, or returns `None` at the end of the input.
+    pub fn read_u8(&mut self) -> Result<Option<u8>, String> {
+        let byte = try!(self.peek_u8());
+        if byte.is_some() {
+            try!(self.reader()).consume(1)
+        }
+        Ok(byte)
+    }
+
+    /// Puts `byte` back, to be read next.
+    pub fn unread_u8(&mut self, byte: u8) -> Result<(), String> {
+        let input = try!(self.reader());
+        if input.start > 0 {
+            input.start -= 1;
+            input.buffer[input.start] = byte
+        } else {
+            input.buffer.insert(0, byte)
+        }
+        Ok(())
+    }
+
+    /// Reads up to `len` bytes, fewer only at the end of the input.
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        let input = try!(self.reader());
+        try!(input.fill(len));
+        let bytes = input.buffered()[..::std::cmp::min(len, input.buffered().len())].to_vec();
+        input.consume(bytes.len());
+        Ok(bytes)
+    }
+
+    /// The next character and the length of its encoding, if there is one.
+    fn next_char(&mut self) -> Result<Option<(char, usize)>, String> {
+        let input = try!(self.reader());
+        try!(input.fill(1));
+        let width = match input.buffered().first() {
+            Some(&byte) => utf8_width(byte),
+            None => return Ok(None),
+        };
+        try!(input.fill(width));
+        let bytes = input.buffered();
+        if width == 0 || bytes.len() < width {
+            return Err("invalid UTF-8 in input".to_owned())
+        }
+        match str::from_utf8(&bytes[..width]) {
+            Ok(string) => Ok(string.chars().next().map(|c| (c, width))),
+            Err(_) => Err("invalid UTF-8 in input".to_owned()),
+        }
+    }
+
+    /// The next character, if there is one, which is left to be read again.
+    pub fn peek_char(&mut self) -> Result<Option<char>, String> {
+        Ok(try!(self.next_char()).map(|(c, _)| c))
+    }
+
+    /// Reads a character, or returns `None` at the end of the input.
+    pub fn read_char(&mut self) -> Result<Option<char>, String> {
+        Ok(match try!(self.next_char()) {
+            Some((c, width)) => {
+                try!(self.reader()).consume(width);
+                Some(c)
+            }
+            None => None,
+        })
+    }
+
+    /// Reads up to `len` characters, fewer only at the end of the input.
+    pub fn read_string(&mut self, len: usize) -> Result<String, String> {
+        self.undoably(|port| {
+            let mut string = String::new();
+            for _ in 0..len {
+                match try!(port.read_char()) {
+                    Some(c) => string.push(c),
+                    None => break,
+                }
+            }
+            Ok(string)
+        })
+    }
+
+    /// Reads a line, without its end, or returns `None` at the end of the
+    /// input.
+    pub fn read_line(&mut self) -> Result<Option<String>, String> {
+        self.undoably(|port| {
+            let mut line = String::new();
+            loop {
+                match try!(port.read_char()) {
+                    Some('\n') => return Ok(Some(line)),
+                    Some(c) => line.push(c),
+                    None if line.is_empty() => return Ok(None),
+                    None => return Ok(Some(line)),
+                }
+            }
+        })
+    }
+
+    /// Checks if reading can go on without waiting: if input is buffered.
+    pub fn is_ready(&mut self) -> Result<bool, String> {
+        Ok(!try!(self.reader()).buffered().is_empty())
+    }
+
+    /// Writes `bytes`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        try!(self.writer()).write(bytes)
+    }
+
+    /// Writes the UTF-8 encoding of `string`.
+    pub fn write_str(&mut self, string: &str) -> Result<(), String> {
+        self.write_bytes(string.as_bytes())
+    }
+
+    /// Flushes what has been written to the sink.
+    pub fn flush(&mut self) -> Result<(), String> {
+        try!(self.writer()).flush()
+    }
+
+    /// Everything written so far, if this is a memory output port.
+    pub fn contents(&mut self) -> Result<Option<&[u8]>, String> {
+        let output = try!(self.writer());
+        Ok(match output.sink {
+            Some(_) => None,
+            None => Some(&output.buffer[..]),
+        })
+    }
+
+    /// The buffering mode, if this is an open output port.
+    pub fn buffering(&self) -> Option<Buffering> {
+        match self.state {
+            State::Output(ref output) => Some(output.buffering),
+            _ => None,
+        }
+    }
+
+    /// Sets the buffering mode of an output port.
+    pub fn set_buffering(&mut self, buffering: Buffering) -> Result<(), String> {
+        let output = try!(self.writer());
+        output.buffering = buffering;
+        if buffering != Buffering::Block {
+            try!(output.flush())
+        }
+        Ok(())
+    }
+}
+
+/// An input port as a `BufRead`, for the reader (see `read::Input`).
+pub struct Reader<'a>(pub &'a mut Port);
+
+/// The I/O error for the error `message` of a port.
+fn io_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+impl<'a> Read for Reader<'a> {
+    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
+        let len = {
+            let buffered = try!(self.fill_buf());
+            let len = ::std::cmp::min(bytes.len(), buffered.len());
+            bytes[..len].copy_from_slice(&buffered[..len]);
+            len
+        };
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl<'a> BufRead for Reader<'a> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let input = try!(self.0.reader().map_err(io_error));
+        try!(input.fill(1).map_err(io_error));
+        Ok(input.buffered())
+    }
+
+    fn consume(&mut self, len: usize) {
+        if let Ok(input) = self.0.reader() {
+            input.consume(len)
+        }
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
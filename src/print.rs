@@ -1 +1,569 @@
-fn print(
+//! The printer: `write`, `write-shared`, `write-simple`, and `display`.
+//!
+//! To print structure that is shared or cyclic, the printer first walks the
+//! pairs and vectors reachable from the datum, and finds the ones that need
+//! a datum label:
+//!
+//! - `write-shared` labels every pair or vector reached more than once.
+//! - `write` and `display` label only those reached again through a cycle,
+//!   found by a depth-first search.
+//! - `write-simple` labels nothing, and does not terminate on cycles.
+//!
+//! Labels are numbered in the order they are printed: the first occurrence
+//! of a labelled object is printed as `#n=` followed by the object, and later
+//! ones as `#n#`.
+//!
+//! The printer does not allocate, so the addresses of objects are stable
+//! while it runs.
+//!
+//! The pretty-printer prints the same text, but breaks lists and vectors
+//! that do not fit in the line across several lines.  A list whose head is
+//! a special form keeps its distinguished operands (see `special_form`) on
+//! the first line and indents the body by two columns; a call keeps its
+//! first argument on the first line and lines up the others beneath it; and
+//! other lists and vectors line up their elements.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use read;
+use value::{self, Kind, Value};
+
+/// How to print a datum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// `write`: machine-readable, labelling cycles.
+    Write,
+
+    /// `write-shared`: machine-readable, labelling all shared structure.
+    Shared,
+
+    /// `write-simple`: machine-readable, without labels.
+    Simple,
+
+    /// `display`: human-readable, labelling cycles.  Strings and characters
+    /// are printed as their contents.
+    Display,
+}
+
+/// Checks if `value` is a pair or vector, which may need a label.
+fn compound(value: &Value) -> bool {
+    match value.kind() {
+        Kind::Pair(_) | Kind::Vector(_) => true,
+        _ => false,
+    }
+}
+
+/// The objects `value` refers to, if it is a pair or vector.
+fn children(value: &Value) -> Vec<Value> {
+    match value.kind() {
+        Kind::Pair(pair) => unsafe { vec![(*pair).car.clone(), (*pair).cdr.clone()] },
+        Kind::Vector(vector) => unsafe {
+            (0..(*vector).len()).map(|i| (*vector).element(i).clone()).collect()
+        },
+        _ => vec![],
+    }
+}
+
+/// Finds the objects reachable from `root` more than once.
+fn find_shared(root: &Value) -> HashSet<usize> {
+    let mut seen = HashSet::new();
+    let mut shared = HashSet::new();
+    let mut pending = vec![root.clone()];
+    while let Some(value) = pending.pop() {
+        if !compound(&value) {
+            continue
+        }
+        if !seen.insert(value.get()) {
+            shared.insert(value.get());
+            continue
+        }
+        let mut children = children(&value);
+        children.reverse();
+        pending.extend(children)
+    }
+    shared
+}
+
+/// Finds the objects reachable from `root` through a cycle.
+fn find_cycles(root: &Value) -> HashSet<usize> {
+    // Each pending entry is an object to visit, or (`None`) the end of the
+    // children of the innermost object being visited.
+    let mut on_path = HashSet::new();
+    let mut path = vec![];
+    let mut done = HashSet::new();
+    let mut cyclic = HashSet::new();
+    let mut pending = vec![Some(root.clone())];
+    while let Some(entry) = pending.pop() {
+        let value = match entry {
+            Some(value) => value,
+            None => {
+                let finished = path.pop().unwrap();
+                on_path.remove(&finished);
+                done.insert(finished);
+                continue
+            }
+        };
+        if !compound(&value) || done.contains(&value.get()) {
+            continue
+        }
+        if on_path.contains(&value.get()) {
+            cyclic.insert(value.get());
+            continue
+        }
+        on_path.insert(value.get());
+        path.push(value.get());
+        pending.push(None);
+        let mut children = children(&value);
+        children.reverse();
+        pending.extend(children.into_iter().map(Some))
+    }
+    cyclic
+}
+
+/// The objects that need datum labels, and the labels assigned so far.
+struct Labels {
+    assigned: HashMap<usize, Option<usize>>,
+    next: usize,
+}
+
+impl Labels {
+    /// The objects reachable from `root` that need labels in `mode`.
+    fn new(root: &Value, mode: Mode) -> Self {
+        let labels = match mode {
+            Mode::Shared => find_shared(root),
+            Mode::Write | Mode::Display => find_cycles(root),
+            Mode::Simple => HashSet::new(),
+        };
+        Labels {
+            assigned: labels.into_iter().map(|address| (address, None)).collect(),
+            next: 0,
+        }
+    }
+
+    /// Checks if `value` needs a label.
+    fn contains(&self, value: &Value) -> bool {
+        self.assigned.contains_key(&value.get())
+    }
+
+    /// Prints the label of `value` to `out`, if it needs one.  Returns
+    /// `true` if the label was printed as a reference (`#n#`), so that the
+    /// object itself must not be printed.
+    fn label(&mut self, out: &mut String, value: &Value) -> bool {
+        let label = match self.assigned.get_mut(&value.get()) {
+            Some(label) => label,
+            None => return false,
+        };
+        match *label {
+            Some(n) => {
+                let _ = write!(out, "#{}#", n);
+                true
+            }
+            None => {
+                *label = Some(self.next);
+                let _ = write!(out, "#{}=", self.next);
+                self.next += 1;
+                false
+            }
+        }
+    }
+}
+
+struct Printer<'a> {
+    out: &'a mut String,
+    mode: Mode,
+    labels: Labels,
+}
+
+impl<'a> Printer<'a> {
+    fn print(&mut self, value: &Value) {
+        if self.labels.label(self.out, value) {
+            return
+        }
+        match value.kind() {
+            Kind::Pair(_) => self.print_list(value),
+            Kind::Vector(vector) => unsafe {
+                self.out.push_str("#(");
+                for i in 0..(*vector).len() {
+                    if i > 0 {
+                        self.out.push(' ')
+                    }
+                    self.print(&(*vector).element(i).clone())
+                }
+                self.out.push(')')
+            },
+            _ => print_atom(self.out, value, self.mode),
+        }
+    }
+
+    /// Prints the list starting with the pair `value`, whose label (if any)
+    /// has been printed.
+    fn print_list(&mut self, value: &Value) {
+        self.out.push('(');
+        self.print(&value.car().unwrap());
+        let mut rest = value.cdr().unwrap();
+        loop {
+            match rest.kind() {
+                Kind::Pair(_) if !self.labels.contains(&rest) => {
+                    self.out.push(' ');
+                    self.print(&rest.car().unwrap());
+                    rest = rest.cdr().unwrap()
+                }
+                Kind::Constant(value::NIL) => break,
+                _ => {
+                    self.out.push_str(" . ");
+                    self.print(&rest);
+                    break
+                }
+            }
+        }
+        self.out.push(')')
+    }
+}
+
+/// The name of `c` in `#\name` syntax, if it has one.
+fn char_name(c: char) -> Option<&'static str> {
+    Some(match c {
+        '\x07' => "alarm",
+        '\x08' => "backspace",
+        '\x7f' => "delete",
+        '\x1b' => "escape",
+        '\n' => "newline",
+        '\0' => "null",
+        '\r' => "return",
+        ' ' => "space",
+        '\t' => "tab",
+        _ => return None,
+    })
+}
+
+fn print_string(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\x{:x};", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"')
+}
+
+/// Checks if `name` can be written as a symbol without `|` quotes.
+fn plain_symbol(name: &str) -> bool {
+    !name.is_empty() && name != "." &&
+    !name.chars().any(|c| {
+        c.is_whitespace() || c.is_control() || "()\"#;'`,|".contains(c)
+    }) && read::parse_number(name).is_none()
+}
+
+/// Prints `float` with the fewest digits that read back as the same float,
+/// which is how Rust formats floats, in exponent notation if it is very
+/// large or very small.
+fn print_float(out: &mut String, float: f64) {
+    if float.is_nan() {
+        out.push_str("+nan.0")
+    } else if float.is_infinite() {
+        out.push_str(if float > 0.0 { "+inf.0" } else { "-inf.0" })
+    } else if float == float.trunc() && float.abs() < 1e16 {
+        let _ = write!(out, "{:.1}", float);
+    } else if float == float.trunc() || float.abs() < 1e-5 {
+        let _ = write!(out, "{:e}", float);
+    } else {
+        let _ = write!(out, "{}", float);
+    }
+}
+
+/// The integer `n` written in base `radix`, which is from 2 to 36, with
+/// lower-case digits.
+pub fn integer_to_string(n: isize, radix: u32) -> String {
+    let mut magnitude = if n < 0 { (n as usize).wrapping_neg() } else { n as usize };
+    let mut digits = vec![];
+    loop {
+        let digit = (magnitude % radix as usize) as u32;
+        digits.push(::std::char::from_digit(digit, radix).unwrap());
+        magnitude /= radix as usize;
+        if magnitude == 0 {
+            break
+        }
+    }
+    if n < 0 {
+        digits.push('-')
+    }
+    digits.into_iter().rev().collect()
+}
+
+/// Prints anything but a pair or vector.
+fn print_atom(out: &mut String, value: &Value, mode: Mode) {
+    let _ = match value.kind() {
+        Kind::Fixnum(_) => write!(out, "{}", value.get() as isize >> 2),
+        Kind::Float(float) => Ok(print_float(out, float)),
+        Kind::Char(c) if mode == Mode::Display => Ok(out.push(c)),
+        Kind::Char(c) => {
+            match char_name(c) {
+                Some(name) => write!(out, "#\\{}", name),
+                None if c.is_control() => write!(out, "#\\x{:x}", c as u32),
+                None => write!(out, "#\\{}", c),
+            }
+        }
+        Kind::String(string) if mode == Mode::Display => {
+            Ok(out.push_str(unsafe { (*string).as_str() }))
+        }
+        Kind::String(string) => Ok(print_string(out, unsafe { (*string).as_str() })),
+        Kind::Symbol(symbol) => {
+            let name = unsafe { (*symbol).name() };
+            if mode == Mode::Display || plain_symbol(&name) ||
+               read::PARAMETER_MARKERS.contains(&&**name) {
+                Ok(out.push_str(&name))
+            } else {
+                write!(out, "|{}|", name.replace('\\', "\\\\").replace('|', "\\|"))
+            }
+        }
+        Kind::Bytevector(bytevector) => {
+            out.push_str("#u8(");
+            let bytes = unsafe { (*bytevector).as_slice() };
+            for (i, byte) in bytes.iter().enumerate() {
+                let _ = write!(out, "{}{}", if i > 0 { " " } else { "" }, byte);
+            }
+            Ok(out.push(')'))
+        }
+        Kind::Constant(value::TRUE) => Ok(out.push_str("#t")),
+        Kind::Constant(value::FALSE) => Ok(out.push_str("#f")),
+        Kind::Constant(value::NIL) => Ok(out.push_str("()")),
+        Kind::Constant(value::EOF) => Ok(out.push_str("#<eof>")),
+        Kind::Constant(_) => Ok(out.push_str("#<unspecified>")),
+        Kind::Primitive(primitive) => write!(out, "#<procedure {}>", unsafe { (*primitive).name }),
+        Kind::Closure(_) => Ok(out.push_str("#<procedure>")),
+        Kind::Record(record) => unsafe {
+            let descriptor = (*record).descriptor.clone();
+            let (kind, descriptor) = if descriptor.get() == value::FALSE {
+                ("record-type", value.clone())
+            } else {
+                ("record", descriptor)
+            };
+            let name = (*(descriptor.as_ptr() as *const value::RecordDescriptor)).name.clone();
+            let mut name_text = String::new();
+            print_atom(&mut name_text, &name, Mode::Display);
+            write!(out, "#<{} {}>", kind, name_text)
+        },
+        Kind::HashTable(_) => Ok(out.push_str("#<hashtable>")),
+        Kind::RandomSource(_) => Ok(out.push_str("#<random-source>")),
+        Kind::RustData(_) => Ok(out.push_str("#<rust-data>")),
+        Kind::Promise(_) => Ok(out.push_str("#<promise>")),
+        Kind::Values(_) => Ok(out.push_str("#<values>")),
+        Kind::Condition(_) => Ok(out.push_str("#<condition>")),
+        Kind::Port(port) => unsafe {
+            let port = (*port).state();
+            let kind = if port.is_input() { "input-port" } else { "output-port" };
+            write!(out, "#<{} {}>", kind, port.name())
+        },
+        Kind::DynVector(dynvector) => write!(out, "#<dynvector {}>", unsafe { (*dynvector).len() }),
+        Kind::Bytecode(_) => Ok(out.push_str("#<code>")),
+        Kind::Pair(_) | Kind::Vector(_) => bug!("print_atom called on a pair or vector"),
+    };
+}
+
+/// Prints `value` to `out`.
+pub fn print(out: &mut String, value: &Value, mode: Mode) {
+    let mut printer = Printer {
+        out: out,
+        mode: mode,
+        labels: Labels::new(value, mode),
+    };
+    printer.print(value)
+}
+
+/// `value`, printed.
+pub fn to_string(value: &Value, mode: Mode) -> String {
+    let mut out = String::new();
+    print(&mut out, value, mode);
+    out
+}
+
+/// A datum laid out for pretty-printing: text that cannot be broken, or a
+/// list or vector, which can be broken between its items.
+enum Doc {
+    Text(String),
+    List {
+        /// The text up to the first item, such as `(` or `#0=#(`.
+        open: String,
+
+        /// The elements, with `.` and the tail of an improper list last.
+        items: Vec<Doc>,
+
+        /// The length of the whole list printed on one line.
+        width: usize,
+
+        /// How many items stay on the first line when the list is broken.
+        inline: usize,
+
+        /// The indentation of the other items, relative to the end of
+        /// `open`.
+        indent: usize,
+    },
+}
+
+impl Doc {
+    fn width(&self) -> usize {
+        match *self {
+            Doc::Text(ref text) => text.chars().count(),
+            Doc::List { width, .. } => width,
+        }
+    }
+
+    fn is_text(&self) -> bool {
+        match *self {
+            Doc::Text(_) => true,
+            Doc::List { .. } => false,
+        }
+    }
+}
+
+/// Calls with heads longer than this have their arguments indented under
+/// the head, rather than lined up with the first argument.
+const MAX_HEAD_WIDTH: usize = 12;
+
+/// The number of distinguished operands of the special form `name`, which
+/// are printed on the same line as it, if it is one.
+fn special_form(name: &str) -> Option<usize> {
+    Some(match name {
+        "begin" | "cond" | "case-lambda" | "delay" | "delay-force" | "make-parameter" => 0,
+        "define" | "define-values" | "define-syntax" | "define-library" | "lambda" |
+        "let" | "let*" | "letrec" | "letrec*" | "let-values" | "let*-values" |
+        "let-syntax" | "letrec-syntax" | "parameterize" | "when" | "unless" | "case" |
+        "syntax-rules" | "guard" | "with-exception-handler" => 1,
+        "do" | "define-record-type" | "receive" => 2,
+        _ => return None,
+    })
+}
+
+struct Pretty {
+    mode: Mode,
+    labels: Labels,
+    width: usize,
+}
+
+impl Pretty {
+    /// Lays out `value`, assigning labels as `Printer::print` does.
+    fn doc(&mut self, value: &Value) -> Doc {
+        let mut open = String::new();
+        if self.labels.label(&mut open, value) {
+            return Doc::Text(open)
+        }
+        let items = match value.kind() {
+            Kind::Pair(_) => {
+                open.push('(');
+                self.list_items(value)
+            }
+            Kind::Vector(vector) => unsafe {
+                open.push_str("#(");
+                (0..(*vector).len()).map(|i| self.doc(&(*vector).element(i).clone())).collect()
+            },
+            _ => {
+                print_atom(&mut open, value, self.mode);
+                return Doc::Text(open)
+            }
+        };
+        let width = open.chars().count() + items.iter().map(Doc::width).sum::<usize>() +
+                    items.len().saturating_sub(1) + 1;
+        let head = match value.car().map(|head| head.kind()) {
+            Ok(Kind::Symbol(_)) => {
+                match items[0] {
+                    Doc::Text(ref head) => Some(head.clone()),
+                    Doc::List { .. } => None,
+                }
+            }
+            _ => None,
+        };
+        let (inline, indent) = match head {
+            Some(head) => {
+                match special_form(&head) {
+                    // A named `let` has one more distinguished operand.
+                    Some(1) if head == "let" && items.len() > 1 && items[1].is_text() => (3, 1),
+                    Some(n) => (n + 1, 1),
+                    None if head.chars().count() <= MAX_HEAD_WIDTH => (2, head.chars().count() + 1),
+                    None => (1, 1),
+                }
+            }
+            None => (1, 0),
+        };
+        Doc::List {
+            open: open,
+            items: items,
+            width: width,
+            inline: inline,
+            indent: indent,
+        }
+    }
+
+    /// Lays out the items of the list starting with the pair `value`.
+    fn list_items(&mut self, value: &Value) -> Vec<Doc> {
+        let mut items = vec![self.doc(&value.car().unwrap())];
+        let mut rest = value.cdr().unwrap();
+        loop {
+            match rest.kind() {
+                Kind::Pair(_) if !self.labels.contains(&rest) => {
+                    items.push(self.doc(&rest.car().unwrap()));
+                    rest = rest.cdr().unwrap()
+                }
+                Kind::Constant(value::NIL) => break,
+                _ => {
+                    items.push(Doc::Text(".".to_owned()));
+                    items.push(self.doc(&rest));
+                    break
+                }
+            }
+        }
+        items
+    }
+
+    /// Prints `doc` to `out`, starting at `column`, and returns the column
+    /// where it ends.
+    fn print(&self, out: &mut String, doc: &Doc, column: usize) -> usize {
+        match *doc {
+            Doc::Text(ref text) => {
+                out.push_str(text);
+                column + doc.width()
+            }
+            Doc::List { ref open, ref items, width, inline, indent } => {
+                let fits = column + width <= self.width;
+                out.push_str(open);
+                let start = column + open.chars().count();
+                let mut column = start;
+                for (i, item) in items.iter().enumerate() {
+                    if i == 0 {
+                        // The first item follows `open` directly.
+                    } else if fits || i < inline {
+                        out.push(' ');
+                        column += 1
+                    } else {
+                        out.push('\n');
+                        column = start + indent;
+                        out.extend((0..column).map(|_| ' '))
+                    }
+                    column = self.print(out, item, column)
+                }
+                out.push(')');
+                column + 1
+            }
+        }
+    }
+}
+
+/// `value`, pretty-printed to fit in `width` columns where possible.
+pub fn pretty(value: &Value, mode: Mode, width: usize) -> String {
+    let mut pretty = Pretty {
+        mode: mode,
+        labels: Labels::new(value, mode),
+        width: width,
+    };
+    let doc = pretty.doc(value);
+    let mut out = String::new();
+    pretty.print(&mut out, &doc, 0);
+    out
+}
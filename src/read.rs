@@ -1,9 +1,11 @@
 use std::io;
 use std::io::prelude::*;
 use std::char;
-use std::iter::Peekable;
+use std::rc::Rc;
 use super::interp;
-use super::api;
+use super::api::{self, SchemeValue};
+use super::value::{self, Value};
+use alloc::{Heap, Location};
 #[derive(Debug)]
 pub enum ReadError {
     /// EOF in list
@@ -27,6 +29,9 @@ pub enum ReadError {
     /// EOF after `#\\`
     EOFAfterSharpBackslash,
 
+    /// Unknown character name after `#\\`
+    BadCharName(String),
+
     /// Bad sharpsign read macro
     BadSharpMacro([char; 2]),
 
@@ -49,8 +54,17 @@ pub enum ReadError {
     /// `|` in symbol unescaped
     PipeInSymbol,
 
-    /// Bad hex number
-    BadHexNumber,
+    /// Bad number after a `#x`, `#b`, `#o`, `#d`, `#e`, or `#i` prefix
+    BadNumber(String),
+
+    /// Bad boolean after `#t` or `#f`
+    BadBoolean(String),
+
+    /// EOF in a `#|` block comment
+    EOFInComment,
+
+    /// EOF after `'`, `` ` ``, `,`, `,@`, or `#;`
+    EOFAfterPrefix,
 
     /// Integer overflow
     Overflow,
@@ -68,6 +82,18 @@ pub enum ReadError {
     NYI,
 }
 
+impl ReadError {
+    /// Whether the input ended in the middle of a datum, so that more input
+    /// could complete it.
+    pub fn is_incomplete(&self) -> bool {
+        match *self {
+            ReadError::EOFInList | ReadError::EOFInVector | ReadError::EOFInString |
+            ReadError::EOFInSymbol | ReadError::EOFInComment | ReadError::EOFAfterPrefix => true,
+            _ => false,
+        }
+    }
+}
+
 /// An event that can be emitted by the reader or tree-walker, and which
 /// is part of the stream that is consumed by the tree-builder, printer,
 /// and bytecode compiler.
@@ -89,9 +115,9 @@ pub enum Event {
     Char(char),
 
     /// Integer `12311324`
-    Int(usize),
+    Int(isize),
 
-    /// Floating-point numbers (not yet implemented)
+    /// Floating-point number `1.5`
     Float(f64),
 
     /// Start of a list `(` (false) or `[` (true)
@@ -133,17 +159,95 @@ pub enum Event {
     /// Dot `.`
     Dot,
 
+    /// Datum comment `#;`
+    DatumComment,
+
     /// End of file
     EOF,
 }
 
+/// The markers in parameter lists, which read as symbols of these names
+/// (see `compiler::Parser::parameters`).
+pub const PARAMETER_MARKERS: &'static [&'static str] = &["#!optional", "#!rest", "#!key"];
+
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 enum StringOrSymbol {
     String,
     Symbol,
 }
+/// A stream of bytes to read from, which keeps track of the position of the
+/// next byte.
+pub struct Input<R: BufRead> {
+    bytes: Bytes<R>,
+
+    /// The result of `bytes.next()`, if it was peeked at.
+    peeked: Option<Option<io::Result<u8>>>,
+
+    file: Rc<String>,
+    line: usize,
+    column: usize,
+}
+
+impl<R: BufRead> Input<R> {
+    /// Reads from `reader`, calling it `file` in source locations.
+    pub fn new(reader: R, file: &str) -> Self {
+        Input {
+            bytes: reader.bytes(),
+            peeked: None,
+            file: Rc::new(file.to_owned()),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// The location of the next byte.
+    pub fn location(&self) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn peek(&mut self) -> Option<&io::Result<u8>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.bytes.next())
+        }
+        self.peeked.as_ref().unwrap().as_ref()
+    }
+
+    /// The byte that was peeked at and not read yet, if any, which is
+    /// forgotten.  Reading from a port gives it back to the port.
+    pub fn take_peeked(&mut self) -> Option<u8> {
+        match self.peeked.take() {
+            Some(Some(Ok(byte))) => Some(byte),
+            _ => None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Input<R> {
+    type Item = io::Result<u8>;
+    fn next(&mut self) -> Option<io::Result<u8>> {
+        let next = match self.peeked.take() {
+            Some(next) => next,
+            None => self.bytes.next(),
+        };
+        match next {
+            Some(Ok(b'\n')) => {
+                self.line += 1;
+                self.column = 1
+            }
+            // Count characters, not UTF-8 continuation bytes.
+            Some(Ok(byte)) if byte & 0xC0 != 0x80 => self.column += 1,
+            _ => {}
+        }
+        next
+    }
+}
+
 use self::ReadError::IoError;
-fn finish_char<R: BufRead>(file: &mut Peekable<Bytes<R>>,
+fn finish_char<R: BufRead>(file: &mut Input<R>,
                            unicode_char: u8)
                            -> Result<char, ReadError> {
     if unicode_char <= 0x7F {
@@ -153,11 +257,15 @@ fn finish_char<R: BufRead>(file: &mut Peekable<Bytes<R>>,
     match len {
         1 | 5...8 => Err(ReadError::InvalidUtf8((unicode_char as u32) << 24)),
         len @ 2...4 => {
+            // The number of continuation bytes.
             let len = len - 1;
-            let mut value: u32 = (unicode_char >> (len + 2)).into();
-            value <<= len * 6;
-            for (count, val) in &mut file.take(len.into()).enumerate() {
-                value &= (try!(val.map_err(IoError)) as u32) << (len - count as u8)
+            let mut value: u32 = (unicode_char & (0x7F >> (len + 1))).into();
+            for val in &mut file.take(len.into()) {
+                let byte = try!(val.map_err(IoError));
+                if byte & 0xC0 != 0x80 {
+                    return Err(ReadError::InvalidUtf8(value))
+                }
+                value = value << 6 | (byte & 0x3F) as u32
             }
             char::from_u32(value).ok_or_else(|| ReadError::InvalidUtf8(value))
         }
@@ -170,13 +278,22 @@ macro_rules! next {
     }
 }
 
+macro_rules! try_opt {
+    ($exp: expr) => {
+        match $exp {
+            Some(x) => x,
+            None => return None,
+        }
+    }
+}
+
 type ReadResult = Result<char, ReadError>;
 
 use std::io::Bytes;
-fn handle_unicode_escape<R: BufRead>(file: &mut Peekable<Bytes<R>>) -> ReadResult {
+fn handle_unicode_escape<R: BufRead>(file: &mut Input<R>) -> ReadResult {
+    let mut escaped_char = 0;
     loop {
         let eof = ReadError::BadEscape;
-        let mut escaped_char = 0;
         let next_character = next!(file, eof);
         let subtract_amount = match next_character {
             b'a'...b'f' => 87,
@@ -196,36 +313,68 @@ fn handle_unicode_escape<R: BufRead>(file: &mut Peekable<Bytes<R>>) -> ReadResul
     }
 }
 
-fn process_escape<R: BufRead>(file: &mut Peekable<Bytes<R>>) -> ReadResult {
-    let bad = ReadError::BadEscape;
-    loop {
-        return Ok(match next!(file, ReadError::BadEscape) {
-            b'n' => '\n',
-            b't' => '\t',
-            b'e' => '\x1b',
-            b'b' => '\x08',
-            b'v' => '\x0b',
-            b'f' => '\x0c',
-            b'\r' => {
-                match file.next() {
-                    Some(Ok(b'\n')) => continue,
-                    Some(Ok(_)) | None => return Err(bad),
-                    Some(Err(x)) => return Err(ReadError::IoError(x)),
-                }
-            }
-            b'\n' => continue,
-            b'x' | b'u' => try!(handle_unicode_escape(file)),
-            l @ b'|' | l @ b'"' | l @ b'\\' | l @ b'#' | l @ b'`' | l @ b',' | l @ b'\'' => {
-                l as char
-            }
-            _ => return Err(bad),
-        });
+/// The next byte of `file`, without consuming it.
+fn peek_byte<R: BufRead>(file: &mut Input<R>) -> Result<Option<u8>, ReadError> {
+    match file.peek() {
+        Some(&Ok(byte)) => return Ok(Some(byte)),
+        None => return Ok(None),
+        Some(&Err(_)) => {}
     }
+    Err(ReadError::IoError(file.next().unwrap().unwrap_err()))
 }
 
+/// Skips spaces and tabs.
+fn skip_intraline_whitespace<R: BufRead>(file: &mut Input<R>) {
+    loop {
+        match file.peek() {
+            Some(&Ok(b' ')) | Some(&Ok(b'\t')) => {}
+            _ => return,
+        }
+        file.next();
+    }
+}
 
+/// Processes the escape sequence after a backslash.  Returns `None` for a
+/// line continuation, which stands for no character at all.
+fn process_escape<R: BufRead>(file: &mut Input<R>) -> Result<Option<char>, ReadError> {
+    let bad = ReadError::BadEscape;
+    Ok(Some(match next!(file, ReadError::BadEscape) {
+        b'a' => '\x07',
+        b'n' => '\n',
+        b'r' => '\r',
+        b't' => '\t',
+        b'e' => '\x1b',
+        b'b' => '\x08',
+        b'v' => '\x0b',
+        b'f' => '\x0c',
+        first @ b' ' | first @ b'\t' | first @ b'\r' | first @ b'\n' => {
+            // `\<intraline whitespace><line ending><intraline whitespace>`
+            let mut byte = first;
+            if byte == b' ' || byte == b'\t' {
+                skip_intraline_whitespace(file);
+                byte = next!(file, ReadError::BadEscape)
+            }
+            match byte {
+                b'\r' => {
+                    if let Some(&Ok(b'\n')) = file.peek() {
+                        file.next();
+                    }
+                }
+                b'\n' => {}
+                _ => return Err(bad),
+            }
+            skip_intraline_whitespace(file);
+            return Ok(None)
+        }
+        b'x' | b'u' => try!(handle_unicode_escape(file)),
+        l @ b'|' | l @ b'"' | l @ b'\\' | l @ b'#' | l @ b'`' | l @ b',' | l @ b'\'' => {
+            l as char
+        }
+        _ => return Err(bad),
+    }))
+}
 
-fn read_escaped<R: BufRead>(file: &mut Peekable<Bytes<R>>,
+fn read_escaped<R: BufRead>(file: &mut Input<R>,
                             delimiter: StringOrSymbol)
                             -> Result<String, ReadError> {
     let premature_eof = || {
@@ -237,24 +386,167 @@ fn read_escaped<R: BufRead>(file: &mut Peekable<Bytes<R>>,
 
     let mut buf = String::new();
     loop {
-        buf.push(match next!(file, premature_eof()) {
-            b'\\' => try!(process_escape(file)),
+        match next!(file, premature_eof()) {
+            b'\\' => buf.extend(try!(process_escape(file))),
             b'|' if delimiter == StringOrSymbol::Symbol => break,
             b'"' if delimiter == StringOrSymbol::String => break,
-            normal_char => try!(finish_char(file, normal_char)),
-        })
+            normal_char => buf.push(try!(finish_char(file, normal_char))),
+        }
     }
     Ok(buf)
 }
 
+/// Checks if `byte` ends a symbol, number, or character name.
+fn is_delimiter(byte: u8) -> bool {
+    match byte {
+        b'\t'...b'\r' | b' ' | b'(' | b')' | b'[' | b']' | b'{' | b'}' | b'"' | b';' |
+        b'\'' | b'`' | b',' => true,
+        _ => false,
+    }
+}
+
+/// The character called `name`, as in `#\newline` or `#\x41`.
+fn char_named(name: &str) -> Result<char, ReadError> {
+    Ok(match name {
+        "alarm" => '\x07',
+        "backspace" => '\x08',
+        "delete" => '\x7f',
+        "escape" => '\x1b',
+        "newline" => '\n',
+        "null" => '\0',
+        "return" => '\r',
+        "space" => ' ',
+        "tab" => '\t',
+        _ if name.starts_with('x') => {
+            try!(u32::from_str_radix(&name[1..], 16)
+                     .ok()
+                     .and_then(char::from_u32)
+                     .ok_or_else(|| ReadError::BadCharName(name.to_owned())))
+        }
+        _ => return Err(ReadError::BadCharName(name.to_owned())),
+    })
+}
+
+/// Parses an unsigned integer in `radix`, negating it if `negative`.
+/// Integers too large for a fixnum are read as floats, since there are no
+/// bignums.  In decimal these are correctly rounded; in other radixes the
+/// digits are accumulated in a float, which is exact up to 2^53.
+fn parse_integer(digits: &str, radix: u32, negative: bool) -> Option<Event> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None
+    }
+    let limit = 1 << (value::FIXNUM_WORD_BITS - 3);
+    let mut integer: isize = 0;
+    let mut float = 0.0;
+    for c in digits.chars() {
+        let digit = c.to_digit(radix).unwrap();
+        float = float * radix as f64 + digit as f64;
+        integer = integer.saturating_mul(radix as isize).saturating_add(digit as isize)
+    }
+    if radix == 10 {
+        float = digits.parse().unwrap()
+    }
+    Some(match (integer < limit, negative) {
+        (true, false) => Event::Int(integer),
+        (true, true) => Event::Int(-integer),
+        (false, false) => Event::Float(float),
+        (false, true) => Event::Float(-float),
+    })
+}
+
+/// The quotient of two numbers read by `parse_integer`.  Ratios that are
+/// not integers are read as floats, since there are no exact rationals.
+fn ratio(numerator: Event, denominator: Event) -> Option<Event> {
+    let as_float = |number| match number {
+        Event::Int(n) => n as f64,
+        Event::Float(f) => f,
+        _ => unreachable!(),
+    };
+    match (numerator, denominator) {
+        (_, Event::Int(0)) => None,
+        (Event::Int(n), Event::Int(d)) if n % d == 0 => Some(Event::Int(n / d)),
+        (n, d) => Some(Event::Float(as_float(n) / as_float(d))),
+    }
+}
+
+/// Parses a real number in `radix`, without a prefix.
+fn parse_real(token: &str, radix: u32) -> Option<Event> {
+    match &*token.to_lowercase() {
+        "+inf.0" => return Some(Event::Float(::std::f64::INFINITY)),
+        "-inf.0" => return Some(Event::Float(::std::f64::NEG_INFINITY)),
+        "+nan.0" | "-nan.0" => return Some(Event::Float(::std::f64::NAN)),
+        _ => {}
+    }
+    let (negative, digits) = match token.as_bytes().first() {
+        Some(&b'+') => (false, &token[1..]),
+        Some(&b'-') => (true, &token[1..]),
+        _ => (false, token),
+    };
+    if let Some(slash) = digits.find('/') {
+        let numerator = try_opt!(parse_integer(&digits[..slash], radix, negative));
+        let denominator = try_opt!(parse_integer(&digits[slash + 1..], radix, false));
+        return ratio(numerator, denominator)
+    }
+    if let Some(integer) = parse_integer(digits, radix, negative) {
+        return Some(integer)
+    }
+    // A decimal, like `1.5`, `.5`, or `1e10`.
+    let decimal = digits.chars().any(|c| c.is_digit(10)) &&
+                  digits.chars().all(|c| c.is_digit(10) || "+-.eE".contains(c));
+    if radix == 10 && decimal {
+        token.parse().ok().map(Event::Float)
+    } else {
+        None
+    }
+}
+
+/// Parses `token` as a number, if it is one.  `token` may start with radix
+/// (`#x`, `#b`, `#o`, `#d`) and exactness (`#e`, `#i`) prefixes.
+pub fn parse_number(token: &str) -> Option<Event> {
+    parse_number_in(token, 10)
+}
+
+/// Parses `token` as a number, as `parse_number` does, in `default_radix`
+/// unless it has a radix prefix.
+pub fn parse_number_in(token: &str, default_radix: u32) -> Option<Event> {
+    let (mut radix, mut exact) = (None, None);
+    let mut rest = token;
+    while rest.starts_with('#') {
+        match rest.as_bytes().get(1).map(u8::to_ascii_lowercase) {
+            Some(b'x') if radix.is_none() => radix = Some(16),
+            Some(b'o') if radix.is_none() => radix = Some(8),
+            Some(b'b') if radix.is_none() => radix = Some(2),
+            Some(b'd') if radix.is_none() => radix = Some(10),
+            Some(b'e') if exact.is_none() => exact = Some(true),
+            Some(b'i') if exact.is_none() => exact = Some(false),
+            _ => return None,
+        }
+        rest = &rest[2..]
+    }
+    match (try_opt!(parse_real(rest, radix.unwrap_or(default_radix))), exact) {
+        (Event::Int(n), Some(false)) => Some(Event::Float(n as f64)),
+        (Event::Float(f), Some(true)) => {
+            let limit = (1usize << (value::FIXNUM_WORD_BITS - 3)) as f64;
+            if f == f.trunc() && f.abs() < limit {
+                Some(Event::Int(f as isize))
+            } else {
+                None
+            }
+        }
+        (number, _) => Some(number),
+    }
+}
+
 pub struct Reader<'a, 'b, T: 'a + BufRead> {
     stream: &'a mut T,
     state: &'b mut interp::State,
 }
 
 pub struct EventSource<'a, R: 'a + BufRead> {
-    file: &'a mut Peekable<Bytes<R>>,
-    last_chr: Option<u8>,
+    file: &'a mut Input<R>,
+
+    /// Where the last event started.
+    start: Location,
 }
 
 macro_rules! my_try {
@@ -277,82 +569,128 @@ type ItemOption<'a, R> = Option<Item<'a, R>>;
 
 
 impl<'a, R: BufRead> EventSource<'a, R> {
-    pub fn new(reader: &'a mut Peekable<Bytes<R>>) -> Self {
+    pub fn new(reader: &'a mut Input<R>) -> Self {
         EventSource {
+            start: reader.location(),
             file: reader,
-            last_chr: Default::default(),
         }
     }
 
     fn handle_splicing(&mut self, nosplice: Event, splice: Event) -> Item<R> {
-        match self.file.next() {
-            Some(Ok(b'@')) => Ok(splice),
-            Some(Ok(l)) => {
-                self.last_chr = Some(l);
-                Ok(nosplice)
-            }
-            None => {
-                self.last_chr = None;
-                Ok(nosplice)
+        if try!(peek_byte(self.file)) == Some(b'@') {
+            self.file.next();
+            Ok(splice)
+        } else {
+            Ok(nosplice)
+        }
+    }
+    /// Reads the rest of a token, up to but not including a delimiter.
+    fn read_token(&mut self, buf: &mut String) -> Result<(), ReadError> {
+        while let Some(byte) = try!(peek_byte(self.file)) {
+            if is_delimiter(byte) {
+                break
             }
-            Some(Err(a)) => Err(ReadError::IoError(a)),
+            self.file.next();
+            buf.push(try!(finish_char(self.file, byte)))
         }
+        Ok(())
     }
-    fn read_hex(&mut self) -> Item<R> {
-        let mut buf = String::new();
-        for i in &mut self.file {
-            match try!(i.map_err(ReadError::IoError)) {
-                i @ b'0'...b'9' | i @ b'A'...b'F' | i @ b'a'...b'f' => buf.push(i as char),
-                _ => return Err(ReadError::BadHexNumber),
+    /// Skips a block comment, after its opening `#|`.  Block comments nest.
+    fn skip_block_comment(&mut self) -> Result<(), ReadError> {
+        let mut depth = 1;
+        let mut last = 0;
+        while depth > 0 {
+            let byte = next!(self.file, ReadError::EOFInComment);
+            match (last, byte) {
+                (b'|', b'#') => {
+                    depth -= 1;
+                    last = 0;
+                }
+                (b'#', b'|') => {
+                    depth += 1;
+                    last = 0;
+                }
+                _ => last = byte,
             }
         }
-        if let Ok(x) = buf.parse() {
-            Ok(Event::Int(x))
-        } else {
-            Err(ReadError::Overflow)
+        Ok(())
+    }
+    /// Skips a line comment, after its opening `;`.
+    fn skip_line_comment(&mut self) -> Result<(), ReadError> {
+        for byte in &mut self.file {
+            if try!(byte.map_err(ReadError::IoError)) == b'\n' {
+                break
+            }
         }
+        Ok(())
     }
     fn process_sharpsign(&mut self) -> ItemOption<R> {
         Some(Ok(match iter_next!(self.file, ReadError::EOFAfterSharp) {
             b'.' => Event::ReadEval,
             b'\\' => {
                 let byte = iter_next!(self.file, ReadError::EOFAfterSharpBackslash);
-                Event::Char(my_try!(finish_char(self.file, byte)))
+                let first = my_try!(finish_char(self.file, byte));
+                Event::Char(my_try!(self.read_char_name(first)))
+            }
+            first @ b't' | first @ b'f' => {
+                let mut name = String::new();
+                name.push(first as char);
+                my_try!(self.read_token(&mut name));
+                match &*name {
+                    "t" | "true" => Event::True,
+                    "f" | "false" => Event::False,
+                    _ => return Some(Err(ReadError::BadBoolean(name))),
+                }
+            }
+            prefix @ b'x' | prefix @ b'X' | prefix @ b'b' | prefix @ b'B' | prefix @ b'o' |
+            prefix @ b'O' | prefix @ b'd' | prefix @ b'D' | prefix @ b'e' | prefix @ b'E' |
+            prefix @ b'i' | prefix @ b'I' => {
+                let mut token = format!("#{}", prefix as char);
+                my_try!(self.read_token(&mut token));
+                my_try!(parse_number(&token).ok_or(ReadError::BadNumber(token)))
             }
-            b't' => Event::True,
-            b'f' => Event::False,
-            b'x' => my_try!(self.read_hex()),
             b'\'' => Event::Syntax,
             b'`' => Event::Quasisyntax,
             b',' => my_try!(self.handle_splicing(Event::Unsyntax, Event::UnsyntaxSplicing)),
             b'(' => Event::StartVec,
+            b';' => Event::DatumComment,
+            b'!' => {
+                let mut name = "#!".to_owned();
+                my_try!(self.read_token(&mut name));
+                if !PARAMETER_MARKERS.contains(&&*name) {
+                    return Some(Err(ReadError::BadSharpMacro(['!', '\0'])))
+                }
+                Event::Symbol(name)
+            }
             dispatch_char => {
                 return Some(Err(ReadError::BadSharpMacro([dispatch_char as char, '\0'])))
             }
         }))
     }
-    #[cfg_attr(feature = "clippy", allow(while_let_on_iterator))]
+    /// Reads the rest of a character after `#\\` and its first character
+    /// `first`: either nothing, or the rest of a character name.
+    fn read_char_name(&mut self, first: char) -> Result<char, ReadError> {
+        let mut name = String::new();
+        name.push(first);
+        try!(self.read_token(&mut name));
+        if name.chars().count() == 1 {
+            Ok(first)
+        } else {
+            char_named(&name)
+        }
+    }
+    /// Reads a symbol or number starting with `start`.
     fn read_symbol(&mut self, start: char) -> Result<Event, ReadError> {
         let mut buf = String::new();
         buf.push(start);
-        while let Some(x) = self.file.next() {
-            match try!(x.map_err(ReadError::IoError)) {
-                b'\\' => buf.push(try!(process_escape(self.file))),
+        while let Some(byte) = try!(peek_byte(self.file)) {
+            if is_delimiter(byte) {
+                break
+            }
+            self.file.next();
+            match byte {
+                b'\\' => buf.extend(try!(process_escape(self.file))),
                 b'|' => return Err(ReadError::PipeInSymbol),
-                a @ b'"' |
-                a @ b'\'' |
-                a @ b'`' |
-                a @ b',' |
-                a @ b'(' |
-                a @ b'[' |
-                a @ b']' |
-                a @ b')' |
-                a @ b'{' |
-                a @ b'}' => {
-                    self.last_chr = Some(a);
-                    break;
-                }
-                b'\t'...b'\r' | b' ' => break, // ASCII whitespace
                 chr => {
                     let unicode_char = try!(finish_char(self.file, chr));
                     if unicode_char.is_whitespace() {
@@ -364,6 +702,8 @@ impl<'a, R: BufRead> EventSource<'a, R> {
         }
         Ok(if &buf == "." {
             Event::Dot
+        } else if let Some(number) = parse_number(&buf) {
+            number
         } else {
             Event::Symbol(buf)
         })
@@ -375,16 +715,10 @@ impl<'a, R: BufRead> Iterator for EventSource<'a, R> {
     type Item = Result<Event, ReadError>;
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         loop {
-            let chr = if let Some(chr) = self.last_chr {
-                self.last_chr = None;
-                chr
-            } else {
-                let next: Option<Result<u8, io::Error>> = self.file.next();
-                if let Some(c) = next {
-                    my_try!(c.map_err(ReadError::IoError))
-                } else {
-                    return None;
-                }
+            self.start = self.file.location();
+            let chr = match self.file.next() {
+                Some(c) => my_try!(c.map_err(ReadError::IoError)),
+                None => return None,
             };
             return Some(Ok(match chr {
                 b'(' => Event::StartList(false),
@@ -392,7 +726,18 @@ impl<'a, R: BufRead> Iterator for EventSource<'a, R> {
                 b'\'' => Event::Quote,
                 b'`' => Event::Quasiquote,
                 b',' => my_try!(self.handle_splicing(Event::Unquote, Event::UnquoteSplicing)),
-                b'#' => return self.process_sharpsign(),
+                b';' => {
+                    my_try!(self.skip_line_comment());
+                    continue
+                }
+                b'#' => {
+                    if let Some(&Ok(b'|')) = self.file.peek() {
+                        self.file.next();
+                        my_try!(self.skip_block_comment());
+                        continue
+                    }
+                    return self.process_sharpsign()
+                }
                 b')' => Event::EndList(false),
                 b']' => Event::EndList(true),
                 b'"' => Event::Str(my_try!(read_escaped(self.file, StringOrSymbol::String))),
@@ -414,156 +759,251 @@ impl<'a, R: BufRead> Iterator for EventSource<'a, R> {
     }
 }
 
-pub fn read<R: BufRead>(s: &mut api::State, r: &mut Peekable<Bytes<R>>) -> Result<(), ReadError> {
+/// Reads one datum from `r` and pushes it, or pushes the EOF object if there
+/// are no more data.  On error, nothing is pushed.
+pub fn read<R: BufRead>(s: &mut api::State, r: &mut Input<R>) -> Result<(), ReadError> {
+    read_to_heap(s.heap(), r)
+}
+
+/// Like `read`, but pushes the datum on the stack of `heap`.
+pub fn read_to_heap<R: BufRead>(heap: &mut Heap, r: &mut Input<R>) -> Result<(), ReadError> {
+    let depth = heap.stack.len();
+    let result = read_datum(heap, r);
+    if result.is_err() {
+        heap.stack.truncate(depth)
+    }
+    result
+}
+
+/// Pushes `value` on the stack of `heap`.
+fn push<T: SchemeValue>(heap: &mut Heap, value: T) -> Result<(), ReadError> {
+    let value = try!(value.to_value(heap).map_err(|_| ReadError::MemLimitExceeded));
+    Ok(heap.stack.push(value))
+}
+
+/// Replaces the `len` values below the top of the stack, and the final cdr
+/// on top, by a list.
+fn list_with_tail(heap: &mut Heap, len: usize) -> Result<(), String> {
+    for _ in 0..len {
+        let top = heap.stack.len() - 1;
+        try!(heap.alloc_pair(top - 1, top));
+        let pair = heap.stack.pop().unwrap();
+        heap.stack.truncate(top - 1);
+        heap.stack.push(pair)
+    }
+    Ok(())
+}
+
+/// Records that the value on top of the stack was read at `location`.
+fn set_location(heap: &mut Heap, location: &Location) -> Result<(), String> {
+    let top = heap.stack.len() - 1;
+    heap.set_location(top, location)
+}
+
+fn read_datum<R: BufRead>(heap: &mut Heap, r: &mut Input<R>) -> Result<(), ReadError> {
     #[derive(Copy, Clone, Debug)]
     enum State {
         List {
             is_square: bool,
             depth: usize,
         },
+        /// After the `.` of a dotted list, before its tail.
         DottedList {
             is_square: bool,
             depth: usize,
         },
-        Vec {
+        /// After the tail of a dotted list, before its `)`.
+        DottedTail {
+            is_square: bool,
             depth: usize,
         },
+        /// In a vector, whose elements are pushed to a growable vector on
+        /// the stack as they are read.
+        Vec,
         ReaderMacro,
+        DatumComment,
     }
+    let mem = |_| ReadError::MemLimitExceeded;
     let mut read_stack: Vec<State> = Vec::new();
+    // Where each datum in `read_stack` started.
+    let mut starts: Vec<Location> = Vec::new();
     let mut source = EventSource::new(r);
     loop {
         let i = match source.next() {
-            None => return Ok(()),
+            None => {
+                return match read_stack.pop() {
+                    None => Ok(heap.stack.push(Value::new(value::EOF))),
+                    Some(State::Vec) => Err(ReadError::EOFInVector),
+                    Some(State::ReaderMacro) |
+                    Some(State::DatumComment) => Err(ReadError::EOFAfterPrefix),
+                    Some(_) => Err(ReadError::EOFInList),
+                }
+            }
             Some(x) => x,
         };
-        match try!(i) {
-            Event::Char(_) => unimplemented!(),
+        let prefix = match try!(i) {
+            Event::Char(c) => {
+                try!(push(heap, c));
+                None
+            }
             Event::Int(x) => {
-                s.push(x).unwrap();
-                // try!(execute_macros(source))
+                // Integers too big for a fixnum are read as flonums.
+                match x.checked_mul(4).and_then(Value::from_fixnum_word) {
+                    Some(value) => heap.stack.push(value),
+                    None => try!(push(heap, x as f64)),
+                }
+                None
+            }
+            Event::Float(x) => {
+                try!(push(heap, x));
+                None
+            }
+            Event::True => {
+                heap.stack.push(Value::new(value::TRUE));
+                None
+            }
+            Event::False => {
+                heap.stack.push(Value::new(value::FALSE));
+                None
             }
             Event::Str(st) => {
-                s.push(st).unwrap();
-                // try!(execute_macros(source))
+                try!(push(heap, st));
+                None
             }
             Event::Symbol(st) => {
-                s.intern(&st).unwrap();
-                // try!(execute_macros(source))
+                heap.intern(&st);
+                None
             }
             Event::Dot => {
-                let len = read_stack.len().wrapping_sub(1);
-                if let Some(x) = read_stack.get_mut(len) {
-                    match *x {
-                        State::List { depth, is_square } => {
-                            *x = State::DottedList {
-                                depth: depth,
-                                is_square: is_square,
-                            };
-                            continue
+                match read_stack.last_mut() {
+                    Some(x) => {
+                        match *x {
+                            State::List { depth, is_square } if depth > 0 => {
+                                *x = State::DottedList {
+                                    depth: depth,
+                                    is_square: is_square,
+                                }
+                            }
+                            _ => return Err(ReadError::BadDot),
                         }
-                        _ => return Err(ReadError::BadDot),
                     }
-                } else {
-                    return Err(ReadError::BadDot);
+                    None => return Err(ReadError::BadDot),
                 }
-                continue;
+                continue
             }
             Event::EndList(is_square) => {
-                if let Some(state) = read_stack.pop() {
-                    match state {
-                        State::DottedList { .. } | State::ReaderMacro =>
-                            return Err(ReadError::UnexpectedCloseParen),
-                        State::Vec { depth } => {
-                            debug_assert!(depth > 0);
-                            if is_square {
-                                return Err(ReadError::BadCloseParen)
-                            } else {
-                                s.vector(1, depth).expect("Out of mem!")
-                            }
+                let start = starts.pop();
+                match read_stack.pop() {
+                    Some(State::Vec) => {
+                        if is_square {
+                            return Err(ReadError::BadCloseParen)
                         }
-                        State::List { is_square: square, depth } => {
-                            if square == is_square {
-                                s.list(depth).expect("Out of mem!")
-                            } else {
-                                return Err(ReadError::BadCloseParen)
-                            }
+                        try!(heap.dynvector_to_vector().map_err(&mem))
+                    }
+                    Some(State::List { is_square: square, depth }) => {
+                        if square != is_square {
+                            return Err(ReadError::BadCloseParen)
+                        }
+                        heap.stack.push(Value::new(value::NIL));
+                        try!(list_with_tail(heap, depth).map_err(&mem));
+                        if depth > 0 {
+                            try!(set_location(heap, &start.unwrap()).map_err(&mem))
+                        }
+                    }
+                    Some(State::DottedTail { is_square: square, depth }) => {
+                        if square != is_square {
+                            return Err(ReadError::ParenMismatch)
                         }
+                        try!(list_with_tail(heap, depth).map_err(&mem));
+                        try!(set_location(heap, &start.unwrap()).map_err(&mem))
                     }
-                } else {
-                    return Ok(())
+                    _ => return Err(ReadError::UnexpectedCloseParen),
                 }
+                None
             }
             Event::StartVec => {
-                read_stack.push(State::Vec { depth: 0 });
-                continue;
+                try!(heap.alloc_dynvector(0).map_err(&mem));
+                read_stack.push(State::Vec);
+                starts.push(source.start.clone());
+                continue
             }
             Event::StartList(x) => {
                 read_stack.push(State::List {
                     is_square: x,
                     depth: 0,
                 });
-                continue;
+                starts.push(source.start.clone());
+                continue
             }
-            Event::Quote => {
-                try!(s.push("quote".to_owned()).map_err(|()| ReadError::MemLimitExceeded));
-                read_stack.push(State::ReaderMacro);
-                continue;
+            Event::DatumComment => {
+                read_stack.push(State::DatumComment);
+                starts.push(source.start.clone());
+                continue
             }
-            Event::Quasiquote => {
-                try!(s.push("backquote".to_owned()).map_err(|()| ReadError::MemLimitExceeded));
-                read_stack.push(State::ReaderMacro);
-                continue;
-            }
-            Event::Unquote => {
-                try!(s.push("unquote".to_owned()).map_err(|()| ReadError::MemLimitExceeded));
-                read_stack.push(State::ReaderMacro);
-                continue;
-            }
-            _ => return Err(ReadError::NYI),
+            Event::Quote => Some("quote"),
+            Event::Quasiquote => Some("quasiquote"),
+            Event::Unquote => Some("unquote"),
+            Event::UnquoteSplicing => Some("unquote-splicing"),
+            Event::Syntax => Some("syntax"),
+            Event::Quasisyntax => Some("quasisyntax"),
+            Event::Unsyntax => Some("unsyntax"),
+            Event::UnsyntaxSplicing => Some("unsyntax-splicing"),
+            Event::ReadEval | Event::EOF => return Err(ReadError::NYI),
+        };
+        if let Some(name) = prefix {
+            // `'x` is `(quote x)`, and so on.
+            heap.intern(name);
+            read_stack.push(State::ReaderMacro);
+            starts.push(source.start.clone());
+            continue
         }
-        let last = read_stack.len().wrapping_sub(1);
-        if let Some(&x) = read_stack.get(last) {
-            match x {
+        // A datum has been read.  Add it to the enclosing datum, which may
+        // complete that too.
+        loop {
+            let last = match read_stack.last_mut() {
+                Some(last) => last,
+                None => return Ok(()),
+            };
+            match *last {
                 State::ReaderMacro => {
-                    try!(s.list(2).map_err(|_| ReadError::MemLimitExceeded));
+                    heap.stack.push(Value::new(value::NIL));
+                    try!(list_with_tail(heap, 2).map_err(&mem));
+                    try!(set_location(heap, &starts.pop().unwrap()).map_err(&mem));
+                    read_stack.pop();
+                    continue
+                }
+                State::DatumComment => {
+                    heap.stack.pop();
+                    starts.pop();
                     read_stack.pop();
                 }
                 State::List { depth, is_square } => {
-                    read_stack[last] = State::List {
+                    *last = State::List {
                         depth: depth + 1,
                         is_square: is_square,
                     }
                 }
-                State::Vec { depth } => {
-                    read_stack[last] = State::Vec {
-                        depth: depth + 1,
-                    }
+                State::Vec => {
+                    let top = heap.stack.len() - 1;
+                    try!(heap.dynvector_push(top - 1, top).map_err(&mem));
+                    heap.stack.pop();
                 }
                 State::DottedList { depth, is_square } => {
-                    try!(s.list_with_tail(depth).map_err(|_| ReadError::MemLimitExceeded));
-                    if let Some(token) = source.next() {
-                        debug!("Token that must be close paren: {:?}\n", token);
-                        match try!(token) {
-                            Event::EndList(x) if x == is_square => continue,
-                            Event::EndList(_) => return Err(ReadError::ParenMismatch),
-                            _ => return Err(ReadError::MissingCloseParen),
-                        }
-                    } else {
-                        s.drop().expect("Empty stack after list_with_tail?");
-                        return Err(ReadError::BadDot);
+                    *last = State::DottedTail {
+                        depth: depth,
+                        is_square: is_square,
                     }
                 }
+                State::DottedTail { .. } => return Err(ReadError::MissingCloseParen),
             }
-        } else {
-            return Ok(());
+            break
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::io::Read;
+    use super::Input;
     use env_logger;
     use api;
     #[test]
@@ -571,15 +1011,128 @@ mod test {
         let _ = env_logger::init();
         let mut interp = api::State::new();
         let iter = b"(a b c . d)";
-        super::read(&mut interp, &mut iter.bytes().peekable()).unwrap();
+        super::read(&mut interp, &mut Input::new(&iter[..], "test")).unwrap();
         assert_eq!(interp.len(), 1);
     }
 
+    #[test]
+    fn read_chars() {
+        let mut interp = api::State::new();
+        let mut iter = Input::new("#\\a #\\newline #\\x41 #\\( #\\λ".as_bytes(), "test");
+        for &c in &['a', '\n', 'A', '(', 'λ'] {
+            super::read(&mut interp, &mut iter).unwrap();
+            assert_eq!(interp.pop(), Ok(c))
+        }
+        let mut iter = Input::new(&b"#\\bogus"[..], "test");
+        assert!(super::read(&mut interp, &mut iter).is_err());
+    }
+
     #[test]
     fn read_to_vec() {
         let _ = env_logger::init();
         let mut interp = api::State::new();
-        let mut iter = b"#(a b c d)".bytes().peekable();
+        let mut iter = Input::new(&b"#(a b c d)"[..], "test");
         super::read(&mut interp, &mut iter).unwrap();
     }
+
+    /// Reads every datum in `source`, returning what `write` prints for each.
+    fn read_all(source: &str) -> Result<Vec<String>, super::ReadError> {
+        let mut interp = api::State::new();
+        let mut iter = Input::new(source.as_bytes(), "test");
+        let mut data = vec![];
+        loop {
+            try!(super::read(&mut interp, &mut iter));
+            let datum = interp.write_string().unwrap();
+            interp.drop().unwrap();
+            if datum == "#<eof>" {
+                assert!(interp.is_empty());
+                return Ok(data)
+            }
+            data.push(datum)
+        }
+    }
+
+    #[test]
+    fn read_numbers() {
+        assert_eq!(read_all("42 -17 +3 #x1F #X-ff #b101 #o17 #d99 6/3 -8/4").unwrap(),
+                   ["42", "-17", "3", "31", "-255", "5", "15", "99", "2", "-2"]);
+        assert_eq!(read_all("1.5 .5 -2. 1e3 #i3 #x#i10 1/2 +inf.0 -inf.0").unwrap(),
+                   ["1.5", "0.5", "-2.0", "1000.0", "3.0", "16.0", "0.5", "+inf.0", "-inf.0"]);
+        assert_eq!(read_all("#e2.0 #e#x10 100000000000000000000").unwrap(),
+                   ["2", "16", "1e20"]);
+        assert_eq!(read_all("0.1 1e-300 -0.0 123456789012345678901234 1.7976931348623157e308")
+                       .unwrap(),
+                   ["0.1", "1e-300", "-0.0", "1.2345678901234568e23", "1.7976931348623157e308"]);
+        // Not numbers.
+        assert_eq!(read_all("+ - ... 1+ -> .5a").unwrap(), ["+", "-", "...", "1+", "->", ".5a"]);
+        for bad in &["#xZZ", "#e1.5", "#x#x1", "#b2", "#x1/0"] {
+            assert!(read_all(bad).is_err(), "{} should not read", bad);
+        }
+    }
+
+    #[test]
+    fn read_data() {
+        assert_eq!(read_all("(a . (b c)) [a b . c] () (#t #f #true #false)").unwrap(),
+                   ["(a b c)", "(a b . c)", "()", "(#t #f #t #f)"]);
+        assert_eq!(read_all("#(1 (2) #()) \"a\\\"b\\x41;\\n\" \"x\\\n   y\" |a b|").unwrap(),
+                   ["#(1 (2) #())", "\"a\\\"bA\\n\"", "\"xy\"", "|a b|"]);
+        assert_eq!(read_all("'a ''a `(x ,y ,@z) #'s").unwrap(),
+                   ["(quote a)",
+                    "(quote (quote a))",
+                    "(quasiquote (x (unquote y) (unquote-splicing z)))",
+                    "(syntax s)"]);
+        assert_eq!(read_all("(a'b)").unwrap(), ["(a (quote b))"]);
+        assert_eq!(read_all("(x #!optional y #!rest z #!key k)").unwrap(),
+                   ["(x #!optional y #!rest z #!key k)"]);
+    }
+
+    #[test]
+    fn read_comments() {
+        assert_eq!(read_all("; line\n1 #| block #| nested |# |# 2 #;(3 4) 5").unwrap(),
+                   ["1", "2", "5"]);
+        assert_eq!(read_all("(1 #;2 3 #; #;4 5) (a . #;b c) #;'x").unwrap(),
+                   ["(1 3)", "(a . c)"]);
+        assert_eq!(read_all("").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn read_errors() {
+        for bad in &["(a", "#(a", "(a . b c)", "(. a)", "(a . )", ")", "(a]", "'", "#| a",
+                     "\"abc", "#tru", "#!eof"] {
+            let mut interp = api::State::new();
+            interp.push(1usize).unwrap();
+            assert!(super::read(&mut interp, &mut Input::new(bad.as_bytes(), "test")).is_err(),
+                    "{} should not read",
+                    bad);
+            assert_eq!(interp.len(), 1);
+        }
+    }
+
+    #[test]
+    fn incomplete_input() {
+        let mut interp = api::State::new();
+        for &(source, incomplete) in &[("(a", true), ("#(a (b)", true), ("\"abc", true),
+                                       ("|ab", true), ("#| a", true), ("'", true),
+                                       ("#;", true), (")", false), ("(a]", false),
+                                       ("#tru", false)] {
+            let error = interp.read(&mut Input::new(source.as_bytes(), "test")).unwrap_err();
+            assert_eq!(error.is_incomplete(), incomplete, "{}", source);
+        }
+        let mut iter = Input::new(&b"a ; b"[..], "test");
+        assert_eq!(interp.read(&mut iter).unwrap(), true);
+        assert_eq!(interp.read(&mut iter).unwrap(), false);
+        assert_eq!(interp.len(), 1);
+    }
+
+    #[test]
+    fn read_locations() {
+        let mut interp = api::State::new();
+        let mut iter = Input::new("(a)\n  (b\n c) λ '(d) ; (e)\n#;(f) [g] ()".as_bytes(), "test");
+        for &expected in &["test:1:1", "test:2:3", "", "test:3:7", "test:4:7", ""] {
+            super::read(&mut interp, &mut iter).unwrap();
+            interp.gc();
+            let location = interp.location().map(|location| location.to_string());
+            assert_eq!(location.unwrap_or_default(), expected);
+        }
+    }
 }
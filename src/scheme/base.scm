@@ -0,0 +1,114 @@
+;;; The part of `(scheme base)` that is written in Scheme: derived syntax,
+;;; and procedures that need nothing but other procedures.  Interpreters
+;;; that are not bare load it before they compile their first form (see
+;;; `compiler::library`).  Each name it defines must be in `BASE_NAMES`.
+
+(define-syntax and
+  (syntax-rules ()
+    ((_) #t)
+    ((_ test) test)
+    ((_ test1 test2 ...) (if test1 (and test2 ...) #f))))
+
+(define-syntax or
+  (syntax-rules ()
+    ((_) #f)
+    ((_ test) test)
+    ((_ test1 test2 ...) (let ((x test1)) (if x x (or test2 ...))))))
+
+(define-syntax when
+  (syntax-rules ()
+    ((_ test result1 result2 ...) (if test (begin result1 result2 ...)))))
+
+(define-syntax unless
+  (syntax-rules ()
+    ((_ test result1 result2 ...) (if test (if #f #f) (begin result1 result2 ...)))))
+
+(define-syntax cond
+  (syntax-rules (else =>)
+    ((_ (else result1 result2 ...)) (begin result1 result2 ...))
+    ((_ (test => result)) (let ((temp test)) (if temp (result temp))))
+    ((_ (test => result) clause1 clause2 ...)
+     (let ((temp test)) (if temp (result temp) (cond clause1 clause2 ...))))
+    ((_ (test)) test)
+    ((_ (test) clause1 clause2 ...)
+     (let ((temp test)) (if temp temp (cond clause1 clause2 ...))))
+    ((_ (test result1 result2 ...)) (if test (begin result1 result2 ...)))
+    ((_ (test result1 result2 ...) clause1 clause2 ...)
+     (if test (begin result1 result2 ...) (cond clause1 clause2 ...)))))
+
+(define-syntax case
+  (syntax-rules (else =>)
+    ((_ (key ...) clauses ...) (let ((atom-key (key ...))) (case atom-key clauses ...)))
+    ((_ key (else => result)) (result key))
+    ((_ key (else result1 result2 ...)) (begin result1 result2 ...))
+    ((_ key ((atoms ...) => result)) (if (memv key '(atoms ...)) (result key)))
+    ((_ key ((atoms ...) => result) clause clauses ...)
+     (if (memv key '(atoms ...)) (result key) (case key clause clauses ...)))
+    ((_ key ((atoms ...) result1 result2 ...))
+     (if (memv key '(atoms ...)) (begin result1 result2 ...)))
+    ((_ key ((atoms ...) result1 result2 ...) clause clauses ...)
+     (if (memv key '(atoms ...)) (begin result1 result2 ...) (case key clause clauses ...)))))
+
+(define-syntax let*
+  (syntax-rules ()
+    ((_ () body1 body2 ...) (let () body1 body2 ...))
+    ((_ ((name1 val1) (name2 val2) ...) body1 body2 ...)
+     (let ((name1 val1)) (let* ((name2 val2) ...) body1 body2 ...)))))
+
+(define-syntax do
+  (syntax-rules ()
+    ((_ ((var init step ...) ...) (test expr ...) command ...)
+     (let loop ((var init) ...)
+       (if test
+           (begin (if #f #f) expr ...)
+           (begin command ... (loop (do "step" var step ...) ...)))))
+    ((_ "step" x) x)
+    ((_ "step" x y) y)))
+
+(define (not x) (if x #f #t))
+
+(define (boolean? x) (or (eq? x #t) (eq? x #f)))
+
+(define (boolean=? x y . rest)
+  (and (boolean? x)
+       (eq? x y)
+       (or (null? rest) (apply boolean=? y rest))))
+
+(define (caar x) (car (car x)))
+(define (cadr x) (car (cdr x)))
+(define (cdar x) (cdr (car x)))
+(define (cddr x) (cdr (cdr x)))
+
+;; Whether `x` is a proper list: the second pointer moves twice as fast, and
+;; meets the first if the list is circular.
+(define (list? x)
+  (let loop ((fast x) (slow x))
+    (cond ((null? fast) #t)
+          ((not (pair? fast)) #f)
+          ((null? (cdr fast)) #t)
+          ((not (pair? (cdr fast))) #f)
+          (else (let ((fast (cddr fast)) (slow (cdr slow)))
+                  (and (not (eq? fast slow)) (loop fast slow)))))))
+
+(define (zero? z) (= z 0))
+(define (positive? x) (> x 0))
+(define (negative? x) (< x 0))
+
+;; The greatest or least of `x` and `rest`, by `better?`, which is inexact
+;; if any of them is.
+(define (%extremum better? x rest)
+  (let loop ((best x) (rest rest) (exact (exact? x)))
+    (if (null? rest)
+        (if exact best (inexact best))
+        (loop (if (better? (car rest) best) (car rest) best)
+              (cdr rest)
+              (and exact (exact? (car rest)))))))
+
+(define (max x . rest) (%extremum > x rest))
+(define (min x . rest) (%extremum < x rest))
+
+(define (call-with-port port proc)
+  (call-with-values (lambda () (proc port))
+    (lambda results
+      (close-port port)
+      (apply values results))))
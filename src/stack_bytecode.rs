@@ -0,0 +1,323 @@
+//! The stack format of bytecode, which compiled files of the previous
+//! version of the format hold, and its translation to the register format
+//! (see `bytecode::Opcode`).  Only built with the `stack-bytecode` feature,
+//! so that such files can still be loaded while they are being recompiled.
+//!
+//! In the stack format, instructions pop their operands off the stack and
+//! push their results, above the variables of the frame: `Enter` has the
+//! number of required arguments as `src`, 1 as `src2` if there is a rest
+//! argument, and the number of local variables as `dst`.  `LoadLocal`,
+//! `StoreLocal` and `Box` take the index of a variable as `wide()`,
+//! `JumpIfFalse` pops its test and jumps to `long()`, `Closure` pops the
+//! `src` values it captures, and `Call` and `TailCall` pop the callee and
+//! their `src` arguments.
+//!
+//! The depth of the stack before each instruction is the same however it is
+//! reached, so the value at depth `d` goes in the register after the
+//! variables and `d` temporaries.
+
+use bytecode::{Bytecode, Opcode};
+use value::{Kind, Value};
+
+/// An instruction with the registers `src` and `src2`.
+fn pair(opcode: Opcode, src: u8, src2: u8) -> Bytecode {
+    Bytecode {
+        opcode: opcode,
+        src: src,
+        src2: src2,
+        dst: 0,
+    }
+}
+
+/// The number of values that `instruction` pops, and the number it pushes.
+fn effect(instruction: Bytecode) -> Option<(usize, usize)> {
+    let src = instruction.src as usize;
+    Some(match instruction.opcode {
+        Opcode::LoadTrue | Opcode::LoadFalse | Opcode::LoadNil | Opcode::LoadUnspecified |
+        Opcode::LoadConstant | Opcode::LoadLocal | Opcode::LoadCaptured |
+        Opcode::LoadGlobal => (0, 1),
+        Opcode::StoreLocal | Opcode::Pop | Opcode::StoreGlobal | Opcode::DefineGlobal |
+        Opcode::Return | Opcode::JumpIfFalse => (1, 0),
+        Opcode::Box | Opcode::Jump => (0, 0),
+        Opcode::Unbox => (1, 1),
+        Opcode::SetBox => (2, 0),
+        Opcode::Closure => (src, 1),
+        Opcode::Call => (src + 1, 1),
+        Opcode::TailCall => (src + 1, 0),
+        _ => return None,
+    })
+}
+
+/// Translates `code`, the instructions of a BCO in the stack format, to
+/// the register format.  Also returns the new index of each instruction,
+/// or of the next one kept if it is dropped, and of the end of the code,
+/// for the source map (see `remap_locations`).
+///
+/// The stack format does not record how many values a closure over the
+/// code captures, so it is taken to be as many as the code loads: a
+/// closure made by translated code captures the first of the values that
+/// the old code gave it, and those it never loads are dropped.
+pub fn translate(code: &[u8]) -> Result<(Vec<u8>, Vec<usize>), String> {
+    let code: Vec<_> = code.chunks(4)
+                           .map(|bytes| {
+                               Bytecode {
+                                   opcode: Opcode::from_u8(bytes[0]).expect("bad opcode"),
+                                   src: bytes[1],
+                                   src2: bytes[2],
+                                   dst: bytes[3],
+                               }
+                           })
+                           .collect();
+    let error = |pc: usize, message: &str| format!("bad stack bytecode at pc {}: {}", pc, message);
+    let enter = match code.first() {
+        Some(&enter) if enter.opcode == Opcode::Enter => enter,
+        _ => return Err(error(0, "it does not start with Enter")),
+    };
+    let (required, rest) = (enter.src as usize, enter.src2 != 0);
+    let variables = required + rest as usize + enter.dst as usize;
+
+    // The depth of the stack before each instruction that can be reached.
+    let mut depths = vec![None; code.len()];
+    let mut pending = vec![(1, 0)];
+    while let Some((pc, depth)) = pending.pop() {
+        if pc >= code.len() {
+            return Err(error(pc, "it continues past the end of the code"))
+        }
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(_) => return Err(error(pc, "the stack depth differs between paths")),
+            None => depths[pc] = Some(depth),
+        }
+        let instruction = code[pc];
+        let (pops, pushes) = match effect(instruction) {
+            Some(effect) => effect,
+            None => return Err(error(pc, &format!("unexpected {:?}", instruction.opcode))),
+        };
+        if depth < pops {
+            return Err(error(pc, "stack underflow"))
+        }
+        let depth = depth - pops + pushes;
+        match instruction.opcode {
+            Opcode::Return | Opcode::TailCall => {}
+            Opcode::Jump => pending.push((instruction.long_operand(), depth)),
+            Opcode::JumpIfFalse => {
+                pending.push((instruction.long_operand(), depth));
+                pending.push((pc + 1, depth))
+            }
+            _ => pending.push((pc + 1, depth)),
+        }
+    }
+    // One more register than the deepest stack, for `Closure` to move the
+    // values it captures up into.
+    let deepest = depths.iter().filter_map(|&depth| depth).max().unwrap_or(0);
+    if variables + deepest + 1 > 255 {
+        return Err(error(0, "it needs too many registers"))
+    }
+    let captured = code.iter()
+                       .filter(|instruction| instruction.opcode == Opcode::LoadCaptured)
+                       .map(|instruction| instruction.wide_operand() + 1)
+                       .max()
+                       .unwrap_or(0);
+    if captured > 255 {
+        return Err(error(0, "it captures too many values"))
+    }
+
+    let mut translated = vec![Bytecode {
+                                  opcode: if rest { Opcode::EnterRest } else { Opcode::Enter },
+                                  src: required as u8,
+                                  src2: captured as u8,
+                                  dst: (variables + deepest + 1) as u8,
+                              }];
+    let mut pcs = vec![0];
+    for (pc, &instruction) in code.iter().enumerate().skip(1) {
+        pcs.push(translated.len());
+        let depth = match depths[pc] {
+            Some(depth) => depth,
+            None => continue,
+        };
+        // The register of the value at the given depth from the top of the
+        // stack, counting from 1.
+        let top = |from: usize| (variables + depth - from) as u8;
+        let (opcode, wide) = (instruction.opcode, instruction.wide_operand());
+        let variable = |pc| {
+            if wide < variables {
+                Ok(wide as u8)
+            } else {
+                Err(error(pc, "operand out of range"))
+            }
+        };
+        match opcode {
+            Opcode::LoadTrue | Opcode::LoadFalse | Opcode::LoadNil | Opcode::LoadUnspecified |
+            Opcode::LoadConstant | Opcode::LoadCaptured | Opcode::LoadGlobal => {
+                translated.push(Bytecode::wide(opcode, top(0), wide as u16))
+            }
+            Opcode::LoadLocal => translated.push(pair(Opcode::Move, top(0), try!(variable(pc)))),
+            Opcode::StoreLocal => translated.push(pair(Opcode::Move, try!(variable(pc)), top(1))),
+            Opcode::Pop => {}
+            Opcode::Box => translated.push(Bytecode::wide(Opcode::Box, try!(variable(pc)), 0)),
+            Opcode::Unbox => translated.push(pair(Opcode::Unbox, top(1), top(1))),
+            Opcode::SetBox => translated.push(pair(Opcode::SetBox, top(1), top(2))),
+            Opcode::StoreGlobal | Opcode::DefineGlobal | Opcode::Return => {
+                translated.push(Bytecode::wide(opcode, top(1), wide as u16))
+            }
+            Opcode::Closure => {
+                // The captured values must follow the register the closure
+                // goes in, which is that of the first of them.
+                let count = instruction.src as usize;
+                for i in 0..count {
+                    translated.push(pair(Opcode::Move, top(i), top(i + 1)))
+                }
+                translated.push(Bytecode::wide(Opcode::Closure, top(count), wide as u16))
+            }
+            Opcode::Call | Opcode::TailCall => {
+                let count = instruction.src;
+                translated.push(pair(opcode, top(count as usize + 1), count))
+            }
+            Opcode::Jump => translated.push(instruction),
+            Opcode::JumpIfFalse => translated.push(Bytecode::wide(opcode, top(1), 0)),
+            _ => unreachable!(),
+        }
+    }
+    pcs.push(translated.len());
+    if translated.len() > 0xffff {
+        return Err(error(0, "it is too long"))
+    }
+    for (pc, &instruction) in code.iter().enumerate() {
+        let new = pcs[pc];
+        match instruction.opcode {
+            Opcode::Jump if depths[pc].is_some() => {
+                translated[new] = Bytecode::long(Opcode::Jump, pcs[instruction.long_operand()])
+            }
+            Opcode::JumpIfFalse if depths[pc].is_some() => {
+                let target = pcs[instruction.long_operand()] as u16;
+                translated[new] = Bytecode::wide(Opcode::JumpIfFalse, translated[new].src, target)
+            }
+            _ => {}
+        }
+    }
+    let mut bytes = Vec::with_capacity(4 * translated.len());
+    for instruction in translated {
+        bytes.extend_from_slice(&instruction.to_bytes())
+    }
+    Ok((bytes, pcs))
+}
+
+/// Changes the pcs in the source map in `constants`, the constants vector
+/// of code that `translate` translated, to the new ones in `pcs`.  Entries
+/// that are not as the compiler makes them are left alone.
+pub fn remap_locations(constants: &Value, pcs: &[usize]) {
+    let map = match constants.kind() {
+        Kind::Vector(constants) if unsafe { (*constants).len() } > 1 => unsafe {
+            (*constants).element(1).clone()
+        },
+        _ => return,
+    };
+    if let Kind::Vector(map) = map.kind() {
+        let len = unsafe { (*map).len() };
+        for entry in 0..len / 4 {
+            let pc = unsafe { (*map).element(4 * entry) };
+            match pc.as_fixnum() {
+                Ok(old) if old < pcs.len() => pc.set(Value::new_fixnum(pcs[old])),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecode::{Bytecode, Opcode};
+    use super::*;
+
+    fn op(opcode: Opcode, src: u8, wide: u16) -> Bytecode {
+        Bytecode::wide(opcode, src, wide)
+    }
+
+    fn bytes(code: &[Bytecode]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for instruction in code {
+            bytes.extend_from_slice(&instruction.to_bytes())
+        }
+        bytes
+    }
+
+    #[test]
+    fn translate_calls_and_conditionals() {
+        // `(lambda (x) (f (g x) (if x 1 2)) #t)`
+        let old = [Bytecode { opcode: Opcode::Enter, src: 1, src2: 0, dst: 0 },
+                   op(Opcode::LoadGlobal, 0, 2),
+                   op(Opcode::LoadGlobal, 0, 3),
+                   op(Opcode::LoadLocal, 0, 0),
+                   op(Opcode::Call, 1, 0),
+                   op(Opcode::LoadLocal, 0, 0),
+                   Bytecode::long(Opcode::JumpIfFalse, 9),
+                   op(Opcode::LoadConstant, 0, 4),
+                   Bytecode::long(Opcode::Jump, 10),
+                   op(Opcode::LoadConstant, 0, 5),
+                   op(Opcode::Call, 2, 0),
+                   op(Opcode::Pop, 0, 0),
+                   op(Opcode::LoadTrue, 0, 0),
+                   op(Opcode::Return, 0, 0)];
+        let new = [Bytecode { opcode: Opcode::Enter, src: 1, src2: 0, dst: 5 },
+                   op(Opcode::LoadGlobal, 1, 2),
+                   op(Opcode::LoadGlobal, 2, 3),
+                   pair(Opcode::Move, 3, 0),
+                   pair(Opcode::Call, 2, 1),
+                   pair(Opcode::Move, 3, 0),
+                   op(Opcode::JumpIfFalse, 3, 9),
+                   op(Opcode::LoadConstant, 3, 4),
+                   Bytecode::long(Opcode::Jump, 10),
+                   op(Opcode::LoadConstant, 3, 5),
+                   pair(Opcode::Call, 1, 2),
+                   op(Opcode::LoadTrue, 1, 0),
+                   op(Opcode::Return, 1, 0)];
+        assert_eq!(translate(&bytes(&old)),
+                   Ok((bytes(&new), vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 11, 12, 13])));
+    }
+
+    #[test]
+    fn translate_closures() {
+        // A closure over an argument and a boxed local variable, and code
+        // whose closures capture as many values as it loads.
+        let old = [Bytecode { opcode: Opcode::Enter, src: 1, src2: 1, dst: 1 },
+                   op(Opcode::Box, 0, 2),
+                   op(Opcode::LoadLocal, 0, 0),
+                   op(Opcode::LoadLocal, 0, 2),
+                   op(Opcode::Closure, 2, 2),
+                   op(Opcode::Return, 0, 0)];
+        let new = [Bytecode { opcode: Opcode::EnterRest, src: 1, src2: 0, dst: 6 },
+                   op(Opcode::Box, 2, 0),
+                   pair(Opcode::Move, 3, 0),
+                   pair(Opcode::Move, 4, 2),
+                   pair(Opcode::Move, 5, 4),
+                   pair(Opcode::Move, 4, 3),
+                   op(Opcode::Closure, 3, 2),
+                   op(Opcode::Return, 3, 0)];
+        assert_eq!(translate(&bytes(&old)).map(|(code, _)| code), Ok(bytes(&new)));
+        let old = [Bytecode { opcode: Opcode::Enter, src: 0, src2: 0, dst: 0 },
+                   op(Opcode::LoadCaptured, 0, 1),
+                   op(Opcode::Unbox, 0, 0),
+                   op(Opcode::Return, 0, 0)];
+        let new = [Bytecode { opcode: Opcode::Enter, src: 0, src2: 2, dst: 2 },
+                   op(Opcode::LoadCaptured, 0, 1),
+                   pair(Opcode::Unbox, 0, 0),
+                   op(Opcode::Return, 0, 0)];
+        assert_eq!(translate(&bytes(&old)).map(|(code, _)| code), Ok(bytes(&new)));
+    }
+
+    #[test]
+    fn translate_errors() {
+        let enter = Bytecode { opcode: Opcode::Enter, src: 0, src2: 0, dst: 0 };
+        let ret = op(Opcode::Return, 0, 0);
+        assert_eq!(translate(&bytes(&[ret])),
+                   Err("bad stack bytecode at pc 0: it does not start with Enter".to_owned()));
+        assert_eq!(translate(&bytes(&[enter, ret])),
+                   Err("bad stack bytecode at pc 1: stack underflow".to_owned()));
+        let code = [enter, op(Opcode::LoadLocal, 0, 0), ret];
+        assert_eq!(translate(&bytes(&code)),
+                   Err("bad stack bytecode at pc 1: operand out of range".to_owned()));
+        let code = [enter, op(Opcode::Move, 0, 0), ret];
+        assert_eq!(translate(&bytes(&code)),
+                   Err("bad stack bytecode at pc 1: unexpected Move".to_owned()));
+    }
+}
@@ -1,3 +1,15 @@
+//! Symbols, and the table that interns them.
+//!
+//! Every interpreter has one `SymbolTable`, which maps names to symbols.
+//! Interning the same name twice gives the same symbol, so symbols can be
+//! compared by pointer (`eq?`), and the compiler can look up identifiers
+//! without comparing strings.
+//!
+//! The table is weak: the GC marks the symbols it finds (see `relocate`),
+//! and `SymbolTable::fixup` removes the others after each full collection.
+//! A symbol that is only referred to by the table is thus collected, along
+//! with its global value.
+
 use value;
 use std::collections::HashMap;
 use std::collections::hash_map::Entry;
@@ -25,6 +37,9 @@ pub struct Symbol {
     /// The contents
     pub contents: UnsafeCell<value::Value>,
 
+    /// Is this bound as a global variable?  If not, `contents` is `#f`.
+    pub bound: Cell<bool>,
+
     /// Is this alive?
     pub alive: Cell<bool>,
 }
@@ -38,9 +53,16 @@ impl Symbol {
             contents: UnsafeCell::new(value::Value::new(value::FALSE)),
             name: name,
             stack: vec![],
+            bound: Cell::new(false),
             alive: Cell::new(false),
         }
     }
+
+    /// The Scheme value referring to this symbol.  Symbols are boxed, so
+    /// this is stable for as long as the symbol is in the table.
+    pub fn to_value(&self) -> value::Value {
+        value::Value::new(self as *const Symbol as usize | value::SYMBOL_TAG)
+    }
 }
 
 /// A symbol table.
@@ -56,6 +78,35 @@ pub struct SymbolTable {
 }
 
 impl SymbolTable {
+    /// The names of the symbols bound as global variables.
+    pub fn globals(&self) -> Vec<Rc<String>> {
+        self.contents
+            .values()
+            .filter(|symbol| symbol.bound.get())
+            .map(|symbol| symbol.name())
+            .collect()
+    }
+
+    /// Looks up the symbol called `name`, if it has been interned.
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.contents.get(&name.to_owned()).map(|symbol| &**symbol)
+    }
+
+    /// Interns `name`, and returns the symbol as a Scheme value.  `init` is
+    /// called on the symbol if it did not exist yet.
+    pub fn intern<F: FnOnce(&Symbol)>(&mut self, name: &str, init: F) -> value::Value {
+        let name = name.to_owned();
+        let symbol = match self.contents.entry(Rc::new(name)) {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+                let symbol = Box::new(Symbol::new(v.key().clone()));
+                init(&symbol);
+                v.insert(symbol)
+            }
+        };
+        symbol.to_value()
+    }
+
     pub fn fixup(&mut self) {
         let mut vec = vec![];
         for (i, sym) in &self.contents {
@@ -7,14 +7,28 @@
 //! | Type      | Representation |
 //! |-----------|----------------|
 //! |Fixnum     | As an immediate pointer, with tag 0 or 4.|
-//! |Flonums    | As a pointer to a (boxed) floating-point number, with tag 1.|
+//! |Flonums    | As a pointer to a (boxed) floating-point number: a `RustData` with type word `FLOAT_TYPE`.|
+//! |Characters| As an immediate, with low bits `CHAR_TAG`.|
 //! |Pairs| As a pointer to a 2-tuple, with pointer tag 3. |
 //! |Arrays| As an untagged, aligned pointer to a Rust slice. |
 //! |Records| As a pointer to a Rust slice, with a special header for the GC that indicates how it should be marked.|
 //! |Resources  | As a pointer into a 3-tuple, consisting of a GC header, a pointer to a `struct` that contains an object ID and custom equality, hashing, and other functions, and a pointer into memory not managed by the GC. |
-
+//!
+//! ### NaN boxing
+//!
+//! With the `nan-boxing` feature (64-bit targets only), flonums are
+//! immediates instead: the bits of the double, plus `DOUBLE_OFFSET`.  The
+//! top 16 bits of such a word are never all clear or all set, whereas
+//! pointers and other immediates are unchanged, and fixnums are limited to
+//! 46 bits so that their words are sign-extended from bit 47.  The GC only
+//! sees values through `immediatep` and `size`, so both layouts share it.
+
+use std::any::Any;
 use std::cell::Cell;
 use symbol;
+use builtins;
+use bytecode;
+use port;
 
 /// A Scheme value.
 ///
@@ -53,8 +67,10 @@ pub struct FinalizedHeader {
 /// | Tag |Meaning|
 /// |-----|-------|
 /// |0b000|Vector (chosen to simplify bounds checks)|
-/// |0b001|Record.  The first word points to a record descriptor
+/// |0b001|Record.  The first word points to a record type descriptor
 /// used to identify the record type.|
+/// |0b011|Closure.  The first word is the code (a BCO or a primitive), and
+/// the others are the captured values.|
 /// |Others|Reserved.  These may be later used by the run-time system.
 ///
 /// This struct _**cannot**_ be moved, because it is followed by Scheme
@@ -67,22 +83,208 @@ pub struct Vector {
     header: usize,
 }
 
-/// A descriptor for a `Record`.
+// A vector allocated by `Heap::alloc_vector` has a reserved word after the
+// header, followed by its elements.
+impl Vector {
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        (self.header & HEADER_SIZE) - 2
+    }
+
+    /// The element at `index`, which must be less than `len()`.
+    pub unsafe fn element(&self, index: usize) -> &Value {
+        &*(self as *const Vector as *const Value).offset(2 + index as isize)
+    }
+}
+
+/// A record type descriptor.  Descriptors are themselves records, whose
+/// own descriptor is `#f`.
+#[repr(C)]
+#[derive(Debug)]
 pub struct RecordDescriptor {
-    /// Always a multiple of 8, but never zero.
-    id: usize,
+    header: usize,
+
+    /// Always `#f`.
+    descriptor: Value,
+
+    /// The name of the record type (a symbol).
+    pub name: Value,
+
+    /// The descriptor of the parent type, or `#f`.
+    pub parent: Value,
+
+    /// `#t` if the type cannot have subtypes.
+    pub sealed: Value,
+
+    /// `#t` if instances of the type are not `record?`.
+    pub opaque: Value,
+
+    /// The number of fields, including inherited ones (a fixnum).
+    pub field_count: Value,
+
+    /// The fields defined by this type (not inherited ones), as a list of
+    /// `(name . mutable?)` pairs.
+    pub fields: Value,
 }
 
-/// A Scheme record type.  This has the same memory layout as `Vector`,
-/// but with a different header.
+/// A Scheme record.  This has the same memory layout as `Vector`, but with
+/// a different header, and is followed by its fields.
 #[repr(C)]
 #[derive(Debug)]
 pub struct Record {
-    /// Header.  Always starts with a nonzero 3 most significant bits.
+    /// Header.  Always has `0b001` as the 3 MSBs.
+    header: usize,
+
+    /// The record type descriptor, or `#f` if this is itself a descriptor.
+    /// Built-in types that are laid out as records, such as promises, have
+    /// a fixnum here instead.
+    pub descriptor: Value,
+}
+
+impl Record {
+    /// The number of fields.
+    pub fn len(&self) -> usize {
+        (self.header & HEADER_SIZE) - 2
+    }
+
+    /// The field at `index`, which must be less than `len()`.
+    pub unsafe fn field(&self, index: usize) -> &Value {
+        &*(self as *const Record as *const Value).offset(2 + index as isize)
+    }
+}
+
+/// The descriptor word of a promise.
+pub const PROMISE_DESCRIPTOR: usize = 0b100;
+
+/// A promise, as made by `delay`, `delay-force`, and `make-promise`.  This
+/// is a record whose descriptor is `PROMISE_DESCRIPTOR`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Promise {
     header: usize,
 
-    /// Scheme values.
-    data: [Value],
+    /// Always `PROMISE_DESCRIPTOR`.
+    descriptor: Value,
+
+    /// A pair `(done? . value)`, where `value` is the value of the promise
+    /// if it is done, and otherwise a thunk that returns a promise.  Forcing
+    /// a chain of promises makes them share this pair.
+    pub state: Value,
+}
+
+/// The descriptor word of a multiple-values object.
+pub const VALUES_DESCRIPTOR: usize = 0b1000;
+
+/// Zero or several values, as returned by `values` (a single value is
+/// returned as itself).  This is a record whose descriptor is
+/// `VALUES_DESCRIPTOR`, followed by the values.
+#[repr(C)]
+#[derive(Debug)]
+pub struct MultipleValues {
+    header: usize,
+
+    /// Always `VALUES_DESCRIPTOR`.
+    descriptor: Value,
+}
+
+impl MultipleValues {
+    /// The number of values.
+    pub fn len(&self) -> usize {
+        (self.header & HEADER_SIZE) - 2
+    }
+
+    /// The value at `index`, which must be less than `len()`.
+    pub unsafe fn value(&self, index: usize) -> &Value {
+        &*(self as *const MultipleValues as *const Value).offset(2 + index as isize)
+    }
+}
+
+/// The descriptor word of a condition.
+pub const CONDITION_DESCRIPTOR: usize = 0b1100;
+
+/// A condition, as raised by `error` and by errors detected by primitives
+/// and the interpreter.  This is a record whose descriptor is
+/// `CONDITION_DESCRIPTOR`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Condition {
+    header: usize,
+
+    /// Always `CONDITION_DESCRIPTOR`.
+    descriptor: Value,
+
+    /// The type of the condition: the symbol `error`, `read-error` or
+    /// `file-error`.
+    pub kind: Value,
+
+    /// The message, normally a string.
+    pub message: Value,
+
+    /// The list of irritants.
+    pub irritants: Value,
+}
+
+/// The descriptor word of a port.
+pub const PORT_DESCRIPTOR: usize = 0b10000;
+
+/// A port.  This is a record whose descriptor is `PORT_DESCRIPTOR`.  The
+/// state of the port lives on the Rust heap (see `alloc::port`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct Port {
+    header: usize,
+
+    /// Always `PORT_DESCRIPTOR`.
+    descriptor: Value,
+
+    /// The address of the `port::Port`.  It is aligned, so the GC takes it
+    /// for a fixnum.
+    state: Value,
+}
+
+impl Port {
+    /// The state of the port.
+    pub unsafe fn state<'a>(&self) -> &'a mut port::Port {
+        &mut *(self.state.get() as *mut port::Port)
+    }
+}
+
+/// The descriptor word of a growable vector.
+pub const DYNVECTOR_DESCRIPTOR: usize = 0b10100;
+
+/// A growable vector, as made by `make-dynvector`.  This is a record whose
+/// descriptor is `DYNVECTOR_DESCRIPTOR` (see `alloc::dynvector`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct DynVector {
+    header: usize,
+
+    /// Always `DYNVECTOR_DESCRIPTOR`.
+    descriptor: Value,
+
+    /// The number of elements, a fixnum.
+    pub len: Value,
+
+    /// A vector whose first `len` elements are the elements.  Its length is
+    /// the capacity.
+    pub storage: Value,
+}
+
+impl DynVector {
+    /// The number of elements.
+    pub fn len(&self) -> usize {
+        self.len.get() >> 2
+    }
+
+    /// The number of elements that fit without growing the storage.
+    pub fn capacity(&self) -> usize {
+        unsafe { (*(self.storage.as_ptr() as *const Vector)).len() }
+    }
+
+    /// The element at `index`, which must be less than `capacity()`.
+    pub unsafe fn element(&self, index: usize) -> &Value {
+        (*(self.storage.as_ptr() as *const Vector)).element(index)
+    }
 }
 
 /// A (mutable) Scheme pair.  Subject to garbage collection.
@@ -99,13 +301,148 @@ pub struct Pair {
     pub cdr: Value,
 }
 
-/// A Scheme closure.  Subject to garbage collection.
+/// A Scheme string.  Subject to garbage collection, but never scanned: it
+/// is a `RustData` with type word `STRING_TYPE`.
+///
+/// The UTF-8 contents follow the struct, starting at offset
+/// `STRING_PAYLOAD`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct SchemeStr {
+    header: usize,
+
+    /// Always `STRING_TYPE`.
+    ty: usize,
+
+    /// The length in bytes of the following `str`.
+    pub len: usize,
+}
+
+/// The offset in bytes of the contents of a string.
+pub const STRING_PAYLOAD: usize = 3 * SIZEOF_PTR;
+
+impl SchemeStr {
+    /// The contents.
+    pub unsafe fn as_str(&self) -> &str {
+        let start = (self as *const SchemeStr as usize + STRING_PAYLOAD) as *const u8;
+        ::std::str::from_utf8_unchecked(::std::slice::from_raw_parts(start, self.len))
+    }
+}
+
+/// A Scheme bytevector.  Subject to garbage collection, but never scanned:
+/// it is a `RustData` with type word `BYTEVECTOR_TYPE`.
+///
+/// The bytes follow the struct, starting at offset `BYTEVECTOR_PAYLOAD` so
+/// that they are 8-byte aligned.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Bytevector {
+    header: usize,
+
+    /// Always `BYTEVECTOR_TYPE`.
+    ty: usize,
+
+    /// The length in bytes.
+    pub len: usize,
+}
+
+/// The offset in bytes of the contents of a bytevector.
+pub const BYTEVECTOR_PAYLOAD: usize = (3 * SIZEOF_PTR + 0b111) & !0b111;
+
+impl Bytevector {
+    /// A pointer to the contents.
+    pub fn as_ptr(&self) -> *mut u8 {
+        (self as *const Bytevector as usize + BYTEVECTOR_PAYLOAD) as *mut u8
+    }
+
+    /// The contents.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        ::std::slice::from_raw_parts(self.as_ptr(), self.len)
+    }
+
+    /// The contents, mutably.
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        ::std::slice::from_raw_parts_mut(self.as_ptr(), self.len)
+    }
+}
+
+/// A boxed floating-point number.  Subject to garbage collection, but never
+/// scanned: it is a `RustData` with type word `FLOAT_TYPE`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Float {
+    header: usize,
+
+    /// Always `FLOAT_TYPE`.
+    ty: usize,
+
+    /// The number.
+    pub value: f64,
+}
+
+/// A source of pseudo-random numbers.  Subject to garbage collection, but
+/// never scanned: it is a `RustData` with type word `RANDOM_SOURCE_TYPE`.
+#[repr(C)]
+#[derive(Debug)]
+pub struct RandomSource {
+    header: usize,
+
+    /// Always `RANDOM_SOURCE_TYPE`.
+    ty: usize,
+
+    /// The state of the generator (see `builtins::random`).
+    pub state: u64,
+}
+
+/// A Rust object owned by the heap.  Subject to garbage collection, but
+/// never scanned: it is a `RustData` with type word `RUST_OBJECT_TYPE`.  The
+/// object itself is boxed on the Rust heap, and is dropped by a finalizer
+/// once this is unreachable (see `alloc::rust_data`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct RustData {
+    header: usize,
+
+    /// Always `RUST_OBJECT_TYPE`.
+    ty: usize,
+
+    /// The object.
+    pub object: *mut Box<dyn Any>,
+}
+
+impl RustData {
+    /// The object, if it is a `T`.
+    pub unsafe fn downcast_ref<'a, T: Any>(&self) -> Option<&'a T> {
+        (*self.object).downcast_ref()
+    }
+
+    /// The object, mutably, if it is a `T`.
+    pub unsafe fn downcast_mut<'a, T: Any>(&self) -> Option<&'a mut T> {
+        (*self.object).downcast_mut()
+    }
+}
+
+/// A Scheme closure.  Subject to garbage collection.  Followed by the
+/// captured values.
 #[repr(C)]
 #[derive(Debug)]
 pub struct Closure {
     header: usize,
-    pub bytecode: Value, // a BCO
-    pub environment: [Value],
+
+    /// The code: a BCO or a primitive.
+    pub code: Value,
+}
+
+impl Closure {
+    /// The number of captured values.
+    pub fn len(&self) -> usize {
+        (self.header & HEADER_SIZE) - 2
+    }
+
+    /// The captured value at `index`, which must be less than `len()`.
+    pub unsafe fn captured(&self, index: usize) -> &Value {
+        &*(self as *const Closure as *const Value).offset(2 + index as isize)
+    }
 }
 
 
@@ -134,6 +471,33 @@ pub const EOF: usize = 0x1B;
 /// The Scheme object representing an unspecified value
 pub const UNSPECIFIED: usize = 0x23;
 
+/// The default object, which optional and keyword parameters that are not
+/// passed hold until their defaults are computed (see
+/// `bytecode::Opcode::EnterOptional`)
+pub const DEFAULT: usize = 0x2B;
+
+/// The type word (the word after the header) of a `RustData` holding a
+/// string.
+pub const STRING_TYPE: usize = 0;
+
+/// The type word of a `RustData` that is a handle to a weak hash table.
+pub const WEAK_TABLE_TYPE: usize = 1;
+
+/// The type word of a `RustData` holding a bytevector.
+pub const BYTEVECTOR_TYPE: usize = 2;
+
+/// The type word of a `RustData` holding a boxed float.
+pub const FLOAT_TYPE: usize = 3;
+
+/// The type word of a `RustData` that is a handle to a hash table.
+pub const HASH_TABLE_TYPE: usize = 4;
+
+/// The type word of a `RustData` holding a random source.
+pub const RANDOM_SOURCE_TYPE: usize = 5;
+
+/// The type word of a `RustData` owning a Rust object.
+pub const RUST_OBJECT_TYPE: usize = 6;
+
 pub struct SymbolValue {
     backing: *mut Value,
 }
@@ -143,6 +507,24 @@ pub enum Kind {
     Vector(*mut Vector),
     Fixnum(usize),
     Symbol(*mut symbol::Symbol),
+    Primitive(*const builtins::Primitive),
+    Bytevector(*mut Bytevector),
+    String(*mut SchemeStr),
+    Float(f64),
+    Char(char),
+    Record(*mut Record),
+    Closure(*mut Closure),
+    HashTable(*mut HashTable),
+    RandomSource(*mut RandomSource),
+    Promise(*mut Promise),
+    Values(*mut MultipleValues),
+    Condition(*mut Condition),
+    Port(*mut Port),
+    DynVector(*mut DynVector),
+    RustData(*mut RustData),
+    Bytecode(*mut bytecode::BCO),
+    /// `#t`, `#f`, `()`, the EOF object, and the unspecified value.
+    Constant(usize),
 }
 
 /// An object containing compiled Scheme bytecode.  Subject to garbage collection.
@@ -173,15 +555,63 @@ impl Value {
         (self.get() & !0b111) as *mut Value
     }
 
+    /// Checks if `self` is a heap object marked immutable.  Immediates,
+    /// symbols, and primitives are never marked.
+    pub fn immutablep(&self) -> bool {
+        match self.size() {
+            Some(size) if size > 0 => unsafe { (*self.as_ptr()).get() & IMMUTABLE != 0 },
+            _ => false,
+        }
+    }
+
+    /// Marks `self` immutable, if it is a heap object.  Returns `false` if
+    /// it was already marked (or cannot be).
+    pub fn make_immutable(&self) -> bool {
+        match self.size() {
+            Some(size) if size > 0 => unsafe {
+                let header = &*self.as_ptr();
+                let was_mutable = header.get() & IMMUTABLE == 0;
+                header.set(Value::new(header.get() | IMMUTABLE));
+                was_mutable
+            },
+            _ => false,
+        }
+    }
+
+    /// Marks `self` immutable, along with everything reachable from it
+    /// through pairs and vectors.  This is what `quote` does to literals.
+    pub fn make_constant(&self) {
+        let mut pending = vec![self.clone()];
+        while let Some(value) = pending.pop() {
+            if !value.make_immutable() {
+                continue
+            }
+            match value.kind() {
+                Kind::Pair(pair) => unsafe {
+                    pending.push((*pair).cdr.clone());
+                    pending.push((*pair).car.clone())
+                },
+                Kind::Vector(vector) => unsafe {
+                    for i in 0..(*vector).len() {
+                        pending.push((*vector).element(i).clone())
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
     /// The heap size of `self`, not including `self`.  Returns `None` for
     /// immediate objects.
     pub fn size(&self) -> Option<usize> {
-        if self.tag() == Tags::Symbol {
+        if self.immediatep() {
+            None
+        } else if self.tag() == Tags::Symbol {
             Some(0)
-        } else if self.immediatep() {
+        } else if self.tag() == Tags::RustFunc {
             None
         } else {
-            Some(unsafe { *((self.contents.get() & !0b111) as *const usize) & !HEADER_TAG })
+            Some(unsafe { *((self.contents.get() & !0b111) as *const usize) & HEADER_SIZE })
         }
     }
 
@@ -221,6 +651,35 @@ impl Value {
     pub fn new(contents: usize) -> Self {
         Value { contents: Cell::new(contents) }
     }
+
+    /// Creates a fixnum.
+    pub fn new_fixnum(number: usize) -> Self {
+        debug_assert!(number >> (FIXNUM_WORD_BITS - 2) == 0, "fixnum overflow");
+        Value::new(number << 2)
+    }
+
+    /// Creates a fixnum from its word (the number shifted left by 2), or
+    /// returns `None` if the number does not fit in a fixnum.
+    pub fn from_fixnum_word(word: isize) -> Option<Self> {
+        let unused = SIZEOF_PTR * 8 - FIXNUM_WORD_BITS;
+        if (word << unused) >> unused == word {
+            Some(Value::new(word as usize))
+        } else {
+            None
+        }
+    }
+
+    /// Creates an immediate flonum.
+    #[cfg(feature = "nan-boxing")]
+    pub fn new_float(float: f64) -> Self {
+        // Other NaNs might not leave room for the offset.
+        let float = if float.is_nan() { ::std::f64::NAN } else { float };
+        Value::new((float.to_bits() as usize).wrapping_add(DOUBLE_OFFSET))
+    }
+    /// Creates a character.
+    pub fn new_char(c: char) -> Self {
+        Value::new((c as usize) << 4 | CHAR_TAG)
+    }
     pub fn set(&self, other: Self) -> () {
         self.contents.set(other.contents.get())
     }
@@ -228,6 +687,9 @@ impl Value {
         self.contents.get()
     }
     pub fn array_set(&self, index: usize, other: &Value) -> Result<(), String> {
+        if self.immutablep() {
+            return Err("can't modify a constant vector".to_owned())
+        }
         match self.kind() {
             Kind::Vector(vec) => unsafe { Self::raw_array_set(vec, index, other.clone()) },
             _ => Err("can't index a non-vector".to_owned()),
@@ -271,15 +733,64 @@ impl Value {
     }
 
     pub fn kind(&self) -> Kind {
+        if cfg!(feature = "nan-boxing") && self.flonump() {
+            return Kind::Float(unsafe { float_val(self) })
+        }
         match self.tag() {
             Tags::Pair => Kind::Pair(unsafe { self.as_ptr() } as *mut Pair),
-            Tags::Vector => Kind::Vector(unsafe { self.as_ptr() } as *mut Vector),
+            Tags::Vector if self.immediatep() => Kind::Constant(self.get()),
+            Tags::Vector => unsafe {
+                let ptr = self.as_ptr();
+                match (*ptr).get() & HEADER_TAG {
+                    x if x == HeaderTag::Record as usize => {
+                        match (*ptr.offset(1)).get() {
+                            PROMISE_DESCRIPTOR => Kind::Promise(ptr as *mut Promise),
+                            VALUES_DESCRIPTOR => Kind::Values(ptr as *mut MultipleValues),
+                            CONDITION_DESCRIPTOR => Kind::Condition(ptr as *mut Condition),
+                            PORT_DESCRIPTOR => Kind::Port(ptr as *mut Port),
+                            DYNVECTOR_DESCRIPTOR => Kind::DynVector(ptr as *mut DynVector),
+                            _ => Kind::Record(ptr as *mut Record),
+                        }
+                    }
+                    x if x == HeaderTag::Closure as usize => Kind::Closure(ptr as *mut Closure),
+                    _ => Kind::Vector(ptr as *mut Vector),
+                }
+            },
             Tags::Num | Tags::Num2 => Kind::Fixnum(self.contents.get() >> 2),
             Tags::Symbol => Kind::Symbol(unsafe { self.as_ptr() } as *mut symbol::Symbol),
+            Tags::RustFunc if self.charp() => {
+                Kind::Char(::std::char::from_u32((self.get() >> 4) as u32)
+                               .expect("invalid character"))
+            }
+            Tags::RustFunc => Kind::Primitive(unsafe { self.as_ptr() } as *const builtins::Primitive),
+            Tags::RustData => unsafe {
+                let ptr = self.as_ptr();
+                // The word after the header of a BCO is its length, not a
+                // type.
+                if (*ptr).get() & HEADER_TAG == HeaderTag::Bytecode as usize {
+                    return Kind::Bytecode(ptr as *mut bytecode::BCO)
+                }
+                match (*ptr.offset(1)).get() {
+                    BYTEVECTOR_TYPE => Kind::Bytevector(ptr as *mut Bytevector),
+                    STRING_TYPE => Kind::String(ptr as *mut SchemeStr),
+                    FLOAT_TYPE => Kind::Float((*(ptr as *const Float)).value),
+                    HASH_TABLE_TYPE => Kind::HashTable(ptr as *mut HashTable),
+                    RANDOM_SOURCE_TYPE => Kind::RandomSource(ptr as *mut RandomSource),
+                    RUST_OBJECT_TYPE => Kind::RustData(ptr as *mut RustData),
+                    _ => unimplemented!(),
+                }
+            },
             _ => unimplemented!(),
         }
     }
 
+    pub fn as_char(&self) -> Result<char, &'static str> {
+        match self.kind() {
+            Kind::Char(c) => Ok(c),
+            _ => Err("not a character"),
+        }
+    }
+
     pub fn as_fixnum(&self) -> Result<usize, &'static str> {
         match self.kind() {
             Kind::Fixnum(val) => Ok(val),
@@ -303,13 +814,32 @@ impl Bignum {
     }
 }
 
+/// The number in a flonum.  `val` must be a flonum.
+#[cfg(not(feature = "nan-boxing"))]
 pub unsafe fn float_val(val: &Value) -> f64 {
-    *((val.get() & 0b111) as *const f64)
+    (*(val.as_ptr() as *const Float)).value
 }
 
-pub struct HashTable;
-pub struct IOPort;
-pub struct RustData;
+/// The number in a flonum.  `val` must be a flonum.
+#[cfg(feature = "nan-boxing")]
+pub unsafe fn float_val(val: &Value) -> f64 {
+    f64::from_bits(val.get().wrapping_sub(DOUBLE_OFFSET) as u64)
+}
+
+/// A handle to a hash table.  Subject to garbage collection, but never
+/// scanned: it is a `RustData` with type word `HASH_TABLE_TYPE`.  The table
+/// itself lives on the Rust heap (see `alloc::hash_table`).
+#[repr(C)]
+#[derive(Debug)]
+pub struct HashTable {
+    header: usize,
+
+    /// Always `HASH_TABLE_TYPE`.
+    ty: usize,
+
+    /// The index of the table in the heap's hash tables.
+    pub index: usize,
+}
 
 // Same set used by Femtolisp
 /// The tag of `fixnum`s
@@ -318,6 +848,26 @@ pub const NUM_TAG: usize = 0b000;
 /// The tag of Rust-implemented functions.
 pub const RUST_FUNC_TAG: usize = 0b001;
 
+/// The low 4 bits of a character, which is an immediate holding the code
+/// point shifted left by 4.  Characters share `RUST_FUNC_TAG` with
+/// primitives, which are 16-byte aligned, so bit 3 tells them apart.
+pub const CHAR_TAG: usize = 0b1001;
+
+/// Added to the bits of a double to NaN-box it.
+#[cfg(feature = "nan-boxing")]
+pub const DOUBLE_OFFSET: usize = 1 << 49;
+
+/// The number of low bits of a word used by a fixnum, including its tag.
+#[cfg(not(feature = "nan-boxing"))]
+pub const FIXNUM_WORD_BITS: usize = SIZEOF_PTR * 8;
+
+/// The number of low bits of a word used by a fixnum, including its tag.
+#[cfg(feature = "nan-boxing")]
+pub const FIXNUM_WORD_BITS: usize = 48;
+
+#[cfg(all(feature = "nan-boxing", not(target_pointer_width = "64")))]
+compile_error!("NaN boxing requires 64-bit pointers");
+
 /// The tag of Scheme-implemented functions.
 pub const FUNCTION_TAG: usize = 0b010;
 
@@ -355,6 +905,13 @@ pub const SIZEOF_PAIR: usize = (3 * self::SIZEOF_PTR + 0b111) >> 3;
 /// Bitmask that includes the tag words of an object header.
 pub const HEADER_TAG: usize = 0b111 << (self::SIZEOF_PTR * 8 - 3);
 
+/// The immutability bit of an object header, just below the tag.  Set on
+/// literal constants, such as those produced by `quote`.
+pub const IMMUTABLE: usize = 1 << (self::SIZEOF_PTR * 8 - 4);
+
+/// Bitmask that includes the size of an object (in words) in its header.
+pub const HEADER_SIZE: usize = !(HEADER_TAG | IMMUTABLE);
+
 /// The header of a pair.
 pub const PAIR_HEADER: usize = HeaderTag::Pair as usize + SIZEOF_PAIR;
 
@@ -421,7 +978,8 @@ impl Value {
     }
     // #[inline(always)]
     pub fn both_fixnums(&self, other: &Self) -> bool {
-        (self.get() | other.get()) & 0b11 == 0
+        (self.get() | other.get()) & 0b11 == 0 &&
+        !(cfg!(feature = "nan-boxing") && (self.flonump() || other.flonump()))
     }
     // #[inline(always)]
     pub fn self_evaluating(&self) -> bool {
@@ -429,21 +987,34 @@ impl Value {
     }
     // #[inline(always)]
     pub fn fixnump(&self) -> bool {
-        self.raw_tag() & 0b11 == 0
+        self.raw_tag() & 0b11 == 0 && !(cfg!(feature = "nan-boxing") && self.flonump())
     }
     // #[inline(always)]
     pub fn pairp(&self) -> bool {
         self.tag() == Tags::Pair
     }
+    #[cfg(not(feature = "nan-boxing"))]
     #[inline(always)]
     pub fn flonump(&self) -> bool {
-        unimplemented!()
+        self.raw_tag() == RUST_DATA_TAG && unsafe { (*self.as_ptr().offset(1)).get() == FLOAT_TYPE }
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    #[inline(always)]
+    pub fn flonump(&self) -> bool {
+        let top = self.get() >> 48;
+        top != 0 && top != 0xFFFF
+    }
+
+    pub fn charp(&self) -> bool {
+        self.get() & 0b1111 == CHAR_TAG
     }
 
     // n#[inline(always)]
     pub fn immediatep(&self) -> bool {
         let val = self.get();
-        val & 0b11 == 0 || val <= 0xFF // special immediates
+        val & 0b11 == 0 || val <= 0xFF || self.charp() || // special immediates
+        cfg!(feature = "nan-boxing") && self.flonump()
     }
 }
 
@@ -452,3 +1023,54 @@ macro_rules! size_of {
         ::std::mem::size_of::<$ty>()
     }
 }
+
+#[cfg(all(test, feature = "nan-boxing"))]
+mod tests {
+    use std::f64;
+    use super::*;
+
+    #[test]
+    fn flonums_round_trip() {
+        let floats = [0.0, -0.0, 1.5, -1.5, f64::INFINITY, f64::NEG_INFINITY, f64::MAX, f64::MIN,
+                      f64::MIN_POSITIVE, 5e-324, -5e-324, f64::NAN, -f64::NAN,
+                      f64::from_bits(!0)];
+        for &float in &floats {
+            let value = Value::new_float(float);
+            assert!(value.flonump() && value.immediatep(), "{:?}", float);
+            assert!(!value.fixnump() && !value.charp(), "{:?}", float);
+            match value.kind() {
+                Kind::Float(back) if float.is_nan() => assert!(back.is_nan()),
+                Kind::Float(back) => assert_eq!(back.to_bits(), float.to_bits()),
+                _ => panic!("{:?} is not a flonum", float),
+            }
+        }
+        let zero = Value::new_float(-0.0);
+        assert!(zero != Value::new_float(0.0));
+        assert!(unsafe { float_val(&zero) }.is_sign_negative());
+    }
+
+    #[test]
+    fn fixnums_and_pointers_are_not_flonums() {
+        let largest = (1 << (FIXNUM_WORD_BITS - 1)) - 4;
+        let smallest = -(1 << (FIXNUM_WORD_BITS - 1));
+        for &word in &[0, 4, -4, largest, smallest] {
+            let value = Value::from_fixnum_word(word).unwrap();
+            assert!(value.fixnump() && value.immediatep() && !value.flonump(), "{:x}", word);
+            assert!(value.both_fixnums(&Value::new_fixnum(1)));
+        }
+        assert_eq!(Value::from_fixnum_word(largest + 4), None);
+        assert_eq!(Value::from_fixnum_word(smallest - 4), None);
+        assert!(!Value::new_fixnum(1).both_fixnums(&Value::new_float(1.0)));
+        // The highest user-space address, and the lowest kernel-space one.
+        for &address in &[0x0000_7fff_ffff_fff0, 0xffff_8000_0000_0000] {
+            let pair = Value::new(address | PAIR_TAG);
+            let vector = Value::new(address | VECTOR_TAG);
+            assert!(!pair.flonump() && !pair.immediatep() && pair.tag() == Tags::Pair);
+            assert!(!vector.flonump() && !vector.immediatep() && vector.tag() == Tags::Vector);
+        }
+        for &constant in &[NIL, FALSE, TRUE, UNSPECIFIED] {
+            assert!(!Value::new(constant).flonump());
+        }
+        assert!(!Value::new_char('\u{10ffff}').flonump());
+    }
+}
@@ -0,0 +1,91 @@
+//! Bindings for JavaScript, with the `wasm` feature.
+//!
+//! Built for `wasm32-unknown-unknown` (for instance with `wasm-pack build
+//! --features wasm`), the crate exports `Scheme`, an interpreter that
+//! JavaScript can evaluate code in:
+//!
+//! ```js
+//! const scheme = new Scheme();
+//! scheme.eval("(define (square x) (* x x))");
+//! scheme.eval("(display (square 12)) 'done");  // "done"
+//! scheme.takeOutput();                            // "144"
+//! ```
+//!
+//! What Scheme code writes to its standard output and error is kept until
+//! `takeOutput` returns it, and its standard input is empty.
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use api::Interpreter;
+use platform::Console;
+use port::{Sink, Source};
+
+/// What Scheme code wrote to its standard output and error.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Console for Output {
+    fn input(&self) -> Box<dyn Source> {
+        Box::new(io::empty())
+    }
+
+    fn output(&self) -> Box<dyn Sink> {
+        Box::new(self.clone())
+    }
+
+    fn error(&self) -> Box<dyn Sink> {
+        Box::new(self.clone())
+    }
+}
+
+/// An interpreter, for JavaScript.
+#[wasm_bindgen]
+pub struct Scheme {
+    interp: Interpreter,
+    output: Output,
+}
+
+#[wasm_bindgen]
+impl Scheme {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Scheme {
+        let output = Output::default();
+        let mut interp = Interpreter::new();
+        interp.state().set_console(Rc::new(output.clone()));
+        Scheme {
+            interp: interp,
+            output: output,
+        }
+    }
+
+    /// Evaluates each datum in `source` at top level, and returns the value
+    /// of the last one, as `write` writes it.  Throws the error message if
+    /// reading, compiling or running the code fails.
+    pub fn eval(&mut self, source: &str) -> Result<String, JsValue> {
+        let result = self.interp.eval_str(source).map(|value| self.interp.write_string(&value));
+        let _ = self.interp.state().flush_output();
+        result.map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// What Scheme code wrote to its standard output and error since the
+    /// last call.
+    #[wasm_bindgen(js_name = takeOutput)]
+    pub fn take_output(&mut self) -> String {
+        let bytes = mem::replace(&mut *self.output.0.borrow_mut(), vec![]);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}